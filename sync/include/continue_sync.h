@@ -0,0 +1,77 @@
+/*
+ * The C ABI of Continue's codebase indexer, from the `sync` crate built with the `sync-ffi`
+ * feature (see src/ffi.rs).
+ *
+ * Strings are UTF-8 and null-terminated both ways. Results are JSON, in the shapes
+ * `continue-sync --json` prints, and belong to the caller, who frees them with
+ * continue_sync_string_free. A call that fails returns NULL (or -1) and, if `error` isn't NULL,
+ * sets `*error` to a message to be freed the same way. A handle can be moved between threads but
+ * mustn't be used from two at once.
+ */
+
+#ifndef CONTINUE_SYNC_H
+#define CONTINUE_SYNC_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Where the index is, for every call made with it */
+typedef struct ContinueSync ContinueSync;
+
+/* One tag, with its tree kept in memory between syncs */
+typedef struct ContinueSyncSession ContinueSyncSession;
+
+/* A handle on the index in `index_root`, or in the default place (~/.continue/index, or
+ * CONTINUE_INDEX_ROOT) if it's NULL. Free it with continue_sync_free */
+ContinueSync *continue_sync_new(const char *index_root, char **error);
+void continue_sync_free(ContinueSync *handle);
+
+/* Free a string returned by any of these functions */
+void continue_sync_string_free(char *text);
+
+/* Sync the tag, returning its SyncResults:
+ * {"compute": [...], "delete": [...], "addTag": [...], "removeTag": [...], "renamed": [...],
+ *  "warnings": [...]} */
+char *continue_sync_sync(ContinueSync *handle, const char *dir, const char *branch,
+                         const char *provider_id, char **error);
+
+/* Remove the tag from the index, returning the SyncResults of what that leaves to do */
+char *continue_sync_delete_tag(ContinueSync *handle, const char *dir, const char *branch,
+                               const char *provider_id, char **error);
+
+/* The tags in the index, for `dir` and `provider_id` unless they're NULL, as an array of
+ * {"tag": {"dir", "branch", "providerId"}, "lastSync"} */
+char *continue_sync_list_tags(ContinueSync *handle, const char *dir, const char *provider_id,
+                              char **error);
+
+/* The files in the tag's saved tree, as an array of {"path", "relative_path", "hash"} */
+char *continue_sync_list_files(ContinueSync *handle, const char *dir, const char *branch,
+                               const char *provider_id, char **error);
+
+/* The hex root hash of the tag's saved tree as a JSON string, or null if it hasn't been synced */
+char *continue_sync_root_hash(ContinueSync *handle, const char *dir, const char *branch,
+                              const char *provider_id, char **error);
+
+/* Mark the `count` hex hashes in `hashes` as computed for the tag. Returns 0, or -1 if it
+ * fails */
+int continue_sync_mark_computed(ContinueSync *handle, const char *dir, const char *branch,
+                                const char *provider_id, const char *const *hashes, size_t count,
+                                char **error);
+
+/* Open a session on the tag, loading its saved tree. Free it with continue_sync_session_free */
+ContinueSyncSession *continue_sync_session_open(ContinueSync *handle, const char *dir,
+                                                const char *branch, const char *provider_id,
+                                                char **error);
+
+/* Sync the session's tag against the tree it has in memory, returning the SyncResults */
+char *continue_sync_session_sync(ContinueSyncSession *session, char **error);
+void continue_sync_session_free(ContinueSyncSession *session);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CONTINUE_SYNC_H */
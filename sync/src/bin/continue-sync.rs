@@ -0,0 +1,785 @@
+//! `continue-sync`, for using the index from scripts and looking into it without the IDE
+//!
+//! Each command prints what it found as text, or as JSON with `--json` (the same shapes the
+//! extension gets). Run with `--help` for the list of commands.
+
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    env, fmt, fs,
+    io::{self, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+    thread,
+};
+use sync::sync::{
+    diff_dir_against_saved, gc, hash_string, list_tags, pack_objects, parse_hash, read_object,
+    search, serve_http, serve_http_with_events, serve_json_rpc, sync, sync_providers, tag_status,
+    verify_index, ChunkOptions, IndexProvider, IndexRoot, SearchIndex, SftpFileSystem, SymbolIndex,
+    SyncError, SyncEvents, SyncOptions, SyncResultItem, SyncResults, SyncWatcher, Tag,
+};
+
+const USAGE: &str = "\
+Usage: continue-sync [--index-root <dir>] [--json] <command> [<args>]
+
+Commands:
+  sync <dir>      Sync a directory and print what needs to change in the index
+                  [--branch <name>] [--provider <id>] [--dry-run] [--force]
+                  [--ssh <destination>], to read <dir> on another machine over SFTP
+                  [--archives], to index the files in zip, jar and tar files
+                  [--store-objects], to save the content of the files to compute
+                  [--chunk-size <bytes>] [--chunk-overlap <bytes>], to split the files
+                  to compute into chunks, given with them in the JSON
+                  [--chunk-syntax], to split source code between its definitions
+                  [--search], to add what it finds to the provider's full-text index
+                  [--symbols], to add the symbols it finds to the provider's symbol index
+  status <dir>    Show when a tag was last synced and whether it's up to date
+                  [--branch <name>] [--provider <id>]
+  search <dir>    Search the files of a tag synced with --search for every word of <text>
+                  --query <text> [--branch <name>] [--provider <id>]
+  symbols <dir>   List the symbols starting with <prefix> in a tag synced with --symbols
+                  --query <prefix> [--branch <name>] [--provider <id>]
+  tags [<dir>]    List the tags in the index [--provider <id>]
+  object <hash>   Print the content saved for a hash by a sync with --store-objects
+                  [--provider <id>]
+  gc <provider>   Clear out what's left in a provider's caches by tags that are gone
+                  [--dry-run]
+  pack <provider> Move the content saved by --store-objects into packs
+  verify <dir>    Check a tag's tree and caches against each other, failing if they differ
+                  [--branch <name>] [--provider <id>]
+  diff <dir>      Print the files added and removed since the last sync, without syncing
+                  [--branch <name>] [--provider <id>]
+  watch <dir>     Sync a directory whenever it changes, printing what each sync finds; with
+                  --host or --port, also answer HTTP requests, streaming the syncs from
+                  /events [--branch <name>] [--provider <id>] [--host <address>] [--port <port>]
+  serve           Answer JSON-RPC requests on stdin, one per line, until it's closed
+  http            Answer read-only HTTP requests about the index
+                  [--host <address>] [--port <port>]
+
+The branch defaults to the one checked out in <dir>'s git repository (so give it with --ssh),
+and the provider to \"default\". The index is kept in ~/.continue/index unless --index-root or
+CONTINUE_INDEX_ROOT says otherwise.
+";
+
+/// Options that take a value
+const VALUE_OPTIONS: &[&str] = &[
+    "--branch",
+    "--provider",
+    "--index-root",
+    "--host",
+    "--port",
+    "--ssh",
+    "--chunk-size",
+    "--chunk-overlap",
+    "--query",
+];
+
+/// Where `http` and `watch` listen unless told otherwise
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_PORT: &str = "7575";
+
+/// The most hits `search` and `symbols` print
+const SEARCH_LIMIT: usize = 20;
+
+/// Options that are on or off
+const FLAGS: &[&str] = &[
+    "--json",
+    "--dry-run",
+    "--force",
+    "--archives",
+    "--store-objects",
+    "--chunk-syntax",
+    "--search",
+    "--symbols",
+    "--help",
+];
+
+#[derive(Debug)]
+enum CliError {
+    Usage(String),
+    Sync(SyncError),
+    Io(io::Error),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::Usage(message) => write!(f, "{message}\n\n{USAGE}"),
+            CliError::Sync(err) => write!(f, "{err}"),
+            CliError::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<SyncError> for CliError {
+    fn from(err: SyncError) -> Self {
+        CliError::Sync(err)
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(err: io::Error) -> Self {
+        CliError::Io(err)
+    }
+}
+
+type Result<T> = std::result::Result<T, CliError>;
+
+/// The command line, split into what the commands need
+#[derive(Debug, Default, PartialEq)]
+struct Args {
+    command: String,
+    positional: Vec<String>,
+    branch: Option<String>,
+    provider: Option<String>,
+    index_root: Option<PathBuf>,
+    host: Option<String>,
+    port: Option<String>,
+    ssh: Option<String>,
+    chunk_size: Option<usize>,
+    chunk_overlap: Option<usize>,
+    query: Option<String>,
+    json: bool,
+    dry_run: bool,
+    force: bool,
+    archives: bool,
+    store_objects: bool,
+    chunk_syntax: bool,
+    search: bool,
+    symbols: bool,
+    help: bool,
+}
+
+impl Args {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        let mut positional = Vec::new();
+        while let Some(arg) = args.next() {
+            let (name, inline_value) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+                _ => (arg.as_str(), None),
+            };
+            if VALUE_OPTIONS.contains(&name) {
+                let value = match inline_value.or_else(|| args.next()) {
+                    Some(value) => value,
+                    None => return Err(CliError::Usage(format!("{name} needs a value"))),
+                };
+                match name {
+                    "--branch" => parsed.branch = Some(value),
+                    "--provider" => parsed.provider = Some(value),
+                    "--host" => parsed.host = Some(value),
+                    "--port" => parsed.port = Some(value),
+                    "--ssh" => parsed.ssh = Some(value),
+                    "--chunk-size" => parsed.chunk_size = Some(parse_size(name, &value)?),
+                    "--chunk-overlap" => parsed.chunk_overlap = Some(parse_size(name, &value)?),
+                    "--query" => parsed.query = Some(value),
+                    _ => parsed.index_root = Some(value.into()),
+                }
+            } else if FLAGS.contains(&name) && inline_value.is_none() {
+                match name {
+                    "--json" => parsed.json = true,
+                    "--dry-run" => parsed.dry_run = true,
+                    "--force" => parsed.force = true,
+                    "--archives" => parsed.archives = true,
+                    "--store-objects" => parsed.store_objects = true,
+                    "--chunk-syntax" => parsed.chunk_syntax = true,
+                    "--search" => parsed.search = true,
+                    "--symbols" => parsed.symbols = true,
+                    _ => parsed.help = true,
+                }
+            } else if name == "-h" {
+                parsed.help = true;
+            } else if name.starts_with('-') && name != "-" {
+                return Err(CliError::Usage(format!("Unknown option {arg}")));
+            } else {
+                positional.push(arg);
+            }
+        }
+        let mut positional = positional.into_iter();
+        parsed.command = positional.next().unwrap_or_default();
+        parsed.positional = positional.collect();
+        Ok(parsed)
+    }
+
+    /// The one positional argument the command takes, if it's optional
+    fn optional_arg(&self) -> Result<Option<&str>> {
+        match self.positional.as_slice() {
+            [] => Ok(None),
+            [arg] => Ok(Some(arg)),
+            [_, extra, ..] => Err(CliError::Usage(format!("Unexpected argument {extra}"))),
+        }
+    }
+
+    /// The one positional argument the command takes
+    fn arg(&self, name: &str) -> Result<&str> {
+        self.optional_arg()?
+            .ok_or_else(|| CliError::Usage(format!("{} needs a <{name}>", self.command)))
+    }
+
+    fn options(&self) -> SyncOptions {
+        let mut options = SyncOptions::new()
+            .dry_run(self.dry_run)
+            .force(self.force)
+            .archives(self.archives)
+            .store_objects(self.store_objects);
+        if self.chunk_size.is_some() || self.chunk_overlap.is_some() || self.chunk_syntax {
+            let defaults = ChunkOptions::default();
+            options = options.chunking(ChunkOptions {
+                max_size: self.chunk_size.unwrap_or(defaults.max_size),
+                overlap: self.chunk_overlap.unwrap_or(defaults.overlap),
+                syntax_aware: self.chunk_syntax,
+            });
+        }
+        match &self.index_root {
+            Some(index_root) => options.index_root(IndexRoot::new(index_root)),
+            None => options,
+        }
+    }
+
+    /// A listener on the host and port given, or the defaults
+    fn listen(&self) -> Result<TcpListener> {
+        let host = self.host.as_deref().unwrap_or(DEFAULT_HOST);
+        let port = self.port.as_deref().unwrap_or(DEFAULT_PORT);
+        let listener = TcpListener::bind(format!("{host}:{port}"))?;
+        eprintln!("Listening on http://{}", listener.local_addr()?);
+        Ok(listener)
+    }
+
+    fn provider(&self) -> &str {
+        self.provider.as_deref().unwrap_or("default")
+    }
+
+    /// The branch given, or the one checked out in `dir`
+    fn branch(&self, dir: &Path) -> String {
+        match &self.branch {
+            Some(branch) => branch.clone(),
+            None => current_branch(dir).unwrap_or_else(|| "NONE".to_string()),
+        }
+    }
+}
+
+/// The number of bytes given to an option
+fn parse_size(name: &str, value: &str) -> Result<usize> {
+    value
+        .parse()
+        .map_err(|_| CliError::Usage(format!("{name} needs a number of bytes, not {value}")))
+}
+
+/// The branch checked out in the git repository `dir` is in, if it is in one and isn't on a
+/// detached HEAD
+fn current_branch(dir: &Path) -> Option<String> {
+    let dir = fs::canonicalize(dir).ok()?;
+    let head = dir
+        .ancestors()
+        .map(|dir| dir.join(".git").join("HEAD"))
+        .find(|head| head.is_file())?;
+    let head = fs::read_to_string(head).ok()?;
+    head.trim()
+        .strip_prefix("ref: refs/heads/")
+        .map(str::to_string)
+}
+
+/// Run the command line, writing what it prints to `out`. Returns whether the command
+/// succeeded, which `verify` doesn't if the index is inconsistent
+fn run(args: &Args, out: &mut dyn Write) -> Result<bool> {
+    if args.help || args.command.is_empty() {
+        write!(out, "{USAGE}")?;
+        return Ok(!args.command.is_empty() || args.help);
+    }
+    let options = args.options();
+    match args.command.as_str() {
+        "sync" => with_tag(args, &options, out, sync_command),
+        "status" => with_tag(args, &options, out, status_command),
+        "verify" => with_tag(args, &options, out, verify_command),
+        "diff" => with_tag(args, &options, out, diff_command),
+        "watch" => with_tag(args, &options, out, watch_command),
+        "search" => with_tag(args, &options, out, search_command),
+        "symbols" => with_tag(args, &options, out, symbols_command),
+        "tags" => {
+            let dir = args.optional_arg()?.map(Path::new);
+            let tags = list_tags(&options, dir, args.provider.as_deref())?;
+            if args.json {
+                return print_json(out, &tags);
+            }
+            for entry in tags {
+                match entry.last_sync {
+                    Some(last_sync) => writeln!(out, "{}\tlast synced {last_sync}", entry.tag)?,
+                    None => writeln!(out, "{}\tnever synced", entry.tag)?,
+                }
+            }
+            Ok(true)
+        }
+        "object" => {
+            let hex = args.arg("hash")?;
+            let hash = parse_hash(hex)
+                .ok_or_else(|| CliError::Usage(format!("{hex} isn't a content hash")))?;
+            let contents = read_object(hash, args.provider(), &options)?.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No content saved for {hex}"),
+                )
+            })?;
+            out.write_all(&contents)?;
+            Ok(true)
+        }
+        "gc" => {
+            let results = gc(args.arg("provider")?, &options)?;
+            if args.json {
+                return print_json(out, &results);
+            }
+            print_items(out, "delete", &results.delete)?;
+            writeln!(
+                out,
+                "{} references, {} cache entries and {} objects removed, {} bytes reclaimed",
+                results.removed_references,
+                results.removed_cache_entries,
+                results.removed_objects,
+                results.reclaimed_bytes
+            )?;
+            Ok(true)
+        }
+        "pack" => {
+            let results = pack_objects(args.arg("provider")?, &options)?;
+            if args.json {
+                return print_json(out, &results);
+            }
+            writeln!(
+                out,
+                "{} objects packed, {} packs written, {} merged into them",
+                results.packed_objects, results.packs_written, results.packs_merged
+            )?;
+            Ok(true)
+        }
+        "serve" => {
+            if let Some(arg) = args.optional_arg()? {
+                return Err(CliError::Usage(format!("Unexpected argument {arg}")));
+            }
+            serve_json_rpc(io::stdin().lock(), io::stdout(), &options)?;
+            Ok(true)
+        }
+        "http" => {
+            if let Some(arg) = args.optional_arg()? {
+                return Err(CliError::Usage(format!("Unexpected argument {arg}")));
+            }
+            serve_http(args.listen()?, &options)?;
+            Ok(true)
+        }
+        command => Err(CliError::Usage(format!("Unknown command {command}"))),
+    }
+}
+
+/// A command on the tag for the directory given, run by `command`
+type TagCommand = fn(&Tag, &SyncOptions, &Args, &mut dyn Write) -> Result<bool>;
+
+fn with_tag(
+    args: &Args,
+    options: &SyncOptions,
+    out: &mut dyn Write,
+    command: TagCommand,
+) -> Result<bool> {
+    let dir = PathBuf::from(args.arg("dir")?);
+    let branch = args.branch(&dir);
+    let tag = Tag {
+        dir: &dir,
+        branch: &branch,
+        provider_id: args.provider(),
+    };
+    command(&tag, options, args, out)
+}
+
+fn sync_command(
+    tag: &Tag,
+    options: &SyncOptions,
+    args: &Args,
+    out: &mut dyn Write,
+) -> Result<bool> {
+    let options = &match &args.ssh {
+        Some(destination) => {
+            let sftp = SftpFileSystem::connect(destination)?;
+            options.clone().file_system(Arc::new(sftp))
+        }
+        None => options.clone(),
+    };
+    let mut search_index = match args.search {
+        true => Some(SearchIndex::open(tag.provider_id, options)?),
+        false => None,
+    };
+    let mut symbol_index = match args.symbols {
+        true => Some(SymbolIndex::open(tag.provider_id, options)?),
+        false => None,
+    };
+    let mut providers: Vec<&mut dyn IndexProvider> = Vec::new();
+    if let Some(index) = &mut search_index {
+        providers.push(index);
+    }
+    if let Some(index) = &mut symbol_index {
+        providers.push(index);
+    }
+    let results = match providers.is_empty() {
+        true => sync(tag, options)?,
+        false => sync_providers(tag, options, &mut providers)?,
+    };
+    if args.json {
+        return print_json(out, &results);
+    }
+    print_results(out, &results)?;
+    Ok(true)
+}
+
+fn search_command(
+    tag: &Tag,
+    options: &SyncOptions,
+    args: &Args,
+    out: &mut dyn Write,
+) -> Result<bool> {
+    let query = args
+        .query
+        .as_deref()
+        .ok_or_else(|| CliError::Usage("search needs --query <text>".to_string()))?;
+    let hits = search(tag, query, SEARCH_LIMIT, options)?;
+    if args.json {
+        return print_json(out, &hits);
+    }
+    for hit in hits {
+        let snippet = hit.snippet.split_whitespace().collect::<Vec<_>>().join(" ");
+        writeln!(
+            out,
+            "{}:{}-{}\t{snippet}",
+            hit.relative_path,
+            hit.start_line + 1,
+            hit.end_line + 1
+        )?;
+    }
+    Ok(true)
+}
+
+fn symbols_command(
+    tag: &Tag,
+    options: &SyncOptions,
+    args: &Args,
+    out: &mut dyn Write,
+) -> Result<bool> {
+    let prefix = args
+        .query
+        .as_deref()
+        .ok_or_else(|| CliError::Usage("symbols needs --query <prefix>".to_string()))?;
+    let hits =
+        SymbolIndex::open(tag.provider_id, options)?.find_prefix(tag, prefix, SEARCH_LIMIT)?;
+    if args.json {
+        return print_json(out, &hits);
+    }
+    for hit in hits {
+        writeln!(
+            out,
+            "{}:{}-{}\t{}\t{}",
+            hit.relative_path,
+            hit.symbol.start_line + 1,
+            hit.symbol.end_line + 1,
+            hit.symbol.kind.as_str(),
+            hit.symbol.name
+        )?;
+    }
+    Ok(true)
+}
+
+fn watch_command(
+    tag: &Tag,
+    options: &SyncOptions,
+    args: &Args,
+    out: &mut dyn Write,
+) -> Result<bool> {
+    let events = Arc::new(SyncEvents::new());
+    if args.host.is_some() || args.port.is_some() {
+        let listener = args.listen()?;
+        let (options, events) = (options.clone(), events.clone());
+        // The server lasts as long as the process, which ends when the watcher does
+        thread::spawn(move || serve_http_with_events(listener, &options, &events));
+    }
+    let mut watcher = SyncWatcher::open(tag, options.clone())?;
+    let mut printed = Ok(());
+    watcher.run(|results| {
+        events.send_results(tag, &results);
+        printed = match args.json {
+            true => serde_json::to_writer(&mut *out, &results)
+                .map_err(io::Error::from)
+                .and_then(|()| writeln!(out)),
+            false => print_results(out, &results),
+        }
+        .and_then(|()| out.flush());
+        Ok(printed.is_ok())
+    })?;
+    printed?;
+    Ok(true)
+}
+
+fn status_command(
+    tag: &Tag,
+    options: &SyncOptions,
+    args: &Args,
+    out: &mut dyn Write,
+) -> Result<bool> {
+    let status = tag_status(tag, options)?;
+    if args.json {
+        return print_json(out, &status);
+    }
+    writeln!(out, "{}", status.tag)?;
+    match status.last_sync {
+        Some(last_sync) => writeln!(out, "  last synced: {last_sync}")?,
+        None => writeln!(out, "  last synced: never")?,
+    }
+    let root_hash = status.root_hash.as_deref().unwrap_or("none");
+    writeln!(out, "  root hash: {root_hash}")?;
+    writeln!(out, "  files: {}", status.files)?;
+    let up_to_date = if status.up_to_date { "yes" } else { "no" };
+    writeln!(out, "  up to date: {up_to_date}")?;
+    Ok(true)
+}
+
+fn verify_command(
+    tag: &Tag,
+    options: &SyncOptions,
+    args: &Args,
+    out: &mut dyn Write,
+) -> Result<bool> {
+    let report = verify_index(tag, options)?;
+    if args.json {
+        print_json(out, &report)?;
+    } else if report.is_consistent() {
+        writeln!(out, "{tag} is consistent")?;
+    } else {
+        for inconsistency in &report.inconsistencies {
+            writeln!(out, "{inconsistency}")?;
+        }
+    }
+    Ok(report.is_consistent())
+}
+
+fn diff_command(
+    tag: &Tag,
+    options: &SyncOptions,
+    args: &Args,
+    out: &mut dyn Write,
+) -> Result<bool> {
+    let (added, removed) = diff_dir_against_saved(tag, options)?;
+    let added: Vec<_> = added.into_iter().filter(|item| item.is_blob).collect();
+    let removed: Vec<_> = removed.into_iter().filter(|item| item.is_blob).collect();
+    if args.json {
+        return print_json(out, &json!({ "added": added, "removed": removed }));
+    }
+    for item in &removed {
+        writeln!(out, "- {}\t{}", item.path.display(), hash_string(item.hash))?;
+    }
+    for item in &added {
+        writeln!(out, "+ {}\t{}", item.path.display(), hash_string(item.hash))?;
+    }
+    Ok(true)
+}
+
+fn print_json(out: &mut dyn Write, value: &impl Serialize) -> Result<bool> {
+    serde_json::to_writer_pretty(&mut *out, value).map_err(io::Error::from)?;
+    writeln!(out)?;
+    Ok(true)
+}
+
+fn print_items(out: &mut dyn Write, action: &str, items: &[SyncResultItem]) -> io::Result<()> {
+    for item in items {
+        writeln!(out, "{action}\t{}\t{}", item.relative_path, item.hash)?;
+    }
+    Ok(())
+}
+
+fn print_results(out: &mut dyn Write, results: &SyncResults) -> io::Result<()> {
+    print_items(out, "compute", &results.compute)?;
+    print_items(out, "delete", &results.delete)?;
+    print_items(out, "add_tag", &results.add_tag)?;
+    print_items(out, "remove_tag", &results.remove_tag)?;
+    for item in &results.renamed {
+        writeln!(
+            out,
+            "renamed\t{} -> {}\t{}",
+            item.old_relative_path, item.new_relative_path, item.hash
+        )?;
+    }
+    for warning in &results.warnings {
+        writeln!(out, "warning\t{warning}")?;
+    }
+    writeln!(
+        out,
+        "{} to compute, {} to delete, {} to add the tag to, {} to remove it from, {} renamed",
+        results.compute.len(),
+        results.delete.len(),
+        results.add_tag.len(),
+        results.remove_tag.len(),
+        results.renamed.len()
+    )
+}
+
+fn main() -> ExitCode {
+    let result = Args::parse(env::args().skip(1)).and_then(|args| run(&args, &mut io::stdout()));
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(err @ CliError::Usage(_)) => {
+            eprint!("{err}");
+            ExitCode::from(2)
+        }
+        Err(err) => {
+            eprintln!("continue-sync: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    /// What running the command line prints, and whether it succeeded
+    fn run_args(args: &[&str]) -> (bool, String) {
+        let mut out = Vec::new();
+        let succeeded = run(&parse(args).unwrap(), &mut out).unwrap();
+        (succeeded, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = parse(&["--json", "sync", "src", "--branch=dev", "--provider", "p"]).unwrap();
+        assert_eq!(
+            args,
+            Args {
+                command: "sync".to_string(),
+                positional: vec!["src".to_string()],
+                branch: Some("dev".to_string()),
+                provider: Some("p".to_string()),
+                json: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(args.arg("dir").unwrap(), "src");
+
+        assert!(matches!(
+            parse(&["sync", "--branch"]),
+            Err(CliError::Usage(_))
+        ));
+        assert!(matches!(
+            parse(&["sync", "--bogus"]),
+            Err(CliError::Usage(_))
+        ));
+        assert!(matches!(
+            parse(&["sync", "--json=yes"]),
+            Err(CliError::Usage(_))
+        ));
+        assert!(matches!(
+            parse(&["sync", "--chunk-size", "big"]),
+            Err(CliError::Usage(_))
+        ));
+        let args = parse(&["sync", "src", "--chunk-overlap=0"]).unwrap();
+        assert_eq!((args.chunk_size, args.chunk_overlap), (None, Some(0)));
+        assert!(
+            parse(&["sync", "src", "--chunk-syntax"])
+                .unwrap()
+                .chunk_syntax
+        );
+        let args = parse(&["sync", "a", "b"]).unwrap();
+        assert!(matches!(args.arg("dir"), Err(CliError::Usage(_))));
+        let args = parse(&["status"]).unwrap();
+        assert!(matches!(args.arg("dir"), Err(CliError::Usage(_))));
+    }
+
+    #[test]
+    fn test_current_branch() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let repo = temp_dir.path();
+        assert_eq!(current_branch(repo), None);
+        fs::create_dir_all(repo.join(".git")).unwrap();
+        fs::create_dir_all(repo.join("src")).unwrap();
+        fs::write(repo.join(".git/HEAD"), "ref: refs/heads/feature/x\n").unwrap();
+        assert_eq!(
+            current_branch(&repo.join("src")).as_deref(),
+            Some("feature/x")
+        );
+        fs::write(repo.join(".git/HEAD"), "0123456789abcdef\n").unwrap();
+        assert_eq!(current_branch(repo), None);
+    }
+
+    #[test]
+    fn test_commands() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().join("workspace");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "A").unwrap();
+        let index_dir = tempfile::tempdir().unwrap();
+        let dir = dir.to_str().unwrap();
+        let index_root = index_dir.path().to_str().unwrap();
+        let run_with = |args: &[&str]| {
+            let mut all = vec!["--index-root", index_root, "--branch", "main"];
+            all.extend(args);
+            run_args(&all)
+        };
+
+        let (_, out) = run_with(&["--json", "status", dir]);
+        let status: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(status["upToDate"], false);
+        assert_eq!(status["lastSync"], serde_json::Value::Null);
+
+        let (succeeded, out) = run_with(&["sync", dir]);
+        assert!(succeeded);
+        assert!(out.starts_with("compute\ta.txt\t"));
+        let (_, out) = run_with(&["--json", "sync", dir]);
+        let results: SyncResults = serde_json::from_str(&out).unwrap();
+        assert_eq!(results, SyncResults::default());
+
+        fs::write(Path::new(dir).join("b.txt"), "B").unwrap();
+        let (_, out) = run_with(&["diff", dir]);
+        assert!(out.starts_with("+ b.txt\t"));
+        let (_, out) = run_with(&["--json", "status", dir]);
+        let status: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(status["upToDate"], false);
+        assert_eq!(status["files"], 1);
+
+        let (_, out) = run_with(&["tags"]);
+        assert!(out.contains("::main::default\tlast synced "));
+        let (succeeded, out) = run_with(&["verify", dir]);
+        assert!(succeeded);
+        assert!(out.ends_with("is consistent\n"));
+        let (succeeded, _) = run_with(&["gc", "default"]);
+        assert!(succeeded);
+
+        let (_, out) = run_with(&["--json", "sync", dir, "--store-objects"]);
+        let results: SyncResults = serde_json::from_str(&out).unwrap();
+        let (succeeded, out) = run_with(&["object", &results.compute[0].hash]);
+        assert!(succeeded);
+        assert_eq!(out, "B");
+        let (_, out) = run_with(&["pack", "default"]);
+        assert_eq!(
+            out,
+            "1 objects packed, 1 packs written, 0 merged into them\n"
+        );
+        let (_, out) = run_with(&["object", &results.compute[0].hash]);
+        assert_eq!(out, "B");
+
+        // A provider of its own, since the default one has computed everything already
+        let (succeeded, _) = run_with(&["sync", dir, "--search", "--provider", "search"]);
+        assert!(succeeded);
+        let (_, out) = run_with(&["search", dir, "--query", "b", "--provider", "search"]);
+        assert_eq!(out, "b.txt:1-1\tB\n");
+        let args = parse(&["search", dir]).unwrap();
+        assert!(matches!(
+            run(&args, &mut Vec::new()),
+            Err(CliError::Usage(_))
+        ));
+
+        fs::write(Path::new(dir).join("c.rs"), "pub fn build() {}\n").unwrap();
+        let (succeeded, _) = run_with(&["sync", dir, "--symbols", "--provider", "symbols"]);
+        assert!(succeeded);
+        let (_, out) = run_with(&["symbols", dir, "--query", "bu", "--provider", "symbols"]);
+        assert_eq!(out, "c.rs:1-1\tfunction\tbuild\n");
+    }
+}
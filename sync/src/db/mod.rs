@@ -17,7 +17,7 @@ fn get_top_n(v: Vec<f32>, vectors: Vec<Vec<f32>>, d: usize, top_n: usize) -> Vec
         .take(top_n)
         .collect();
 
-    return top_n_indices;
+    top_n_indices
 }
 
 #[derive(Debug, Clone)]
@@ -32,11 +32,11 @@ pub struct Chunk {
 }
 
 pub fn embedding_to_text(embedding: Vec<f32>) -> String {
-    return embedding
+    embedding
         .iter()
         .map(|f| f.to_string())
         .collect::<Vec<String>>()
-        .join(",");
+        .join(",")
 }
 
 pub fn text_to_embedding(text: String) -> Result<Vec<f32>, &'static str> {
@@ -61,7 +61,7 @@ fn get_conn() -> Connection {
         .join("index")
         .join("sync.db");
     fs::create_dir_all(path.parent().unwrap()).unwrap();
-    return Connection::open(path).unwrap();
+    Connection::open(path).unwrap()
 }
 
 pub fn create_database() {
@@ -141,11 +141,21 @@ pub fn remove_tag(hash: String, tag: String) {
     .unwrap();
 }
 
+pub fn rename_chunks(hash: String, old_path: String, new_path: String) {
+    let conn = get_conn();
+
+    conn.execute(
+        "UPDATE chunks SET file_path=?3 WHERE hash=?1 AND file_path=?2",
+        (&hash, &old_path, &new_path),
+    )
+    .unwrap();
+}
+
 pub fn retrieve(n: usize, tags: Vec<String>, v: Vec<f32>) -> Vec<Chunk> {
     let conn = get_conn();
 
     let mut stmt = conn
-        .prepare(&format!(
+        .prepare(
             "
         SELECT * FROM chunks
         WHERE hash IN (
@@ -153,7 +163,7 @@ pub fn retrieve(n: usize, tags: Vec<String>, v: Vec<f32>) -> Vec<Chunk> {
             FROM tags
             WHERE tag IN (?1)
         )",
-        ))
+        )
         .unwrap();
     let chunk_rows = stmt
         .query_map((tags.join(", "),), |row| {
@@ -179,13 +189,13 @@ pub fn retrieve(n: usize, tags: Vec<String>, v: Vec<f32>) -> Vec<Chunk> {
     }
 
     let top_n_indices = get_top_n(v, vectors, 384, n);
-    return chunks
+    chunks
         .iter()
         .cloned()
         .enumerate()
         .filter(|(index, _chunk)| top_n_indices.contains(index))
         .map(|(_index, chunk)| chunk)
-        .collect::<Vec<Chunk>>();
+        .collect::<Vec<Chunk>>()
 }
 
 #[cfg(test)]
@@ -219,7 +229,7 @@ mod tests {
 
     #[test]
     fn benchmark_load_vectors() {
-        let conn = Connection::open("sync.db").unwrap();
+        let conn = Connection::open_in_memory().unwrap();
 
         conn.execute(
             "CREATE TABLE chunks (
@@ -259,13 +269,13 @@ mod tests {
         let chunk_iter = stmt
             .query_map([], |row| {
                 Ok(Chunk {
-                    hash: row.get(1)?,
-                    content: row.get(2)?,
-                    embedding: text_to_embedding(row.get(3)?).unwrap(),
-                    start_line: row.get(4)?,
-                    end_line: row.get(5)?,
-                    file_path: row.get(6)?,
-                    index: row.get(7)?,
+                    hash: String::new(),
+                    content: row.get(1)?,
+                    embedding: text_to_embedding(row.get(2)?).unwrap(),
+                    start_line: 0,
+                    end_line: 0,
+                    file_path: String::new(),
+                    index: 0,
                 })
             })
             .unwrap();
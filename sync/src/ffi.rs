@@ -0,0 +1,410 @@
+//! A C ABI for embedding the indexer without Rust, built with the `sync-ffi` feature, and declared
+//! for C in `include/continue_sync.h`
+//!
+//! Everything goes through two opaque handles: a `ContinueSync`, which says where the index is,
+//! and a `ContinueSyncSession`, which keeps one tag's tree in memory between syncs like
+//! `SyncSession`. Strings are UTF-8 and null-terminated both ways. Results come back as JSON, in
+//! the shapes `--json` prints, in strings the caller owns and frees with
+//! `continue_sync_string_free`. A call that fails returns null (or -1) and, if `error` isn't
+//! null, puts a message in `*error` to be freed the same way.
+//!
+//! # Safety
+//!
+//! Every pointer passed in must be null or valid for the call: strings null-terminated, handles
+//! from the matching `_new` or `_open` function and not yet freed. A handle can be moved between
+//! threads but mustn't be used from two at once.
+
+use crate::sync::{self, IndexRoot, ObjectHash, OwnedTag, SyncOptions, SyncSession};
+use serde::Serialize;
+use std::{
+    ffi::{c_char, c_int, CStr, CString},
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    ptr,
+};
+
+/// Where the index is, for every call made with it
+pub struct ContinueSync {
+    options: SyncOptions,
+}
+
+/// One tag, with its tree kept in memory between syncs
+pub struct ContinueSyncSession {
+    session: SyncSession,
+}
+
+/// `text` as a C string the caller frees with `continue_sync_string_free`
+fn to_c_string(text: String) -> *mut c_char {
+    // Neither JSON nor error messages have NULs in them, but a message could quote a path
+    let text = text.replace('\0', " ");
+    CString::new(text).expect("NULs were replaced").into_raw()
+}
+
+/// Put `message` in `*error`, if the caller wants it
+unsafe fn set_error(error: *mut *mut c_char, message: String) {
+    if !error.is_null() {
+        *error = to_c_string(message);
+    }
+}
+
+/// The string argument `name`, which has to be given
+unsafe fn arg<'a>(text: *const c_char, name: &str) -> Result<&'a str, String> {
+    optional_arg(text, name)?.ok_or_else(|| format!("{name} is null"))
+}
+
+/// The string argument `name`, or None if it's null
+unsafe fn optional_arg<'a>(text: *const c_char, name: &str) -> Result<Option<&'a str>, String> {
+    match text.is_null() {
+        true => Ok(None),
+        false => CStr::from_ptr(text)
+            .to_str()
+            .map(Some)
+            .map_err(|_| format!("{name} isn't UTF-8")),
+    }
+}
+
+/// The handle argument, which has to be given
+unsafe fn handle<'a, T>(handle: *mut T) -> Result<&'a mut T, String> {
+    handle.as_mut().ok_or_else(|| "handle is null".to_string())
+}
+
+/// Run `work`, catching any panic, since one can't unwind into C. Returns None with the error
+/// in `*error` if it fails
+unsafe fn call<T>(error: *mut *mut c_char, work: impl FnOnce() -> Result<T, String>) -> Option<T> {
+    let result = panic::catch_unwind(AssertUnwindSafe(work))
+        .unwrap_or_else(|_| Err("the indexer panicked".to_string()));
+    match result {
+        Ok(value) => Some(value),
+        Err(message) => {
+            set_error(error, message);
+            None
+        }
+    }
+}
+
+/// Run `work`, returning what it returns as JSON, or null with the error in `*error`
+unsafe fn call_json<T: Serialize>(
+    error: *mut *mut c_char,
+    work: impl FnOnce() -> Result<T, String>,
+) -> *mut c_char {
+    let json = call(error, || {
+        let value = work()?;
+        serde_json::to_string(&value).map_err(|err| err.to_string())
+    });
+    json.map_or(ptr::null_mut(), to_c_string)
+}
+
+unsafe fn tag_args(
+    dir: *const c_char,
+    branch: *const c_char,
+    provider_id: *const c_char,
+) -> Result<OwnedTag, String> {
+    Ok(OwnedTag::new(
+        arg(dir, "dir")?,
+        arg(branch, "branch")?,
+        arg(provider_id, "provider_id")?,
+    ))
+}
+
+/// A handle on the index in `index_root`, or in the default place if it's null. Free it with
+/// `continue_sync_free`
+#[no_mangle]
+pub unsafe extern "C" fn continue_sync_new(
+    index_root: *const c_char,
+    error: *mut *mut c_char,
+) -> *mut ContinueSync {
+    let handle = call(error, || {
+        let options = match optional_arg(index_root, "index_root")? {
+            Some(index_root) => SyncOptions::new().index_root(IndexRoot::new(index_root)),
+            None => SyncOptions::new(),
+        };
+        Ok(ContinueSync { options })
+    });
+    handle.map_or(ptr::null_mut(), |handle| Box::into_raw(Box::new(handle)))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn continue_sync_free(handle: *mut ContinueSync) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// Free a string returned by any of these functions
+#[no_mangle]
+pub unsafe extern "C" fn continue_sync_string_free(text: *mut c_char) {
+    if !text.is_null() {
+        drop(CString::from_raw(text));
+    }
+}
+
+/// Sync the tag, returning its `SyncResults` as JSON
+#[no_mangle]
+pub unsafe extern "C" fn continue_sync_sync(
+    handle: *mut ContinueSync,
+    dir: *const c_char,
+    branch: *const c_char,
+    provider_id: *const c_char,
+    error: *mut *mut c_char,
+) -> *mut c_char {
+    call_json(error, || {
+        let options = &self::handle(handle)?.options;
+        let tag = tag_args(dir, branch, provider_id)?;
+        sync::sync(&tag.as_tag(), options).map_err(|err| err.to_string())
+    })
+}
+
+/// Remove the tag from the index, returning the `SyncResults` of what that leaves to do as JSON
+#[no_mangle]
+pub unsafe extern "C" fn continue_sync_delete_tag(
+    handle: *mut ContinueSync,
+    dir: *const c_char,
+    branch: *const c_char,
+    provider_id: *const c_char,
+    error: *mut *mut c_char,
+) -> *mut c_char {
+    call_json(error, || {
+        let options = &self::handle(handle)?.options;
+        let tag = tag_args(dir, branch, provider_id)?;
+        sync::delete_tag(&tag.as_tag(), options).map_err(|err| err.to_string())
+    })
+}
+
+/// The tags in the index as a JSON array of `TagEntry`s, for `dir` and `provider_id` unless
+/// they're null
+#[no_mangle]
+pub unsafe extern "C" fn continue_sync_list_tags(
+    handle: *mut ContinueSync,
+    dir: *const c_char,
+    provider_id: *const c_char,
+    error: *mut *mut c_char,
+) -> *mut c_char {
+    call_json(error, || {
+        let options = &self::handle(handle)?.options;
+        let dir = optional_arg(dir, "dir")?.map(Path::new);
+        let provider_id = optional_arg(provider_id, "provider_id")?;
+        sync::list_tags(options, dir, provider_id).map_err(|err| err.to_string())
+    })
+}
+
+/// The files in the tag's saved tree, as a JSON array
+#[no_mangle]
+pub unsafe extern "C" fn continue_sync_list_files(
+    handle: *mut ContinueSync,
+    dir: *const c_char,
+    branch: *const c_char,
+    provider_id: *const c_char,
+    error: *mut *mut c_char,
+) -> *mut c_char {
+    call_json(error, || {
+        let options = &self::handle(handle)?.options;
+        let tag = tag_args(dir, branch, provider_id)?;
+        sync::list_indexed_files(&tag.as_tag(), options).map_err(|err| err.to_string())
+    })
+}
+
+/// The root hash of the tag's saved tree as a JSON string, or `null` if it hasn't been synced
+#[no_mangle]
+pub unsafe extern "C" fn continue_sync_root_hash(
+    handle: *mut ContinueSync,
+    dir: *const c_char,
+    branch: *const c_char,
+    provider_id: *const c_char,
+    error: *mut *mut c_char,
+) -> *mut c_char {
+    call_json(error, || {
+        let options = &self::handle(handle)?.options;
+        let tag = tag_args(dir, branch, provider_id)?;
+        let hash = sync::get_root_hash(&tag.as_tag(), options).map_err(|err| err.to_string())?;
+        Ok(hash.map(sync::hash_string))
+    })
+}
+
+/// Mark the `count` hex hashes in `hashes` as computed for the tag (see `mark_computed`).
+/// Returns 0, or -1 if it fails
+#[no_mangle]
+pub unsafe extern "C" fn continue_sync_mark_computed(
+    handle: *mut ContinueSync,
+    dir: *const c_char,
+    branch: *const c_char,
+    provider_id: *const c_char,
+    hashes: *const *const c_char,
+    count: usize,
+    error: *mut *mut c_char,
+) -> c_int {
+    let marked = call(error, || {
+        let options = &self::handle(handle)?.options;
+        let tag = tag_args(dir, branch, provider_id)?;
+        let hashes = match count {
+            0 => &[],
+            _ if hashes.is_null() => return Err("hashes is null".to_string()),
+            _ => std::slice::from_raw_parts(hashes, count),
+        };
+        let hashes = hashes
+            .iter()
+            .map(|&hash| {
+                let hash = arg(hash, "hash")?;
+                sync::parse_hash(hash).ok_or_else(|| format!("{hash} isn't a hash"))
+            })
+            .collect::<Result<Vec<ObjectHash>, String>>()?;
+        sync::mark_computed(&tag.as_tag(), &hashes, options).map_err(|err| err.to_string())
+    });
+    match marked {
+        Some(()) => 0,
+        None => -1,
+    }
+}
+
+/// Open a session on the tag, loading its saved tree. Free it with `continue_sync_session_free`
+#[no_mangle]
+pub unsafe extern "C" fn continue_sync_session_open(
+    handle: *mut ContinueSync,
+    dir: *const c_char,
+    branch: *const c_char,
+    provider_id: *const c_char,
+    error: *mut *mut c_char,
+) -> *mut ContinueSyncSession {
+    let session = call(error, || {
+        let options = self::handle(handle)?.options.clone();
+        let tag = tag_args(dir, branch, provider_id)?;
+        let session = SyncSession::open(&tag.as_tag(), options).map_err(|err| err.to_string())?;
+        Ok(ContinueSyncSession { session })
+    });
+    session.map_or(ptr::null_mut(), |session| Box::into_raw(Box::new(session)))
+}
+
+/// Sync the session's tag against the tree it has in memory, returning the `SyncResults` as JSON
+#[no_mangle]
+pub unsafe extern "C" fn continue_sync_session_sync(
+    session: *mut ContinueSyncSession,
+    error: *mut *mut c_char,
+) -> *mut c_char {
+    call_json(error, || {
+        let session = &mut handle(session)?.session;
+        session.sync().map_err(|err| err.to_string())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn continue_sync_session_free(session: *mut ContinueSyncSession) {
+    if !session.is_null() {
+        drop(Box::from_raw(session));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{temp_index_root, TempDirBuilder};
+    use serde_json::Value;
+
+    fn c(text: &str) -> CString {
+        CString::new(text).unwrap()
+    }
+
+    /// The JSON in `text`, freeing it
+    unsafe fn take_json(text: *mut c_char) -> Value {
+        assert!(!text.is_null());
+        let json = serde_json::from_slice(CStr::from_ptr(text).to_bytes()).unwrap();
+        continue_sync_string_free(text);
+        json
+    }
+
+    /// The message in `error`, freeing it
+    unsafe fn take_error(error: *mut c_char) -> String {
+        assert!(!error.is_null());
+        let message = CStr::from_ptr(error).to_str().unwrap().to_string();
+        continue_sync_string_free(error);
+        message
+    }
+
+    #[test]
+    fn test_ffi() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("b.txt", "B")
+            .create();
+        let (index_dir, _index_root) = temp_index_root();
+        let dir = c(temp_dir.path().to_str().unwrap());
+        let (branch, provider_id) = (c("main"), c("default"));
+        let root = c(index_dir.path().to_str().unwrap());
+        let mut error = ptr::null_mut();
+        unsafe {
+            let handle = continue_sync_new(root.as_ptr(), &mut error);
+            assert!(!handle.is_null());
+            let tag = (dir.as_ptr(), branch.as_ptr(), provider_id.as_ptr());
+
+            let results = take_json(continue_sync_sync(handle, tag.0, tag.1, tag.2, &mut error));
+            assert_eq!(results["compute"].as_array().unwrap().len(), 2);
+            let tags = take_json(continue_sync_list_tags(
+                handle,
+                ptr::null(),
+                ptr::null(),
+                &mut error,
+            ));
+            assert_eq!(tags.as_array().unwrap().len(), 1);
+            let files = take_json(continue_sync_list_files(
+                handle, tag.0, tag.1, tag.2, &mut error,
+            ));
+            assert_eq!(files.as_array().unwrap().len(), 2);
+            let root_hash = take_json(continue_sync_root_hash(
+                handle, tag.0, tag.1, tag.2, &mut error,
+            ));
+            assert_eq!(root_hash.as_str().unwrap().len(), 40);
+
+            let hash = c(results["compute"][0]["hash"].as_str().unwrap());
+            let hashes = [hash.as_ptr()];
+            let marked = continue_sync_mark_computed(
+                handle,
+                tag.0,
+                tag.1,
+                tag.2,
+                hashes.as_ptr(),
+                1,
+                &mut error,
+            );
+            assert_eq!(marked, 0);
+            let bad = c("zz");
+            let marked = continue_sync_mark_computed(
+                handle,
+                tag.0,
+                tag.1,
+                tag.2,
+                [bad.as_ptr()].as_ptr(),
+                1,
+                &mut error,
+            );
+            assert_eq!(marked, -1);
+            assert_eq!(take_error(error), "zz isn't a hash");
+
+            let session = continue_sync_session_open(handle, tag.0, tag.1, tag.2, &mut error);
+            assert!(!session.is_null());
+            std::fs::write(temp_dir.path().join("c.txt"), "C").unwrap();
+            let results = take_json(continue_sync_session_sync(session, &mut error));
+            assert_eq!(results["compute"][0]["relative_path"], "c.txt");
+            continue_sync_session_free(session);
+
+            let results = take_json(continue_sync_delete_tag(
+                handle, tag.0, tag.1, tag.2, &mut error,
+            ));
+            assert_eq!(results["delete"].as_array().unwrap().len(), 3);
+            let root_hash = take_json(continue_sync_root_hash(
+                handle, tag.0, tag.1, tag.2, &mut error,
+            ));
+            assert_eq!(root_hash, Value::Null);
+
+            let results = continue_sync_sync(handle, tag.0, ptr::null(), tag.2, &mut error);
+            assert!(results.is_null());
+            assert_eq!(take_error(error), "branch is null");
+            // Errors can be ignored
+            assert!(
+                continue_sync_sync(handle, ptr::null(), tag.1, tag.2, ptr::null_mut()).is_null()
+            );
+            continue_sync_free(handle);
+
+            let results = continue_sync_sync(ptr::null_mut(), tag.0, tag.1, tag.2, &mut error);
+            assert!(results.is_null());
+            assert_eq!(take_error(error), "handle is null");
+        }
+    }
+}
@@ -12,7 +12,10 @@ pub fn local_find_gitignores(workspace_dir: &Path) -> io::Result<HashMap<PathBuf
             gitignores.extend(local_find_gitignores(&path)?);
         } else {
             match path.file_name().and_then(|name| name.to_str()) {
-                Some(file_name) if file_name.ends_with(".gitignore") || file_name.ends_with(".continueignore") => {
+                Some(file_name)
+                    if file_name.ends_with(".gitignore")
+                        || file_name.ends_with(".continueignore") =>
+                {
                     let mut contents = String::new();
                     fs::File::open(&path)?.read_to_string(&mut contents)?;
                     gitignores.insert(path, contents);
@@ -24,18 +27,6 @@ pub fn local_find_gitignores(workspace_dir: &Path) -> io::Result<HashMap<PathBuf
     Ok(gitignores)
 }
 
-fn main() -> io::Result<()> {
-    let workspace_dir = Path::new("path/to/workspace");
-    let gitignore_map = local_find_gitignores(workspace_dir)?;
-    
-    // Print out the result (optional)
-    for (path, contents) in gitignore_map {
-        println!("{}:\n{}", path.display(), contents);
-    }
-    
-    Ok(())
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -90,12 +81,12 @@ mod tests {
         // Get the current directory
         let current_dir = std::env::current_dir()?;
         let parent_dir = current_dir.parent().unwrap();
-        let gitignores = local_find_gitignores(&parent_dir)?;
+        let gitignores = local_find_gitignores(parent_dir)?;
 
         // Verify that the returned HashMap contains the correct paths and contents
         let top_level_gitignore = parent_dir.join(".gitignore");
         assert!(gitignores.contains_key(&top_level_gitignore));
-        assert!(gitignores[&top_level_gitignore].contains(".tiktoken_cache"));
+        assert!(gitignores[&top_level_gitignore].contains("target/"));
 
         Ok(())
     }
@@ -0,0 +1,570 @@
+//! The Node module (`index.node`), built with neon. Not part of wasm32 builds, where the host
+//! calls the crate some other way
+
+use crate::{db, sync, sync_db};
+use neon::prelude::*;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/// Files per batch in `sync`, between calls to `onProgress`
+const BATCH_SIZE: usize = 1000;
+
+fn build_js_array<'a>(
+    rs_array: Vec<sync::SyncResultItem>,
+    cx: &mut FunctionContext<'a>,
+) -> Handle<'a, JsArray> {
+    let js_array = JsArray::new(cx, rs_array.len() as u32);
+    for (i, item) in rs_array.iter().enumerate() {
+        let js_object = JsObject::new(cx);
+
+        let name = JsString::new(cx, &item.path);
+        let _ = js_object.set(cx, "name", name);
+        let relative_path = JsString::new(cx, &item.relative_path);
+        let _ = js_object.set(cx, "relativePath", relative_path);
+        let hash = JsString::new(cx, &item.hash);
+        let _ = js_object.set(cx, "hash", hash);
+
+        let _ = js_array.set(cx, i as u32, js_object);
+    }
+
+    js_array
+}
+
+fn sync_results(mut cx: FunctionContext) -> JsResult<JsArray> {
+    let dir = cx.argument::<JsString>(0)?.value(&mut cx);
+    let branch = cx.argument::<JsString>(1)?.value(&mut cx);
+    let provider_id = cx.argument::<JsString>(2)?.value(&mut cx);
+
+    let tag = sync::Tag {
+        dir: Path::new(&dir),
+        branch: &branch,
+        provider_id: &provider_id.to_string(),
+    };
+
+    let compute = match sync_db::sync_db(&tag) {
+        Ok(compute) => compute,
+        Err(err) => return cx.throw_error(err.to_string()),
+    };
+    let compute_js_array = build_js_array(compute, &mut cx);
+
+    Ok(compute_js_array)
+}
+
+fn sync_results_in_batches(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let dir = cx.argument::<JsString>(0)?.value(&mut cx);
+    let branch = cx.argument::<JsString>(1)?.value(&mut cx);
+    let provider_id = cx.argument::<JsString>(2)?.value(&mut cx);
+    let batch_size = cx.argument::<JsNumber>(3)?.value(&mut cx) as usize;
+    let callback = cx.argument::<JsFunction>(4)?;
+
+    let tag = sync::Tag {
+        dir: Path::new(&dir),
+        branch: &branch,
+        provider_id: &provider_id.to_string(),
+    };
+
+    // Set if the callback throws, so the sync stops and the exception is passed on
+    let mut thrown = None;
+    let result = sync_db::sync_db_in_batches(&tag, batch_size, |compute| {
+        let compute_js_array = build_js_array(compute, &mut cx);
+        let this = cx.undefined();
+        match callback.call(&mut cx, this, [compute_js_array.upcast::<JsValue>()]) {
+            Ok(_) => Ok(()),
+            Err(throw) => {
+                thrown = Some(throw);
+                Err(std::io::Error::other("callback threw").into())
+            }
+        }
+    });
+    if let Some(throw) = thrown {
+        return Err(throw);
+    }
+    if let Err(err) = result {
+        return cx.throw_error(err.to_string());
+    }
+
+    Ok(cx.undefined())
+}
+
+/// The `VerifyReport` for the tag, as JSON: `{ inconsistencies: [{ kind, ... }] }`
+fn verify_index(mut cx: FunctionContext) -> JsResult<JsString> {
+    let dir = cx.argument::<JsString>(0)?.value(&mut cx);
+    let branch = cx.argument::<JsString>(1)?.value(&mut cx);
+    let provider_id = cx.argument::<JsString>(2)?.value(&mut cx);
+
+    let tag = sync::Tag {
+        dir: Path::new(&dir),
+        branch: &branch,
+        provider_id: &provider_id,
+    };
+    let report = sync::verify_index(&tag, &sync::SyncOptions::default())
+        .and_then(|report| Ok(serde_json::to_string(&report)?));
+    match report {
+        Ok(report) => Ok(cx.string(report)),
+        Err(err) => cx.throw_error(err.to_string()),
+    }
+}
+
+/// What `to_js` makes JS values with: a neon context, or in the tests, JSON that can be
+/// checked without Node
+trait JsBuilder<'a> {
+    type Value;
+
+    fn js_null(&mut self) -> Self::Value;
+    fn js_bool(&mut self, value: bool) -> Self::Value;
+    fn js_number(&mut self, value: f64) -> Self::Value;
+    fn js_string(&mut self, value: &str) -> Self::Value;
+    fn js_array(&mut self, items: Vec<Self::Value>) -> NeonResult<Self::Value>;
+    fn js_object(&mut self, fields: Vec<(&str, Self::Value)>) -> NeonResult<Self::Value>;
+}
+
+impl<'a, C: Context<'a>> JsBuilder<'a> for C {
+    type Value = Handle<'a, JsValue>;
+
+    fn js_null(&mut self) -> Self::Value {
+        self.null().upcast()
+    }
+
+    fn js_bool(&mut self, value: bool) -> Self::Value {
+        self.boolean(value).upcast()
+    }
+
+    fn js_number(&mut self, value: f64) -> Self::Value {
+        self.number(value).upcast()
+    }
+
+    fn js_string(&mut self, value: &str) -> Self::Value {
+        self.string(value).upcast()
+    }
+
+    fn js_array(&mut self, items: Vec<Self::Value>) -> NeonResult<Self::Value> {
+        let array = JsArray::new(self, items.len() as u32);
+        for (i, item) in items.into_iter().enumerate() {
+            array.set(self, i as u32, item)?;
+        }
+        Ok(array.upcast())
+    }
+
+    fn js_object(&mut self, fields: Vec<(&str, Self::Value)>) -> NeonResult<Self::Value> {
+        let object = self.empty_object();
+        for (name, field) in fields {
+            object.set(self, name, field)?;
+        }
+        Ok(object.upcast())
+    }
+}
+
+/// `value` as the plain JS object, array or primitive it stands for. Every number is a JS
+/// number, so integers past 2^53 lose precision
+fn to_js<'a, B: JsBuilder<'a>>(cx: &mut B, value: &Value) -> NeonResult<B::Value> {
+    Ok(match value {
+        Value::Null => cx.js_null(),
+        Value::Bool(value) => cx.js_bool(*value),
+        Value::Number(value) => cx.js_number(value.as_f64().unwrap_or(f64::NAN)),
+        Value::String(value) => cx.js_string(value),
+        Value::Array(items) => {
+            let items = items
+                .iter()
+                .map(|item| to_js(cx, item))
+                .collect::<NeonResult<_>>()?;
+            cx.js_array(items)?
+        }
+        Value::Object(fields) => {
+            let fields = fields
+                .iter()
+                .map(|(name, field)| Ok((name.as_str(), to_js(cx, field)?)))
+                .collect::<NeonResult<_>>()?;
+            cx.js_object(fields)?
+        }
+    })
+}
+
+/// What `onProgress` is called with after each batch of a `sync`
+fn progress_value(results: &sync::SyncResults, batches: usize, files: usize) -> Value {
+    json!({ "results": results, "batches": batches, "files": files })
+}
+
+/// The tag in the first three arguments: the directory, branch and provider id
+fn tag_arguments(cx: &mut FunctionContext) -> NeonResult<sync::OwnedTag> {
+    let dir = cx.argument::<JsString>(0)?.value(cx);
+    let branch = cx.argument::<JsString>(1)?.value(cx);
+    let provider_id = cx.argument::<JsString>(2)?.value(cx);
+    Ok(sync::OwnedTag::new(dir, branch, provider_id))
+}
+
+/// Run `work` off the main thread, and settle the promise returned with what it returns, as
+/// plain JS objects
+fn promise_of<'a, T: Serialize>(
+    cx: &mut FunctionContext<'a>,
+    work: impl FnOnce() -> sync::Result<T> + Send + 'static,
+) -> JsResult<'a, JsPromise> {
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    thread::spawn(move || {
+        let result = work().and_then(|value| Ok(serde_json::to_value(value)?));
+        deferred.settle_with(&channel, move |mut cx| match result {
+            Ok(value) => to_js(&mut cx, &value),
+            Err(err) => cx.throw_error(err.to_string()),
+        });
+    });
+    Ok(promise)
+}
+
+/// `sync(dir, branch, providerId, onProgress?)`: a promise of the tag's `SyncResults`, synced off
+/// the main thread. The sync goes in batches, and `onProgress`, if it's given, is called with
+/// `{ results, batches, files }` after each one, before the next is started. If it throws, the
+/// sync stops and the promise is rejected with what it threw, though the batches it was called
+/// with are saved as synced all the same
+fn sync_async(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let tag = tag_arguments(&mut cx)?;
+    let on_progress = match cx.argument_opt(3) {
+        Some(arg) if !arg.is_a::<JsUndefined, _>(&mut cx) => Some(Arc::new(
+            arg.downcast_or_throw::<JsFunction, _>(&mut cx)?
+                .root(&mut cx),
+        )),
+        _ => None,
+    };
+    // What `onProgress` threw, in an array of its own since only objects can be rooted
+    let thrown: Arc<Mutex<Option<Root<JsArray>>>> = Arc::default();
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+
+    thread::spawn(move || {
+        let options = sync::SyncOptions::default();
+        let mut merged = sync::SyncResults::default();
+        let (mut batches, mut files) = (0, 0);
+        let result = sync::sync_in_batches(&tag.as_tag(), &options, BATCH_SIZE, |results| {
+            batches += 1;
+            files += results.action_count();
+            if let Some(on_progress) = &on_progress {
+                let progress = progress_value(&results, batches, files);
+                let (on_progress, thrown) = (on_progress.clone(), thrown.clone());
+                let called = channel.send(move |mut cx| {
+                    let progress = to_js(&mut cx, &progress)?;
+                    let callback = on_progress.to_inner(&mut cx);
+                    let this = cx.undefined();
+                    match cx.try_catch(|cx| callback.call(cx, this, [progress])) {
+                        Ok(_) => Ok(true),
+                        Err(exception) => {
+                            let holder = cx.empty_array();
+                            holder.set(&mut cx, 0, exception)?;
+                            *thrown.lock().unwrap() = Some(holder.root(&mut cx));
+                            Ok(false)
+                        }
+                    }
+                });
+                // Waiting for the callback keeps the sync from getting ahead of it
+                if !matches!(called.join(), Ok(true)) {
+                    return Err(sync::SyncError::Cancelled);
+                }
+            }
+            merged.append(results);
+            Ok(())
+        });
+
+        deferred.settle_with(&channel, move |mut cx| {
+            if let Some(on_progress) = on_progress.and_then(|root| Arc::try_unwrap(root).ok()) {
+                on_progress.drop(&mut cx);
+            }
+            if let Some(holder) = thrown.lock().unwrap().take() {
+                let exception: Handle<JsValue> = holder.into_inner(&mut cx).get(&mut cx, 0)?;
+                return cx.throw(exception);
+            }
+            match result.and_then(|()| Ok(serde_json::to_value(&merged)?)) {
+                Ok(value) => to_js(&mut cx, &value),
+                Err(err) => cx.throw_error(err.to_string()),
+            }
+        });
+    });
+    Ok(promise)
+}
+
+/// An argument that may be left out, as far as `optional_string` cares
+enum OptionalArg {
+    /// Not passed, or undefined or null
+    Missing,
+    String(String),
+    Other,
+}
+
+impl OptionalArg {
+    fn get(cx: &mut FunctionContext, i: i32) -> Self {
+        match cx.argument_opt(i) {
+            Some(arg) => match arg.downcast::<JsString, _>(cx) {
+                Ok(arg) => OptionalArg::String(arg.value(cx)),
+                Err(_) if arg.is_a::<JsUndefined, _>(cx) || arg.is_a::<JsNull, _>(cx) => {
+                    OptionalArg::Missing
+                }
+                Err(_) => OptionalArg::Other,
+            },
+            None => OptionalArg::Missing,
+        }
+    }
+}
+
+/// Argument `i` as a string if it's given, or the message for the `TypeError` if it isn't one
+fn optional_string(i: i32, arg: OptionalArg) -> Result<Option<String>, String> {
+    match arg {
+        OptionalArg::Missing => Ok(None),
+        OptionalArg::String(arg) => Ok(Some(arg)),
+        OptionalArg::Other => Err(format!("argument {i} must be a string")),
+    }
+}
+
+/// `listTags(dir?, providerId?)`: a promise of the `TagEntry`s in the index, for `dir` and the
+/// provider if they're given
+fn list_tags(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let mut optional_string = |i| match optional_string(i, OptionalArg::get(&mut cx, i)) {
+        Ok(arg) => Ok(arg),
+        Err(message) => cx.throw_type_error(message),
+    };
+    let dir = optional_string(0)?;
+    let provider_id = optional_string(1)?;
+    promise_of(&mut cx, move || {
+        sync::list_tags(
+            &sync::SyncOptions::default(),
+            dir.as_deref().map(Path::new),
+            provider_id.as_deref(),
+        )
+    })
+}
+
+/// `deleteTag(dir, branch, providerId)`: a promise of the `SyncResults` for removing the tag from
+/// the index, deleted off the main thread
+fn delete_tag(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let tag = tag_arguments(&mut cx)?;
+    promise_of(&mut cx, move || {
+        sync::delete_tag(&tag.as_tag(), &sync::SyncOptions::default())
+    })
+}
+
+fn db_add_chunk(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let chunk_obj = cx.argument::<JsObject>(0)?;
+
+    let hash = chunk_obj
+        .get::<JsString, _, _>(&mut cx, "digest")?
+        .value(&mut cx);
+
+    let content = chunk_obj
+        .get::<JsString, _, _>(&mut cx, "content")?
+        .value(&mut cx);
+
+    let start_line = chunk_obj
+        .get::<JsNumber, _, _>(&mut cx, "startLine")?
+        .value(&mut cx) as usize;
+
+    let end_line = chunk_obj
+        .get::<JsNumber, _, _>(&mut cx, "endLine")?
+        .value(&mut cx) as usize;
+
+    let file_path = chunk_obj
+        .get::<JsString, _, _>(&mut cx, "filepath")?
+        .value(&mut cx);
+
+    let index = chunk_obj
+        .get::<JsNumber, _, _>(&mut cx, "index")?
+        .value(&mut cx) as usize;
+
+    let tags_vec = cx.argument::<JsArray>(1)?.to_vec(&mut cx).unwrap();
+    let mut tags: Vec<String> = Vec::new();
+    for item in tags_vec {
+        let tag = item
+            .downcast::<JsString, _>(&mut cx)
+            .unwrap()
+            .value(&mut cx);
+        tags.push(tag);
+    }
+
+    let embedding_vec = cx.argument::<JsArray>(2)?.to_vec(&mut cx).unwrap();
+    let mut embedding: Vec<f32> = Vec::new();
+    for item in embedding_vec {
+        let float = item
+            .downcast::<JsNumber, _>(&mut cx)
+            .unwrap()
+            .value(&mut cx) as f32;
+        embedding.push(float);
+    }
+
+    let chunk = db::Chunk {
+        hash,
+        content,
+        start_line,
+        end_line,
+        file_path,
+        index,
+        embedding,
+    };
+
+    db::add_chunk(chunk, tags);
+
+    Ok(JsUndefined::new(&mut cx))
+}
+
+fn db_retrieve(mut cx: FunctionContext) -> JsResult<JsArray> {
+    let n = cx.argument::<JsNumber>(0)?.value(&mut cx) as usize;
+
+    let tags_vec = cx.argument::<JsArray>(1)?.to_vec(&mut cx).unwrap();
+    let mut tags: Vec<String> = Vec::new();
+    for item in tags_vec {
+        let tag = item
+            .downcast::<JsString, _>(&mut cx)
+            .unwrap()
+            .value(&mut cx);
+        tags.push(tag);
+    }
+
+    let v_vec = cx.argument::<JsArray>(2)?.to_vec(&mut cx).unwrap();
+    let mut v: Vec<f32> = Vec::new();
+    for item in v_vec {
+        let float = item
+            .downcast::<JsNumber, _>(&mut cx)
+            .unwrap()
+            .value(&mut cx) as f32;
+        v.push(float);
+    }
+
+    let results = db::retrieve(n, tags, v);
+
+    let js_array = JsArray::new(&mut cx, results.len() as u32);
+    for (i, chunk) in results.iter().enumerate() {
+        let js_object = JsObject::new(&mut cx);
+
+        let hash = JsString::new(&mut cx, &chunk.hash);
+        let _ = js_object.set(&mut cx, "hash", hash);
+
+        let content = JsString::new(&mut cx, &chunk.content);
+        let _ = js_object.set(&mut cx, "content", content);
+
+        let start_line = JsNumber::new(&mut cx, chunk.start_line as f64);
+        let _ = js_object.set(&mut cx, "startLine", start_line);
+
+        let end_line = JsNumber::new(&mut cx, chunk.end_line as f64);
+        let _ = js_object.set(&mut cx, "endLine", end_line);
+
+        let file_path = JsString::new(&mut cx, &chunk.file_path);
+        let _ = js_object.set(&mut cx, "filepath", file_path);
+
+        let index = JsNumber::new(&mut cx, chunk.index as f64);
+        let _ = js_object.set(&mut cx, "index", index);
+
+        let _ = js_array.set(&mut cx, i as u32, js_object);
+    }
+
+    Ok(js_array)
+}
+
+#[neon::main]
+fn main(mut cx: ModuleContext) -> NeonResult<()> {
+    cx.export_function("sync_results", sync_results)?;
+    cx.export_function("sync_results_in_batches", sync_results_in_batches)?;
+    cx.export_function("verify_index", verify_index)?;
+    cx.export_function("sync", sync_async)?;
+    cx.export_function("listTags", list_tags)?;
+    cx.export_function("deleteTag", delete_tag)?;
+    let _ = cx.export_function("add_chunk", db_add_chunk);
+    let _ = cx.export_function("retrieve", db_retrieve);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds the JSON a JS value would stringify to, with every number a float as in JS
+    struct Json;
+
+    impl JsBuilder<'static> for Json {
+        type Value = Value;
+
+        fn js_null(&mut self) -> Value {
+            Value::Null
+        }
+
+        fn js_bool(&mut self, value: bool) -> Value {
+            Value::Bool(value)
+        }
+
+        fn js_number(&mut self, value: f64) -> Value {
+            serde_json::Number::from_f64(value).map_or(Value::Null, Value::Number)
+        }
+
+        fn js_string(&mut self, value: &str) -> Value {
+            Value::String(value.to_string())
+        }
+
+        fn js_array(&mut self, items: Vec<Value>) -> NeonResult<Value> {
+            Ok(Value::Array(items))
+        }
+
+        fn js_object(&mut self, fields: Vec<(&str, Value)>) -> NeonResult<Value> {
+            Ok(Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(name, field)| (name.to_string(), field))
+                    .collect(),
+            ))
+        }
+    }
+
+    #[test]
+    fn test_to_js() {
+        let value = json!({
+            "a": [null, true, "b", { "c": [] }],
+            "d": 1,
+            "e": -2.5,
+            "f": u64::MAX,
+        });
+        let js = to_js(&mut Json, &value).unwrap();
+        assert_eq!(
+            js,
+            json!({
+                "a": [null, true, "b", { "c": [] }],
+                "d": 1.0,
+                "e": -2.5,
+                "f": u64::MAX as f64,
+            })
+        );
+    }
+
+    #[test]
+    fn test_optional_string() {
+        assert_eq!(optional_string(0, OptionalArg::Missing), Ok(None));
+        assert_eq!(
+            optional_string(0, OptionalArg::String("src".to_string())),
+            Ok(Some("src".to_string()))
+        );
+        assert_eq!(
+            optional_string(1, OptionalArg::Other),
+            Err("argument 1 must be a string".to_string())
+        );
+    }
+
+    #[test]
+    fn test_progress_value() {
+        let item = |path: &str| sync::SyncResultItem {
+            path: format!("/repo/{path}"),
+            relative_path: path.to_string(),
+            hash: "ab".repeat(20),
+            chunks: Vec::new(),
+        };
+        let results = sync::SyncResults {
+            compute: vec![item("a.txt")],
+            remove_tag: vec![item("b.txt")],
+            ..Default::default()
+        };
+        let progress = to_js(&mut Json, &progress_value(&results, 2, 1500)).unwrap();
+        assert_eq!(progress["batches"], json!(2.0));
+        assert_eq!(progress["files"], json!(1500.0));
+        // The same shape as `--json` prints: camelCase lists of snake_case items
+        let results = &progress["results"];
+        assert_eq!(results["compute"][0]["relative_path"], "a.txt");
+        assert_eq!(results["compute"][0]["path"], "/repo/a.txt");
+        assert_eq!(results["removeTag"][0]["hash"], "ab".repeat(20));
+        assert_eq!(results["delete"], json!([]));
+    }
+}
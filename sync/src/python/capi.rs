@@ -0,0 +1,285 @@
+//! The C API glue for the `continue_sync` module: its functions, method table and init
+//! function
+
+use super::{error_message, hash_arg, to_json, to_str};
+use crate::sync::{self, OwnedTag, SyncOptions};
+use serde::Serialize;
+use std::{
+    ffi::{c_char, c_int, c_void},
+    path::Path,
+    ptr,
+};
+
+/// The head of every Python object, which is all that's needed of one here
+#[repr(C)]
+pub struct PyObject {
+    ob_refcnt: isize,
+    ob_type: *mut c_void,
+}
+
+type PyCFunction = unsafe extern "C" fn(*mut PyObject, *mut PyObject) -> *mut PyObject;
+
+#[repr(C)]
+struct PyMethodDef {
+    ml_name: *const c_char,
+    ml_meth: Option<PyCFunction>,
+    ml_flags: c_int,
+    ml_doc: *const c_char,
+}
+
+#[repr(C)]
+struct PyModuleDefBase {
+    ob_base: PyObject,
+    m_init: Option<unsafe extern "C" fn() -> *mut PyObject>,
+    m_index: isize,
+    m_copy: *mut PyObject,
+}
+
+#[repr(C)]
+struct PyModuleDef {
+    m_base: PyModuleDefBase,
+    m_name: *const c_char,
+    m_doc: *const c_char,
+    m_size: isize,
+    m_methods: *mut PyMethodDef,
+    m_slots: *mut c_void,
+    m_traverse: *mut c_void,
+    m_clear: *mut c_void,
+    m_free: *mut c_void,
+}
+
+const METH_VARARGS: c_int = 1;
+
+/// `PYTHON_API_VERSION`, unchanged since Python 3.2
+const PYTHON_API_VERSION: c_int = 1013;
+
+extern "C" {
+    static mut PyExc_RuntimeError: *mut PyObject;
+
+    fn PyModule_Create2(module: *mut PyModuleDef, api_version: c_int) -> *mut PyObject;
+    fn PyArg_ParseTuple(args: *mut PyObject, format: *const c_char, ...) -> c_int;
+    fn PyImport_ImportModule(name: *const c_char) -> *mut PyObject;
+    fn PyObject_GetAttrString(object: *mut PyObject, name: *const c_char) -> *mut PyObject;
+    fn PyObject_CallFunctionObjArgs(callable: *mut PyObject, ...) -> *mut PyObject;
+    fn PyUnicode_FromStringAndSize(text: *const c_char, len: isize) -> *mut PyObject;
+    fn PyErr_SetString(exception: *mut PyObject, message: *const c_char);
+    fn Py_DecRef(object: *mut PyObject);
+    fn PyEval_SaveThread() -> *mut c_void;
+    fn PyEval_RestoreThread(state: *mut c_void);
+}
+
+/// A C string literal
+macro_rules! c {
+    ($text:expr) => {
+        concat!($text, "\0").as_ptr() as *const c_char
+    };
+}
+
+/// Raise a `RuntimeError` with `message`, returning the null the C API expects alongside it
+unsafe fn raise(message: &str) -> *mut PyObject {
+    PyErr_SetString(PyExc_RuntimeError, error_message(message).as_ptr());
+    ptr::null_mut()
+}
+
+/// Run `work` without the GIL, and return what it returns as the Python objects `json.loads`
+/// makes of it, or raise `RuntimeError` if it fails
+unsafe fn call<T: Serialize>(work: impl FnOnce() -> sync::Result<T>) -> *mut PyObject {
+    let state = PyEval_SaveThread();
+    let result = to_json(work);
+    PyEval_RestoreThread(state);
+    let json = match result {
+        Ok(json) => json,
+        Err(message) => return raise(&message),
+    };
+    let module = PyImport_ImportModule(c!("json"));
+    if module.is_null() {
+        return ptr::null_mut();
+    }
+    let loads = PyObject_GetAttrString(module, c!("loads"));
+    Py_DecRef(module);
+    if loads.is_null() {
+        return ptr::null_mut();
+    }
+    let text = PyUnicode_FromStringAndSize(json.as_ptr() as *const c_char, json.len() as isize);
+    let value = match text.is_null() {
+        true => ptr::null_mut(),
+        false => PyObject_CallFunctionObjArgs(loads, text, ptr::null_mut::<PyObject>()),
+    };
+    Py_DecRef(text);
+    Py_DecRef(loads);
+    value
+}
+
+/// The tag in a call's `(dir, branch, provider_id)` arguments, or None with a `TypeError` raised
+unsafe fn tag_args(args: *mut PyObject) -> Option<OwnedTag> {
+    let (mut dir, mut branch, mut provider_id) = (ptr::null(), ptr::null(), ptr::null());
+    let parsed = PyArg_ParseTuple(
+        args,
+        c!("sss"),
+        &mut dir as *mut *const c_char,
+        &mut branch as *mut *const c_char,
+        &mut provider_id as *mut *const c_char,
+    );
+    if parsed == 0 {
+        return None;
+    }
+    Some(OwnedTag::new(
+        to_str(dir)?,
+        to_str(branch)?,
+        to_str(provider_id)?,
+    ))
+}
+
+unsafe extern "C" fn py_sync(_module: *mut PyObject, args: *mut PyObject) -> *mut PyObject {
+    match tag_args(args) {
+        Some(tag) => call(|| sync::sync(&tag.as_tag(), &SyncOptions::default())),
+        None => ptr::null_mut(),
+    }
+}
+
+unsafe extern "C" fn py_delete_tag(_module: *mut PyObject, args: *mut PyObject) -> *mut PyObject {
+    match tag_args(args) {
+        Some(tag) => call(|| sync::delete_tag(&tag.as_tag(), &SyncOptions::default())),
+        None => ptr::null_mut(),
+    }
+}
+
+unsafe extern "C" fn py_list_tags(_module: *mut PyObject, args: *mut PyObject) -> *mut PyObject {
+    let (mut dir, mut provider_id) = (ptr::null(), ptr::null());
+    let parsed = PyArg_ParseTuple(
+        args,
+        c!("|zz"),
+        &mut dir as *mut *const c_char,
+        &mut provider_id as *mut *const c_char,
+    );
+    if parsed == 0 {
+        return ptr::null_mut();
+    }
+    let (dir, provider_id) = (to_str(dir), to_str(provider_id));
+    call(|| {
+        sync::list_tags(
+            &SyncOptions::default(),
+            dir.as_deref().map(Path::new),
+            provider_id.as_deref(),
+        )
+    })
+}
+
+unsafe extern "C" fn py_list_files(_module: *mut PyObject, args: *mut PyObject) -> *mut PyObject {
+    match tag_args(args) {
+        Some(tag) => call(|| sync::list_indexed_files(&tag.as_tag(), &SyncOptions::default())),
+        None => ptr::null_mut(),
+    }
+}
+
+unsafe extern "C" fn py_root_hash(_module: *mut PyObject, args: *mut PyObject) -> *mut PyObject {
+    match tag_args(args) {
+        Some(tag) => call(|| {
+            let hash = sync::get_root_hash(&tag.as_tag(), &SyncOptions::default())?;
+            Ok(hash.map(sync::hash_string))
+        }),
+        None => ptr::null_mut(),
+    }
+}
+
+unsafe extern "C" fn py_tags_containing(
+    _module: *mut PyObject,
+    args: *mut PyObject,
+) -> *mut PyObject {
+    let (mut hash, mut provider_id) = (ptr::null(), c!("default"));
+    let parsed = PyArg_ParseTuple(
+        args,
+        c!("s|s"),
+        &mut hash as *mut *const c_char,
+        &mut provider_id as *mut *const c_char,
+    );
+    if parsed == 0 {
+        return ptr::null_mut();
+    }
+    let (hash, provider_id) = (to_str(hash).unwrap_or_default(), to_str(provider_id));
+    let hash = match hash_arg(&hash) {
+        Ok(hash) => hash,
+        Err(message) => return raise(&message),
+    };
+    call(|| {
+        let provider_id = provider_id.as_deref().unwrap_or("default");
+        sync::which_tags_contain(hash, provider_id, &SyncOptions::default())
+    })
+}
+
+/// A method taking positional arguments
+const fn method(name: *const c_char, function: PyCFunction, doc: *const c_char) -> PyMethodDef {
+    PyMethodDef {
+        ml_name: name,
+        ml_meth: Some(function),
+        ml_flags: METH_VARARGS,
+        ml_doc: doc,
+    }
+}
+
+static mut METHODS: [PyMethodDef; 7] = [
+    method(
+        c!("sync"),
+        py_sync,
+        c!("sync(dir, branch, provider_id) -> dict of the files to compute, delete, addTag, removeTag and the renamed ones"),
+    ),
+    method(
+        c!("delete_tag"),
+        py_delete_tag,
+        c!("delete_tag(dir, branch, provider_id) -> dict, as from sync, of what removing the tag leaves to do"),
+    ),
+    method(
+        c!("list_tags"),
+        py_list_tags,
+        c!("list_tags(dir=None, provider_id=None) -> list of the tags in the index and when they were last synced"),
+    ),
+    method(
+        c!("list_files"),
+        py_list_files,
+        c!("list_files(dir, branch, provider_id) -> list of the files in the tag's saved tree"),
+    ),
+    method(
+        c!("root_hash"),
+        py_root_hash,
+        c!("root_hash(dir, branch, provider_id) -> the hex root hash of the tag's saved tree, or None"),
+    ),
+    method(
+        c!("tags_containing"),
+        py_tags_containing,
+        c!("tags_containing(hash, provider_id='default') -> list of the tags with files that have the content"),
+    ),
+    PyMethodDef {
+        ml_name: ptr::null(),
+        ml_meth: None,
+        ml_flags: 0,
+        ml_doc: ptr::null(),
+    },
+];
+
+static mut MODULE: PyModuleDef = PyModuleDef {
+    m_base: PyModuleDefBase {
+        ob_base: PyObject {
+            ob_refcnt: 1,
+            ob_type: ptr::null_mut(),
+        },
+        m_init: None,
+        m_index: 0,
+        m_copy: ptr::null_mut(),
+    },
+    m_name: c!("continue_sync"),
+    m_doc: c!("Syncs directories with Continue's codebase index"),
+    m_size: -1,
+    m_methods: ptr::null_mut(),
+    m_slots: ptr::null_mut(),
+    m_traverse: ptr::null_mut(),
+    m_clear: ptr::null_mut(),
+    m_free: ptr::null_mut(),
+};
+
+/// Called by the interpreter on `import continue_sync`
+#[no_mangle]
+pub unsafe extern "C" fn PyInit_continue_sync() -> *mut PyObject {
+    let module = ptr::addr_of_mut!(MODULE);
+    (*module).m_methods = ptr::addr_of_mut!(METHODS).cast();
+    PyModule_Create2(module, PYTHON_API_VERSION)
+}
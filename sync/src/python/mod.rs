@@ -0,0 +1,120 @@
+//! The `continue_sync` Python extension module, built with the `python` feature
+//!
+//! The module is written against CPython's C API directly (see `capi`), declaring the handful
+//! of functions it uses rather than pulling in a bindings crate. Results cross over as JSON and
+//! come back out of `json.loads`, so Python sees the same dicts and lists the other bindings
+//! get. The GIL is released while the index is being read or written, so other Python threads
+//! carry on.
+//!
+//! Build with `cargo build --release --features python` and copy `libsync.so` to
+//! `continue_sync.so` (`continue_sync.pyd` on Windows) somewhere on `sys.path`. Symbols from
+//! the interpreter are resolved when it loads the module, so the feature doesn't link against
+//! libpython, and binaries built with it (tests included) won't link on their own. That's why
+//! the conversions between Python's arguments and results and the crate's are here, outside
+//! the feature, where tests without it can check them.
+
+use crate::sync::{self, ObjectHash};
+use serde::Serialize;
+use std::{
+    ffi::{c_char, CStr, CString},
+    panic::{self, AssertUnwindSafe},
+};
+
+#[cfg(feature = "python")]
+mod capi;
+
+/// What a call hands back to Python: the JSON `json.loads` makes its result of, or the
+/// message of the `RuntimeError` it raises instead
+fn to_json<T: Serialize>(work: impl FnOnce() -> sync::Result<T>) -> Result<String, String> {
+    // A panic can't unwind into the interpreter, so it's raised like any other failure
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        work().and_then(|value| Ok(serde_json::to_string(&value)?))
+    }));
+    match result {
+        Ok(Ok(json)) => Ok(json),
+        Ok(Err(err)) => Err(err.to_string()),
+        Err(_) => Err("the sync panicked".to_string()),
+    }
+}
+
+/// `text` as a Rust string, or None if it's null or isn't UTF-8 (which `PyArg_ParseTuple`
+/// never hands out)
+///
+/// # Safety
+///
+/// `text` is null or a NUL-terminated string
+unsafe fn to_str(text: *const c_char) -> Option<String> {
+    match text.is_null() {
+        true => None,
+        false => CStr::from_ptr(text).to_str().ok().map(str::to_string),
+    }
+}
+
+/// `message` as the C string an exception is raised with, any NULs in it made spaces
+fn error_message(message: &str) -> CString {
+    CString::new(message.replace('\0', " ")).unwrap()
+}
+
+/// The hash in `tags_containing`'s first argument, or the message for the `RuntimeError` if
+/// it isn't one
+fn hash_arg(hash: &str) -> Result<ObjectHash, String> {
+    sync::parse_hash(hash).ok_or_else(|| format!("{hash} isn't a hash"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sync::{SyncError, SyncOptions, Tag},
+        utils::{temp_index_root, TempDirBuilder},
+    };
+    use serde_json::Value;
+
+    #[test]
+    fn test_to_json() {
+        let temp_dir = TempDirBuilder::new().add("a.txt", "A").create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+
+        // The dict `sync` returns
+        let json = to_json(|| sync::sync(tag, &options)).unwrap();
+        let results: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(results["compute"][0]["relative_path"], "a.txt");
+        assert_eq!(results["addTag"], Value::Array(Vec::new()));
+
+        let json = to_json(|| sync::get_root_hash(tag, &options)).unwrap();
+        assert!(json.starts_with('['));
+        assert_eq!(to_json(|| Ok(None::<String>)).unwrap(), "null");
+
+        assert_eq!(
+            to_json(|| Err::<(), _>(SyncError::Cancelled)),
+            Err("The sync was cancelled".to_string())
+        );
+        assert_eq!(
+            to_json(|| -> sync::Result<()> { panic!("while syncing") }),
+            Err("the sync panicked".to_string())
+        );
+    }
+
+    #[test]
+    fn test_to_str() {
+        unsafe {
+            assert_eq!(to_str(std::ptr::null()), None);
+            assert_eq!(to_str(b"src\0".as_ptr().cast()), Some("src".to_string()));
+            assert_eq!(to_str(b"caf\xe9\0".as_ptr().cast()), None);
+        }
+    }
+
+    #[test]
+    fn test_error_messages() {
+        assert_eq!(error_message("bad\0path").as_bytes(), b"bad path");
+        let hash = "ab".repeat(20);
+        assert_eq!(sync::hash_string(hash_arg(&hash).unwrap()), hash);
+        assert_eq!(hash_arg("abc"), Err("abc isn't a hash".to_string()));
+    }
+}
@@ -0,0 +1,495 @@
+//! Just enough zip and tar to list and unpack the files in an archive, for indexing archives as
+//! directories with `SyncOptions::archives`
+//!
+//! Zip members may be stored or deflated (which is all a jar ever is), and tar files may be
+//! gzipped, with GNU and pax long names. Zip64, encryption and other compression methods aren't
+//! handled, and an archive that uses them is skipped with a warning, the same as an unreadable
+//! file.
+
+use super::{gzip, merkle::path_from_bytes};
+use std::{
+    collections::BTreeMap,
+    convert::TryInto,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// An archive that would unpack to more than this is given up on, however big each member is
+const MAX_UNPACKED_SIZE: u64 = 512 * 1024 * 1024;
+
+const ZIP_END_SIGNATURE: u32 = 0x0605_4b50;
+const ZIP_ENTRY_SIGNATURE: u32 = 0x0201_4b50;
+const ZIP_LOCAL_SIGNATURE: u32 = 0x0403_4b50;
+
+/// The fixed part of a zip's end of central directory record, which a comment of up to 64 KiB
+/// can follow
+const ZIP_END_LEN: usize = 22;
+
+const TAR_BLOCK: usize = 512;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+/// What kind of archive the file at `path` is, going by its name
+fn kind(path: &Path) -> Option<Kind> {
+    let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+    if name.ends_with(".zip") || name.ends_with(".jar") {
+        Some(Kind::Zip)
+    } else if name.ends_with(".tar") {
+        Some(Kind::Tar)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(Kind::TarGz)
+    } else {
+        None
+    }
+}
+
+/// Patterns that match every archive `kind` recognizes, and nothing else
+pub(crate) const PATTERNS: &[&str] = &["*.zip", "*.jar", "*.tar", "*.tar.gz", "*.tgz"];
+
+pub(crate) fn is_archive(path: &Path) -> bool {
+    kind(path).is_some()
+}
+
+/// A file in an archive
+pub(crate) struct Member {
+    /// Relative to the archive
+    pub(crate) path: PathBuf,
+    pub(crate) size: u64,
+
+    /// None if it's larger than the archive was unpacked with
+    pub(crate) contents: Option<Vec<u8>>,
+}
+
+/// The files in `data`, the archive at `path` (whose name says what kind it is), in order of
+/// their paths, unpacking those of up to `max_size` bytes. Directories, links and hidden files
+/// are left out, as are paths that would lead out of the archive. A path that is in the archive
+/// twice is the last one, as unpacking it would leave, and a file that is also a directory is
+/// the directory
+pub(crate) fn members(path: &Path, data: &[u8], max_size: u64) -> Result<Vec<Member>, String> {
+    let members = match kind(path) {
+        Some(Kind::Zip) => zip_members(data, max_size)?,
+        Some(Kind::Tar) => tar_members(data, max_size)?,
+        Some(Kind::TarGz) => {
+            let limit = MAX_UNPACKED_SIZE.try_into().unwrap_or(usize::MAX);
+            tar_members(&gzip::decompress_at_most(data, limit)?, max_size)?
+        }
+        None => return Err("not an archive".to_string()),
+    };
+    let members: BTreeMap<PathBuf, Member> = members
+        .into_iter()
+        .map(|member| (member.path.clone(), member))
+        .collect();
+    // A directory's files follow right after it in order
+    let mut members: Vec<Member> = members.into_values().collect();
+    let directories: Vec<bool> = members
+        .windows(2)
+        .map(|pair| pair[1].path.starts_with(&pair[0].path))
+        .chain([false])
+        .collect();
+    let mut directories = directories.into_iter();
+    members.retain(|_| !directories.next().unwrap());
+    Ok(members)
+}
+
+/// The contents of the file at `path`, which may be a member of an archive indexed with
+/// `SyncOptions::archives` (`<archive>/<member>`), in which case it's unpacked from there
+pub fn read_indexed_file(path: &Path) -> io::Result<Vec<u8>> {
+    let err = match fs::read(path) {
+        Ok(contents) => return Ok(contents),
+        Err(err) => err,
+    };
+    let Some(archive) = path
+        .ancestors()
+        .skip(1)
+        .find(|archive| is_archive(archive) && archive.is_file())
+    else {
+        return Err(err);
+    };
+    let member = path.strip_prefix(archive).unwrap_or(path);
+    let data = fs::read(archive)?;
+    let members = members(archive, &data, u64::MAX).map_err(|message| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {message}", archive.display()),
+        )
+    })?;
+    members
+        .into_iter()
+        .find(|found| found.path == member)
+        .and_then(|found| found.contents)
+        .ok_or(err)
+}
+
+/// The path of a member named `name`, relative to the archive, or None if it's hidden or would
+/// lead out of it. Names are split on `/` in zip and tar files alike
+fn member_path(name: &[u8]) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in name.split(|&byte| byte == b'/') {
+        match component {
+            b"" | b"." => {}
+            // A backslash would be another separator on Windows
+            _ if component[0] == b'.' || component.contains(&b'\\') => return None,
+            _ => path.push(path_from_bytes(component.to_vec())),
+        }
+    }
+    match path.as_os_str().is_empty() {
+        true => None,
+        false => Some(path),
+    }
+}
+
+fn bytes_at(data: &[u8], pos: usize, len: usize) -> Result<&[u8], String> {
+    pos.checked_add(len)
+        .and_then(|end| data.get(pos..end))
+        .ok_or_else(|| "the archive ends early".to_string())
+}
+
+fn u16_at(data: &[u8], pos: usize) -> Result<u16, String> {
+    Ok(u16::from_le_bytes(
+        bytes_at(data, pos, 2)?.try_into().unwrap(),
+    ))
+}
+
+fn u32_at(data: &[u8], pos: usize) -> Result<u32, String> {
+    Ok(u32::from_le_bytes(
+        bytes_at(data, pos, 4)?.try_into().unwrap(),
+    ))
+}
+
+/// Count `size` bytes towards what the archive unpacks to so far
+fn add_unpacked(unpacked: &mut u64, size: u64) -> Result<(), String> {
+    *unpacked += size;
+    match *unpacked > MAX_UNPACKED_SIZE {
+        true => Err(format!("unpacks to more than {MAX_UNPACKED_SIZE} bytes")),
+        false => Ok(()),
+    }
+}
+
+fn zip_members(data: &[u8], max_size: u64) -> Result<Vec<Member>, String> {
+    let last = data
+        .len()
+        .checked_sub(ZIP_END_LEN)
+        .ok_or("not a zip file")?;
+    let end = (last.saturating_sub(u16::MAX as usize)..=last)
+        .rev()
+        .find(|&pos| u32_at(data, pos) == Ok(ZIP_END_SIGNATURE))
+        .ok_or("not a zip file")?;
+    let count = u16_at(data, end + 10)?;
+    let pos = u32_at(data, end + 16)?;
+    if count == u16::MAX || pos == u32::MAX {
+        return Err("zip64 archives aren't supported".to_string());
+    }
+
+    let mut pos = pos as usize;
+    let mut members = Vec::new();
+    let mut unpacked = 0;
+    for _ in 0..count {
+        if u32_at(data, pos)? != ZIP_ENTRY_SIGNATURE {
+            return Err("the zip's central directory is corrupt".to_string());
+        }
+        let flags = u16_at(data, pos + 8)?;
+        let method = u16_at(data, pos + 10)?;
+        let crc = u32_at(data, pos + 16)?;
+        let compressed_size = u32_at(data, pos + 20)?;
+        let size = u32_at(data, pos + 24)?;
+        let name_len = u16_at(data, pos + 28)? as usize;
+        let extra_len = u16_at(data, pos + 30)? as usize;
+        let comment_len = u16_at(data, pos + 32)? as usize;
+        let mode = u32_at(data, pos + 38)? >> 16;
+        let local = u32_at(data, pos + 42)?;
+        let name = bytes_at(data, pos + 46, name_len)?;
+        pos += 46 + name_len + extra_len + comment_len;
+
+        // Directories end with a slash, and symlinks are marked in their Unix mode
+        let symlink = mode & 0o170_000 == 0o120_000;
+        let path = match member_path(name) {
+            Some(path) if !name.ends_with(b"/") && !symlink => path,
+            _ => continue,
+        };
+        if [compressed_size, size, local].contains(&u32::MAX) {
+            return Err("zip64 archives aren't supported".to_string());
+        }
+        if flags & 1 != 0 {
+            return Err("encrypted zip files aren't supported".to_string());
+        }
+        let size = size as u64;
+        if size > max_size {
+            members.push(Member {
+                path,
+                size,
+                contents: None,
+            });
+            continue;
+        }
+        add_unpacked(&mut unpacked, size)?;
+
+        let local = local as usize;
+        if u32_at(data, local)? != ZIP_LOCAL_SIGNATURE {
+            return Err(format!("{} is corrupt", path.display()));
+        }
+        let start =
+            local + 30 + u16_at(data, local + 26)? as usize + u16_at(data, local + 28)? as usize;
+        let compressed = bytes_at(data, start, compressed_size as usize)?;
+        let contents = match method {
+            0 => compressed.to_vec(),
+            8 => gzip::inflate_raw(compressed, size as usize)?,
+            method => return Err(format!("zip compression method {method} isn't supported")),
+        };
+        if contents.len() as u64 != size || gzip::crc32(&contents) != crc {
+            return Err(format!("{} is corrupt", path.display()));
+        }
+        members.push(Member {
+            path,
+            size,
+            contents: Some(contents),
+        });
+    }
+    Ok(members)
+}
+
+/// A number in a tar header: octal digits, or big-endian binary for large ones
+fn tar_number(field: &[u8]) -> Result<u64, String> {
+    if field.first().is_some_and(|&byte| byte & 0x80 != 0) {
+        let (first, rest) = field.split_first().unwrap();
+        return Ok(rest
+            .iter()
+            .fold(u64::from(first & 0x7f), |n, &byte| n << 8 | u64::from(byte)));
+    }
+    let mut digits = field
+        .iter()
+        .skip_while(|&&byte| byte == b' ')
+        .take_while(|&&byte| (b'0'..=b'7').contains(&byte));
+    digits.try_fold(0u64, |n, &byte| {
+        n.checked_mul(8)
+            .map(|n| n + u64::from(byte - b'0'))
+            .ok_or_else(|| "a tar header number is too large".to_string())
+    })
+}
+
+/// Up to the first NUL
+fn tar_string(field: &[u8]) -> &[u8] {
+    field.split(|&byte| byte == 0).next().unwrap_or_default()
+}
+
+/// The path in a pax extended header, if it has one
+fn pax_path(records: &[u8]) -> Option<Vec<u8>> {
+    let mut rest = records;
+    let mut path = None;
+    while !rest.is_empty() {
+        // Each record is "<length> <key>=<value>\n", the length counting all of it
+        let space = rest.iter().position(|&byte| byte == b' ')?;
+        let len: usize = std::str::from_utf8(&rest[..space]).ok()?.parse().ok()?;
+        let record = rest.get(space + 1..len)?.strip_suffix(b"\n")?;
+        if let Some(value) = record.strip_prefix(b"path=") {
+            path = Some(value.to_vec());
+        }
+        rest = &rest[len..];
+    }
+    path
+}
+
+fn tar_members(data: &[u8], max_size: u64) -> Result<Vec<Member>, String> {
+    let mut members = Vec::new();
+    let mut unpacked = 0;
+    let mut pos = 0;
+    // From a GNU long name or pax header, for the member after it
+    let mut long_name = None;
+    while pos + TAR_BLOCK <= data.len() {
+        let header = &data[pos..pos + TAR_BLOCK];
+        if header.iter().all(|&byte| byte == 0) {
+            break;
+        }
+        let checksum: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &byte)| match i {
+                148..=155 => u64::from(b' '),
+                _ => u64::from(byte),
+            })
+            .sum();
+        if tar_number(&header[148..156])? != checksum {
+            return Err("not a tar file".to_string());
+        }
+        let size = tar_number(&header[124..136])?;
+        let start = pos + TAR_BLOCK;
+        let body = bytes_at(data, start, size.try_into().unwrap_or(usize::MAX))?;
+        pos = start + body.len().div_ceil(TAR_BLOCK) * TAR_BLOCK;
+
+        match header[156] {
+            b'L' => {
+                long_name = Some(tar_string(body).to_vec());
+                continue;
+            }
+            b'x' => {
+                long_name = pax_path(body).or(long_name);
+                continue;
+            }
+            // Regular files, including the contiguous kind
+            b'0' | 0 | b'7' => {}
+            _ => {
+                long_name = None;
+                continue;
+            }
+        }
+        let name = long_name.take().unwrap_or_else(|| {
+            let name = tar_string(&header[0..100]);
+            let prefix = tar_string(&header[345..500]);
+            match &header[257..262] == b"ustar" && !prefix.is_empty() {
+                true => [prefix, b"/", name].concat(),
+                false => name.to_vec(),
+            }
+        });
+        let Some(path) = member_path(&name) else {
+            continue;
+        };
+        let contents = match size > max_size {
+            true => None,
+            false => {
+                add_unpacked(&mut unpacked, size)?;
+                Some(body.to_vec())
+            }
+        };
+        members.push(Member {
+            path,
+            size,
+            contents,
+        });
+    }
+    Ok(members)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /// A zip file with `files` deflated in it
+    pub(in crate::sync) fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let (mut data, mut directory) = (Vec::new(), Vec::new());
+        for &(name, contents) in files {
+            // Deflate data is gzip without its header and trailer
+            let gzipped = gzip::compress(contents);
+            let compressed = &gzipped[10..gzipped.len() - 8];
+            let crc = gzip::crc32(contents).to_le_bytes();
+            let compressed_len = (compressed.len() as u32).to_le_bytes();
+            let len = (contents.len() as u32).to_le_bytes();
+            let name_len = (name.len() as u16).to_le_bytes();
+            let offset = (data.len() as u32).to_le_bytes();
+
+            data.extend_from_slice(&ZIP_LOCAL_SIGNATURE.to_le_bytes());
+            data.extend_from_slice(&[20, 0, 0, 0, 8, 0, 0, 0, 0, 0]);
+            data.extend_from_slice(&crc);
+            data.extend_from_slice(&compressed_len);
+            data.extend_from_slice(&len);
+            data.extend_from_slice(&name_len);
+            data.extend_from_slice(&[0, 0]);
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(compressed);
+
+            directory.extend_from_slice(&ZIP_ENTRY_SIGNATURE.to_le_bytes());
+            directory.extend_from_slice(&[20, 0, 20, 0, 0, 0, 8, 0, 0, 0, 0, 0]);
+            directory.extend_from_slice(&crc);
+            directory.extend_from_slice(&compressed_len);
+            directory.extend_from_slice(&len);
+            directory.extend_from_slice(&name_len);
+            directory.extend_from_slice(&[0; 12]);
+            directory.extend_from_slice(&offset);
+            directory.extend_from_slice(name.as_bytes());
+        }
+        let count = (files.len() as u16).to_le_bytes();
+        let directory_len = (directory.len() as u32).to_le_bytes();
+        let directory_offset = (data.len() as u32).to_le_bytes();
+        data.extend_from_slice(&directory);
+        data.extend_from_slice(&ZIP_END_SIGNATURE.to_le_bytes());
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend_from_slice(&count);
+        data.extend_from_slice(&count);
+        data.extend_from_slice(&directory_len);
+        data.extend_from_slice(&directory_offset);
+        data.extend_from_slice(&[0, 0]);
+        data
+    }
+
+    fn paths(members: &[Member]) -> Vec<&Path> {
+        members.iter().map(|member| member.path.as_path()).collect()
+    }
+
+    #[test]
+    fn test_zip_members() {
+        let data = zip(&[
+            ("src/", b""),
+            ("src/Main.java", b"class Main {}"),
+            ("/README", b"read me"),
+            ("../escape", b"no"),
+            ("src/.hidden", b"no"),
+            ("big.txt", &[b'x'; 100]),
+        ]);
+        let members = members(Path::new("lib-sources.JAR"), &data, 50).unwrap();
+        assert_eq!(paths(&members), ["README", "big.txt", "src/Main.java"]);
+        assert_eq!(members[2].contents.as_deref(), Some(&b"class Main {}"[..]));
+        assert_eq!(
+            (members[1].size, members[1].contents.is_none()),
+            (100, true)
+        );
+
+        // The checksum of src/Main.java in the central directory, which comes second
+        let mut corrupt = data.clone();
+        let signature = ZIP_ENTRY_SIGNATURE.to_le_bytes();
+        let mut entries = (0..corrupt.len()).filter(|&at| corrupt[at..].starts_with(&signature));
+        let at = entries.nth(1).unwrap();
+        corrupt[at + 16] ^= 1;
+        assert!(super::members(Path::new("a.zip"), &corrupt, 50).is_err());
+        assert!(super::members(Path::new("a.zip"), b"not a zip", 50).is_err());
+    }
+
+    /// A tar header for a file of `len` bytes
+    fn tar_header(name: &[u8], len: usize, kind: u8) -> Vec<u8> {
+        let mut header = vec![0; TAR_BLOCK];
+        header[..name.len()].copy_from_slice(name);
+        header[124..135].copy_from_slice(format!("{len:011o}").as_bytes());
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[148..156].fill(b' ');
+        let checksum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+        header[148..155].copy_from_slice(format!("{checksum:06o}\0").as_bytes());
+        header
+    }
+
+    fn tar_entry(tar: &mut Vec<u8>, name: &[u8], contents: &[u8], kind: u8) {
+        tar.extend(tar_header(name, contents.len(), kind));
+        tar.extend_from_slice(contents);
+        tar.resize(tar.len().div_ceil(TAR_BLOCK) * TAR_BLOCK, 0);
+    }
+
+    #[test]
+    fn test_tar_members() {
+        let long = format!("pkg/{}/deep.rs", "d".repeat(120));
+        let mut tar = Vec::new();
+        tar_entry(&mut tar, b"pkg/", b"", b'5');
+        tar_entry(&mut tar, b"pkg/lib.rs", b"fn lib() {}", b'0');
+        tar_entry(&mut tar, b"././@LongLink", long.as_bytes(), b'L');
+        tar_entry(&mut tar, b"truncated", b"fn deep() {}", b'0');
+        let pax = b"23 path=pkg/renamed.rs\n";
+        tar_entry(&mut tar, b"PaxHeader", pax, b'x');
+        tar_entry(&mut tar, b"pkg/original.rs", b"fn renamed() {}", b'0');
+        tar_entry(&mut tar, b"pkg/link.rs", b"", b'2');
+        tar.extend([0; 2 * TAR_BLOCK]);
+
+        let members = members(Path::new("crate.tar"), &tar, 1024).unwrap();
+        let long = long.as_str();
+        assert_eq!(paths(&members), [long, "pkg/lib.rs", "pkg/renamed.rs"]);
+        assert_eq!(
+            members[2].contents.as_deref(),
+            Some(&b"fn renamed() {}"[..])
+        );
+
+        let gzipped = gzip::compress(&tar);
+        let members = super::members(Path::new("crate.tgz"), &gzipped, 1024).unwrap();
+        assert_eq!(members.len(), 3);
+        assert!(super::members(Path::new("crate.tar"), &[1; 1024], 1024).is_err());
+    }
+}
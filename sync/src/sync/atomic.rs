@@ -0,0 +1,72 @@
+//! Replacing index files in one step, so that a crash part way through a write leaves either the
+//! old contents or the new ones, never a mix
+
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// `<file>.tmp`, next to the file, so that renaming it never crosses filesystems
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    path.with_file_name(tmp_name)
+}
+
+/// Write `contents` to a temporary file and rename it over `path`. With `fsync`, the contents
+/// are flushed to disk before the rename, and the rename itself before returning, so the new
+/// contents also survive a power cut
+pub(crate) fn write(path: &Path, contents: &[u8], fsync: bool) -> io::Result<()> {
+    let tmp_path = tmp_path(path);
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    if fsync {
+        file.sync_all()?;
+    }
+    drop(file);
+
+    fs::rename(&tmp_path, path)?;
+    if fsync {
+        sync_dir(path)?;
+    }
+    Ok(())
+}
+
+/// Flush the directory entry of `path`, which only exists as a file on Unix
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDirBuilder;
+
+    #[test]
+    fn test_write() {
+        let temp_dir = TempDirBuilder::new().add("a.txt", "old").create();
+        let path = temp_dir.path().join("a.txt");
+
+        for fsync in [false, true] {
+            write(&path, b"new", fsync).unwrap();
+            assert_eq!(fs::read(&path).unwrap(), b"new");
+            assert!(!tmp_path(&path).exists());
+        }
+
+        // A temporary file left by a crashed write is simply replaced
+        fs::write(tmp_path(&path), "partial").unwrap();
+        write(&path, b"newer", false).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"newer");
+        assert!(!tmp_path(&path).exists());
+    }
+}
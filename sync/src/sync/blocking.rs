@@ -0,0 +1,296 @@
+//! Async versions of the functions that walk, hash and write the index, for callers running
+//! on an executor (tokio or any other) that mustn't be blocked for the length of a sync
+//!
+//! Each call runs the blocking function on a small pool of threads and returns a `Blocking`
+//! future that the thread wakes once it's done, so no particular runtime is needed. Hashing
+//! already spreads over its own threads within the sync, so the pool only needs a few, and
+//! calls past that many wait for one of them. Threads are started as they're needed and stop
+//! once they've been idle for a while.
+
+use super::{
+    delete_tag, error::Result, mark_computed, merkle::ObjectHash, options::SyncOptions, sync,
+    OwnedTag, SyncResults, Tag,
+};
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex, OnceLock},
+    task::{Context, Poll, Waker},
+    thread,
+    time::Duration,
+};
+
+/// The most calls that run at once
+const MAX_THREADS: usize = 4;
+
+/// How long a thread waits for another call before it stops
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The result of a blocking call running on another thread. Dropping it doesn't stop the call,
+/// which carries on to the end with nothing waiting for it
+pub struct Blocking<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+struct Shared<T> {
+    result: Option<thread::Result<T>>,
+    waker: Option<Waker>,
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Runs jobs on up to `max_threads` threads, queueing the rest
+struct Pool {
+    max_threads: usize,
+    state: Mutex<PoolState>,
+    queued: Condvar,
+
+    /// How the pool's threads are made
+    builder: fn() -> thread::Builder,
+}
+
+#[derive(Default)]
+struct PoolState {
+    jobs: VecDeque<Job>,
+    threads: usize,
+    idle: usize,
+}
+
+impl Pool {
+    fn new(max_threads: usize, builder: fn() -> thread::Builder) -> Arc<Self> {
+        Arc::new(Pool {
+            max_threads,
+            state: Mutex::default(),
+            queued: Condvar::new(),
+            builder,
+        })
+    }
+
+    /// Queue `job`, starting a thread for it if none is free and there's room for another. It
+    /// fails only if there's no thread to run it and one can't be started
+    fn execute(self: &Arc<Self>, job: Job) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.jobs.push_back(job);
+        if state.idle > state.jobs.len() - 1 || state.threads >= self.max_threads {
+            self.queued.notify_one();
+            return Ok(());
+        }
+        let pool = self.clone();
+        match (self.builder)().spawn(move || pool.work()) {
+            Ok(_) => {
+                state.threads += 1;
+                Ok(())
+            }
+            // The threads there are will get to it
+            Err(_) if state.threads > 0 => Ok(()),
+            Err(err) => {
+                state.jobs.pop_back();
+                Err(err)
+            }
+        }
+    }
+
+    /// Run jobs until none has come for `IDLE_TIMEOUT`
+    fn work(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(job) = state.jobs.pop_front() {
+                drop(state);
+                job();
+                state = self.state.lock().unwrap();
+                continue;
+            }
+            state.idle += 1;
+            let (next, timeout) = self.queued.wait_timeout(state, IDLE_TIMEOUT).unwrap();
+            state = next;
+            state.idle -= 1;
+            if timeout.timed_out() && state.jobs.is_empty() {
+                state.threads -= 1;
+                return;
+            }
+        }
+    }
+}
+
+/// The pool every call shares
+fn pool() -> &'static Arc<Pool> {
+    static POOL: OnceLock<Arc<Pool>> = OnceLock::new();
+    POOL.get_or_init(|| {
+        Pool::new(MAX_THREADS, || {
+            thread::Builder::new().name("continue-sync".to_string())
+        })
+    })
+}
+
+/// Run `work` on `pool`. If there's no thread to run it on, the future is the error
+fn spawn_on<T: Send + 'static>(
+    pool: &Arc<Pool>,
+    work: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Blocking<Result<T>> {
+    let shared = Arc::new(Mutex::new(Shared {
+        result: None,
+        waker: None,
+    }));
+    let done = shared.clone();
+    let spawned = pool.execute(Box::new(move || {
+        // A panic is passed on to whoever polls the future, as if it had made the call
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(work));
+        let mut done = done.lock().unwrap();
+        done.result = Some(result);
+        if let Some(waker) = done.waker.take() {
+            waker.wake();
+        }
+    }));
+    if let Err(err) = spawned {
+        shared.lock().unwrap().result = Some(Ok(Err(err.into())));
+    }
+    Blocking { shared }
+}
+
+/// Run `work` on the shared pool
+pub(crate) fn spawn<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Blocking<Result<T>> {
+    spawn_on(pool(), work)
+}
+
+impl<T> Future for Blocking<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.result.take() {
+            Some(Ok(value)) => Poll::Ready(value),
+            Some(Err(panic)) => std::panic::resume_unwind(panic),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Same as `sync`, without blocking the caller's thread
+pub fn sync_async(tag: &Tag, options: &SyncOptions) -> Blocking<Result<SyncResults>> {
+    let tag = OwnedTag::from(tag);
+    let options = options.clone();
+    spawn(move || sync(&tag.as_tag(), &options))
+}
+
+/// Same as `delete_tag`, without blocking the caller's thread
+pub fn delete_tag_async(tag: &Tag, options: &SyncOptions) -> Blocking<Result<SyncResults>> {
+    let tag = OwnedTag::from(tag);
+    let options = options.clone();
+    spawn(move || delete_tag(&tag.as_tag(), &options))
+}
+
+/// Same as `mark_computed`, without blocking the caller's thread
+pub fn mark_computed_async(
+    tag: &Tag,
+    hashes: &[ObjectHash],
+    options: &SyncOptions,
+) -> Blocking<Result<()>> {
+    let tag = OwnedTag::from(tag);
+    let hashes = hashes.to_vec();
+    let options = options.clone();
+    spawn(move || mark_computed(&tag.as_tag(), &hashes, &options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sync::SyncError,
+        utils::{temp_index_root, TempDirBuilder},
+    };
+    use std::task::Wake;
+
+    /// Wakes the thread that's waiting for a future
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Poll `future` until it's ready, parking the thread in between, as an executor would
+    fn block_on<T>(future: impl Future<Output = T>) -> T {
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(value) => return value,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_sync_async() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("b.txt", "B")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+
+        let results = block_on(sync_async(tag, &options)).unwrap();
+        assert_eq!(results.compute.len(), 2);
+        assert_eq!(
+            block_on(sync_async(tag, &options)).unwrap(),
+            SyncResults::default()
+        );
+        let results = block_on(delete_tag_async(tag, &options)).unwrap();
+        assert_eq!(results.delete.len(), 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "while syncing")]
+    fn test_blocking_panic() {
+        block_on(spawn(|| -> Result<()> { panic!("while syncing") })).unwrap()
+    }
+
+    #[test]
+    fn test_pool_is_bounded() {
+        let pool = Pool::new(2, thread::Builder::new);
+        let running = Arc::new(Mutex::new((0, 0)));
+        let calls: Vec<_> = (0..8)
+            .map(|_| {
+                let running = running.clone();
+                spawn_on(&pool, move || {
+                    {
+                        let (now, most) = &mut *running.lock().unwrap();
+                        *now += 1;
+                        *most = (*most).max(*now);
+                    }
+                    thread::sleep(Duration::from_millis(20));
+                    running.lock().unwrap().0 -= 1;
+                    Ok(())
+                })
+            })
+            .collect();
+        for call in calls {
+            block_on(call).unwrap();
+        }
+        assert_eq!(running.lock().unwrap().1, 2);
+        assert_eq!(pool.state.lock().unwrap().threads, 2);
+    }
+
+    #[test]
+    fn test_spawn_failure() {
+        // A stack no system can give a thread
+        let pool = Pool::new(2, || thread::Builder::new().stack_size(1 << 50));
+        let err = block_on(spawn_on(&pool, || Ok(()))).unwrap_err();
+        assert!(matches!(err, SyncError::Io(_)));
+        assert!(pool.state.lock().unwrap().jobs.is_empty());
+    }
+}
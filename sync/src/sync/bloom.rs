@@ -0,0 +1,207 @@
+use super::{
+    error::{Result, SyncError},
+    file_system::FileSystem,
+};
+use std::{
+    convert::TryInto,
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+const MAGIC: &[u8; 4] = b"BLM1";
+const HEADER_SIZE: usize = 4 + 4 + 8 + 8 + 8;
+const BITS_PER_ITEM: usize = 10;
+const NUM_HASHES: u32 = 7;
+const MIN_CAPACITY: usize = 1024;
+
+/// A Bloom filter over 20-byte content hashes
+///
+/// The items are already SHA-1 hashes, so the bit positions are taken straight from their bytes
+/// rather than hashing them again
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct BloomFilter {
+    bits: Vec<u64>,
+    capacity: usize,
+}
+
+/// Identifies the exact version of the file a filter was built from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Stamp {
+    pub(crate) len: u64,
+    pub(crate) mtime: u64,
+}
+
+impl Stamp {
+    pub(crate) fn of(file_system: &dyn FileSystem, path: &Path) -> Result<Self> {
+        let metadata = file_system.metadata(path)?;
+        let modified = metadata.modified.ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::Unsupported,
+                format!("{} has no modification time", path.display()),
+            )
+        })?;
+        let mtime = modified
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |mtime| mtime.as_nanos() as u64);
+        Ok(Stamp {
+            len: metadata.len,
+            mtime,
+        })
+    }
+}
+
+impl BloomFilter {
+    /// An empty filter that stays accurate for at least `items` items
+    pub(crate) fn with_capacity(items: usize) -> Self {
+        let capacity = (items * 2).max(MIN_CAPACITY);
+        let num_bits = capacity * BITS_PER_ITEM;
+        BloomFilter {
+            bits: vec![0; num_bits.div_ceil(64)],
+            capacity,
+        }
+    }
+
+    /// How many items the filter was sized for. Past this, false positives become common
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn positions(&self, item: &[u8; 20]) -> impl Iterator<Item = usize> {
+        let num_bits = (self.bits.len() * 64) as u64;
+        let h1 = u64::from_le_bytes(item[0..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(item[8..16].try_into().unwrap()) | 1;
+        (0..NUM_HASHES as u64)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+
+    pub(crate) fn insert(&mut self, item: &[u8; 20]) {
+        let positions: Vec<usize> = self.positions(item).collect();
+        for position in positions {
+            self.bits[position / 64] |= 1 << (position % 64);
+        }
+    }
+
+    /// False means the item is definitely not in the set
+    pub(crate) fn may_contain(&self, item: &[u8; 20]) -> bool {
+        self.positions(item)
+            .all(|position| self.bits[position / 64] & (1 << (position % 64)) != 0)
+    }
+
+    /// Load the filter at `path`, as long as it was saved for the file with this stamp.
+    /// A missing or out of date filter is None
+    pub(crate) fn load(
+        file_system: &dyn FileSystem,
+        path: &Path,
+        stamp: Stamp,
+    ) -> Result<Option<Self>> {
+        let contents = match file_system.read(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let corrupted =
+            || SyncError::CorruptedIndex(format!("invalid bloom filter {}", path.display()));
+        if contents.len() < HEADER_SIZE || &contents[0..4] != MAGIC {
+            return Err(corrupted());
+        }
+        let num_hashes = u32::from_le_bytes(contents[4..8].try_into().unwrap());
+        let capacity = u64::from_le_bytes(contents[8..16].try_into().unwrap()) as usize;
+        let saved_stamp = Stamp {
+            len: u64::from_le_bytes(contents[16..24].try_into().unwrap()),
+            mtime: u64::from_le_bytes(contents[24..32].try_into().unwrap()),
+        };
+        if num_hashes != NUM_HASHES || saved_stamp != stamp {
+            return Ok(None);
+        }
+
+        let words = &contents[HEADER_SIZE..];
+        if words.is_empty() || words.len() % 8 != 0 {
+            return Err(corrupted());
+        }
+        let bits = words
+            .chunks_exact(8)
+            .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        Ok(Some(BloomFilter { bits, capacity }))
+    }
+
+    /// Save the filter as belonging to the file with this stamp. The file is replaced in one
+    /// step, so a reader never sees a half-written filter
+    pub(crate) fn persist(
+        &self,
+        file_system: &dyn FileSystem,
+        path: &Path,
+        stamp: Stamp,
+    ) -> Result<()> {
+        let mut contents = Vec::with_capacity(HEADER_SIZE + self.bits.len() * 8);
+        contents.extend_from_slice(MAGIC);
+        contents.extend_from_slice(&NUM_HASHES.to_le_bytes());
+        contents.extend_from_slice(&(self.capacity as u64).to_le_bytes());
+        contents.extend_from_slice(&stamp.len.to_le_bytes());
+        contents.extend_from_slice(&stamp.mtime.to_le_bytes());
+        for word in &self.bits {
+            contents.extend_from_slice(&word.to_le_bytes());
+        }
+
+        file_system.write(path, &contents, true)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sync::OsFileSystem, utils::TempDirBuilder};
+    use sha1::{Digest, Sha1};
+    use std::fs;
+
+    fn item(i: u32) -> [u8; 20] {
+        Sha1::digest(i.to_le_bytes()).into()
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut filter = BloomFilter::with_capacity(1000);
+        for i in 0..1000 {
+            filter.insert(&item(i));
+        }
+        assert!((0..1000).all(|i| filter.may_contain(&item(i))));
+
+        // Sized at twice the items, the false positive rate should be well under 1%
+        let false_positives = (1000..11000)
+            .filter(|i| filter.may_contain(&item(*i)))
+            .count();
+        assert!(false_positives < 100, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn test_bloom_filter_persist() {
+        let temp_dir = TempDirBuilder::new().create();
+        let path = temp_dir.path().join(".index_cache.bloom");
+        let stamp = Stamp { len: 40, mtime: 1 };
+        assert_eq!(
+            BloomFilter::load(&OsFileSystem, &path, stamp).unwrap(),
+            None
+        );
+
+        let mut filter = BloomFilter::with_capacity(10);
+        filter.insert(&item(0));
+        filter.persist(&OsFileSystem, &path, stamp).unwrap();
+        assert_eq!(
+            BloomFilter::load(&OsFileSystem, &path, stamp).unwrap(),
+            Some(filter)
+        );
+
+        // Saved for a different version of the file
+        let newer = Stamp { len: 60, mtime: 2 };
+        assert_eq!(
+            BloomFilter::load(&OsFileSystem, &path, newer).unwrap(),
+            None
+        );
+
+        fs::write(&path, "garbage").unwrap();
+        let result = BloomFilter::load(&OsFileSystem, &path, stamp);
+        assert!(matches!(result, Err(SyncError::CorruptedIndex(_))));
+    }
+}
@@ -0,0 +1,376 @@
+//! Splitting files into chunks for embedding, with `SyncOptions::chunking`
+//!
+//! Chunks are whole lines where they can be: a chunk takes as many lines as fit in
+//! `max_size` bytes, and a line that doesn't fit in one on its own is split between characters.
+//! Each chunk after the first starts with the last lines of the one before it, up to `overlap`
+//! bytes of them, so that whatever straddles the boundary is whole in one of them. Ranges are
+//! of the file's bytes as they were read, the same bytes `read_object` gives back.
+//!
+//! With `syntax_aware`, source code in a language `syntax.rs` knows is split where its
+//! definitions end instead: a chunk takes as many whole functions, classes and the like as fit,
+//! and one that doesn't fit is split between the definitions in it, and so on down until only
+//! lines are left to split it by. These chunks don't overlap, since nothing straddles them.
+
+use super::{
+    merkle::hash_string,
+    syntax::{self, Language},
+};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::{ops::Range, path::Path};
+
+/// How deep in a definition to look for others to split it between, before splitting by lines
+const MAX_DEPTH: usize = 8;
+
+/// How `SyncOptions::chunking` splits the files to compute
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkOptions {
+    /// The most bytes in a chunk
+    pub max_size: usize,
+
+    /// The most bytes of lines a chunk repeats from the end of the one before it
+    pub overlap: usize,
+
+    /// Whether to split source code between its definitions where the language is one we know
+    pub syntax_aware: bool,
+}
+
+impl Default for ChunkOptions {
+    fn default() -> Self {
+        ChunkOptions {
+            max_size: 2048,
+            overlap: 256,
+            syntax_aware: false,
+        }
+    }
+}
+
+/// A piece of a file to compute
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Chunk {
+    /// Where the chunk starts in the file, in bytes
+    pub start_byte: u64,
+
+    /// Where the chunk ends in the file, in bytes, not including this one
+    pub end_byte: u64,
+
+    /// The line the chunk starts on, counting from 0
+    pub start_line: u64,
+
+    /// The line the chunk ends on, including it
+    pub end_line: u64,
+
+    /// Hex-encoded hash of the chunk's content, the same wherever the content is
+    pub hash: String,
+}
+
+/// A line, or as much of one as fits in a chunk
+#[derive(Clone, Copy)]
+struct Piece {
+    start: usize,
+    end: usize,
+    line: u64,
+}
+
+/// Where to end a piece of a line starting at `start` that fits in `max_size` bytes, without
+/// splitting a UTF-8 character unless it's bigger than that
+fn piece_end(contents: &[u8], start: usize, end: usize, max_size: usize) -> usize {
+    if end - start <= max_size {
+        return end;
+    }
+    let mut split = start + max_size;
+    while split > start + 1 && (contents[split] & 0xc0) == 0x80 {
+        split -= 1;
+    }
+    split
+}
+
+/// The lines of `contents`, each with its `\n`, split where they're longer than `max_size`
+fn pieces(contents: &[u8], max_size: usize) -> Vec<Piece> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    for (line, text) in contents.split_inclusive(|&byte| byte == b'\n').enumerate() {
+        let end = start + text.len();
+        while start < end {
+            let piece_end = piece_end(contents, start, end, max_size);
+            pieces.push(Piece {
+                start,
+                end: piece_end,
+                line: line as u64,
+            });
+            start = piece_end;
+        }
+    }
+    pieces
+}
+
+fn chunk_hash(content: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(b"chunk ");
+    hasher.update(content);
+    hash_string(hasher.finalize().into())
+}
+
+fn new_chunk(contents: &[u8], bytes: Range<usize>, start_line: u64, end_line: u64) -> Chunk {
+    Chunk {
+        start_byte: bytes.start as u64,
+        end_byte: bytes.end as u64,
+        start_line,
+        end_line,
+        hash: chunk_hash(&contents[bytes]),
+    }
+}
+
+/// Split the file at `path` with `contents` into chunks as `options` says. Empty contents have
+/// none
+pub fn chunk(path: &Path, contents: &[u8], options: ChunkOptions) -> Vec<Chunk> {
+    let max_size = options.max_size.max(1);
+    let pieces = pieces(contents, max_size);
+    let mut chunks = Vec::new();
+    match Language::of(path).filter(|_| options.syntax_aware) {
+        Some(language) => {
+            let splitter = SyntaxSplitter {
+                contents,
+                lines: syntax::line_ranges(contents),
+                ends: syntax::definition_ends(language, contents),
+                pieces,
+                max_size,
+            };
+            splitter.split(0..splitter.lines.len(), 0, &mut chunks);
+        }
+        None => line_chunks(contents, &pieces, max_size, options.overlap, &mut chunks),
+    }
+    chunks
+}
+
+/// Add the chunks of lines that `pieces` make up to `chunks`
+fn line_chunks(
+    contents: &[u8],
+    pieces: &[Piece],
+    max_size: usize,
+    overlap: usize,
+    chunks: &mut Vec<Chunk>,
+) {
+    let mut first = 0;
+    while first < pieces.len() {
+        // As many pieces as fit, and always at least one
+        let start = pieces[first].start;
+        let mut last = first;
+        while last + 1 < pieces.len() && pieces[last + 1].end - start <= max_size {
+            last += 1;
+        }
+        let end = pieces[last].end;
+        chunks.push(new_chunk(
+            contents,
+            start..end,
+            pieces[first].line,
+            pieces[last].line,
+        ));
+
+        if last + 1 == pieces.len() {
+            break;
+        }
+
+        // The next chunk repeats the last pieces that fit in the overlap, but always moves on
+        let mut next = last + 1;
+        while next > first + 1 && end - pieces[next - 1].start <= overlap {
+            next -= 1;
+        }
+        first = next;
+    }
+}
+
+/// Splits a file between its definitions, with `syntax::definition_ends`
+struct SyntaxSplitter<'a> {
+    contents: &'a [u8],
+    /// Where each line is in `contents`, with its `\n`
+    lines: Vec<Range<usize>>,
+    ends: Vec<Option<usize>>,
+    pieces: Vec<Piece>,
+    max_size: usize,
+}
+
+impl SyntaxSplitter<'_> {
+    fn size(&self, lines: &Range<usize>) -> usize {
+        self.lines[lines.end - 1].end - self.lines[lines.start].start
+    }
+
+    /// Add the chunks of `lines` to `chunks`, split between the definitions that end at `depth`
+    /// or deeper
+    fn split(&self, lines: Range<usize>, depth: usize, chunks: &mut Vec<Chunk>) {
+        if lines.is_empty() {
+            return;
+        }
+        if self.size(&lines) <= self.max_size {
+            return self.push(lines, chunks);
+        }
+        if depth > MAX_DEPTH {
+            // Nothing left to split by but lines
+            let first = self
+                .pieces
+                .partition_point(|piece| piece.line < lines.start as u64);
+            let end = self
+                .pieces
+                .partition_point(|piece| piece.line < lines.end as u64);
+            let pieces = &self.pieces[first..end];
+            return line_chunks(self.contents, pieces, self.max_size, 0, chunks);
+        }
+
+        let definitions = syntax::definitions(&self.ends, lines.clone(), depth);
+        if definitions.len() == 1 {
+            return self.split(lines, depth + 1, chunks);
+        }
+
+        // As many definitions in a chunk as fit, and those that don't fit split on their own
+        let mut current: Option<Range<usize>> = None;
+        for definition in definitions {
+            if self.size(&definition) > self.max_size {
+                if let Some(current) = current.take() {
+                    self.push(current, chunks);
+                }
+                self.split(definition, depth + 1, chunks);
+                continue;
+            }
+            current = match current {
+                Some(current) if self.size(&(current.start..definition.end)) <= self.max_size => {
+                    Some(current.start..definition.end)
+                }
+                Some(current) => {
+                    self.push(current, chunks);
+                    Some(definition)
+                }
+                None => Some(definition),
+            };
+        }
+        if let Some(current) = current {
+            self.push(current, chunks);
+        }
+    }
+
+    fn push(&self, lines: Range<usize>, chunks: &mut Vec<Chunk>) {
+        let bytes = self.lines[lines.start].start..self.lines[lines.end - 1].end;
+        let (start_line, end_line) = (lines.start as u64, lines.end as u64 - 1);
+        chunks.push(new_chunk(self.contents, bytes, start_line, end_line));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(contents: &str, max_size: usize, overlap: usize) -> Vec<(&str, u64, u64)> {
+        let options = ChunkOptions {
+            max_size,
+            overlap,
+            syntax_aware: false,
+        };
+        chunk(Path::new("a.txt"), contents.as_bytes(), options)
+            .into_iter()
+            .map(|chunk| {
+                let text = &contents[chunk.start_byte as usize..chunk.end_byte as usize];
+                (text, chunk.start_line, chunk.end_line)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_chunk() {
+        let path = Path::new("a.txt");
+        assert!(chunk(path, b"", ChunkOptions::default()).is_empty());
+        assert_eq!(ranges("one\ntwo\n", 100, 0), [("one\ntwo\n", 0, 1)]);
+        assert_eq!(
+            ranges("aaa\nbbb\nccc\nddd", 8, 0),
+            [("aaa\nbbb\n", 0, 1), ("ccc\nddd", 2, 3)]
+        );
+
+        // Each chunk repeats the lines that fit in the overlap
+        assert_eq!(
+            ranges("aaa\nbbb\nccc\nddd", 8, 4),
+            [
+                ("aaa\nbbb\n", 0, 1),
+                ("bbb\nccc\n", 1, 2),
+                ("ccc\nddd", 2, 3)
+            ]
+        );
+
+        // A long line is split, but not inside a character
+        assert_eq!(ranges("abcdéf\nx", 5, 0), [("abcd", 0, 0), ("éf\nx", 0, 1)]);
+
+        // The same content hashes the same in any file
+        let options = ChunkOptions {
+            max_size: 4,
+            overlap: 0,
+            syntax_aware: false,
+        };
+        let chunks = chunk(path, b"abc\nabc\n", options);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].hash, chunks[1].hash);
+        assert_ne!(chunks[0].hash, chunk(path, b"abd\n", options)[0].hash);
+    }
+
+    #[test]
+    fn test_chunk_syntax_aware() {
+        let options = ChunkOptions {
+            max_size: 40,
+            overlap: 10,
+            syntax_aware: true,
+        };
+        let ranges = |path: &str, contents: &'static str| -> Vec<(&str, u64, u64)> {
+            chunk(Path::new(path), contents.as_bytes(), options)
+                .into_iter()
+                .map(|chunk| {
+                    let text = &contents[chunk.start_byte as usize..chunk.end_byte as usize];
+                    (text, chunk.start_line, chunk.end_line)
+                })
+                .collect()
+        };
+
+        // Whole functions in each chunk, with the comments before them
+        let rust = "fn a() {}\n// b\nfn b() {\n    b();\n}\nfn c() {}\n";
+        assert_eq!(
+            ranges("lib.rs", rust),
+            [
+                ("fn a() {}\n// b\nfn b() {\n    b();\n}\n", 0, 4),
+                ("fn c() {}\n", 5, 5)
+            ]
+        );
+
+        // A class too big for one chunk is split between its methods
+        let python = "\
+class A:
+    def b(s):
+        return 1
+
+    def c(s):
+        return 2
+";
+        assert_eq!(
+            ranges("a.py", python),
+            [
+                ("class A:\n    def b(s):\n        return 1\n", 0, 2),
+                ("\n    def c(s):\n        return 2\n", 3, 5)
+            ]
+        );
+
+        // A statement with nothing in it to split by is split by lines, without overlap
+        let long = "fn a() {\n    f(\n        1111111111,\n        2222222222,\n    );\n}\n";
+        assert_eq!(
+            ranges("lib.rs", long),
+            [
+                ("fn a() {\n    f(\n        1111111111,\n", 0, 2),
+                ("        2222222222,\n    );\n", 3, 4),
+                ("}\n", 5, 5)
+            ]
+        );
+
+        // A language we don't know is split by lines, with overlap
+        assert_eq!(
+            ranges("a.txt", rust),
+            [
+                ("fn a() {}\n// b\nfn b() {\n    b();\n}\n", 0, 4),
+                ("}\nfn c() {}\n", 4, 5)
+            ]
+        );
+    }
+}
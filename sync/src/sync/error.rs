@@ -0,0 +1,45 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum SyncError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Error walking directory: {0}")]
+    Walk(#[from] ignore::Error),
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error("Database error: {0}")]
+    Database(#[from] rusqlite::Error),
+
+    /// An index file exists but its contents can't be understood
+    #[error("Corrupted index: {0}")]
+    CorruptedIndex(String),
+
+    /// Another sync holds the lock on this part of the index, and `SyncOptions::lock_wait`
+    /// said not to wait (any longer) for it
+    #[error("The index is locked by another sync: {0}")]
+    Locked(String),
+
+    /// The index's filesystem is read-only, or the index can't be written to for lack of
+    /// permission. Syncs find out before they change anything
+    #[error("The index can't be written to: {0}")]
+    ReadOnlyIndex(String),
+
+    /// There's less space left where the index is kept than `SyncOptions::min_free_space`, or
+    /// it ran out part way through a sync
+    #[error("Not enough disk space for the index: {0}")]
+    DiskFull(String),
+
+    /// The caller stopped the sync part way through, from its callback
+    #[error("The sync was cancelled")]
+    Cancelled,
+
+    #[error("Could not determine the home directory")]
+    MissingHomeDir,
+}
+
+pub type Result<T> = std::result::Result<T, SyncError>;
@@ -0,0 +1,529 @@
+//! Where a workspace's files are read from, and where the index's caches are kept, for hosts
+//! that serve them some other way than the OS: a browser or web worker, VS Code for the web, an
+//! IDE's virtual files, or memory in tests
+//!
+//! Without `SyncOptions::file_system`, a sync walks and reads the workspace straight from the
+//! disk, with everything the OS offers (the ignore crate's walk, mmap, io_uring, extended
+//! attributes). With it, the workspace is walked and read through the `FileSystem` given
+//! instead, and the home directory the index goes under is looked up there too.
+//! `SyncOptions::index_file_system` does the same for everything in the index: the trees, the
+//! file caches (`DiskSet`s, their Bloom filters and sorted lengths), rev_tags, the journal, the
+//! tags' sync times and the locks. That's what a wasm32 build has to use, as its std can't
+//! reach a disk.
+
+use super::{atomic, error::SyncError, lock, options::LockWait};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::SystemTime,
+};
+
+/// What a `FileSystem` knows about a path
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub is_dir: bool,
+
+    /// The size of a file, in bytes
+    pub len: u64,
+
+    /// When a file was last written, if that's known. Files without one are read by every sync
+    pub modified: Option<SystemTime>,
+}
+
+/// Access to the workspace and the home directory, for syncs made with
+/// `SyncOptions::file_system`, and to the index, with `SyncOptions::index_file_system`. Paths
+/// are absolute, as the tag's directory and its descendants
+pub trait FileSystem: fmt::Debug + Send + Sync {
+    /// What's at `path`, following symlinks
+    fn metadata(&self, path: &Path) -> io::Result<FileMetadata>;
+
+    /// The names of the entries in the directory at `path`, in any order
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>>;
+
+    /// The contents of the file at `path`
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// The user's home directory, under which the index is kept unless it's given elsewhere
+    fn home_dir(&self) -> Option<PathBuf>;
+
+    /// Open the file at `path` for reading and writing, creating it empty if it isn't there
+    fn open(&self, path: &Path) -> io::Result<Box<dyn FileHandle>>;
+
+    /// Replace the file at `path` with `contents` in one step, so that a reader (or a crash)
+    /// never sees half of it. With `fsync`, the new contents have to survive a power cut too
+    fn write(&self, path: &Path, contents: &[u8], fsync: bool) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Create the directory at `path`, and any above it that are missing
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Remove the empty directory at `path`. Without an override there's nothing to remove,
+    /// as for a `FileSystem` whose directories are only there through the files in them
+    fn remove_dir(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Move the file or directory at `from` to `to`, which has to be free already. Without an
+    /// override nothing can be moved, which only costs an index from an older version some of
+    /// what it had synced
+    fn rename(&self, from: &Path, _to: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("can't move {}", from.display()),
+        ))
+    }
+
+    /// Take the lock at `path` without waiting, for as long as what's returned is kept, or None
+    /// if it's held already. Without an override every lock is granted, which is enough for a
+    /// host that never runs two syncs on the same index at once
+    fn try_lock(&self, _path: &Path) -> io::Result<Option<Box<dyn Send>>> {
+        Ok(Some(Box::new(())))
+    }
+}
+
+/// An open file, from `FileSystem::open`
+pub trait FileHandle: Read + Write + Seek + Send {
+    /// The size of the file, in bytes
+    fn size(&self) -> io::Result<u64>;
+
+    /// Cut the file short at `size` bytes, or extend it with zeros
+    fn set_size(&mut self, size: u64) -> io::Result<()>;
+}
+
+impl FileHandle for File {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn set_size(&mut self, size: u64) -> io::Result<()> {
+        self.set_len(size)
+    }
+}
+
+/// The OS's own filesystem, which is what a sync uses without `SyncOptions::file_system`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OsFileSystem;
+
+impl FileSystem for OsFileSystem {
+    fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        let metadata = fs::metadata(path)?;
+        Ok(FileMetadata {
+            is_dir: metadata.is_dir(),
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        fs::read_dir(path)?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect()
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn home_dir(&self) -> Option<PathBuf> {
+        local_home_dir()
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn write(&self, path: &Path, contents: &[u8], fsync: bool) -> io::Result<()> {
+        atomic::write(path, contents, fsync)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn try_lock(&self, path: &Path) -> io::Result<Option<Box<dyn Send>>> {
+        match lock::lock(path, LockWait::Fail) {
+            Ok(held) => Ok(Some(Box::new(held))),
+            Err(SyncError::Locked(_)) => Ok(None),
+            Err(SyncError::Io(err)) => Err(err),
+            Err(err) => Err(io::Error::other(err.to_string())),
+        }
+    }
+}
+
+/// The home directory of the user running the process. On wasm32 there's no telling, so the
+/// host has to give an index root, or a `FileSystem` with a home directory of its own
+pub(crate) fn local_home_dir() -> Option<PathBuf> {
+    #[cfg(not(target_arch = "wasm32"))]
+    return homedir::get_my_home().ok().flatten();
+    #[cfg(target_arch = "wasm32")]
+    None
+}
+
+/// Files kept in memory, for hosts that hand the workspace over themselves and for tests.
+/// Directories are whatever the files' paths have in them, so there are no empty ones
+#[derive(Debug, Default)]
+pub struct MemoryFileSystem {
+    files: MemoryFiles,
+    home_dir: Option<PathBuf>,
+
+    /// The paths of the locks that are held, which only this process can take
+    locks: Arc<Mutex<BTreeSet<PathBuf>>>,
+}
+
+#[derive(Debug)]
+struct MemoryFile {
+    contents: Vec<u8>,
+    modified: SystemTime,
+}
+
+impl MemoryFile {
+    fn new(contents: Vec<u8>) -> Self {
+        MemoryFile {
+            contents,
+            modified: SystemTime::now(),
+        }
+    }
+}
+
+type MemoryFiles = Arc<RwLock<BTreeMap<PathBuf, MemoryFile>>>;
+
+impl MemoryFileSystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Look the index up under `home_dir`, rather than having no home directory
+    pub fn with_home_dir(mut self, home_dir: impl Into<PathBuf>) -> Self {
+        self.home_dir = Some(home_dir.into());
+        self
+    }
+
+    /// Add the file at `path`, or replace what's in it
+    pub fn insert(&self, path: impl Into<PathBuf>, contents: impl Into<Vec<u8>>) {
+        let mut files = self.files.write().unwrap();
+        files.insert(path.into(), MemoryFile::new(contents.into()));
+    }
+
+    /// Remove the file at `path`, returning whether there was one
+    pub fn remove(&self, path: impl AsRef<Path>) -> bool {
+        self.files.write().unwrap().remove(path.as_ref()).is_some()
+    }
+
+    fn not_found(path: &Path) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} does not exist", path.display()),
+        )
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        let files = self.files.read().unwrap();
+        if let Some(file) = files.get(path) {
+            return Ok(FileMetadata {
+                is_dir: false,
+                len: file.contents.len() as u64,
+                modified: Some(file.modified),
+            });
+        }
+        // The first path after it in order is under it, if anything is
+        match files.range(path.to_path_buf()..).next() {
+            Some((first, _)) if first.starts_with(path) => Ok(FileMetadata {
+                is_dir: true,
+                len: 0,
+                modified: None,
+            }),
+            _ => Err(Self::not_found(path)),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        let files = self.files.read().unwrap();
+        let mut names: Vec<OsString> = files
+            .range(path.to_path_buf()..)
+            .map_while(|(file, _)| file.strip_prefix(path).ok())
+            .filter_map(|relative| relative.iter().next())
+            .map(|name| name.to_os_string())
+            .collect();
+        names.dedup();
+        match names.is_empty() {
+            true => Err(Self::not_found(path)),
+            false => Ok(names),
+        }
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let files = self.files.read().unwrap();
+        files
+            .get(path)
+            .map(|file| file.contents.clone())
+            .ok_or_else(|| Self::not_found(path))
+    }
+
+    fn home_dir(&self) -> Option<PathBuf> {
+        self.home_dir.clone()
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+        let mut files = self.files.write().unwrap();
+        files
+            .entry(path.to_path_buf())
+            .or_insert_with(|| MemoryFile::new(Vec::new()));
+        Ok(Box::new(MemoryFileHandle {
+            files: self.files.clone(),
+            path: path.to_path_buf(),
+            position: 0,
+        }))
+    }
+
+    fn write(&self, path: &Path, contents: &[u8], _fsync: bool) -> io::Result<()> {
+        self.insert(path, contents);
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        match self.remove(path) {
+            true => Ok(()),
+            false => Err(Self::not_found(path)),
+        }
+    }
+
+    /// Directories only exist through the files in them, so there's nothing to create
+    fn create_dir_all(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Moves the file at `from`, or every file under it
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut files = self.files.write().unwrap();
+        let moved: Vec<PathBuf> = files
+            .range(from.to_path_buf()..)
+            .map(|(path, _)| path)
+            .take_while(|path| path.starts_with(from))
+            .cloned()
+            .collect();
+        if moved.is_empty() {
+            return Err(Self::not_found(from));
+        }
+        for path in moved {
+            let file = files.remove(&path).unwrap();
+            let rest = path.strip_prefix(from).unwrap();
+            match rest.as_os_str().is_empty() {
+                true => files.insert(to.to_path_buf(), file),
+                false => files.insert(to.join(rest), file),
+            };
+        }
+        Ok(())
+    }
+
+    fn try_lock(&self, path: &Path) -> io::Result<Option<Box<dyn Send>>> {
+        let mut locks = self.locks.lock().unwrap();
+        if !locks.insert(path.to_path_buf()) {
+            return Ok(None);
+        }
+        Ok(Some(Box::new(MemoryLock {
+            locks: self.locks.clone(),
+            path: path.to_path_buf(),
+        })))
+    }
+}
+
+/// A lock taken with `MemoryFileSystem::try_lock`, released on drop
+struct MemoryLock {
+    locks: Arc<Mutex<BTreeSet<PathBuf>>>,
+    path: PathBuf,
+}
+
+impl Drop for MemoryLock {
+    fn drop(&mut self) {
+        self.locks.lock().unwrap().remove(&self.path);
+    }
+}
+
+/// A file in a `MemoryFileSystem`, which every read and write goes straight to. Once the file
+/// is removed, they fail
+struct MemoryFileHandle {
+    files: MemoryFiles,
+    path: PathBuf,
+    position: u64,
+}
+
+impl MemoryFileHandle {
+    fn with_file<T>(&self, f: impl FnOnce(&mut MemoryFile) -> T) -> io::Result<T> {
+        let mut files = self.files.write().unwrap();
+        match files.get_mut(&self.path) {
+            Some(file) => Ok(f(file)),
+            None => Err(MemoryFileSystem::not_found(&self.path)),
+        }
+    }
+}
+
+impl Read for MemoryFileHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let position = self.position as usize;
+        let read = self.with_file(|file| {
+            let rest = file.contents.get(position..).unwrap_or_default();
+            let len = rest.len().min(buf.len());
+            buf[..len].copy_from_slice(&rest[..len]);
+            len
+        })?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for MemoryFileHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let position = self.position as usize;
+        self.with_file(|file| {
+            let end = position + buf.len();
+            if file.contents.len() < end {
+                file.contents.resize(end, 0);
+            }
+            file.contents[position..end].copy_from_slice(buf);
+            file.modified = SystemTime::now();
+        })?;
+        self.position += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFileHandle {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(position) => (position, 0),
+            SeekFrom::End(offset) => (self.size()?, offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+        self.position = base.checked_add_signed(offset).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position")
+        })?;
+        Ok(self.position)
+    }
+}
+
+impl FileHandle for MemoryFileHandle {
+    fn size(&self) -> io::Result<u64> {
+        self.with_file(|file| file.contents.len() as u64)
+    }
+
+    fn set_size(&mut self, size: u64) -> io::Result<()> {
+        self.with_file(|file| {
+            file.contents.resize(size as usize, 0);
+            file.modified = SystemTime::now();
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_file_system() {
+        let fs = MemoryFileSystem::new();
+        fs.insert("/work/a.txt", "A");
+        fs.insert("/work/src/b.rs", "B");
+        fs.insert("/work/src/c.rs", "C");
+        fs.insert("/workshop/d.txt", "D");
+
+        let dir = fs.metadata(Path::new("/work/src")).unwrap();
+        assert!(dir.is_dir);
+        let file = fs.metadata(Path::new("/work/a.txt")).unwrap();
+        assert_eq!((file.is_dir, file.len), (false, 1));
+        assert!(fs.metadata(Path::new("/work/s")).is_err());
+
+        assert_eq!(fs.read_dir(Path::new("/work")).unwrap(), ["a.txt", "src"]);
+        assert_eq!(fs.read_dir(Path::new("/")).unwrap(), ["work", "workshop"]);
+        assert_eq!(fs.read(Path::new("/work/src/c.rs")).unwrap(), b"C");
+        assert!(fs.read(Path::new("/work/src")).is_err());
+
+        assert!(fs.remove("/work/a.txt"));
+        assert!(!fs.remove("/work/a.txt"));
+        assert_eq!(fs.read_dir(Path::new("/work")).unwrap(), ["src"]);
+        assert_eq!(fs.home_dir(), None);
+
+        fs.rename(Path::new("/work/src"), Path::new("/work/lib"))
+            .unwrap();
+        assert_eq!(fs.read_dir(Path::new("/work")).unwrap(), ["lib"]);
+        assert_eq!(fs.read(Path::new("/work/lib/b.rs")).unwrap(), b"B");
+        fs.rename(Path::new("/workshop/d.txt"), Path::new("/d.txt"))
+            .unwrap();
+        assert_eq!(fs.read(Path::new("/d.txt")).unwrap(), b"D");
+        assert!(fs
+            .rename(Path::new("/work/src"), Path::new("/src"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_memory_file_handle() {
+        let fs = MemoryFileSystem::new();
+        let path = Path::new("/index/.index_cache");
+        let mut file = fs.open(path).unwrap();
+        assert_eq!(file.size().unwrap(), 0);
+
+        file.write_all(b"hello world").unwrap();
+        file.seek(SeekFrom::Start(6)).unwrap();
+        file.write_all(b"there").unwrap();
+        assert_eq!(fs.read(path).unwrap(), b"hello there");
+
+        file.seek(SeekFrom::End(-5)).unwrap();
+        let mut word = String::new();
+        file.read_to_string(&mut word).unwrap();
+        assert_eq!(word, "there");
+
+        file.set_size(5).unwrap();
+        assert_eq!(fs.metadata(path).unwrap().len, 5);
+        // Opening it again keeps what's there
+        assert_eq!(fs.open(path).unwrap().size().unwrap(), 5);
+
+        fs.write(path, b"new", false).unwrap();
+        assert_eq!(file.size().unwrap(), 3);
+        fs.remove_file(path).unwrap();
+        assert!(file.size().is_err());
+        assert!(fs.remove_file(path).is_err());
+    }
+
+    #[test]
+    fn test_memory_lock() {
+        let fs = MemoryFileSystem::new();
+        let path = Path::new("/index/default.lock");
+        let held = fs.try_lock(path).unwrap().unwrap();
+        assert!(fs.try_lock(path).unwrap().is_none());
+        assert!(fs
+            .try_lock(Path::new("/index/other.lock"))
+            .unwrap()
+            .is_some());
+        drop(held);
+        assert!(fs.try_lock(path).unwrap().is_some());
+    }
+}
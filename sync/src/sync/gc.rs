@@ -0,0 +1,409 @@
+//! Clearing out what a provider's caches and rev_tags still hold for tags that are gone
+
+#[cfg(not(target_arch = "wasm32"))]
+use super::index_db::IndexDb;
+use super::{
+    error::Result,
+    file_system::FileSystem,
+    index_root::IndexRoot,
+    journal, lock,
+    merkle::{hash_string, ObjectHash},
+    objects::ObjectStore,
+    options::{IndexStorage, SyncOptions},
+    read_pending, rebuild_cache,
+    rev_tags::RevTags,
+    DiskSet, IndexCache, OwnedTag, SyncResultItem,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    path::PathBuf,
+};
+
+/// The result of `gc`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GcResults {
+    /// Content that no indexed tag references any more, to delete from downstream indexes.
+    /// These have no path
+    pub delete: Vec<SyncResultItem>,
+
+    /// References in rev_tags from tags that are no longer indexed
+    pub removed_references: usize,
+
+    /// Hashes taken out of the global cache and the tags' caches
+    pub removed_cache_entries: usize,
+
+    /// Content removed from the object store (see `SyncOptions::store_objects`)
+    #[serde(default)]
+    pub removed_objects: usize,
+
+    /// How much smaller the provider's cache files, databases and objects are afterwards.
+    /// Always 0 for a dry run
+    pub reclaimed_bytes: u64,
+}
+
+/// Remove what the provider's rev_tags and caches hold that no indexed tag accounts for:
+/// references from tags whose directory is gone, hashes in the global cache with no references
+/// left, hashes in a tag's cache that it doesn't reference, and saved content that no tag
+/// references or is still to compute. Unlike `repair_index`, the trees aren't read, so this
+/// never adds anything back. With `dry_run`, nothing is written
+pub fn gc(provider_id: &str, options: &SyncOptions) -> Result<GcResults> {
+    let index_root = &options.resolve_index_root()?;
+    let file_system = &options.resolve_index_file_system();
+    let mut tags: Vec<OwnedTag> = index_root
+        .tag_dirs_in(&**file_system)?
+        .into_iter()
+        .map(|(_, tag)| tag)
+        .filter(|tag| tag.provider_id == provider_id)
+        .collect();
+    tags.sort_by_key(|tag| tag.to_string());
+    let live: HashSet<String> = tags.iter().map(|tag| tag.to_string()).collect();
+
+    file_system.create_dir_all(&index_root.provider_dir(provider_id))?;
+    let lock_path = index_root.provider_lock(provider_id);
+    let _lock = lock::lock_index(options, &lock_path, options.lock_wait)?;
+
+    // An interrupted change is finished first, so it isn't mistaken for garbage
+    let journal_path = index_root.journal(provider_id);
+    if file_system.metadata(&journal_path).is_ok() {
+        match tags.first() {
+            Some(tag) => drop(IndexCache::new(&tag.as_tag(), index_root, options)?),
+            None => journal::remove(&**file_system, &journal_path)?,
+        }
+    }
+
+    // Tags that are gone still have their own caches in the database, where the files went
+    // with their directories
+    let (global, refs, tag_hashes, dead_tags) = match options.storage {
+        IndexStorage::Files => {
+            let global_path = global_cache_path(index_root, provider_id);
+            let global = match file_system.metadata(&global_path).is_ok() {
+                true => DiskSet::read(&**file_system, &global_path)?,
+                false => Vec::new(),
+            };
+            let refs = RevTags::open(index_root, provider_id, options)?.all()?;
+            let mut tag_hashes = Vec::new();
+            for tag in &tags {
+                let path = IndexCache::index_cache_path_for_tag(&tag.as_tag(), index_root);
+                tag_hashes.push(match file_system.metadata(&path).is_ok() {
+                    true => DiskSet::read(&**file_system, &path)?,
+                    false => Vec::new(),
+                });
+            }
+            (global, refs, tag_hashes, Vec::<(usize, String)>::new())
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        IndexStorage::Sqlite => {
+            let index_db = IndexDb::open(index_root)?;
+            for tag in &tags {
+                index_db.migrate_files(&tag.as_tag(), index_root, &**file_system)?;
+            }
+            let mut tag_hashes = Vec::new();
+            for tag in &tags {
+                tag_hashes.push(index_db.tag_hashes(&tag.to_string())?);
+            }
+            let dead_tags = index_db
+                .cached_tags()?
+                .into_iter()
+                .filter(|tag| !live.contains(tag))
+                .filter(|tag| {
+                    tag.parse::<OwnedTag>()
+                        .is_ok_and(|tag| tag.provider_id == provider_id)
+                })
+                .map(|tag| Ok((index_db.tag_hashes(&tag)?.len(), tag)))
+                .collect::<Result<Vec<_>>>()?;
+            (
+                index_db.global_hashes(provider_id)?,
+                index_db.all_rev_tags(provider_id)?,
+                tag_hashes,
+                dead_tags,
+            )
+        }
+    };
+
+    let mut results = GcResults {
+        removed_cache_entries: dead_tags.iter().map(|(entries, _)| entries).sum(),
+        ..GcResults::default()
+    };
+    let kept_refs: Vec<(ObjectHash, String)> = refs
+        .iter()
+        .filter(|(_, tag)| live.contains(tag))
+        .cloned()
+        .collect();
+    results.removed_references = refs.len() - kept_refs.len();
+    let mut referenced: HashMap<&str, HashSet<ObjectHash>> = HashMap::new();
+    for (hash, tag) in &kept_refs {
+        referenced.entry(tag).or_default().insert(*hash);
+    }
+    let any_referenced: HashSet<ObjectHash> = kept_refs.iter().map(|(hash, _)| *hash).collect();
+
+    let new_global: HashSet<ObjectHash> = global
+        .iter()
+        .filter(|hash| any_referenced.contains(*hash))
+        .copied()
+        .collect();
+    results.removed_cache_entries += global.len() - new_global.len();
+    let mut new_tag_hashes: Vec<(String, HashSet<ObjectHash>)> = Vec::new();
+    for (tag, hashes) in tags.iter().zip(&tag_hashes) {
+        let tag_str = tag.to_string();
+        let kept: HashSet<ObjectHash> = hashes
+            .iter()
+            .filter(|hash| {
+                referenced
+                    .get(tag_str.as_str())
+                    .is_some_and(|referenced| referenced.contains(*hash))
+            })
+            .copied()
+            .collect();
+        results.removed_cache_entries += hashes.len() - kept.len();
+        new_tag_hashes.push((tag_str, kept));
+    }
+
+    let mut deleted: Vec<&ObjectHash> = global
+        .iter()
+        .chain(refs.iter().map(|(hash, _)| hash))
+        .filter(|hash| !any_referenced.contains(*hash))
+        .collect();
+    deleted.sort();
+    deleted.dedup();
+    results.delete = deleted
+        .into_iter()
+        .map(|hash| SyncResultItem {
+            path: String::new(),
+            relative_path: String::new(),
+            hash: hash_string(*hash),
+            chunks: Vec::new(),
+        })
+        .collect();
+
+    // Files held back by `SyncOptions::defer_compute` aren't referenced yet
+    let mut live_objects = any_referenced.clone();
+    for tag in &tags {
+        let pending = read_pending(&**file_system, &tag.as_tag(), index_root)?;
+        live_objects.extend(pending.into_iter().map(|item| item.hash));
+    }
+    let mut objects = ObjectStore::open(index_root, provider_id, options)?;
+    let (removed_objects, object_bytes) =
+        objects.remove_unreferenced(&live_objects, options.dry_run)?;
+    results.removed_objects = removed_objects;
+
+    let unchanged = results.removed_references == 0 && results.removed_cache_entries == 0;
+    if options.dry_run || unchanged {
+        results.reclaimed_bytes = object_bytes;
+        return Ok(results);
+    }
+    match options.storage {
+        IndexStorage::Files => {
+            let mut paths = vec![
+                global_cache_path(index_root, provider_id),
+                RevTags::path(index_root, provider_id, options),
+            ];
+            paths.extend(
+                tags.iter()
+                    .map(|tag| IndexCache::index_cache_path_for_tag(&tag.as_tag(), index_root)),
+            );
+            let before = total_size(&**file_system, &paths)?;
+
+            if new_global.len() < global.len() {
+                drop(rebuild_cache(file_system, &paths[0], true, new_global)?);
+            }
+            for ((tag_path, (_, kept)), hashes) in
+                paths[2..].iter().zip(new_tag_hashes).zip(&tag_hashes)
+            {
+                if kept.len() < hashes.len() {
+                    drop(rebuild_cache(file_system, tag_path, false, kept)?);
+                }
+            }
+            let rev_tags = RevTags::open(index_root, provider_id, options)?;
+            if results.removed_references > 0 {
+                rev_tags.replace_all(&kept_refs)?;
+                rev_tags.vacuum()?;
+            }
+            drop(rev_tags);
+
+            results.reclaimed_bytes =
+                before.saturating_sub(total_size(&**file_system, &paths)?) + object_bytes;
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        IndexStorage::Sqlite => {
+            let paths = [index_root.index_db()];
+            let before = total_size(&**file_system, &paths)?;
+
+            let index_db = IndexDb::open(index_root)?;
+            index_db.replace_provider(provider_id, &new_global, &new_tag_hashes, &kept_refs)?;
+            for (_, tag) in &dead_tags {
+                index_db.delete_tag(tag)?;
+            }
+            index_db.vacuum()?;
+            drop(index_db);
+
+            results.reclaimed_bytes =
+                before.saturating_sub(total_size(&**file_system, &paths)?) + object_bytes;
+        }
+    }
+    Ok(results)
+}
+
+fn global_cache_path(index_root: &IndexRoot, provider_id: &str) -> PathBuf {
+    index_root.provider_dir(provider_id).join(".index_cache")
+}
+
+/// The size of the files at `paths`, along with the ones SQLite and the caches keep next to them
+fn total_size(file_system: &dyn FileSystem, paths: &[PathBuf]) -> Result<u64> {
+    let mut total = 0;
+    for path in paths {
+        for extension in ["", ".bloom", ".sorted", "-wal", "-shm"] {
+            let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+            file_name.push(extension);
+            match file_system.metadata(&path.with_file_name(file_name)) {
+                Ok(metadata) => total += metadata.len,
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sync::{
+            merkle::parse_hash, pack_objects, read_object, sync, verify_index, OsFileSystem, Tag,
+            ITEM_SIZE,
+        },
+        utils::{temp_index_root, TempDirBuilder},
+    };
+    use std::{fs, sync::Arc};
+
+    #[test]
+    fn test_gc() {
+        let dir1 = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("b.txt", "B")
+            .create();
+        let dir2 = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("c.txt", "C")
+            .create();
+        let tag1 = &Tag {
+            dir: dir1.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let tag2 = &Tag {
+            dir: dir2.path(),
+            ..*tag1
+        };
+        let (tag1, tag2) = (&tag1.canonicalize(), &tag2.canonicalize());
+
+        for storage in [IndexStorage::Files, IndexStorage::Sqlite] {
+            let (_index_dir, index_root) = temp_index_root();
+            let options = SyncOptions::new()
+                .index_root(index_root.clone())
+                .storage(storage);
+            let mut hashes = HashMap::new();
+            for tag in [tag1, tag2] {
+                for file in sync(&tag.as_tag(), &options).unwrap().compute {
+                    hashes.insert(file.relative_path, parse_hash(&file.hash).unwrap());
+                }
+            }
+            assert_eq!(gc("default", &options).unwrap(), GcResults::default());
+
+            // The second tag's directory is removed by hand, leaving its references behind
+            fs::remove_dir_all(index_root.tag_dir(&tag2.as_tag())).unwrap();
+            let mut removed_cache_entries = match storage {
+                // Its cache, and the hash of c.txt in the global cache
+                IndexStorage::Sqlite => 3,
+                IndexStorage::Files => 1,
+            };
+            if storage == IndexStorage::Files {
+                // And the first tag's cache has a hash it doesn't reference
+                let path = IndexCache::index_cache_path_for_tag(&tag1.as_tag(), &index_root);
+                let mut tag_cache = DiskSet::new(Arc::new(OsFileSystem), &path).unwrap();
+                tag_cache.add(&[7; ITEM_SIZE]).unwrap();
+                tag_cache.flush().unwrap();
+                removed_cache_entries += 1;
+            }
+
+            let expected = GcResults {
+                delete: vec![SyncResultItem {
+                    path: String::new(),
+                    relative_path: String::new(),
+                    hash: hash_string(hashes["c.txt"]),
+                    chunks: Vec::new(),
+                }],
+                removed_references: 2,
+                removed_cache_entries,
+                removed_objects: 0,
+                reclaimed_bytes: 0,
+            };
+            let dry_run = options.clone().dry_run(true);
+            assert_eq!(gc("default", &dry_run).unwrap(), expected);
+
+            let results = gc("default", &options).unwrap();
+            // The database may have had as much waiting in its write-ahead log as the vacuum
+            // saves, but the cache files always shrink
+            assert!(storage == IndexStorage::Sqlite || results.reclaimed_bytes > 0);
+            assert_eq!(
+                results,
+                GcResults {
+                    reclaimed_bytes: results.reclaimed_bytes,
+                    ..expected
+                }
+            );
+            assert!(verify_index(&tag1.as_tag(), &options)
+                .unwrap()
+                .is_consistent());
+            assert_eq!(gc("default", &options).unwrap(), GcResults::default());
+        }
+    }
+
+    #[test]
+    fn test_gc_objects() {
+        let dir1 = TempDirBuilder::new().add("a.txt", "A").create();
+        let dir2 = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("c.txt", "C")
+            .create();
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new()
+            .index_root(index_root.clone())
+            .store_objects(true);
+        let mut hashes = HashMap::new();
+        for dir in [&dir1, &dir2] {
+            let tag = Tag {
+                dir: dir.path(),
+                branch: "main",
+                provider_id: "default",
+            };
+            for file in sync(&tag, &options).unwrap().compute {
+                hashes.insert(file.relative_path, parse_hash(&file.hash).unwrap());
+            }
+        }
+        let packed = pack_objects("default", &options).unwrap();
+        assert_eq!(packed.packed_objects, 2);
+
+        // The content only the second tag had goes with it, packed or not
+        let tag2 = Tag {
+            dir: dir2.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        fs::remove_dir_all(index_root.tag_dir(&tag2.canonicalize().as_tag())).unwrap();
+        let results = gc("default", &options).unwrap();
+        assert_eq!(results.removed_objects, 1);
+        assert_eq!(
+            read_object(hashes["c.txt"], "default", &options).unwrap(),
+            None
+        );
+        assert_eq!(
+            read_object(hashes["a.txt"], "default", &options).unwrap(),
+            Some(b"A\n".to_vec())
+        );
+        assert_eq!(gc("default", &options).unwrap(), GcResults::default());
+    }
+}
@@ -0,0 +1,604 @@
+//! Just enough gzip (RFC 1951 and 1952) to compress tree records without another dependency
+//!
+//! Compression uses LZ77 with the fixed Huffman codes, which is a fraction of the work of
+//! choosing codes per block and still shrinks the repetitive paths in a tree well.
+//! Decompression handles everything gzip can produce, so files compressed by other tools load
+//! too
+
+const MAGIC: [u8; 2] = [0x1f, 0x8b];
+const METHOD_DEFLATE: u8 = 8;
+
+const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// The order code length code lengths are sent in, for dynamic blocks
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+pub(crate) fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+pub(crate) fn compress(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    writer.bytes.extend_from_slice(&MAGIC);
+    // Method, no flags, no mtime, no extra flags, unknown OS. Leaving out the mtime keeps the
+    // output the same for the same input
+    writer
+        .bytes
+        .extend_from_slice(&[METHOD_DEFLATE, 0, 0, 0, 0, 0, 0, 0xff]);
+
+    // A single final block with the fixed codes
+    writer.write_bits(1, 1);
+    writer.write_bits(1, 2);
+    deflate_fixed(data, &mut writer);
+    write_fixed_literal(&mut writer, 256);
+    writer.align();
+
+    writer.bytes.extend_from_slice(&crc32(data).to_le_bytes());
+    writer
+        .bytes
+        .extend_from_slice(&(data.len() as u32).to_le_bytes());
+    writer.bytes
+}
+
+pub(crate) fn decompress(data: &[u8]) -> Result<Vec<u8>, String> {
+    decompress_at_most(data, usize::MAX)
+}
+
+/// Decompress `data`, giving up once more than `limit` bytes have come out of it, so that a
+/// small file can't fill the memory
+pub(crate) fn decompress_at_most(data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    if data.len() < 18 || !is_gzip(data) {
+        return Err("not gzip".to_string());
+    }
+    if data[2] != METHOD_DEFLATE {
+        return Err(format!("unknown compression method {}", data[2]));
+    }
+
+    let flags = data[3];
+    let mut pos = 10;
+    let truncated = || "gzip header ended early".to_string();
+    if flags & 0x04 != 0 {
+        let extra = data.get(pos..pos + 2).ok_or_else(truncated)?;
+        pos += 2 + u16::from_le_bytes([extra[0], extra[1]]) as usize;
+    }
+    for flag in [0x08, 0x10] {
+        // Zero-terminated file name and comment
+        if flags & flag != 0 {
+            let len = data
+                .get(pos..)
+                .and_then(|rest| rest.iter().position(|&b| b == 0))
+                .ok_or_else(truncated)?;
+            pos += len + 1;
+        }
+    }
+    if flags & 0x02 != 0 {
+        pos += 2;
+    }
+    if pos > data.len() {
+        return Err(truncated());
+    }
+
+    let mut reader = BitReader {
+        data,
+        pos,
+        bit_buf: 0,
+        bit_count: 0,
+    };
+    let out = inflate(&mut reader, limit)?;
+
+    let trailer = data
+        .get(reader.pos..reader.pos + 8)
+        .ok_or_else(|| "gzip trailer missing".to_string())?;
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    if crc != crc32(&out) || size != out.len() as u32 {
+        return Err("gzip checksum doesn't match".to_string());
+    }
+    Ok(out)
+}
+
+/// Decompress raw deflate data, without gzip's header and trailer, as zip files hold it. Gives up
+/// once more than `limit` bytes have come out of it
+pub(crate) fn inflate_raw(data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    let mut reader = BitReader {
+        data,
+        pos: 0,
+        bit_buf: 0,
+        bit_count: 0,
+    };
+    inflate(&mut reader, limit)
+}
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        let mut crc = i as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+        *entry = crc;
+    }
+
+    let mut crc = !0u32;
+    for &byte in data {
+        crc = table[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bit_buf: u64,
+    bit_count: u32,
+}
+
+impl BitWriter {
+    /// Write the low `count` bits of `value`, least significant first
+    fn write_bits(&mut self, value: u32, count: u32) {
+        self.bit_buf |= (value as u64) << self.bit_count;
+        self.bit_count += count;
+        while self.bit_count >= 8 {
+            self.bytes.push(self.bit_buf as u8);
+            self.bit_buf >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    /// Huffman codes are sent most significant bit first
+    fn write_code(&mut self, code: u32, len: u32) {
+        let reversed = code.reverse_bits() >> (32 - len);
+        self.write_bits(reversed, len);
+    }
+
+    fn align(&mut self) {
+        if self.bit_count > 0 {
+            self.bytes.push(self.bit_buf as u8);
+            self.bit_buf = 0;
+            self.bit_count = 0;
+        }
+    }
+}
+
+fn write_fixed_literal(writer: &mut BitWriter, symbol: u32) {
+    match symbol {
+        0..=143 => writer.write_code(0x30 + symbol, 8),
+        144..=255 => writer.write_code(0x190 + symbol - 144, 9),
+        256..=279 => writer.write_code(symbol - 256, 7),
+        _ => writer.write_code(0xc0 + symbol - 280, 8),
+    }
+}
+
+fn write_match(writer: &mut BitWriter, len: usize, dist: usize) {
+    let len_index = LENGTH_BASE
+        .iter()
+        .rposition(|&base| base as usize <= len)
+        .unwrap();
+    write_fixed_literal(writer, 257 + len_index as u32);
+    writer.write_bits(
+        (len - LENGTH_BASE[len_index] as usize) as u32,
+        LENGTH_EXTRA[len_index] as u32,
+    );
+
+    let dist_index = DIST_BASE
+        .iter()
+        .rposition(|&base| base as usize <= dist)
+        .unwrap();
+    writer.write_code(dist_index as u32, 5);
+    writer.write_bits(
+        (dist - DIST_BASE[dist_index] as usize) as u32,
+        DIST_EXTRA[dist_index] as u32,
+    );
+}
+
+fn hash3(data: &[u8], pos: usize) -> usize {
+    let value = (data[pos] as u32) << 16 | (data[pos + 1] as u32) << 8 | data[pos + 2] as u32;
+    (value.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+/// Greedy LZ77 over hash chains of the last `WINDOW_SIZE` positions
+fn deflate_fixed(data: &[u8], writer: &mut BitWriter) {
+    const NONE: usize = usize::MAX;
+    let mut head = vec![NONE; 1 << HASH_BITS];
+    let mut prev = vec![NONE; WINDOW_SIZE];
+
+    let insert = |head: &mut [usize], prev: &mut [usize], pos: usize| {
+        if pos + MIN_MATCH <= data.len() {
+            let hash = hash3(data, pos);
+            prev[pos % WINDOW_SIZE] = head[hash];
+            head[hash] = pos;
+        }
+    };
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let mut best_len = 0;
+        let mut best_dist = 0;
+        if pos + MIN_MATCH <= data.len() {
+            let max_len = MAX_MATCH.min(data.len() - pos);
+            let mut candidate = head[hash3(data, pos)];
+            let mut chain = 0;
+            while candidate != NONE && pos - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+                let len = data[candidate..]
+                    .iter()
+                    .zip(&data[pos..pos + max_len])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best_len {
+                    best_len = len;
+                    best_dist = pos - candidate;
+                    if len == max_len {
+                        break;
+                    }
+                }
+                let next = prev[candidate % WINDOW_SIZE];
+                // Older than the window, or overwritten by a newer position
+                if next == NONE || next >= candidate {
+                    break;
+                }
+                candidate = next;
+                chain += 1;
+            }
+        }
+
+        if best_len >= MIN_MATCH {
+            write_match(writer, best_len, best_dist);
+            for i in pos..pos + best_len {
+                insert(&mut head, &mut prev, i);
+            }
+            pos += best_len;
+        } else {
+            write_fixed_literal(writer, data[pos] as u32);
+            insert(&mut head, &mut prev, pos);
+            pos += 1;
+        }
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit_buf: u32,
+    bit_count: u32,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, count: u32) -> Result<u32, String> {
+        while self.bit_count < count {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or_else(|| "compressed data ended early".to_string())?;
+            self.pos += 1;
+            self.bit_buf |= (byte as u32) << self.bit_count;
+            self.bit_count += 8;
+        }
+        let value = self.bit_buf & ((1u64 << count) - 1) as u32;
+        self.bit_buf = if count == 32 {
+            0
+        } else {
+            self.bit_buf >> count
+        };
+        self.bit_count -= count;
+        Ok(value)
+    }
+
+    /// Drop the rest of the current byte
+    fn align(&mut self) {
+        self.bit_buf = 0;
+        self.bit_count = 0;
+    }
+}
+
+/// A canonical Huffman code, as the number of codes of each length and the symbols in code order
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0u16; 16];
+        for len in 1..16 {
+            offsets[len] = offsets[len - 1] + counts[len - 1];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, String> {
+        let mut code = 0i32;
+        let mut first = 0i32;
+        let mut index = 0i32;
+        for len in 1..16 {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return self
+                    .symbols
+                    .get((index + code - first) as usize)
+                    .copied()
+                    .ok_or_else(|| "invalid Huffman code".to_string());
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("invalid Huffman code".to_string())
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), String> {
+    let num_lengths = reader.bits(5)? as usize + 257;
+    let num_dists = reader.bits(5)? as usize + 1;
+    let num_code_lengths = reader.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0u8; 19];
+    for &index in &CODE_LENGTH_ORDER[..num_code_lengths] {
+        code_lengths[index] = reader.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths);
+
+    let mut lengths = vec![0u8; num_lengths + num_dists];
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = code_length_code.decode(reader)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths[..i]
+                    .last()
+                    .ok_or_else(|| "repeated length with nothing before it".to_string())?;
+                (previous, 3 + reader.bits(2)? as usize)
+            }
+            17 => (0, 3 + reader.bits(3)? as usize),
+            _ => (0, 11 + reader.bits(7)? as usize),
+        };
+        if i + repeat > lengths.len() {
+            return Err("too many code lengths".to_string());
+        }
+        lengths[i..i + repeat].fill(value);
+        i += repeat;
+    }
+
+    Ok((
+        Huffman::new(&lengths[..num_lengths]),
+        Huffman::new(&lengths[num_lengths..]),
+    ))
+}
+
+fn inflate(reader: &mut BitReader, limit: usize) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let header = reader
+                    .data
+                    .get(reader.pos..reader.pos + 4)
+                    .ok_or_else(|| "stored block ended early".to_string())?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                let nlen = u16::from_le_bytes([header[2], header[3]]);
+                if len != !nlen {
+                    return Err("stored block length doesn't match".to_string());
+                }
+                let start = reader.pos + 4;
+                let block = reader
+                    .data
+                    .get(start..start + len as usize)
+                    .ok_or_else(|| "stored block ended early".to_string())?;
+                out.extend_from_slice(block);
+                reader.pos = start + len as usize;
+                check_limit(&out, limit)?;
+            }
+            1 => {
+                let (lengths, dists) = fixed_codes();
+                inflate_block(reader, &lengths, &dists, &mut out, limit)?;
+            }
+            2 => {
+                let (lengths, dists) = dynamic_codes(reader)?;
+                inflate_block(reader, &lengths, &dists, &mut out, limit)?;
+            }
+            _ => return Err("invalid block type".to_string()),
+        }
+        if last {
+            // The trailer starts at the next whole byte
+            reader.align();
+            check_limit(&out, limit)?;
+            return Ok(out);
+        }
+    }
+}
+
+fn inflate_block(
+    reader: &mut BitReader,
+    lengths: &Huffman,
+    dists: &Huffman,
+    out: &mut Vec<u8>,
+    limit: usize,
+) -> Result<(), String> {
+    loop {
+        check_limit(out, limit)?;
+        let symbol = lengths.decode(reader)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let index = symbol - 257;
+                if index >= LENGTH_BASE.len() {
+                    return Err("invalid length".to_string());
+                }
+                let len =
+                    LENGTH_BASE[index] as usize + reader.bits(LENGTH_EXTRA[index] as u32)? as usize;
+
+                let index = dists.decode(reader)? as usize;
+                if index >= DIST_BASE.len() {
+                    return Err("invalid distance".to_string());
+                }
+                let dist =
+                    DIST_BASE[index] as usize + reader.bits(DIST_EXTRA[index] as u32)? as usize;
+                if dist > out.len() {
+                    return Err("distance before the start of the data".to_string());
+                }
+
+                // The match can overlap what it is copying, so copy one byte at a time
+                let start = out.len() - dist;
+                for i in 0..len {
+                    out.push(out[start + i]);
+                }
+            }
+        }
+    }
+}
+
+fn check_limit(out: &[u8], limit: usize) -> Result<(), String> {
+    match out.len() > limit {
+        true => Err(format!("decompresses to more than {limit} bytes")),
+        false => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gzip_round_trip() {
+        let repetitive = "/home/user/project/src/components/Button.tsx\n".repeat(2000);
+        let mut noisy = Vec::new();
+        let mut state = 12345u32;
+        for _ in 0..100_000 {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            noisy.push((state >> 16) as u8);
+        }
+
+        for data in [
+            &b""[..],
+            b"a",
+            b"abcabcabcabc",
+            repetitive.as_bytes(),
+            &noisy,
+        ] {
+            let compressed = compress(data);
+            assert!(is_gzip(&compressed));
+            assert_eq!(decompress(&compressed).unwrap(), data);
+        }
+        assert!(compress(repetitive.as_bytes()).len() < repetitive.len() / 20);
+
+        // The same input always compresses to the same bytes
+        assert_eq!(compress(&noisy), compress(&noisy));
+
+        let compressed = compress(repetitive.as_bytes());
+        let limited = decompress_at_most(&compressed, repetitive.len());
+        assert_eq!(limited.unwrap(), repetitive.as_bytes());
+        assert!(decompress_at_most(&compressed, repetitive.len() - 1).is_err());
+    }
+
+    fn from_hex(hex: &[&str]) -> Vec<u8> {
+        let hex = hex.concat();
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_gzip_from_other_tools() {
+        // `printf 'hello hello hello hello\n' | gzip -9n`, which uses the fixed codes
+        let fixed = from_hex(&["1f8b0800000000000203cb48cdc9c957c84027b9000088590b18000000"]);
+        assert_eq!(decompress(&fixed).unwrap(), b"hello hello hello hello\n");
+
+        // The same, with a file name added to the header
+        let mut named = fixed[..10].to_vec();
+        named[3] = 0x08;
+        named.extend_from_slice(b"a.txt\0");
+        named.extend_from_slice(&fixed[10..]);
+        assert_eq!(decompress(&named).unwrap(), b"hello hello hello hello\n");
+
+        // 300 numbered lines, which gzip compresses with a dynamic block
+        let dynamic = from_hex(&[
+        "1f8b08000000000002036dd8318edb401444c1dca7d011d83d1c0ee7405a7801c14e7c7f180e0d56ca480fbb",
+        "5217ffe7fbd7fb75bc7e7fbdfefc7cbfbebe3fef1f9f7f8ff27cd4e7a3f17c743e1fcde7a3ebf9683d1fddcf",
+        "471b1f551f1f9f3f08080a8284a02188082a828ca0a3e8a8fe0ee8283a8a8ea2a3e8283a8a8ea263a063a063",
+        "e81f0a1d031d031d031d031d031d031d273a4e749ce838f5cd40c7898e131d273a4e749ce898e898e898e898",
+        "e898fa8aa363a263a263a263a2e342c7858e0b1d173a2e745cfaad42c7858e0b1d173a163a163a163a163a16",
+        "3a163a967e74d1b1d0b1d071a3e346c78d8e1b1d373a6e74dce8b8b51ee8b8d1b1d1b1d1b1d1b1d1b1d1b1d1",
+        "b1d1b1d1b13583dc410de1a1253c348587b6f0d0181e5ac3437378680f0f0de2a1224fbb8a38ee5c77ce3bf7",
+        "9d03cf85e7c46be3a3914fa9151569e7a3a18f963e9afa68eba3b18fd63e9afb68ef3308301569f2a3cd8f46",
+        "3f5afd68f6a3dd8f863f5afe68fa73d2942ad2fa47f31fed7f048048001101220344088814904926ab481088",
+        "24105120b240848148031107220f4420c845f9ab4826885010a9206241e4820806910c221a4436c8e2cb8c8a",
+        "c483c807111022214444888c1021215242c484dc7c3f5391a4105121b2428485480b1117222f446088c490cd",
+        "574ebe73eaa55366a8cc5099a132436586ca0c95192a33546668f81aad2299a132436586ca0c95192a335466",
+        "28ef023c0cf832a022de06781ce07580e701de07782090192a335466e8e0b1434532436586ca0c95192a3354",
+        "66a8cc5099a132434fde6f5424335466a8cc5099a132436586ca0c95192a3374f224a52299a132436586ca0c",
+        "95192a335466a8cc5099a117af6c2a92192a335466a8cc5099a132436586ca0c9519ba78385491cc5099a132",
+        "436586ca0c95192a335466a8ccd09bb75015c90c95192a335466a8cc5099a132436586ca0cdd3ceffe5ff417",
+        "93b159822e180000",
+        ]);
+        let expected: String = (0..300)
+            .map(|i| format!("line {i} of the file\n"))
+            .collect();
+        assert_eq!(decompress(&dynamic).unwrap(), expected.as_bytes());
+
+        // A stored block, as written for data that doesn't compress
+        let mut stored = vec![0x1f, 0x8b, 0x08, 0x00, 0, 0, 0, 0, 0x00, 0xff];
+        stored.extend_from_slice(&[0x01, 0x03, 0x00, 0xfc, 0xff, b'x', b'y', b'z']);
+        stored.extend_from_slice(&crc32(b"xyz").to_le_bytes());
+        stored.extend_from_slice(&3u32.to_le_bytes());
+        assert_eq!(decompress(&stored).unwrap(), b"xyz");
+
+        let mut corrupted = compress(b"some data");
+        let last = corrupted.len() - 5;
+        corrupted[last] ^= 1;
+        assert!(decompress(&corrupted).is_err());
+        assert!(decompress(b"not gzip at all").is_err());
+    }
+}
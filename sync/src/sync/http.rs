@@ -0,0 +1,480 @@
+//! A small read-only HTTP API over the index, for dashboards and other tools that want to look
+//! into it without linking the crate (`continue-sync http`)
+//!
+//! Every response is JSON, and every connection is closed after its response:
+//! - `GET /tags`: the `TagEntry`s from `list_tags`, filtered by the `dir` and `providerId` query
+//!   parameters if they're given
+//! - `GET /tags/{tag}/files`: the files in the tag's saved tree
+//! - `GET /tags/{tag}/root`: `{"rootHash": ...}`, null if the tag hasn't been synced
+//! - `GET /hash/{hash}/tags`: the tags whose files have the content, for the provider in the
+//!   `providerId` query parameter (`default` if it isn't given)
+//!
+//! `{tag}` is the tag's string (`dir::branch::provider_id`), percent-encoded as a single path
+//! segment, and `{hash}` is hex. Nothing here writes to the index, but anyone who can connect
+//! can read every path in it, so it's best bound to localhost.
+//!
+//! A daemon that keeps the index up to date (`continue-sync watch --port ...`) also serves
+//! `GET /events`, which stays open and streams what each sync finds as server-sent events, so
+//! UI clients can follow along instead of polling:
+//! - `results`: `{"tag": ..., "results": SyncResults}` for each sync that found something
+//! - `progress`: `{"syncs": ..., "files": ...}`, the totals since the daemon started, after
+//!   each `results`
+
+use super::{
+    error::Result, get_root_hash, list_indexed_files, list_tags, options::SyncOptions, parse_hash,
+    which_tags_contain, OwnedTag, SyncResults, Tag,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::Path,
+    str::FromStr,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Longest request line or header line read, to keep a bad client from using up memory
+const MAX_LINE: u64 = 8 * 1024;
+
+/// Most header lines read before giving up on a request
+const MAX_HEADERS: usize = 100;
+
+/// How long an event stream goes quiet before a comment is sent down it, which is how clients
+/// that went away are noticed
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// A response: its status code and JSON body
+#[derive(Debug, PartialEq)]
+pub(crate) struct Response {
+    pub(crate) status: u16,
+    pub(crate) body: Value,
+}
+
+impl Response {
+    fn ok(body: impl Serialize) -> Self {
+        Response {
+            status: 200,
+            body: serde_json::to_value(body).expect("results serialize to JSON"),
+        }
+    }
+
+    pub(crate) fn error(status: u16, message: impl Into<String>) -> Self {
+        Response {
+            status,
+            body: json!({ "error": message.into() }),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Internal Server Error",
+        }
+    }
+
+    fn write_to(&self, stream: &mut impl Write) -> io::Result<()> {
+        let body = self.body.to_string();
+        write!(
+            stream,
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{body}",
+            self.status,
+            self.reason(),
+            body.len()
+        )?;
+        stream.flush()
+    }
+}
+
+/// A request's method, its path split into decoded segments, and its decoded query parameters
+#[derive(Debug, PartialEq)]
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) segments: Vec<String>,
+    pub(crate) query: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Parse the request line (`GET /tags?dir=... HTTP/1.1`)
+    pub(crate) fn parse(line: &str) -> Option<Self> {
+        let mut parts = line.split_whitespace();
+        let method = parts.next()?.to_string();
+        let target = parts.next()?;
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let segments = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| percent_decode(segment, false))
+            .collect::<Option<_>>()?;
+        let query = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                Some((percent_decode(name, true)?, percent_decode(value, true)?))
+            })
+            .collect::<Option<_>>()?;
+        Some(Request {
+            method,
+            segments,
+            query,
+        })
+    }
+
+    pub(crate) fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// `%XX` escapes (and `+`, in a query, as forms send spaces that way) turned back into what
+/// they stand for, or None if that isn't UTF-8
+fn percent_decode(text: &str, in_query: bool) -> Option<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        rest = after;
+        match byte {
+            b'%' => {
+                let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &rest[2..];
+            }
+            b'+' if in_query => bytes.push(b' '),
+            byte => bytes.push(byte),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Answer a GET request from the index
+pub(crate) fn route(request: &Request, options: &SyncOptions) -> Response {
+    if request.method != "GET" {
+        return Response::error(405, format!("{} isn't supported", request.method));
+    }
+    let segments: Vec<&str> = request.segments.iter().map(String::as_str).collect();
+    let result = match segments.as_slice() {
+        ["tags"] => list_tags(
+            options,
+            request.param("dir").map(Path::new),
+            request.param("providerId"),
+        )
+        .map(Response::ok),
+        ["tags", tag, "files"] => match OwnedTag::from_str(tag) {
+            Ok(tag) => list_indexed_files(&tag.as_tag(), options).map(Response::ok),
+            Err(err) => Ok(Response::error(400, err.to_string())),
+        },
+        ["tags", tag, "root"] => match OwnedTag::from_str(tag) {
+            Ok(tag) => get_root_hash(&tag.as_tag(), options)
+                .map(|hash| Response::ok(json!({ "rootHash": hash.map(super::hash_string) }))),
+            Err(err) => Ok(Response::error(400, err.to_string())),
+        },
+        ["hash", hash, "tags"] => match parse_hash(hash) {
+            Some(hash) => {
+                let provider_id = request.param("providerId").unwrap_or("default");
+                which_tags_contain(hash, provider_id, options).map(Response::ok)
+            }
+            None => Ok(Response::error(400, format!("{hash} isn't a hash"))),
+        },
+        _ => Ok(Response::error(404, "Not found")),
+    };
+    result.unwrap_or_else(|err| Response::error(500, err.to_string()))
+}
+
+/// Read one request from `stream`, up to the end of its headers. Any body is left unread
+pub(crate) fn read_request(stream: &mut impl Read) -> io::Result<Option<Request>> {
+    let mut reader = BufReader::new(stream);
+    let mut read_line = || -> io::Result<String> {
+        let mut line = String::new();
+        (&mut reader).take(MAX_LINE).read_line(&mut line)?;
+        Ok(line)
+    };
+    let request = Request::parse(&read_line()?);
+    for _ in 0..MAX_HEADERS {
+        let line = read_line()?;
+        if line.trim_end().is_empty() {
+            return Ok(request);
+        }
+    }
+    Ok(None)
+}
+
+/// What a daemon's syncs find, passed on to every client following `GET /events`
+#[derive(Default)]
+pub struct SyncEvents {
+    clients: Mutex<Vec<Sender<Arc<str>>>>,
+    /// Syncs that found something, and the files they found, so far
+    totals: Mutex<(usize, usize)>,
+}
+
+impl SyncEvents {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send the results of a sync of `tag` to every client, followed by the totals so far
+    pub fn send_results(&self, tag: &Tag, results: &SyncResults) {
+        self.send(
+            "results",
+            &json!({ "tag": tag.to_string(), "results": results }),
+        );
+        let (syncs, files) = {
+            let mut totals = self.totals.lock().unwrap();
+            totals.0 += 1;
+            totals.1 += results.action_count();
+            *totals
+        };
+        self.send("progress", &json!({ "syncs": syncs, "files": files }));
+    }
+
+    fn send(&self, event: &str, data: &Value) {
+        // Compact JSON has no newlines, so it fits on the one data line
+        let frame: Arc<str> = format!("event: {event}\ndata: {data}\n\n").into();
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|client| client.send(frame.clone()).is_ok());
+    }
+
+    fn subscribe(&self) -> Receiver<Arc<str>> {
+        let (sender, receiver) = mpsc::channel();
+        self.clients.lock().unwrap().push(sender);
+        receiver
+    }
+}
+
+/// Send `events` down `stream` as they come, until the client goes away
+fn stream_events(stream: &mut impl Write, events: &SyncEvents) -> io::Result<()> {
+    let receiver = events.subscribe();
+    stream.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+          Connection: close\r\n\r\n",
+    )?;
+    stream.flush()?;
+    loop {
+        match receiver.recv_timeout(KEEP_ALIVE) {
+            Ok(frame) => stream.write_all(frame.as_bytes())?,
+            Err(RecvTimeoutError::Timeout) => stream.write_all(b": keep-alive\n\n")?,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        stream.flush()?;
+    }
+}
+
+fn handle_connection(
+    mut stream: TcpStream,
+    options: &SyncOptions,
+    events: Option<&SyncEvents>,
+) -> io::Result<()> {
+    let response = match read_request(&mut stream)? {
+        Some(request) => match (events, request.method.as_str(), &request.segments[..]) {
+            (Some(events), "GET", [segment]) if segment == "events" => {
+                return stream_events(&mut stream, events)
+            }
+            _ => route(&request, options),
+        },
+        None => Response::error(400, "Couldn't read the request"),
+    };
+    response.write_to(&mut stream)
+}
+
+fn serve(listener: TcpListener, options: &SyncOptions, events: Option<&SyncEvents>) -> Result<()> {
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            // A client that goes away before its response has only itself to blame
+            scope.spawn(move || handle_connection(stream, options, events));
+        }
+        Ok(())
+    })
+}
+
+/// Answer HTTP requests on `listener` until it fails, each connection on a thread of its own.
+/// `options` are used for every request, so say where the index is
+pub fn serve_http(listener: TcpListener, options: &SyncOptions) -> Result<()> {
+    serve(listener, options, None)
+}
+
+/// Same as `serve_http`, also streaming whatever is sent through `events` to the clients of
+/// `GET /events`
+pub fn serve_http_with_events(
+    listener: TcpListener,
+    options: &SyncOptions,
+    events: &SyncEvents,
+) -> Result<()> {
+    serve(listener, options, Some(events))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sync::{sync, Tag},
+        utils::{temp_index_root, TempDirBuilder},
+    };
+
+    fn get(target: &str, options: &SyncOptions) -> Response {
+        route(
+            &Request::parse(&format!("GET {target} HTTP/1.1")).unwrap(),
+            options,
+        )
+    }
+
+    /// Percent-encode everything but letters and digits
+    fn encode(text: &str) -> String {
+        text.bytes()
+            .map(|byte| match byte.is_ascii_alphanumeric() {
+                true => (byte as char).to_string(),
+                false => format!("%{byte:02X}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let request = Request::parse("GET /tags/a+%3A%3Ab/files?dir=%2Fx+y&providerId HTTP/1.1");
+        assert_eq!(
+            request,
+            Some(Request {
+                method: "GET".to_string(),
+                segments: vec!["tags".into(), "a+::b".into(), "files".into()],
+                query: vec![
+                    ("dir".into(), "/x y".into()),
+                    ("providerId".into(), String::new())
+                ],
+            })
+        );
+        assert_eq!(Request::parse("GET /%zz HTTP/1.1"), None);
+        assert_eq!(Request::parse("GET /%ff HTTP/1.1"), None);
+        assert_eq!(Request::parse(""), None);
+    }
+
+    #[test]
+    fn test_routes() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("b.txt", "B")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let results = sync(tag, &options).unwrap();
+        let tag_segment = encode(&tag.canonicalize().to_string());
+
+        let response = get("/tags", &options);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body.as_array().unwrap().len(), 1);
+        let response = get("/tags?providerId=other", &options);
+        assert_eq!(response.body, json!([]));
+
+        let response = get(&format!("/tags/{tag_segment}/files"), &options);
+        assert_eq!(response.body.as_array().unwrap().len(), 2);
+        let response = get(&format!("/tags/{tag_segment}/root"), &options);
+        let root_hash = crate::sync::get_root_hash(tag, &options).unwrap().unwrap();
+        assert_eq!(
+            response.body,
+            json!({ "rootHash": crate::sync::hash_string(root_hash) })
+        );
+
+        let hash = &results.compute[0].hash;
+        let response = get(&format!("/hash/{hash}/tags"), &options);
+        assert_eq!(response.body[0]["branch"], "main");
+        let response = get(&format!("/hash/{hash}/tags?providerId=other"), &options);
+        assert_eq!(response.body, json!([]));
+
+        assert_eq!(get("/hash/nothex/tags", &options).status, 400);
+        assert_eq!(get("/tags/not-a-tag/files", &options).status, 400);
+        assert_eq!(get("/nowhere", &options).status, 404);
+        let request = Request::parse("DELETE /tags HTTP/1.1").unwrap();
+        assert_eq!(route(&request, &options).status, 405);
+    }
+
+    #[test]
+    fn test_serve_http() {
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || serve_http(listener, &options));
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"GET /tags HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\n[]"));
+    }
+
+    #[test]
+    fn test_event_stream() {
+        let temp_dir = TempDirBuilder::new().add("a.txt", "A").create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let results = sync(tag, &options).unwrap();
+        let events: &'static SyncEvents = Box::leak(Box::default());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server_options = options.clone();
+        thread::spawn(move || serve_http_with_events(listener, &server_options, events));
+
+        let mut stream = BufReader::new(TcpStream::connect(address).unwrap());
+        stream
+            .get_mut()
+            .write_all(b"GET /events HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let mut read_until_blank = || {
+            let mut lines = Vec::new();
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).unwrap();
+                match line.trim_end() {
+                    "" => return lines,
+                    line => lines.push(line.to_string()),
+                }
+            }
+        };
+        // Once the headers are in, the client is subscribed
+        let headers = read_until_blank();
+        assert_eq!(headers[0], "HTTP/1.1 200 OK");
+        assert!(headers.contains(&"Content-Type: text/event-stream".to_string()));
+
+        events.send_results(tag, &results);
+        let event = read_until_blank();
+        assert_eq!(event[0], "event: results");
+        let data: Value = serde_json::from_str(event[1].strip_prefix("data: ").unwrap()).unwrap();
+        assert_eq!(data["tag"], tag.to_string());
+        assert_eq!(data["results"], serde_json::to_value(&results).unwrap());
+        let event = read_until_blank();
+        assert_eq!(event, ["event: progress", r#"data: {"files":1,"syncs":1}"#]);
+
+        // Everything else is still answered
+        let mut other = TcpStream::connect(address).unwrap();
+        other.write_all(b"GET /tags HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        other.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(get("/events", &options).status, 404);
+    }
+}
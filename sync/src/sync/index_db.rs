@@ -0,0 +1,545 @@
+use super::{
+    error::{Result, SyncError},
+    file_system::{FileSystem, OsFileSystem},
+    index_root::IndexRoot,
+    merkle::ObjectHash,
+    rev_tags::{self, RevTags},
+    DiskSet, Tag,
+};
+use rusqlite::{Connection, OptionalExtension};
+use std::{collections::HashSet, fs, io::ErrorKind, path::Path};
+
+/// Every cache for every provider and tag, in one database at the index root, for
+/// `IndexStorage::Sqlite`
+///
+/// Holds the same state as the files used by `IndexStorage::Files`: the global cache of
+/// computed hashes for each provider, each tag's own cache, rev_tags and the time of each tag's
+/// last sync. Merkle trees are still saved as files.
+pub(crate) struct IndexDb {
+    conn: Connection,
+}
+
+impl IndexDb {
+    pub(crate) fn open(index_root: &IndexRoot) -> Result<Self> {
+        fs::create_dir_all(index_root.path())?;
+        let conn = Connection::open(index_root.index_db())?;
+
+        // Changes outside of `begin`/`commit` are their own transactions, which is only fast
+        // without a sync per commit
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS global_cache (
+                provider_id TEXT NOT NULL,
+                hash BLOB NOT NULL,
+                PRIMARY KEY (provider_id, hash)
+            ) WITHOUT ROWID;
+            CREATE TABLE IF NOT EXISTS tag_cache (
+                tag TEXT NOT NULL,
+                hash BLOB NOT NULL,
+                PRIMARY KEY (tag, hash)
+            ) WITHOUT ROWID;
+            CREATE TABLE IF NOT EXISTS rev_tags (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                provider_id TEXT NOT NULL,
+                hash BLOB NOT NULL,
+                tag TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS rev_tags_hash ON rev_tags (provider_id, hash);
+            CREATE TABLE IF NOT EXISTS refcounts (
+                provider_id TEXT NOT NULL,
+                hash BLOB NOT NULL,
+                count INTEGER NOT NULL,
+                PRIMARY KEY (provider_id, hash)
+            ) WITHOUT ROWID;
+            CREATE TABLE IF NOT EXISTS last_sync (
+                tag TEXT PRIMARY KEY,
+                time INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS migrated (
+                name TEXT PRIMARY KEY
+            );",
+        )?;
+        let index_db = IndexDb { conn };
+        index_db.count_missing_refs()?;
+        Ok(index_db)
+    }
+
+    /// Count the references of a database written before there were refcounts, as in
+    /// `RevTags`
+    fn count_missing_refs(&self) -> Result<()> {
+        let counted = self
+            .conn
+            .prepare_cached("SELECT 1 FROM refcounts LIMIT 1")?
+            .query_row((), |_| Ok(()))
+            .optional()?;
+        if counted.is_none() {
+            self.conn.execute_batch(
+                "INSERT INTO refcounts (provider_id, hash, count)
+                SELECT provider_id, hash, COUNT(*) FROM rev_tags GROUP BY provider_id, hash;",
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Wait for every commit to reach the disk, rather than only the ones that checkpoint the
+    /// write-ahead log, for `SyncOptions::fsync`
+    pub(crate) fn sync_every_commit(&self) -> Result<()> {
+        self.conn.pragma_update(None, "synchronous", "FULL")?;
+        Ok(())
+    }
+
+    /// Start grouping changes into one transaction, dropping any that were left unfinished
+    pub(crate) fn begin(&self) -> Result<()> {
+        if !self.conn.is_autocommit() {
+            self.conn.execute_batch("ROLLBACK")?;
+        }
+        self.conn.execute_batch("BEGIN")?;
+        Ok(())
+    }
+
+    pub(crate) fn commit(&self) -> Result<()> {
+        self.conn.execute_batch("COMMIT")?;
+        Ok(())
+    }
+
+    pub(crate) fn rollback(&self) -> Result<()> {
+        if !self.conn.is_autocommit() {
+            self.conn.execute_batch("ROLLBACK")?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn global_contains(&self, provider_id: &str, hash: &ObjectHash) -> Result<bool> {
+        let found = self
+            .conn
+            .prepare_cached("SELECT 1 FROM global_cache WHERE provider_id = ?1 AND hash = ?2")?
+            .query_row((provider_id, &hash[..]), |_| Ok(()))
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    pub(crate) fn global_add(&self, provider_id: &str, hash: &ObjectHash) -> Result<()> {
+        self.conn
+            .prepare_cached(
+                "INSERT OR IGNORE INTO global_cache (provider_id, hash) VALUES (?1, ?2)",
+            )?
+            .execute((provider_id, &hash[..]))?;
+        Ok(())
+    }
+
+    pub(crate) fn global_remove(&self, provider_id: &str, hash: &ObjectHash) -> Result<()> {
+        self.conn
+            .prepare_cached("DELETE FROM global_cache WHERE provider_id = ?1 AND hash = ?2")?
+            .execute((provider_id, &hash[..]))?;
+        Ok(())
+    }
+
+    pub(crate) fn tag_add(&self, tag: &str, hash: &ObjectHash) -> Result<()> {
+        self.conn
+            .prepare_cached("INSERT OR IGNORE INTO tag_cache (tag, hash) VALUES (?1, ?2)")?
+            .execute((tag, &hash[..]))?;
+        Ok(())
+    }
+
+    pub(crate) fn tag_remove(&self, tag: &str, hash: &ObjectHash) -> Result<()> {
+        self.conn
+            .prepare_cached("DELETE FROM tag_cache WHERE tag = ?1 AND hash = ?2")?
+            .execute((tag, &hash[..]))?;
+        Ok(())
+    }
+
+    /// Every tag referencing the hash, once per reference, as in `RevTags::get`
+    pub(crate) fn rev_tags(&self, provider_id: &str, hash: &ObjectHash) -> Result<Vec<String>> {
+        let mut statement = self.conn.prepare_cached(
+            "SELECT tag FROM rev_tags WHERE provider_id = ?1 AND hash = ?2 ORDER BY id",
+        )?;
+        let tags = statement
+            .query_map((provider_id, &hash[..]), |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(tags)
+    }
+
+    pub(crate) fn add_rev_tag(
+        &self,
+        provider_id: &str,
+        hash: &ObjectHash,
+        tag: &str,
+    ) -> Result<()> {
+        self.conn
+            .prepare_cached("INSERT INTO rev_tags (provider_id, hash, tag) VALUES (?1, ?2, ?3)")?
+            .execute((provider_id, &hash[..], tag))?;
+        self.conn
+            .prepare_cached(
+                "INSERT INTO refcounts (provider_id, hash, count) VALUES (?1, ?2, 1)
+                ON CONFLICT (provider_id, hash) DO UPDATE SET count = count + 1",
+            )?
+            .execute((provider_id, &hash[..]))?;
+        Ok(())
+    }
+
+    /// Remove one reference from the tag to the hash, if there are any
+    pub(crate) fn remove_one_rev_tag(
+        &self,
+        provider_id: &str,
+        hash: &ObjectHash,
+        tag: &str,
+    ) -> Result<()> {
+        let removed = self
+            .conn
+            .prepare_cached(
+                "DELETE FROM rev_tags WHERE id = (
+                    SELECT MIN(id) FROM rev_tags WHERE provider_id = ?1 AND hash = ?2 AND tag = ?3
+                )",
+            )?
+            .execute((provider_id, &hash[..], tag))?;
+        if removed > 0 {
+            self.conn
+                .prepare_cached(
+                    "UPDATE refcounts SET count = count - 1 WHERE provider_id = ?1 AND hash = ?2",
+                )?
+                .execute((provider_id, &hash[..]))?;
+            self.conn
+                .prepare_cached(
+                    "DELETE FROM refcounts WHERE provider_id = ?1 AND hash = ?2 AND count <= 0",
+                )?
+                .execute((provider_id, &hash[..]))?;
+        }
+        Ok(())
+    }
+
+    /// Remove every reference to the hash, from every tag
+    pub(crate) fn remove_all_rev_tags(&self, provider_id: &str, hash: &ObjectHash) -> Result<()> {
+        self.conn
+            .prepare_cached("DELETE FROM rev_tags WHERE provider_id = ?1 AND hash = ?2")?
+            .execute((provider_id, &hash[..]))?;
+        self.conn
+            .prepare_cached("DELETE FROM refcounts WHERE provider_id = ?1 AND hash = ?2")?
+            .execute((provider_id, &hash[..]))?;
+        Ok(())
+    }
+
+    /// How many references there are to the hash, from any of the provider's tags
+    pub(crate) fn refcount(&self, provider_id: &str, hash: &ObjectHash) -> Result<u64> {
+        let count: Option<i64> = self
+            .conn
+            .prepare_cached("SELECT count FROM refcounts WHERE provider_id = ?1 AND hash = ?2")?
+            .query_row((provider_id, &hash[..]), |row| row.get(0))
+            .optional()?;
+        Ok(count.map_or(0, |count| count.max(0) as u64))
+    }
+
+    /// How many references there are to the hash from the tag alone, as in
+    /// `RevTags::tag_refcount`
+    pub(crate) fn tag_refcount(
+        &self,
+        provider_id: &str,
+        hash: &ObjectHash,
+        tag: &str,
+    ) -> Result<u64> {
+        let count: i64 = self
+            .conn
+            .prepare_cached(
+                "SELECT COUNT(*) FROM rev_tags WHERE provider_id = ?1 AND hash = ?2 AND tag = ?3",
+            )?
+            .query_row((provider_id, &hash[..], tag), |row| row.get(0))?;
+        Ok(count as u64)
+    }
+
+    /// Every one of the provider's refcounts
+    pub(crate) fn all_refcounts(&self, provider_id: &str) -> Result<Vec<(ObjectHash, u64)>> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT hash, count FROM refcounts WHERE provider_id = ?1")?;
+        let rows = statement
+            .query_map((provider_id,), |row| {
+                Ok((row.get(0)?, row.get::<_, i64>(1)?.max(0) as u64))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(rows)
+    }
+
+    /// Every hash in the tag's cache
+    pub(crate) fn tag_hashes(&self, tag: &str) -> Result<Vec<ObjectHash>> {
+        self.hashes("SELECT hash FROM tag_cache WHERE tag = ?1", tag)
+    }
+
+    /// Every hash in the provider's global cache
+    pub(crate) fn global_hashes(&self, provider_id: &str) -> Result<Vec<ObjectHash>> {
+        self.hashes(
+            "SELECT hash FROM global_cache WHERE provider_id = ?1",
+            provider_id,
+        )
+    }
+
+    fn hashes(&self, query: &str, key: &str) -> Result<Vec<ObjectHash>> {
+        let mut statement = self.conn.prepare(query)?;
+        let hashes = statement
+            .query_map((key,), |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(hashes)
+    }
+
+    /// Every reference from every one of the provider's tags, as in `RevTags::all`
+    pub(crate) fn all_rev_tags(&self, provider_id: &str) -> Result<Vec<(ObjectHash, String)>> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT hash, tag FROM rev_tags WHERE provider_id = ?1 ORDER BY id")?;
+        let rows = statement
+            .query_map((provider_id,), |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(rows)
+    }
+
+    /// Replace the provider's global cache and rev_tags, and the caches of `tags`, in one
+    /// transaction
+    pub(crate) fn replace_provider(
+        &self,
+        provider_id: &str,
+        global_cache: &HashSet<ObjectHash>,
+        tags: &[(String, HashSet<ObjectHash>)],
+        rev_tags: &[(ObjectHash, String)],
+    ) -> Result<()> {
+        self.begin()?;
+        self.conn
+            .prepare_cached("DELETE FROM global_cache WHERE provider_id = ?1")?
+            .execute((provider_id,))?;
+        self.conn
+            .prepare_cached("DELETE FROM rev_tags WHERE provider_id = ?1")?
+            .execute((provider_id,))?;
+        self.conn
+            .prepare_cached("DELETE FROM refcounts WHERE provider_id = ?1")?
+            .execute((provider_id,))?;
+        for hash in global_cache {
+            self.global_add(provider_id, hash)?;
+        }
+        for (tag, tag_cache) in tags {
+            self.conn
+                .prepare_cached("DELETE FROM tag_cache WHERE tag = ?1")?
+                .execute((tag,))?;
+            for hash in tag_cache {
+                self.tag_add(tag, hash)?;
+            }
+        }
+        for (hash, tag) in rev_tags {
+            self.add_rev_tag(provider_id, hash, tag)?;
+        }
+        self.commit()
+    }
+
+    /// Every tag with anything in its cache
+    pub(crate) fn cached_tags(&self) -> Result<Vec<String>> {
+        let mut statement = self.conn.prepare("SELECT DISTINCT tag FROM tag_cache")?;
+        let tags = statement
+            .query_map((), |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(tags)
+    }
+
+    /// Give the space left by removed rows back to the filesystem
+    pub(crate) fn vacuum(&self) -> Result<()> {
+        self.conn
+            .execute_batch("VACUUM; PRAGMA wal_checkpoint(TRUNCATE);")?;
+        Ok(())
+    }
+
+    /// Seconds since the epoch when the tag was last synced, if ever
+    pub(crate) fn sync_time(&self, tag: &str) -> Result<Option<u64>> {
+        let time: Option<i64> = self
+            .conn
+            .prepare_cached("SELECT time FROM last_sync WHERE tag = ?1")?
+            .query_row((tag,), |row| row.get(0))
+            .optional()?;
+        Ok(time.map(|time| time as u64))
+    }
+
+    pub(crate) fn set_sync_time(&self, tag: &str, time: u64) -> Result<()> {
+        self.conn
+            .prepare_cached("INSERT OR REPLACE INTO last_sync (tag, time) VALUES (?1, ?2)")?
+            .execute((tag, time as i64))?;
+        Ok(())
+    }
+
+    /// Forget the tag's own cache and sync time. References to it in rev_tags are removed
+    /// along with its files, as for any other sync
+    pub(crate) fn delete_tag(&self, tag: &str) -> Result<()> {
+        self.conn
+            .prepare_cached("DELETE FROM tag_cache WHERE tag = ?1")?
+            .execute((tag,))?;
+        self.conn
+            .prepare_cached("DELETE FROM last_sync WHERE tag = ?1")?
+            .execute((tag,))?;
+        Ok(())
+    }
+
+    /// Move the state kept in files by `IndexStorage::Files` for the tag, and for its provider,
+    /// into the database. Each is only moved once, and the files are removed afterwards. The
+    /// caches are read from `file_system` (see `SyncOptions::index_file_system`)
+    pub(crate) fn migrate_files(
+        &self,
+        tag: &Tag,
+        index_root: &IndexRoot,
+        file_system: &dyn FileSystem,
+    ) -> Result<()> {
+        let tag_str = tag.to_string();
+        let provider_name = format!("provider:{}", tag.provider_id);
+        let tag_name = format!("tag:{tag_str}");
+        let provider_dir = index_root.provider_dir(tag.provider_id);
+        let tag_dir = index_root.tag_dir(tag);
+
+        if !self.is_migrated(&provider_name)? {
+            let global_cache = read_hashes(file_system, &provider_dir.join(".index_cache"))?;
+            let mut rev_tags = match index_root.rev_tags_db(tag.provider_id).exists() {
+                true => RevTags::open_sqlite(index_root, tag.provider_id)?.all()?,
+                false => Vec::new(),
+            };
+            let rev_tags_file = index_root.rev_tags_file(tag.provider_id);
+            rev_tags.extend(rev_tags::read_file(file_system, &rev_tags_file)?);
+
+            self.begin()?;
+            for hash in &global_cache {
+                self.global_add(tag.provider_id, hash)?;
+            }
+            for (hash, rev_tag) in &rev_tags {
+                self.add_rev_tag(tag.provider_id, hash, rev_tag)?;
+            }
+            self.set_migrated(&provider_name)?;
+            self.commit()?;
+
+            // Only remove the files once everything in them is safely in the database
+            remove_files(
+                file_system,
+                &provider_dir,
+                &[
+                    ".index_cache",
+                    ".index_cache.bloom",
+                    ".index_cache.sorted",
+                    "rev_tags.json",
+                ],
+            )?;
+            remove_files(
+                &OsFileSystem,
+                &provider_dir,
+                &["rev_tags.db", "rev_tags.db-wal", "rev_tags.db-shm"],
+            )?;
+        }
+
+        if !self.is_migrated(&tag_name)? {
+            let tag_cache = read_hashes(file_system, &tag_dir.join(".index_cache"))?;
+            let last_sync = super::read_sync_time(file_system, &tag_dir)?;
+
+            self.begin()?;
+            for hash in &tag_cache {
+                self.tag_add(&tag_str, hash)?;
+            }
+            if let Some(time) = last_sync {
+                // A sync since the database was first used is more recent than the file
+                self.conn
+                    .prepare_cached("INSERT OR IGNORE INTO last_sync (tag, time) VALUES (?1, ?2)")?
+                    .execute((&tag_str, time as i64))?;
+            }
+            self.set_migrated(&tag_name)?;
+            self.commit()?;
+
+            remove_files(
+                file_system,
+                &tag_dir,
+                &[".index_cache", ".index_cache.sorted"],
+            )?;
+            remove_files(&OsFileSystem, &tag_dir, &[".last_sync"])?;
+        }
+        Ok(())
+    }
+
+    fn is_migrated(&self, name: &str) -> Result<bool> {
+        let found = self
+            .conn
+            .prepare_cached("SELECT 1 FROM migrated WHERE name = ?1")?
+            .query_row((name,), |_| Ok(()))
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    fn set_migrated(&self, name: &str) -> Result<()> {
+        self.conn
+            .prepare_cached("INSERT OR IGNORE INTO migrated (name) VALUES (?1)")?
+            .execute((name,))?;
+        Ok(())
+    }
+}
+
+/// The hashes in a cache file written by `DiskSet`, or none if there isn't one
+fn read_hashes(file_system: &dyn FileSystem, path: &Path) -> Result<Vec<ObjectHash>> {
+    match DiskSet::read(file_system, path) {
+        Err(SyncError::Io(err)) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        result => result,
+    }
+}
+
+fn remove_files(file_system: &dyn FileSystem, dir: &Path, names: &[&str]) -> Result<()> {
+    for name in names {
+        match file_system.remove_file(&dir.join(name)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sync::{list_tags, sync, IndexStorage, SyncOptions, SyncResults},
+        utils::{temp_index_root, TempDirBuilder},
+    };
+
+    #[test]
+    fn test_migrate_files() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("b.txt", "B")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let tag2 = &Tag {
+            branch: "other",
+            ..*tag
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let files = SyncOptions::new().index_root(index_root.clone());
+        let sqlite = files.clone().storage(IndexStorage::Sqlite);
+        sync(tag, &files).unwrap();
+        sync(tag2, &files).unwrap();
+
+        // The tag's and the provider's state is moved over the first time the caches are needed
+        assert_eq!(sync(tag, &sqlite).unwrap(), SyncResults::default());
+        let index_db = IndexDb::open(&index_root).unwrap();
+        assert!(!index_db.is_migrated("provider:default").unwrap());
+
+        // Everything is carried over, so only the change is new
+        std::fs::write(temp_dir.path().join("a.txt"), "A changed").unwrap();
+        let results = sync(tag, &sqlite).unwrap();
+        assert_eq!(results.compute.len(), 1);
+        assert_eq!(results.remove_tag.len(), 1);
+        assert!(index_db.is_migrated("provider:default").unwrap());
+        assert!(index_db.is_migrated(&format!("tag:{tag}")).unwrap());
+        assert!(!index_db.is_migrated(&format!("tag:{tag2}")).unwrap());
+        assert!(!index_root.rev_tags_db("default").exists());
+        assert!(!index_root.tag_dir(tag).join(".index_cache").exists());
+        for entry in list_tags(&sqlite, None, None).unwrap() {
+            assert!(entry.last_sync.is_some());
+        }
+
+        // The other tag's references came along with the provider's, and its own cache comes
+        // over once it's synced
+        let results = sync(tag2, &sqlite).unwrap();
+        assert_eq!(results.add_tag.len(), 1);
+        assert_eq!(results.delete.len(), 1);
+        assert!(index_db.is_migrated(&format!("tag:{tag2}")).unwrap());
+        assert_eq!(sync(tag, &sqlite).unwrap(), SyncResults::default());
+    }
+}
@@ -0,0 +1,503 @@
+use super::{
+    error::{Result, SyncError},
+    file_system::{FileSystem, OsFileSystem},
+    merkle::{hash_string, path_to_bytes},
+    win_path, OwnedTag, SyncWarning, Tag,
+};
+use sha1::{Digest, Sha1};
+use std::{
+    ffi::OsString,
+    fs,
+    io::ErrorKind,
+    path::{Component, Path, PathBuf, Prefix},
+};
+
+/// How much of the workspace path is kept in its tag directory's name, to keep names well short
+/// of the usual 255 byte limit
+const MAX_READABLE_LEN: usize = 64;
+
+/// How tag directories were named before `tag_dir_name`, and still the readable part of their
+/// names. Different workspaces can share a name here, like `/a/bc` and `/ab/c`
+pub(crate) fn remove_seps_from_path(dir: &Path) -> OsString {
+    let mut path = OsString::new();
+    for component in dir.components() {
+        match component {
+            // Remove leading slash, which on Windows comes after the prefix
+            Component::RootDir => {}
+            // A Windows prefix can't be pushed onto another path without replacing it, and
+            // `\\?\C:` is the same directory as `C:`
+            Component::Prefix(prefix) => match prefix.kind() {
+                Prefix::Disk(drive) | Prefix::VerbatimDisk(drive) => {
+                    path.push(char::from(drive.to_ascii_uppercase()).to_string())
+                }
+                Prefix::UNC(server, share) | Prefix::VerbatimUNC(server, share) => {
+                    path.push("UNC");
+                    path.push(server);
+                    path.push(share);
+                }
+                Prefix::Verbatim(name) | Prefix::DeviceNS(name) => path.push(name),
+            },
+            _ => path.push(component.as_os_str()),
+        }
+    }
+    path
+}
+
+/// The workspace path with its separators removed, cut short if needed, followed by a hash of
+/// the whole path, which tells apart the workspaces that `remove_seps_from_path` can't. Every
+/// spelling of a Windows path has the same name, as it's the same tag
+fn tag_dir_name(dir: &Path) -> OsString {
+    // Collecting the components also drops any trailing separator
+    let dir: PathBuf = win_path::normalize(dir).components().collect();
+    let hash = hash_string(Sha1::digest(path_to_bytes(&dir)).into());
+
+    let readable = remove_seps_from_path(&dir);
+    let readable = readable.to_string_lossy();
+    let mut end = readable.len().min(MAX_READABLE_LEN);
+    while !readable.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}-{}", &readable[..end], &hash[..16]).into()
+}
+
+/// Every directory under `dir` (including itself) with a `.tag` file in it, and its tag.
+/// Branch names can contain slashes, so tag directories can be nested inside each other
+pub(crate) fn find_tag_dirs(
+    file_system: &dyn FileSystem,
+    dir: &Path,
+    found: &mut Vec<(PathBuf, OwnedTag)>,
+) -> Result<()> {
+    let tag_file = dir.join(".tag");
+    if file_system
+        .metadata(&tag_file)
+        .is_ok_and(|metadata| !metadata.is_dir)
+    {
+        let tag = String::from_utf8_lossy(&file_system.read(&tag_file)?)
+            .parse::<OwnedTag>()
+            .map_err(|err| {
+                SyncError::CorruptedIndex(format!("{} in {}", err, tag_file.display()))
+            })?;
+        found.push((dir.to_path_buf(), tag));
+    }
+    for name in file_system.read_dir(dir)? {
+        let path = dir.join(name);
+        if file_system.metadata(&path)?.is_dir {
+            find_tag_dirs(file_system, &path, found)?;
+        }
+    }
+    Ok(())
+}
+
+/// A directory in `temp_dir`, the system's temporary directory, that only the current user can
+/// get into, for the fallback index. On Unix it's named after the user's uid, so the next
+/// process finds it again, and created with mode 0700. If it's already there but isn't a
+/// directory of the user's that only they can use, someone else could have made it to read or
+/// plant index files, so a new directory with a random name is used instead, which lasts the
+/// process
+#[cfg(unix)]
+fn private_temp_dir(temp_dir: &Path) -> PathBuf {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+
+    // SAFETY: getuid can't fail, and only returns a number
+    let uid = unsafe { libc::getuid() };
+    let dir = temp_dir.join(format!("continue-index-{uid}"));
+    let _ = fs::DirBuilder::new().mode(0o700).create(&dir);
+    match fs::symlink_metadata(&dir) {
+        Ok(metadata)
+            if metadata.is_dir() && metadata.uid() == uid && metadata.mode() & 0o077 == 0 =>
+        {
+            dir
+        }
+        _ => {
+            let random = tempfile::Builder::new()
+                .prefix("continue-index-")
+                .tempdir_in(temp_dir)
+                .map(|dir| dir.into_path());
+            match random {
+                Ok(random) => {
+                    let _ = fs::set_permissions(&random, fs::Permissions::from_mode(0o700));
+                    random
+                }
+                // The sync will fail to write there too, but not into someone else's directory
+                Err(_) => temp_dir.join(format!("continue-index-{uid}-{}", rand::random::<u64>())),
+            }
+        }
+    }
+}
+
+/// Elsewhere the temporary directory is the user's own already
+#[cfg(not(unix))]
+fn private_temp_dir(temp_dir: &Path) -> PathBuf {
+    temp_dir.join("continue-index")
+}
+
+/// Clear away the directories between `tags_dir` and the tag directory moved out of `old_dir`
+/// that only held it
+pub(crate) fn remove_empty_parents(file_system: &dyn FileSystem, old_dir: &Path, tags_dir: &Path) {
+    let mut dir = old_dir.parent();
+    while let Some(parent) = dir.filter(|dir| *dir != tags_dir) {
+        if file_system.remove_dir(parent).is_err() {
+            break;
+        }
+        dir = parent.parent();
+    }
+}
+
+/// Overrides the default index root for every call that doesn't set `SyncOptions::index_root`
+pub const INDEX_ROOT_VAR: &str = "CONTINUE_INDEX_ROOT";
+
+/// Where all index state (trees, caches, rev_tags) is stored on disk
+///
+/// Defaults to ~/.continue/index, but can be pointed anywhere so that tests, servers,
+/// and non-standard setups don't have to share the user's index
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexRoot {
+    dir: PathBuf,
+    global_ignore_file: PathBuf,
+
+    /// Whether this is the temporary directory used when there's no home directory
+    fallback: bool,
+}
+
+impl IndexRoot {
+    /// Store the index in `dir`, along with its own global .continueignore
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        let dir = dir.into();
+        IndexRoot {
+            global_ignore_file: dir.join(".continueignore"),
+            dir,
+            fallback: false,
+        }
+    }
+
+    /// ~/.continue/index, sharing ~/.continue/.continueignore with the rest of Continue
+    pub fn from_home() -> Result<Self> {
+        IndexRoot::from_home_in(&OsFileSystem)
+    }
+
+    /// The same as `from_home`, with the home directory `file_system` has
+    pub(crate) fn from_home_in(file_system: &dyn FileSystem) -> Result<Self> {
+        let mut continue_dir = file_system.home_dir().ok_or(SyncError::MissingHomeDir)?;
+        continue_dir.push(".continue");
+
+        Ok(IndexRoot {
+            dir: continue_dir.join("index"),
+            global_ignore_file: continue_dir.join(".continueignore"),
+            fallback: false,
+        })
+    }
+
+    /// The index root used when `SyncOptions::index_root` isn't set: `$CONTINUE_INDEX_ROOT`,
+    /// then `from_home`. Where there's no home directory either (as in some containers and CI
+    /// jobs), a directory in the system's temporary directory, which syncs warn about
+    pub fn from_env_or_home() -> Self {
+        IndexRoot::from_env_or_home_in(&OsFileSystem)
+    }
+
+    /// The same as `from_env_or_home`, with the home directory `file_system` has
+    pub(crate) fn from_env_or_home_in(file_system: &dyn FileSystem) -> Self {
+        match std::env::var_os(INDEX_ROOT_VAR).filter(|dir| !dir.is_empty()) {
+            Some(dir) => IndexRoot::new(dir),
+            None => IndexRoot::from_home_in(file_system).unwrap_or_else(|_| IndexRoot::fallback()),
+        }
+    }
+
+    /// The index root in the temporary directory, which syncs warn about with
+    /// `SyncWarning::NoHomeDir`
+    fn fallback() -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        let temp_dir = std::env::temp_dir();
+        // There's no temporary directory to ask wasm32 for, so it's /tmp in the host's files
+        #[cfg(target_arch = "wasm32")]
+        let temp_dir = PathBuf::from("/tmp");
+        IndexRoot {
+            fallback: true,
+            ..IndexRoot::new(private_temp_dir(&temp_dir))
+        }
+    }
+
+    /// The warning every sync into the temporary fallback index gives, since it may not last
+    pub(crate) fn fallback_warning(&self) -> Option<SyncWarning> {
+        match self.fallback {
+            true => Some(SyncWarning::NoHomeDir {
+                index_root: self.dir.display().to_string(),
+            }),
+            false => None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn global_ignore_file(&self) -> &Path {
+        &self.global_ignore_file
+    }
+
+    /// <root>/tags/<dir>/<branch>/<provider_id>, where `<dir>` is named by `tag_dir_name`
+    pub(crate) fn tag_dir(&self, tag: &Tag) -> PathBuf {
+        let mut path = self.dir.join("tags");
+        path.push(tag_dir_name(tag.dir));
+        path.push(tag.branch);
+        path.push(tag.provider_id);
+        path
+    }
+
+    /// Move the tag directories of an index written by an older version, which were named by
+    /// `remove_seps_from_path`, to where `tag_dir` now puts them. Only tags with a `.tag` file
+    /// can be found this way; the rest are moved when they're next synced (see
+    /// `migrate::migrate_old_tag_dir`). The first of the index's migrations
+    pub(crate) fn migrate_tag_dirs(&self, file_system: &dyn FileSystem) -> Result<()> {
+        let tags_dir = self.dir.join("tags");
+        if !file_system
+            .metadata(&tags_dir)
+            .is_ok_and(|metadata| metadata.is_dir)
+        {
+            return Ok(());
+        }
+
+        let mut found = Vec::new();
+        find_tag_dirs(file_system, &tags_dir, &mut found)?;
+        // The innermost tag directories are moved out of the ones they're nested in first
+        found.sort_by_key(|(dir, _)| std::cmp::Reverse(dir.components().count()));
+        for (old_dir, tag) in found {
+            let new_dir = self.tag_dir(&tag.as_tag());
+            if old_dir == new_dir || file_system.metadata(&new_dir).is_ok() {
+                continue;
+            }
+            file_system.create_dir_all(new_dir.parent().unwrap_or(&tags_dir))?;
+            match file_system.rename(&old_dir, &new_dir) {
+                // Another process moved it first
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                result => result?,
+            }
+            remove_empty_parents(file_system, &old_dir, &tags_dir);
+        }
+        Ok(())
+    }
+
+    /// <root>/providers/<provider_id>
+    pub(crate) fn provider_dir(&self, provider_id: &str) -> PathBuf {
+        let mut path = self.dir.join("providers");
+        path.push(provider_id);
+        path
+    }
+
+    /// Every tag with a directory in the index, and that directory
+    pub(crate) fn tag_dirs_in(
+        &self,
+        file_system: &dyn FileSystem,
+    ) -> Result<Vec<(PathBuf, OwnedTag)>> {
+        let tags_dir = self.dir.join("tags");
+        let mut found = Vec::new();
+        if file_system
+            .metadata(&tags_dir)
+            .is_ok_and(|metadata| metadata.is_dir)
+        {
+            find_tag_dirs(file_system, &tags_dir, &mut found)?;
+        }
+        Ok(found)
+    }
+
+    /// Held by every sync of one of the provider's tags, since they all share its caches
+    pub(crate) fn provider_lock(&self, provider_id: &str) -> PathBuf {
+        self.provider_dir(provider_id).join(".lock")
+    }
+
+    /// The change to the provider's caches that is being written, with `IndexStorage::Files`
+    pub(crate) fn journal(&self, provider_id: &str) -> PathBuf {
+        self.provider_dir(provider_id).join(".journal")
+    }
+
+    /// Where rev_tags shards were kept before the database, only read to migrate them
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn rev_tags_dir(&self, provider_id: &str) -> PathBuf {
+        self.provider_dir(provider_id).join("rev_tags")
+    }
+
+    /// The content of the provider's files, by hash, with `SyncOptions::store_objects`
+    pub(crate) fn objects_dir(&self, provider_id: &str) -> PathBuf {
+        self.provider_dir(provider_id).join("objects")
+    }
+
+    /// SQLite full-text index of the provider's tags, for `SearchIndex`
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn search_db(&self, provider_id: &str) -> PathBuf {
+        self.provider_dir(provider_id).join("search.db")
+    }
+
+    /// SQLite index of the top-level symbols in the provider's files, for `SymbolIndex`
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn symbols_db(&self, provider_id: &str) -> PathBuf {
+        self.provider_dir(provider_id).join("symbols.db")
+    }
+
+    /// SQLite database holding every cache, for `IndexStorage::Sqlite`
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn index_db(&self) -> PathBuf {
+        self.dir.join("index.db")
+    }
+
+    /// SQLite database mapping hashes to the tags that reference them
+    pub(crate) fn rev_tags_db(&self, provider_id: &str) -> PathBuf {
+        self.provider_dir(provider_id).join("rev_tags.db")
+    }
+
+    /// The provider's rev_tags, when they're kept in the index's `FileSystem` rather than in
+    /// `rev_tags_db`
+    pub(crate) fn rev_tags_file(&self, provider_id: &str) -> PathBuf {
+        self.provider_dir(provider_id).join("rev_tags.json")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDirBuilder;
+
+    #[test]
+    fn test_index_root_layout() {
+        let root = IndexRoot::new("/tmp/index");
+        let tag = Tag {
+            dir: Path::new("/path/to/workspace"),
+            branch: "main",
+            provider_id: "default",
+        };
+
+        assert_eq!(
+            root.tag_dir(&tag),
+            Path::new("/tmp/index/tags")
+                .join(tag_dir_name(tag.dir))
+                .join("main/default")
+        );
+        assert_eq!(
+            root.rev_tags_db("default"),
+            Path::new("/tmp/index/providers/default/rev_tags.db")
+        );
+        assert_eq!(
+            root.global_ignore_file(),
+            Path::new("/tmp/index/.continueignore")
+        );
+    }
+
+    #[test]
+    fn test_fallback_index_root() {
+        let fallback = IndexRoot::fallback();
+        assert!(fallback.path().starts_with(std::env::temp_dir()));
+        assert_eq!(IndexRoot::fallback(), fallback);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let metadata = fs::metadata(fallback.path()).unwrap();
+            assert_eq!(metadata.uid(), unsafe { libc::getuid() });
+            assert_eq!(metadata.mode() & 0o777, 0o700);
+        }
+        assert_eq!(
+            fallback.global_ignore_file(),
+            fallback.path().join(".continueignore")
+        );
+        assert!(matches!(
+            fallback.fallback_warning(),
+            Some(SyncWarning::NoHomeDir { index_root }) if Path::new(&index_root) == fallback.path()
+        ));
+        assert_eq!(IndexRoot::new("/tmp/index").fallback_warning(), None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_private_temp_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDirBuilder::new().create();
+        let dir = private_temp_dir(temp_dir.path());
+        assert_eq!(dir, private_temp_dir(temp_dir.path()));
+
+        // One anyone could have put there isn't trusted
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o777)).unwrap();
+        let other = private_temp_dir(temp_dir.path());
+        assert_ne!(other, dir);
+        assert!(other.starts_with(temp_dir.path()));
+        let mode = fs::metadata(&other).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+
+    #[test]
+    fn test_tag_dir_names() {
+        let name = tag_dir_name(Path::new("/path/to/workspace"));
+        let name = name.to_str().unwrap();
+        assert!(name.starts_with("pathtoworkspace-"));
+        assert_eq!(name.len(), "pathtoworkspace-".len() + 16);
+
+        // Paths that only differ in where their separators are get their own directories
+        assert_ne!(
+            tag_dir_name(Path::new("/a/bc")),
+            tag_dir_name(Path::new("/ab/c"))
+        );
+        assert_eq!(
+            tag_dir_name(Path::new("/a/bc/")),
+            tag_dir_name(Path::new("/a/bc"))
+        );
+
+        let long = tag_dir_name(&Path::new("/").join("é".repeat(100)));
+        assert!(long.len() <= MAX_READABLE_LEN + 17);
+    }
+
+    #[test]
+    fn test_migrate_tag_dirs() {
+        let temp_dir = TempDirBuilder::new().create();
+        let root = IndexRoot::new(temp_dir.path());
+        let tag = |dir: &'static str, branch: &'static str| Tag {
+            dir: Path::new(dir),
+            branch,
+            provider_id: "default",
+        };
+        // Two workspaces that shared a directory, and a branch nested inside another
+        let tags = [tag("/a/bc", "main"), tag("/ab/c", "main/default")];
+        for tag in &tags {
+            let old_dir = temp_dir
+                .path()
+                .join("tags")
+                .join(remove_seps_from_path(tag.dir))
+                .join(tag.branch)
+                .join(tag.provider_id);
+            fs::create_dir_all(&old_dir).unwrap();
+            fs::write(old_dir.join(".tag"), tag.to_string()).unwrap();
+            fs::write(old_dir.join("merkle_tree"), tag.branch).unwrap();
+        }
+
+        root.migrate_tag_dirs(&OsFileSystem).unwrap();
+        for tag in &tags {
+            let tree = fs::read_to_string(root.tag_dir(tag).join("merkle_tree")).unwrap();
+            assert_eq!(tree, tag.branch);
+        }
+        assert!(!temp_dir.path().join("tags/abc").exists());
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn test_windows_tag_dirs() {
+        let root = IndexRoot::new(r"C:\index");
+        let tag_dir = |dir: &str| {
+            root.tag_dir(&Tag {
+                dir: Path::new(dir),
+                branch: "main",
+                provider_id: "default",
+            })
+        };
+
+        // Every spelling of a directory shares one tag directory inside the root
+        for dir in [r"C:\Users\me", "c:/Users/me", r"\\?\C:\Users\me"] {
+            assert_eq!(tag_dir(dir), tag_dir(r"C:\Users\me"));
+        }
+        for dir in [r"\\server\share\me", r"\\?\UNC\server\share\me"] {
+            assert_eq!(tag_dir(dir), tag_dir(r"\\server\share\me"));
+        }
+        let name = tag_dir(r"\\server\share\me");
+        let name = name
+            .strip_prefix(r"C:\index\tags")
+            .unwrap()
+            .to_str()
+            .unwrap();
+        assert!(name.starts_with("UNCservershareme-"));
+    }
+}
@@ -0,0 +1,169 @@
+//! The journal that makes a bulk change to the file caches all-or-nothing
+//!
+//! With `IndexStorage::Files`, one change to the caches touches three files: the provider's
+//! global cache, the tag's cache and the rev_tags database. Before any of them is written, the
+//! whole change is saved to `<provider dir>/.journal`, ending with a checksum that marks it as
+//! complete, and the journal is removed once all three are written. rev_tags records the id of
+//! the last journal it committed along with the change itself.
+//!
+//! If the journal is still there when the caches are next opened, the change was interrupted:
+//! a complete journal is applied again (skipping rev_tags if it already has the id), which is
+//! safe because adding and removing hashes in the caches can be repeated, and an incomplete one
+//! is dropped, since nothing had been written yet.
+
+use super::{error::Result, file_system::FileSystem, merkle::ObjectHash};
+use sha1::{Digest, Sha1};
+use std::{
+    convert::TryInto,
+    io::ErrorKind,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const MAGIC: &[u8; 4] = b"JRN1";
+const CHECKSUM_SIZE: usize = 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum JournalOp {
+    /// `IndexCache::add_global`
+    AddGlobal = 0,
+    /// `IndexCache::global_remove`
+    GlobalRemove = 1,
+    /// `IndexCache::local_remove`
+    LocalRemove = 2,
+}
+
+/// One bulk change, made by `tag`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct JournalEntry {
+    pub(crate) id: i64,
+    pub(crate) tag: String,
+    pub(crate) ops: Vec<(JournalOp, ObjectHash)>,
+}
+
+impl JournalEntry {
+    /// An entry with an id that hasn't been used by an earlier one
+    pub(crate) fn new(tag: String, ops: Vec<(JournalOp, ObjectHash)>) -> Self {
+        let id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_nanos() as i64);
+        JournalEntry { id, tag, ops }
+    }
+
+    pub(crate) fn write(
+        &self,
+        file_system: &dyn FileSystem,
+        path: &Path,
+        fsync: bool,
+    ) -> Result<()> {
+        file_system.write(path, &self.encode(), fsync)?;
+        Ok(())
+    }
+
+    /// The entry in the journal at `path`, or None if there isn't one or it was never finished
+    pub(crate) fn read(file_system: &dyn FileSystem, path: &Path) -> Result<Option<Self>> {
+        match file_system.read(path) {
+            Ok(contents) => Ok(Self::decode(&contents)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The magic, the id, the length-prefixed tag, the number of ops and each op (its kind,
+    /// then the hash), then a SHA-1 of everything before it
+    fn encode(&self) -> Vec<u8> {
+        let mut contents = MAGIC.to_vec();
+        contents.extend_from_slice(&self.id.to_le_bytes());
+        contents.extend_from_slice(&(self.tag.len() as u32).to_le_bytes());
+        contents.extend_from_slice(self.tag.as_bytes());
+        contents.extend_from_slice(&(self.ops.len() as u32).to_le_bytes());
+        for (op, hash) in &self.ops {
+            contents.push(*op as u8);
+            contents.extend_from_slice(hash);
+        }
+        let checksum = Sha1::digest(&contents);
+        contents.extend_from_slice(&checksum);
+        contents
+    }
+
+    fn decode(contents: &[u8]) -> Option<Self> {
+        let body_len = contents.len().checked_sub(CHECKSUM_SIZE)?;
+        let (body, checksum) = contents.split_at(body_len);
+        if Sha1::digest(body)[..] != checksum[..] || !body.starts_with(MAGIC) {
+            return None;
+        }
+
+        let mut rest = &body[MAGIC.len()..];
+        let mut take = |len: usize| {
+            let (taken, remaining) = (rest.get(..len)?, rest.get(len..)?);
+            rest = remaining;
+            Some(taken)
+        };
+        let id = i64::from_le_bytes(take(8)?.try_into().ok()?);
+        let tag_len = u32::from_le_bytes(take(4)?.try_into().ok()?) as usize;
+        let tag = String::from_utf8(take(tag_len)?.to_vec()).ok()?;
+        let num_ops = u32::from_le_bytes(take(4)?.try_into().ok()?) as usize;
+        let mut ops = Vec::new();
+        for _ in 0..num_ops {
+            let op = match take(1)?[0] {
+                0 => JournalOp::AddGlobal,
+                1 => JournalOp::GlobalRemove,
+                2 => JournalOp::LocalRemove,
+                _ => return None,
+            };
+            ops.push((op, take(20)?.try_into().ok()?));
+        }
+        match rest.is_empty() {
+            true => Some(JournalEntry { id, tag, ops }),
+            false => None,
+        }
+    }
+}
+
+/// Remove the journal once its change is safely in every cache
+pub(crate) fn remove(file_system: &dyn FileSystem, path: &Path) -> Result<()> {
+    match file_system.remove_file(path) {
+        Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sync::OsFileSystem, utils::TempDirBuilder};
+
+    #[test]
+    fn test_journal_entry() {
+        let temp_dir = TempDirBuilder::new().create();
+        let path = temp_dir.path().join(".journal");
+        assert_eq!(JournalEntry::read(&OsFileSystem, &path).unwrap(), None);
+
+        let entry = JournalEntry::new(
+            "tag".to_string(),
+            vec![
+                (JournalOp::AddGlobal, [1; 20]),
+                (JournalOp::GlobalRemove, [2; 20]),
+                (JournalOp::LocalRemove, [3; 20]),
+            ],
+        );
+        entry.write(&OsFileSystem, &path, false).unwrap();
+        assert_eq!(
+            JournalEntry::read(&OsFileSystem, &path).unwrap(),
+            Some(entry.clone())
+        );
+
+        // A journal cut short anywhere, or changed, doesn't count
+        let contents = entry.encode();
+        for len in 0..contents.len() {
+            assert_eq!(JournalEntry::decode(&contents[..len]), None);
+        }
+        let mut changed = contents;
+        changed[10] ^= 1;
+        assert_eq!(JournalEntry::decode(&changed), None);
+
+        remove(&OsFileSystem, &path).unwrap();
+        remove(&OsFileSystem, &path).unwrap();
+        assert_eq!(JournalEntry::read(&OsFileSystem, &path).unwrap(), None);
+    }
+}
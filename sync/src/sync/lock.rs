@@ -0,0 +1,295 @@
+//! Advisory file locks that stop two processes sharing an index from syncing over each other.
+//! They're flock on Unix and LockFileEx on Windows; anywhere else every lock is granted straight
+//! away
+
+use super::{
+    error::{Result, SyncError},
+    options::{LockWait, SyncOptions},
+};
+use std::{
+    fs::{File, OpenOptions},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long to sleep between attempts while waiting with a timeout
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// An exclusive lock on a file, held until this is dropped
+pub(crate) struct FileLock {
+    file: File,
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // Closing the file releases it anyway, though on Windows not necessarily at once
+        let _ = imp::unlock(&self.file);
+    }
+}
+
+/// Take the lock on the file at `path`, creating it if needed. If another process (or another
+/// `FileLock` in this one) holds it, `wait` decides whether to wait for it
+pub(crate) fn lock(path: &Path, wait: LockWait) -> Result<FileLock> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    let locked = || SyncError::Locked(path.display().to_string());
+
+    let deadline = match wait {
+        LockWait::Block => {
+            imp::lock(&file)?;
+            return Ok(FileLock { file });
+        }
+        LockWait::Fail => Instant::now(),
+        LockWait::Timeout(timeout) => Instant::now() + timeout,
+    };
+    loop {
+        if imp::try_lock(&file)? {
+            return Ok(FileLock { file });
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(locked());
+        }
+        thread::sleep(POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+/// A lock from `lock_index`, held until it's dropped
+pub(crate) type IndexLock = Box<dyn Send>;
+
+/// Take the lock at `path` in the index, which is in `SyncOptions::index_file_system` if
+/// there is one. The OS's own locks are waited on as `lock` does, and any other `FileSystem`'s
+/// are polled
+pub(crate) fn lock_index(options: &SyncOptions, path: &Path, wait: LockWait) -> Result<IndexLock> {
+    let file_system = match &options.index_file_system {
+        Some(file_system) => file_system,
+        None => return Ok(Box::new(lock(path, wait)?)),
+    };
+    let locked = || SyncError::Locked(path.display().to_string());
+    // wasm32 has a single thread, so nothing would let go of the lock while this waited
+    if cfg!(target_arch = "wasm32") {
+        return file_system.try_lock(path)?.ok_or_else(locked);
+    }
+
+    let deadline = match wait {
+        LockWait::Block => None,
+        LockWait::Fail => Some(Instant::now()),
+        LockWait::Timeout(timeout) => Some(Instant::now() + timeout),
+    };
+    loop {
+        if let Some(held) = file_system.try_lock(path)? {
+            return Ok(held);
+        }
+        let wait = match deadline {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+            None => POLL_INTERVAL,
+        };
+        if wait.is_zero() {
+            return Err(locked());
+        }
+        thread::sleep(POLL_INTERVAL.min(wait));
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::{fs::File, io, os::unix::io::AsRawFd};
+
+    fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+        loop {
+            match unsafe { libc::flock(file.as_raw_fd(), operation) } {
+                0 => return Ok(()),
+                _ => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+
+    pub(super) fn lock(file: &File) -> io::Result<()> {
+        flock(file, libc::LOCK_EX)
+    }
+
+    /// Whether the lock was taken, without waiting for it
+    pub(super) fn try_lock(file: &File) -> io::Result<bool> {
+        match flock(file, libc::LOCK_EX | libc::LOCK_NB) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub(super) fn unlock(file: &File) -> io::Result<()> {
+        flock(file, libc::LOCK_UN)
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::{ffi::c_void, fs::File, io, os::windows::io::AsRawHandle};
+
+    const LOCKFILE_FAIL_IMMEDIATELY: u32 = 0x1;
+    const LOCKFILE_EXCLUSIVE_LOCK: u32 = 0x2;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+
+    #[repr(C)]
+    struct Overlapped {
+        internal: usize,
+        internal_high: usize,
+        offset: u32,
+        offset_high: u32,
+        event: *mut c_void,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn LockFileEx(
+            file: *mut c_void,
+            flags: u32,
+            reserved: u32,
+            bytes_low: u32,
+            bytes_high: u32,
+            overlapped: *mut Overlapped,
+        ) -> i32;
+        fn UnlockFileEx(
+            file: *mut c_void,
+            reserved: u32,
+            bytes_low: u32,
+            bytes_high: u32,
+            overlapped: *mut Overlapped,
+        ) -> i32;
+    }
+
+    /// Where every lock starts, which is the start of the file. They run to the end of any file
+    fn whole_file() -> Overlapped {
+        Overlapped {
+            internal: 0,
+            internal_high: 0,
+            offset: 0,
+            offset_high: 0,
+            event: std::ptr::null_mut(),
+        }
+    }
+
+    fn lock_file(file: &File, flags: u32) -> io::Result<()> {
+        let mut overlapped = whole_file();
+        // SAFETY: the handle is open for as long as `file` is borrowed, and the file was opened
+        // for synchronous I/O, so the call is done with `overlapped` when it returns
+        let locked = unsafe {
+            LockFileEx(
+                file.as_raw_handle(),
+                flags,
+                0,
+                u32::MAX,
+                u32::MAX,
+                &mut overlapped,
+            )
+        };
+        match locked {
+            0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    pub(super) fn lock(file: &File) -> io::Result<()> {
+        lock_file(file, LOCKFILE_EXCLUSIVE_LOCK)
+    }
+
+    /// Whether the lock was taken, without waiting for it
+    pub(super) fn try_lock(file: &File) -> io::Result<bool> {
+        match lock_file(file, LOCKFILE_EXCLUSIVE_LOCK | LOCKFILE_FAIL_IMMEDIATELY) {
+            Ok(()) => Ok(true),
+            Err(err) if err.raw_os_error() == Some(ERROR_LOCK_VIOLATION) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub(super) fn unlock(file: &File) -> io::Result<()> {
+        let mut overlapped = whole_file();
+        // SAFETY: as in `lock_file`
+        let unlocked =
+            unsafe { UnlockFileEx(file.as_raw_handle(), 0, u32::MAX, u32::MAX, &mut overlapped) };
+        match unlocked {
+            0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod imp {
+    use std::{fs::File, io};
+
+    pub(super) fn lock(_file: &File) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub(super) fn try_lock(_file: &File) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    pub(super) fn unlock(_file: &File) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sync::MemoryFileSystem, utils::TempDirBuilder};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_lock() {
+        let temp_dir = TempDirBuilder::new().create();
+        let path = temp_dir.path().join(".lock");
+
+        let held = lock(&path, LockWait::Fail).unwrap();
+        if cfg!(any(unix, windows)) {
+            assert!(matches!(
+                lock(&path, LockWait::Fail),
+                Err(SyncError::Locked(_))
+            ));
+            let start = Instant::now();
+            assert!(matches!(
+                lock(&path, LockWait::Timeout(Duration::from_millis(50))),
+                Err(SyncError::Locked(_))
+            ));
+            assert!(start.elapsed() >= Duration::from_millis(50));
+        }
+
+        // Released on drop, including to a lock that's waiting for it
+        let waiter = thread::spawn(move || lock(&path, LockWait::Block).map(|_| ()));
+        thread::sleep(Duration::from_millis(20));
+        drop(held);
+        waiter.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_lock_index() {
+        let file_system = Arc::new(MemoryFileSystem::new());
+        let options = SyncOptions::new().index_file_system(file_system.clone());
+        let path = Path::new("/index/default.lock");
+
+        let held = lock_index(&options, path, LockWait::Fail).unwrap();
+        assert!(matches!(
+            lock_index(&options, path, LockWait::Timeout(Duration::from_millis(20))),
+            Err(SyncError::Locked(_))
+        ));
+        // Nothing was written to the disk for it
+        assert!(!path.exists());
+
+        let waiter = thread::spawn(move || lock_index(&options, path, LockWait::Block).map(|_| ()));
+        thread::sleep(Duration::from_millis(20));
+        drop(held);
+        waiter.join().unwrap().unwrap();
+    }
+}
@@ -1,14 +1,61 @@
-use homedir::get_my_home;
-use ignore::{Walk, WalkBuilder};
+use super::{
+    archive, atomic,
+    encoding::{binary_error, Decoder},
+    error::{Result, SyncError},
+    file_system::{FileMetadata, FileSystem, OsFileSystem},
+    gzip,
+    index_root::IndexRoot,
+    mmap::Mmap,
+    nfc,
+    options::SyncOptions,
+    tag::canonical_dir,
+    throttle::{self, LowPriority},
+    warning::SyncWarning,
+    win_path, xattr,
+};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    overrides::{Override, OverrideBuilder},
+    DirEntry, Match, Walk, WalkBuilder,
+};
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 use std::{
-    io::{Read, Result, Write},
-    path::{Path, PathBuf},
+    borrow::Cow,
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::{TryFrom, TryInto},
+    fs::{self, Metadata},
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 pub type ObjectHash = [u8; 20];
 
+/// Where `Tree::persist` keeps the directory records for the tree file at `filepath`
+pub(crate) fn subtrees_dir(filepath: &Path) -> PathBuf {
+    let mut file_name = filepath.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".subtrees");
+    filepath.with_file_name(file_name)
+}
+
+/// The first line of the tree file at `filepath`: its manifest, or the root node of a tree
+/// saved as one JSONL file by older versions
+fn first_line(file_system: &dyn FileSystem, filepath: &Path) -> Result<String> {
+    let contents = file_system.read(filepath)?;
+    let line = contents
+        .split(|&byte| byte == b'\n')
+        .next()
+        .unwrap_or_default();
+    Ok(String::from_utf8_lossy(line).into_owned())
+}
+
 pub fn hash_string(hash: ObjectHash) -> String {
     hash.iter().fold(String::new(), |mut output, byte| {
         output.push_str(&format!("{byte:02x}"));
@@ -16,12 +63,118 @@ pub fn hash_string(hash: ObjectHash) -> String {
     })
 }
 
-#[derive(Clone, Default)]
+/// Inverse of `hash_string`
+pub fn parse_hash(hash_str: &str) -> Option<ObjectHash> {
+    if hash_str.len() != 2 * std::mem::size_of::<ObjectHash>() || !hash_str.is_ascii() {
+        return None;
+    }
+    let mut hash = ObjectHash::default();
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hash_str[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// Serialize an `ObjectHash` as its hex string, for use with `#[serde(with = "hex_hash")]`
+pub(crate) mod hex_hash {
+    use super::{hash_string, parse_hash, ObjectHash};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(hash: &ObjectHash, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hash_string(*hash))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ObjectHash, D::Error> {
+        let hash_str = String::deserialize(deserializer)?;
+        parse_hash(&hash_str).ok_or_else(|| D::Error::custom(format!("invalid hash {hash_str:?}")))
+    }
+}
+
+/// The bytes of a path. On Unix these are exactly the bytes the filesystem uses; elsewhere paths
+/// are Unicode, apart from unpaired surrogates on Windows, which are replaced
+pub(crate) fn path_to_bytes(path: &Path) -> Cow<'_, [u8]> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Cow::Borrowed(path.as_os_str().as_bytes())
+    }
+    #[cfg(not(unix))]
+    match path.to_string_lossy() {
+        Cow::Borrowed(path) => Cow::Borrowed(path.as_bytes()),
+        Cow::Owned(path) => Cow::Owned(path.into_bytes()),
+    }
+}
+
+/// Inverse of `path_to_bytes`
+pub(crate) fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+        PathBuf::from(std::ffi::OsString::from_vec(bytes))
+    }
+    #[cfg(not(unix))]
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Serialize a path as a string if it's valid UTF-8, or as an array of its bytes otherwise, for
+/// use with `#[serde(with = "os_path")]`. Either can be deserialized, so the paths of files
+/// with non-UTF-8 names survive being saved
+pub(crate) mod os_path {
+    use super::{path_from_bytes, path_to_bytes};
+    use serde::{
+        de::{self, SeqAccess, Visitor},
+        Deserializer, Serializer,
+    };
+    use std::{
+        fmt,
+        path::{Path, PathBuf},
+    };
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        match path.to_str() {
+            Some(path) => serializer.serialize_str(path),
+            None => serializer.serialize_bytes(&path_to_bytes(path)),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        struct PathVisitor;
+
+        impl<'de> Visitor<'de> for PathVisitor {
+            type Value = PathBuf;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a path, as a string or an array of bytes")
+            }
+
+            fn visit_str<E: de::Error>(self, path: &str) -> Result<PathBuf, E> {
+                Ok(PathBuf::from(path))
+            }
+
+            fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<PathBuf, E> {
+                Ok(path_from_bytes(bytes.to_vec()))
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<PathBuf, A::Error> {
+                let mut bytes = Vec::new();
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(path_from_bytes(bytes))
+            }
+        }
+
+        deserializer.deserialize_any(PathVisitor)
+    }
+}
+
+/// A directory in the Merkle tree, whose hash is computed from the hashes of its children
+#[derive(Clone, Debug, Default)]
 pub struct Tree {
     parent: Option<ObjectHash>,
     children: Vec<Object>,
     hash: ObjectHash,
-    path: String,
+    path: PathBuf,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -31,18 +184,463 @@ struct SerializeableNode {
     /// Blobs would have no children
     children: Option<Vec<ObjectHash>>,
     hash: ObjectHash,
-    path: String,
+    #[serde(with = "os_path")]
+    path: PathBuf,
+
+    /// Only set for blobs. Trees written before these existed don't have them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mtime: Option<u64>,
+}
+
+/// The only line of a tree file written by `persist`. The tree itself is kept as one record per
+/// directory in `<file>.subtrees`, each named after the hash of its own contents, so a directory
+/// whose record already exists is never written again
+#[derive(Serialize, Deserialize)]
+struct TreeManifest {
+    /// Missing from manifests written before it was added, which are the same as version 1
+    #[serde(default = "first_manifest_version")]
+    version: u32,
+    hash: ObjectHash,
+
+    /// Name of the root directory's record
+    root: String,
+}
+
+/// Parents aren't stored, since they are known for everything once the tree is loaded, and
+/// would otherwise change the record of every directory next to one that changed
+#[derive(Serialize, Deserialize)]
+struct SubtreeRecord {
+    hash: ObjectHash,
+    #[serde(with = "os_path")]
+    path: PathBuf,
+    children: Vec<ChildRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum ChildRecord {
+    Blob(SerializeableNode),
+
+    /// Name of the directory's own record
+    Tree(String),
+}
+
+/// How `Tree::persist_as` writes directory records. Either can be loaded, whichever was used
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TreeFormat {
+    /// Compact and quick to parse
+    #[default]
+    Binary,
+
+    /// Readable, for debugging
+    Json,
+}
+
+/// Whether `Tree::persist_as` compresses directory records. Compressed records are recognised
+/// when loading either way
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+}
+
+/// Binary records start with these, followed by a version byte. JSON records start with `{`
+const RECORD_MAGIC: &[u8; 4] = b"CMT\0";
+const RECORD_VERSION: u8 = 1;
+
+const MANIFEST_VERSION: u32 = 1;
+
+fn first_manifest_version() -> u32 {
+    1
+}
+
+impl TreeManifest {
+    /// The manifest on the first line of a tree file, if that's what it is. A newer one than
+    /// this version understands is corrupted to it
+    fn parse(line: &str) -> Result<Option<Self>> {
+        match serde_json::from_str::<TreeManifest>(line) {
+            Ok(manifest) if manifest.version > MANIFEST_VERSION => Err(SyncError::CorruptedIndex(
+                format!("tree manifest has unknown version {}", manifest.version),
+            )),
+            Ok(manifest) => Ok(Some(manifest)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Point `filepath` at a new tree, then delete the records that aren't `live` in it
+    fn write(
+        &self,
+        file_system: &dyn FileSystem,
+        filepath: &Path,
+        live: &HashSet<String>,
+        fsync: bool,
+    ) -> Result<()> {
+        let mut manifest = serde_json::to_string(self)?;
+        manifest.push('\n');
+        file_system.write(filepath, manifest.as_bytes(), fsync)?;
+
+        // Only now is nothing pointing at the records of directories that changed or went away
+        let subtrees_dir = subtrees_dir(filepath);
+        let names = match file_system.read_dir(&subtrees_dir) {
+            Ok(names) => names,
+            // A file system without empty directories has none for a tree without records
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        for name in names {
+            if !live.contains(name.to_str().unwrap_or_default()) {
+                match file_system.remove_file(&subtrees_dir.join(name)) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SubtreeRecord {
+    fn read(file_system: &dyn FileSystem, subtrees_dir: &Path, name: &str) -> Result<Self> {
+        let corrupted = |message: String| {
+            SyncError::CorruptedIndex(format!("subtree record {name}: {message}"))
+        };
+        let contents = match file_system.read(&subtrees_dir.join(name)) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(corrupted("missing".to_string()))
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if hash_string(Sha1::digest(&contents).into()) != name {
+            return Err(corrupted("contents don't match its name".to_string()));
+        }
+        let record = Self::decode(&contents).map_err(corrupted)?;
+        for child in &record.children {
+            if let ChildRecord::Blob(node) = child {
+                check_child_path(&record.path, &node.path).map_err(corrupted)?;
+            }
+        }
+        Ok(record)
+    }
+
+    /// `read`, for the record of a directory saved in the one at `parent`
+    fn read_child(
+        file_system: &dyn FileSystem,
+        subtrees_dir: &Path,
+        name: &str,
+        parent: &Path,
+    ) -> Result<Self> {
+        let record = Self::read(file_system, subtrees_dir, name)?;
+        check_child_path(parent, &record.path).map_err(|message| {
+            SyncError::CorruptedIndex(format!("subtree record {name}: {message}"))
+        })?;
+        Ok(record)
+    }
+
+    /// Returns the record's name, only writing it if `save` is set and it doesn't exist yet
+    fn write(
+        &self,
+        file_system: &dyn FileSystem,
+        subtrees_dir: &Path,
+        format: TreeFormat,
+        compression: Compression,
+        fsync: bool,
+        save: bool,
+    ) -> Result<String> {
+        let contents = self.encode(format, compression)?;
+        let name = hash_string(Sha1::digest(&contents).into());
+        let record_path = subtrees_dir.join(&name);
+        if save && file_system.metadata(&record_path).is_err() {
+            file_system.write(&record_path, &contents, fsync)?;
+        }
+        Ok(name)
+    }
+
+    fn encode(&self, format: TreeFormat, compression: Compression) -> Result<Vec<u8>> {
+        let contents = match format {
+            TreeFormat::Binary => self.to_binary(),
+            TreeFormat::Json => serde_json::to_vec(self)?,
+        };
+        match compression {
+            Compression::None => Ok(contents),
+            Compression::Gzip => Ok(gzip::compress(&contents)),
+        }
+    }
+
+    fn decode(contents: &[u8]) -> std::result::Result<Self, String> {
+        if gzip::is_gzip(contents) {
+            return Self::decode(&gzip::decompress(contents)?);
+        }
+        match contents.strip_prefix(RECORD_MAGIC) {
+            Some(rest) => Self::from_binary(rest),
+            None => serde_json::from_slice(contents).map_err(|err| err.to_string()),
+        }
+    }
+
+    /// Paths are a u32 length followed by their bytes (see `path_to_bytes`), and all numbers
+    /// are little-endian
+    fn to_binary(&self) -> Vec<u8> {
+        fn push_path(out: &mut Vec<u8>, path: &Path) {
+            let bytes = path_to_bytes(path);
+            out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            out.extend_from_slice(&bytes);
+        }
+
+        let mut out = RECORD_MAGIC.to_vec();
+        out.push(RECORD_VERSION);
+        out.extend_from_slice(&self.hash);
+        push_path(&mut out, &self.path);
+        out.extend_from_slice(&(self.children.len() as u32).to_le_bytes());
+        for child in &self.children {
+            match child {
+                ChildRecord::Blob(node) => {
+                    out.push(0);
+                    out.extend_from_slice(&node.hash);
+                    push_path(&mut out, &node.path);
+                    match (node.size, node.mtime) {
+                        (Some(size), Some(mtime)) => {
+                            out.push(1);
+                            out.extend_from_slice(&size.to_le_bytes());
+                            out.extend_from_slice(&mtime.to_le_bytes());
+                        }
+                        _ => out.push(0),
+                    }
+                }
+                ChildRecord::Tree(name) => {
+                    out.push(1);
+                    out.extend_from_slice(&parse_hash(name).expect("record names are hashes"));
+                }
+            }
+        }
+        out
+    }
+
+    fn from_binary(bytes: &[u8]) -> std::result::Result<Self, String> {
+        let mut reader = BinaryReader { bytes };
+        let version = reader.u8()?;
+        if version != RECORD_VERSION {
+            return Err(format!("unsupported version {version}"));
+        }
+
+        let hash = reader.hash()?;
+        let path = reader.path()?;
+        let num_children = reader.u32()?;
+        let mut children = Vec::new();
+        for _ in 0..num_children {
+            let child = match reader.u8()? {
+                0 => {
+                    let hash = reader.hash()?;
+                    let path = reader.path()?;
+                    let (size, mtime) = match reader.u8()? {
+                        0 => (None, None),
+                        _ => (Some(reader.u64()?), Some(reader.u64()?)),
+                    };
+                    ChildRecord::Blob(SerializeableNode {
+                        parent: None,
+                        children: None,
+                        hash,
+                        path,
+                        size,
+                        mtime,
+                    })
+                }
+                1 => ChildRecord::Tree(hash_string(reader.hash()?)),
+                kind => return Err(format!("unknown child kind {kind}")),
+            };
+            children.push(child);
+        }
+        if !reader.bytes.is_empty() {
+            return Err("trailing bytes".to_string());
+        }
+
+        Ok(SubtreeRecord {
+            hash,
+            path,
+            children,
+        })
+    }
+}
+
+/// Directories nested deeper than this in a saved tree are taken to be corrupted, rather than
+/// recursed into until the stack runs out. It's far deeper than any real project, but shallow
+/// enough for the 2 MiB stack of a spawned thread
+const MAX_TREE_DEPTH: usize = 256;
+
+/// A saved path is only trusted if it names something directly inside the directory it was
+/// saved in, so that a corrupted or crafted tree can't lead anywhere outside the tag's root
+fn check_child_path(parent: &Path, child: &Path) -> std::result::Result<(), String> {
+    let escapes = || {
+        format!(
+            "{} isn't directly inside {}",
+            child.display(),
+            parent.display()
+        )
+    };
+    let mut components = child
+        .strip_prefix(parent)
+        .map_err(|_| escapes())?
+        .components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(()),
+        _ => Err(escapes()),
+    }
+}
+
+fn check_depth(depth: usize) -> Result<()> {
+    match depth > MAX_TREE_DEPTH {
+        true => Err(SyncError::CorruptedIndex(format!(
+            "tree is nested more than {MAX_TREE_DEPTH} directories deep"
+        ))),
+        false => Ok(()),
+    }
+}
+
+/// Two children with the same path would each be reported as the file that's there
+fn check_unique_children(path: &Path, children: &[Object]) -> Result<()> {
+    let mut seen = HashSet::new();
+    for child in children {
+        if !seen.insert(child.path()) {
+            return Err(SyncError::CorruptedIndex(format!(
+                "{} is saved twice in {}",
+                child.path().display(),
+                path.display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) struct BinaryReader<'a> {
+    pub(crate) bytes: &'a [u8],
+}
+
+impl<'a> BinaryReader<'a> {
+    pub(crate) fn take(&mut self, len: usize) -> std::result::Result<&'a [u8], String> {
+        if self.bytes.len() < len {
+            return Err("ended early".to_string());
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub(crate) fn u8(&mut self) -> std::result::Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> std::result::Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> std::result::Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn hash(&mut self) -> std::result::Result<ObjectHash, String> {
+        Ok(self.take(20)?.try_into().unwrap())
+    }
+
+    fn path(&mut self) -> std::result::Result<PathBuf, String> {
+        let len = self.u32()? as usize;
+        Ok(path_from_bytes(self.take(len)?.to_vec()))
+    }
 }
 
-#[derive(Clone)]
-struct Blob {
+/// A file in the Merkle tree, whose hash is computed from its contents
+#[derive(Clone, Debug)]
+pub struct Blob {
     parent: Option<ObjectHash>,
     hash: ObjectHash,
-    path: String,
+    path: PathBuf,
+
+    /// The file's size and modification time when it was hashed, if known
+    stat: Option<FileStat>,
+}
+
+/// Enough metadata to tell that a file probably hasn't changed since it was last hashed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct FileStat {
+    size: u64,
+
+    /// Nanoseconds since the epoch
+    mtime: u64,
+}
+
+impl FileStat {
+    fn from_metadata(metadata: &Metadata) -> Option<Self> {
+        Some(FileStat {
+            size: metadata.len(),
+            mtime: epoch_nanos(metadata.modified().ok()?)?,
+        })
+    }
+
+    /// The same, from a `FileSystem`, which may not know when the file was written
+    fn from_file_metadata(metadata: FileMetadata) -> Option<Self> {
+        Some(FileStat {
+            size: metadata.len,
+            mtime: epoch_nanos(metadata.modified?)?,
+        })
+    }
+
+    /// Whether the file was written after `since` (in nanoseconds since the epoch). Such a
+    /// stat can't be trusted to find the file unchanged later: another write within the same
+    /// tick of the filesystem's clock would leave it exactly the same
+    fn modified_since(self, since: u64) -> bool {
+        self.mtime >= since
+    }
+}
+
+fn epoch_nanos(time: SystemTime) -> Option<u64> {
+    u64::try_from(time.duration_since(UNIX_EPOCH).ok()?.as_nanos()).ok()
+}
+
+/// When a walk started, to tell what changed while the sync was running
+#[derive(Clone, Copy)]
+struct WalkStart(u64);
+
+impl WalkStart {
+    fn now() -> Self {
+        WalkStart(epoch_nanos(SystemTime::now()).unwrap_or(u64::MAX))
+    }
+
+    /// The stat a walked file is hashed with: none if it was written since the walk started,
+    /// in which case it's reported in `warnings` and hashed again by the next sync
+    fn file_stat(
+        self,
+        path: &Path,
+        metadata: &Metadata,
+        warnings: &mut Vec<SyncWarning>,
+    ) -> Option<FileStat> {
+        let stat = FileStat::from_metadata(metadata)?;
+        if !stat.modified_since(self.0) {
+            return Some(stat);
+        }
+        warnings.push(SyncWarning::ChangedDuringSync {
+            path: path.display().to_string(),
+        });
+        None
+    }
+
+    /// Warn if a file was added to, removed from or renamed in the directory since the walk
+    /// started, which the walk may or may not have seen. Checked once the directory is
+    /// finished, so that changes while it was being walked count
+    fn check_dir(self, path: &Path, warnings: &mut Vec<SyncWarning>) {
+        let stat = fs::metadata(win_path::extended(path))
+            .ok()
+            .and_then(|metadata| FileStat::from_metadata(&metadata));
+        if stat.is_some_and(|stat| stat.modified_since(self.0)) {
+            warnings.push(SyncWarning::ChangedDuringSync {
+                path: path.display().to_string(),
+            });
+        }
+    }
 }
 
-#[derive(Clone)]
-enum Object {
+#[derive(Clone, Debug)]
+pub enum Object {
     Tree(Tree),
     Blob(Blob),
 }
@@ -59,117 +657,316 @@ impl From<Blob> for Object {
     }
 }
 
+/// A borrowed `Object`, as visited by `Tree::walk` and `Tree::objects`
+#[derive(Clone, Copy, Debug)]
+pub enum ObjectRef<'a> {
+    Tree(&'a Tree),
+    Blob(&'a Blob),
+}
+
+impl<'a> From<&'a Object> for ObjectRef<'a> {
+    fn from(obj: &'a Object) -> Self {
+        match obj {
+            Object::Tree(tree) => Self::Tree(tree),
+            Object::Blob(blob) => Self::Blob(blob),
+        }
+    }
+}
+
+impl<'a> ObjectRef<'a> {
+    pub fn hash(self) -> ObjectHash {
+        match self {
+            Self::Tree(tree) => tree.hash,
+            Self::Blob(blob) => blob.hash,
+        }
+    }
+
+    pub fn path(self) -> &'a Path {
+        match self {
+            Self::Tree(tree) => &tree.path,
+            Self::Blob(blob) => &blob.path,
+        }
+    }
+
+    pub fn parent(self) -> Option<ObjectHash> {
+        match self {
+            Self::Tree(tree) => tree.parent,
+            Self::Blob(blob) => blob.parent,
+        }
+    }
+
+    pub fn is_blob(self) -> bool {
+        matches!(self, Self::Blob(_))
+    }
+
+    pub fn as_tree(self) -> Option<&'a Tree> {
+        match self {
+            Self::Tree(tree) => Some(tree),
+            Self::Blob(_) => None,
+        }
+    }
+
+    pub fn as_blob(self) -> Option<&'a Blob> {
+        match self {
+            Self::Tree(_) => None,
+            Self::Blob(blob) => Some(blob),
+        }
+    }
+
+    /// Copy the object out of the tree, along with everything beneath it
+    pub fn to_object(self) -> Object {
+        match self {
+            Self::Tree(tree) => tree.clone().into(),
+            Self::Blob(blob) => blob.clone().into(),
+        }
+    }
+
+    fn descr(self) -> ObjDescription {
+        ObjDescription {
+            hash: self.hash(),
+            path: self.path().to_path_buf(),
+            is_blob: self.is_blob(),
+        }
+    }
+}
+
+/// Iterator over a tree and everything beneath it, parents first and each directory's children
+/// in `Tree::children` order. See `Tree::objects`
+pub struct Objects<'a> {
+    stack: Vec<ObjectRef<'a>>,
+}
+
+impl<'a> Iterator for Objects<'a> {
+    type Item = ObjectRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let obj = self.stack.pop()?;
+        if let ObjectRef::Tree(tree) = obj {
+            // Reversed, so the first child comes off the stack first
+            self.stack
+                .extend(tree.children.iter().rev().map(ObjectRef::from));
+        }
+        Some(obj)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ObjDescription {
+    #[serde(with = "hex_hash")]
     pub hash: ObjectHash,
-    pub path: String,
+    #[serde(with = "os_path")]
+    pub path: PathBuf,
     pub is_blob: bool,
 }
 
 impl Object {
-    fn hash(&self) -> ObjectHash {
+    pub fn hash(&self) -> ObjectHash {
         match self {
             Self::Tree(tree) => tree.hash,
             Self::Blob(blob) => blob.hash,
         }
     }
 
-    fn path(&self) -> &String {
+    pub fn path(&self) -> &Path {
         match self {
             Self::Tree(tree) => &tree.path,
             Self::Blob(blob) => &blob.path,
         }
     }
 
-    fn json_for_obj(&self) -> String {
+    pub fn parent(&self) -> Option<ObjectHash> {
         match self {
-            Self::Tree(tree) => tree.json_for_obj(),
-            Self::Blob(blob) => blob.json_for_obj(),
+            Self::Tree(tree) => tree.parent,
+            Self::Blob(blob) => blob.parent,
         }
     }
 
-    fn descr(&self) -> ObjDescription {
-        return ObjDescription {
-            hash: self.hash(),
-            path: self.path().clone(),
-            is_blob: matches!(self, Self::Blob(_)),
-        };
+    pub fn is_blob(&self) -> bool {
+        matches!(self, Self::Blob(_))
+    }
+
+    pub fn as_tree(&self) -> Option<&Tree> {
+        match self {
+            Self::Tree(tree) => Some(tree),
+            Self::Blob(_) => None,
+        }
     }
 
-    /// Return a tuple of (paths to add, paths to remove)
-    fn diff(&self, new_obj: &Self) -> (Vec<ObjDescription>, Vec<ObjDescription>) {
-        let mut add: Vec<ObjDescription> = Vec::new();
-        let mut remove: Vec<ObjDescription> = Vec::new();
+    pub fn as_blob(&self) -> Option<&Blob> {
+        match self {
+            Self::Tree(_) => None,
+            Self::Blob(blob) => Some(blob),
+        }
+    }
 
+    /// Report the changes going from self to new_obj
+    fn diff_each<'a>(
+        &'a self,
+        new_obj: &'a Self,
+        fold_case: bool,
+        on_change: &mut dyn FnMut(Change<'a>),
+    ) {
         if self.hash() == new_obj.hash() {
-            return (add, remove);
+            return;
         }
 
         match (self, new_obj) {
             (Self::Tree(old_tree), Self::Tree(new_tree)) => {
                 // This is where you recurse like below
-                let (child_add, child_remove) = old_tree.diff_children(new_tree);
-                add.push(new_tree.descr());
-                remove.push(old_tree.descr());
-                add.extend(child_add);
-                remove.extend(child_remove);
+                on_change(Change::Add(ObjectRef::Tree(new_tree)));
+                on_change(Change::Remove(ObjectRef::Tree(old_tree)));
+                old_tree.diff_children(new_tree, fold_case, on_change);
             }
             (Self::Blob(old_blob), Self::Blob(new_blob)) => {
-                add.push(new_blob.descr());
-                remove.push(old_blob.descr());
+                on_change(Change::Add(ObjectRef::Blob(new_blob)));
+                on_change(Change::Remove(ObjectRef::Blob(old_blob)));
             }
             (Self::Blob(old_blob), Self::Tree(new_tree)) => {
                 // Remove blob, add entire new tree
-                remove.push(old_blob.descr());
-                add.extend(new_tree.all_obj_descriptions());
+                on_change(Change::Remove(ObjectRef::Blob(old_blob)));
+                new_tree
+                    .objects()
+                    .for_each(|obj| on_change(Change::Add(obj)));
             }
             (Self::Tree(old_tree), Self::Blob(new_blob)) => {
                 // Remove entire old tree, add blob
-                remove.extend(old_tree.all_obj_descriptions());
-                add.push(new_blob.descr());
+                old_tree
+                    .objects()
+                    .for_each(|obj| on_change(Change::Remove(obj)));
+                on_change(Change::Add(ObjectRef::Blob(new_blob)));
             }
         }
-
-        (add, remove)
     }
 }
 
+/// One entry of a diff, borrowed from whichever tree it is in
+#[derive(Clone, Copy, Debug)]
+pub enum Change<'a> {
+    /// In the new tree, but not the old one
+    Add(ObjectRef<'a>),
+
+    /// In the old tree, but not the new one
+    Remove(ObjectRef<'a>),
+}
+
+/// Compare two trees, returning the objects that were (added, removed) going from old to new
+///
+/// The root's children are diffed on every available core, when enough of them changed for it
+/// to be worth it
 pub fn diff(old_tree: &Tree, new_tree: &Tree) -> (Vec<ObjDescription>, Vec<ObjDescription>) {
-    let mut add: Vec<ObjDescription> = Vec::new();
-    let mut remove: Vec<ObjDescription> = Vec::new();
+    diff_with(old_tree, new_tree, false)
+}
+
+/// Same as `diff`, but with `fold_case`, objects whose paths differ only in case are matched up
+/// as the same object, as they are on a case-insensitive filesystem
+pub(crate) fn diff_with(
+    old_tree: &Tree,
+    new_tree: &Tree,
+    fold_case: bool,
+) -> (Vec<ObjDescription>, Vec<ObjDescription>) {
+    fn collect<'a>(
+        (add, remove): &mut (Vec<ObjDescription>, Vec<ObjDescription>),
+    ) -> impl FnMut(Change<'a>) + '_ {
+        move |change| match change {
+            Change::Add(obj) => add.push(obj.descr()),
+            Change::Remove(obj) => remove.push(obj.descr()),
+        }
+    }
 
+    let mut results = (Vec::new(), Vec::new());
     if old_tree.hash == new_tree.hash {
-        return (add, remove);
+        return results;
+    }
+    results.0.push(ObjectRef::Tree(new_tree).descr());
+    results.1.push(ObjectRef::Tree(old_tree).descr());
+
+    let (pairs, unmatched) = old_tree.pair_children(new_tree, fold_case);
+    let changed_subtrees = pairs
+        .iter()
+        .filter(|(old_child, child)| {
+            old_child.is_some_and(|old_child| old_child.hash() != child.hash()) && !child.is_blob()
+        })
+        .count();
+    if changed_subtrees >= MIN_PARALLEL_SUBTREES {
+        // Concatenated in order, these are exactly what diffing one child at a time would give
+        for (child_add, child_remove) in map_in_parallel(&pairs, |&(old_child, child)| {
+            let mut child_results = (Vec::new(), Vec::new());
+            diff_child(
+                old_child,
+                child,
+                fold_case,
+                &mut collect(&mut child_results),
+            );
+            child_results
+        }) {
+            results.0.extend(child_add);
+            results.1.extend(child_remove);
+        }
+    } else {
+        for (old_child, child) in pairs {
+            diff_child(old_child, child, fold_case, &mut collect(&mut results));
+        }
     }
+    remove_unmatched(unmatched, &mut collect(&mut results));
+    results
+}
 
-    let (child_add, child_remove) = old_tree.diff_children(new_tree);
+/// Fewer changed subtrees than this are quicker to diff on one thread than to hand out
+const MIN_PARALLEL_SUBTREES: usize = 4;
 
-    add.push(new_tree.descr());
-    remove.push(old_tree.descr());
-    add.extend(child_add);
-    remove.extend(child_remove);
+/// Same as `diff`, but handing each change to `on_change` as it is found instead of collecting
+/// them, so a large diff can be processed in batches without holding all of it in memory.
+/// Adds and removes are each reported in the same order `diff` returns them
+pub fn diff_each<'a>(
+    old_tree: &'a Tree,
+    new_tree: &'a Tree,
+    mut on_change: impl FnMut(Change<'a>),
+) {
+    if old_tree.hash == new_tree.hash {
+        return;
+    }
 
-    (add, remove)
+    on_change(Change::Add(ObjectRef::Tree(new_tree)));
+    on_change(Change::Remove(ObjectRef::Tree(old_tree)));
+    old_tree.diff_children(new_tree, false, &mut on_change);
 }
 
 impl Blob {
-    fn json_for_obj(&self) -> String {
-        let node = SerializeableNode {
+    pub fn hash(&self) -> ObjectHash {
+        self.hash
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Hash of the tree containing this blob
+    pub fn parent(&self) -> Option<ObjectHash> {
+        self.parent
+    }
+
+    fn to_node(&self) -> SerializeableNode {
+        SerializeableNode {
             parent: self.parent,
             children: None,
             hash: self.hash,
             path: self.path.clone(),
-        };
-
-        let mut json = serde_json::to_string(&node).unwrap();
-        json.push('\n');
-        json
+            size: self.stat.map(|stat| stat.size),
+            mtime: self.stat.map(|stat| stat.mtime),
+        }
     }
 
-    fn descr(&self) -> ObjDescription {
-        ObjDescription {
-            hash: self.hash,
-            path: self.path.clone(),
-            is_blob: true,
+    fn from_node(node: SerializeableNode) -> Self {
+        let stat = match (node.size, node.mtime) {
+            (Some(size), Some(mtime)) => Some(FileStat { size, mtime }),
+            _ => None,
+        };
+        Blob {
+            parent: node.parent,
+            hash: node.hash,
+            path: node.path,
+            stat,
         }
     }
 }
@@ -181,87 +978,304 @@ impl Blob {
 // }
 
 impl Tree {
-    fn descr(&self) -> ObjDescription {
-        ObjDescription {
-            hash: self.hash,
-            path: self.path.clone(),
-            is_blob: false,
-        }
+    /// For the root of a tree this is the root hash, which changes whenever anything beneath it changes
+    pub fn hash(&self) -> ObjectHash {
+        self.hash
     }
 
-    fn json_for_node(&self) -> String {
-        let node = SerializeableNode {
-            parent: self.parent,
-            children: Some(self.children.iter().map(Object::hash).collect()),
-            hash: self.hash,
-            path: self.path.clone(),
-        };
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
 
-        let mut json = serde_json::to_string(&node).unwrap();
-        json.push('\n');
-        json
+    /// Hash of the tree containing this one, or None for the root
+    pub fn parent(&self) -> Option<ObjectHash> {
+        self.parent
     }
 
-    fn json_for_obj(&self) -> String {
-        let mut result = String::new();
-        result.push_str(&self.json_for_node());
+    /// Direct children, in bytewise order of their file names whatever order the walk found
+    /// them in (see `by_name`)
+    pub fn children(&self) -> &[Object] {
+        &self.children
+    }
 
-        for child in &self.children {
-            result.push_str(&child.json_for_obj());
-        }
+    /// Look up a direct child by its path
+    pub fn child(&self, path: impl AsRef<Path>) -> Option<&Object> {
+        self.children
+            .iter()
+            .find(|child| child.path() == path.as_ref())
+    }
 
-        result
+    fn node_from_jsonl(lines: &mut std::str::Lines) -> Result<SerializeableNode> {
+        let line = lines.next().ok_or_else(|| {
+            SyncError::CorruptedIndex("tree file ended before all nodes were read".to_string())
+        })?;
+        serde_json::from_str(line)
+            .map_err(|err| SyncError::CorruptedIndex(format!("invalid tree node: {err}")))
     }
 
-    fn obj_from_jsonl(lines: &mut std::str::Lines, first_line: Option<SerializeableNode>) -> Self {
-        let root_node =
-            first_line.unwrap_or_else(|| serde_json::from_str(lines.next().unwrap()).unwrap());
+    fn obj_from_jsonl(
+        lines: &mut std::str::Lines,
+        first_line: Option<SerializeableNode>,
+        depth: usize,
+    ) -> Result<Self> {
+        check_depth(depth)?;
+        let root_node = match first_line {
+            Some(node) => node,
+            None => Self::node_from_jsonl(lines)?,
+        };
 
-        let children = root_node
+        let root_path = &root_node.path;
+        let children: Vec<Object> = root_node
             .children
-            .unwrap()
+            .ok_or_else(|| {
+                SyncError::CorruptedIndex("root of tree file is not a tree".to_string())
+            })?
             .into_iter()
             .map(|_child_hash| {
-                let child_jsonl = lines.next().unwrap();
-                let child_node: SerializeableNode = serde_json::from_str(child_jsonl).unwrap();
+                let child_node = Self::node_from_jsonl(lines)?;
+                check_child_path(root_path, &child_node.path).map_err(SyncError::CorruptedIndex)?;
                 if child_node.children.is_some() {
-                    Self::obj_from_jsonl(lines, Some(child_node)).into()
+                    Ok(Self::obj_from_jsonl(lines, Some(child_node), depth + 1)?.into())
                 } else {
-                    Blob {
-                        parent: child_node.parent,
-                        hash: child_node.hash,
-                        path: child_node.path,
-                    }
-                    .into()
+                    Ok(Blob::from_node(child_node).into())
                 }
             })
-            .collect();
+            .collect::<Result<_>>()?;
+        check_unique_children(&root_node.path, &children)?;
 
-        Self {
+        Ok(Self {
             parent: root_node.parent,
             children,
             hash: root_node.hash,
             path: root_node.path,
-        }
+        })
+    }
+
+    /// Write the record for this directory, after those of every directory beneath it, unless
+    /// it already exists. Returns the record's name, and adds it and everything beneath it to
+    /// `live`
+    fn persist_subtree(
+        &self,
+        file_system: &dyn FileSystem,
+        subtrees_dir: &Path,
+        format: TreeFormat,
+        compression: Compression,
+        fsync: bool,
+        live: &mut HashSet<String>,
+    ) -> Result<String> {
+        let children = self
+            .children
+            .iter()
+            .map(|child| match child {
+                Object::Tree(tree) => Ok(ChildRecord::Tree(tree.persist_subtree(
+                    file_system,
+                    subtrees_dir,
+                    format,
+                    compression,
+                    fsync,
+                    live,
+                )?)),
+                Object::Blob(blob) => Ok(ChildRecord::Blob(SerializeableNode {
+                    parent: None,
+                    ..blob.to_node()
+                })),
+            })
+            .collect::<Result<_>>()?;
+        let record = SubtreeRecord {
+            hash: self.hash,
+            path: self.path.clone(),
+            children,
+        };
+
+        let name = record.write(file_system, subtrees_dir, format, compression, fsync, true)?;
+        live.insert(name.clone());
+        Ok(name)
     }
 
-    /// Persist the tree to disk as JSONL
-    pub fn persist(&self, filepath: &Path) {
-        if let Some(dir) = filepath.parent() {
-            std::fs::create_dir_all(dir)
-                .unwrap_or_else(|_| panic!("Failed to create dir {}", dir.display()));
+    /// The directory saved in the record `name`, and everything beneath it. `parent` is the
+    /// hash and path of the directory it's saved in, or None for the root
+    fn load_subtree(
+        file_system: &dyn FileSystem,
+        subtrees_dir: &Path,
+        name: &str,
+        parent: Option<(ObjectHash, &Path)>,
+        depth: usize,
+    ) -> Result<Self> {
+        check_depth(depth)?;
+        let record = match parent {
+            Some((_, parent_path)) => {
+                SubtreeRecord::read_child(file_system, subtrees_dir, name, parent_path)?
+            }
+            None => SubtreeRecord::read(file_system, subtrees_dir, name)?,
+        };
+        let hash = record.hash;
+        let path = record.path;
+        let children: Vec<Object> = record
+            .children
+            .into_iter()
+            .map(|child| match child {
+                ChildRecord::Tree(name) => {
+                    let parent = Some((hash, path.as_path()));
+                    Ok(
+                        Self::load_subtree(file_system, subtrees_dir, &name, parent, depth + 1)?
+                            .into(),
+                    )
+                }
+                ChildRecord::Blob(node) => Ok(Blob {
+                    parent: Some(hash),
+                    ..Blob::from_node(node)
+                }
+                .into()),
+            })
+            .collect::<Result<_>>()?;
+        check_unique_children(&path, &children)?;
+        Ok(Self {
+            parent: parent.map(|(hash, _)| hash),
+            children,
+            hash,
+            path,
+        })
+    }
+
+    /// Persist the tree to disk, only writing the directories that changed since it was last
+    /// persisted to the same file
+    pub fn persist(&self, filepath: &Path) -> Result<()> {
+        self.persist_as(filepath, TreeFormat::default(), Compression::default())
+    }
+
+    /// `persist`, writing the records in the given format and compression. Switching either
+    /// rewrites every record
+    pub fn persist_as(
+        &self,
+        filepath: &Path,
+        format: TreeFormat,
+        compression: Compression,
+    ) -> Result<()> {
+        self.persist_records(&OsFileSystem, filepath, format, compression, false)
+    }
+
+    /// `persist`, with the format, compression and fsync set in `options`, in the index's file
+    /// system (see `SyncOptions::index_file_system`)
+    pub fn persist_with(&self, filepath: &Path, options: &SyncOptions) -> Result<()> {
+        self.persist_records(
+            &*options.resolve_index_file_system(),
+            filepath,
+            options.tree_format,
+            options.tree_compression,
+            options.fsync,
+        )
+    }
+
+    fn persist_records(
+        &self,
+        file_system: &dyn FileSystem,
+        filepath: &Path,
+        format: TreeFormat,
+        compression: Compression,
+        fsync: bool,
+    ) -> Result<()> {
+        let subtrees_dir = subtrees_dir(filepath);
+        file_system.create_dir_all(&subtrees_dir)?;
+
+        let mut live = HashSet::new();
+        let root = self.persist_subtree(
+            file_system,
+            &subtrees_dir,
+            format,
+            compression,
+            fsync,
+            &mut live,
+        )?;
+        TreeManifest {
+            version: MANIFEST_VERSION,
+            hash: self.hash,
+            root,
         }
-        let mut file = std::fs::File::create(filepath).unwrap();
-        file.write_all(self.json_for_obj().as_bytes()).unwrap();
+        .write(file_system, filepath, &live, fsync)
     }
 
-    /// Load the tree from JSONL file
+    /// Load a tree written by `persist`, or a single JSONL file written by older versions
     pub fn load(filepath: &Path) -> Result<Self> {
-        let mut file = std::fs::File::open(filepath)?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
+        Self::load_in(&OsFileSystem, filepath)
+    }
+
+    /// `load`, from `file_system`
+    pub(crate) fn load_in(file_system: &dyn FileSystem, filepath: &Path) -> Result<Self> {
+        let contents = String::from_utf8(file_system.read(filepath)?)
+            .map_err(|_| SyncError::CorruptedIndex("tree isn't UTF-8".to_string()))?;
         let mut lines = contents.lines();
-        Ok(Self::obj_from_jsonl(&mut lines, None))
+
+        // The root node of a JSONL tree has no `root`, so it is never mistaken for a manifest
+        let manifest = match lines.clone().next() {
+            Some(line) => TreeManifest::parse(line)?,
+            None => None,
+        };
+        match manifest {
+            Some(manifest) => {
+                let subtrees_dir = subtrees_dir(filepath);
+                let tree = Self::load_subtree(file_system, &subtrees_dir, &manifest.root, None, 0)?;
+                if tree.hash != manifest.hash {
+                    return Err(SyncError::CorruptedIndex(
+                        "root record doesn't match the tree's hash".to_string(),
+                    ));
+                }
+                Ok(tree.relative_to_root())
+            }
+            None => Ok(Self::obj_from_jsonl(&mut lines, None, 0)?.relative_to_root()),
+        }
+    }
+
+    /// Older versions saved absolute paths, with the root's path in front of every other one.
+    /// Takes it off them, so they're relative to the root like the ones saved now
+    fn relative_to_root(mut self) -> Self {
+        let root = std::mem::take(&mut self.path);
+        if !root.as_os_str().is_empty() {
+            self.strip_root(&root);
+        }
+        self
+    }
+
+    fn strip_root(&mut self, root: &Path) {
+        let strip = |path: &mut PathBuf| {
+            if let Ok(relative) = path.strip_prefix(root) {
+                *path = relative.to_path_buf();
+            }
+        };
+        for child in &mut self.children {
+            match child {
+                Object::Tree(tree) => {
+                    strip(&mut tree.path);
+                    tree.strip_root(root);
+                }
+                Object::Blob(blob) => strip(&mut blob.path),
+            }
+        }
+    }
+
+    /// Read only the root hash from a file written by `persist`, without loading the tree
+    pub fn load_root_hash(filepath: &Path) -> Result<ObjectHash> {
+        Self::load_root_hash_in(&OsFileSystem, filepath)
+    }
+
+    /// `load_root_hash`, from `file_system`
+    pub(crate) fn load_root_hash_in(
+        file_system: &dyn FileSystem,
+        filepath: &Path,
+    ) -> Result<ObjectHash> {
+        // The first line is either the manifest or, for older trees, the root node. Both start
+        // with the root hash
+        #[derive(Deserialize)]
+        struct RootNode {
+            hash: ObjectHash,
+        }
+
+        let line = first_line(file_system, filepath)?;
+        if let Some(manifest) = TreeManifest::parse(&line)? {
+            return Ok(manifest.hash);
+        }
+        let root: RootNode = serde_json::from_str(&line)
+            .map_err(|err| SyncError::CorruptedIndex(format!("invalid tree node: {err}")))?;
+        Ok(root.hash)
     }
 
     // pub fn empty() -> Self {
@@ -282,67 +1296,124 @@ impl Tree {
         }
     }
 
-    fn walk(&self, callback: &mut dyn FnMut(&Object)) {
-        callback(&Object::Tree(self.clone()));
-        for child in &self.children {
-            match child {
-                Object::Tree(tree) => tree.walk(callback),
-                Object::Blob(blob) => callback(&Object::Blob(blob.clone())),
-            }
+    /// Call `callback` on this tree and everything beneath it, parents before their children
+    pub fn walk(&self, mut callback: impl FnMut(ObjectRef<'_>)) {
+        for obj in self.objects() {
+            callback(obj);
         }
     }
 
-    fn all_obj_descriptions(&self) -> Vec<ObjDescription> {
-        let mut result = Vec::new();
-        self.walk(&mut |obj| result.push(obj.descr()));
-        result
+    /// This tree and everything beneath it, parents before their children. Nothing is copied
+    pub fn objects(&self) -> Objects<'_> {
+        Objects {
+            stack: vec![ObjectRef::Tree(self)],
+        }
+    }
+
+    pub(crate) fn trees_by_path(&self) -> HashMap<&Path, &Tree> {
+        self.objects()
+            .filter_map(ObjectRef::as_tree)
+            .map(|tree| (tree.path.as_path(), tree))
+            .collect()
+    }
+
+    fn blobs_by_path(&self) -> HashMap<&Path, &Blob> {
+        self.objects()
+            .filter_map(ObjectRef::as_blob)
+            .map(|blob| (blob.path.as_path(), blob))
+            .collect()
+    }
+
+    pub(crate) fn all_obj_descriptions(&self) -> Vec<ObjDescription> {
+        self.objects().map(ObjectRef::descr).collect()
     }
 
-    /// Return a list of paths that have changed and the type of change (0 = add, 1 = update, 2 = remove)
-    /// other is considered the "new" tree
-    fn diff_children(&self, new_tree: &Self) -> (Vec<ObjDescription>, Vec<ObjDescription>) {
-        let mut add = Vec::new();
-        let mut remove = Vec::new();
+    /// Report the changes to the children going from self to new_tree
+    fn diff_children<'a>(
+        &'a self,
+        new_tree: &'a Self,
+        fold_case: bool,
+        on_change: &mut dyn FnMut(Change<'a>),
+    ) {
+        let (pairs, unmatched) = self.pair_children(new_tree, fold_case);
+        for (old_child, child) in pairs {
+            diff_child(old_child, child, fold_case, on_change);
+        }
+        remove_unmatched(unmatched, on_change);
+    }
 
+    /// Match each of new_tree's children with the child of self at the same path (see
+    /// `match_key`), if any. Returns the pairs, in new_tree's order, and the children of self
+    /// left over, in self's order. Each child of self is in exactly one of them, even if (with
+    /// `fold_case`) more than one has the same key: the first is matched, the rest are left over
+    fn pair_children<'a>(
+        &'a self,
+        new_tree: &'a Self,
+        fold_case: bool,
+    ) -> (ChildPairs<'a>, Vec<&'a Object>) {
         // There are situations where the names of two folders could be swapped and then each slightly changed
         // where you would need some heuristics to avoid throwing them out...but...don't worry for now. Just match by path
 
-        let mut old_path_to_object: std::collections::HashMap<String, &Object> = self
+        let mut old_index: HashMap<Cow<Path>, usize> = HashMap::new();
+        for (index, child) in self.children.iter().enumerate() {
+            old_index
+                .entry(match_key(child.path(), fold_case))
+                .or_insert(index);
+        }
+
+        let mut matched = vec![false; self.children.len()];
+        let pairs = new_tree
+            .children
+            .iter()
+            .map(|child| {
+                let old_child =
+                    old_index
+                        .remove(&*match_key(child.path(), fold_case))
+                        .map(|index| {
+                            matched[index] = true;
+                            &self.children[index]
+                        });
+                (old_child, child)
+            })
+            .collect();
+        let unmatched = self
             .children
             .iter()
-            .map(|child| (child.path().clone(), child))
+            .zip(matched)
+            .filter(|(_, matched)| !matched)
+            .map(|(child, _)| child)
             .collect();
+        (pairs, unmatched)
+    }
+}
 
-        for child in &new_tree.children {
-            if let Some(old_child) = old_path_to_object.remove(child.path()) {
-                // If the same path name exists in old children
-                let (child_add, child_remove) = old_child.diff(child);
-                add.extend(child_add);
-                remove.extend(child_remove);
-            } else {
-                // Name didn't exist before. Add all
-                match child {
-                    Object::Tree(tree) => {
-                        add.extend(tree.all_obj_descriptions());
-                    }
-                    Object::Blob(blob) => {
-                        add.push(blob.descr());
-                    }
-                }
-            }
-        }
+/// Children of two trees at the same path, the old one missing if it's new
+type ChildPairs<'a> = Vec<(Option<&'a Object>, &'a Object)>;
 
-        // Remove - along with all children
-        for obj in old_path_to_object.values() {
-            match obj {
-                Object::Tree(tree) => tree.walk(&mut |_obj| {
-                    remove.extend(tree.all_obj_descriptions());
-                }),
-                Object::Blob(_) => remove.push(obj.descr()),
-            }
-        }
+fn diff_child<'a>(
+    old_child: Option<&'a Object>,
+    child: &'a Object,
+    fold_case: bool,
+    on_change: &mut dyn FnMut(Change<'a>),
+) {
+    match (old_child, child) {
+        // If the same path name exists in old children
+        (Some(old_child), _) => old_child.diff_each(child, fold_case, on_change),
+        // Name didn't exist before. Add all
+        (None, Object::Tree(tree)) => tree.objects().for_each(|obj| on_change(Change::Add(obj))),
+        (None, Object::Blob(blob)) => on_change(Change::Add(ObjectRef::Blob(blob))),
+    }
+}
 
-        (add, remove)
+/// Remove - along with all children, each reported once, parents before their children
+fn remove_unmatched<'a>(unmatched: Vec<&'a Object>, on_change: &mut dyn FnMut(Change<'a>)) {
+    for obj in unmatched {
+        match obj {
+            Object::Tree(tree) => tree
+                .objects()
+                .for_each(|obj| on_change(Change::Remove(obj))),
+            Object::Blob(blob) => on_change(Change::Remove(ObjectRef::Blob(blob))),
+        }
     }
 }
 
@@ -419,266 +1490,3243 @@ const GLOBAL_IGNORE_PATTERNS: &[&str] = &[
     "*.parquet",
 ];
 
-fn global_ignore_path() -> PathBuf {
-    let mut path = get_my_home().unwrap().unwrap();
-    path.push(".continue");
-    path.push(".continueignore");
-    path
+/// What the global ignore file starts out with
+fn default_global_ignore() -> String {
+    GLOBAL_IGNORE_PATTERNS
+        .iter()
+        .map(|pattern| format!("{pattern}\n"))
+        .collect()
 }
 
-fn create_global_ignore_file() -> PathBuf {
+/// The lines that let archives back in past the global ignore file, for `SyncOptions::archives`
+fn archive_unignores() -> String {
+    (archive::PATTERNS.iter())
+        .map(|pattern| format!("!{pattern}\n"))
+        .collect()
+}
+
+fn create_global_ignore_file(index_root: &IndexRoot) -> Result<&Path> {
     // Because you have to pass a real filepath to the ignore crate, you can't just pass a string
-    let path = global_ignore_path();
+    let path = index_root.global_ignore_file();
 
     if !path.exists() {
-        let mut file = std::fs::File::create(path).unwrap();
-        for pattern in GLOBAL_IGNORE_PATTERNS {
-            file.write_all(pattern.as_bytes()).unwrap();
-            file.write_all(b"\n").unwrap();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = std::fs::File::create(path)?;
+        file.write_all(default_global_ignore().as_bytes())?;
+    }
+
+    Ok(path)
+}
+
+/// What's in the global ignore file, read from the index's `FileSystem` and created there if
+/// it isn't there yet, for a walk through `SyncOptions::file_system`, which doesn't need it on
+/// disk. With `SyncOptions::archives`, archives are let back in
+fn global_ignore_contents(index_root: &IndexRoot, options: &SyncOptions) -> Result<String> {
+    let file_system = options.resolve_index_file_system();
+    let path = index_root.global_ignore_file();
+    let mut contents = match file_system.read(path) {
+        Ok(contents) => String::from_utf8_lossy(&contents).into_owned(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let contents = default_global_ignore();
+            if let Some(dir) = path.parent() {
+                file_system.create_dir_all(dir)?;
+            }
+            file_system.write(path, contents.as_bytes(), false)?;
+            contents
         }
+        Err(err) => return Err(err.into()),
+    };
+    if options.archives {
+        contents.push_str(&archive_unignores());
+    }
+    Ok(contents)
+}
+
+/// The global ignore file, or with `SyncOptions::archives`, a copy of it next to it that lets
+/// archives back in
+fn global_ignore_file(index_root: &IndexRoot, options: &SyncOptions) -> Result<PathBuf> {
+    let path = create_global_ignore_file(index_root)?;
+    if !options.archives {
+        return Ok(path.to_path_buf());
+    }
+    let mut contents = fs::read(path)?;
+    contents.extend_from_slice(archive_unignores().as_bytes());
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".archives");
+    let archives_path = path.with_file_name(name);
+    if fs::read(&archives_path).ok().as_ref() != Some(&contents) {
+        atomic::write(&archives_path, &contents, false)?;
     }
+    Ok(archives_path)
+}
 
-    global_ignore_path()
+pub fn build_walk(dir: &Path, options: &SyncOptions) -> Result<Walk> {
+    Ok(walk_builder(dir, options)?.build())
 }
 
-pub fn build_walk(dir: &Path) -> Walk {
-    let path = create_global_ignore_file();
+fn walk_builder(dir: &Path, options: &SyncOptions) -> Result<WalkBuilder> {
+    let index_root = options.resolve_index_root()?;
+    let path = &global_ignore_file(&index_root, options)?;
     // Make sure it sorts alphabetically by default
     let mut binding = WalkBuilder::new(dir);
     let builder = binding.add_custom_ignore_filename(".continueignore");
 
-    builder.add_ignore(path);
-    builder.build()
+    if let Some(err) = builder.add_ignore(path) {
+        return Err(err.into());
+    }
+
+    // Overrides starting with ! are treated as ignore patterns
+    let mut overrides = OverrideBuilder::new(dir);
+    for pattern in &options.ignore_patterns {
+        overrides.add(&format!("!{pattern}"))?;
+    }
+    builder.overrides(overrides.build()?);
+
+    builder.follow_links(options.follow_symlinks);
+    builder.sort_by_file_name(|a, b| by_name(Path::new(a), Path::new(b)));
+    Ok(binding)
 }
 
-fn sha1_hash(content: &str) -> ObjectHash {
-    let mut hasher = Sha1::new();
-    hasher.update(content);
-    hasher.finalize().into()
+/// The order of a directory's children in the tree, which its hash depends on: bytewise on
+/// their paths (so on their names, as they share the rest), whatever order the filesystem or
+/// the platform's string comparison would give
+fn by_name(a: &Path, b: &Path) -> std::cmp::Ordering {
+    path_to_bytes(a).cmp(&path_to_bytes(b))
 }
 
-fn blob_hash(content: &str, file_ext: &str) -> ObjectHash {
-    sha1_hash(&format!("blob {file_ext} {content}"))
+/// Whether the file is over `SyncOptions::max_file_size`, warning about it if it is
+fn too_large(
+    path: &Path,
+    size: u64,
+    options: &SyncOptions,
+    warnings: &mut Vec<SyncWarning>,
+) -> bool {
+    if size <= options.resolve_max_file_size() {
+        return false;
+    }
+    warnings.push(SyncWarning::FileTooLarge {
+        path: path.display().to_string(),
+        size,
+    });
+    true
 }
 
-fn create_blob(filepath: &Path, parent: Option<ObjectHash>) -> Result<Blob> {
-    let content = std::fs::read_to_string(filepath)?;
-    let hash = blob_hash(
-        &content,
-        filepath.extension().map_or("", |ext| ext.to_str().unwrap()),
-    );
-    Ok(Blob {
-        parent,
-        hash,
-        path: filepath.to_str().unwrap().to_string(),
-    })
+/// The path an entry of the walk of `walk_root` is kept under in the tree: relative to the root,
+/// so the index still holds when the directory is moved, and the same for every spelling of it
+/// that the filesystem treats as one (see `win_path` and `nfc`)
+fn stored_path(path: &Path, walk_root: &Path, options: &SyncOptions) -> PathBuf {
+    let path = win_path::normalize(path.strip_prefix(walk_root).unwrap_or(path));
+    let composed = path
+        .to_str()
+        .filter(|_| options.resolve_normalize_unicode())
+        .map(nfc::compose);
+    match composed {
+        Some(Cow::Owned(composed)) => PathBuf::from(composed),
+        _ => path.into_owned(),
+    }
 }
 
-fn tree_hash(children: impl IntoIterator<Item = ObjectHash>) -> ObjectHash {
-    let mut hasher = Sha1::new();
-    hasher.update(b"tree");
+/// Whether paths under `dir` that differ only in case should be matched up when diffing: as set
+/// in the options, or else whether the filesystem `dir` is on ignores case
+/// (paths from a `FileSystem` are taken to be case-sensitive)
+pub(crate) fn fold_case(dir: &Path, options: &SyncOptions) -> bool {
+    options
+        .case_insensitive
+        .unwrap_or_else(|| match options.file_system {
+            Some(_) => false,
+            None => detect_case_insensitive(dir),
+        })
+}
 
-    // Note you're not just concatenating
-    for child in children {
-        hasher.update(child);
+/// Look `dir` up with the case of its letters swapped: on a case-insensitive filesystem that
+/// finds `dir` itself. A path with no letters to swap (or that isn't there) can't tell, so the
+/// platform's usual filesystem decides
+fn detect_case_insensitive(dir: &Path) -> bool {
+    let swapped: Option<String> = dir.to_str().map(|dir| {
+        dir.chars()
+            .map(|c| match c.is_lowercase() {
+                true => c.to_uppercase().next().unwrap_or(c),
+                false => c.to_lowercase().next().unwrap_or(c),
+            })
+            .collect()
+    });
+    match (swapped, fs::metadata(dir)) {
+        (Some(swapped), Ok(metadata)) if Path::new(&swapped) != dir => {
+            match fs::metadata(&swapped) {
+                Ok(swapped_metadata) => same_file(&metadata, &swapped_metadata),
+                Err(_) => false,
+            }
+        }
+        _ => cfg!(any(windows, target_os = "macos")),
     }
-    let result = hasher.finalize();
-    let hash_bytes: [u8; 20] = result.into();
-    hash_bytes
 }
 
-struct PreTree {
-    children: Vec<Object>,
-    path: String,
+#[cfg(unix)]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    (a.dev(), a.ino()) == (b.dev(), b.ino())
 }
 
-impl PreTree {
-    fn finalize(&self) -> Tree {
-        return Tree {
-            parent: None,
-            children: self.children.clone(),
-            hash: tree_hash(self.children.iter().map(Object::hash)),
-            path: self.path.clone(),
-        };
+/// Without inode numbers, finding anything at the swapped path has to do
+#[cfg(not(unix))]
+fn same_file(_a: &fs::Metadata, _b: &fs::Metadata) -> bool {
+    true
+}
+
+/// The key a path is matched up by when diffing: with `fold_case`, its lower case form
+fn match_key(path: &Path, fold_case: bool) -> Cow<'_, Path> {
+    match path.to_str() {
+        Some(path_str) if fold_case && path_str.chars().any(char::is_uppercase) => {
+            Cow::Owned(PathBuf::from(path_str.to_lowercase()))
+        }
+        _ => Cow::Borrowed(path),
     }
 }
 
-/// Compute merkle tree and all sub-objects
-/// The last element in the vector is the root of the tree
-pub fn compute_tree_for_dir(dir: &Path, _parent: Option<ObjectHash>) -> Result<Tree> {
-    let mut walk = build_walk(dir);
-    let root_entry = walk
-        .next() // This is just "."
-        .expect("Directory does not exist")
-        .expect("Error parsing directory");
+/// What's left of a walk result, or None if the entry it's for was deleted after the walk found
+/// it, can't be read or is a symlink that can't be followed, which is reported in `warnings`.
+/// Only failing to read `walk_root` itself fails the walk, since skipping it would make every
+/// file look deleted
+fn walked<T>(
+    result: std::result::Result<T, ignore::Error>,
+    walk_root: &Path,
+    warnings: &mut Vec<SyncWarning>,
+) -> Result<Option<T>> {
+    let err = match result {
+        Ok(value) => return Ok(Some(value)),
+        Err(err) => err,
+    };
+    if let Some(path) = loop_path(&err) {
+        warnings.push(SyncWarning::SymlinkLoop {
+            path: win_path::normalize(path).display().to_string(),
+        });
+        return Ok(None);
+    }
+    let warning = match failed_path(&err) {
+        Some((path, io::ErrorKind::NotFound)) if is_symlink(path) => {
+            SyncWarning::UnfollowedSymlink {
+                path: win_path::normalize(path).display().to_string(),
+            }
+        }
+        Some((path, io::ErrorKind::NotFound)) => SyncWarning::ChangedDuringSync {
+            path: win_path::normalize(path).display().to_string(),
+        },
+        Some((path, io::ErrorKind::PermissionDenied)) if path != walk_root => {
+            SyncWarning::UnreadableDir {
+                path: win_path::normalize(path).display().to_string(),
+                message: err.to_string(),
+            }
+        }
+        _ => return Err(err.into()),
+    };
+    warnings.push(warning);
+    Ok(None)
+}
 
-    // The last in the vector is the latest
-    // The first in the stack will end up being the root
-    let mut tree_stack: Vec<PreTree> = Vec::new();
-    tree_stack.push(PreTree {
-        children: Vec::new(),
-        path: root_entry.path().to_str().unwrap().to_string(),
-    });
-    let mut current_dir = dir.to_path_buf();
+/// The path the error is about, and the kind of error it was for it
+fn failed_path(err: &ignore::Error) -> Option<(&Path, io::ErrorKind)> {
+    match err {
+        ignore::Error::WithPath { path, err } => Some((path, err.io_error()?.kind())),
+        ignore::Error::WithDepth { err, .. } => failed_path(err),
+        _ => None,
+    }
+}
 
-    for entry in walk {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        let metadata = entry.metadata().unwrap();
+/// The symlink that leads back to one of the directories it's in, if that's what the error is
+/// about
+fn loop_path(err: &ignore::Error) -> Option<&Path> {
+    match err {
+        ignore::Error::Loop { child, .. } => Some(child),
+        ignore::Error::WithPath { err, .. } | ignore::Error::WithDepth { err, .. } => {
+            loop_path(err)
+        }
+        _ => None,
+    }
+}
 
-        // Check whether current_dir is complete
-        while !path.starts_with(current_dir.as_path()) {
-            // We've moved up by (at least) one directory
-            // We need to pop the current directory off the stack
-            // and create a tree object for it
-            let partial_tree = tree_stack.pop().unwrap();
-            tree_stack
-                .last_mut()
-                .unwrap()
-                .children
-                .push(Object::Tree(partial_tree.finalize()));
+/// Whether the path is a symlink, which a walk following them can't find when it's broken
+fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_symlink())
+}
 
-            // Update current_dir
-            current_dir = current_dir.parent().unwrap().to_path_buf();
+/// The directories a walk has been into, so that with `SyncOptions::follow_symlinks` a link to
+/// one of them (or a directory reached through one) isn't walked a second time
+#[derive(Default)]
+struct VisitedDirs {
+    dirs: HashSet<(u64, u64)>,
+    skipped: Option<PathBuf>,
+}
+
+impl VisitedDirs {
+    /// Whether the walk result is for something inside the last directory `skip` left out. The
+    /// walk still goes into it, so what's there (errors included) is dropped here
+    fn within_skipped(&mut self, result: &std::result::Result<DirEntry, ignore::Error>) -> bool {
+        let path = match result {
+            Ok(entry) => Some(entry.path()),
+            Err(err) => loop_path(err).or_else(|| Some(failed_path(err)?.0)),
+        };
+        match (&self.skipped, path) {
+            (Some(skipped), Some(path)) if path.starts_with(skipped) => true,
+            _ => {
+                self.skipped = None;
+                false
+            }
         }
+    }
 
-        if metadata.is_dir() {
-            let partial_tree = PreTree {
-                children: Vec::new(),
-                path: path.to_str().unwrap().to_string(),
-            };
-            tree_stack.push(partial_tree);
-            current_dir = path.to_owned();
-        } else {
-            match create_blob(path, None) {
-                Ok(blob) => {
-                    tree_stack
-                        .last_mut()
-                        .unwrap()
-                        .children
-                        .push(Object::Blob(blob));
-                }
-                Err(_err) => {
-                    // Not UTF-8 formatted. Binary file. Ignore.
-                }
+    /// Whether the walk should leave the entry out because it's a symlink that isn't followed
+    /// or a directory that's already been walked, which is reported in `warnings`
+    fn skip(
+        &mut self,
+        entry: &DirEntry,
+        metadata: &Metadata,
+        options: &SyncOptions,
+        warnings: &mut Vec<SyncWarning>,
+    ) -> bool {
+        let path = || win_path::normalize(entry.path()).display().to_string();
+        if !options.follow_symlinks {
+            if entry.path_is_symlink() {
+                warnings.push(SyncWarning::UnfollowedSymlink { path: path() });
             }
+            return entry.path_is_symlink();
         }
+        if !metadata.is_dir() || self.enter(metadata) {
+            return false;
+        }
+        warnings.push(SyncWarning::SymlinkLoop { path: path() });
+        self.skipped = Some(entry.path().to_path_buf());
+        true
     }
 
-    // Collapse the stack upward
-    while tree_stack.len() > 1 {
-        let partial_tree = tree_stack.pop().unwrap();
-        tree_stack
-            .last_mut()
-            .unwrap()
-            .children
-            .push(Object::Tree(partial_tree.finalize()));
+    /// Record the directory as walked, returning false if it already was. Without inode
+    /// numbers every directory is new, and only links back to a directory's own parents are
+    /// caught (by `ignore`, as `loop_path` errors)
+    fn enter(&mut self, metadata: &Metadata) -> bool {
+        match file_id(metadata) {
+            Some(id) => self.dirs.insert(id),
+            None => true,
+        }
     }
+}
 
-    assert!(
-        tree_stack.len() == 1,
-        "Tree stack should only have exactly one element"
-    );
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
 
-    // Convert to Tree
-    let mut root_tree = tree_stack.pop().unwrap().finalize();
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
 
-    // Go through and update the parent of each child
-    root_tree.set_childrens_parent();
+/// The warning for a file that couldn't be hashed, if there is one
+fn skipped_file_warning(path: &Path, err: SyncError) -> Option<SyncWarning> {
+    let path = path.display().to_string();
+    match err {
+        SyncError::Io(err) if err.kind() == io::ErrorKind::InvalidData => {
+            Some(SyncWarning::BinaryFile { path })
+        }
+        SyncError::Io(err) if err.kind() == io::ErrorKind::NotFound => {
+            Some(SyncWarning::ChangedDuringSync { path })
+        }
+        err => Some(SyncWarning::UnreadableFile {
+            path,
+            message: err.to_string(),
+        }),
+    }
+}
 
-    Ok(root_tree)
+/// Files are hashed this many bytes at a time, so memory use doesn't grow with file size
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+thread_local! {
+    /// Each hashing thread reads every file it hashes through the same buffer
+    static HASH_BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
 }
 
-// Tests
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::utils::TempDirBuilder;
-    use std::fs::{self};
+/// How a file's content is normalized before it's hashed, with `SyncOptions::normalize_content`.
+/// Content with nothing to normalize hashes the same whichever is used
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ContentNormalization {
+    /// Hash every byte as it is
+    #[default]
+    None,
 
-    #[test]
-    fn test_compute_tree_for_temp_dir() {
-        let temp_dir = TempDirBuilder::new()
-            .add("dir1/file1.txt", "Hello, world!")
-            .add("dir1/file2.txt", "Hello, world!")
-            .add("dir2/file3.txt", "Hello, world!")
-            .add("dir2/subdir/continue.py", "[continue for i in range(10)]")
-            .add("__init__.py", "a = 5")
-            .create();
+    /// Hash each `\r\n` as `\n`
+    LineEndings,
 
-        let tree = compute_tree_for_dir(temp_dir.path(), None).expect("Failed to compute tree");
+    /// Also leave out spaces and tabs at the end of each line, and at the end of the file
+    TrailingWhitespace,
+}
 
-        // Check that every node but the root has a parent, matching that parent's hash
-        tree.walk(&mut |obj| match obj {
-            Object::Tree(tree) => {
-                for child in &tree.children {
-                    match child {
-                        Object::Tree(child_tree) => {
-                            assert_eq!(child_tree.parent, Some(tree.hash));
-                        }
-                        Object::Blob(child_blob) => {
-                            assert_eq!(child_blob.parent, Some(tree.hash));
-                        }
-                    }
+impl ContentNormalization {
+    /// How it's saved next to a tag's tree
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            ContentNormalization::None => "none",
+            ContentNormalization::LineEndings => "line_endings",
+            ContentNormalization::TrailingWhitespace => "trailing_whitespace",
+        }
+    }
+
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        [
+            ContentNormalization::None,
+            ContentNormalization::LineEndings,
+            ContentNormalization::TrailingWhitespace,
+        ]
+        .iter()
+        .copied()
+        .find(|normalization| normalization.as_str() == name)
+    }
+}
+
+/// Hashes "blob {file_ext} {content}", normalizing the content as it's given it. Only ASCII is
+/// ever dropped, so content can be split anywhere, even inside a character
+struct BlobHasher {
+    hasher: Sha1,
+    normalization: ContentNormalization,
+
+    /// Whitespace (and `\r`) seen at the end of the content so far, which is only hashed if
+    /// something other than the end of the line follows it
+    held: Vec<u8>,
+}
+
+impl BlobHasher {
+    fn new(file_ext: &str, normalization: ContentNormalization) -> Self {
+        let mut hasher = Sha1::new();
+        hasher.update(b"blob ");
+        hasher.update(file_ext.as_bytes());
+        hasher.update(b" ");
+        BlobHasher {
+            hasher,
+            normalization,
+            held: Vec::new(),
+        }
+    }
+
+    fn held_byte(&self, byte: u8) -> bool {
+        match self.normalization {
+            ContentNormalization::None => false,
+            ContentNormalization::LineEndings => byte == b'\r' && self.held.is_empty(),
+            ContentNormalization::TrailingWhitespace => matches!(byte, b' ' | b'\t' | b'\r'),
+        }
+    }
+
+    fn update(&mut self, content: &[u8]) {
+        if self.normalization == ContentNormalization::None {
+            self.hasher.update(content);
+            return;
+        }
+
+        // Bytes from `start` up to the current one are hashed as they are
+        let mut start = 0;
+        for (index, &byte) in content.iter().enumerate() {
+            if !self.held.is_empty() && !self.held_byte(byte) {
+                // Held bytes before the end of a line are dropped, and any others kept
+                if byte != b'\n' {
+                    self.hasher.update(&self.held);
+                }
+                self.held.clear();
+                start = index;
+            }
+            if self.held_byte(byte) {
+                self.hasher.update(&content[start..index]);
+                self.held.push(byte);
+                start = index + 1;
+            }
+        }
+        self.hasher.update(&content[start..]);
+    }
+
+    fn finalize(mut self) -> ObjectHash {
+        if self.normalization == ContentNormalization::LineEndings {
+            // A `\r` at the very end isn't a line ending
+            self.hasher.update(&self.held);
+        }
+        self.hasher.finalize().into()
+    }
+}
+
+/// How a file's content is turned into its hash
+#[derive(Clone, Copy, Debug, Default)]
+struct ContentRules {
+    normalization: ContentNormalization,
+
+    /// Hash it even if it looks binary, for extensions given to `SyncOptions::text_extension`
+    allow_binary: bool,
+}
+
+/// Hash "blob {file_ext} {content}" without holding all of the content in memory. Content that
+/// isn't UTF-8 is transcoded to it first, and binary content is an `InvalidData` error, the same
+/// as `read_to_string` gives for anything that isn't UTF-8 (see `encoding`)
+fn blob_hash(reader: impl Read, file_ext: &str, rules: ContentRules) -> io::Result<ObjectHash> {
+    HASH_BUFFER.with(|buffer| {
+        let mut buffer = buffer.borrow_mut();
+        buffer.resize(HASH_BUFFER_SIZE, 0);
+        blob_hash_with_buffer(reader, file_ext, rules, &mut buffer)
+    })
+}
+
+fn blob_hash_with_buffer(
+    mut reader: impl Read,
+    file_ext: &str,
+    rules: ContentRules,
+    buffer: &mut [u8],
+) -> io::Result<ObjectHash> {
+    let mut hasher = BlobHasher::new(file_ext, rules.normalization);
+    let mut decoder = Decoder::new(!rules.allow_binary);
+    loop {
+        let read = match reader.read(buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        decoder.decode(&buffer[..read], &mut |text| hasher.update(text))?;
+    }
+    decoder.finish(&mut |text| hasher.update(text))?;
+    Ok(hasher.finalize())
+}
+
+/// The same as `blob_hash`, for content that is already in memory
+fn blob_hash_bytes(content: &[u8], file_ext: &str, rules: ContentRules) -> io::Result<ObjectHash> {
+    let mut hasher = BlobHasher::new(file_ext, rules.normalization);
+    let mut decoder = Decoder::new(!rules.allow_binary);
+    decoder.decode(content, &mut |text| hasher.update(text))?;
+    decoder.finish(&mut |text| hasher.update(text))?;
+    Ok(hasher.finalize())
+}
+
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+fn read_blob_hash(
+    file: std::fs::File,
+    file_ext: &str,
+    rules: ContentRules,
+) -> io::Result<ObjectHash> {
+    match super::uring::with_reader(&file, |reader| blob_hash(reader, file_ext, rules))? {
+        Some(hash) => Ok(hash),
+        None => blob_hash(file, file_ext, rules),
+    }
+}
+
+#[cfg(not(all(feature = "io_uring", target_os = "linux")))]
+fn read_blob_hash(
+    file: std::fs::File,
+    file_ext: &str,
+    rules: ContentRules,
+) -> io::Result<ObjectHash> {
+    blob_hash(file, file_ext, rules)
+}
+
+/// The extension that goes in a file's hash. One that isn't valid UTF-8 is hashed with
+/// replacement characters, which is still the same every time
+fn file_ext(filepath: &Path) -> Cow<'_, str> {
+    filepath
+        .extension()
+        .map_or(Cow::Borrowed(""), |ext| ext.to_string_lossy())
+}
+
+/// Files smaller than this fit in one read anyway, so aren't worth mapping
+const MIN_MMAP_SIZE: u64 = HASH_BUFFER_SIZE as u64;
+
+/// Hash a file's content, hashing straight from a memory mapping of the file if `mmap` is set
+/// and the file is big enough
+fn create_blob(
+    filepath: &Path,
+    parent: Option<ObjectHash>,
+    mmap: bool,
+    rules: ContentRules,
+) -> Result<Blob> {
+    let file = std::fs::File::open(win_path::extended(filepath))?;
+    let file_ext = &file_ext(filepath);
+    let mapped = match mmap && file.metadata()?.len() >= MIN_MMAP_SIZE {
+        true => Mmap::map(&file)?,
+        false => None,
+    };
+    let hash = match mapped {
+        Some(mapped) => blob_hash_bytes(&mapped, file_ext, rules)?,
+        None => read_blob_hash(file, file_ext, rules)?,
+    };
+    Ok(Blob {
+        parent,
+        hash,
+        path: filepath.to_path_buf(),
+        stat: None,
+    })
+}
+
+fn tree_hash(children: impl IntoIterator<Item = ObjectHash>) -> ObjectHash {
+    let mut hasher = Sha1::new();
+    hasher.update(b"tree");
+
+    // Note you're not just concatenating
+    for child in children {
+        hasher.update(child);
+    }
+    let result = hasher.finalize();
+    let hash_bytes: [u8; 20] = result.into();
+    hash_bytes
+}
+
+/// The extended attribute a file's hash is cached in, with `SyncOptions::xattr_cache`
+const HASH_XATTR: &str = "user.continue.hash";
+const HASH_XATTR_VERSION: u8 = 2;
+
+/// The hash saved in the file's extended attribute, as long as it was saved for this size,
+/// mtime, normalization and extension (which is part of the hash)
+fn cached_hash(
+    filepath: &Path,
+    stat: FileStat,
+    normalization: ContentNormalization,
+    file_ext: &str,
+) -> Option<ObjectHash> {
+    // Any problem just means hashing the file again
+    let value = xattr::get(filepath, HASH_XATTR, 64 + file_ext.len()).ok()??;
+    let mut reader = BinaryReader { bytes: &value };
+    if reader.u8().ok()? != HASH_XATTR_VERSION
+        || reader.u64().ok()? != stat.size
+        || reader.u64().ok()? != stat.mtime
+        || reader.u8().ok()? != normalization as u8
+    {
+        return None;
+    }
+    let hash = reader.hash().ok()?;
+    match reader.bytes == file_ext.as_bytes() {
+        true => Some(hash),
+        false => None,
+    }
+}
+
+fn cache_hash(
+    filepath: &Path,
+    stat: FileStat,
+    normalization: ContentNormalization,
+    file_ext: &str,
+    hash: ObjectHash,
+) {
+    let mut value = vec![HASH_XATTR_VERSION];
+    value.extend_from_slice(&stat.size.to_le_bytes());
+    value.extend_from_slice(&stat.mtime.to_le_bytes());
+    value.push(normalization as u8);
+    value.extend_from_slice(&hash);
+    value.extend_from_slice(file_ext.as_bytes());
+    // Read-only files and filesystems without extended attributes just don't get cached
+    let _ = xattr::set(filepath, HASH_XATTR, &value);
+}
+
+/// The previous blob for the file at `path`, if its size and mtime haven't changed
+fn previous_blob(
+    path: &Path,
+    stat: Option<FileStat>,
+    previous_blobs: &HashMap<&Path, &Blob>,
+) -> Option<Blob> {
+    let previous = previous_blobs.get(path)?;
+    match stat.is_some() && previous.stat == stat {
+        true => Some(Blob {
+            parent: None,
+            ..(*previous).clone()
+        }),
+        false => None,
+    }
+}
+
+/// Reuse the previous blob for the file at `path` in `dir` if its size and mtime haven't
+/// changed, otherwise hash it
+fn create_or_reuse_blob(
+    dir: &Path,
+    path: &Path,
+    stat: Option<FileStat>,
+    previous_blobs: &HashMap<&Path, &Blob>,
+    options: &SyncOptions,
+) -> Result<Blob> {
+    let file_ext = &file_ext(path);
+    let binary = options.binary_by_extension(file_ext);
+    if binary == Some(true) {
+        // Not worth reading to find out
+        return Err(binary_error().into());
+    }
+
+    if let Some(previous) = previous_blob(path, stat, previous_blobs) {
+        return Ok(previous);
+    }
+
+    let filepath = &dir.join(path);
+    let normalization = options.content_normalization;
+    let xattr_stat = stat.filter(|_| options.xattr_cache);
+    if let Some(stat) = xattr_stat {
+        if let Some(hash) = cached_hash(filepath, stat, normalization, file_ext) {
+            return Ok(Blob {
+                parent: None,
+                hash,
+                path: path.to_path_buf(),
+                stat: Some(stat),
+            });
+        }
+    }
+
+    // A file written to while it was read may have been hashed half old and half new, so it's
+    // kept without its stat, which means the next sync hashes it again
+    let rules = ContentRules {
+        normalization,
+        allow_binary: binary == Some(false),
+    };
+    let mut blob = create_blob(filepath, None, options.mmap, rules)?;
+    let after = fs::metadata(win_path::extended(filepath)).ok();
+    options.throttle_read(after.as_ref().map_or(0, |metadata| metadata.len()));
+    blob.path = path.to_path_buf();
+    blob.stat = stat.filter(|&stat| after.as_ref().and_then(FileStat::from_metadata) == Some(stat));
+    if let Some(stat) = xattr_stat.filter(|_| blob.stat.is_some()) {
+        cache_hash(filepath, stat, normalization, file_ext, blob.hash);
+    }
+    Ok(blob)
+}
+
+/// Run `f` on every item on every available core, returning the results in the same order
+fn map_in_parallel<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> R + Sync) -> Vec<R> {
+    // wasm32 can't start threads, so it all happens on this one
+    if cfg!(target_arch = "wasm32") {
+        return items.iter().map(f).collect();
+    }
+    let threads = thread::available_parallelism()
+        .map_or(1, |threads| threads.get())
+        .clamp(1, items.len().max(1));
+
+    // Items vary a lot in cost, so hand them out one at a time rather than in fixed chunks
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<Option<R>> = items.iter().map(|_| None).collect();
+    let lowered = throttle::is_lowered();
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    // The workers read for the thread that started them, so go at its priority
+                    let _priority = LowPriority::enter(lowered);
+                    let mut done = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        if index >= items.len() {
+                            break done;
+                        }
+                        done.push((index, f(&items[index])));
+                    }
+                })
+            })
+            .collect();
+
+        for worker in workers {
+            for (index, result) in worker.join().expect("worker thread panicked") {
+                results[index] = Some(result);
+            }
+        }
+    });
+
+    results
+        .into_iter()
+        .map(|result| result.expect("every item was handled"))
+        .collect()
+}
+
+/// How many walked files can wait for a hashing thread before the walk waits for them
+const HASH_QUEUE_DEPTH: usize = 256;
+
+/// Run `f` on every item `produce` hands its callback, on every available core while `produce`
+/// is still going, so that finding the items and working through them overlap. Returns what
+/// `produce` did, and the results in the order the items were handed over. The items go
+/// through a bounded channel, so a producer that gets far ahead waits for the workers
+fn map_while_producing<T: Send, R: Send, P>(
+    produce: impl FnOnce(&mut dyn FnMut(T)) -> P,
+    f: impl Fn(T) -> R + Sync,
+) -> (P, Vec<R>) {
+    if cfg!(target_arch = "wasm32") {
+        let mut results = Vec::new();
+        let produced = produce(&mut |item| results.push(f(item)));
+        return (produced, results);
+    }
+    let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+    let (sender, receiver) = mpsc::sync_channel::<(usize, T)>(HASH_QUEUE_DEPTH);
+    // Held by the workers alone, so that if they all panic the producer isn't left waiting
+    let receiver = Arc::new(Mutex::new(receiver));
+    let lowered = throttle::is_lowered();
+    let f = &f;
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                let receiver = receiver.clone();
+                scope.spawn(move || {
+                    let _priority = LowPriority::enter(lowered);
+                    let mut done = Vec::new();
+                    loop {
+                        let next = receiver.lock().unwrap().recv();
+                        match next {
+                            Ok((index, item)) => done.push((index, f(item))),
+                            Err(_) => break done,
+                        }
+                    }
+                })
+            })
+            .collect();
+        drop(receiver);
+
+        let mut sent = 0;
+        let produced = produce(&mut |item| {
+            sender.send((sent, item)).expect("worker thread panicked");
+            sent += 1;
+        });
+        drop(sender);
+
+        let mut results: Vec<Option<R>> = (0..sent).map(|_| None).collect();
+        for worker in workers {
+            for (index, result) in worker.join().expect("worker thread panicked") {
+                results[index] = Some(result);
+            }
+        }
+        let results = results
+            .into_iter()
+            .map(|result| result.expect("every item was handled"))
+            .collect();
+        (produced, results)
+    })
+}
+
+/// The contents of unsaved editor buffers, by the path of their file in the tree
+type Overlay = HashMap<PathBuf, Arc<[u8]>>;
+
+/// The buffers given to `SyncOptions::unsaved_buffer` whose files are in `dir`
+fn overlay_in(dir: &Path, options: &SyncOptions) -> Overlay {
+    let mut overlay = Overlay::new();
+    for (path, contents) in &options.unsaved_buffers {
+        // The tag's directory has had symlinks resolved, which the editor's path may not have
+        let path = match path.starts_with(dir) {
+            true => Cow::Borrowed(path),
+            false => Cow::Owned(canonical_dir(path)),
+        };
+        if path.starts_with(dir) && *path != *dir {
+            overlay.insert(stored_path(&path, dir, options), contents.clone());
+        }
+    }
+    overlay
+}
+
+/// The blob for the file at `path` whose contents are already in memory: an unsaved buffer or
+/// an archive's member. It has no stat, so that the file of a buffer is read again once the
+/// buffer is gone
+fn contents_blob(path: &Path, contents: &[u8], options: &SyncOptions) -> Result<Blob> {
+    let file_ext = &file_ext(path);
+    let binary = options.binary_by_extension(file_ext);
+    if binary == Some(true) {
+        return Err(binary_error().into());
+    }
+    let rules = ContentRules {
+        normalization: options.content_normalization,
+        allow_binary: binary == Some(false),
+    };
+    Ok(Blob {
+        parent: None,
+        hash: blob_hash_bytes(contents, file_ext, rules)?,
+        path: path.to_path_buf(),
+        stat: None,
+    })
+}
+
+/// Hash files in `dir` on every available core, returning the blobs in the same order as
+/// `files`. A blob without the stat its file was walked with was changed while it was hashed.
+/// Files in `overlay` are hashed from there, and should be walked without a stat
+fn create_blobs_in_parallel(
+    dir: &Path,
+    files: &[(&Path, Option<FileStat>)],
+    previous_blobs: &HashMap<&Path, &Blob>,
+    overlay: &Overlay,
+    options: &SyncOptions,
+) -> Vec<Result<Blob>> {
+    map_in_parallel(files, |&(path, stat)| match overlay.get(path) {
+        Some(contents) => contents_blob(path, contents, options),
+        None => create_or_reuse_blob(dir, path, stat, previous_blobs, options),
+    })
+}
+
+/// The tree for the archive at `path`, unpacked from what `read` gives, with a file for each of
+/// the archive's (see `SyncOptions::archives`), along with warnings about those left out. The
+/// members' blobs are kept with the archive's size and mtime, so the tree from before is reused
+/// as it is while those haven't changed
+fn create_or_reuse_archive_tree(
+    dir: &Path,
+    path: &Path,
+    stat: Option<FileStat>,
+    previous_trees: &HashMap<&Path, &Tree>,
+    options: &SyncOptions,
+    read: impl FnOnce() -> io::Result<Vec<u8>>,
+) -> Result<(Tree, Vec<SyncWarning>)> {
+    if let (Some(stat), Some(previous)) = (stat, previous_trees.get(path)) {
+        let mut stamped = Vec::new();
+        previous.walk(|object| {
+            if let Some(blob) = object.as_blob() {
+                stamped.push(blob.stat == Some(stat));
+            }
+        });
+        if !stamped.is_empty() && stamped.into_iter().all(|stamped| stamped) {
+            return Ok(((*previous).clone(), Vec::new()));
+        }
+    }
+    let data = read()?;
+    options.throttle_read(data.len() as u64);
+    let members =
+        archive::members(path, &data, options.resolve_max_file_size()).map_err(io::Error::other)?;
+    let mut warnings = Vec::new();
+    let mut blobs = Vec::new();
+    for member in members {
+        let member_path = path.join(&member.path);
+        let contents = match member.contents {
+            Some(contents) => contents,
+            None => {
+                warnings.push(SyncWarning::FileTooLarge {
+                    path: dir.join(&member_path).display().to_string(),
+                    size: member.size,
+                });
+                continue;
+            }
+        };
+        match contents_blob(&member_path, &contents, options) {
+            Ok(blob) => blobs.push(Blob { stat, ..blob }),
+            // Archives are full of compiled files, which would be a warning each
+            Err(SyncError::Io(err)) if err.kind() == io::ErrorKind::InvalidData => {}
+            Err(err) => warnings.extend(skipped_file_warning(&dir.join(&member_path), err)),
+        }
+    }
+    Ok((nest_blobs(path, blobs), warnings))
+}
+
+/// The tree at `path` holding `blobs`, which are all under it, with a tree for each directory
+/// they're in
+fn nest_blobs(path: &Path, blobs: Vec<Blob>) -> Tree {
+    let mut children = Vec::new();
+    let mut subdirs: BTreeMap<PathBuf, Vec<Blob>> = BTreeMap::new();
+    for blob in blobs {
+        let relative = blob.path.strip_prefix(path).unwrap_or(&blob.path);
+        let mut components = relative.components();
+        let subdir = components.next().map(|first| path.join(first));
+        match (subdir, components.next()) {
+            (Some(subdir), Some(_)) => subdirs.entry(subdir).or_default().push(blob),
+            _ => children.push(Object::Blob(blob)),
+        }
+    }
+    for (subdir, blobs) in subdirs {
+        children.push(Object::Tree(nest_blobs(&subdir, blobs)));
+    }
+    PreTree {
+        children,
+        path: path.to_path_buf(),
+    }
+    .finalize()
+}
+
+/// Call `store` with the content each of `items`, files in the tree of the tag whose directory
+/// is `dir`, was hashed from, for `SyncOptions::store_objects`: its unsaved buffer, or the file
+/// read again (through `SyncOptions::file_system`, and out of its archive for a member of one).
+/// Files are read on every core, and an archive once however many of its members there are. A
+/// file that can't be read, or no longer hashes the same, is a warning instead
+pub(crate) fn for_each_hashed_file(
+    dir: &Path,
+    items: &[&ObjDescription],
+    options: &SyncOptions,
+    store: impl Fn(&ObjDescription, &[u8]) -> Result<()> + Sync,
+) -> Result<Vec<SyncWarning>> {
+    let overlay = overlay_in(dir, options);
+    let read = |path: &Path| {
+        let contents = match &options.file_system {
+            Some(file_system) => file_system.read(path),
+            None => fs::read(win_path::extended(path)),
+        }?;
+        options.throttle_read(contents.len() as u64);
+        Ok(contents)
+    };
+    let is_file = |path: &Path| match &options.file_system {
+        Some(file_system) => file_system.metadata(path).is_ok_and(|meta| !meta.is_dir),
+        None => path.is_file(),
+    };
+    let store_contents = |item: &ObjDescription, contents: io::Result<Cow<[u8]>>| {
+        let filepath = dir.join(&item.path);
+        match contents.map_err(SyncError::from).and_then(|contents| {
+            let blob = contents_blob(&item.path, &contents, options)?;
+            Ok((blob, contents))
+        }) {
+            Ok((blob, contents)) if blob.hash == item.hash => store(item, &contents).map(|()| None),
+            Ok(_) => Ok(Some(SyncWarning::ChangedDuringSync {
+                path: filepath.display().to_string(),
+            })),
+            Err(err) => Ok(skipped_file_warning(&filepath, err)),
+        }
+    };
+
+    // Members of archives are unpacked together, from their archive
+    let mut files = Vec::new();
+    let mut archives: BTreeMap<&Path, Vec<&ObjDescription>> = BTreeMap::new();
+    for &item in items {
+        let archive = match options.archives && !overlay.contains_key(&item.path) {
+            true => item
+                .path
+                .ancestors()
+                .skip(1)
+                .find(|path| archive::is_archive(path) && is_file(&dir.join(path))),
+            false => None,
+        };
+        match archive {
+            Some(archive) => archives.entry(archive).or_default().push(item),
+            None => files.push(item),
+        }
+    }
+
+    let mut warnings = Vec::new();
+    let stored = map_in_parallel(&files, |item| match overlay.get(&item.path) {
+        Some(contents) => store_contents(item, Ok(Cow::Borrowed(contents))),
+        None => store_contents(item, read(&dir.join(&item.path)).map(Cow::Owned)),
+    });
+    for result in stored {
+        warnings.extend(result?);
+    }
+    for (archive, items) in archives {
+        let filepath = dir.join(archive);
+        let members = read(&filepath)
+            .map_err(|err| err.to_string())
+            .and_then(|data| archive::members(archive, &data, u64::MAX));
+        let mut members: HashMap<PathBuf, Vec<u8>> = match members {
+            Ok(members) => members
+                .into_iter()
+                .filter_map(|member| Some((archive.join(member.path), member.contents?)))
+                .collect(),
+            Err(message) => {
+                warnings.push(SyncWarning::UnreadableFile {
+                    path: filepath.display().to_string(),
+                    message,
+                });
+                continue;
+            }
+        };
+        for item in items {
+            let contents = members
+                .remove(&item.path)
+                .map(Cow::Owned)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound));
+            warnings.extend(store_contents(item, contents)?);
+        }
+    }
+    Ok(warnings)
+}
+
+/// A file that differs between a saved tree and its directory, as found by `stream_diff_dir`
+pub(crate) enum BlobChange {
+    Add(ObjDescription),
+    Remove(ObjDescription),
+
+    /// Only matters when the caches are being ignored
+    Unchanged(ObjDescription),
+}
+
+fn blob_descr(hash: ObjectHash, path: PathBuf) -> ObjDescription {
+    ObjDescription {
+        hash,
+        path,
+        is_blob: true,
+    }
+}
+
+/// A directory being walked by `stream_diff_dir`, which is finished once the walk leaves it
+struct StreamFrame {
+    path: PathBuf,
+
+    /// The path, hash and record of every child so far
+    children: Vec<(PathBuf, ObjectHash, ChildRecord)>,
+
+    /// Files that haven't been hashed yet. They are hashed together, as soon as the walk
+    /// reaches a subdirectory or leaves the directory, so that changes are reported in the
+    /// same order as `diff` would
+    files: Vec<(PathBuf, Option<FileStat>)>,
+
+    /// Files in the saved directory, by `match_key`, until they are found again
+    old_blobs: BTreeMap<PathBuf, Blob>,
+
+    /// Record names of the saved directory's subdirectories, by `match_key`, until they are
+    /// walked
+    old_subdirs: BTreeMap<PathBuf, String>,
+}
+
+/// Everything `stream_diff_dir` needs while finishing directories
+struct TreeStream<'a> {
+    /// The directory being walked, which the paths in the tree are relative to
+    dir: PathBuf,
+    /// See `saved_root_path`
+    old_root: PathBuf,
+    /// Where the tree is saved, the index's file system
+    file_system: &'a dyn FileSystem,
+    subtrees_dir: PathBuf,
+    options: &'a SyncOptions,
+    live: HashSet<String>,
+    /// See `fold_case`
+    fold_case: bool,
+    /// Hash every file, instead of reusing the saved hashes of those that haven't changed
+    rehash: bool,
+    walk_start: WalkStart,
+    overlay: Overlay,
+    warnings: &'a mut Vec<SyncWarning>,
+    on_change: &'a mut dyn FnMut(BlobChange) -> Result<()>,
+}
+
+impl TreeStream<'_> {
+    /// A path from a saved record, relative to the root
+    fn old_path(&self, path: PathBuf) -> PathBuf {
+        match path.strip_prefix(&self.old_root) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => path,
+        }
+    }
+
+    fn open_frame(&self, path: PathBuf, old_name: Option<&str>) -> Result<StreamFrame> {
+        let (old_path, old_children) = match old_name {
+            Some(name) => {
+                let record = SubtreeRecord::read(self.file_system, &self.subtrees_dir, name)?;
+                (record.path, record.children)
+            }
+            None => (PathBuf::new(), Vec::new()),
+        };
+
+        // Only the paths of the subdirectories are kept, their own records are read again
+        // when they are walked
+        let mut old_blobs = BTreeMap::new();
+        let mut old_subdirs = BTreeMap::new();
+        for child in old_children {
+            let (path, duplicate) = match child {
+                ChildRecord::Blob(mut node) => {
+                    node.path = self.old_path(node.path);
+                    let key = match_key(&node.path, self.fold_case).into_owned();
+                    let path = node.path.clone();
+                    (path, old_blobs.insert(key, Blob::from_node(node)).is_some())
+                }
+                ChildRecord::Tree(name) => {
+                    let subdir = SubtreeRecord::read_child(
+                        self.file_system,
+                        &self.subtrees_dir,
+                        &name,
+                        &old_path,
+                    )?;
+                    let path = self.old_path(subdir.path);
+                    let key = match_key(&path, self.fold_case).into_owned();
+                    (path, old_subdirs.insert(key, name).is_some())
+                }
+            };
+            if duplicate {
+                return Err(SyncError::CorruptedIndex(format!(
+                    "{} is saved twice in {}",
+                    path.display(),
+                    old_path.display()
+                )));
+            }
+        }
+
+        Ok(StreamFrame {
+            path,
+            children: Vec::new(),
+            files: Vec::new(),
+            old_blobs,
+            old_subdirs,
+        })
+    }
+
+    /// Hash the files waiting in the directory and report how they changed
+    fn hash_files(&mut self, frame: &mut StreamFrame) -> Result<()> {
+        if frame.files.is_empty() {
+            return Ok(());
+        }
+        let files: Vec<(&Path, Option<FileStat>)> = frame
+            .files
+            .iter()
+            .map(|(path, stat)| (path.as_path(), *stat))
+            .collect();
+        let previous: HashMap<&Path, &Blob> = frame
+            .old_blobs
+            .values()
+            .filter(|_| !self.rehash)
+            .map(|blob| (blob.path.as_path(), blob))
+            .collect();
+        let blobs =
+            create_blobs_in_parallel(&self.dir, &files, &previous, &self.overlay, self.options);
+
+        for ((path, stat), blob) in frame.files.drain(..).zip(blobs) {
+            match blob {
+                Ok(blob) => {
+                    if stat.is_some() && blob.stat.is_none() {
+                        self.warnings.push(SyncWarning::ChangedDuringSync {
+                            path: self.dir.join(&blob.path).display().to_string(),
+                        });
+                    }
+                    let descr = blob_descr(blob.hash, blob.path.clone());
+                    match frame
+                        .old_blobs
+                        .remove(&*match_key(&blob.path, self.fold_case))
+                    {
+                        Some(old) if old.hash == blob.hash => {
+                            (self.on_change)(BlobChange::Unchanged(descr))?
+                        }
+                        Some(old) => {
+                            (self.on_change)(BlobChange::Remove(blob_descr(old.hash, old.path)))?;
+                            (self.on_change)(BlobChange::Add(descr))?;
+                        }
+                        None => (self.on_change)(BlobChange::Add(descr))?,
+                    }
+                    let node = SerializeableNode {
+                        parent: None,
+                        ..blob.to_node()
+                    };
+                    let child = (blob.path.clone(), blob.hash, ChildRecord::Blob(node));
+                    frame.children.push(child);
+                }
+                Err(err) => {
+                    let warning = skipped_file_warning(&self.dir.join(&path), err);
+                    self.warnings.extend(warning);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Report how the rest of the directory changed and write its record. Returns the
+    /// directory's path, hash and record name
+    fn finish_frame(&mut self, mut frame: StreamFrame) -> Result<(PathBuf, ObjectHash, String)> {
+        self.hash_files(&mut frame)?;
+        self.walk_start
+            .check_dir(&self.dir.join(&frame.path), self.warnings);
+
+        // Files and directories that are gone, or changed from one to the other
+        for (_, old) in std::mem::take(&mut frame.old_blobs) {
+            (self.on_change)(BlobChange::Remove(blob_descr(old.hash, old.path)))?;
+        }
+        for name in frame.old_subdirs.values() {
+            self.remove_saved(name)?;
+        }
+
+        frame.children.sort_by(|(a, _, _), (b, _, _)| by_name(a, b));
+        let (hashes, children): (Vec<ObjectHash>, Vec<ChildRecord>) = frame
+            .children
+            .into_iter()
+            .map(|(_, hash, child)| (hash, child))
+            .unzip();
+        let record = SubtreeRecord {
+            hash: tree_hash(hashes),
+            path: frame.path,
+            children,
+        };
+        let name = record.write(
+            self.file_system,
+            &self.subtrees_dir,
+            self.options.tree_format,
+            self.options.tree_compression,
+            self.options.fsync,
+            !self.options.dry_run,
+        )?;
+        self.live.insert(name.clone());
+        Ok((record.path, record.hash, name))
+    }
+
+    /// Report every file in a saved directory as removed
+    fn remove_saved(&mut self, name: &str) -> Result<()> {
+        let record = SubtreeRecord::read(self.file_system, &self.subtrees_dir, name)?;
+        self.remove_saved_children(record, 0)
+    }
+
+    fn remove_saved_children(&mut self, record: SubtreeRecord, depth: usize) -> Result<()> {
+        check_depth(depth)?;
+        for child in record.children {
+            match child {
+                ChildRecord::Blob(node) => {
+                    let path = self.old_path(node.path);
+                    (self.on_change)(BlobChange::Remove(blob_descr(node.hash, path)))?
+                }
+                ChildRecord::Tree(name) => {
+                    let subdir = SubtreeRecord::read_child(
+                        self.file_system,
+                        &self.subtrees_dir,
+                        &name,
+                        &record.path,
+                    )?;
+                    self.remove_saved_children(subdir, depth + 1)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The name of the root record of the tree saved at `tree_path`, if there is one. A tree saved
+/// as one JSONL file by older versions is split into records first. A tree that can't be read
+/// is reported in `warnings` and treated as missing, the same as `sync()` does
+fn open_saved_tree(
+    file_system: &dyn FileSystem,
+    tree_path: &Path,
+    options: &SyncOptions,
+    warnings: &mut Vec<SyncWarning>,
+) -> Result<Option<String>> {
+    let line = match first_line(file_system, tree_path) {
+        Ok(line) => line,
+        Err(SyncError::Io(err)) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if let Ok(Some(manifest)) = TreeManifest::parse(&line) {
+        return Ok(Some(manifest.root));
+    }
+
+    match Tree::load_in(file_system, tree_path) {
+        Ok(tree) => {
+            // The records are left behind by a dry run, but nothing points at them and the
+            // next real sync deletes them
+            let subtrees_dir = subtrees_dir(tree_path);
+            file_system.create_dir_all(&subtrees_dir)?;
+            let root = tree.persist_subtree(
+                file_system,
+                &subtrees_dir,
+                options.tree_format,
+                options.tree_compression,
+                options.fsync,
+                &mut HashSet::new(),
+            )?;
+            Ok(Some(root))
+        }
+        Err(SyncError::CorruptedIndex(message)) => {
+            warnings.push(SyncWarning::CorruptedTree { message });
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// The path of the root record `root`, which older versions saved as the absolute path of the
+/// directory, with every other path under it. It's stripped off what they saved, so every path
+/// is relative to the root whichever version saved it
+fn saved_root_path(
+    file_system: &dyn FileSystem,
+    subtrees_dir: &Path,
+    root: Option<&str>,
+) -> Result<PathBuf> {
+    match root {
+        Some(root) => Ok(SubtreeRecord::read(file_system, subtrees_dir, root)?.path),
+        None => Ok(PathBuf::new()),
+    }
+}
+
+/// Every file in the tree saved at `tree_path`, one directory in memory at a time
+pub(crate) fn for_each_saved_blob(
+    tree_path: &Path,
+    options: &SyncOptions,
+    warnings: &mut Vec<SyncWarning>,
+    on_blob: &mut dyn FnMut(ObjDescription) -> Result<()>,
+) -> Result<()> {
+    let file_system = &*options.resolve_index_file_system();
+    let root = match open_saved_tree(file_system, tree_path, options, warnings)? {
+        Some(root) => root,
+        None => return Ok(()),
+    };
+    let mut on_change = |change| match change {
+        BlobChange::Remove(descr) => on_blob(descr),
+        _ => Ok(()),
+    };
+    let subtrees_dir = subtrees_dir(tree_path);
+    TreeStream {
+        dir: PathBuf::new(),
+        old_root: saved_root_path(file_system, &subtrees_dir, Some(&root))?,
+        file_system,
+        subtrees_dir,
+        options,
+        live: HashSet::new(),
+        fold_case: false,
+        rehash: false,
+        walk_start: WalkStart::now(),
+        overlay: Overlay::new(),
+        warnings,
+        on_change: &mut on_change,
+    }
+    .remove_saved(&root)
+}
+
+/// Compare `dir` with the tree saved at `tree_path` (or with nothing, if `from_empty`) while
+/// walking it, reporting each file that changed to `on_change` and saving the new tree in its
+/// place unless it's a dry run. Every file is hashed again if `rehash` is set, rather than only
+/// those whose size or mtime changed. Returns the new root hash.
+///
+/// Unlike `compute_tree_for_dir` and `diff`, neither tree is ever held in memory: only the
+/// directories between the root and the one being walked are, so memory use grows with the
+/// depth of the tree rather than the number of files in it. The saved tree is the same as
+/// `Tree::persist` would save
+pub(crate) fn stream_diff_dir(
+    dir: &Path,
+    tree_path: &Path,
+    from_empty: bool,
+    rehash: bool,
+    options: &SyncOptions,
+    warnings: &mut Vec<SyncWarning>,
+    on_change: &mut dyn FnMut(BlobChange) -> Result<()>,
+) -> Result<ObjectHash> {
+    let file_system = &*options.resolve_index_file_system();
+    let old_root = match from_empty {
+        true => None,
+        false => open_saved_tree(file_system, tree_path, options, warnings)?,
+    };
+    let subtrees_dir = subtrees_dir(tree_path);
+    if !options.dry_run {
+        file_system.create_dir_all(&subtrees_dir)?;
+    }
+
+    let dir = &*win_path::normalize(dir);
+    let walk_root = &*win_path::extended(dir);
+    let walk_start = WalkStart::now();
+    let mut walk = build_walk(walk_root, options)?;
+    let root_entry = walk
+        .next() // This is just "."
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Directory {} does not exist", dir.display()),
+            )
+        })??;
+
+    let mut stream = TreeStream {
+        dir: dir.to_path_buf(),
+        old_root: saved_root_path(file_system, &subtrees_dir, old_root.as_deref())?,
+        file_system,
+        subtrees_dir,
+        options,
+        live: HashSet::new(),
+        fold_case: fold_case(dir, options),
+        rehash,
+        walk_start,
+        overlay: overlay_in(dir, options),
+        warnings,
+        on_change,
+    };
+    let root_path = stored_path(root_entry.path(), walk_root, options);
+    let mut stack = vec![stream.open_frame(root_path.clone(), old_root.as_deref())?];
+    let mut current_dir = root_path;
+    let mut visited = VisitedDirs::default();
+    if let Ok(metadata) = root_entry.metadata() {
+        visited.enter(&metadata);
+    }
+
+    for entry in walk {
+        if visited.within_skipped(&entry) {
+            continue;
+        }
+        let entry = match walked(entry, walk_root, stream.warnings)? {
+            Some(entry) => entry,
+            None => continue,
+        };
+        let metadata = match walked(entry.metadata(), walk_root, stream.warnings)? {
+            Some(metadata) => metadata,
+            None => continue,
+        };
+        if visited.skip(&entry, &metadata, options, stream.warnings) {
+            continue;
+        }
+        let path = stored_path(entry.path(), walk_root, options);
+
+        // Every directory the walk has left is finished
+        while !path.starts_with(&current_dir) {
+            let (path, hash, name) = stream.finish_frame(stack.pop().unwrap())?;
+            let parent = stack.last_mut().unwrap();
+            parent.children.push((path, hash, ChildRecord::Tree(name)));
+            current_dir = current_dir.parent().unwrap().to_path_buf();
+        }
+
+        let parent = stack.last_mut().unwrap();
+        if !metadata.is_dir()
+            && too_large(&dir.join(&path), metadata.len(), options, stream.warnings)
+        {
+            continue;
+        }
+        if metadata.is_dir() {
+            stream.hash_files(parent)?;
+            let old_name = parent
+                .old_subdirs
+                .remove(&*match_key(&path, stream.fold_case));
+            stack.push(stream.open_frame(path.clone(), old_name.as_deref())?);
+            current_dir = path;
+        } else {
+            let stat = match stream.overlay.contains_key(&path) {
+                true => None,
+                false => walk_start.file_stat(&dir.join(&path), &metadata, stream.warnings),
+            };
+            parent.files.push((path, stat));
+        }
+    }
+
+    while stack.len() > 1 {
+        let (path, hash, name) = stream.finish_frame(stack.pop().unwrap())?;
+        let parent = stack.last_mut().unwrap();
+        parent.children.push((path, hash, ChildRecord::Tree(name)));
+    }
+    let (_, hash, root) = stream.finish_frame(stack.pop().unwrap())?;
+
+    if !options.dry_run {
+        TreeManifest {
+            version: MANIFEST_VERSION,
+            hash,
+            root,
+        }
+        .write(file_system, tree_path, &stream.live, options.fsync)?;
+    }
+    Ok(hash)
+}
+
+struct PreTree {
+    children: Vec<Object>,
+    path: PathBuf,
+}
+
+impl PreTree {
+    fn finalize(&self) -> Tree {
+        let mut children = self.children.clone();
+        children.sort_by(|a, b| by_name(a.path(), b.path()));
+        Tree {
+            parent: None,
+            hash: tree_hash(children.iter().map(Object::hash)),
+            children,
+            path: self.path.clone(),
+        }
+    }
+}
+
+/// Compute the merkle tree for a directory, respecting ignore files and `options.ignore`
+pub fn compute_tree_for_dir(dir: &Path, options: &SyncOptions) -> Result<Tree> {
+    compute_tree_with_warnings(dir, options, &Tree::default(), &mut Vec::new())
+}
+
+/// Same as `compute_tree_for_dir`, also reporting any files that had to be skipped. Files whose
+/// size and mtime match their blob in `previous` aren't read again
+pub(crate) fn compute_tree_with_warnings(
+    dir: &Path,
+    options: &SyncOptions,
+    previous: &Tree,
+    warnings: &mut Vec<SyncWarning>,
+) -> Result<Tree> {
+    update_tree(dir, options, previous, None, warnings)
+}
+
+/// What a walk found at a path
+enum Walked {
+    Dir,
+    File(Option<FileStat>),
+
+    /// A file indexed as a directory, with `SyncOptions::archives`
+    Archive(Option<FileStat>),
+
+    /// A directory that `update_tree` didn't walk, whose tree from before is reused
+    Unchanged(Tree),
+}
+
+/// The directories `update_tree` leaves out of the walk, since nothing changed in them
+struct UnchangedDirs {
+    walk_root: PathBuf,
+    options: SyncOptions,
+    changed: Vec<PathBuf>,
+    previous: HashSet<PathBuf>,
+
+    /// Filled in as the walk leaves them out
+    skipped: std::sync::Mutex<Vec<PathBuf>>,
+}
+
+impl UnchangedDirs {
+    /// Whether to leave the entry out of the walk, recording it if so
+    fn skip(&self, entry: &DirEntry) -> bool {
+        if entry.depth() == 0 || !entry.file_type().is_some_and(|kind| kind.is_dir()) {
+            return false;
+        }
+        let path = stored_path(entry.path(), &self.walk_root, &self.options);
+        let changed = self
+            .changed
+            .iter()
+            .any(|changed| changed.starts_with(&path) || path.starts_with(changed));
+        if changed || !self.previous.contains(&path) {
+            return false;
+        }
+        self.skipped.lock().unwrap().push(path);
+        true
+    }
+}
+
+/// Same as `compute_tree_with_warnings`, but only walking the directories that the `changed`
+/// paths (relative to `dir`) are in or under, or everything if it's None. Every other directory
+/// in `previous` is reused as it is, without being listed again, so this is only right if
+/// nothing else has changed since `previous` was computed (the files of unsaved buffers are
+/// always looked at). Warnings are only given for what was walked
+pub(crate) fn update_tree(
+    dir: &Path,
+    options: &SyncOptions,
+    previous: &Tree,
+    changed: Option<&[PathBuf]>,
+    warnings: &mut Vec<SyncWarning>,
+) -> Result<Tree> {
+    if let Some(file_system) = &options.file_system {
+        return update_tree_in(&**file_system, dir, options, previous, warnings);
+    }
+    let dir = &*win_path::normalize(dir);
+    let walk_root = &*win_path::extended(dir);
+    let walk_start = WalkStart::now();
+    let mut builder = walk_builder(walk_root, options)?;
+    let previous_trees = previous.trees_by_path();
+    let overlay = overlay_in(dir, options);
+    let unchanged = changed.map(|changed| {
+        let unchanged = Arc::new(UnchangedDirs {
+            walk_root: walk_root.to_path_buf(),
+            options: options.clone(),
+            changed: changed
+                .iter()
+                .map(|path| stored_path(&walk_root.join(path), walk_root, options))
+                .chain(overlay.keys().cloned())
+                .collect(),
+            previous: previous_trees
+                .keys()
+                .map(|path| path.to_path_buf())
+                .collect(),
+            skipped: Default::default(),
+        });
+        let filter = unchanged.clone();
+        builder.filter_entry(move |entry| !filter.skip(entry));
+        unchanged
+    });
+    let mut walk = builder.build();
+    let root_entry = walk
+        .next() // This is just "."
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Directory {} does not exist", dir.display()),
+            )
+        })??;
+
+    // The last in the vector is the latest
+    // The first in the stack will end up being the root
+    let mut tree_stack: Vec<PreTree> = Vec::new();
+    let root_path = stored_path(root_entry.path(), walk_root, options);
+    tree_stack.push(PreTree {
+        children: Vec::new(),
+        path: root_path.clone(),
+    });
+    let mut current_dir = root_path;
+    let mut visited = VisitedDirs::default();
+    if let Ok(metadata) = root_entry.metadata() {
+        visited.enter(&metadata);
+    }
+
+    // Files are hashed on every core as the walk finds them, so that reading and hashing
+    // overlap with listing the directories still to come
+    let previous_blobs = previous.blobs_by_path();
+    let hash = |(path, stat): (PathBuf, Option<FileStat>)| {
+        let blob = match overlay.get(&path) {
+            Some(contents) => contents_blob(&path, contents, options),
+            None => create_or_reuse_blob(dir, &path, stat, &previous_blobs, options),
+        };
+        (path, blob)
+    };
+    let (walked, blobs) = map_while_producing(
+        |hash_file| -> Result<Vec<(PathBuf, Walked)>> {
+            let mut entries = Vec::new();
+            for entry in walk {
+                if visited.within_skipped(&entry) {
+                    continue;
+                }
+                let entry = match walked(entry, walk_root, warnings)? {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                let metadata = match walked(entry.metadata(), walk_root, warnings)? {
+                    Some(metadata) => metadata,
+                    None => continue,
+                };
+                if visited.skip(&entry, &metadata, options, warnings) {
+                    continue;
+                }
+                let path = stored_path(entry.path(), walk_root, options);
+                if !metadata.is_dir()
+                    && too_large(&dir.join(&path), metadata.len(), options, warnings)
+                {
+                    continue;
+                }
+                let walked = match metadata.is_dir() {
+                    true => Walked::Dir,
+                    false if overlay.contains_key(&path) => Walked::File(None),
+                    false => {
+                        let stat = walk_start.file_stat(&dir.join(&path), &metadata, warnings);
+                        match options.archives && archive::is_archive(&path) {
+                            true => Walked::Archive(stat),
+                            false => Walked::File(stat),
+                        }
+                    }
+                };
+                if let Walked::File(stat) = walked {
+                    hash_file((path.clone(), stat));
+                }
+                entries.push((path, walked));
+            }
+            Ok(entries)
+        },
+        hash,
+    );
+    let mut entries = walked?;
+    if let Some(unchanged) = unchanged {
+        // They go where the walk would have found them, which is the order of their paths
+        let skipped = std::mem::take(&mut *unchanged.skipped.lock().unwrap());
+        for path in skipped {
+            let tree = (*previous_trees[path.as_path()]).clone();
+            entries.push((path, Walked::Unchanged(tree)));
+        }
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+    }
+    let mut blobs: HashMap<PathBuf, Result<Blob>> = blobs.into_iter().collect();
+
+    let archives: Vec<(&Path, Option<FileStat>)> = entries
+        .iter()
+        .filter_map(|(path, walked)| match walked {
+            Walked::Archive(stat) => Some((path.as_path(), *stat)),
+            _ => None,
+        })
+        .collect();
+    let mut archive_trees = map_in_parallel(&archives, |&(path, stat)| {
+        let read = || fs::read(win_path::extended(&dir.join(path)));
+        create_or_reuse_archive_tree(dir, path, stat, &previous_trees, options, read)
+    })
+    .into_iter();
+    walk_start.check_dir(dir, warnings);
+    for (path, _) in entries
+        .iter()
+        .filter(|(_, walked)| matches!(walked, Walked::Dir))
+    {
+        walk_start.check_dir(&dir.join(path), warnings);
+    }
+
+    for (path, walked) in entries {
+        let path = path.as_path();
+
+        // Check whether current_dir is complete
+        while !path.starts_with(current_dir.as_path()) {
+            // We've moved up by (at least) one directory
+            // We need to pop the current directory off the stack
+            // and create a tree object for it
+            let partial_tree = tree_stack.pop().unwrap();
+            tree_stack
+                .last_mut()
+                .unwrap()
+                .children
+                .push(Object::Tree(partial_tree.finalize()));
+
+            // Update current_dir
+            current_dir = current_dir.parent().unwrap().to_path_buf();
+        }
+
+        let stat = match walked {
+            Walked::Dir => {
+                let partial_tree = PreTree {
+                    children: Vec::new(),
+                    path: path.to_path_buf(),
+                };
+                tree_stack.push(partial_tree);
+                current_dir = path.to_owned();
+                continue;
+            }
+            Walked::Unchanged(tree) => {
+                let parent = tree_stack.last_mut().unwrap();
+                parent.children.push(Object::Tree(tree));
+                continue;
+            }
+            Walked::Archive(_) => {
+                match archive_trees.next().expect("a tree for every archive") {
+                    Ok((tree, archive_warnings)) => {
+                        let parent = tree_stack.last_mut().unwrap();
+                        parent.children.push(Object::Tree(tree));
+                        warnings.extend(archive_warnings);
+                    }
+                    Err(err) => warnings.extend(skipped_file_warning(&dir.join(path), err)),
+                }
+                continue;
+            }
+            Walked::File(stat) => stat,
+        };
+        match blobs.remove(path).expect("a blob for every file") {
+            Ok(blob) => {
+                if stat.is_some() && blob.stat.is_none() {
+                    warnings.push(SyncWarning::ChangedDuringSync {
+                        path: dir.join(&blob.path).display().to_string(),
+                    });
+                }
+                tree_stack
+                    .last_mut()
+                    .unwrap()
+                    .children
+                    .push(Object::Blob(blob));
+            }
+            Err(err) => warnings.extend(skipped_file_warning(&dir.join(path), err)),
+        }
+    }
+
+    // Collapse the stack upward
+    while tree_stack.len() > 1 {
+        let partial_tree = tree_stack.pop().unwrap();
+        tree_stack
+            .last_mut()
+            .unwrap()
+            .children
+            .push(Object::Tree(partial_tree.finalize()));
+    }
+
+    assert!(
+        tree_stack.len() == 1,
+        "Tree stack should only have exactly one element"
+    );
+
+    // Convert to Tree
+    let mut root_tree = tree_stack.pop().unwrap().finalize();
+
+    // Go through and update the parent of each child
+    root_tree.set_childrens_parent();
+
+    Ok(root_tree)
+}
+
+/// Ignore files in a directory, in the order the ignore crate ranks them: a match in one decides
+/// before the next is looked at
+const IGNORE_FILE_NAMES: &[&str] = &[".continueignore", ".ignore", ".gitignore"];
+
+/// The rules `walk_builder` gives the ignore crate's walk, for walks through a `FileSystem`
+struct IgnoreRules {
+    overrides: Override,
+    global: Gitignore,
+
+    /// Whether .gitignore files count, which they only do in a git repository
+    git: bool,
+}
+
+impl IgnoreRules {
+    fn new(file_system: &dyn FileSystem, dir: &Path, options: &SyncOptions) -> Result<Self> {
+        let index_root = options.resolve_index_root()?;
+        let mut global = GitignoreBuilder::new(dir);
+        let global_ignore_file = index_root.global_ignore_file();
+        for line in global_ignore_contents(&index_root, options)?.lines() {
+            global.add_line(Some(global_ignore_file.to_path_buf()), line)?;
+        }
+        let mut overrides = OverrideBuilder::new(dir);
+        for pattern in &options.ignore_patterns {
+            overrides.add(&format!("!{pattern}"))?;
+        }
+        let git = dir
+            .ancestors()
+            .any(|dir| file_system.metadata(&dir.join(".git")).is_ok());
+        Ok(IgnoreRules {
+            overrides: overrides.build()?,
+            global: global.build()?,
+            git,
+        })
+    }
+
+    /// The ignore files in `dir`, most important first
+    fn read_dir_rules(&self, file_system: &dyn FileSystem, dir: &Path) -> Result<Vec<Gitignore>> {
+        let mut rules = Vec::new();
+        for &name in IGNORE_FILE_NAMES {
+            if name == ".gitignore" && !self.git {
+                continue;
+            }
+            let path = dir.join(name);
+            // One that can't be read counts as missing, as it does for the ignore crate
+            let contents = match file_system.read(&path) {
+                Ok(contents) => contents,
+                Err(_) => continue,
+            };
+            let mut builder = GitignoreBuilder::new(dir);
+            for line in String::from_utf8_lossy(&contents).lines() {
+                builder.add_line(Some(path.clone()), line)?;
+            }
+            rules.push(builder.build()?);
+        }
+        Ok(rules)
+    }
+
+    /// Whether the walk leaves `path` out, given the ignore files of each directory it's in
+    fn ignored(&self, path: &Path, is_dir: bool, dir_rules: &[Vec<Gitignore>]) -> bool {
+        let hidden = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        if hidden || self.overrides.matched(path, is_dir).is_ignore() {
+            return true;
+        }
+        // The closest directory's ignore files decide first
+        for gitignore in dir_rules.iter().rev().flatten() {
+            match gitignore.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        self.global.matched(path, is_dir).is_ignore()
+    }
+}
+
+/// A walk of a workspace through a `FileSystem`
+struct FileSystemWalk<'a> {
+    file_system: &'a dyn FileSystem,
+    dir: &'a Path,
+    options: &'a SyncOptions,
+    rules: IgnoreRules,
+    previous_blobs: HashMap<&'a Path, &'a Blob>,
+    previous_trees: HashMap<&'a Path, &'a Tree>,
+    overlay: Overlay,
+    warnings: &'a mut Vec<SyncWarning>,
+}
+
+impl FileSystemWalk<'_> {
+    /// The tree for the directory at `path`, `depth` below the workspace, given the ignore
+    /// files of the directories it's in
+    fn tree(
+        &mut self,
+        path: &Path,
+        depth: usize,
+        dir_rules: &mut Vec<Vec<Gitignore>>,
+    ) -> Result<Option<Tree>> {
+        let names = match self.file_system.read_dir(path) {
+            Ok(names) => names,
+            // Skipping the workspace itself would make every file look deleted
+            Err(err) if depth == 0 => return Err(err.into()),
+            Err(err) => {
+                self.warnings.push(SyncWarning::UnreadableDir {
+                    path: path.display().to_string(),
+                    message: err.to_string(),
+                });
+                return Ok(None);
+            }
+        };
+        let mut names: Vec<PathBuf> = names.into_iter().map(PathBuf::from).collect();
+        names.sort_by(|a, b| by_name(a, b));
+
+        dir_rules.push(self.rules.read_dir_rules(self.file_system, path)?);
+        let mut children = Vec::new();
+        for name in names {
+            let child = path.join(name);
+            let metadata = match self.file_system.metadata(&child) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    self.warnings
+                        .extend(skipped_file_warning(&child, err.into()));
+                    continue;
+                }
+            };
+            if self.rules.ignored(&child, metadata.is_dir, dir_rules) {
+                continue;
+            }
+            if !metadata.is_dir {
+                children.extend(self.file(&child, metadata));
+            } else if depth >= MAX_TREE_DEPTH {
+                // Only a symlink back up could go this deep
+                self.warnings.push(SyncWarning::SymlinkLoop {
+                    path: child.display().to_string(),
+                });
+            } else if let Some(tree) = self.tree(&child, depth + 1, dir_rules)? {
+                children.push(Object::Tree(tree));
+            }
+        }
+        dir_rules.pop();
+        let tree = PreTree {
+            children,
+            path: stored_path(path, self.dir, self.options),
+        };
+        Ok(Some(tree.finalize()))
+    }
+
+    /// The blob for the file at `filepath`, or the tree for it if it's an archive indexed as a
+    /// directory, or None if it's left out, with a warning saying why
+    fn file(&mut self, filepath: &Path, metadata: FileMetadata) -> Option<Object> {
+        if too_large(filepath, metadata.len, self.options, self.warnings) {
+            return None;
+        }
+        let path = stored_path(filepath, self.dir, self.options);
+        let object = match self.overlay.get(&path) {
+            Some(contents) => contents_blob(&path, contents, self.options).map(Object::Blob),
+            None if self.options.archives && archive::is_archive(&path) => self
+                .archive_tree(filepath, &path, metadata)
+                .map(Object::Tree),
+            None => self.read_blob(filepath, path, metadata).map(Object::Blob),
+        };
+        match object {
+            Ok(object) => Some(object),
+            Err(err) => {
+                self.warnings.extend(skipped_file_warning(filepath, err));
+                None
+            }
+        }
+    }
+
+    /// The tree for the archive at `filepath`, stored as `path`
+    fn archive_tree(
+        &mut self,
+        filepath: &Path,
+        path: &Path,
+        metadata: FileMetadata,
+    ) -> Result<Tree> {
+        let stat = FileStat::from_file_metadata(metadata);
+        let read = || self.file_system.read(filepath);
+        let (tree, warnings) = create_or_reuse_archive_tree(
+            self.dir,
+            path,
+            stat,
+            &self.previous_trees,
+            self.options,
+            read,
+        )?;
+        self.warnings.extend(warnings);
+        Ok(tree)
+    }
+
+    /// The blob for the file at `filepath`, stored as `path`, reused from before if its size
+    /// and mtime haven't changed
+    fn read_blob(&self, filepath: &Path, path: PathBuf, metadata: FileMetadata) -> Result<Blob> {
+        let options = self.options;
+        let stat = FileStat::from_file_metadata(metadata);
+        let file_ext = &file_ext(&path);
+        let binary = options.binary_by_extension(file_ext);
+        if binary == Some(true) {
+            return Err(binary_error().into());
+        }
+        if let Some(previous) = previous_blob(&path, stat, &self.previous_blobs) {
+            return Ok(previous);
+        }
+        let contents = self.file_system.read(filepath)?;
+        options.throttle_read(contents.len() as u64);
+        let rules = ContentRules {
+            normalization: options.content_normalization,
+            allow_binary: binary == Some(false),
+        };
+        Ok(Blob {
+            parent: None,
+            hash: blob_hash_bytes(&contents, file_ext, rules)?,
+            path,
+            stat,
+        })
+    }
+}
+
+/// Same as `update_tree` with every directory walked, walking and reading the workspace through
+/// `file_system` instead of the disk (see `SyncOptions::file_system`)
+fn update_tree_in(
+    file_system: &dyn FileSystem,
+    dir: &Path,
+    options: &SyncOptions,
+    previous: &Tree,
+    warnings: &mut Vec<SyncWarning>,
+) -> Result<Tree> {
+    if !file_system.metadata(dir)?.is_dir {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Directory {} does not exist", dir.display()),
+        )
+        .into());
+    }
+    let mut walk = FileSystemWalk {
+        file_system,
+        dir,
+        options,
+        rules: IgnoreRules::new(file_system, dir, options)?,
+        previous_blobs: previous.blobs_by_path(),
+        previous_trees: previous.trees_by_path(),
+        overlay: overlay_in(dir, options),
+        warnings,
+    };
+    let mut root = walk
+        .tree(dir, 0, &mut Vec::new())?
+        .expect("the workspace is listed or fails the walk");
+    root.set_childrens_parent();
+    Ok(root)
+}
+
+// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{temp_index_root, TempDirBuilder};
+    use std::fs::{self};
+
+    #[test]
+    fn test_compute_tree_for_temp_dir() {
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let temp_dir = TempDirBuilder::new()
+            .add("dir1/file1.txt", "Hello, world!")
+            .add("dir1/file2.txt", "Hello, world!")
+            .add("dir2/file3.txt", "Hello, world!")
+            .add("dir2/subdir/continue.py", "[continue for i in range(10)]")
+            .add("__init__.py", "a = 5")
+            .create();
+
+        let tree = compute_tree_for_dir(temp_dir.path(), &options).expect("Failed to compute tree");
+
+        // Check that every node but the root has a parent, matching that parent's hash
+        tree.walk(|obj| match obj {
+            ObjectRef::Tree(tree) => {
+                for child in &tree.children {
+                    match child {
+                        Object::Tree(child_tree) => {
+                            assert_eq!(child_tree.parent, Some(tree.hash));
+                        }
+                        Object::Blob(child_blob) => {
+                            assert_eq!(child_blob.parent, Some(tree.hash));
+                        }
+                    }
+                }
+            }
+            ObjectRef::Blob(_) => {}
+        });
+
+        // TODO: If a folder was removed, and another added, but they have the same hash, you should then assume it was renamed
+
+        // Make sure hash was calculated in same way as always: with the children of each
+        // directory in bytewise order of their names (see `by_name`), whatever order the walk
+        // found them in
+        assert_eq!(
+            hash_string(tree.hash),
+            "5e5caabe89427c855df360afc25ca5bc0cf3ff80"
+        );
+
+        let temp_dir2 = TempDirBuilder::new()
+            .add("dir1/file1.txt", "Hello, world!")
+            .add("dir1/file2.txt", "Hello, world!")
+            .add("dir2/file3.txt", "Hello, world!")
+            .add("dir2/subdir/continue.py", "[continue for i in range(11)]") // Difference here
+            .add("__init__.py", "a = 5")
+            .create();
+
+        let tree2 =
+            compute_tree_for_dir(temp_dir2.path(), &options).expect("Failed to compute tree");
+
+        // Check that certain nodes have different hashes: children are `__init__.py`, `dir1`
+        // and `dir2` in that order, and only `dir2` has changed
+        assert_ne!(tree.hash, tree2.hash);
+        assert_eq!(tree.children[0].hash(), tree2.children[0].hash());
+        assert_eq!(tree.children[1].hash(), tree2.children[1].hash());
+        assert_ne!(tree.children[2].hash(), tree2.children[2].hash());
+
+        // Make a small change and recompute the tree
+        let path = temp_dir.path().join("dir2/subdir/continue.py");
+        fs::write(path, "[continue for i in range(11)]").expect("Failed to write to file");
+        let tree_prime =
+            compute_tree_for_dir(temp_dir.path(), &options).expect("Failed to compute tree");
+
+        // All nodes up the tree from dir2/subdir/continue.py should be marked as changed
+        let (add, remove) = diff(&tree, &tree_prime);
+        assert_eq!(add.len(), 4);
+        assert_eq!(remove.len(), 4);
+
+        // Try adding a file at the root level
+        let path = temp_dir.path().join("new_file.txt");
+        fs::write(path, "42").expect("Failed to write to file");
+        let tree_prime_prime =
+            compute_tree_for_dir(temp_dir.path(), &options).expect("Failed to compute tree");
+
+        // Compare original and ''
+        let (add, remove) = diff(&tree, &tree_prime_prime);
+        assert_eq!(add.len(), 5);
+        assert_eq!(remove.len(), 4);
+
+        // Compare ' and ''
+        let (add, remove) = diff(&tree_prime, &tree_prime_prime);
+        assert_eq!(add.len(), 2);
+        assert_eq!(remove.len(), 1);
+
+        // The streaming diff sees the same changes, in the same order, without copying them
+        let mut streamed_add = Vec::new();
+        let mut streamed_remove = Vec::new();
+        diff_each(&tree, &tree_prime_prime, |change| match change {
+            Change::Add(obj) => streamed_add.push(obj.descr()),
+            Change::Remove(obj) => streamed_remove.push(obj.descr()),
+        });
+        assert_eq!(
+            (streamed_add, streamed_remove),
+            diff(&tree, &tree_prime_prime)
+        );
+        let mut count = 0;
+        diff_each(&tree, &tree, |_| count += 1);
+        assert_eq!(count, 0);
+
+        temp_dir.close().expect("Failed to clean up temp dir");
+        temp_dir2.close().expect("Failed to clean up temp dir");
+    }
+
+    #[test]
+    fn test_parallel_diff() {
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let mut builder = TempDirBuilder::new();
+        for dir in 0..8 {
+            for file in 0..5 {
+                builder.add(&format!("dir{dir}/sub/file{file}.txt"), "old");
+            }
+        }
+        builder.add("gone/file.txt", "gone");
+        let temp_dir = builder.create();
+        let old_tree = compute_tree_for_dir(temp_dir.path(), &options).unwrap();
+
+        for dir in 0..8 {
+            fs::write(
+                temp_dir.path().join(format!("dir{dir}/sub/file0.txt")),
+                "new",
+            )
+            .unwrap();
+        }
+        fs::write(temp_dir.path().join("dir3/added.txt"), "added").unwrap();
+        fs::remove_dir_all(temp_dir.path().join("gone")).unwrap();
+        let new_tree = compute_tree_for_dir(temp_dir.path(), &options).unwrap();
+
+        // Enough subtrees changed to diff them in parallel, which must give the same results in
+        // the same order as diffing them one at a time
+        let mut sequential = (Vec::new(), Vec::new());
+        diff_each(&old_tree, &new_tree, |change| match change {
+            Change::Add(obj) => sequential.0.push(obj.descr()),
+            Change::Remove(obj) => sequential.1.push(obj.descr()),
+        });
+        let (add, remove) = diff(&old_tree, &new_tree);
+        assert_eq!((add.clone(), remove.clone()), sequential);
+        assert_eq!(add.iter().filter(|item| item.is_blob).count(), 9);
+        assert!(remove
+            .iter()
+            .any(|item| item.path.ends_with("file.txt") && item.is_blob));
+    }
+
+    #[test]
+    fn test_diff_nested_removal() {
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let mut builder = TempDirBuilder::new();
+        builder.add("kept.txt", "kept");
+        let mut dir = PathBuf::from("gone");
+        for depth in 0..6 {
+            builder.add(&dir.join("a.txt").to_string_lossy(), &format!("a{depth}"));
+            builder.add(&dir.join("b.txt").to_string_lossy(), &format!("b{depth}"));
+            dir.push(format!("level{depth}"));
+        }
+        let temp_dir = builder.create();
+        let old_tree = compute_tree_for_dir(temp_dir.path(), &options).unwrap();
+        fs::remove_dir_all(temp_dir.path().join("gone")).unwrap();
+        let new_tree = compute_tree_for_dir(temp_dir.path(), &options).unwrap();
+
+        // Every object under the deleted directory is removed exactly once, parents first
+        let (add, remove) = diff(&old_tree, &new_tree);
+        assert_eq!(add.len(), 1);
+        let gone: Vec<ObjDescription> = old_tree.children[0]
+            .as_tree()
+            .unwrap()
+            .all_obj_descriptions();
+        assert_eq!(gone.len(), 6 * 3);
+        assert_eq!(remove[0], ObjectRef::Tree(&old_tree).descr());
+        assert_eq!(remove[1..], gone[..]);
+        let unique: HashSet<&ObjDescription> = remove.iter().collect();
+        assert_eq!(unique.len(), remove.len());
+
+        // The same when the removal is nested inside a directory that's still there
+        let temp_dir = TempDirBuilder::new()
+            .add("outer/kept.txt", "kept")
+            .add("outer/gone/a/b/c.txt", "c")
+            .add("outer/gone/a/d.txt", "d")
+            .create();
+        let old_tree = compute_tree_for_dir(temp_dir.path(), &options).unwrap();
+        fs::remove_dir_all(temp_dir.path().join("outer/gone")).unwrap();
+        let new_tree = compute_tree_for_dir(temp_dir.path(), &options).unwrap();
+        let (_, remove) = diff(&old_tree, &new_tree);
+        let removed_blobs: Vec<&Path> = remove
+            .iter()
+            .filter(|item| item.is_blob)
+            .map(|item| item.path.as_path())
+            .collect();
+        assert_eq!(
+            removed_blobs,
+            [
+                Path::new("outer/gone/a/b/c.txt"),
+                Path::new("outer/gone/a/d.txt")
+            ]
+        );
+        assert_eq!(remove.len(), 2 + 3 + 2);
+    }
+
+    #[test]
+    fn test_pair_children_with_colliding_keys() {
+        let blob = |path: &str, hash: u8| {
+            Object::Blob(Blob {
+                parent: None,
+                hash: [hash; 20],
+                path: PathBuf::from(path),
+                stat: None,
+            })
+        };
+        let tree = |children: Vec<Object>| Tree {
+            parent: None,
+            hash: tree_hash(children.iter().map(Object::hash)),
+            children,
+            path: PathBuf::new(),
+        };
+        let old_tree = tree(vec![blob("README.md", 1), blob("readme.md", 2)]);
+        let new_tree = tree(vec![blob("readme.md", 3)]);
+
+        // Folding case, both old files have the same key: one is matched and the other removed,
+        // rather than either being lost
+        let (add, remove) = diff_with(&old_tree, &new_tree, true);
+        assert_eq!(add.len(), 2);
+        let removed: Vec<ObjectHash> = remove
+            .iter()
+            .filter(|item| item.is_blob)
+            .map(|item| item.hash)
+            .collect();
+        assert_eq!(removed, [[1; 20], [2; 20]]);
+    }
+
+    #[test]
+    fn test_create_blobs_in_parallel() {
+        let mut builder = TempDirBuilder::new();
+        for i in 0..200 {
+            builder.add(&format!("file{i}.txt"), &"x".repeat(i));
+        }
+        let temp_dir = builder.create();
+
+        let dir = temp_dir.path();
+        let paths: Vec<_> = (0..200)
+            .map(|i| PathBuf::from(format!("file{i}.txt")))
+            .collect();
+        let paths: Vec<&Path> = paths.iter().map(|path| path.as_path()).collect();
+        let files: Vec<_> = paths.iter().map(|path| (*path, None)).collect();
+        let options = SyncOptions::default();
+        let blobs =
+            create_blobs_in_parallel(dir, &files, &HashMap::new(), &Overlay::new(), &options);
+        assert_eq!(blobs.len(), paths.len());
+        for (path, blob) in paths.iter().zip(blobs) {
+            let blob = blob.unwrap();
+            assert_eq!(blob.path, *path);
+            assert_eq!(
+                blob.hash,
+                create_blob(&dir.join(path), None, false, ContentRules::default())
+                    .unwrap()
+                    .hash
+            );
+        }
+        assert!(
+            create_blobs_in_parallel(dir, &[], &HashMap::new(), &Overlay::new(), &options)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_map_while_producing() {
+        // The workers start on the first items before the producer is done: it waits to hear
+        // that one has been handled before it hands over the rest
+        let (handled, wait_for_handled) = mpsc::channel();
+        let handled = Mutex::new(handled);
+        let (produced, results) = map_while_producing(
+            |send| {
+                send(0);
+                wait_for_handled
+                    .recv_timeout(std::time::Duration::from_secs(10))
+                    .expect("nothing handled while producing");
+                for i in 1..1000 {
+                    send(i);
                 }
+                "done"
+            },
+            |i: usize| {
+                let _ = handled.lock().unwrap().send(());
+                i * 2
+            },
+        );
+        assert_eq!(produced, "done");
+        assert_eq!(results, (0..1000).map(|i| i * 2).collect::<Vec<_>>());
+
+        let (produced, results) = map_while_producing(|_| 1, |i: usize| i);
+        assert_eq!((produced, results), (1, Vec::new()));
+    }
+
+    #[test]
+    fn test_reuse_unchanged_blobs() {
+        let (index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "AAAA")
+            .add("b.txt", "BBBB")
+            .create();
+        let path = temp_dir.path().join("a.txt");
+        let tree = compute_tree_for_dir(temp_dir.path(), &options).unwrap();
+
+        // Same size and mtime, so the new contents go unnoticed
+        let mtime = std::fs::metadata(&path).unwrap().modified().unwrap();
+        std::fs::write(&path, "CCCC\n").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        let reused =
+            compute_tree_with_warnings(temp_dir.path(), &options, &tree, &mut Vec::new()).unwrap();
+        assert_eq!(reused.hash(), tree.hash());
+
+        // The metadata survives being saved and loaded
+        let tree_path = index_dir.path().join("merkle_tree");
+        tree.persist(&tree_path).unwrap();
+        let loaded = Tree::load(&tree_path).unwrap();
+        let reused =
+            compute_tree_with_warnings(temp_dir.path(), &options, &loaded, &mut Vec::new())
+                .unwrap();
+        assert_eq!(reused.hash(), tree.hash());
+
+        let rehashed = compute_tree_for_dir(temp_dir.path(), &options).unwrap();
+        assert_ne!(rehashed.hash(), tree.hash());
+
+        // Any change to the metadata means the file is read again
+        std::fs::write(&path, "CCCCC\n").unwrap();
+        let changed =
+            compute_tree_with_warnings(temp_dir.path(), &options, &tree, &mut Vec::new()).unwrap();
+        assert_ne!(changed.hash(), tree.hash());
+    }
+
+    #[test]
+    fn test_update_tree() {
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let temp_dir = TempDirBuilder::new()
+            .add("a/x.txt", "X")
+            .add("a/deep/y.txt", "Y")
+            .add("b/z.txt", "Z")
+            .add("c.txt", "C")
+            .create();
+        let dir = temp_dir.path();
+        let tree = compute_tree_for_dir(dir, &options).unwrap();
+        let update = |changed: &[&str]| {
+            let changed: Vec<PathBuf> = changed.iter().map(PathBuf::from).collect();
+            update_tree(dir, &options, &tree, Some(&changed), &mut Vec::new()).unwrap()
+        };
+
+        std::fs::write(dir.join("a/x.txt"), "X changed").unwrap();
+        std::fs::create_dir(dir.join("b/new")).unwrap();
+        std::fs::write(dir.join("b/new/w.txt"), "W").unwrap();
+        std::fs::remove_file(dir.join("c.txt")).unwrap();
+        let updated = update(&["a/x.txt", "b/new", "c.txt"]);
+        let computed = compute_tree_for_dir(dir, &options).unwrap();
+        assert_eq!(updated.hash(), computed.hash());
+        assert_eq!(
+            updated.all_obj_descriptions(),
+            computed.all_obj_descriptions()
+        );
+
+        // Directories nothing was said about aren't looked at, though the ones above a change
+        // are listed again
+        let updated = update(&["a/x.txt"]);
+        assert!(!updated
+            .blobs_by_path()
+            .contains_key(Path::new("b/new/w.txt")));
+        assert!(!updated.blobs_by_path().contains_key(Path::new("c.txt")));
+        assert_eq!(
+            updated.blobs_by_path()[Path::new("a/deep/y.txt")].hash,
+            tree.blobs_by_path()[Path::new("a/deep/y.txt")].hash
+        );
+
+        // Unless one of the paths is above them
+        let updated = update(&["b"]);
+        assert!(updated
+            .blobs_by_path()
+            .contains_key(Path::new("b/new/w.txt")));
+        let updated = update(&[""]);
+        assert_eq!(updated.hash(), computed.hash());
+    }
+
+    #[test]
+    fn test_changed_during_sync() {
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let temp_dir = TempDirBuilder::new().add("a.txt", "A").create();
+        let dir = temp_dir.path();
+        let path = Path::new("a.txt");
+        let stat = FileStat::from_metadata(&fs::metadata(dir.join(path)).unwrap()).unwrap();
+        let previous = HashMap::new();
+
+        let blob = create_or_reuse_blob(dir, path, Some(stat), &previous, &options).unwrap();
+        assert_eq!(blob.stat, Some(stat));
+
+        // Walked at one size but hashed at another, so it isn't trusted next time
+        let walked_stat = FileStat {
+            size: stat.size + 1,
+            ..stat
+        };
+        let blob = create_or_reuse_blob(dir, path, Some(walked_stat), &previous, &options).unwrap();
+        assert_eq!(blob.stat, None);
+
+        // Deleted after the walk found it
+        let gone = dir.join("gone.txt");
+        let err = create_or_reuse_blob(dir, Path::new("gone.txt"), Some(stat), &previous, &options)
+            .unwrap_err();
+        assert_eq!(
+            skipped_file_warning(&gone, err),
+            Some(SyncWarning::ChangedDuringSync {
+                path: gone.display().to_string()
+            })
+        );
+
+        let mut warnings = Vec::new();
+        let not_found = ignore::Error::WithDepth {
+            depth: 1,
+            err: Box::new(ignore::Error::WithPath {
+                path: gone.clone(),
+                err: Box::new(io::Error::from(io::ErrorKind::NotFound).into()),
+            }),
+        };
+        assert!(walked::<()>(Err(not_found), dir, &mut warnings)
+            .unwrap()
+            .is_none());
+        assert_eq!(warnings.len(), 1);
+
+        // A directory that can't be read is skipped, unless it's the one being walked
+        let denied = |path: &Path| ignore::Error::WithPath {
+            path: path.to_path_buf(),
+            err: Box::new(io::Error::from(io::ErrorKind::PermissionDenied).into()),
+        };
+        let locked = dir.join("locked");
+        assert!(walked::<()>(Err(denied(&locked)), dir, &mut warnings)
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            &warnings[1],
+            SyncWarning::UnreadableDir { path, .. } if *path == locked.display().to_string()
+        ));
+        assert!(walked::<()>(Err(denied(dir)), dir, &mut warnings).is_err());
+        let other = ignore::Error::WithPath {
+            path: gone,
+            err: Box::new(io::Error::from(io::ErrorKind::InvalidInput).into()),
+        };
+        assert!(walked::<()>(Err(other), dir, &mut warnings).is_err());
+    }
+
+    #[test]
+    fn test_stored_path() {
+        let decomposed = Path::new("/dir/cafe\u{0301}.txt");
+        let walk_root = Path::new("/dir");
+        let options = SyncOptions::new();
+        assert_eq!(
+            stored_path(
+                decomposed,
+                walk_root,
+                &options.clone().normalize_unicode(true)
+            ),
+            Path::new("café.txt")
+        );
+        assert_eq!(stored_path(walk_root, walk_root, &options), Path::new(""));
+        assert_eq!(
+            stored_path(decomposed, walk_root, &options.normalize_unicode(false)),
+            Path::new("cafe\u{0301}.txt")
+        );
+    }
+
+    #[test]
+    fn test_child_order() {
+        let blob = |name: &str| {
+            Object::Blob(Blob {
+                parent: None,
+                hash: blob_hash_bytes(name.as_bytes(), "txt", ContentRules::default()).unwrap(),
+                path: PathBuf::from(name),
+                stat: None,
+            })
+        };
+        let names = ["b.txt", "é.txt", "B.txt", "a.txt", "a.txt.bak"];
+        let tree = |names: &[&str]| {
+            PreTree {
+                children: names.iter().map(|name| blob(name)).collect(),
+                path: PathBuf::new(),
             }
-            Object::Blob(_) => {}
-        });
+            .finalize()
+        };
 
-        // TODO: If a folder was removed, and another added, but they have the same hash, you should then assume it was renamed
+        // Bytewise, so upper case first and anything past ASCII last, whatever the input order
+        let sorted = tree(&names);
+        let order: Vec<&Path> = sorted.children().iter().map(Object::path).collect();
+        assert_eq!(
+            order,
+            ["B.txt", "a.txt", "a.txt.bak", "b.txt", "é.txt"].map(Path::new)
+        );
+        let mut reversed = names;
+        reversed.reverse();
+        assert_eq!(tree(&reversed).hash(), sorted.hash());
+    }
+
+    #[test]
+    fn test_relative_paths() {
+        let (index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let builder = || {
+            TempDirBuilder::new()
+                .add("dir/a.txt", "A")
+                .add("b.txt", "B")
+                .create()
+        };
+        let (dir, copy) = (builder(), builder());
+        let tree = compute_tree_for_dir(dir.path(), &options).unwrap();
+        let copied = compute_tree_for_dir(copy.path(), &options).unwrap();
+        assert_eq!(tree.all_obj_descriptions(), copied.all_obj_descriptions());
+
+        // Older versions saved the absolute paths
+        fn make_absolute(tree: &mut Tree, root: &Path) {
+            tree.path = root.join(&tree.path);
+            for child in &mut tree.children {
+                match child {
+                    Object::Tree(tree) => make_absolute(tree, root),
+                    Object::Blob(blob) => blob.path = root.join(&blob.path),
+                }
+            }
+        }
+        let mut old = tree.clone();
+        make_absolute(&mut old, dir.path());
+        let tree_path = index_dir.path().join("merkle_tree");
+        old.persist(&tree_path).unwrap();
+        let loaded = Tree::load(&tree_path).unwrap();
+        assert_eq!(loaded.all_obj_descriptions(), tree.all_obj_descriptions());
+
+        let mut changes = 0;
+        let mut count_change = |change| {
+            if !matches!(change, BlobChange::Unchanged(_)) {
+                changes += 1;
+            }
+            Ok(())
+        };
+        let warnings = &mut Vec::new();
+        stream_diff_dir(
+            dir.path(),
+            &tree_path,
+            false,
+            false,
+            &options,
+            warnings,
+            &mut count_change,
+        )
+        .unwrap();
+        assert_eq!(changes, 0);
+    }
 
-        // Make sure hash was calculated in same way as always
+    #[test]
+    fn test_fold_case() {
         assert_eq!(
-            hash_string(tree.hash),
-            "cb6bf3834fdc9c356a23fca2cb6f6d7a571474c4"
+            match_key(Path::new("/Dir/README.md"), true),
+            Path::new("/dir/readme.md")
+        );
+        assert_eq!(
+            match_key(Path::new("/Dir/README.md"), false),
+            Path::new("/Dir/README.md")
         );
 
-        let temp_dir2 = TempDirBuilder::new()
+        let temp_dir = TempDirBuilder::new().create();
+        let detected = detect_case_insensitive(temp_dir.path());
+        if cfg!(target_os = "linux") {
+            assert!(!detected);
+        }
+        let options = SyncOptions::new();
+        assert_eq!(fold_case(temp_dir.path(), &options), detected);
+        assert!(fold_case(temp_dir.path(), &options.case_insensitive(true)));
+    }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn test_unicode_forms_are_one_file() {
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let temp_dir = TempDirBuilder::new().create();
+        fs::write(temp_dir.path().join("cafe\u{0301}.txt"), "A").unwrap();
+        let tree = compute_tree_for_dir(temp_dir.path(), &options).unwrap();
+
+        // Written again by a tool that composes it, it's still the same file at the same path
+        fs::write(temp_dir.path().join("café.txt"), "A").unwrap();
+        let again = compute_tree_for_dir(temp_dir.path(), &options).unwrap();
+        let (add, remove) = diff(&tree, &again);
+        assert!(add.is_empty() && remove.is_empty());
+        assert!(tree
+            .blobs_by_path()
+            .contains_key(temp_dir.path().join("café.txt").as_path()));
+    }
+
+    #[test]
+    fn test_streaming_blob_hash() {
+        // Multi-byte characters end up split across buffer boundaries
+        let content = "aé€😀".repeat(HASH_BUFFER_SIZE / 3);
+        let mut hasher = Sha1::new();
+        hasher.update(format!("blob rs {content}"));
+        let expected: ObjectHash = hasher.finalize().into();
+        assert_eq!(
+            blob_hash(content.as_bytes(), "rs", ContentRules::default()).unwrap(),
+            expected
+        );
+
+        // Bytes that aren't UTF-8 are hashed as the Windows-1252 characters, even at a boundary
+        let hash = |content: &[u8]| blob_hash(content, "rs", ContentRules::default());
+        let mut latin1 = content.clone().into_bytes();
+        latin1.push(0xff);
+        assert_eq!(
+            hash(&latin1).unwrap(),
+            hash(format!("{content}\u{ff}").as_bytes()).unwrap()
+        );
+        // A character cut off at the end of the file
+        assert_eq!(
+            hash("€".as_bytes().get(..2).unwrap()).unwrap(),
+            hash("\u{e2}\u{201a}".as_bytes()).unwrap()
+        );
+
+        // Unless there are NUL bytes near the start as well
+        let mut binary = vec![0];
+        binary.extend(latin1);
+        assert_eq!(
+            hash(&binary).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_normalized_blob_hash() {
+        use ContentNormalization::{LineEndings, None as Exact, TrailingWhitespace};
+        let hash = |content: &str, normalization| {
+            let rules = ContentRules {
+                normalization,
+                ..Default::default()
+            };
+            blob_hash(content.as_bytes(), "rs", rules).unwrap()
+        };
+        let lf = "fn main() {\n    run();\n}\n";
+        let crlf = "fn main() {\r\n    run();\r\n}\r\n";
+        let trailing = "fn main() { \r\n    run();\t\n}\n  ";
+        assert_ne!(hash(lf, Exact), hash(crlf, Exact));
+        assert_eq!(hash(lf, LineEndings), hash(crlf, LineEndings));
+        assert_eq!(hash(lf, LineEndings), hash(lf, Exact));
+        assert_ne!(hash(lf, LineEndings), hash(trailing, LineEndings));
+        assert_eq!(
+            hash(lf, TrailingWhitespace),
+            hash(trailing, TrailingWhitespace)
+        );
+
+        // Anything that doesn't end a line is kept
+        assert_eq!(hash("a\rb", LineEndings), hash("a\rb", Exact));
+        assert_eq!(hash("a\r", LineEndings), hash("a\r", Exact));
+        assert_eq!(hash("a\r\r\nb", LineEndings), hash("a\r\nb", Exact));
+        assert_eq!(hash("a \tb\n", TrailingWhitespace), hash("a \tb\n", Exact));
+
+        // Whitespace and line endings split across reads
+        let content = "é \t\r\n😀\r\r\n€  x".repeat(10);
+        for normalization in [LineEndings, TrailingWhitespace] {
+            let rules = ContentRules {
+                normalization,
+                ..Default::default()
+            };
+            let buffer = &mut [0; 4];
+            let split = blob_hash_with_buffer(content.as_bytes(), "rs", rules, buffer).unwrap();
+            assert_eq!(split, hash(&content, normalization));
+            let in_memory = blob_hash_bytes(content.as_bytes(), "rs", rules).unwrap();
+            assert_eq!(split, in_memory);
+        }
+    }
+
+    #[test]
+    fn test_binary_files() {
+        let temp_dir = TempDirBuilder::new()
+            .add("image.PNG", "not really a PNG")
+            .add("notes.txt", "text")
+            .add("main.rs", "fn main() {}")
+            .create();
+        fs::write(temp_dir.path().join("data.xyz"), b"\x01\x00\x02").unwrap();
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let indexed = |options: &SyncOptions| {
+            let mut warnings = Vec::new();
+            let tree = compute_tree_with_warnings(
+                temp_dir.path(),
+                options,
+                &Tree::default(),
+                &mut warnings,
+            )
+            .unwrap();
+            let mut paths: Vec<_> = tree.blobs_by_path().into_keys().collect();
+            paths.sort();
+            let mut skipped: Vec<_> = warnings
+                .iter()
+                .map(|warning| match warning {
+                    SyncWarning::BinaryFile { path } => {
+                        path.rsplit('/').next().unwrap().to_string()
+                    }
+                    warning => panic!("unexpected warning {:?}", warning),
+                })
+                .collect();
+            skipped.sort();
+            (paths.len(), skipped)
+        };
+
+        // By extension (in any case) or by content
+        let skipped = vec!["data.xyz".to_string(), "image.PNG".to_string()];
+        assert_eq!(indexed(&options), (2, skipped));
+        let options = options
+            .binary_extension("TXT")
+            .text_extension("xyz")
+            .text_extension("png");
+        assert_eq!(indexed(&options), (3, vec!["notes.txt".to_string()]));
+    }
+
+    #[test]
+    fn test_xattr_cache() {
+        let temp_dir = TempDirBuilder::new().add("a.rs", "A").create();
+        let path = temp_dir.path().join("a.rs");
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root).xattr_cache(true);
+        let real_hash = compute_tree_for_dir(temp_dir.path(), &options)
+            .unwrap()
+            .hash;
+        if xattr::get(&path, HASH_XATTR, 100).unwrap().is_none() {
+            // This filesystem doesn't have extended attributes
+            return;
+        }
+
+        // Swap the saved hash for another one, which is trusted as long as the file is unchanged
+        let stat = FileStat::from_metadata(&fs::metadata(&path).unwrap()).unwrap();
+        cache_hash(&path, stat, ContentNormalization::None, "rs", [7; 20]);
+        let tree = compute_tree_for_dir(temp_dir.path(), &options).unwrap();
+        assert_eq!(tree.blobs_by_path()[Path::new("a.rs")].hash, [7; 20]);
+        let without = SyncOptions::new().index_root(options.resolve_index_root().unwrap());
+        assert_eq!(
+            compute_tree_for_dir(temp_dir.path(), &without)
+                .unwrap()
+                .hash,
+            real_hash
+        );
+
+        // Saved for another extension, or for an older version of the file
+        cache_hash(&path, stat, ContentNormalization::None, "txt", [7; 20]);
+        assert_eq!(
+            compute_tree_for_dir(temp_dir.path(), &options)
+                .unwrap()
+                .hash,
+            real_hash
+        );
+        cache_hash(&path, stat, ContentNormalization::None, "rs", [7; 20]);
+        fs::write(&path, "changed").unwrap();
+        let tree = compute_tree_for_dir(temp_dir.path(), &options).unwrap();
+        assert_ne!(tree.blobs_by_path()[Path::new("a.rs")].hash, [7; 20]);
+    }
+
+    #[test]
+    fn test_mmap_blob_hash() {
+        let content = "aé€😀".repeat(HASH_BUFFER_SIZE);
+        let mut latin1 = content.clone().into_bytes();
+        latin1.push(0xff);
+        let mut invalid = vec![0];
+        invalid.extend(&latin1);
+        let temp_dir = TempDirBuilder::new()
+            .add("large.rs", &content)
+            .add("small.rs", "small")
+            .create();
+        fs::write(temp_dir.path().join("latin1.rs"), latin1).unwrap();
+        fs::write(temp_dir.path().join("invalid.rs"), invalid).unwrap();
+
+        for name in ["large.rs", "small.rs", "latin1.rs"] {
+            let path = temp_dir.path().join(name);
+            let mapped = create_blob(&path, None, true, ContentRules::default()).unwrap();
+            assert_eq!(
+                mapped.hash,
+                create_blob(&path, None, false, ContentRules::default())
+                    .unwrap()
+                    .hash
+            );
+        }
+        let result = create_blob(
+            &temp_dir.path().join("invalid.rs"),
+            None,
+            true,
+            ContentRules::default(),
+        );
+        assert!(
+            matches!(result, Err(SyncError::Io(err)) if err.kind() == io::ErrorKind::InvalidData)
+        );
+
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let tree = compute_tree_for_dir(temp_dir.path(), &options.clone().mmap(true)).unwrap();
+        assert_eq!(
+            tree.hash,
+            compute_tree_for_dir(temp_dir.path(), &options)
+                .unwrap()
+                .hash
+        );
+    }
+
+    #[test]
+    fn test_tree_accessors() {
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let temp_dir = TempDirBuilder::new()
             .add("dir1/file1.txt", "Hello, world!")
-            .add("dir1/file2.txt", "Hello, world!")
-            .add("dir2/file3.txt", "Hello, world!")
-            .add("dir2/subdir/continue.py", "[continue for i in range(11)]") // Difference here
             .add("__init__.py", "a = 5")
             .create();
 
-        let tree2 = compute_tree_for_dir(temp_dir2.path(), None).expect("Failed to compute tree");
+        let tree = compute_tree_for_dir(temp_dir.path(), &options).expect("Failed to compute tree");
+        assert_eq!(tree.parent(), None);
+        assert_eq!(tree.children().len(), 2);
 
-        // Make sure hash was calculated in same way as always
+        // Paths are relative to the directory the tree is for
+        assert_eq!(tree.path(), Path::new(""));
+        let init = tree.child("__init__.py").unwrap();
+        assert!(init.is_blob());
+        assert_eq!(init.parent(), Some(tree.hash()));
+        assert_eq!(init.as_blob().unwrap().path(), Path::new("__init__.py"));
 
-        // Check that certain nodes have different hashes
-        assert_ne!(tree.hash, tree2.hash);
-        assert_ne!(tree.children[0].hash(), tree2.children[0].hash());
-        assert_eq!(tree.children[1].hash(), tree2.children[1].hash());
-        assert_eq!(tree.children[2].hash(), tree2.children[2].hash());
+        let dir1 = tree.children()[1].as_tree().unwrap();
+        assert_eq!(dir1.path(), Path::new("dir1"));
+        assert_eq!(dir1.children()[0].path(), Path::new("dir1/file1.txt"));
+        assert_eq!(dir1.parent(), Some(tree.hash()));
+        assert_eq!(
+            dir1.hash(),
+            tree_hash(dir1.children().iter().map(Object::hash))
+        );
 
-        // Make a small change and recompute the tree
-        let path = temp_dir.path().join("dir2/subdir/continue.py");
-        fs::write(path, "[continue for i in range(11)]").expect("Failed to write to file");
-        let tree_prime =
-            compute_tree_for_dir(temp_dir.path(), None).expect("Failed to compute tree");
+        // Parents come before their children, in the same order as the children
+        let order: Vec<ObjectHash> = tree.objects().map(ObjectRef::hash).collect();
+        assert_eq!(
+            order,
+            [
+                tree.hash(),
+                init.hash(),
+                dir1.hash(),
+                dir1.children()[0].hash()
+            ]
+        );
+        let mut blobs = 0;
+        tree.walk(|obj| blobs += obj.is_blob() as usize);
+        assert_eq!(blobs, 2);
+        let copied = tree.objects().nth(2).unwrap().to_object();
+        assert_eq!(copied.as_tree().unwrap().children().len(), 1);
+    }
 
-        // All nodes up the tree from dir2/subdir/continue.py should be marked as changed
-        let (add, remove) = diff(&tree, &tree_prime);
-        assert_eq!(add.len(), 4);
-        assert_eq!(remove.len(), 4);
+    #[test]
+    fn test_obj_description_json() {
+        let descr = ObjDescription {
+            hash: [0xab; 20],
+            path: PathBuf::from("dir/file.txt"),
+            is_blob: true,
+        };
 
-        // Try adding a file at the root level
-        let path = temp_dir.path().join("new_file.txt");
-        fs::write(path, "42").expect("Failed to write to file");
-        let tree_prime_prime =
-            compute_tree_for_dir(temp_dir.path(), None).expect("Failed to compute tree");
+        let json = serde_json::to_string(&descr).unwrap();
+        assert_eq!(
+            json,
+            r#"{"hash":"abababababababababababababababababababab","path":"dir/file.txt","isBlob":true}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<ObjDescription>(&json).unwrap(),
+            descr
+        );
 
-        // Compare original and ''
-        let (add, remove) = diff(&tree, &tree_prime_prime);
-        assert_eq!(add.len(), 5);
-        assert_eq!(remove.len(), 4);
+        // A path that isn't UTF-8 is kept as its bytes
+        #[cfg(unix)]
+        {
+            use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+            let descr = ObjDescription {
+                path: Path::new(OsStr::from_bytes(b"dir/\xff.txt")).to_path_buf(),
+                ..descr
+            };
+            let json = serde_json::to_string(&descr).unwrap();
+            assert!(json.contains(r#""path":[100,105,114,47,255,46,116,120,116]"#));
+            assert_eq!(
+                serde_json::from_str::<ObjDescription>(&json).unwrap(),
+                descr
+            );
+        }
 
-        // Compare ' and ''
-        let (add, remove) = diff(&tree_prime, &tree_prime_prime);
-        assert_eq!(add.len(), 2);
-        assert_eq!(remove.len(), 1);
+        assert_eq!(parse_hash(&hash_string([7; 20])), Some([7; 20]));
+        assert_eq!(parse_hash("abc"), None);
+        assert_eq!(parse_hash(&"zz".repeat(20)), None);
+    }
 
-        temp_dir.close().expect("Failed to clean up temp dir");
-        temp_dir2.close().expect("Failed to clean up temp dir");
+    #[test]
+    fn test_load_corrupted_tree() {
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let temp_dir = TempDirBuilder::new()
+            .add("truncated", r#"{"parent":null,"children":[[1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1]],"hash":[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],"path":"."}"#)
+            .add("garbage", "not json")
+            .create();
+
+        for name in ["truncated", "garbage"] {
+            let result = Tree::load(&temp_dir.path().join(name));
+            assert!(matches!(result, Err(SyncError::CorruptedIndex(_))));
+        }
+
+        // Only the first line is needed for the root hash
+        let hash = Tree::load_root_hash(&temp_dir.path().join("truncated")).unwrap();
+        assert_eq!(hash, [0; 20]);
+        let result = Tree::load_root_hash(&temp_dir.path().join("garbage"));
+        assert!(matches!(result, Err(SyncError::CorruptedIndex(_))));
+
+        let result = Tree::load(&temp_dir.path().join("missing"));
+        assert!(matches!(result, Err(SyncError::Io(err)) if err.kind() == io::ErrorKind::NotFound));
+
+        let result = compute_tree_for_dir(&temp_dir.path().join("missing"), &options);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_persist_only_changed_subtrees() {
+        let (index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let temp_dir = TempDirBuilder::new()
+            .add("dir1/file1.txt", "1")
+            .add("dir2/file2.txt", "2")
+            .add("dir2/subdir/file3.txt", "3")
+            .create();
+        let tree_path = index_dir.path().join("merkle_tree");
+        let record_names = || -> HashSet<String> {
+            fs::read_dir(subtrees_dir(&tree_path))
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect()
+        };
+
+        let tree = compute_tree_for_dir(temp_dir.path(), &options).unwrap();
+        tree.persist(&tree_path).unwrap();
+        let before = record_names();
+        assert_eq!(before.len(), 4);
+        assert_eq!(Tree::load(&tree_path).unwrap().hash(), tree.hash());
+        assert_eq!(Tree::load_root_hash(&tree_path).unwrap(), tree.hash());
+
+        // Only the changed directory and those above it are written again
+        fs::write(temp_dir.path().join("dir2/file2.txt"), "changed").unwrap();
+        let tree = compute_tree_for_dir(temp_dir.path(), &options).unwrap();
+        tree.persist(&tree_path).unwrap();
+        let after = record_names();
+        assert_eq!(after.len(), 4);
+        assert_eq!(before.intersection(&after).count(), 2);
+        let loaded = Tree::load(&tree_path).unwrap();
+        assert_eq!(loaded.hash(), tree.hash());
+        assert_eq!(loaded.all_obj_descriptions(), tree.all_obj_descriptions());
+
+        // A manifest from before the version is the first version, and a newer one can't be read
+        let manifest = fs::read_to_string(&tree_path).unwrap();
+        fs::write(&tree_path, manifest.replace(r#""version":1,"#, "")).unwrap();
+        assert_eq!(Tree::load(&tree_path).unwrap().hash(), tree.hash());
+        fs::write(
+            &tree_path,
+            manifest.replace(r#""version":1"#, r#""version":2"#),
+        )
+        .unwrap();
+        let result = Tree::load(&tree_path);
+        assert!(matches!(result, Err(SyncError::CorruptedIndex(_))));
+        let result = Tree::load_root_hash(&tree_path);
+        assert!(matches!(result, Err(SyncError::CorruptedIndex(_))));
+        fs::write(&tree_path, manifest).unwrap();
+
+        // A record that doesn't match its name can't be trusted
+        let name = after.iter().next().unwrap();
+        fs::write(subtrees_dir(&tree_path).join(name), "{}").unwrap();
+        let result = Tree::load(&tree_path);
+        assert!(matches!(result, Err(SyncError::CorruptedIndex(_))));
+    }
+
+    #[test]
+    fn test_load_escaping_tree() {
+        let (index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let temp_dir = TempDirBuilder::new().add("a.txt", "A").create();
+        let tree_path = index_dir.path().join("merkle_tree");
+        let subtrees_dir = subtrees_dir(&tree_path);
+        fs::create_dir_all(&subtrees_dir).unwrap();
+        let blob = |path: &str| {
+            ChildRecord::Blob(SerializeableNode {
+                parent: None,
+                children: None,
+                hash: [1; 20],
+                path: PathBuf::from(path),
+                size: None,
+                mtime: None,
+            })
+        };
+        let write_record = |path: &str, children: Vec<ChildRecord>| {
+            let record = SubtreeRecord {
+                hash: [2; 20],
+                path: PathBuf::from(path),
+                children,
+            };
+            record
+                .write(
+                    &OsFileSystem,
+                    &subtrees_dir,
+                    TreeFormat::Binary,
+                    Compression::None,
+                    false,
+                    true,
+                )
+                .unwrap()
+        };
+        let save_root = |children: Vec<ChildRecord>| {
+            let manifest = TreeManifest {
+                version: MANIFEST_VERSION,
+                hash: [2; 20],
+                root: write_record("", children),
+            };
+            fs::write(&tree_path, serde_json::to_string(&manifest).unwrap()).unwrap();
+        };
+
+        save_root(vec![blob("a.txt"), blob("dir")]);
+        assert_eq!(Tree::load(&tree_path).unwrap().children().len(), 2);
+
+        // Paths that lead out of the root, or aren't directly inside their directory
+        let subdir = write_record("../dir", vec![blob("../dir/b.txt")]);
+        let nested = write_record("dir/sub", vec![blob("dir/sub/c.txt")]);
+        let cases = vec![
+            vec![blob("../a.txt")],
+            vec![blob("/etc/passwd")],
+            vec![blob("dir/a.txt")],
+            vec![blob("")],
+            vec![ChildRecord::Tree(subdir)],
+            vec![ChildRecord::Tree(nested)],
+            vec![blob("a.txt"), blob("a.txt")],
+        ];
+        for children in cases {
+            save_root(children);
+            let result = Tree::load(&tree_path);
+            assert!(matches!(result, Err(SyncError::CorruptedIndex(_))));
+
+            // The same goes for streaming, which reads the records one at a time
+            let result = stream_diff_dir(
+                temp_dir.path(),
+                &tree_path,
+                false,
+                false,
+                &options,
+                &mut Vec::new(),
+                &mut |_| Ok(()),
+            );
+            assert!(matches!(result, Err(SyncError::CorruptedIndex(_))));
+        }
+
+        // And for trees saved as one JSONL file by older versions
+        let root = r#"{"parent":null,"children":[[1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1]],"hash":[2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2],"path":"/root"}"#;
+        let child = r#"{"parent":null,"children":null,"hash":[1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1],"path":"/root/a.txt"}"#;
+        fs::write(&tree_path, format!("{root}\n{child}\n")).unwrap();
+        assert_eq!(
+            Tree::load(&tree_path).unwrap().children()[0].path(),
+            Path::new("a.txt")
+        );
+        let escaping = child.replace("/root/a.txt", "/root/../a.txt");
+        fs::write(&tree_path, format!("{root}\n{escaping}\n")).unwrap();
+        let result = Tree::load(&tree_path);
+        assert!(matches!(result, Err(SyncError::CorruptedIndex(_))));
+
+        // Nesting deep enough to run out of stack is corrupted too
+        let mut name = write_record(&"d/".repeat(MAX_TREE_DEPTH + 1), Vec::new());
+        for depth in (0..=MAX_TREE_DEPTH).rev() {
+            name = write_record(&"d/".repeat(depth), vec![ChildRecord::Tree(name)]);
+        }
+        let manifest = TreeManifest {
+            version: MANIFEST_VERSION,
+            hash: [2; 20],
+            root: name,
+        };
+        fs::write(&tree_path, serde_json::to_string(&manifest).unwrap()).unwrap();
+        let result = Tree::load(&tree_path);
+        assert!(matches!(result, Err(SyncError::CorruptedIndex(_))));
+    }
+
+    #[test]
+    fn test_tree_formats() {
+        let (index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let temp_dir = TempDirBuilder::new()
+            .add("dir1/file1.txt", "1")
+            .add("file2.txt", "2")
+            .create();
+        let tree = compute_tree_for_dir(temp_dir.path(), &options).unwrap();
+        let tree_path = index_dir.path().join("merkle_tree");
+        let records = || -> Vec<Vec<u8>> {
+            fs::read_dir(subtrees_dir(&tree_path))
+                .unwrap()
+                .map(|entry| fs::read(entry.unwrap().path()).unwrap())
+                .collect()
+        };
+
+        for format in [TreeFormat::Json, TreeFormat::Binary] {
+            tree.persist_as(&tree_path, format, Compression::None)
+                .unwrap();
+            let records = records();
+            assert_eq!(records.len(), 2);
+            for record in &records {
+                assert_eq!(
+                    record.starts_with(RECORD_MAGIC),
+                    format == TreeFormat::Binary
+                );
+            }
+
+            let loaded = Tree::load(&tree_path).unwrap();
+            assert_eq!(loaded.all_obj_descriptions(), tree.all_obj_descriptions());
+            let blobs = loaded.blobs_by_path();
+            for (path, blob) in tree.blobs_by_path() {
+                assert_eq!(blobs[path].stat, blob.stat);
+                assert_eq!(blobs[path].parent, blob.parent);
+            }
+        }
+
+        // Truncated, or from a newer version
+        let record = records().pop().unwrap();
+        let result = SubtreeRecord::decode(&record[..record.len() - 1]);
+        assert!(result.is_err());
+        let mut newer = record;
+        newer[RECORD_MAGIC.len()] = RECORD_VERSION + 1;
+        let result = SubtreeRecord::decode(&newer);
+        assert_eq!(result.err().unwrap(), "unsupported version 2");
+    }
+
+    #[test]
+    fn test_compressed_records() {
+        let (index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let mut builder = TempDirBuilder::new();
+        for i in 0..50 {
+            builder.add(&format!("some/long/directory/name/file{i}.txt"), "x");
+        }
+        let temp_dir = builder.create();
+        let tree = compute_tree_for_dir(temp_dir.path(), &options).unwrap();
+        let tree_path = index_dir.path().join("merkle_tree");
+        let records_size = || -> u64 {
+            fs::read_dir(subtrees_dir(&tree_path))
+                .unwrap()
+                .map(|entry| entry.unwrap().metadata().unwrap().len())
+                .sum()
+        };
+
+        for format in [TreeFormat::Json, TreeFormat::Binary] {
+            tree.persist_as(&tree_path, format, Compression::None)
+                .unwrap();
+            let uncompressed = records_size();
+            tree.persist_as(&tree_path, format, Compression::Gzip)
+                .unwrap();
+            assert!(records_size() * 2 < uncompressed);
+
+            let loaded = Tree::load(&tree_path).unwrap();
+            assert_eq!(loaded.all_obj_descriptions(), tree.all_obj_descriptions());
+        }
+    }
+
+    #[test]
+    fn test_load_jsonl_tree() {
+        let temp_dir = TempDirBuilder::new()
+            .add(
+                "merkle_tree",
+                &[
+                    r#"{"parent":null,"children":[[1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1]],"hash":[2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2],"path":"."}"#,
+                    r#"{"parent":[2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2],"children":null,"hash":[1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1],"path":"./a.txt"}"#,
+                ]
+                .join("\n"),
+            )
+            .create();
+
+        // Written by older versions, before trees were split into records, and with the root's
+        // path in front of every other one
+        let tree = Tree::load(&temp_dir.path().join("merkle_tree")).unwrap();
+        assert_eq!(tree.hash(), [2; 20]);
+        assert_eq!(tree.path(), Path::new(""));
+        assert_eq!(tree.children()[0].path(), "a.txt");
+        assert_eq!(tree.children()[0].parent(), Some([2; 20]));
     }
 }
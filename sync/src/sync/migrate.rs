@@ -0,0 +1,456 @@
+//! Upgrading an index written by an older version in place the first time it's used, so that a
+//! change to how its files are laid out or written never means deleting it
+//!
+//! `<root>/.version` holds how many of `MIGRATIONS` have been applied. Each one brings the index
+//! up one version and is recorded as soon as it's done, so an interrupted one is simply run
+//! again. Only one process migrates at a time, holding `<root>/.migrate.lock`, and an index from
+//! a newer version than this one is refused rather than misread. Everything goes through
+//! `SyncOptions::index_file_system`, and an index that isn't there yet is left alone until a
+//! sync creates it (see `create_index`)
+
+use super::{
+    error::{Result, SyncError},
+    file_system::FileSystem,
+    index_root::{remove_empty_parents, remove_seps_from_path, IndexRoot},
+    lock,
+    merkle::Tree,
+    options::{LockWait, SyncOptions},
+    read_pending, write_pending, DiskSet, OwnedTag, Tag,
+};
+use std::{
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+type Migration = fn(&SyncOptions, &IndexRoot) -> Result<()>;
+
+/// In order, so an index at version N has had the first N applied
+const MIGRATIONS: [Migration; 3] = [
+    // 1: tag directories named by a hash of the workspace path
+    migrate_tag_dirs,
+    // 2: caches with a header and checksum
+    migrate_caches,
+    // 3: trees split into records, and trees and `.pending` files with paths relative to the
+    // workspace
+    migrate_trees,
+];
+
+const INDEX_VERSION: u32 = MIGRATIONS.len() as u32;
+
+/// Apply every migration the index at `index_root` hasn't had yet. Nothing is written to an
+/// index that isn't there, so a call that only reads doesn't create one
+pub(crate) fn migrate(options: &SyncOptions, index_root: &IndexRoot) -> Result<()> {
+    let file_system = &*options.resolve_index_file_system();
+    if !file_system
+        .metadata(index_root.path())
+        .is_ok_and(|metadata| metadata.is_dir)
+    {
+        return Ok(());
+    }
+    if read_version(file_system, index_root)? == INDEX_VERSION {
+        return Ok(());
+    }
+
+    let lock_path = index_root.path().join(".migrate.lock");
+    let _lock = lock::lock_index(options, &lock_path, LockWait::Block)?;
+    // Another process may have migrated it while this one waited
+    let mut version = read_version(file_system, index_root)?;
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(options, index_root)?;
+        version += 1;
+        write_version(file_system, index_root, version)?;
+    }
+    Ok(())
+}
+
+/// Create the index at `index_root` for a sync to write to, if it isn't there yet. Nothing has
+/// been written to it, so everything will be in the current format
+pub(crate) fn create_index(file_system: &dyn FileSystem, index_root: &IndexRoot) -> io::Result<()> {
+    match file_system.metadata(index_root.path()) {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            file_system.create_dir_all(index_root.path())?;
+            write_version(file_system, index_root, INDEX_VERSION)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn write_version(
+    file_system: &dyn FileSystem,
+    index_root: &IndexRoot,
+    version: u32,
+) -> io::Result<()> {
+    file_system.write(
+        &version_path(index_root),
+        version.to_string().as_bytes(),
+        false,
+    )
+}
+
+fn version_path(index_root: &IndexRoot) -> PathBuf {
+    index_root.path().join(".version")
+}
+
+fn read_version(file_system: &dyn FileSystem, index_root: &IndexRoot) -> Result<u32> {
+    let path = version_path(index_root);
+    let version = match file_system.read(&path) {
+        Ok(contents) => String::from_utf8_lossy(&contents)
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| {
+                SyncError::CorruptedIndex(format!("{} isn't a version number", path.display()))
+            })?,
+        // Before the version file, only the move of the tag directories recorded that it was done
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            match file_system.read(&index_root.path().join("tags").join(".layout")) {
+                Ok(layout) if layout == b"2" => 1,
+                _ => 0,
+            }
+        }
+        Err(err) => return Err(err.into()),
+    };
+    match version <= INDEX_VERSION {
+        true => Ok(version),
+        false => Err(SyncError::CorruptedIndex(format!(
+            "{} was written by a newer version (format {version}, this one reads up to \
+             {INDEX_VERSION})",
+            index_root.path().display()
+        ))),
+    }
+}
+
+/// Move the tag's directory from where an older version put it, named by
+/// `remove_seps_from_path`, to its `IndexRoot::tag_dir`, unless a sync has saved a tree there
+/// already (what a dry run leaves there is replaced). This catches the tags
+/// `IndexRoot::migrate_tag_dirs` can't find, which are those from before there were `.tag`
+/// files, so it's left to a sync of the tag rather than done for the whole index. A directory
+/// whose `.tag` file names another tag, one of the workspaces the old names couldn't tell apart,
+/// is left for that tag. Failing just means syncing the tag again from scratch, so it isn't an
+/// error
+pub(crate) fn migrate_old_tag_dir(file_system: &dyn FileSystem, index_root: &IndexRoot, tag: &Tag) {
+    let new_dir = index_root.tag_dir(tag);
+    if file_system.metadata(&new_dir.join("merkle_tree")).is_ok() {
+        return;
+    }
+    let tags_dir = index_root.path().join("tags");
+    let mut old_dir = tags_dir.join(remove_seps_from_path(tag.dir));
+    old_dir.push(tag.branch);
+    old_dir.push(tag.provider_id);
+    if !file_system
+        .metadata(&old_dir)
+        .is_ok_and(|metadata| metadata.is_dir)
+    {
+        return;
+    }
+    if let Ok(stored) = file_system.read(&old_dir.join(".tag")) {
+        match String::from_utf8_lossy(&stored).parse::<OwnedTag>() {
+            Ok(stored) if stored.as_tag() == *tag => {}
+            _ => return,
+        }
+    }
+    let moved = remove_dir_all(file_system, &new_dir)
+        .and_then(|()| file_system.create_dir_all(new_dir.parent().unwrap_or(&tags_dir)))
+        .and_then(|()| file_system.rename(&old_dir, &new_dir));
+    if moved.is_ok() {
+        remove_empty_parents(file_system, &old_dir, &tags_dir);
+    }
+}
+
+/// Remove the directory at `dir` and everything in it, if it's there
+fn remove_dir_all(file_system: &dyn FileSystem, dir: &Path) -> io::Result<()> {
+    let names = match file_system.read_dir(dir) {
+        Ok(names) => names,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    for name in names {
+        let path = dir.join(name);
+        match file_system.metadata(&path)?.is_dir {
+            true => remove_dir_all(file_system, &path)?,
+            false => file_system.remove_file(&path)?,
+        }
+    }
+    file_system.remove_dir(dir)
+}
+
+fn migrate_tag_dirs(options: &SyncOptions, index_root: &IndexRoot) -> Result<()> {
+    index_root.migrate_tag_dirs(&*options.resolve_index_file_system())
+}
+
+/// Open every cache file, which rewrites one without a header with it. A cache that's corrupted
+/// is left to be rebuilt the next time it's used
+fn migrate_caches(options: &SyncOptions, index_root: &IndexRoot) -> Result<()> {
+    let file_system = options.resolve_index_file_system();
+    let mut paths: Vec<PathBuf> = index_root
+        .tag_dirs_in(&*file_system)?
+        .into_iter()
+        .map(|(tag_dir, _)| tag_dir.join(".index_cache"))
+        .collect();
+    let providers_dir = index_root.path().join("providers");
+    match file_system.read_dir(&providers_dir) {
+        Ok(names) => {
+            for name in names {
+                paths.push(providers_dir.join(name).join(".index_cache"));
+            }
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    for path in paths {
+        if !file_system
+            .metadata(&path)
+            .is_ok_and(|metadata| !metadata.is_dir)
+        {
+            continue;
+        }
+        match DiskSet::new(file_system.clone(), &path) {
+            Ok(_) | Err(SyncError::CorruptedIndex(_)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Load and save every tree again, which splits a single JSONL file into records and takes
+/// the workspace path off the front of absolute paths. `.pending` files get the same treatment.
+/// A tree that's corrupted is left to be rebuilt by the next sync
+fn migrate_trees(options: &SyncOptions, index_root: &IndexRoot) -> Result<()> {
+    let file_system = &*options.resolve_index_file_system();
+    for (tag_dir, tag) in index_root.tag_dirs_in(file_system)? {
+        let tag = &tag.as_tag();
+        let tree_path = tag_dir.join("merkle_tree");
+        match Tree::load_in(file_system, &tree_path) {
+            Ok(tree) => tree.persist_with(&tree_path, options)?,
+            Err(SyncError::CorruptedIndex(_)) => {}
+            Err(SyncError::Io(err)) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        let mut pending = match read_pending(file_system, tag, index_root) {
+            Ok(pending) => pending,
+            Err(SyncError::CorruptedIndex(_)) => continue,
+            Err(err) => return Err(err),
+        };
+        let mut changed = false;
+        for item in &mut pending {
+            if let Ok(relative) = item.path.strip_prefix(tag.dir) {
+                item.path = relative.to_path_buf();
+                changed = true;
+            }
+        }
+        if changed {
+            write_pending(tag, index_root, &pending, options)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::index_root;
+    use crate::{
+        sync::{
+            file_system::{MemoryFileSystem, OsFileSystem},
+            list_indexed_files,
+            merkle::ObjDescription,
+            sync, SyncOptions, Tag, ITEM_SIZE,
+        },
+        utils::{temp_index_root, TempDirBuilder},
+    };
+    use std::fs;
+
+    #[test]
+    fn test_migrate() {
+        let temp_dir = TempDirBuilder::new().add("a.txt", "A").create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let canonical = tag.canonicalize();
+        let tag = &canonical.as_tag();
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root.clone());
+
+        // An index as the first versions wrote it: the tag directory named without a hash, a
+        // cache without a header, and a JSONL tree with absolute paths
+        let old_dir = index_root
+            .path()
+            .join("tags")
+            .join(index_root::remove_seps_from_path(tag.dir))
+            .join("main")
+            .join("default");
+        fs::create_dir_all(&old_dir).unwrap();
+        fs::write(old_dir.join(".tag"), tag.to_string()).unwrap();
+        fs::write(old_dir.join(".index_cache"), [1; ITEM_SIZE]).unwrap();
+        let root_path = serde_json::to_string(tag.dir).unwrap();
+        let file_path = serde_json::to_string(&tag.dir.join("a.txt")).unwrap();
+        fs::write(
+            old_dir.join("merkle_tree"),
+            [
+                format!(
+                    r#"{{"parent":null,"children":[[1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1]],"hash":[2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2],"path":{root_path}}}"#
+                ),
+                format!(
+                    r#"{{"parent":[2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2,2],"children":null,"hash":[1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1,1],"path":{file_path}}}"#
+                ),
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+        let pending = [ObjDescription {
+            hash: [1; ITEM_SIZE],
+            path: tag.dir.join("a.txt"),
+            is_blob: true,
+        }];
+        fs::write(
+            old_dir.join(".pending"),
+            serde_json::to_vec(&pending).unwrap(),
+        )
+        .unwrap();
+
+        let files = list_indexed_files(tag, &options).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].relative_path, "a.txt");
+        assert_eq!(
+            read_version(&OsFileSystem, &index_root).unwrap(),
+            INDEX_VERSION
+        );
+
+        let tag_dir = index_root.tag_dir(tag);
+        assert!(!old_dir.exists());
+        let cache = fs::read(tag_dir.join(".index_cache")).unwrap();
+        assert!(cache.starts_with(b"DSET"));
+        let tree = fs::read_to_string(tag_dir.join("merkle_tree")).unwrap();
+        assert!(tree.starts_with(r#"{"version":1,"#));
+        assert_eq!(
+            read_pending(&OsFileSystem, tag, &index_root).unwrap()[0].path,
+            Path::new("a.txt")
+        );
+
+        // Once it's done, nothing is looked for again
+        let stray = index_root.path().join("tags/stray/main/default");
+        fs::create_dir_all(&stray).unwrap();
+        fs::write(stray.join(".tag"), tag.to_string()).unwrap();
+        migrate(&options, &index_root).unwrap();
+        assert!(stray.exists());
+        fs::remove_dir_all(index_root.path().join("tags/stray")).unwrap();
+
+        // An index from a newer version isn't touched
+        fs::write(version_path(&index_root), (INDEX_VERSION + 1).to_string()).unwrap();
+        assert!(matches!(
+            sync(tag, &options),
+            Err(SyncError::CorruptedIndex(_))
+        ));
+    }
+
+    #[test]
+    fn test_migrate_old_tag_dir() {
+        let temp_dir = TempDirBuilder::new().create();
+        let root = IndexRoot::new(temp_dir.path());
+        let tag = |dir: &'static str| Tag {
+            dir: Path::new(dir),
+            branch: "main",
+            provider_id: "default",
+        };
+        let old_dir = temp_dir.path().join("tags/abc/main/default");
+        fs::create_dir_all(&old_dir).unwrap();
+        fs::write(old_dir.join("merkle_tree"), "tree").unwrap();
+
+        // From before there were .tag files, so `migrate_tag_dirs` doesn't see it, and looking
+        // the tag up doesn't move it either
+        root.migrate_tag_dirs(&OsFileSystem).unwrap();
+        assert!(!root.tag_dir(&tag("/a/bc")).exists());
+        assert!(old_dir.exists());
+
+        // The first of the workspaces sharing the old name to be synced gets it
+        migrate_old_tag_dir(&OsFileSystem, &root, &tag("/a/bc"));
+        assert_eq!(
+            fs::read_to_string(root.tag_dir(&tag("/a/bc")).join("merkle_tree")).unwrap(),
+            "tree"
+        );
+        assert!(!temp_dir.path().join("tags/abc").exists());
+        migrate_old_tag_dir(&OsFileSystem, &root, &tag("/ab/c"));
+        assert!(!root.tag_dir(&tag("/ab/c")).exists());
+
+        // One with a .tag file is only moved for its own tag
+        fs::create_dir_all(&old_dir).unwrap();
+        fs::write(old_dir.join(".tag"), tag("/ab/c").to_string()).unwrap();
+        migrate_old_tag_dir(&OsFileSystem, &root, &tag("/abc"));
+        assert!(!root.tag_dir(&tag("/abc")).exists());
+        assert!(old_dir.exists());
+        migrate_old_tag_dir(&OsFileSystem, &root, &tag("/ab/c"));
+        assert!(root.tag_dir(&tag("/ab/c")).join(".tag").exists());
+        assert!(!old_dir.exists());
+
+        // And the same for an index in another FileSystem
+        let memory = MemoryFileSystem::new();
+        let root = IndexRoot::new("/index");
+        memory.insert("/index/tags/abc/main/default/merkle_tree", "tree");
+        migrate_old_tag_dir(&memory, &root, &tag("/a/bc"));
+        let tree = root.tag_dir(&tag("/a/bc")).join("merkle_tree");
+        assert_eq!(memory.read(&tree).unwrap(), b"tree");
+        assert!(memory.metadata(Path::new("/index/tags/abc")).is_err());
+    }
+
+    #[test]
+    fn test_old_tag_dir_dry_run() {
+        let temp_dir = TempDirBuilder::new().add("a.txt", "A").create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let canonical = tag.canonicalize();
+        let tag = &canonical.as_tag();
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root.clone());
+        let old_dir = index_root
+            .path()
+            .join("tags")
+            .join(index_root::remove_seps_from_path(tag.dir))
+            .join("main")
+            .join("default");
+        fs::create_dir_all(&old_dir).unwrap();
+        fs::write(old_dir.join("marker"), "").unwrap();
+
+        // Only a sync that writes to the index moves it
+        list_indexed_files(tag, &options).unwrap();
+        sync(tag, &options.clone().dry_run(true)).unwrap();
+        assert!(old_dir.join("marker").exists());
+        sync(tag, &options).unwrap();
+        assert!(!old_dir.exists());
+        assert!(index_root.tag_dir(tag).join("marker").exists());
+    }
+
+    #[test]
+    fn test_layout_marker() {
+        // Indexes that only recorded the move of their tag directories skip that migration
+        let (_index_dir, index_root) = temp_index_root();
+        fs::create_dir_all(index_root.path().join("tags")).unwrap();
+        assert_eq!(read_version(&OsFileSystem, &index_root).unwrap(), 0);
+        fs::write(index_root.path().join("tags/.layout"), "2").unwrap();
+        assert_eq!(read_version(&OsFileSystem, &index_root).unwrap(), 1);
+
+        // A new index isn't created by a call that only reads, and starts at the current
+        // version once a sync creates it
+        let temp_dir = TempDirBuilder::new().add("a.txt", "A").create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let new_root = IndexRoot::new(index_root.path().join("new"));
+        let options = SyncOptions::new().index_root(new_root.clone());
+        assert!(list_indexed_files(tag, &options).unwrap().is_empty());
+        assert!(!new_root.path().exists());
+        sync(tag, &options).unwrap();
+        assert_eq!(
+            read_version(&OsFileSystem, &new_root).unwrap(),
+            INDEX_VERSION
+        );
+    }
+}
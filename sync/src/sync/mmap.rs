@@ -0,0 +1,98 @@
+use std::{convert::TryFrom, fs::File, io, ops::Deref};
+
+/// A whole file mapped read-only into memory
+///
+/// The mapping is only as stable as the file: if something truncates the file while it's
+/// mapped, reading past the new end kills the process with SIGBUS rather than returning an
+/// error. That's why mapping is opt-in, with `SyncOptions::mmap`
+pub(crate) struct Mmap {
+    ptr: *const u8,
+    len: usize,
+}
+
+// The mapping is read-only, so it can be read from any thread
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    /// Map `file`, or None if this platform can't or the file is empty (which can't be mapped)
+    pub(crate) fn map(file: &File) -> io::Result<Option<Self>> {
+        let len = file.metadata()?.len();
+        if len == 0 {
+            return Ok(None);
+        }
+        let len = usize::try_from(len)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file too large to map"))?;
+        Self::map_len(file, len)
+    }
+
+    #[cfg(unix)]
+    fn map_len(file: &File, len: usize) -> io::Result<Option<Self>> {
+        use std::os::unix::io::AsRawFd;
+
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The file is read from start to end exactly once
+        unsafe { libc::madvise(ptr, len, libc::MADV_SEQUENTIAL) };
+        Ok(Some(Mmap {
+            ptr: ptr as *const u8,
+            len,
+        }))
+    }
+
+    #[cfg(not(unix))]
+    fn map_len(_file: &File, _len: usize) -> io::Result<Option<Self>> {
+        Ok(None)
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDirBuilder;
+
+    #[test]
+    fn test_mmap() {
+        let temp_dir = TempDirBuilder::new().create();
+        let path = temp_dir.path().join("a.txt");
+        std::fs::write(&path, "contents").unwrap();
+        let mapped = Mmap::map(&File::open(&path).unwrap()).unwrap();
+        if cfg!(unix) {
+            assert_eq!(&*mapped.unwrap(), b"contents");
+        } else {
+            assert!(mapped.is_none());
+        }
+
+        std::fs::write(&path, "").unwrap();
+        assert!(Mmap::map(&File::open(&path).unwrap()).unwrap().is_none());
+    }
+}
@@ -1,584 +1,4616 @@
+mod archive;
+mod atomic;
+mod blocking;
+mod bloom;
+mod chunk;
+mod encoding;
+mod error;
+mod file_system;
+mod gc;
+mod gzip;
+mod http;
+#[cfg(not(target_arch = "wasm32"))]
+mod index_db;
+mod index_root;
+mod journal;
+mod lock;
 mod merkle;
-use homedir::get_my_home;
-use merkle::{compute_tree_for_dir, diff, hash_string};
+mod migrate;
+mod mmap;
+mod nfc;
+mod objects;
+mod options;
+mod provider;
+mod repair;
+mod rev_tags;
+mod rpc;
+#[cfg(not(target_arch = "wasm32"))]
+mod search;
+mod session;
+mod sftp;
+mod symbols;
+mod syntax;
+mod tag;
+mod throttle;
+#[cfg(all(feature = "io_uring", target_os = "linux"))]
+mod uring;
+mod verify;
+mod warning;
+mod watch;
+mod win_path;
+mod writable;
+mod xattr;
+use bloom::{BloomFilter, Stamp};
+#[cfg(not(target_arch = "wasm32"))]
+use index_db::IndexDb;
+use journal::{JournalEntry, JournalOp};
+use lock::IndexLock;
+use merkle::{compute_tree_with_warnings, update_tree, BlobChange};
+use objects::ObjectStore;
+use rev_tags::RevTags;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    fs::{self, File, OpenOptions},
-    io::{Read, Seek, SeekFrom, Write},
+    collections::{HashMap, HashSet, VecDeque},
+    convert::TryInto,
+    io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
     time::{SystemTime, UNIX_EPOCH},
 };
+use throttle::LowPriority;
 
-use self::merkle::{ObjDescription, Tree};
+pub use self::archive::read_indexed_file;
+pub use self::blocking::{delete_tag_async, mark_computed_async, sync_async, Blocking};
+pub use self::chunk::{chunk, Chunk, ChunkOptions};
+pub use self::error::{Result, SyncError};
+pub use self::file_system::{FileHandle, FileMetadata, FileSystem, MemoryFileSystem, OsFileSystem};
+pub use self::gc::{gc, GcResults};
+pub use self::http::{serve_http, serve_http_with_events, SyncEvents};
+pub use self::index_root::{IndexRoot, INDEX_ROOT_VAR};
+pub use self::merkle::{
+    compute_tree_for_dir, diff, diff_each, hash_string, parse_hash, Blob, Change, Compression,
+    ContentNormalization, ObjDescription, Object, ObjectHash, ObjectRef, Objects, Tree, TreeFormat,
+};
+pub use self::objects::{pack_objects, read_object, PackResults};
+pub use self::options::{IndexStorage, LockWait, SyncOptions};
+pub use self::provider::{sync_providers, IndexProvider};
+pub use self::repair::{repair_index, RepairResults, TagRepair};
+pub use self::rpc::serve_json_rpc;
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::search::{search, SearchHit, SearchIndex};
+pub use self::session::SyncSession;
+pub use self::sftp::SftpFileSystem;
+pub use self::symbols::{extract_symbols, Symbol, SymbolHit, SymbolKind};
+#[cfg(not(target_arch = "wasm32"))]
+pub use self::symbols::{find_symbols, SymbolIndex};
+pub use self::tag::{OwnedTag, ParseTagError, Tag};
+pub use self::throttle::Throttle;
+pub use self::verify::{verify_index, Inconsistency, VerifyReport};
+pub use self::warning::SyncWarning;
+pub use self::watch::SyncWatcher;
 
-#[derive(Clone)]
-pub struct Tag<'a> {
-    pub dir: &'a Path,
-    pub branch: &'a str,
-    pub provider_id: &'a str,
+/// The contents of the file at `path` in the index, or None if there's no such file
+fn read_index_file(file_system: &dyn FileSystem, path: &Path) -> Result<Option<Vec<u8>>> {
+    match file_system.read(path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
 }
 
-impl<'a> Tag<'a> {
-    pub fn to_string(&self) -> String {
-        return format!(
-            "{}::{}::{}",
-            self.dir.to_str().unwrap(),
-            self.branch,
-            self.provider_id
-        );
-    }
+/// Seconds since the epoch when the tag in `tag_dir` was last synced, if ever
+fn read_sync_time(file_system: &dyn FileSystem, tag_dir: &Path) -> Result<Option<u64>> {
+    let contents = match read_index_file(file_system, &tag_dir.join(".last_sync"))? {
+        Some(contents) => contents,
+        None => return Ok(None),
+    };
+    let invalid =
+        || SyncError::CorruptedIndex(format!("invalid .last_sync in {}", tag_dir.display()));
+    let contents = std::str::from_utf8(&contents).map_err(|_| invalid())?;
+    contents
+        .trim()
+        .parse::<u64>()
+        .map(Some)
+        .map_err(|_| invalid())
 }
 
-fn remove_seps_from_path(dir: &Path) -> String {
-    let mut path = String::new();
-    for component in dir.components() {
-        path.push_str(component.as_os_str().to_str().unwrap());
+fn write_sync_time(tag: &Tag, index_root: &IndexRoot, options: &SyncOptions) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    #[cfg(not(target_arch = "wasm32"))]
+    if options.storage == IndexStorage::Sqlite {
+        let index_db = IndexDb::open(index_root)?;
+        if options.fsync {
+            index_db.sync_every_commit()?;
+        }
+        return index_db.set_sync_time(&tag.to_string(), now);
     }
 
-    // Remove leading slash
-    if path.starts_with('/') || path.starts_with('\\') {
-        path.remove(0);
-    }
-    path
+    let path = index_root.tag_dir(tag).join(".last_sync");
+    let file_system = options.resolve_index_file_system();
+    file_system.write(&path, now.to_string().as_bytes(), options.fsync)?;
+    Ok(())
+}
+
+/// The tag directory name can't be turned back into a tag, so store the tag itself next to it
+fn write_tag_file(tag: &Tag, index_root: &IndexRoot, options: &SyncOptions) -> Result<()> {
+    let path = index_root.tag_dir(tag).join(".tag");
+    let file_system = options.resolve_index_file_system();
+    file_system.write(&path, tag.to_string().as_bytes(), options.fsync)?;
+    Ok(())
+}
+
+/// How the files in the tag's saved tree were normalized before they were hashed. Trees saved
+/// before this was recorded weren't normalized at all
+fn read_normalization(
+    file_system: &dyn FileSystem,
+    tag: &Tag,
+    index_root: &IndexRoot,
+) -> Result<ContentNormalization> {
+    let path = index_root.tag_dir(tag).join(".normalization");
+    let contents = match read_index_file(file_system, &path)? {
+        Some(contents) => contents,
+        None => return Ok(ContentNormalization::None),
+    };
+    std::str::from_utf8(&contents)
+        .ok()
+        .and_then(|contents| ContentNormalization::parse(contents.trim()))
+        .ok_or_else(|| {
+            SyncError::CorruptedIndex(format!("invalid normalization in {}", path.display()))
+        })
+}
+
+/// Only written once the tree hashed with `options.content_normalization` has been saved, so
+/// the saved hashes are never reused for the wrong normalization
+fn write_normalization(tag: &Tag, index_root: &IndexRoot, options: &SyncOptions) -> Result<()> {
+    let path = index_root.tag_dir(tag).join(".normalization");
+    let normalization = options.content_normalization.as_str();
+    let file_system = options.resolve_index_file_system();
+    file_system.write(&path, normalization.as_bytes(), options.fsync)?;
+    Ok(())
 }
 
-fn path_for_tag(tag: &Tag) -> PathBuf {
-    let mut path = get_my_home().unwrap().unwrap();
-    path.push(".continue/index/tags");
-    path.push(remove_seps_from_path(tag.dir));
-    path.push(tag.branch);
-    path.push(tag.provider_id);
-    return path;
+/// Files held back from the caches by `SyncOptions::defer_compute`, waiting for
+/// `mark_computed`
+fn read_pending(
+    file_system: &dyn FileSystem,
+    tag: &Tag,
+    index_root: &IndexRoot,
+) -> Result<Vec<ObjDescription>> {
+    let path = index_root.tag_dir(tag).join(".pending");
+    let contents = match read_index_file(file_system, &path)? {
+        Some(contents) => contents,
+        None => return Ok(Vec::new()),
+    };
+    serde_json::from_slice(&contents)
+        .map_err(|err| SyncError::CorruptedIndex(format!("{}: {err}", path.display())))
 }
 
-/// Stored in ~/.continue/index/.last_sync
-fn get_last_sync_time(tag: &Tag) -> u64 {
-    // TODO: Error handle here
-    let path = path_for_tag(tag).join(".last_sync");
+fn write_pending(
+    tag: &Tag,
+    index_root: &IndexRoot,
+    pending: &[ObjDescription],
+    options: &SyncOptions,
+) -> Result<()> {
+    let path = index_root.tag_dir(tag).join(".pending");
+    let file_system = options.resolve_index_file_system();
+    if pending.is_empty() {
+        return match file_system.remove_file(&path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        };
+    }
+    file_system.write(&path, &serde_json::to_vec(pending)?, options.fsync)?;
+    Ok(())
+}
 
-//     let mut file = File::open(path).unwrap();
-//     let mut contents = String::new();
-//     file.read_to_string(&mut contents).unwrap();
+/// A tag that has been synced into an index, as returned by `list_tags()`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TagEntry {
+    pub tag: OwnedTag,
 
-//     contents.parse::<u64>().unwrap()
-// }
+    /// Seconds since the epoch when the tag was last synced
+    pub last_sync: Option<u64>,
+}
 
-fn write_sync_time(tag: &Tag) {
-    let path = path_for_tag(tag).join(".last_sync");
+/// All tags that have been synced into the index, optionally only those for a given directory
+/// and/or provider
+pub fn list_tags(
+    options: &SyncOptions,
+    dir: Option<&Path>,
+    provider_id: Option<&str>,
+) -> Result<Vec<TagEntry>> {
+    let index_root = options.resolve_index_root()?;
+    let file_system = &*options.resolve_index_file_system();
+    let dir = dir.map(tag::canonical_dir);
 
-    let mut file = File::create(path).unwrap();
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_secs();
-    file.write_all(now.to_string().as_bytes()).unwrap();
-}
-
-
-/// Use stat to find files since last sync time
-// pub fn get_modified_files(tag: &Tag) -> Vec<PathBuf> {
-//     let last_sync_time = get_last_sync_time(tag);
-//     let mut modified_files = Vec::new();
-//     for entry in build_walk(tag.dir) {
-//         let entry = entry.unwrap();
-//         let path = entry.path();
-//         let metadata = path.metadata().unwrap();
-//         let modified = metadata.modified().unwrap();
-//     build_walk(dir)
-//         .filter_map(|entry| {
-//             let entry = entry.unwrap();
-//             let path = entry.path();
-//             let metadata = path.metadata().unwrap();
-//             let modified = metadata.modified().unwrap();
-
-//             if modified.duration_since(UNIX_EPOCH).unwrap().as_secs() > last_sync_time {
-//                 Some(path.to_path_buf())
-//             } else {
-//                 None
-//             }
-//         })
-//         .collect()
-// }
+    let mut entries = Vec::new();
+    for (tag_dir, tag) in index_root.tag_dirs_in(file_system)? {
+        entries.push(TagEntry {
+            tag,
+            last_sync: read_sync_time(file_system, &tag_dir)?,
+        });
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    if options.storage == IndexStorage::Sqlite && index_root.index_db().exists() {
+        // Tags that haven't been synced since switching over still have their .last_sync
+        let index_db = IndexDb::open(&index_root)?;
+        for entry in &mut entries {
+            if let Some(last_sync) = index_db.sync_time(&entry.tag.to_string())? {
+                entry.last_sync = Some(last_sync);
+            }
+        }
+    }
+
+    entries.retain(|entry| {
+        dir.as_ref().is_none_or(|dir| entry.tag.dir == *dir)
+            && provider_id.is_none_or(|id| entry.tag.provider_id == id)
+    });
+    entries.sort_by_key(|entry| entry.tag.to_string());
+    Ok(entries)
+}
 
 // Merkle trees are unique to directories, even if nested, but .index_cache is shared between all
 
+/// A set of hashes stored as a flat file of 20-byte items, kept in memory while open
+///
+/// The file is only read once, when the set is first used. Changes are kept in memory until
+/// `flush` (or drop): adds are appended to the end of the file together, and removes move the
+/// last item into the removed item's place, so only the part of the file from the first removed
+/// item on is written again.
+///
+/// `compact` sorts the file, which lets items be looked up with a binary search instead of
+/// reading all of it. How much of the file is sorted is saved next to it (as `<file>.sorted`),
+/// and the file is compacted again on drop once too much of it is out of order
+///
+/// The items come between a header, which holds the format version and the number of items,
+/// and a checksum, the XOR of all of them. That doesn't depend on their order, so adds and
+/// removes keep it up to date without reading the file. A file that was cut short or has the
+/// wrong length for its header is caught on open, and one that fails its checksum once it's
+/// read; either is `SyncError::CorruptedIndex`. Files from before the header are rewritten
+/// with one when they're opened
 struct DiskSet {
-    file: File,
+    file_system: Arc<dyn FileSystem>,
+    file: Box<dyn FileHandle>,
+    path: PathBuf,
+
+    /// Number of items in the file, not counting `pending`
+    len: usize,
+
+    /// Added items that haven't been written to the end of the file yet
+    pending: Vec<u8>,
+
+    /// The position of the first item that was removed since the last flush. Everything in
+    /// `loaded` from there on needs writing, pending items included
+    removed_from: Option<usize>,
+
+    /// The XOR of every item in the set, pending ones included
+    checksum: [u8; ITEM_SIZE],
+
+    /// The first `sorted_len` items in the file are in order
+    sorted_len: usize,
+
+    /// The items after the sorted ones, including pending ones. Only read for lookups in a set
+    /// that hasn't been loaded
+    tail: Option<HashSet<[u8; ITEM_SIZE]>>,
+
+    /// Whether the file has changed since it was opened
+    changed: bool,
+
+    /// None until the file has been read
+    loaded: Option<LoadedItems>,
+
+    /// Answers "definitely not present" without reading the file. Only kept for sets opened
+    /// with `with_bloom_filter`
+    bloom: Option<BloomFilter>,
+    bloom_changed: bool,
+}
+
+struct LoadedItems {
+    /// Items in the order they appear in the file
+    items: Vec<[u8; ITEM_SIZE]>,
+
+    /// Position of every item in `items`
+    positions: HashMap<[u8; ITEM_SIZE], usize>,
 }
 
 const ITEM_SIZE: usize = 20;
+const DISK_SET_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A set is compacted on drop once this many items, and a quarter of the set, are out of order
+const MIN_UNSORTED_TO_COMPACT: usize = 1024;
+
+const SORTED_MAGIC: &[u8; 4] = b"SRT1";
+
+/// The header takes the space of one item, so that the items stay aligned to their size: the
+/// magic, the version, three bytes of padding, a reserved word and the number of items
+const DISK_SET_MAGIC: &[u8; 4] = b"DSET";
+const DISK_SET_VERSION: u8 = 1;
+const HEADER_SIZE: usize = ITEM_SIZE;
+const CHECKSUM_SIZE: usize = ITEM_SIZE;
+
+/// Written over the version of a file that failed its checksum, so that it isn't trusted on
+/// the next open either
+const FAILED_CHECKSUM_VERSION: u8 = 0;
 
 impl DiskSet {
-    pub fn new(path: &str) -> Self {
-        let path = Path::new(path);
-        if !path.exists() {
-            File::create(path).unwrap();
-        }
+    pub fn new(file_system: Arc<dyn FileSystem>, path: impl AsRef<Path>) -> Result<Self> {
+        let mut disk_set = Self::open(file_system, path.as_ref())?;
+        disk_set.load()?;
+        Ok(disk_set)
+    }
 
-        Self {
-            file: OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(path)
-                .unwrap(),
+    /// Keep a Bloom filter next to the file (as `<file>.bloom`), so that most lookups of items
+    /// that aren't in the set never have to read it
+    pub fn with_bloom_filter(
+        file_system: Arc<dyn FileSystem>,
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let mut disk_set = Self::open(file_system, path.as_ref())?;
+        let file_system = &*disk_set.file_system;
+        let stamp = Stamp::of(file_system, &disk_set.path)?;
+        match BloomFilter::load(file_system, &disk_set.bloom_path(), stamp) {
+            Ok(Some(bloom)) => disk_set.bloom = Some(bloom),
+            // Missing, out of date, or unreadable, so build it again from the set
+            Ok(None) | Err(SyncError::CorruptedIndex(_)) => disk_set.rebuild_bloom_filter()?,
+            Err(err) => return Err(err),
         }
+        Ok(disk_set)
     }
 
-    pub fn contains(&mut self, item: &[u8; ITEM_SIZE]) -> bool {
-        self.file.seek(SeekFrom::Start(0)).unwrap();
-        let mut buffer = [0; ITEM_SIZE];
-        while self.file.read_exact(&mut buffer).is_ok() {
-            if &buffer == item {
-                return true;
+    fn open(file_system: Arc<dyn FileSystem>, path: &Path) -> Result<Self> {
+        let mut file = file_system.open(path)?;
+        let file_len = file.size()?;
+        let mut start = Vec::with_capacity(HEADER_SIZE);
+        (&mut file)
+            .take(HEADER_SIZE as u64)
+            .read_to_end(&mut start)?;
+        let header = Self::parse_header(path, &start, file_len)?;
+
+        let mut disk_set = Self {
+            file_system,
+            file,
+            path: path.to_path_buf(),
+            len: 0,
+            pending: Vec::new(),
+            removed_from: None,
+            checksum: [0; ITEM_SIZE],
+            sorted_len: 0,
+            tail: None,
+            changed: false,
+            loaded: None,
+            bloom: None,
+            bloom_changed: false,
+        };
+        match header {
+            Some(len) => {
+                disk_set.len = len;
+                disk_set.file.seek(SeekFrom::Start(Self::offset(len)))?;
+                disk_set.file.read_exact(&mut disk_set.checksum)?;
+                disk_set.sorted_len = disk_set.load_sorted_len()?;
+            }
+            // A new file, or one from before the header. Rewriting it leaves its sorted length
+            // out of date, so none of it is known to be sorted
+            None => {
+                let items = Self::read(&*disk_set.file_system, path)?;
+                disk_set.checksum = Self::write_items(&mut disk_set.file, &items)?;
+                disk_set.len = items.len();
+                disk_set.changed = true;
             }
         }
-        false
+        Ok(disk_set)
     }
 
-    pub fn add(&mut self, item: &[u8; ITEM_SIZE]) {
-        if self.contains(item) {
-            return;
+    /// The number of items the file says it has, or None if it has no header. Anything that
+    /// doesn't fit the file's length is corrupted
+    fn parse_header(path: &Path, start: &[u8], file_len: u64) -> Result<Option<usize>> {
+        let corrupted =
+            |problem: String| SyncError::CorruptedIndex(format!("{} {problem}", path.display()));
+        if !start.starts_with(DISK_SET_MAGIC) {
+            return match file_len.is_multiple_of(ITEM_SIZE as u64) {
+                true => Ok(None),
+                false => Err(corrupted(format!(
+                    "is not a whole number of {ITEM_SIZE}-byte items"
+                ))),
+            };
         }
+        if start.len() < HEADER_SIZE {
+            return Err(corrupted("is cut short in its header".to_string()));
+        }
+        match start[4] {
+            DISK_SET_VERSION => {}
+            FAILED_CHECKSUM_VERSION => return Err(corrupted("failed its checksum".to_string())),
+            version => return Err(corrupted(format!("has unknown format version {version}"))),
+        }
+        let len = u64::from_le_bytes(start[12..20].try_into().unwrap()) as usize;
+        match len
+            .checked_mul(ITEM_SIZE)
+            .and_then(|size| size.checked_add(HEADER_SIZE + CHECKSUM_SIZE))
+        {
+            Some(expected) if expected as u64 == file_len => Ok(Some(len)),
+            _ => Err(corrupted(format!(
+                "should hold {len} items but is {file_len} bytes"
+            ))),
+        }
+    }
 
-        self.file.write_all(item).unwrap();
-        self.file.flush().unwrap();
+    /// Every item in the file at `path`, with or without a header, as long as it's intact
+    fn read(file_system: &dyn FileSystem, path: &Path) -> Result<Vec<[u8; ITEM_SIZE]>> {
+        let contents = file_system.read(path)?;
+        let to_items = |bytes: &[u8]| -> Vec<[u8; ITEM_SIZE]> {
+            bytes
+                .chunks_exact(ITEM_SIZE)
+                .map(|chunk| chunk.try_into().unwrap())
+                .collect()
+        };
+        let len = match Self::parse_header(path, &contents, contents.len() as u64)? {
+            Some(len) => len,
+            None => return Ok(to_items(&contents)),
+        };
+        let items = to_items(&contents[Self::offset(0) as usize..Self::offset(len) as usize]);
+        match checksum(&items)[..] == contents[Self::offset(len) as usize..] {
+            true => Ok(items),
+            false => Err(SyncError::CorruptedIndex(format!(
+                "{} failed its checksum",
+                path.display()
+            ))),
+        }
     }
 
-    pub fn remove(&mut self, item: &[u8; ITEM_SIZE]) {
-        self.file.seek(SeekFrom::Start(0)).unwrap();
-        let mut buffer = [0; ITEM_SIZE];
-        let mut pos = 0;
-        let mut found = false;
-        while self.file.read_exact(&mut buffer).is_ok() {
-            if &buffer == item {
-                found = true;
-                break;
+    /// Replace everything in `file` with `items`, returning their checksum
+    fn write_items(
+        file: &mut (impl Write + Seek),
+        items: &[[u8; ITEM_SIZE]],
+    ) -> Result<[u8; ITEM_SIZE]> {
+        let checksum = checksum(items);
+        let mut contents = Vec::with_capacity(Self::file_len(items.len()) as usize);
+        contents.extend_from_slice(&Self::header(items.len()));
+        contents.extend_from_slice(&items.concat());
+        contents.extend_from_slice(&checksum);
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&contents)?;
+        file.flush()?;
+        Ok(checksum)
+    }
+
+    fn header(len: usize) -> [u8; HEADER_SIZE] {
+        let mut header = [0; HEADER_SIZE];
+        header[..4].copy_from_slice(DISK_SET_MAGIC);
+        header[4] = DISK_SET_VERSION;
+        header[12..20].copy_from_slice(&(len as u64).to_le_bytes());
+        header
+    }
+
+    /// Bring the header and checksum up to date with the items in the file
+    fn write_footer(&mut self) -> Result<()> {
+        self.file.seek(SeekFrom::Start(Self::offset(self.len)))?;
+        self.file.write_all(&self.checksum)?;
+        self.file.set_size(Self::file_len(self.len))?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&Self::header(self.len))?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// `<file><extension>`, for the files kept next to this one
+    fn sidecar_path(&self, extension: &str) -> PathBuf {
+        let mut file_name = self.path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(extension);
+        self.path.with_file_name(file_name)
+    }
+
+    fn bloom_path(&self) -> PathBuf {
+        self.sidecar_path(".bloom")
+    }
+
+    fn sorted_path(&self) -> PathBuf {
+        self.sidecar_path(".sorted")
+    }
+
+    /// How many items at the start of the file are sorted, as saved for this exact version of
+    /// the file. Anything else means none of it is known to be
+    fn load_sorted_len(&self) -> Result<usize> {
+        let contents = match self.file_system.read(&self.sorted_path()) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.into()),
+        };
+        if contents.len() != 28 || &contents[0..4] != SORTED_MAGIC {
+            return Ok(0);
+        }
+        let word = |i: usize| u64::from_le_bytes(contents[i..i + 8].try_into().unwrap());
+        let saved_stamp = Stamp {
+            len: word(4),
+            mtime: word(12),
+        };
+        let sorted_len = word(20) as usize;
+        match saved_stamp == Stamp::of(&*self.file_system, &self.path)? && sorted_len <= self.len {
+            true => Ok(sorted_len),
+            false => Ok(0),
+        }
+    }
+
+    fn persist_sorted_len(&self, stamp: Stamp) -> Result<()> {
+        let mut contents = SORTED_MAGIC.to_vec();
+        contents.extend_from_slice(&stamp.len.to_le_bytes());
+        contents.extend_from_slice(&stamp.mtime.to_le_bytes());
+        contents.extend_from_slice(&(self.sorted_len as u64).to_le_bytes());
+        self.file_system
+            .write(&self.sorted_path(), &contents, false)?;
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<&mut LoadedItems> {
+        if self.loaded.is_none() {
+            self.flush()?;
+            self.file.seek(SeekFrom::Start(Self::offset(0)))?;
+            let mut reader = BufReader::with_capacity(DISK_SET_BUFFER_SIZE, &mut self.file);
+
+            let mut loaded = LoadedItems {
+                items: Vec::with_capacity(self.len),
+                positions: HashMap::with_capacity(self.len),
+            };
+            let mut read_checksum = [0; ITEM_SIZE];
+            let mut item = [0; ITEM_SIZE];
+            for _ in 0..self.len {
+                reader.read_exact(&mut item)?;
+                xor_into(&mut read_checksum, &item);
+                if !loaded.positions.contains_key(&item) {
+                    loaded.positions.insert(item, loaded.items.len());
+                    loaded.items.push(item);
+                }
+            }
+            drop(reader);
+
+            if read_checksum != self.checksum {
+                self.file.seek(SeekFrom::Start(4))?;
+                self.file.write_all(&[FAILED_CHECKSUM_VERSION])?;
+                self.file.flush()?;
+                return Err(SyncError::CorruptedIndex(format!(
+                    "{} failed its checksum",
+                    self.path.display()
+                )));
+            }
+
+            // Only possible if the file was written by something else, but don't keep the
+            // duplicates
+            if loaded.items.len() != self.len {
+                self.file.set_size(0)?;
+                self.checksum = Self::write_items(&mut self.file, &loaded.items)?;
+                self.sorted_len = 0;
+                self.changed = true;
             }
-            pos = self.file.stream_position().unwrap() as usize;
+            self.len = loaded.items.len();
+            self.loaded = Some(loaded);
+            self.tail = None;
         }
+        Ok(self.loaded.as_mut().unwrap())
+    }
+
+    /// Rewrite the file in sorted order, without duplicates
+    pub fn compact(&mut self) -> Result<()> {
+        // Including the ones that were still pending
+        let items = std::mem::take(&mut self.load()?.items);
+        self.replace_all(items)
+    }
+
+    /// Rewrite the file with just `items`, in sorted order, dropping whatever the set had,
+    /// changes that haven't been flushed included
+    pub fn replace_all(&mut self, items: impl IntoIterator<Item = [u8; ITEM_SIZE]>) -> Result<()> {
+        let mut items: Vec<_> = items.into_iter().collect();
+        items.sort_unstable();
+        items.dedup();
+        let positions = (items.iter().enumerate())
+            .map(|(position, item)| (*item, position))
+            .collect();
+
+        self.file.set_size(0)?;
+        self.checksum = Self::write_items(&mut self.file, &items)?;
+        self.len = items.len();
+        self.pending.clear();
+        self.removed_from = None;
+        self.sorted_len = self.len;
+        self.loaded = Some(LoadedItems { items, positions });
+        self.tail = None;
+        self.changed = true;
+        // The filter can't forget items that are gone, but it has to be saved against the new
+        // file
+        self.bloom_changed = self.bloom.is_some();
+        Ok(())
+    }
+
+    fn needs_compaction(&self) -> bool {
+        let unsorted = self.len - self.sorted_len;
+        unsorted >= MIN_UNSORTED_TO_COMPACT && unsorted * 4 >= self.len
+    }
 
-        if found {
-            // Calculate the position of the last item
-            let len = self.file.metadata().unwrap().len() as usize;
-            let last_item_pos = len - ITEM_SIZE;
+    /// Binary search the sorted part of the file
+    fn sorted_contains(&mut self, item: &[u8; ITEM_SIZE]) -> Result<bool> {
+        let (mut low, mut high) = (0, self.sorted_len);
+        let mut probe = [0; ITEM_SIZE];
+        while low < high {
+            let middle = low + (high - low) / 2;
+            self.file.seek(SeekFrom::Start(Self::offset(middle)))?;
+            self.file.read_exact(&mut probe)?;
+            match probe.cmp(item) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return Ok(true),
+            }
+        }
+        Ok(false)
+    }
 
-            // Move the last item in the file to the position of the item we want to remove
+    fn tail(&mut self) -> Result<&HashSet<[u8; ITEM_SIZE]>> {
+        if self.tail.is_none() {
+            let mut tail = HashSet::new();
             self.file
-                .seek(SeekFrom::Start(last_item_pos as u64))
-                .unwrap();
-            self.file.read_exact(&mut buffer).unwrap();
-            self.file.seek(SeekFrom::Start(pos as u64)).unwrap();
-            self.file.write_all(&buffer).unwrap();
+                .seek(SeekFrom::Start(Self::offset(self.sorted_len)))?;
+            let mut reader = BufReader::with_capacity(DISK_SET_BUFFER_SIZE, &mut self.file);
+            let mut item = [0; ITEM_SIZE];
+            for _ in self.sorted_len..self.len {
+                reader.read_exact(&mut item)?;
+                tail.insert(item);
+            }
+            for chunk in self.pending.chunks_exact(ITEM_SIZE) {
+                tail.insert(chunk.try_into().unwrap());
+            }
+            self.tail = Some(tail);
+        }
+        Ok(self.tail.as_ref().unwrap())
+    }
 
-            // Truncate the file at the position of the last item
-            self.file.set_len(last_item_pos as u64).unwrap();
+    fn rebuild_bloom_filter(&mut self) -> Result<()> {
+        let loaded = self.load()?;
+        let mut bloom = BloomFilter::with_capacity(loaded.items.len());
+        for item in &loaded.items {
+            bloom.insert(item);
         }
+        self.bloom = Some(bloom);
+        self.bloom_changed = true;
+        Ok(())
     }
-}
 
-struct IndexCache<'a> {
-    tag: Box<Tag<'a>>,
-    global_cache: DiskSet,
-    tag_cache: DiskSet,
-}
+    /// True if the Bloom filter rules the item out
+    fn definitely_absent(&self, item: &[u8; ITEM_SIZE]) -> bool {
+        self.bloom
+            .as_ref()
+            .is_some_and(|bloom| !bloom.may_contain(item))
+    }
 
-impl<'a> IndexCache<'a> {
-    fn index_cache_path_for_tag(tag: &Tag) -> PathBuf {
-        let mut path = path_for_tag(tag);
-        path.push(".index_cache");
-        path
+    fn offset(position: usize) -> u64 {
+        (HEADER_SIZE + position * ITEM_SIZE) as u64
     }
 
-    fn rev_tags_dir(provider_id: &str) -> PathBuf {
-        let mut path = IndexCache::provider_dir(provider_id);
-        path.push("rev_tags");
-        return path;
+    /// The length of a file with `len` items
+    fn file_len(len: usize) -> u64 {
+        Self::offset(len) + CHECKSUM_SIZE as u64
     }
 
-    fn rev_tags_path(hash: [u8; ITEM_SIZE], provider_id: &str) -> PathBuf {
-        let hash_str = hash_string(hash);
-        let mut path = IndexCache::rev_tags_dir(provider_id);
-        // Branch by 1) first two chars of hash
-        path.push(&hash_str[0..2]);
-        path
+    /// Write any buffered changes to the file
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(removed_from) = self.removed_from.take() {
+            // Pending items haven't been written anywhere yet
+            let from = removed_from.min(self.len);
+            let items = &self.loaded.as_ref().expect("removes load the set").items;
+            self.file.seek(SeekFrom::Start(Self::offset(from)))?;
+            self.file.write_all(&items[from..].concat())?;
+            self.len = items.len();
+            self.pending.clear();
+            self.changed = true;
+            return self.write_footer();
+        }
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        self.file.seek(SeekFrom::Start(Self::offset(self.len)))?;
+        self.file.write_all(&self.pending)?;
+        self.len += self.pending.len() / ITEM_SIZE;
+        self.pending.clear();
+        self.changed = true;
+        self.write_footer()
     }
 
-    fn tag_str(&self) -> String {
-        return self.tag.to_string();
+    pub fn contains(&mut self, item: &[u8; ITEM_SIZE]) -> Result<bool> {
+        if self.definitely_absent(item) {
+            return Ok(false);
+        }
+        if self.loaded.is_none() && self.sorted_len > 0 {
+            return Ok(self.sorted_contains(item)? || self.tail()?.contains(item));
+        }
+        Ok(self.load()?.positions.contains_key(item))
     }
 
-    fn provider_dir(provider_id: &str) -> PathBuf {
-        let mut path = get_my_home().unwrap().unwrap();
-        path.push(".continue/index/providers");
-        path.push(provider_id);
-        return path;
+    pub fn add(&mut self, item: &[u8; ITEM_SIZE]) -> Result<()> {
+        if self.contains(item)? {
+            return Ok(());
+        }
+
+        self.pending.extend_from_slice(item);
+        xor_into(&mut self.checksum, item);
+        if let Some(loaded) = &mut self.loaded {
+            loaded.positions.insert(*item, loaded.items.len());
+            loaded.items.push(*item);
+        }
+        if let Some(tail) = &mut self.tail {
+            tail.insert(*item);
+        }
+
+        if let Some(bloom) = &mut self.bloom {
+            bloom.insert(item);
+            self.bloom_changed = true;
+            if self.len + self.pending.len() / ITEM_SIZE > bloom.capacity() {
+                self.rebuild_bloom_filter()?;
+            }
+        }
+        Ok(())
     }
 
-    fn new(tag: &'a Tag) -> IndexCache<'a> {
-        return IndexCache {
-            tag: Box::new(tag.clone()),
-            global_cache: DiskSet::new(
-                IndexCache::provider_dir(tag.provider_id)
-                    .join(".index_cache")
-                    .to_str()
-                    .unwrap(),
-            ),
-            tag_cache: DiskSet::new(IndexCache::index_cache_path_for_tag(tag).to_str().unwrap()),
+    pub fn remove(&mut self, item: &[u8; ITEM_SIZE]) -> Result<()> {
+        if self.definitely_absent(item) {
+            return Ok(());
+        }
+        let loaded = self.load()?;
+        let position = match loaded.positions.remove(item) {
+            Some(position) => position,
+            None => return Ok(()),
         };
+
+        // Move the last item to the position of the item we want to remove, which is written
+        // to the file on flush
+        loaded.items.swap_remove(position);
+        if let Some(moved) = loaded.items.get(position) {
+            loaded.positions.insert(*moved, position);
+        }
+        let len = loaded.items.len();
+        self.removed_from = Some(
+            self.removed_from
+                .map_or(position, |from| from.min(position)),
+        );
+        xor_into(&mut self.checksum, item);
+
+        // The moved item is most likely out of order there
+        self.sorted_len = self.sorted_len.min(position).min(len);
+
+        // The filter can't forget the item, but it has to be saved against the new file
+        self.bloom_changed = self.bloom.is_some();
+        Ok(())
     }
+}
 
-    // rev_tags files are just json files with the following format:
-    // { "hash": ["tag1", "tag2", ...], ... }
+impl Drop for DiskSet {
+    fn drop(&mut self) {
+        // Nothing can be done about an error here. Without the items on disk the Bloom filter is
+        // stale, which the length check below catches
+        let _ = self.flush();
+        if self.needs_compaction() {
+            let _ = self.compact();
+        }
+        if self.changed {
+            // The same goes for the sorted length, which is only worth saving if some of the
+            // file is sorted
+            let _ = match Stamp::of(&*self.file_system, &self.path) {
+                Ok(stamp) if stamp.len == Self::file_len(self.len) && self.sorted_len > 0 => {
+                    self.persist_sorted_len(stamp)
+                }
+                _ => (self.file_system)
+                    .remove_file(&self.sorted_path())
+                    .map_err(SyncError::from),
+            };
+        }
+        if let (Some(bloom), true) = (&self.bloom, self.bloom_changed) {
+            // If someone else changed the file while it was open, the filter may be missing
+            // their items, so leave it to be rebuilt next time. Failing to save is fine too, it
+            // only means rebuilding the filter on the next open
+            let file_system = &*self.file_system;
+            let _ = match Stamp::of(file_system, &self.path) {
+                Ok(stamp) if stamp.len == Self::file_len(self.len) => {
+                    bloom.persist(file_system, &self.bloom_path(), stamp)
+                }
+                _ => (file_system.remove_file(&self.bloom_path())).map_err(SyncError::from),
+            };
+        }
+    }
+}
 
-    // TODO: You could add_bulk, remove_bulk if this gets slow
-    fn read_rev_tags(&self, hash: [u8; ITEM_SIZE]) -> HashMap<String, Vec<String>> {
-        let rev_tags_path = IndexCache::rev_tags_path(hash, self.tag.provider_id);
-        let mut rev_tags_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(rev_tags_path)
-            .unwrap();
-        let mut contents = String::new();
-        rev_tags_file.read_to_string(&mut contents).unwrap();
+/// The XOR of all the items, which a `DiskSet` keeps as its checksum
+fn checksum(items: &[[u8; ITEM_SIZE]]) -> [u8; ITEM_SIZE] {
+    let mut checksum = [0; ITEM_SIZE];
+    for item in items {
+        xor_into(&mut checksum, item);
+    }
+    checksum
+}
 
-        serde_json::from_str(&contents).unwrap_or_default()
+fn xor_into(checksum: &mut [u8; ITEM_SIZE], item: &[u8; ITEM_SIZE]) {
+    for (byte, item_byte) in checksum.iter_mut().zip(item) {
+        *byte ^= item_byte;
     }
+}
 
-    fn write_rev_tags(&self, hash: [u8; ITEM_SIZE], rev_tags: HashMap<String, Vec<String>>) {
-        let rev_tags_path = IndexCache::rev_tags_path(hash, self.tag.provider_id);
-        let mut rev_tags_file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(rev_tags_path)
-            .unwrap();
-        let json = serde_json::to_string(&rev_tags).unwrap();
+/// Open one of a provider's file caches: the global one (with a Bloom filter) without a tag,
+/// or the tag's own one. If the file is corrupted it's rebuilt from rev_tags, which has every
+/// hash in the provider's caches along with the tags that have it
+fn open_or_rebuild(
+    file_system: &Arc<dyn FileSystem>,
+    path: &Path,
+    rev_tags: &RevTags,
+    tag: Option<&str>,
+) -> Result<DiskSet> {
+    match open_cache(file_system, path, tag.is_none()) {
+        Err(SyncError::CorruptedIndex(_)) => {}
+        result => return result,
+    }
+    let hashes = rev_tags
+        .all()?
+        .into_iter()
+        .filter(|(_, rev_tag)| tag.is_none_or(|tag| tag == rev_tag))
+        .map(|(hash, _)| hash);
+    rebuild_cache(file_system, path, tag.is_none(), hashes)
+}
+
+fn open_cache(file_system: &Arc<dyn FileSystem>, path: &Path, bloom: bool) -> Result<DiskSet> {
+    match bloom {
+        true => DiskSet::with_bloom_filter(file_system.clone(), path),
+        false => DiskSet::new(file_system.clone(), path),
+    }
+}
+
+/// Replace the cache at `path`, and the files next to it, with one holding just `hashes`
+fn rebuild_cache(
+    file_system: &Arc<dyn FileSystem>,
+    path: &Path,
+    bloom: bool,
+    hashes: impl IntoIterator<Item = ObjectHash>,
+) -> Result<DiskSet> {
+    for extension in ["", ".bloom", ".sorted"] {
+        let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(extension);
+        match file_system.remove_file(&path.with_file_name(file_name)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+    }
+    let mut disk_set = open_cache(file_system, path, bloom)?;
+    for hash in hashes {
+        disk_set.add(&hash)?;
+    }
+    disk_set.flush()?;
+    Ok(disk_set)
+}
+
+struct IndexCache {
+    tag_str: String,
+    #[cfg(not(target_arch = "wasm32"))]
+    provider_id: String,
+
+    /// The tag's directory, under which the paths of the results are
+    dir: PathBuf,
+    storage: CacheStorage,
+
+    /// Where the content of files to compute is saved, with `SyncOptions::store_objects`
+    objects: Option<ObjectStore>,
+}
+
+/// The caches behind an `IndexCache`, as chosen by `SyncOptions::storage`
+enum CacheStorage {
+    Files {
+        global_cache: Box<DiskSet>,
+        tag_cache: Box<DiskSet>,
+        rev_tags: RevTags,
+
+        /// The changes made since the last bulk change was written, to go in its journal
+        journal: Vec<(JournalOp, ObjectHash)>,
+        journal_path: PathBuf,
+        fsync: bool,
+
+        /// Where the caches and the journal are kept (see `SyncOptions::index_file_system`)
+        file_system: Arc<dyn FileSystem>,
+    },
+    #[cfg(not(target_arch = "wasm32"))]
+    Sqlite(IndexDb),
+}
+
+impl IndexCache {
+    fn index_cache_path_for_tag(tag: &Tag, index_root: &IndexRoot) -> PathBuf {
+        let mut path = index_root.tag_dir(tag);
+        path.push(".index_cache");
+        path
+    }
+
+    fn new(tag: &Tag, index_root: &IndexRoot, options: &SyncOptions) -> Result<IndexCache> {
+        let storage = match options.storage {
+            IndexStorage::Files => {
+                let rev_tags = RevTags::open(index_root, tag.provider_id, options)?;
+                if options.fsync {
+                    rev_tags.sync_every_commit()?;
+                }
+                let global_path = index_root
+                    .provider_dir(tag.provider_id)
+                    .join(".index_cache");
+                let tag_path = IndexCache::index_cache_path_for_tag(tag, index_root);
+                let file_system = options.resolve_index_file_system();
+                for path in [&global_path, &tag_path] {
+                    file_system.create_dir_all(path.parent().unwrap_or(Path::new("")))?;
+                }
+                CacheStorage::Files {
+                    global_cache: Box::new(open_or_rebuild(
+                        &file_system,
+                        &global_path,
+                        &rev_tags,
+                        None,
+                    )?),
+                    tag_cache: Box::new(open_or_rebuild(
+                        &file_system,
+                        &tag_path,
+                        &rev_tags,
+                        Some(&tag.to_string()),
+                    )?),
+                    rev_tags,
+                    journal: Vec::new(),
+                    journal_path: index_root.journal(tag.provider_id),
+                    fsync: options.fsync,
+                    file_system,
+                }
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            IndexStorage::Sqlite => {
+                let file_system = options.resolve_index_file_system();
+                if file_system
+                    .metadata(&index_root.journal(tag.provider_id))
+                    .is_ok()
+                {
+                    // Finish the change the files were left in the middle of before they're
+                    // moved over
+                    let files = options.clone().storage(IndexStorage::Files);
+                    drop(IndexCache::new(tag, index_root, &files)?);
+                }
+                let index_db = IndexDb::open(index_root)?;
+                if options.fsync {
+                    index_db.sync_every_commit()?;
+                }
+                index_db.migrate_files(tag, index_root, &*file_system)?;
+                #[cfg(not(target_arch = "wasm32"))]
+                CacheStorage::Sqlite(index_db)
+            }
+        };
+        let mut index_cache = IndexCache {
+            tag_str: tag.to_string(),
+            #[cfg(not(target_arch = "wasm32"))]
+            provider_id: tag.provider_id.to_string(),
+            dir: tag.dir.to_path_buf(),
+            storage,
+            objects: match options.store_objects {
+                true => Some(ObjectStore::open(index_root, tag.provider_id, options)?),
+                false => None,
+            },
+        };
+        index_cache.recover_journal(index_root)?;
+        Ok(index_cache)
+    }
+
+    /// Finish the bulk change in the journal, if one was interrupted, so that the caches agree
+    /// with each other again. See `journal.rs`
+    fn recover_journal(&mut self, index_root: &IndexRoot) -> Result<()> {
+        let (global_cache, tag_cache, rev_tags, journal_path, file_system) = match &mut self.storage
+        {
+            CacheStorage::Files {
+                global_cache,
+                tag_cache,
+                rev_tags,
+                journal_path,
+                file_system,
+                ..
+            } => (global_cache, tag_cache, rev_tags, journal_path, file_system),
+            #[cfg(not(target_arch = "wasm32"))]
+            CacheStorage::Sqlite(_) => return Ok(()),
+        };
+        let entry = match JournalEntry::read(&**file_system, journal_path)? {
+            Some(entry) => entry,
+            // Nothing was written before the journal was finished, so there's nothing to do
+            None => return journal::remove(&**file_system, journal_path),
+        };
+
+        // The change may have been made by another of the provider's tags
+        let mut other_tag_cache = None;
+        let entry_tag_cache = match entry.tag == self.tag_str {
+            true => tag_cache,
+            false => {
+                let tag = entry.tag.parse::<OwnedTag>().map_err(|err| {
+                    SyncError::CorruptedIndex(format!("{err} in {}", journal_path.display()))
+                })?;
+                let path = IndexCache::index_cache_path_for_tag(&tag.as_tag(), index_root);
+                other_tag_cache.insert(Box::new(open_or_rebuild(
+                    file_system,
+                    &path,
+                    rev_tags,
+                    Some(&entry.tag),
+                )?))
+            }
+        };
 
-        // Rewrite the whole file
-        rev_tags_file.set_len(0).unwrap();
-        rev_tags_file.seek(SeekFrom::Start(0)).unwrap();
-        rev_tags_file.write_all(json.as_bytes()).unwrap();
-        rev_tags_file.flush().unwrap();
+        let rev_tags_committed = rev_tags.has_journal(entry.id)?;
+        rev_tags.begin()?;
+        for (op, hash) in &entry.ops {
+            match op {
+                JournalOp::AddGlobal => {
+                    global_cache.add(hash)?;
+                    entry_tag_cache.add(hash)?;
+                    if !rev_tags_committed {
+                        rev_tags.add(hash, &entry.tag)?;
+                    }
+                }
+                JournalOp::GlobalRemove => {
+                    global_cache.remove(hash)?;
+                    entry_tag_cache.remove(hash)?;
+                    if !rev_tags_committed {
+                        rev_tags.remove_all(hash)?;
+                    }
+                }
+                JournalOp::LocalRemove => {
+                    if !rev_tags_committed {
+                        rev_tags.remove_one(hash, &entry.tag)?;
+                    }
+                    if rev_tags.tag_refcount(hash, &entry.tag)? == 0 {
+                        entry_tag_cache.remove(hash)?;
+                    }
+                }
+            }
+        }
+        rev_tags.record_journal(entry.id)?;
+        global_cache.flush()?;
+        entry_tag_cache.flush()?;
+        rev_tags.commit()?;
+        journal::remove(&**file_system, journal_path)
     }
 
-    fn add_global(&mut self, item: &ObjDescription) {
-        self.global_cache.add(&item.hash);
-        self.tag_cache.add(&item.hash);
+    fn add_global(&mut self, item: &ObjDescription) -> Result<()> {
+        match &mut self.storage {
+            CacheStorage::Files {
+                global_cache,
+                tag_cache,
+                rev_tags,
+                journal,
+                ..
+            } => {
+                journal.push((JournalOp::AddGlobal, item.hash));
+                global_cache.add(&item.hash)?;
+                tag_cache.add(&item.hash)?;
 
-        // Add to rev_tags
-        let mut rev_tags = Self::read_rev_tags(item.hash);
-        let tag_str = self.tag_str();
-        let hash_str = hash_string(item.hash);
-        if !rev_tags.contains_key(hash_str.as_str()) {
-            rev_tags.insert(hash_str.clone(), Vec::new());
+                // Add to rev_tags
+                rev_tags.add(&item.hash, &self.tag_str)
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            CacheStorage::Sqlite(index_db) => {
+                index_db.global_add(&self.provider_id, &item.hash)?;
+                index_db.tag_add(&self.tag_str, &item.hash)?;
+                index_db.add_rev_tag(&self.provider_id, &item.hash, &self.tag_str)
+            }
         }
-        rev_tags.get_mut(hash_str.as_str()).unwrap().push(tag_str);
-        Self::write_rev_tags(item.hash, &rev_tags);
     }
 
-    fn global_remove(&mut self, item: &ObjDescription) {
-        self.global_cache.remove(&item.hash);
-        self.tag_cache.remove(&item.hash);
+    fn global_remove(&mut self, item: &ObjDescription) -> Result<()> {
+        match &mut self.storage {
+            CacheStorage::Files {
+                global_cache,
+                tag_cache,
+                rev_tags,
+                journal,
+                ..
+            } => {
+                journal.push((JournalOp::GlobalRemove, item.hash));
+                global_cache.remove(&item.hash)?;
+                tag_cache.remove(&item.hash)?;
 
-        // Remove from rev_tags
-        let mut rev_tags = Self::read_rev_tags(item.hash);
-        let hash_str = hash_string(item.hash);
-        if rev_tags.contains_key(hash_str.as_str()) {
-            rev_tags.remove(hash_str.as_str());
+                // Remove from rev_tags
+                rev_tags.remove_all(&item.hash)
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            CacheStorage::Sqlite(index_db) => {
+                index_db.global_remove(&self.provider_id, &item.hash)?;
+                index_db.tag_remove(&self.tag_str, &item.hash)?;
+                index_db.remove_all_rev_tags(&self.provider_id, &item.hash)
+            }
         }
-        Self::write_rev_tags(item.hash, &rev_tags);
     }
 
-    fn local_remove(&mut self, item: &ObjDescription) {
-        self.tag_cache.remove(&item.hash);
+    fn local_remove(&mut self, item: &ObjDescription) -> Result<()> {
+        match &mut self.storage {
+            CacheStorage::Files {
+                tag_cache,
+                rev_tags,
+                journal,
+                ..
+            } => {
+                journal.push((JournalOp::LocalRemove, item.hash));
+                rev_tags.remove_one(&item.hash, &self.tag_str)?;
 
-        // Remove from rev_tags
-        let mut rev_tags = Self::read_rev_tags(item.hash);
-        let tag_str = self.tag_str();
-        let hash_str = hash_string(item.hash);
-        if rev_tags.contains_key(hash_str.as_str()) {
-            let tags = rev_tags.get_mut(hash_str.as_str()).unwrap();
-            let index = tags.iter().position(|x| *x == tag_str).unwrap();
-            tags.remove(index);
-            if tags.is_empty() {
-                rev_tags.remove(hash_str.as_str());
+                // The tag still has the content if another of its paths does
+                match rev_tags.tag_refcount(&item.hash, &self.tag_str)? {
+                    0 => tag_cache.remove(&item.hash),
+                    _ => Ok(()),
+                }
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            CacheStorage::Sqlite(index_db) => {
+                index_db.remove_one_rev_tag(&self.provider_id, &item.hash, &self.tag_str)?;
+                match index_db.tag_refcount(&self.provider_id, &item.hash, &self.tag_str)? {
+                    0 => index_db.tag_remove(&self.tag_str, &item.hash),
+                    _ => Ok(()),
+                }
             }
         }
-        Self::write_rev_tags(item.hash, &rev_tags);
     }
 
-    fn global_contains(&mut self, hash: &[u8; ITEM_SIZE]) -> bool {
-        self.global_cache.contains(hash)
+    fn global_contains(&mut self, hash: &[u8; ITEM_SIZE]) -> Result<bool> {
+        match &mut self.storage {
+            CacheStorage::Files { global_cache, .. } => global_cache.contains(hash),
+            #[cfg(not(target_arch = "wasm32"))]
+            CacheStorage::Sqlite(index_db) => index_db.global_contains(&self.provider_id, hash),
+        }
     }
 
     // fn tag_contains(&mut self, hash: &[u8; ITEM_SIZE]) -> bool {
     //     self.tag_cache.contains(hash)
     // }
 
-    fn get_rev_tags(hash: &[u8; ITEM_SIZE]) -> Vec<String> {
-        let mut rev_tags = Self::read_rev_tags(*hash);
-        let hash_str = hash_string(*hash);
-        if rev_tags.contains_key(hash_str.as_str()) {
-            rev_tags.remove(hash_str.as_str()).unwrap()
-        } else {
-            Vec::new()
+    /// Run `f` with all of its rev_tags changes in a single transaction, and its cache adds
+    /// written together at the end
+    fn bulk<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        match &self.storage {
+            CacheStorage::Files { rev_tags, .. } => rev_tags.begin()?,
+            #[cfg(not(target_arch = "wasm32"))]
+            CacheStorage::Sqlite(index_db) => index_db.begin()?,
+        }
+        match f(self) {
+            Ok(value) => {
+                match &mut self.storage {
+                    CacheStorage::Files {
+                        global_cache,
+                        tag_cache,
+                        rev_tags,
+                        journal,
+                        journal_path,
+                        fsync,
+                        file_system,
+                    } => {
+                        // Once the journal is written, the change is finished on the next open
+                        // even if it's interrupted here
+                        let ops = std::mem::take(journal);
+                        let written = !ops.is_empty();
+                        if written {
+                            let entry = JournalEntry::new(self.tag_str.clone(), ops);
+                            entry.write(&**file_system, journal_path, *fsync)?;
+                            rev_tags.record_journal(entry.id)?;
+                        }
+                        global_cache.flush()?;
+                        tag_cache.flush()?;
+                        rev_tags.commit()?;
+                        if written {
+                            journal::remove(&**file_system, journal_path)?;
+                        }
+                    }
+                    #[cfg(not(target_arch = "wasm32"))]
+                    CacheStorage::Sqlite(index_db) => index_db.commit()?,
+                }
+                Ok(value)
+            }
+            Err(err) => {
+                // Nothing is gained by losing the error to one from undoing the change
+                let _ = self.undo_bulk();
+                Err(err)
+            }
         }
     }
-}
 
-pub fn sync(
-    tag: &Tag,
-) -> Result<
-    (
-        Vec<(String, String)>,
-        Vec<(String, String)>,
-        Vec<(String, String)>,
-        Vec<(String, String)>,
-    ),
-    Box<dyn std::error::Error>,
-> {
-    // Make sure that the tag directory exists
-    // Create the directory and all its parent directories if they don't exist
-    fs::create_dir_all(path_for_tag(tag)).unwrap();
-    if let Some(parent) = IndexCache::rev_tags_path([0; ITEM_SIZE], tag.provider_id).parent() {
-        fs::create_dir_all(parent).unwrap();
+    /// Undo the changes of a `bulk` that failed. Some of them may already be in the cache
+    /// files (a set that isn't loaded writes its adds when a remove loads it) and the rest would
+    /// be written on drop, so the caches are rewritten from rev_tags once it's rolled back
+    fn undo_bulk(&mut self) -> Result<()> {
+        match &mut self.storage {
+            CacheStorage::Files {
+                global_cache,
+                tag_cache,
+                rev_tags,
+                journal,
+                ..
+            } => {
+                journal.clear();
+                rev_tags.rollback()?;
+                let all = rev_tags.all()?;
+                global_cache.replace_all(all.iter().map(|(hash, _)| *hash))?;
+                let tag_str = &self.tag_str;
+                let tag_hashes = all.iter().filter(|(_, tag)| tag == tag_str);
+                tag_cache.replace_all(tag_hashes.map(|(hash, _)| *hash))
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            CacheStorage::Sqlite(index_db) => index_db.rollback(),
+        }
     }
 
-    let mut tree_path = path_for_tag(tag);
-    tree_path.push("merkle_tree");
+    fn add_bulk(&mut self, items: &[ObjDescription]) -> Result<()> {
+        self.bulk(|index_cache| {
+            for item in items {
+                index_cache.add_global(item)?;
+            }
+            Ok(())
+        })
+    }
 
-    let old_tree = Tree::load(&tree_path).unwrap_or_default();
+    /// `remove_from_tag` for every item, in one transaction
+    fn remove_bulk(
+        &mut self,
+        items: &[ObjDescription],
+        dry_run: bool,
+        results: &mut SyncResults,
+    ) -> Result<()> {
+        self.bulk(|index_cache| {
+            for item in items {
+                remove_from_tag(index_cache, item, dry_run, results)?;
+            }
+            Ok(())
+        })
+    }
 
-    // Calculate and save new tree
-    // TODO: Use modified files to speed up calculation
-    // let modified_files = get_modified_files(dir, branch);
-    let new_tree = compute_tree_for_dir(tag.dir, None)?;
+    /// How many references the provider's tags have to the hash, counting each file with it
+    fn refcount(&self, hash: &[u8; ITEM_SIZE]) -> Result<u64> {
+        match &self.storage {
+            CacheStorage::Files { rev_tags, .. } => rev_tags.refcount(hash),
+            #[cfg(not(target_arch = "wasm32"))]
+            CacheStorage::Sqlite(index_db) => index_db.refcount(&self.provider_id, hash),
+        }
+    }
 
-    // Update last sync time
-    write_sync_time(tag);
+    #[cfg(test)]
+    fn get_rev_tags(&self, hash: &[u8; ITEM_SIZE]) -> Result<Vec<String>> {
+        match &self.storage {
+            CacheStorage::Files { rev_tags, .. } => rev_tags.get(hash),
+            #[cfg(not(target_arch = "wasm32"))]
+            CacheStorage::Sqlite(index_db) => index_db.rev_tags(&self.provider_id, hash),
+        }
+    }
+}
 
-    // Save new tree
-    new_tree.persist(&tree_path);
+/// A single file referenced by the sync results. Both paths are for display and for the
+/// extension, so a path that isn't valid UTF-8 has replacement characters in them
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncResultItem {
+    /// Where the file is now, under the tag's directory
+    pub path: String,
 
-    // Compute diff
-    let (add, remove) = diff(&old_tree, &new_tree);
+    /// The path within the tag's directory, as it's kept in the tree
+    #[serde(default)]
+    pub relative_path: String,
 
-    // Compute the four action types: compute, remove, add tag, remove tag,
-    // transform into desired format: [(path, hash), ...],
-    // and update .index_cache
-    let mut index_cache = IndexCache::new(tag);
+    /// Hex-encoded content hash of the file
+    pub hash: String,
 
-    let mut compute: Vec<(String, String)> = Vec::new();
-    let mut delete: Vec<(String, String)> = Vec::new();
-    let mut add_label: Vec<(String, String)> = Vec::new();
-    let mut remove_label: Vec<(String, String)> = Vec::new();
+    /// The file's chunks, for a file to compute with `SyncOptions::chunking`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<Chunk>,
+}
 
-    for item in add {
-        if !item.is_blob {
-            continue;
+impl SyncResultItem {
+    /// The item for a file in the tree of the tag whose directory is `dir`
+    pub fn new(dir: &Path, item: &ObjDescription) -> Self {
+        SyncResultItem {
+            path: dir.join(&item.path).to_string_lossy().into_owned(),
+            relative_path: item.path.to_string_lossy().into_owned(),
+            hash: hash_string(item.hash),
+            chunks: Vec::new(),
         }
-        let path = item.path.as_str().to_string();
-        let hash = hash_string(item.hash);
+    }
+}
 
-        // Need to specify between global and local contains
-        if index_cache.global_contains(&item.hash) {
-            add_label.push((path, hash));
+/// A file whose content was already indexed for the tag at another path, which it's no longer
+/// at. The content doesn't need computing again, only whatever is kept about its path updating
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RenamedItem {
+    /// Where the file was, under the tag's directory
+    pub old_path: String,
 
-            // Add to local cache
-            index_cache.add_global(&item);
-        } else {
-            compute.push((path, hash));
+    /// Where the file is now, under the tag's directory
+    pub new_path: String,
 
-            // Add to global and local cache
-            index_cache.add_global(&item);
-        }
-    }
+    /// `old_path` within the tag's directory, as it was kept in the tree
+    pub old_relative_path: String,
 
-    for item in remove {
-        if !item.is_blob {
-            continue;
-        }
-        if index_cache.global_contains(&item.hash) {
-            if IndexCache::get_rev_tags(&item.hash).len() <= 1 {
-                // If it's cached only for this tag, remove it from the global cache as well
-                index_cache.global_remove(&item);
-                let hash = hash_string(item.hash);
-                let path = item.path.as_str().to_string();
-                delete.push((path, hash));
-            } else {
-                // Otherwise, remove label, remove from local cache
-                index_cache.local_remove(&item);
-                let hash = hash_string(item.hash);
-                let path = item.path.as_str().to_string();
-                remove_label.push((path, hash));
-            }
-        } else {
-            // Should never happen
+    /// `new_path` within the tag's directory, as it's kept in the tree
+    pub new_relative_path: String,
+
+    /// Hex-encoded content hash of the file, the same at both paths
+    pub hash: String,
+}
+
+impl RenamedItem {
+    fn new(dir: &Path, old: &ObjDescription, new: &ObjDescription) -> Self {
+        let (old, new) = (SyncResultItem::new(dir, old), SyncResultItem::new(dir, new));
+        RenamedItem {
+            old_path: old.path,
+            new_path: new.path,
+            old_relative_path: old.relative_path,
+            new_relative_path: new.relative_path,
+            hash: new.hash,
         }
     }
-
-    Ok((compute, delete, add_label, remove_label))
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{sync::merkle::ObjectHash, utils::TempDirBuilder};
-    use std::fs::remove_file;
+/// The actions a consumer needs to take to bring its index up to date with a tag
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncResults {
+    /// Files that need to be newly computed or updated
+    pub compute: Vec<SyncResultItem>,
 
-    #[test]
-    fn test_disk_set() {
-        let path = "testfile";
-        let mut disk_set = DiskSet::new(path);
+    /// Files that need to be deleted from the index
+    pub delete: Vec<SyncResultItem>,
 
-        let item1: ObjectHash = [1; ITEM_SIZE];
-        let item2: ObjectHash = [20; ITEM_SIZE];
-        let item3: ObjectHash = [30; ITEM_SIZE];
+    /// Files that exist in the index but need to have a label added for this tag
+    pub add_tag: Vec<SyncResultItem>,
 
-        // Test add and contains
-        disk_set.add(&item1);
-        disk_set.add(&item2);
-        assert!(disk_set.contains(&item1));
-        assert!(disk_set.contains(&item2));
+    /// Files that exist in the index but need to have the label for this tag removed
+    pub remove_tag: Vec<SyncResultItem>,
 
-        // Test the exact contents of the file
-        disk_set.file.seek(SeekFrom::Start(0)).unwrap();
-        let mut buffer = [0; ITEM_SIZE];
-        disk_set.file.read_exact(&mut buffer).unwrap();
-        assert_eq!(buffer, item1);
-        disk_set.file.read_exact(&mut buffer).unwrap();
-        assert_eq!(buffer, item2);
+    /// Files that were moved or renamed within the tag, which are in none of the other lists
+    #[serde(default)]
+    pub renamed: Vec<RenamedItem>,
 
-        // Test remove
-        disk_set.remove(&item1);
-        assert!(!disk_set.contains(&item1));
-        assert!(disk_set.contains(&item2));
+    /// Problems that were worked around during the sync, such as files that were skipped
+    #[serde(default)]
+    pub warnings: Vec<SyncWarning>,
+}
 
-        // Test one more add
-        disk_set.add(&item3);
-        assert!(disk_set.contains(&item3));
+impl SyncResults {
+    /// Whether there's anything for the consumer to do, warnings aside
+    pub fn has_changes(&self) -> bool {
+        !(self.compute.is_empty()
+            && self.delete.is_empty()
+            && self.add_tag.is_empty()
+            && self.remove_tag.is_empty()
+            && self.renamed.is_empty())
+    }
 
-        // Test the length of the file
-        disk_set.file.seek(SeekFrom::Start(0)).unwrap();
-        let mut buffer = [0; ITEM_SIZE];
-        let mut count = 0;
-        while disk_set.file.read_exact(&mut buffer).is_ok() {
-            count += 1;
-        }
-        assert_eq!(count, 2);
+    /// How many files the consumer has something to do about, warnings aside
+    pub fn action_count(&self) -> usize {
+        self.compute.len()
+            + self.delete.len()
+            + self.add_tag.len()
+            + self.remove_tag.len()
+            + self.renamed.len()
+    }
 
-        // Clean up
-        remove_file(path).unwrap();
+    /// Add the results of another batch to these
+    pub fn append(&mut self, other: SyncResults) {
+        self.compute.extend(other.compute);
+        self.delete.extend(other.delete);
+        self.add_tag.extend(other.add_tag);
+        self.remove_tag.extend(other.remove_tag);
+        self.renamed.extend(other.renamed);
+        self.warnings.extend(other.warnings);
     }
 
-    #[test]
-    fn test_sync() {
-        let ti = std::time::Instant::now();
-        let tag = Tag {
-            dir: Path::new("../"),
-            branch: "nate/pyO3",
-            provider_id: "default",
-        };
-        let results = sync(&tag);
-        println!("Sync took {:?}", ti.elapsed());
-        // Vast majority (90+%) of this time is spent in compute_tree_for_dir
+    /// How many files and directories were left out because they couldn't be read
+    pub fn unreadable_count(&self) -> usize {
+        self.warnings
+            .iter()
+            .filter(|warning| warning.is_unreadable())
+            .count()
     }
 
-    #[test]
-    fn test_on_vscode_extension() {
-        let results = sync(&Tag {
-            dir: Path::new("../extensions/vscode"),
+    /// Whether anything in the directory changed while it was being synced, in which case the
+    /// results may mix files from before the change with ones from after it. Syncing again
+    /// once the changes settle catches up
+    pub fn possibly_stale(&self) -> bool {
+        self.warnings
+            .iter()
+            .any(|warning| matches!(warning, SyncWarning::ChangedDuringSync { .. }))
+    }
+}
+
+fn tree_path(tag: &Tag, index_root: &IndexRoot) -> PathBuf {
+    let mut path = index_root.tag_dir(tag);
+    path.push("merkle_tree");
+    path
+}
+
+fn load_saved_tree(
+    file_system: &dyn FileSystem,
+    tag: &Tag,
+    index_root: &IndexRoot,
+) -> Result<Tree> {
+    load_saved_tree_or_warn(file_system, tag, index_root, &mut Vec::new())
+}
+
+fn load_saved_tree_or_warn(
+    file_system: &dyn FileSystem,
+    tag: &Tag,
+    index_root: &IndexRoot,
+    warnings: &mut Vec<SyncWarning>,
+) -> Result<Tree> {
+    match Tree::load_in(file_system, &tree_path(tag, index_root)) {
+        Ok(tree) => Ok(tree),
+        // No tree has been saved yet
+        Err(SyncError::Io(err)) if err.kind() == ErrorKind::NotFound => Ok(Tree::default()),
+        // It can't be read back, so start from scratch
+        Err(SyncError::CorruptedIndex(message)) => {
+            warnings.push(SyncWarning::CorruptedTree { message });
+            Ok(Tree::default())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// The root hash of the tree saved by the tag's last sync, or None if it has never been synced
+pub fn get_root_hash(tag: &Tag, options: &SyncOptions) -> Result<Option<ObjectHash>> {
+    let canonical = tag.canonicalize();
+    let tag = &canonical.as_tag();
+    let index_root = options.resolve_index_root()?;
+    let file_system = &*options.resolve_index_file_system();
+    match Tree::load_root_hash_in(file_system, &tree_path(tag, &index_root)) {
+        Ok(hash) => Ok(Some(hash)),
+        Err(SyncError::Io(err)) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// All tags (for the given provider) whose last sync included a file with this content
+pub fn which_tags_contain(
+    hash: ObjectHash,
+    provider_id: &str,
+    options: &SyncOptions,
+) -> Result<Vec<OwnedTag>> {
+    let index_root = options.resolve_index_root()?;
+    let tag_strs = match options.storage {
+        IndexStorage::Files => RevTags::open(&index_root, provider_id, options)?.get(&hash)?,
+        #[cfg(not(target_arch = "wasm32"))]
+        IndexStorage::Sqlite => IndexDb::open(&index_root)?.rev_tags(provider_id, &hash)?,
+    };
+
+    // A tag appears once for every path it has with this content
+    let mut tags: Vec<OwnedTag> = Vec::new();
+    for tag_str in tag_strs {
+        let tag = tag_str
+            .parse::<OwnedTag>()
+            .map_err(|err| SyncError::CorruptedIndex(format!("{err} in rev_tags")))?;
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    Ok(tags)
+}
+
+/// Every file in the tree saved by the tag's last sync, in path order
+pub fn list_indexed_files(tag: &Tag, options: &SyncOptions) -> Result<Vec<SyncResultItem>> {
+    let canonical = tag.canonicalize();
+    let tag = &canonical.as_tag();
+    let index_root = options.resolve_index_root()?;
+    let tree = load_saved_tree(&*options.resolve_index_file_system(), tag, &index_root)?;
+    Ok(tree
+        .all_obj_descriptions()
+        .iter()
+        .filter(|item| item.is_blob)
+        .map(|item| SyncResultItem::new(tag.dir, item))
+        .collect())
+}
+
+/// The paths (under the tag's directory) of every file with this content in the tree saved by
+/// the tag's last sync
+pub fn find_paths_for_hash(
+    tag: &Tag,
+    hash: ObjectHash,
+    options: &SyncOptions,
+) -> Result<Vec<PathBuf>> {
+    let canonical = tag.canonicalize();
+    let tag = &canonical.as_tag();
+    let index_root = options.resolve_index_root()?;
+    let tree = load_saved_tree(&*options.resolve_index_file_system(), tag, &index_root)?;
+    Ok(tree
+        .objects()
+        .filter_map(ObjectRef::as_blob)
+        .filter(|blob| blob.hash() == hash)
+        .map(|blob| tag.dir.join(blob.path()))
+        .collect())
+}
+
+/// Whether syncing the tag now would find no changes, without producing a diff or touching
+/// any caches
+pub fn is_index_up_to_date(tag: &Tag, options: &SyncOptions) -> Result<bool> {
+    let canonical = tag.canonicalize();
+    let tag = &canonical.as_tag();
+    let options = &options.for_provider(tag.provider_id);
+    let index_root = options.resolve_index_root()?;
+    let file_system = &*options.resolve_index_file_system();
+    if file_system.metadata(&tree_path(tag, &index_root)).is_err() {
+        return Ok(false);
+    }
+
+    // Loading the whole tree means unchanged files don't have to be hashed again
+    let saved_tree = load_saved_tree(file_system, tag, &index_root)?;
+    let empty_tree = Tree::default();
+    let reuse = reusable_tree(tag, &index_root, options, &saved_tree, &empty_tree)?;
+    let new_tree = compute_tree_with_warnings(tag.dir, options, reuse, &mut Vec::new())?;
+    Ok(new_tree.hash() == saved_tree.hash())
+}
+
+/// Where a tag stands, as returned by `tag_status()`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TagStatus {
+    pub tag: OwnedTag,
+
+    /// Seconds since the epoch when the tag was last synced, or None if it never has been
+    pub last_sync: Option<u64>,
+
+    /// Hex-encoded root hash of the tree saved by the last sync
+    pub root_hash: Option<String>,
+
+    /// How many files the saved tree has
+    pub files: usize,
+
+    /// Whether a sync now would find nothing to change
+    pub up_to_date: bool,
+}
+
+/// When the tag was last synced, what its saved tree holds, and whether the directory has
+/// changed since. Nothing is written
+pub fn tag_status(tag: &Tag, options: &SyncOptions) -> Result<TagStatus> {
+    let canonical = tag.canonicalize();
+    let tag = &canonical.as_tag();
+    let last_sync = list_tags(options, Some(tag.dir), Some(tag.provider_id))?
+        .into_iter()
+        .find(|entry| entry.tag.branch == tag.branch)
+        .and_then(|entry| entry.last_sync);
+    Ok(TagStatus {
+        last_sync,
+        root_hash: get_root_hash(tag, options)?.map(hash_string),
+        files: list_indexed_files(tag, options)?.len(),
+        up_to_date: is_index_up_to_date(tag, options)?,
+        tag: canonical,
+    })
+}
+
+/// Diff the current contents of the tag's directory against the tree saved by the last sync,
+/// returning (added, removed). Unlike `sync()`, nothing on disk is updated
+pub fn diff_dir_against_saved(
+    tag: &Tag,
+    options: &SyncOptions,
+) -> Result<(Vec<ObjDescription>, Vec<ObjDescription>)> {
+    let canonical = tag.canonicalize();
+    let tag = &canonical.as_tag();
+    let options = &options.for_provider(tag.provider_id);
+    let index_root = &options.resolve_index_root()?;
+    let old_tree = load_saved_tree(&*options.resolve_index_file_system(), tag, index_root)?;
+    let empty_tree = Tree::default();
+    let reuse = reusable_tree(tag, index_root, options, &old_tree, &empty_tree)?;
+    let new_tree = compute_tree_with_warnings(tag.dir, options, reuse, &mut Vec::new())?;
+    let fold_case = merkle::fold_case(tag.dir, options);
+    Ok(merkle::diff_with(&old_tree, &new_tree, fold_case))
+}
+
+pub fn sync(tag: &Tag, options: &SyncOptions) -> Result<SyncResults> {
+    let _priority = LowPriority::enter(options.throttle.low_priority);
+    let canonical = tag.canonicalize();
+    let tag = &canonical.as_tag();
+    let options = &options.for_provider(tag.provider_id);
+    let index_root = &options.resolve_index_root()?;
+    let prepared = prepare_index(tag, index_root, options);
+
+    let mut warnings = Vec::new();
+    if let Err(err) = prepared {
+        let old_tree = load_saved_tree_or_warn(
+            &*options.resolve_index_file_system(),
+            tag,
+            index_root,
+            &mut warnings,
+        )?;
+        let (results, _) =
+            sync_read_only(tag, options, index_root, &old_tree, None, warnings, err)?;
+        return Ok(results);
+    }
+    let _lock = lock_provider(tag, index_root, options)?;
+    let old_tree = load_saved_tree_or_warn(
+        &*options.resolve_index_file_system(),
+        tag,
+        index_root,
+        &mut warnings,
+    )?;
+    let (results, _) = sync_from(
+        tag, options, index_root, &old_tree, None, &mut None, warnings,
+    )
+    .map_err(|err| writable::classify(err, index_root.path()))?;
+    Ok(results)
+}
+
+/// Get the index ready for a sync to change the tag's part of it: create the index if it's new
+/// (see `migrate::create_index`), move the tag's directory from where an older version put it
+/// (see `migrate::migrate_old_tag_dir`), create its directories, and make sure they can be
+/// written to before anything is (see `writable.rs`). A dry run only needs the directories
+fn prepare_index(tag: &Tag, index_root: &IndexRoot, options: &SyncOptions) -> Result<()> {
+    migrate::create_index(&*options.resolve_index_file_system(), index_root)
+        .map_err(|err| writable::write_error(err, index_root.path()))?;
+    if !options.dry_run {
+        migrate::migrate_old_tag_dir(&*options.resolve_index_file_system(), index_root, tag);
+        let min_free_space = options.resolve_min_free_space();
+        match &options.index_file_system {
+            Some(file_system) => {
+                writable::check_writable_in(&**file_system, &index_root.tag_dir(tag))?
+            }
+            None => writable::check_writable(&index_root.tag_dir(tag), min_free_space)?,
+        }
+    }
+    create_tag_dirs(tag, index_root, options)
+        .map_err(|err| writable::classify(err, index_root.path()))
+}
+
+/// The results of a sync of a tag whose last tree is `old_tree` that can't write to the index
+/// (`err`), if `SyncOptions::read_only_fallback` allows it, and the new tree. Nothing is saved
+/// and the caches aren't opened, so whether content has been computed before can't be known:
+/// new content is reported as `compute` and content that's gone as `remove_tag`, never
+/// `delete`, in case another tag still has it. `changed` is as for `sync_from`
+fn sync_read_only(
+    tag: &Tag,
+    options: &SyncOptions,
+    index_root: &IndexRoot,
+    old_tree: &Tree,
+    changed: Option<&[PathBuf]>,
+    mut warnings: Vec<SyncWarning>,
+    err: SyncError,
+) -> Result<(SyncResults, Tree)> {
+    match err {
+        SyncError::ReadOnlyIndex(_) | SyncError::DiskFull(_) if options.read_only_fallback => {}
+        err => return Err(err),
+    }
+    warnings.push(SyncWarning::IndexNotWritable {
+        index_root: index_root.path().display().to_string(),
+        message: err.to_string(),
+    });
+    warnings.extend(index_root.fallback_warning());
+
+    let empty_tree = Tree::default();
+    let reuse = reusable_tree(tag, index_root, options, old_tree, &empty_tree)?;
+    let changed = changed.filter(|_| std::ptr::eq(reuse, old_tree));
+    let new_tree = update_tree(tag.dir, options, reuse, changed, &mut warnings)?;
+    let fold_case = merkle::fold_case(tag.dir, options);
+    let (add, remove) = merkle::diff_with(old_tree, &new_tree, fold_case);
+    let pending: HashSet<ObjDescription> =
+        read_pending(&*options.resolve_index_file_system(), tag, index_root)?
+            .into_iter()
+            .collect();
+    let mut add: Vec<ObjDescription> = add.into_iter().filter(|item| item.is_blob).collect();
+    let adding: HashSet<ObjDescription> = add.iter().cloned().collect();
+    add.extend(
+        new_tree
+            .all_obj_descriptions()
+            .into_iter()
+            .filter(|item| pending.contains(item) && !adding.contains(item)),
+    );
+    let remove: Vec<ObjDescription> = remove
+        .into_iter()
+        .filter(|item| item.is_blob && !pending.contains(item))
+        .collect();
+
+    let mut results = SyncResults {
+        warnings,
+        ..Default::default()
+    };
+    // Content in the old tree has been reported before, by the sync that found it
+    let (add, remove) = pair_renames(tag.dir, &add, &remove, &mut results, |_| Ok(true))?;
+    let mut added = HashSet::new();
+    let mut computing = Vec::new();
+    for item in &add {
+        let result = SyncResultItem::new(tag.dir, item);
+        match added.insert(item.hash) {
+            true => {
+                results.compute.push(result);
+                computing.push(item);
+            }
+            false => results.add_tag.push(result),
+        }
+    }
+    read_computed(tag.dir, None, options, &computing, 0, &mut results)?;
+    results.remove_tag = remove
+        .iter()
+        .map(|item| SyncResultItem::new(tag.dir, item))
+        .collect();
+    Ok((results, new_tree))
+}
+
+/// The caches in `index_cache`, opening them first if this is the first time they're needed
+fn open_index_cache<'a>(
+    index_cache: &'a mut Option<IndexCache>,
+    tag: &Tag,
+    index_root: &IndexRoot,
+    options: &SyncOptions,
+) -> Result<&'a mut IndexCache> {
+    if index_cache.is_none() {
+        *index_cache = Some(IndexCache::new(tag, index_root, options)?);
+    }
+    Ok(index_cache.as_mut().unwrap())
+}
+
+/// Keep other syncs away from the caches shared by the provider's tags, and from this tag's
+/// tree, until the lock is dropped
+fn lock_provider(tag: &Tag, index_root: &IndexRoot, options: &SyncOptions) -> Result<IndexLock> {
+    lock::lock_index(
+        options,
+        &index_root.provider_lock(tag.provider_id),
+        options.lock_wait,
+    )
+}
+
+fn create_tag_dirs(tag: &Tag, index_root: &IndexRoot, options: &SyncOptions) -> Result<()> {
+    // Make sure that the tag directory exists
+    // Create the directory and all its parent directories if they don't exist
+    let file_system = options.resolve_index_file_system();
+    file_system.create_dir_all(&index_root.tag_dir(tag))?;
+    file_system.create_dir_all(&index_root.provider_dir(tag.provider_id))?;
+    Ok(())
+}
+
+/// The tree whose hashes can be reused for files whose size and mtime haven't changed: the
+/// saved one, unless it was hashed with another normalization, in which case every file needs
+/// hashing again
+fn reusable_tree<'a>(
+    tag: &Tag,
+    index_root: &IndexRoot,
+    options: &SyncOptions,
+    saved_tree: &'a Tree,
+    empty_tree: &'a Tree,
+) -> Result<&'a Tree> {
+    match read_normalization(&*options.resolve_index_file_system(), tag, index_root)?
+        == options.content_normalization
+    {
+        true => Ok(saved_tree),
+        false => Ok(empty_tree),
+    }
+}
+
+/// Sync a tag whose last saved tree is `old_tree`, returning the results and the new tree.
+/// `warnings` are any problems found before getting here. The caches are only opened (into
+/// `index_cache`) if something changed. If `changed` is given, only those paths are looked at
+/// again, as in `merkle::update_tree`
+fn sync_from(
+    tag: &Tag,
+    options: &SyncOptions,
+    index_root: &IndexRoot,
+    old_tree: &Tree,
+    changed: Option<&[PathBuf]>,
+    index_cache: &mut Option<IndexCache>,
+    mut warnings: Vec<SyncWarning>,
+) -> Result<(SyncResults, Tree)> {
+    warnings.extend(index_root.fallback_warning());
+    let force = options.force || options.clear_tag_cache;
+    let empty_tree = Tree::default();
+    let mut old_tree = old_tree;
+    let pending: HashSet<ObjDescription> =
+        read_pending(&*options.resolve_index_file_system(), tag, index_root)?
+            .into_iter()
+            .collect();
+
+    if options.clear_tag_cache {
+        // Drop this tag's references to everything in the old tree, so that the whole
+        // directory is added back below. Hashes shared with other tags stay in the global cache
+        if !options.dry_run {
+            open_index_cache(index_cache, tag, index_root, options)?.bulk(|index_cache| {
+                for item in old_tree.all_obj_descriptions() {
+                    if item.is_blob && !pending.contains(&item) {
+                        index_cache.local_remove(&item)?;
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        old_tree = &empty_tree;
+    }
+
+    // Calculate and save new tree, only rehashing files whose size or mtime changed
+    let renormalize = read_normalization(&*options.resolve_index_file_system(), tag, index_root)?
+        != options.content_normalization;
+    let reuse = match renormalize {
+        true => &empty_tree,
+        false => old_tree,
+    };
+    // A tree that can't be reused can't be updated either, so everything is walked then
+    let changed = changed.filter(|_| std::ptr::eq(reuse, old_tree));
+    let new_tree = update_tree(tag.dir, options, reuse, changed, &mut warnings)?;
+
+    if !force && new_tree.hash() == old_tree.hash() && pending.is_empty() {
+        // Nothing changed, so there's nothing to save or look up in the caches
+        if !options.dry_run {
+            write_sync_time(tag, index_root, options)?;
+            if renormalize {
+                write_normalization(tag, index_root, options)?;
+            }
+        }
+        let results = SyncResults {
+            warnings,
+            ..Default::default()
+        };
+        return Ok((results, new_tree));
+    }
+
+    if !options.dry_run {
+        // Update last sync time
+        write_sync_time(tag, index_root, options)?;
+        write_tag_file(tag, index_root, options)?;
+
+        // Save new tree
+        new_tree.persist_with(&tree_path(tag, index_root), options)?;
+        if renormalize {
+            write_normalization(tag, index_root, options)?;
+        }
+    }
+
+    // Compute diff
+    let fold_case = merkle::fold_case(tag.dir, options);
+    let (mut add, mut remove) = merkle::diff_with(old_tree, &new_tree, fold_case);
+    if !pending.is_empty() {
+        // Files still waiting for `mark_computed` are reported again, and were never in the
+        // caches to be removed from them
+        let adding: HashSet<&ObjDescription> = add.iter().collect();
+        let still_pending: Vec<ObjDescription> = new_tree
+            .all_obj_descriptions()
+            .into_iter()
+            .filter(|item| pending.contains(item) && !adding.contains(item))
+            .collect();
+        add.extend(still_pending);
+        remove.retain(|item| !pending.contains(item));
+    }
+
+    let mut results = SyncResults {
+        warnings,
+        ..Default::default()
+    };
+    let unchanged: Vec<ObjDescription> = match force {
+        true => {
+            let adding: HashSet<(&ObjectHash, &Path)> = add
+                .iter()
+                .map(|item| (&item.hash, item.path.as_path()))
+                .collect();
+            new_tree
+                .all_obj_descriptions()
+                .into_iter()
+                .filter(|item| item.is_blob && !adding.contains(&(&item.hash, &item.path)))
+                .collect()
+        }
+        false => Vec::new(),
+    };
+    let add: Vec<ObjDescription> = add.into_iter().filter(|item| item.is_blob).collect();
+    let mut added = Added::default();
+    apply_changes(
+        open_index_cache(index_cache, tag, index_root, options)?,
+        options,
+        &unchanged,
+        &add,
+        &remove,
+        &mut added,
+        &mut results,
+    )?;
+    if !options.dry_run {
+        write_pending(tag, index_root, &added.held_back, options)?;
+    }
+
+    Ok((results, new_tree))
+}
+
+/// The files added so far by one sync, which may span several calls to `apply_changes`
+#[derive(Default)]
+struct Added {
+    /// Content added at any path
+    hashes: HashSet<ObjectHash>,
+
+    /// Files kept out of the caches by `SyncOptions::defer_compute`
+    held_back: Vec<ObjDescription>,
+}
+
+/// Compute the four action types (compute, remove, add tag, remove tag) for a set of changes
+/// to a tag's files, and update the caches unless it's a dry run. `unchanged` files are only
+/// reported when forcing. `added` is what was already added earlier in the same sync
+fn apply_changes(
+    index_cache: &mut IndexCache,
+    options: &SyncOptions,
+    unchanged: &[ObjDescription],
+    add: &[ObjDescription],
+    remove: &[ObjDescription],
+    added: &mut Added,
+    results: &mut SyncResults,
+) -> Result<()> {
+    let force = options.force || options.clear_tag_cache;
+    let mut computing: Vec<&ObjDescription> = Vec::new();
+    let computing_from = results.compute.len();
+    if force {
+        // Unchanged files are already recorded in the caches, so only report them
+        let dir = &index_cache.dir;
+        results
+            .compute
+            .extend(unchanged.iter().map(|item| SyncResultItem::new(dir, item)));
+        computing.extend(unchanged);
+    }
+
+    let (add, remove) = match force {
+        true => (add.to_vec(), remove.to_vec()),
+        false => {
+            let dir = index_cache.dir.clone();
+            pair_renames(&dir, add, remove, results, |hash| {
+                index_cache.global_contains(hash)
+            })?
+        }
+    };
+
+    // The same content can be added at more than one path, but only needs computing once
+    let mut cache = Vec::with_capacity(add.len());
+    for item in &add {
+        // Need to specify between global and local contains
+        let computed = index_cache.global_contains(&item.hash)?;
+        let result = SyncResultItem::new(&index_cache.dir, item);
+        if !force && (added.hashes.contains(&item.hash) || computed) {
+            results.add_tag.push(result);
+        } else {
+            results.compute.push(result);
+            computing.push(item);
+        }
+        added.hashes.insert(item.hash);
+
+        match options.defer_compute && !computed {
+            true => added.held_back.push(item.clone()),
+            false => cache.push(item.clone()),
+        }
+    }
+
+    let objects = index_cache.objects.as_ref().filter(|_| !options.dry_run);
+    read_computed(
+        &index_cache.dir,
+        objects,
+        options,
+        &computing,
+        computing_from,
+        results,
+    )?;
+
+    // Add to global and local cache
+    if !options.dry_run {
+        index_cache.add_bulk(&cache)?;
+    }
+
+    index_cache.remove_bulk(&remove, options.dry_run, results)
+}
+
+/// Read the files to compute again for what's kept of their content: saved in `objects`, with
+/// `SyncOptions::store_objects`, and split into chunks for their results (those from
+/// `computing_from` on), with `SyncOptions::chunking`
+fn read_computed(
+    dir: &Path,
+    objects: Option<&ObjectStore>,
+    options: &SyncOptions,
+    computing: &[&ObjDescription],
+    computing_from: usize,
+    results: &mut SyncResults,
+) -> Result<()> {
+    // Content that's saved already is only read again to be chunked
+    let mut seen = HashSet::new();
+    let items: Vec<&ObjDescription> = computing
+        .iter()
+        .copied()
+        .filter(|item| seen.insert(item.hash))
+        .filter(|item| {
+            options.chunking.is_some()
+                || objects.is_some_and(|objects| !objects.contains(&item.hash))
+        })
+        .collect();
+    if items.is_empty() {
+        return Ok(());
+    }
+    let chunks = Mutex::new(HashMap::new());
+    let warnings = merkle::for_each_hashed_file(dir, &items, options, |item, contents| {
+        if let Some(objects) = objects.filter(|objects| !objects.contains(&item.hash)) {
+            objects.put(&item.hash, contents)?;
+        }
+        if let Some(chunking) = options.chunking {
+            let hash = hash_string(item.hash);
+            chunks
+                .lock()
+                .unwrap()
+                .insert(hash, chunk::chunk(&item.path, contents, chunking));
+        }
+        Ok(())
+    })?;
+    results.warnings.extend(warnings);
+    let chunks = chunks.into_inner().unwrap();
+    for result in &mut results.compute[computing_from..] {
+        if let Some(chunks) = chunks.get(&result.hash) {
+            result.chunks = chunks.clone();
+        }
+    }
+    Ok(())
+}
+
+/// Report each added file with the same content as a removed one as `renamed` from it, as long
+/// as that content has been `computed`. Neither is in the caches' changes, since the tag still
+/// references the content as many times. Returns the adds and removes left over, in order
+fn pair_renames(
+    dir: &Path,
+    add: &[ObjDescription],
+    remove: &[ObjDescription],
+    results: &mut SyncResults,
+    mut computed: impl FnMut(&ObjectHash) -> Result<bool>,
+) -> Result<(Vec<ObjDescription>, Vec<ObjDescription>)> {
+    let mut removed_at: HashMap<ObjectHash, VecDeque<usize>> = HashMap::new();
+    for (index, item) in remove.iter().enumerate().filter(|(_, item)| item.is_blob) {
+        removed_at.entry(item.hash).or_default().push_back(index);
+    }
+    let mut paired = vec![false; remove.len()];
+    let mut unpaired = Vec::with_capacity(add.len());
+    for item in add {
+        let index = match removed_at.get_mut(&item.hash) {
+            Some(indexes) if computed(&item.hash)? => indexes.pop_front(),
+            _ => None,
+        };
+        match index {
+            Some(index) => {
+                paired[index] = true;
+                let renamed = RenamedItem::new(dir, &remove[index], item);
+                results.renamed.push(renamed);
+            }
+            None => unpaired.push(item.clone()),
+        }
+    }
+    let remove = remove
+        .iter()
+        .zip(paired)
+        .filter(|(_, paired)| !paired)
+        .map(|(item, _)| item.clone())
+        .collect();
+    Ok((unpaired, remove))
+}
+
+/// The changes `sync_in_batches` has found but not yet applied
+struct Batch<'a> {
+    index_cache: IndexCache,
+    options: &'a SyncOptions,
+    size: usize,
+    on_batch: &'a mut dyn FnMut(SyncResults) -> Result<()>,
+    unchanged: Vec<ObjDescription>,
+    add: Vec<ObjDescription>,
+    remove: Vec<ObjDescription>,
+    added: Added,
+
+    /// Files held back by an earlier sync, which are reported again and never removed
+    pending: HashSet<ObjDescription>,
+}
+
+impl Batch<'_> {
+    fn len(&self) -> usize {
+        self.unchanged.len() + self.add.len() + self.remove.len()
+    }
+
+    fn push(&mut self, change: BlobChange) -> Result<()> {
+        match change {
+            BlobChange::Add(item) => self.add.push(item),
+            BlobChange::Remove(item) if self.pending.contains(&item) => {}
+            BlobChange::Remove(item) => self.remove.push(item),
+            BlobChange::Unchanged(item) if self.pending.contains(&item) => self.add.push(item),
+            BlobChange::Unchanged(item) if self.options.force || self.options.clear_tag_cache => {
+                self.unchanged.push(item)
+            }
+            BlobChange::Unchanged(_) => {}
+        }
+        if self.len() >= self.size {
+            self.flush(Vec::new())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, warnings: Vec<SyncWarning>) -> Result<()> {
+        if self.len() == 0 && warnings.is_empty() {
+            return Ok(());
+        }
+        let mut results = SyncResults {
+            warnings,
+            ..Default::default()
+        };
+        apply_changes(
+            &mut self.index_cache,
+            self.options,
+            &self.unchanged,
+            &self.add,
+            &self.remove,
+            &mut self.added,
+            &mut results,
+        )?;
+        self.unchanged.clear();
+        self.add.clear();
+        self.remove.clear();
+        if !self.options.dry_run && !self.options.defer_compute {
+            // Once added, the content is in the global cache, so there's no need to keep it
+            self.added.hashes.clear();
+        }
+        (self.on_batch)(results)?;
+        if let Some(pause) = self.options.throttle.batch_pause {
+            thread::sleep(pause);
+        }
+        Ok(())
+    }
+}
+
+/// Like `sync()`, but for directories too big to keep in memory all at once. The directory is
+/// walked, compared with the saved tree and saved again one subdirectory at a time, so memory
+/// use grows with how deep the directory is rather than how many files it has. The results are
+/// handed to `on_batch` as they're found, in batches of up to `batch_size` files, and any
+/// warnings come with the last batch.
+///
+/// Each batch is applied to the caches on its own, so content that moves from one path to
+/// another in a different batch can be reported as `delete` and `compute` rather than
+/// `remove_tag` and `add_tag`. A workspace read through `SyncOptions::file_system`, or synced
+/// with `SyncOptions::archives`, is synced whole, and handed over as one batch
+pub fn sync_in_batches(
+    tag: &Tag,
+    options: &SyncOptions,
+    batch_size: usize,
+    mut on_batch: impl FnMut(SyncResults) -> Result<()>,
+) -> Result<()> {
+    let _priority = LowPriority::enter(options.throttle.low_priority);
+    if options.file_system.is_some() || options.archives {
+        return on_batch(sync(tag, options)?);
+    }
+    let canonical = tag.canonicalize();
+    let tag = &canonical.as_tag();
+    let options = &options.for_provider(tag.provider_id);
+    let index_root = &options.resolve_index_root()?;
+    if let Err(err) = prepare_index(tag, index_root, options) {
+        // Without saving the tree as it goes there's no saving memory either, so the whole
+        // directory is compared at once and handed over as one batch
+        let mut warnings = Vec::new();
+        let old_tree = load_saved_tree_or_warn(
+            &*options.resolve_index_file_system(),
+            tag,
+            index_root,
+            &mut warnings,
+        )?;
+        let (results, _) =
+            sync_read_only(tag, options, index_root, &old_tree, None, warnings, err)?;
+        return on_batch(results);
+    }
+    let _lock = lock_provider(tag, index_root, options)?;
+    sync_batches_from(tag, options, index_root, batch_size, &mut on_batch)
+        .map_err(|err| writable::classify(err, index_root.path()))
+}
+
+/// The rest of `sync_in_batches`, once the index is ready and locked
+fn sync_batches_from(
+    tag: &Tag,
+    options: &SyncOptions,
+    index_root: &IndexRoot,
+    batch_size: usize,
+    on_batch: &mut dyn FnMut(SyncResults) -> Result<()>,
+) -> Result<()> {
+    let tree_path = tree_path(tag, index_root);
+    let renormalize = read_normalization(&*options.resolve_index_file_system(), tag, index_root)?
+        != options.content_normalization;
+    let mut index_cache = IndexCache::new(tag, index_root, options)?;
+    let mut warnings: Vec<SyncWarning> = index_root.fallback_warning().into_iter().collect();
+    let pending: HashSet<ObjDescription> =
+        read_pending(&*options.resolve_index_file_system(), tag, index_root)?
+            .into_iter()
+            .collect();
+
+    if options.clear_tag_cache {
+        // The same as in `sync_from`, but without loading the old tree
+        index_cache.bulk(|index_cache| {
+            merkle::for_each_saved_blob(&tree_path, options, &mut warnings, &mut |item| {
+                match options.dry_run || pending.contains(&item) {
+                    true => Ok(()),
+                    false => index_cache.local_remove(&item),
+                }
+            })
+        })?;
+    }
+
+    let mut batch = Batch {
+        index_cache,
+        options,
+        size: batch_size.max(1),
+        on_batch,
+        unchanged: Vec::new(),
+        add: Vec::new(),
+        remove: Vec::new(),
+        added: Added::default(),
+        pending,
+    };
+    merkle::stream_diff_dir(
+        tag.dir,
+        &tree_path,
+        options.clear_tag_cache,
+        renormalize,
+        options,
+        &mut warnings,
+        &mut |change| batch.push(change),
+    )?;
+    batch.flush(warnings)?;
+
+    if !options.dry_run {
+        write_sync_time(tag, index_root, options)?;
+        write_tag_file(tag, index_root, options)?;
+        write_pending(tag, index_root, &batch.added.held_back, options)?;
+        if renormalize {
+            write_normalization(tag, index_root, options)?;
+        }
+    }
+    Ok(())
+}
+
+/// Record that a blob is no longer part of the tag, as either a delete or a remove_tag action
+fn remove_from_tag(
+    index_cache: &mut IndexCache,
+    item: &ObjDescription,
+    dry_run: bool,
+    results: &mut SyncResults,
+) -> Result<()> {
+    if !item.is_blob {
+        return Ok(());
+    }
+    if !index_cache.global_contains(&item.hash)? {
+        // Should never happen
+        return Ok(());
+    }
+
+    // The file's own reference is dropped first, so whether anything still needs the content
+    // is what's left of the count, however the removes and adds before it were interleaved
+    let last_reference = match dry_run {
+        true => index_cache.refcount(&item.hash)? <= 1,
+        false => {
+            index_cache.local_remove(item)?;
+            index_cache.refcount(&item.hash)? == 0
+        }
+    };
+    if last_reference {
+        // Nothing else uses it, so remove it from the global cache as well
+        if !dry_run {
+            index_cache.global_remove(item)?;
+        }
+        results
+            .delete
+            .push(SyncResultItem::new(&index_cache.dir, item));
+    } else {
+        results
+            .remove_tag
+            .push(SyncResultItem::new(&index_cache.dir, item));
+    }
+    Ok(())
+}
+
+/// Forget a tag entirely, e.g. after its branch or workspace is deleted
+///
+/// Everything in the tag's last synced tree is reported as `delete` if no other tag uses it, or
+/// `remove_tag` otherwise, and all of the tag's files in the index are removed
+pub fn delete_tag(tag: &Tag, options: &SyncOptions) -> Result<SyncResults> {
+    let canonical = tag.canonicalize();
+    let tag = &canonical.as_tag();
+    let index_root = &options.resolve_index_root()?;
+    let file_system = &*options.resolve_index_file_system();
+    let tag_dir = index_root.tag_dir(tag);
+    let mut results = SyncResults::default();
+    if !file_system
+        .metadata(&tag_dir)
+        .is_ok_and(|metadata| metadata.is_dir)
+    {
+        return Ok(results);
+    }
+
+    prepare_index(tag, index_root, options)?;
+    let _lock = lock_provider(tag, index_root, options)?;
+    let old_tree = load_saved_tree(file_system, tag, index_root)?;
+    let pending: HashSet<ObjDescription> = read_pending(file_system, tag, index_root)?
+        .into_iter()
+        .collect();
+    let mut removed = old_tree.all_obj_descriptions();
+    removed.retain(|item| !pending.contains(item));
+    let mut index_cache = IndexCache::new(tag, index_root, options)?;
+    index_cache.remove_bulk(&removed, options.dry_run, &mut results)?;
+    drop(index_cache);
+
+    if !options.dry_run {
+        #[cfg(not(target_arch = "wasm32"))]
+        if options.storage == IndexStorage::Sqlite {
+            IndexDb::open(index_root)?.delete_tag(&tag.to_string())?;
+        }
+        let subtrees_dir = merkle::subtrees_dir(&tag_dir.join("merkle_tree"));
+        let subtrees = match file_system.read_dir(&subtrees_dir) {
+            Ok(names) => names,
+            Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let subtrees = subtrees.into_iter().map(|name| subtrees_dir.join(name));
+        let files = [
+            "merkle_tree",
+            ".index_cache",
+            ".index_cache.sorted",
+            ".last_sync",
+            ".tag",
+            ".pending",
+            ".normalization",
+        ];
+        for path in subtrees.chain(files.iter().map(|name| tag_dir.join(name))) {
+            match file_system.remove_file(&path) {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+        let _ = file_system.remove_dir(&subtrees_dir);
+        // Leave the directory alone if another tag is nested inside it (branch names can
+        // contain slashes)
+        let _ = file_system.remove_dir(&tag_dir);
+    }
+
+    Ok(results)
+}
+
+/// Add the files a sync with `SyncOptions::defer_compute` held back to the caches, once the
+/// caller has finished computing the ones with these `hashes`. Until then, each sync reports
+/// them as `compute` again
+pub fn mark_computed(tag: &Tag, hashes: &[ObjectHash], options: &SyncOptions) -> Result<()> {
+    let canonical = tag.canonicalize();
+    let tag = &canonical.as_tag();
+    let index_root = &options.resolve_index_root()?;
+    let file_system = &*options.resolve_index_file_system();
+    if read_pending(file_system, tag, index_root)?.is_empty() {
+        return Ok(());
+    }
+
+    prepare_index(tag, index_root, options)?;
+    let _lock = lock_provider(tag, index_root, options)?;
+    let hashes: HashSet<&ObjectHash> = hashes.iter().collect();
+    let (computed, pending): (Vec<ObjDescription>, Vec<ObjDescription>) =
+        read_pending(file_system, tag, index_root)?
+            .into_iter()
+            .partition(|item| hashes.contains(&item.hash));
+    if !options.dry_run && !computed.is_empty() {
+        IndexCache::new(tag, index_root, options)?.add_bulk(&computed)?;
+        write_pending(tag, index_root, &pending, options)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sync::merkle::ObjectHash,
+        utils::{temp_index_root, TempDirBuilder},
+    };
+    use std::{
+        fs::{self, remove_file, File},
+        io,
+        time::{Duration, Instant},
+    };
+
+    fn os_file_system() -> Arc<dyn FileSystem> {
+        Arc::new(OsFileSystem)
+    }
+
+    /// The contents of a `DiskSet` file holding `items`
+    fn encoded(items: &[ObjectHash]) -> Vec<u8> {
+        let mut contents = io::Cursor::new(Vec::new());
+        DiskSet::write_items(&mut contents, items).unwrap();
+        contents.into_inner()
+    }
+
+    #[test]
+    fn test_disk_set() {
+        let path = "testfile";
+        let mut disk_set = DiskSet::new(os_file_system(), path).unwrap();
+
+        let item1: ObjectHash = [1; ITEM_SIZE];
+        let item2: ObjectHash = [20; ITEM_SIZE];
+        let item3: ObjectHash = [30; ITEM_SIZE];
+
+        // Test add and contains
+        disk_set.add(&item1).unwrap();
+        disk_set.add(&item2).unwrap();
+        assert!(disk_set.contains(&item1).unwrap());
+        assert!(disk_set.contains(&item2).unwrap());
+
+        // Adds are buffered until flushed
+        assert_eq!(fs::metadata(path).unwrap().len(), DiskSet::file_len(0));
+        disk_set.flush().unwrap();
+
+        // Test the exact contents of the file
+        disk_set
+            .file
+            .seek(SeekFrom::Start(DiskSet::offset(0)))
+            .unwrap();
+        let mut buffer = [0; ITEM_SIZE];
+        disk_set.file.read_exact(&mut buffer).unwrap();
+        assert_eq!(buffer, item1);
+        disk_set.file.read_exact(&mut buffer).unwrap();
+        assert_eq!(buffer, item2);
+
+        // Test remove
+        disk_set.remove(&item1).unwrap();
+        assert!(!disk_set.contains(&item1).unwrap());
+        assert!(disk_set.contains(&item2).unwrap());
+
+        // Test one more add
+        disk_set.add(&item3).unwrap();
+        assert!(disk_set.contains(&item3).unwrap());
+
+        // Test the length of the file
+        disk_set.flush().unwrap();
+        assert_eq!(
+            DiskSet::read(&OsFileSystem, Path::new(path)).unwrap().len(),
+            2
+        );
+
+        // Clean up
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_disk_set_reopen() {
+        let temp_dir = TempDirBuilder::new().create();
+        let path = temp_dir.path().join(".index_cache");
+        let items: Vec<ObjectHash> = (0..5).map(|i| [i; ITEM_SIZE]).collect();
+
+        let mut disk_set = DiskSet::new(os_file_system(), &path).unwrap();
+        for item in &items {
+            disk_set.add(item).unwrap();
+        }
+        disk_set.remove(&items[1]).unwrap();
+        disk_set.remove(&items[4]).unwrap();
+        drop(disk_set);
+
+        let mut disk_set = DiskSet::new(os_file_system(), &path).unwrap();
+        for (i, item) in items.iter().enumerate() {
+            assert_eq!(disk_set.contains(item).unwrap(), i != 1 && i != 4);
+        }
+        assert_eq!(fs::metadata(&path).unwrap().len(), DiskSet::file_len(3));
+
+        // Removes are written together on flush, even of items that were never written
+        let before = fs::read(&path).unwrap();
+        disk_set.add(&[9; ITEM_SIZE]).unwrap();
+        disk_set.add(&[10; ITEM_SIZE]).unwrap();
+        disk_set.remove(&items[0]).unwrap();
+        disk_set.remove(&[9; ITEM_SIZE]).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), before);
+        disk_set.flush().unwrap();
+        let mut contents = DiskSet::read(&OsFileSystem, &path).unwrap();
+        contents.sort();
+        assert_eq!(contents, [items[2], items[3], [10; ITEM_SIZE]]);
+        drop(disk_set);
+
+        // Duplicates written by something else are dropped
+        fs::write(
+            &path,
+            encoded(&[[7; ITEM_SIZE], [8; ITEM_SIZE], [7; ITEM_SIZE]]),
+        )
+        .unwrap();
+        let mut disk_set = DiskSet::new(os_file_system(), &path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), DiskSet::file_len(2));
+        disk_set.remove(&[7; ITEM_SIZE]).unwrap();
+        assert!(!disk_set.contains(&[7; ITEM_SIZE]).unwrap());
+        assert!(DiskSet::new(os_file_system(), &path)
+            .unwrap()
+            .contains(&[8; ITEM_SIZE])
+            .unwrap());
+    }
+
+    #[test]
+    fn test_disk_set_bloom_filter() {
+        let temp_dir = TempDirBuilder::new().create();
+        let path = temp_dir.path().join(".index_cache");
+        let bloom_path = temp_dir.path().join(".index_cache.bloom");
+        let items: Vec<ObjectHash> = (0..5).map(|i| [i; ITEM_SIZE]).collect();
+
+        let mut disk_set = DiskSet::with_bloom_filter(os_file_system(), &path).unwrap();
+        for item in &items {
+            disk_set.add(item).unwrap();
+        }
+        disk_set.remove(&items[0]).unwrap();
+        drop(disk_set);
+        assert!(bloom_path.exists());
+
+        // The saved filter is used as is, so the file isn't read until an item might be in it
+        let mut disk_set = DiskSet::with_bloom_filter(os_file_system(), &path).unwrap();
+        assert!(disk_set.loaded.is_none());
+        assert!(!disk_set.contains(&[100; ITEM_SIZE]).unwrap());
+        disk_set.add(&[100; ITEM_SIZE]).unwrap();
+        assert!(disk_set.loaded.is_none());
+        assert!(disk_set.contains(&items[1]).unwrap());
+        assert!(!disk_set.contains(&items[0]).unwrap());
+        drop(disk_set);
+
+        // Changed behind the filter's back, so the filter is rebuilt instead of trusted
+        let mut contents = DiskSet::read(&OsFileSystem, &path).unwrap();
+        contents.push([200; ITEM_SIZE]);
+        fs::write(&path, encoded(&contents)).unwrap();
+        let mut disk_set = DiskSet::with_bloom_filter(os_file_system(), &path).unwrap();
+        assert!(disk_set.contains(&[200; ITEM_SIZE]).unwrap());
+        assert!(disk_set.contains(&[100; ITEM_SIZE]).unwrap());
+        drop(disk_set);
+
+        fs::write(&bloom_path, "garbage").unwrap();
+        let mut disk_set = DiskSet::with_bloom_filter(os_file_system(), &path).unwrap();
+        assert!(disk_set.contains(&[200; ITEM_SIZE]).unwrap());
+    }
+
+    #[test]
+    fn test_disk_set_compaction() {
+        let temp_dir = TempDirBuilder::new().create();
+        let path = temp_dir.path().join(".index_cache");
+        let items: Vec<ObjectHash> = [5, 3, 9, 1, 7].iter().map(|i| [*i; ITEM_SIZE]).collect();
+
+        let mut disk_set = DiskSet::with_bloom_filter(os_file_system(), &path).unwrap();
+        for item in &items {
+            disk_set.add(item).unwrap();
+        }
+        disk_set.compact().unwrap();
+        let mut sorted = items.clone();
+        sorted.sort();
+        assert_eq!(fs::read(&path).unwrap(), encoded(&sorted));
+        disk_set.add(&[4; ITEM_SIZE]).unwrap();
+        drop(disk_set);
+
+        // Found by searching the sorted items and reading the rest, without loading the set
+        let mut disk_set = DiskSet::with_bloom_filter(os_file_system(), &path).unwrap();
+        assert_eq!(disk_set.sorted_len, 5);
+        for item in items.iter().chain([&[4; ITEM_SIZE]]) {
+            assert!(disk_set.contains(item).unwrap());
+        }
+        assert!(!disk_set.contains(&[6; ITEM_SIZE]).unwrap());
+        disk_set.add(&[6; ITEM_SIZE]).unwrap();
+        assert!(disk_set.contains(&[6; ITEM_SIZE]).unwrap());
+        assert!(disk_set.loaded.is_none());
+
+        // A removed item's place is taken by the last item, which ends the sorted part there
+        disk_set.remove(&[5; ITEM_SIZE]).unwrap();
+        assert_eq!(disk_set.sorted_len, 2);
+        drop(disk_set);
+        let mut disk_set = DiskSet::with_bloom_filter(os_file_system(), &path).unwrap();
+        assert_eq!(disk_set.sorted_len, 2);
+        assert!(!disk_set.contains(&[5; ITEM_SIZE]).unwrap());
+        assert!(disk_set.contains(&[9; ITEM_SIZE]).unwrap());
+
+        // Compacted again once enough of it is out of order
+        for i in 0..2000u32 {
+            let mut item = [0xff; ITEM_SIZE];
+            item[..4].copy_from_slice(&(2000 - i).to_be_bytes());
+            disk_set.add(&item).unwrap();
+        }
+        drop(disk_set);
+        let disk_set = DiskSet::with_bloom_filter(os_file_system(), &path).unwrap();
+        assert_eq!(disk_set.sorted_len, disk_set.len);
+        let contents = DiskSet::read(&OsFileSystem, &path).unwrap();
+        assert!(contents.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // Changed behind its back, so none of it is trusted to be sorted
+        drop(disk_set);
+        fs::write(&path, encoded(&items)).unwrap();
+        let disk_set = DiskSet::with_bloom_filter(os_file_system(), &path).unwrap();
+        assert_eq!(disk_set.sorted_len, 0);
+    }
+
+    #[test]
+    fn test_sync_results_json() {
+        let results = SyncResults {
+            compute: vec![SyncResultItem {
+                path: "/dir/a.txt".to_string(),
+                relative_path: "a.txt".to_string(),
+                hash: "1234".to_string(),
+                chunks: Vec::new(),
+            }],
+            renamed: vec![RenamedItem {
+                old_path: "/dir/b.txt".to_string(),
+                new_path: "/dir/c.txt".to_string(),
+                old_relative_path: "b.txt".to_string(),
+                new_relative_path: "c.txt".to_string(),
+                hash: "5678".to_string(),
+            }],
+            ..Default::default()
+        };
+
+        let json = serde_json::to_string(&results).unwrap();
+        assert_eq!(
+            json,
+            r#"{"compute":[{"path":"/dir/a.txt","relative_path":"a.txt","hash":"1234"}],"delete":[],"addTag":[],"removeTag":[],"renamed":[{"old_path":"/dir/b.txt","new_path":"/dir/c.txt","old_relative_path":"b.txt","new_relative_path":"c.txt","hash":"5678"}],"warnings":[]}"#
+        );
+        assert_eq!(serde_json::from_str::<SyncResults>(&json).unwrap(), results);
+
+        // Results saved before renames were reported still load
+        let old_json = r#"{"compute":[],"delete":[],"addTag":[],"removeTag":[]}"#;
+        assert_eq!(
+            serde_json::from_str::<SyncResults>(old_json).unwrap(),
+            SyncResults::default()
+        );
+    }
+
+    #[test]
+    fn test_journal_recovery() {
+        let temp_dir = TempDirBuilder::new().add("a.txt", "A").create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root.clone());
+        sync(tag, &options).unwrap();
+        let journal_path = index_root.journal("default");
+        let tag_cache_path = IndexCache::index_cache_path_for_tag(tag, &index_root);
+        let hash = [1; ITEM_SIZE];
+
+        // Interrupted before anything but the journal was written, so all of it is done again
+        let entry = JournalEntry::new(tag.to_string(), vec![(JournalOp::AddGlobal, hash)]);
+        entry.write(&OsFileSystem, &journal_path, false).unwrap();
+        let mut index_cache = IndexCache::new(tag, &index_root, &options).unwrap();
+        assert!(!journal_path.exists());
+        assert!(index_cache.global_contains(&hash).unwrap());
+        assert_eq!(index_cache.get_rev_tags(&hash).unwrap(), [tag.to_string()]);
+        drop(index_cache);
+        assert!(DiskSet::new(os_file_system(), &tag_cache_path)
+            .unwrap()
+            .contains(&hash)
+            .unwrap());
+
+        // Interrupted after rev_tags was committed, so that part isn't done twice
+        let rev_tags = RevTags::open_sqlite(&index_root, "default").unwrap();
+        rev_tags.add(&hash, &tag.to_string()).unwrap();
+        let entry = JournalEntry::new(tag.to_string(), vec![(JournalOp::LocalRemove, hash)]);
+        entry.write(&OsFileSystem, &journal_path, false).unwrap();
+        rev_tags.begin().unwrap();
+        rev_tags.remove_one(&hash, &tag.to_string()).unwrap();
+        rev_tags.record_journal(entry.id).unwrap();
+        rev_tags.commit().unwrap();
+        drop(rev_tags);
+        let index_cache = IndexCache::new(tag, &index_root, &options).unwrap();
+        assert!(!journal_path.exists());
+        assert_eq!(index_cache.get_rev_tags(&hash).unwrap(), [tag.to_string()]);
+        drop(index_cache);
+        // The tag still has the content once, so it stays in the tag's cache
+        assert!(DiskSet::new(os_file_system(), &tag_cache_path)
+            .unwrap()
+            .contains(&hash)
+            .unwrap());
+
+        // A journal that was never finished is dropped without changing anything
+        fs::write(&journal_path, b"JRN1 cut short").unwrap();
+        let mut index_cache = IndexCache::new(tag, &index_root, &options).unwrap();
+        assert!(!journal_path.exists());
+        assert!(index_cache.global_contains(&hash).unwrap());
+        drop(index_cache);
+
+        // Syncs leave no journal behind
+        fs::write(temp_dir.path().join("a.txt"), "A changed").unwrap();
+        assert_eq!(sync(tag, &options).unwrap().compute.len(), 1);
+        assert!(!journal_path.exists());
+    }
+
+    #[test]
+    fn test_failed_bulk() {
+        let temp_dir = TempDirBuilder::new().add("a.txt", "A").create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root.clone());
+        let results = sync(tag, &options).unwrap();
+        let synced = parse_hash(&results.compute[0].hash).unwrap();
+        let added = [1; ITEM_SIZE];
+        let item = |hash| ObjDescription {
+            hash,
+            path: PathBuf::from("b.txt"),
+            is_blob: true,
+        };
+
+        // Fails partway through, after the remove has loaded the global cache, which writes the
+        // add to its file
+        let mut index_cache = IndexCache::new(tag, &index_root, &options).unwrap();
+        let result = index_cache.bulk(|index_cache| {
+            index_cache.add_global(&item(added))?;
+            index_cache.global_remove(&item(synced))?;
+            Err::<(), _>(io::Error::other("injected").into())
+        });
+        assert!(result.is_err());
+        drop(index_cache);
+
+        // Neither change is left in either cache, so they still agree with rev_tags
+        let mut index_cache = IndexCache::new(tag, &index_root, &options).unwrap();
+        assert!(!index_cache.global_contains(&added).unwrap());
+        assert!(index_cache.global_contains(&synced).unwrap());
+        drop(index_cache);
+        let tag_cache_path = IndexCache::index_cache_path_for_tag(tag, &index_root);
+        let mut tag_cache = DiskSet::new(os_file_system(), &tag_cache_path).unwrap();
+        assert!(!tag_cache.contains(&added).unwrap());
+        assert!(tag_cache.contains(&synced).unwrap());
+        assert!(verify_index(tag, &options).unwrap().is_consistent());
+    }
+
+    #[test]
+    fn test_corrupted_disk_set() {
+        let temp_dir = TempDirBuilder::new().create();
+        let path = temp_dir.path().join(".index_cache");
+        fs::write(&path, [0; ITEM_SIZE + 1]).unwrap();
+
+        let result = DiskSet::new(os_file_system(), &path);
+        assert!(matches!(result, Err(SyncError::CorruptedIndex(_))));
+    }
+
+    #[test]
+    fn test_disk_set_format() {
+        let temp_dir = TempDirBuilder::new().create();
+        let path = temp_dir.path().join(".index_cache");
+        let items: Vec<ObjectHash> = (1..4).map(|i| [i; ITEM_SIZE]).collect();
+        let is_corrupted =
+            |result: Result<DiskSet>| matches!(result, Err(SyncError::CorruptedIndex(_)));
+
+        // A file from before the header is read as it is, and written with one
+        fs::write(&path, items.concat()).unwrap();
+        let mut disk_set = DiskSet::new(os_file_system(), &path).unwrap();
+        assert!(disk_set.contains(&items[2]).unwrap());
+        drop(disk_set);
+        assert_eq!(fs::read(&path).unwrap(), encoded(&items));
+
+        // Cut short anywhere, with a header that says otherwise
+        let contents = encoded(&items);
+        for len in 1..contents.len() {
+            fs::write(&path, &contents[..len]).unwrap();
+            assert!(
+                is_corrupted(DiskSet::new(os_file_system(), &path)),
+                "cut to {} bytes",
+                len
+            );
+        }
+
+        // Changed, in an item or the checksum, including once opened without being read
+        for position in [DiskSet::offset(1), DiskSet::offset(3)] {
+            let mut changed = contents.clone();
+            changed[position as usize] ^= 1;
+            fs::write(&path, &changed).unwrap();
+            assert!(is_corrupted(DiskSet::new(os_file_system(), &path)));
+        }
+        let mut changed = contents.clone();
+        changed[DiskSet::offset(0) as usize] ^= 1;
+        fs::write(&path, &changed).unwrap();
+        fs::remove_file(temp_dir.path().join(".index_cache.sorted")).ok();
+        let mut disk_set = DiskSet::open(os_file_system(), &path).unwrap();
+        assert!(matches!(disk_set.load(), Err(SyncError::CorruptedIndex(_))));
+        drop(disk_set);
+        assert!(is_corrupted(DiskSet::new(os_file_system(), &path)));
+
+        // Written by a newer version
+        let mut newer = contents;
+        newer[4] = DISK_SET_VERSION + 1;
+        fs::write(&path, newer).unwrap();
+        assert!(is_corrupted(DiskSet::new(os_file_system(), &path)));
+    }
+
+    #[test]
+    fn test_rebuild_corrupted_cache() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("b.txt", "B")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root.clone());
+        sync(tag, &options).unwrap();
+
+        let hashes = |path: &Path| {
+            let mut hashes = DiskSet::read(&OsFileSystem, path).unwrap();
+            hashes.sort();
+            hashes
+        };
+        let global_path = index_root.provider_dir("default").join(".index_cache");
+        let tag_path = IndexCache::index_cache_path_for_tag(tag, &index_root);
+        let (global_hashes, tag_hashes) = (hashes(&global_path), hashes(&tag_path));
+        assert_eq!(global_hashes.len(), 2);
+
+        // Both caches come back from rev_tags
+        for path in [&global_path, &tag_path] {
+            let len = fs::metadata(path).unwrap().len();
+            File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_len(len - 1)
+                .unwrap();
+        }
+        drop(IndexCache::new(tag, &index_root, &options).unwrap());
+        assert_eq!(hashes(&global_path), global_hashes);
+        assert_eq!(hashes(&tag_path), tag_hashes);
+    }
+
+    #[test]
+    fn test_sync() {
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let ti = std::time::Instant::now();
+        let tag = Tag {
+            dir: Path::new("../"),
             branch: "nate/pyO3",
             provider_id: "default",
-        });
+        };
+        let _results = sync(&tag, &options);
+        println!("Sync took {:?}", ti.elapsed());
+        // Vast majority (90+%) of this time is spent in compute_tree_for_dir
+    }
+
+    #[test]
+    fn test_on_vscode_extension() {
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let _results = sync(
+            &Tag {
+                dir: Path::new("../extensions/vscode"),
+                branch: "nate/pyO3",
+                provider_id: "default",
+            },
+            &options,
+        );
+    }
+
+    #[test]
+    fn test_double_sync() {
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let ti = std::time::Instant::now();
+        let results = sync(
+            &Tag {
+                dir: Path::new("../"),
+                branch: "nate/pyO3",
+                provider_id: "default",
+            },
+            &options,
+        )
+        .expect("Sync failed.");
+        println!("First sync took {:?}", ti.elapsed());
+        assert!(!results.compute.is_empty());
+
+        let ti = std::time::Instant::now();
+        let results = sync(
+            &Tag {
+                dir: Path::new("../"),
+                branch: "nate/pyO3",
+                provider_id: "default",
+            },
+            &options,
+        )
+        .expect("Sync failed");
+        println!("Second sync took {:?}", ti.elapsed());
+        assert_eq!(results.compute.len(), 0);
+        assert_eq!(results.delete.len(), 0);
+    }
+
+    #[test]
+    fn test_unchanged_sync() {
+        let temp_dir = TempDirBuilder::new().add("a.txt", "A").create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root.clone());
+        sync(tag, &options).expect("Sync failed.");
+
+        // Only the sync time is written, and the caches aren't even opened
+        let global_cache = index_root.provider_dir("default").join(".index_cache");
+        let last_sync = index_root.tag_dir(tag).join(".last_sync");
+        let tree_modified = || {
+            fs::metadata(tree_path(tag, &index_root))
+                .unwrap()
+                .modified()
+        };
+        let before = tree_modified().unwrap();
+        fs::remove_file(&global_cache).unwrap();
+        fs::write(&last_sync, "0").unwrap();
+        assert_eq!(sync(tag, &options).unwrap(), SyncResults::default());
+        assert!(!global_cache.exists());
+        assert_eq!(tree_modified().unwrap(), before);
+        assert_ne!(fs::read_to_string(&last_sync).unwrap(), "0");
+
+        // Forcing still goes through the caches
+        let results = sync(tag, &options.clone().force(true)).expect("Sync failed.");
+        assert_eq!(results.compute.len(), 1);
+        assert!(global_cache.exists());
+    }
+
+    #[test]
+    fn test_sync_v3() {
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        // Create temp directory
+        let temp_dir = TempDirBuilder::new()
+            .add("dir1/file1.txt", "File 1")
+            .add("dir1/file2.txt", "File 2")
+            .add("dir2/file3.txt", "File 3")
+            .add("dir2/subdir/continue.py", "[continue for i in range(10)]")
+            .add("__init__.py", "a = 5")
+            .create();
+
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "BRANCH",
+            provider_id: "default",
+        };
+        // Sync once
+        sync(tag, &options).expect("Sync failed.");
+
+        // Make changes
+        let mut file = File::create(temp_dir.path().join("dir1/file1.txt")).unwrap();
+        file.write_all(b"File 1 changed").unwrap();
+        let mut file = File::create(temp_dir.path().join("dir2/file3.txt")).unwrap();
+        file.write_all(b"File 3 changed").unwrap();
+
+        // Sync again
+        let results = sync(tag, &options).expect("Sync failed.");
+
+        // Check results
+        assert_eq!(results.compute.len(), 2);
+        assert_eq!(results.delete.len(), 2);
+        assert_eq!(results.add_tag.len(), 0);
+        assert_eq!(results.remove_tag.len(), 0);
+
+        // Start a new branch
+
+        let tag2 = &Tag {
+            dir: temp_dir.path(),
+            branch: "BRANCH2",
+            provider_id: "default",
+        };
+        // Sync again
+        let results = sync(tag2, &options).expect("Sync failed.");
+
+        // Check results
+        assert_eq!(results.compute.len(), 0);
+        assert_eq!(results.delete.len(), 0);
+        assert_eq!(results.add_tag.len(), 5);
+        assert_eq!(results.remove_tag.len(), 0);
+
+        // Delete a file in this new branch
+        remove_file(temp_dir.path().join("dir1/file2.txt")).unwrap();
+
+        // Sync again
+        let results = sync(tag2, &options).expect("Sync failed.");
+
+        // Check results
+        assert_eq!(results.compute.len(), 0);
+        assert_eq!(results.delete.len(), 0);
+        assert_eq!(results.add_tag.len(), 0);
+        assert_eq!(results.remove_tag.len(), 1);
+    }
+
+    #[test]
+    fn test_sync_escaping_tree() {
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root.clone());
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("dir/b.txt", "B")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        sync(tag, &options).unwrap();
+
+        // A tree in the format of older versions, with a file outside the directory
+        let root = serde_json::to_string(temp_dir.path().to_str().unwrap()).unwrap();
+        let outside =
+            serde_json::to_string(temp_dir.path().join("../outside.txt").to_str().unwrap())
+                .unwrap();
+        let hash = serde_json::to_string(&[1u8; 20]).unwrap();
+        let tree = format!(
+            "{{\"parent\":null,\"children\":[{hash}],\"hash\":{hash},\"path\":{root}}}\n\
+            {{\"parent\":null,\"children\":null,\"hash\":{hash},\"path\":{outside}}}\n"
+        );
+        fs::write(tree_path(tag, &index_root), tree).unwrap();
+
+        // It's never reported, and the directory is indexed again from scratch
+        let results = sync(tag, &options).unwrap();
+        assert!(matches!(
+            results.warnings.as_slice(),
+            [SyncWarning::CorruptedTree { .. }]
+        ));
+        assert_eq!(results.add_tag.len(), 2);
+        assert!(results.remove_tag.is_empty() && results.delete.is_empty());
+        let results = sync(tag, &options).unwrap();
+        assert_eq!(results, SyncResults::default());
+    }
+
+    #[test]
+    fn test_sync_normalize_content() {
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let temp_dir = TempDirBuilder::new()
+            .add("unix.txt", "a\nb\n")
+            .add("windows.txt", "a\r\nb\r\n")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "BRANCH",
+            provider_id: "default",
+        };
+        let results = sync(tag, &options).expect("Sync failed.");
+        assert_eq!(results.compute.len(), 2);
+
+        // Switching it on rehashes every file, even though none of them changed
+        let normalized = options
+            .clone()
+            .normalize_content(ContentNormalization::LineEndings);
+        let results = sync(tag, &normalized).expect("Sync failed.");
+        assert_eq!(results.compute.len(), 0);
+        assert_eq!(results.add_tag.len(), 1);
+        assert_eq!(results.add_tag[0].relative_path, "windows.txt");
+        assert_eq!(results.delete.len(), 1);
+        assert!(is_index_up_to_date(tag, &normalized).unwrap());
+        assert!(!is_index_up_to_date(tag, &options).unwrap());
+        assert!(verify::verify_index(tag, &normalized)
+            .unwrap()
+            .is_consistent());
+
+        // Another provider's setting doesn't apply, but its own does
+        let other = options
+            .clone()
+            .normalize_content(ContentNormalization::LineEndings)
+            .normalize_content_for("default", ContentNormalization::None);
+        let results = sync(tag, &other).expect("Sync failed.");
+        assert_eq!(results.compute.len(), 1);
+        assert_eq!(results.compute[0].relative_path, "windows.txt");
+        assert_eq!(results.remove_tag.len(), 1);
+        assert!(is_index_up_to_date(tag, &options).unwrap());
+
+        // The same goes for syncing in batches
+        let results = merge_batches(tag, &normalized, 10);
+        assert_eq!(results.add_tag.len(), 1);
+        assert_eq!(results.delete.len(), 1);
+        assert!(is_index_up_to_date(tag, &normalized).unwrap());
+    }
+
+    #[test]
+    fn test_sync_renamed() {
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("dir/b.txt", "B")
+            .add("c.txt", "same")
+            .add("d.txt", "same")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "BRANCH",
+            provider_id: "default",
+        };
+        sync(tag, &options).expect("Sync failed.");
+
+        // Move a file into another directory and a copy of duplicated content
+        std::fs::rename(
+            temp_dir.path().join("dir/b.txt"),
+            temp_dir.path().join("b.txt"),
+        )
+        .unwrap();
+        std::fs::rename(temp_dir.path().join("d.txt"), temp_dir.path().join("e.txt")).unwrap();
+
+        let results = sync(tag, &options).expect("Sync failed.");
+        assert!(results.compute.is_empty());
+        assert!(results.delete.is_empty());
+        assert!(results.add_tag.is_empty());
+        assert!(results.remove_tag.is_empty());
+        let mut renamed: Vec<_> = results
+            .renamed
+            .iter()
+            .map(|item| (&item.old_relative_path[..], &item.new_relative_path[..]))
+            .collect();
+        renamed.sort();
+        assert_eq!(renamed, vec![("d.txt", "e.txt"), ("dir/b.txt", "b.txt")]);
+        assert!(verify::verify_index(tag, &options).unwrap().is_consistent());
+
+        // The content is still referenced once per file, so removing one copy doesn't delete it
+        remove_file(temp_dir.path().join("e.txt")).unwrap();
+        let results = sync(tag, &options).expect("Sync failed.");
+        assert!(results.renamed.is_empty());
+        assert!(results.delete.is_empty());
+        assert_eq!(results.remove_tag.len(), 1);
+
+        // Forcing recomputes instead of reporting a rename
+        std::fs::rename(temp_dir.path().join("b.txt"), temp_dir.path().join("f.txt")).unwrap();
+        let results = sync(tag, &options.clone().force(true)).expect("Sync failed.");
+        assert!(results.renamed.is_empty());
+        assert!(results
+            .compute
+            .iter()
+            .any(|item| item.relative_path == "f.txt"));
+    }
+
+    #[test]
+    fn test_sync_index_not_writable() {
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root.clone());
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("dir/b.txt", "B")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        sync(tag, &options).unwrap();
+        let root_hash = get_root_hash(tag, &options).unwrap();
+
+        fs::write(temp_dir.path().join("a.txt"), "A changed").unwrap();
+        fs::rename(
+            temp_dir.path().join("dir/b.txt"),
+            temp_dir.path().join("b.txt"),
+        )
+        .unwrap();
+
+        // Too little space fails before anything is written
+        let full = options.clone().min_free_space(u64::MAX);
+        assert!(matches!(sync(tag, &full), Err(SyncError::DiskFull(_))));
+        assert!(matches!(
+            sync_in_batches(tag, &full, 10, |_| Ok(())),
+            Err(SyncError::DiskFull(_))
+        ));
+        assert_eq!(get_root_hash(tag, &options).unwrap(), root_hash);
+
+        // Unless the changes are wanted anyway, which leaves the index as it is too
+        let fallback = full.read_only_fallback(true);
+        for _ in 0..2 {
+            let results = sync(tag, &fallback).unwrap();
+            assert!(matches!(
+                results.warnings.as_slice(),
+                [SyncWarning::IndexNotWritable { .. }]
+            ));
+            assert_eq!(results.compute.len(), 1);
+            assert_eq!(results.remove_tag.len(), 1);
+            assert_eq!(results.renamed.len(), 1);
+            assert!(results.delete.is_empty() && results.add_tag.is_empty());
+            assert_eq!(get_root_hash(tag, &options).unwrap(), root_hash);
+        }
+        let mut batches = Vec::new();
+        sync_in_batches(tag, &fallback, 10, |batch| {
+            batches.push(batch);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].compute.len(), 1);
+        assert!(verify::verify_index(tag, &options).unwrap().is_consistent());
+
+        // A session moves on from each sync in memory
+        let mut session = SyncSession::open(tag, fallback).unwrap();
+        assert_eq!(session.sync().unwrap().compute.len(), 1);
+        let results = session.sync().unwrap();
+        assert_eq!(
+            results,
+            SyncResults {
+                warnings: results.warnings.clone(),
+                ..Default::default()
+            }
+        );
+
+        // Once the index can be written, the changes are saved as usual
+        let results = sync(tag, &options).unwrap();
+        assert_eq!(results.compute.len(), 1);
+        assert_eq!(results.delete.len(), 1);
+        assert_eq!(results.renamed.len(), 1);
+        assert!(verify::verify_index(tag, &options).unwrap().is_consistent());
+    }
+
+    #[test]
+    fn test_sync_duplicate_removed() {
+        // Two paths in a tag with the same content, one of which goes away
+        for storage in [IndexStorage::Files, IndexStorage::Sqlite].iter().copied() {
+            let temp_dir = TempDirBuilder::new()
+                .add("a.txt", "same")
+                .add("dir/b.txt", "same")
+                .create();
+            let tag = &Tag {
+                dir: temp_dir.path(),
+                branch: "main",
+                provider_id: "default",
+            };
+            let (_index_dir, index_root) = temp_index_root();
+            let options = SyncOptions::new().index_root(index_root).storage(storage);
+            let results = sync(tag, &options).unwrap();
+            assert_eq!(results.compute.len() + results.add_tag.len(), 2);
+            assert!(verify::verify_index(tag, &options).unwrap().is_consistent());
+
+            remove_file(temp_dir.path().join("a.txt")).unwrap();
+            let results = sync(tag, &options).unwrap();
+            assert!(results.delete.is_empty());
+            assert!(verify::verify_index(tag, &options).unwrap().is_consistent());
+
+            // The content is still the tag's, so the last copy going is what deletes it
+            remove_file(temp_dir.path().join("dir/b.txt")).unwrap();
+            let results = sync(tag, &options).unwrap();
+            assert_eq!(results.delete.len(), 1);
+            assert!(verify::verify_index(tag, &options).unwrap().is_consistent());
+        }
+    }
+
+    #[test]
+    fn test_sync_options() {
+        let temp_dir = TempDirBuilder::new()
+            .add("src/main.rs", "fn main() {}")
+            .add("README.md", "# Readme")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root).ignore("*.md");
+
+        // A dry run reports changes but doesn't record them
+        let dry_run = options.clone().dry_run(true);
+        let results = sync(tag, &dry_run).expect("Sync failed.");
+        assert_eq!(results.compute.len(), 1);
+        assert!(results.compute[0].path.ends_with("main.rs"));
+        let results = sync(tag, &dry_run).expect("Sync failed.");
+        assert_eq!(results.compute.len(), 1);
+
+        let results = sync(tag, &options).expect("Sync failed.");
+        assert_eq!(results.compute.len(), 1);
+        let results = sync(tag, &options).expect("Sync failed.");
+        assert!(results.compute.is_empty());
+    }
+
+    #[test]
+    fn test_sync_fsync() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("dir/b.txt", "B")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root.clone());
+
+        for storage in [IndexStorage::Files, IndexStorage::Sqlite] {
+            let options = options.clone().storage(storage).fsync(true);
+            let results = sync(tag, &options.clone().clear_tag_cache(true)).unwrap();
+            assert_eq!(results.compute.len() + results.add_tag.len(), 2);
+            fs::write(temp_dir.path().join("a.txt"), format!("{storage:?}")).unwrap();
+            assert_eq!(sync(tag, &options).unwrap().compute.len(), 1);
+        }
+
+        // Every file was written next to its final name and renamed into place
+        let mut stack = vec![index_root.path().to_path_buf()];
+        while let Some(dir) = stack.pop() {
+            for entry in fs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                assert!(path.extension().is_none_or(|extension| extension != "tmp"));
+                if path.is_dir() {
+                    stack.push(path);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_sync_lock() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("b.txt", "B")
+            .add("dir/c.txt", "C")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root.clone());
+
+        // Syncs of the same tag at the same time take turns, so only one of them sees the files
+        let computed: usize = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| sync(tag, &options).unwrap().compute.len()))
+                .collect();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .sum()
+        });
+        assert_eq!(computed, 3);
+
+        // A sync that can't have the lock fails instead of waiting, if asked to
+        let held = lock::lock(&index_root.provider_lock("default"), LockWait::Block).unwrap();
+        if cfg!(unix) {
+            let result = sync(tag, &options.clone().lock_wait(LockWait::Fail));
+            assert!(matches!(result, Err(SyncError::Locked(_))));
+        }
+        drop(held);
+        let result = sync(tag, &options.clone().lock_wait(LockWait::Fail));
+        assert_eq!(result.unwrap(), SyncResults::default());
+    }
+
+    #[test]
+    fn test_defer_compute() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("b.txt", "B")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let tag2 = &Tag {
+            branch: "other",
+            ..*tag
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root.clone());
+        let deferred = options.clone().defer_compute(true);
+        let paths = |items: &[SyncResultItem]| {
+            let mut paths: Vec<String> = items
+                .iter()
+                .map(|item| item.path.rsplit('/').next().unwrap().to_string())
+                .collect();
+            paths.sort();
+            paths
+        };
+
+        // Until they're marked as computed, the files are reported again by every sync
+        let results = sync(tag, &deferred).unwrap();
+        assert_eq!(paths(&results.compute), ["a.txt", "b.txt"]);
+        let results = sync(tag, &deferred).unwrap();
+        assert_eq!(paths(&results.compute), ["a.txt", "b.txt"]);
+        let mut batches = Vec::new();
+        sync_in_batches(tag, &deferred, 1, |results| {
+            batches.extend(results.compute);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(paths(&batches), ["a.txt", "b.txt"]);
+
+        // Marking some of them finishes those
+        let a_hash = parse_hash(&results.compute[0].hash).unwrap();
+        let a_path = paths(&results.compute[..1]).remove(0);
+        mark_computed(tag, &[a_hash], &deferred).unwrap();
+        let results = sync(tag, &deferred).unwrap();
+        assert_eq!(results.compute.len(), 1);
+        assert_ne!(paths(&results.compute), [a_path.as_str()]);
+        let results = sync(tag2, &options).unwrap();
+        assert_eq!(paths(&results.add_tag), [a_path.as_str()]);
+        assert_eq!(results.compute.len(), 1);
+
+        // A held back file that's deleted was never in the index, so isn't reported
+        for item in sync(tag, &deferred).unwrap().compute {
+            fs::remove_file(temp_dir.path().join(item.path)).unwrap();
+        }
+        assert_eq!(sync(tag, &deferred).unwrap(), SyncResults::default());
+        assert!(!index_root.tag_dir(tag).join(".pending").exists());
+    }
+
+    #[test]
+    fn test_case_only_rename() {
+        let temp_dir = TempDirBuilder::new()
+            .add("README.md", "R")
+            .add("a.txt", "A")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        sync(tag, &options).unwrap();
+
+        fs::rename(
+            temp_dir.path().join("README.md"),
+            temp_dir.path().join("readme.md"),
+        )
+        .unwrap();
+        fs::write(temp_dir.path().join("a.txt"), "A changed").unwrap();
+        let readme_changes = |options: &SyncOptions| {
+            let (add, remove) = diff_dir_against_saved(tag, options).unwrap();
+            add.iter()
+                .chain(&remove)
+                .filter(|item| item.path.extension() == Some("md".as_ref()))
+                .count()
+        };
+
+        // A filesystem that tells the two names apart sees a delete and an add, one that
+        // doesn't sees the same file
+        assert_eq!(readme_changes(&options.clone().case_insensitive(false)), 2);
+        let options = options.case_insensitive(true);
+        assert_eq!(readme_changes(&options), 0);
+
+        let results = sync(tag, &options).unwrap();
+        assert_eq!(results.compute.len(), 1);
+        assert!(results.compute[0].path.ends_with("a.txt"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_workspace_through_symlink() {
+        let temp_dir = TempDirBuilder::new().add("workspace/a.txt", "A").create();
+        let workspace = temp_dir.path().join("workspace");
+        let link = temp_dir.path().join("link");
+        std::os::unix::fs::symlink(&workspace, &link).unwrap();
+        let tag = |dir| Tag {
+            dir,
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+
+        // The link, and a path with `..` in it, are both the same tag as the directory
+        assert_eq!(sync(&tag(&link), &options).unwrap().compute.len(), 1);
+        assert_eq!(
+            sync(&tag(&workspace), &options).unwrap(),
+            SyncResults::default()
+        );
+        let dotted = workspace.join("../workspace");
+        assert_eq!(
+            sync(&tag(&dotted), &options).unwrap(),
+            SyncResults::default()
+        );
+
+        let tags = list_tags(&options, Some(&link), None).unwrap();
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].tag.dir, fs::canonicalize(&workspace).unwrap());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_follow_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let temp_dir = TempDirBuilder::new()
+            .add("workspace/dir/a.txt", "A")
+            .add("outside/b.txt", "B")
+            .create();
+        let workspace = temp_dir.path().join("workspace");
+        symlink(workspace.join("dir/a.txt"), workspace.join("file_link")).unwrap();
+        symlink(
+            temp_dir.path().join("outside"),
+            workspace.join("outside_link"),
+        )
+        .unwrap();
+        symlink(&workspace, workspace.join("dir/parent_link")).unwrap();
+        symlink(workspace.join("dir"), workspace.join("zz_dir_link")).unwrap();
+        symlink(workspace.join("missing"), workspace.join("broken_link")).unwrap();
+        let tag = &Tag {
+            dir: &workspace,
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let relative_paths = |options: &SyncOptions| {
+            let results = sync(tag, &options.clone().clear_tag_cache(true)).unwrap();
+            let mut paths: Vec<_> = results
+                .compute
+                .iter()
+                .map(|item| item.relative_path.clone())
+                .collect();
+            paths.sort();
+            (paths, results.warnings)
+        };
+
+        // Without following them, every link is left out and reported
+        let (paths, warnings) = relative_paths(&options);
+        assert_eq!(paths, ["dir/a.txt"]);
+        assert_eq!(warnings.len(), 5);
+        assert!(warnings
+            .iter()
+            .all(|warning| matches!(warning, SyncWarning::UnfollowedSymlink { .. })));
+
+        // Following them, the links to the parent and to a directory already in the tree aren't
+        // walked again, and the broken one is still reported
+        let options = options.follow_symlinks(true);
+        let (paths, warnings) = relative_paths(&options);
+        assert_eq!(paths, ["dir/a.txt", "file_link", "outside_link/b.txt"]);
+        let mut loops: Vec<_> = warnings
+            .iter()
+            .filter_map(|warning| match warning {
+                SyncWarning::SymlinkLoop { path } => Some(path.as_str()),
+                _ => None,
+            })
+            .collect();
+        loops.sort();
+        assert_eq!(loops.len(), 2);
+        assert!(loops[0].ends_with("parent_link"));
+        assert!(loops[1].ends_with("zz_dir_link"));
+        assert!(warnings.iter().any(|warning| matches!(
+            warning,
+            SyncWarning::UnfollowedSymlink { path } if path.ends_with("broken_link")
+        )));
+
+        // Building the tree in one go leaves out the same entries
+        let tree = compute_tree_for_dir(&workspace, &options).unwrap();
+        let mut tree_paths: Vec<_> = tree
+            .all_obj_descriptions()
+            .iter()
+            .filter(|item| item.is_blob)
+            .map(|item| item.path.to_string_lossy().into_owned())
+            .collect();
+        tree_paths.sort();
+        assert_eq!(tree_paths, paths);
+    }
+
+    #[test]
+    fn test_result_paths() {
+        let temp_dir = TempDirBuilder::new().add("dir/a.txt", "A").create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+
+        let results = sync(tag, &options).unwrap();
+        let path = temp_dir.path().join("dir").join("a.txt");
+        assert_eq!(results.compute[0].path, path.to_str().unwrap());
+        assert_eq!(
+            Path::new(&results.compute[0].relative_path),
+            Path::new("dir/a.txt")
+        );
+        let hash = parse_hash(&results.compute[0].hash).unwrap();
+        assert_eq!(find_paths_for_hash(tag, hash, &options).unwrap(), [path]);
+    }
+
+    #[test]
+    fn test_diff_dir_against_saved() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("b.txt", "B")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+
+        // With nothing saved, everything is new
+        let (add, remove) = diff_dir_against_saved(tag, &options).unwrap();
+        assert_eq!(add.iter().filter(|item| item.is_blob).count(), 2);
+        assert!(!remove.iter().any(|item| item.is_blob));
+
+        sync(tag, &options).expect("Sync failed.");
+        fs::write(temp_dir.path().join("a.txt"), "A changed").unwrap();
+        let (add, remove) = diff_dir_against_saved(tag, &options).unwrap();
+        let blobs = |items: &[ObjDescription]| -> Vec<PathBuf> {
+            items
+                .iter()
+                .filter(|item| item.is_blob)
+                .map(|item| item.path.clone())
+                .collect()
+        };
+        assert_eq!(blobs(&add), blobs(&remove));
+        assert!(blobs(&add)[0].ends_with("a.txt"));
+
+        // The diff didn't save anything, so sync still sees the change
+        let results = sync(tag, &options).expect("Sync failed.");
+        assert_eq!(results.compute.len(), 1);
+        assert_eq!(results.delete.len(), 1);
+    }
+
+    #[test]
+    fn test_list_tags() {
+        let temp_dir = TempDirBuilder::new().add("a.txt", "A").create();
+        let other_dir = TempDirBuilder::new().add("b.txt", "B").create();
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        assert!(list_tags(&options, None, None).unwrap().is_empty());
+
+        let tags = [
+            Tag {
+                dir: temp_dir.path(),
+                branch: "main",
+                provider_id: "default",
+            },
+            Tag {
+                dir: temp_dir.path(),
+                branch: "feature/x",
+                provider_id: "default",
+            },
+            Tag {
+                dir: temp_dir.path(),
+                branch: "main",
+                provider_id: "other",
+            },
+            Tag {
+                dir: other_dir.path(),
+                branch: "main",
+                provider_id: "default",
+            },
+        ];
+        for tag in &tags {
+            sync(tag, &options).expect("Sync failed.");
+        }
+        // Dry runs don't record the tag
+        let unsynced = Tag {
+            branch: "dry",
+            ..tags[0]
+        };
+        sync(&unsynced, &options.clone().dry_run(true)).expect("Sync failed.");
+
+        let entries = list_tags(&options, None, None).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert!(entries.iter().all(|entry| entry.last_sync.is_some()));
+        for tag in &tags {
+            assert!(entries.iter().any(|entry| entry.tag == OwnedTag::from(tag)));
+        }
+
+        let entries = list_tags(&options, Some(temp_dir.path()), None).unwrap();
+        assert_eq!(entries.len(), 3);
+        let entries = list_tags(&options, Some(temp_dir.path()), Some("default")).unwrap();
+        assert_eq!(entries.len(), 2);
+        let entries = list_tags(&options, None, Some("other")).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].tag, OwnedTag::from(&tags[2]));
+    }
+
+    #[test]
+    fn test_delete_tag() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("b.txt", "B")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let tag2 = &Tag {
+            branch: "main/nested",
+            ..*tag
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        sync(tag, &options).expect("Sync failed.");
+        fs::write(temp_dir.path().join("b.txt"), "B changed").unwrap();
+        sync(tag2, &options).expect("Sync failed.");
+
+        // a.txt is shared with tag2, b.txt is only in this tag
+        let dry_run = delete_tag(tag, &options.clone().dry_run(true)).unwrap();
+        let results = delete_tag(tag, &options).unwrap();
+        assert_eq!(results, dry_run);
+        assert_eq!(results.delete.len(), 1);
+        assert!(results.delete[0].path.ends_with("b.txt"));
+        assert_eq!(results.remove_tag.len(), 1);
+        assert!(results.remove_tag[0].path.ends_with("a.txt"));
+
+        let entries = list_tags(&options, None, None).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].tag, OwnedTag::from(tag2));
+        assert_eq!(delete_tag(tag, &options).unwrap(), SyncResults::default());
+
+        // The remaining tag now holds the only reference to everything
+        let results = delete_tag(tag2, &options).unwrap();
+        assert_eq!(results.delete.len(), 2);
+        assert!(results.remove_tag.is_empty());
+        assert!(list_tags(&options, None, None).unwrap().is_empty());
+
+        // Syncing again starts from scratch
+        let results = sync(tag, &options).expect("Sync failed.");
+        assert_eq!(results.compute.len(), 2);
+    }
+
+    #[test]
+    fn test_sqlite_storage() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("b.txt", "B")
+            .add("c.txt", "C")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let tag2 = &Tag {
+            branch: "other",
+            ..*tag
+        };
+        let (_files_dir, files_root) = temp_index_root();
+        let files = SyncOptions::new().index_root(files_root);
+        let (_sqlite_dir, sqlite_root) = temp_index_root();
+        let sqlite = SyncOptions::new()
+            .index_root(sqlite_root.clone())
+            .storage(IndexStorage::Sqlite);
+
+        // Every action comes out the same as with the files
+        let check = |f: &dyn Fn(&SyncOptions) -> SyncResults| {
+            let expected = f(&files);
+            assert_eq!(f(&sqlite), expected);
+            expected
+        };
+        assert_eq!(
+            check(&|options| sync(tag, options).unwrap()).compute.len(),
+            3
+        );
+        assert_eq!(
+            check(&|options| sync(tag2, options).unwrap()).add_tag.len(),
+            3
+        );
+        fs::write(temp_dir.path().join("a.txt"), "A changed").unwrap();
+        fs::remove_file(temp_dir.path().join("b.txt")).unwrap();
+        let results = check(&|options| sync(tag, options).unwrap());
+        assert_eq!(results.compute.len(), 1);
+        assert_eq!(results.remove_tag.len(), 2);
+        assert_eq!(
+            check(&|options| delete_tag(tag2, options).unwrap())
+                .delete
+                .len(),
+            2
+        );
+        check(&|options| sync(tag, &options.clone().clear_tag_cache(true)).unwrap());
+
+        // Nothing but the trees is kept in files
+        let tag_dir = sqlite_root.tag_dir(tag);
+        assert!(sqlite_root.index_db().is_file());
+        assert!(tag_dir.join("merkle_tree").is_file());
+        assert!(!tag_dir.join(".index_cache").exists());
+        assert!(!tag_dir.join(".last_sync").exists());
+        assert!(!sqlite_root.rev_tags_db("default").exists());
+
+        let hash = parse_hash(&results.compute[0].hash).unwrap();
+        assert_eq!(
+            which_tags_contain(hash, "default", &sqlite).unwrap(),
+            [OwnedTag::from(tag)]
+        );
+        let entries = list_tags(&sqlite, None, None).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].last_sync.is_some());
+    }
+
+    #[test]
+    fn test_get_root_hash() {
+        let temp_dir = TempDirBuilder::new().add("a.txt", "A").create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        assert_eq!(get_root_hash(tag, &options).unwrap(), None);
+
+        sync(tag, &options).expect("Sync failed.");
+        let tree = compute_tree_for_dir(temp_dir.path(), &options).unwrap();
+        assert_eq!(get_root_hash(tag, &options).unwrap(), Some(tree.hash()));
     }
 
     #[test]
-    fn test_double_sync() {
-        let ti = std::time::Instant::now();
-        let results = sync(&Tag {
-            dir: Path::new("../"),
-            branch: "nate/pyO3",
+    fn test_list_indexed_files() {
+        let temp_dir = TempDirBuilder::new()
+            .add("b.txt", "B")
+            .add("dir/a.txt", "A")
+            .add("dir/sub/c.txt", "C")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        assert!(list_indexed_files(tag, &options).unwrap().is_empty());
+
+        let results = sync(tag, &options).expect("Sync failed.");
+        let files = list_indexed_files(tag, &options).unwrap();
+        assert_eq!(files, results.compute);
+        assert_eq!(files.len(), 3);
+        assert!(files[0].path.ends_with("b.txt"));
+        assert!(files[1].path.ends_with("a.txt"));
+        assert!(files[2].path.ends_with("c.txt"));
+    }
+
+    #[test]
+    fn test_which_tags_contain() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("copy/a.txt", "A")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let tag2 = &Tag {
+            branch: "other",
+            ..*tag
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let results = sync(tag, &options).expect("Sync failed.");
+        sync(tag2, &options).expect("Sync failed.");
+
+        let hash = parse_hash(&results.compute[0].hash).unwrap();
+        assert_eq!(
+            which_tags_contain(hash, "default", &options).unwrap(),
+            vec![OwnedTag::from(tag), OwnedTag::from(tag2)]
+        );
+        assert!(which_tags_contain(hash, "other", &options)
+            .unwrap()
+            .is_empty());
+        assert!(which_tags_contain([0; 20], "default", &options)
+            .unwrap()
+            .is_empty());
+
+        delete_tag(tag, &options).unwrap();
+        assert_eq!(
+            which_tags_contain(hash, "default", &options).unwrap(),
+            vec![OwnedTag::from(tag2)]
+        );
+    }
+
+    #[test]
+    fn test_find_paths_for_hash() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("b.txt", "B")
+            .add("copy/a.txt", "A")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let results = sync(tag, &options).expect("Sync failed.");
+        let hash = parse_hash(&results.compute[0].hash).unwrap();
+
+        let paths = find_paths_for_hash(tag, hash, &options).unwrap();
+        assert_eq!(paths.len(), 2);
+        assert!(paths[0].ends_with("a.txt"));
+        assert!(paths[1].ends_with("copy/a.txt"));
+
+        // Follows renames once they've been synced
+        fs::rename(temp_dir.path().join("a.txt"), temp_dir.path().join("c.txt")).unwrap();
+        sync(tag, &options).expect("Sync failed.");
+        let paths = find_paths_for_hash(tag, hash, &options).unwrap();
+        assert_eq!(paths.len(), 2);
+        assert!(paths[0].ends_with("c.txt"));
+
+        assert!(find_paths_for_hash(tag, [0; 20], &options)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_is_index_up_to_date() {
+        let temp_dir = TempDirBuilder::new().add("a.txt", "A").create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        assert!(!is_index_up_to_date(tag, &options).unwrap());
+
+        sync(tag, &options).expect("Sync failed.");
+        assert!(is_index_up_to_date(tag, &options).unwrap());
+
+        fs::write(temp_dir.path().join("a.txt"), "A changed").unwrap();
+        assert!(!is_index_up_to_date(tag, &options).unwrap());
+        // Checking doesn't count as a sync
+        assert!(!is_index_up_to_date(tag, &options).unwrap());
+
+        sync(tag, &options).expect("Sync failed.");
+        assert!(is_index_up_to_date(tag, &options).unwrap());
+    }
+
+    #[test]
+    fn test_sync_legacy_encodings() {
+        let temp_dir = TempDirBuilder::new().create();
+        fs::write(temp_dir.path().join("utf8.txt"), "café").unwrap();
+        fs::write(temp_dir.path().join("latin1.txt"), b"caf\xe9").unwrap();
+        fs::write(
+            temp_dir.path().join("utf16.txt"),
+            b"\xff\xfec\0a\0f\0\xe9\0",
+        )
+        .unwrap();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+
+        // All three are indexed, as the same text
+        let results = sync(tag, &options).expect("Sync failed.");
+        assert_eq!(results.compute.len(), 1);
+        assert_eq!(results.add_tag.len(), 2);
+        assert!(results.warnings.is_empty());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_sync_warnings() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("binary.bin", "")
+            .create();
+        let png = [
+            0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', 0, 0, 0, b'\r',
+        ];
+        fs::write(temp_dir.path().join("binary.bin"), png).unwrap();
+        fs::write(temp_dir.path().join(OsStr::from_bytes(b"bad\xff.txt")), "B").unwrap();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root.clone());
+
+        // Binary files are skipped, and non-UTF-8 names are only replaced for display
+        let results = sync(tag, &options).expect("Sync failed.");
+        assert_eq!(results.compute.len(), 2);
+        let binary = SyncWarning::BinaryFile {
+            path: temp_dir.path().join("binary.bin").display().to_string(),
+        };
+        assert_eq!(results.warnings, vec![binary.clone()]);
+        assert!(results
+            .compute
+            .iter()
+            .any(|item| item.path.ends_with("bad\u{FFFD}.txt")));
+
+        // The exact name is saved, in either format, so the file is found again unchanged
+        for format in [TreeFormat::Json, TreeFormat::Binary] {
+            let options = options.clone().tree_format(format);
+            let results = sync(tag, &options).unwrap();
+            assert_eq!(results.warnings, vec![binary.clone()]);
+            assert_eq!(
+                results,
+                SyncResults {
+                    warnings: results.warnings.clone(),
+                    ..Default::default()
+                }
+            );
+            let tree =
+                load_saved_tree(&*options.resolve_index_file_system(), tag, &index_root).unwrap();
+            assert!(tree
+                .all_obj_descriptions()
+                .iter()
+                .any(|item| item.path.ends_with(OsStr::from_bytes(b"bad\xff.txt"))));
+            fs::write(temp_dir.path().join("a.txt"), format!("{format:?}")).unwrap();
+            assert_eq!(sync(tag, &options).unwrap().compute.len(), 1);
+        }
+
+        fs::write(tree_path(tag, &index_root), "not json").unwrap();
+        let results = sync(tag, &options).expect("Sync failed.");
+        assert!(results
+            .warnings
+            .iter()
+            .any(|warning| matches!(warning, SyncWarning::CorruptedTree { .. })));
+        assert_eq!(results.add_tag.len(), 2);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_sync_possibly_stale() {
+        use std::time::{Duration, SystemTime};
+
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("dir/b.txt", "B")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let results = sync(tag, &options).unwrap();
+        assert!(!results.possibly_stale());
+
+        // Anything modified after the sync started is flagged, files and directories alike
+        let set_mtime = |path: &Path, mtime: SystemTime| {
+            fs::File::open(temp_dir.path().join(path))
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap()
+        };
+        let later = SystemTime::now() + Duration::from_secs(3600);
+        set_mtime(Path::new("a.txt"), later);
+        let results = sync(tag, &options).unwrap();
+        assert!(results.possibly_stale());
+        assert_eq!(
+            results.warnings,
+            [SyncWarning::ChangedDuringSync {
+                path: temp_dir.path().join("a.txt").display().to_string()
+            }]
+        );
+
+        set_mtime(Path::new("a.txt"), SystemTime::now());
+        set_mtime(Path::new("dir"), later);
+        let results = sync(tag, &options).unwrap();
+        assert_eq!(
+            results.warnings,
+            [SyncWarning::ChangedDuringSync {
+                path: temp_dir.path().join("dir").display().to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_max_file_size() {
+        let temp_dir = TempDirBuilder::new()
+            .add("small.txt", "small")
+            .add("large.txt", &"large".repeat(100))
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
             provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root).max_file_size(100);
+
+        let expected = vec![SyncWarning::FileTooLarge {
+            path: temp_dir.path().join("large.txt").display().to_string(),
+            size: 501,
+        }];
+        let results = sync(tag, &options).expect("Sync failed.");
+        assert_eq!(results.compute.len(), 1);
+        assert_eq!(results.warnings, expected);
+        let mut batched = Vec::new();
+        sync_in_batches(tag, &options, 10, |results| {
+            batched.extend(results.warnings);
+            Ok(())
         })
         .expect("Sync failed.");
-        println!("First sync took {:?}", ti.elapsed());
-        assert!(!results.0.is_empty());
-        assert!(!results.1.is_empty());
+        assert_eq!(batched, expected);
 
-        let ti = std::time::Instant::now();
-        let results = sync(&Tag {
-            dir: Path::new("../"),
-            branch: "nate/pyO3",
+        // Once it's allowed, it's added like any other file
+        let results = sync(tag, &options.clone().max_file_size(1000)).expect("Sync failed.");
+        assert_eq!(results.compute.len(), 1);
+        assert!(results.warnings.is_empty());
+        assert_eq!(
+            options.max_file_size(u64::MAX).resolve_max_file_size(),
+            u64::MAX
+        );
+        assert_eq!(
+            SyncOptions::new().resolve_max_file_size(),
+            options::DEFAULT_MAX_FILE_SIZE
+        );
+    }
+
+    #[test]
+    fn test_sync_force() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("b.txt", "B")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let tag2 = &Tag {
+            branch: "other",
+            ..*tag
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        sync(tag, &options).expect("Sync failed.");
+        sync(tag2, &options).expect("Sync failed.");
+
+        // Everything is reported again, including files cached for other tags
+        fs::write(temp_dir.path().join("c.txt"), "C").unwrap();
+        let results = sync(tag, &options.clone().force(true)).expect("Sync failed.");
+        assert_eq!(results.compute.len(), 3);
+        assert!(results.add_tag.is_empty());
+        assert!(sync(tag, &options).unwrap().compute.is_empty());
+
+        let results = sync(tag, &options.clone().clear_tag_cache(true)).expect("Sync failed.");
+        assert_eq!(results.compute.len(), 3);
+
+        // The caches still count exactly one reference per tag, so removing a file from
+        // the other tag only removes its label
+        remove_file(temp_dir.path().join("a.txt")).unwrap();
+        let results = sync(tag2, &options).expect("Sync failed.");
+        assert_eq!(results.remove_tag.len(), 1);
+        assert!(results.delete.is_empty());
+        let results = sync(tag, &options).expect("Sync failed.");
+        assert_eq!(results.delete.len(), 1);
+    }
+
+    #[test]
+    fn test_sync_duplicate_content() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "same")
+            .add("b.txt", "same")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
             provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+
+        // Both paths are added in the same batch, but the content is only computed once
+        let results = sync(tag, &options).expect("Sync failed.");
+        assert_eq!(results.compute.len(), 1);
+        assert_eq!(results.add_tag.len(), 1);
+
+        // Each path holds its own reference
+        remove_file(temp_dir.path().join("a.txt")).unwrap();
+        let results = sync(tag, &options).expect("Sync failed.");
+        assert_eq!(results.remove_tag.len(), 1);
+        assert!(results.delete.is_empty());
+        let results = delete_tag(tag, &options).expect("Delete failed.");
+        assert_eq!(results.delete.len(), 1);
+    }
+
+    fn merge_batches(tag: &Tag, options: &SyncOptions, batch_size: usize) -> SyncResults {
+        let mut merged = SyncResults::default();
+        sync_in_batches(tag, options, batch_size, |results| {
+            let len = results.action_count();
+            assert!(len <= batch_size, "batch of {} files", len);
+            merged.append(results);
+            Ok(())
         })
-        .expect("Sync failed");
-        println!("Second sync took {:?}", ti.elapsed());
-        assert_eq!(results.0.len(), 0);
-        assert_eq!(results.1.len(), 0);
+        .expect("Sync failed.");
+        merged
     }
 
     #[test]
-    fn test_sync_v3() {
-        // Create temp directory
+    fn test_sync_in_batches() {
         let temp_dir = TempDirBuilder::new()
-            .add("dir1/file1.txt", "File 1")
-            .add("dir1/file2.txt", "File 2")
-            .add("dir2/file3.txt", "File 3")
-            .add("dir2/subdir/continue.py", "[continue for i in range(10)]")
-            .add("__init__.py", "a = 5")
+            .add("a.txt", "A")
+            .add("b.txt", "B")
+            .add("c.txt", "same")
+            .add("dir/d.txt", "D")
+            .add("dir/e.txt", "same")
+            .add("dir/sub/f.txt", "F")
+            .add("other/g.txt", "G")
             .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let (_other_dir, other_root) = temp_index_root();
+        let other_options = SyncOptions::new().index_root(other_root);
+
+        // The same actions as a normal sync, and the same tree saved
+        let check = |expected_changes: bool| {
+            let merged = merge_batches(tag, &options, 2);
+            let expected = sync(tag, &other_options).expect("Sync failed.");
+            assert_eq!(merged, expected);
+            assert_eq!(merged == SyncResults::default(), !expected_changes);
+            assert_eq!(
+                get_root_hash(tag, &options).unwrap(),
+                get_root_hash(tag, &other_options).unwrap()
+            );
+            assert!(sync(tag, &options).unwrap() == SyncResults::default());
+        };
+        check(true);
+        check(false);
+
+        fs::write(temp_dir.path().join("a.txt"), "A2").unwrap();
+        fs::write(temp_dir.path().join("dir/sub/h.txt"), "H").unwrap();
+        remove_file(temp_dir.path().join("b.txt")).unwrap();
+        check(true);
+
+        // A directory that goes away takes everything in it along
+        fs::remove_dir_all(temp_dir.path().join("dir")).unwrap();
+        let results = merge_batches(tag, &options, 2);
+        assert_eq!(results.delete.len(), 3);
+        assert_eq!(results.remove_tag.len(), 1);
+        assert!(sync(tag, &options).unwrap() == SyncResults::default());
 
+        // Forcing reports everything again
+        let results = merge_batches(tag, &options.clone().clear_tag_cache(true), 2);
+        assert_eq!(results.compute.len(), 3);
+        let results = merge_batches(tag, &options.clone().force(true).dry_run(true), 2);
+        assert_eq!(results.compute.len(), 3);
+    }
+
+    #[test]
+    fn test_sync_throttled() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", &"A".repeat(1000))
+            .add("b.txt", &"B".repeat(1000))
+            .add("dir/c.txt", &"C".repeat(1000))
+            .create();
         let tag = &Tag {
             dir: temp_dir.path(),
-            branch: "BRANCH",
+            branch: "main",
             provider_id: "default",
         };
-        // Sync once
-        sync(&tag).expect("Sync failed.");
+        let (_index_dir, index_root) = temp_index_root();
+        let (_other_dir, other_root) = temp_index_root();
+        let throttle = Throttle {
+            max_bytes_per_sec: Some(10_000),
+            batch_pause: Some(Duration::from_millis(50)),
+            low_priority: true,
+        };
+        let options = SyncOptions::new().index_root(index_root).throttle(throttle);
 
-        // Make changes
-        let mut file = File::create(temp_dir.path().join("dir1/file1.txt")).unwrap();
-        file.write_all(b"File 1 changed").unwrap();
-        let mut file = File::create(temp_dir.path().join("dir2/file3.txt")).unwrap();
-        file.write_all(b"File 3 changed").unwrap();
+        // The same results, just slower: 3000 bytes at 10000 a second take 300ms, and there are
+        // two batches to pause after
+        let start = Instant::now();
+        let mut batches = 0;
+        sync_in_batches(tag, &options, 2, |_| {
+            batches += 1;
+            Ok(())
+        })
+        .expect("Sync failed.");
+        assert_eq!(batches, 2);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert!(!throttle::is_lowered());
+        let other_options = SyncOptions::new().index_root(other_root);
+        sync(tag, &other_options).expect("Sync failed.");
+        assert_eq!(
+            get_root_hash(tag, &options).unwrap(),
+            get_root_hash(tag, &other_options).unwrap()
+        );
 
-        // Sync again
-        let results = sync(tag).expect("Sync failed.");
+        // Clones share the limit, so syncing several providers doesn't multiply it
+        let limiter = |options: &SyncOptions| options.read_limiter.clone().unwrap();
+        assert!(Arc::ptr_eq(
+            &limiter(&options),
+            &limiter(&options.for_provider("other"))
+        ));
+    }
 
-        // Check results
-        assert_eq!(results.0.len(), 2);
-        assert_eq!(results.1.len(), 2);
-        assert_eq!(results.2.len(), 0);
-        assert_eq!(results.3.len(), 0);
+    #[test]
+    fn test_sync_unsaved_buffers() {
+        let temp_dir = TempDirBuilder::new()
+            .add(".continueignore", "ignored.txt")
+            .add("a.txt", "A")
+            .add("dir/b.txt", "B")
+            .add("ignored.txt", "I")
+            .create();
+        let edited_dir = TempDirBuilder::new()
+            .add(".continueignore", "ignored.txt")
+            .add("a.txt", "A edited")
+            .add("dir/b.txt", "B")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let buffers = options
+            .clone()
+            .unsaved_buffer(temp_dir.path().join("a.txt"), "A edited\n")
+            .unsaved_buffer(temp_dir.path().join("ignored.txt"), "I edited\n")
+            .unsaved_buffer(temp_dir.path().join("untitled.txt"), "U\n");
 
-        // Start a new branch
+        // What the buffers have, as if they'd been saved, and only for files the walk finds
+        let results = sync(tag, &buffers).unwrap();
+        assert_eq!(results.compute.len(), 2);
+        assert!(results.warnings.is_empty());
+        let (_edited_index_dir, edited_root) = temp_index_root();
+        let edited_tag = &Tag {
+            dir: edited_dir.path(),
+            ..*tag
+        };
+        let edited_options = SyncOptions::new().index_root(edited_root);
+        sync(edited_tag, &edited_options).unwrap();
+        let edited_hash = get_root_hash(edited_tag, &edited_options).unwrap();
+        assert_eq!(get_root_hash(tag, &buffers).unwrap(), edited_hash);
+        assert!(sync(tag, &buffers).unwrap() == SyncResults::default());
 
-        let tag2 = &Tag {
+        // Once the buffers are gone, so is their content
+        let results = sync(tag, &options).unwrap();
+        assert_eq!((results.compute.len(), results.delete.len()), (1, 1));
+        assert_eq!(results.compute[0].relative_path, "a.txt");
+
+        // The same again in batches
+        merge_batches(tag, &buffers, 1);
+        assert_eq!(get_root_hash(tag, &buffers).unwrap(), edited_hash);
+    }
+
+    #[test]
+    fn test_index_file_system() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("b.txt", "B")
+            .create();
+        let tag = &Tag {
             dir: temp_dir.path(),
-            branch: "BRANCH2",
+            branch: "main",
             provider_id: "default",
         };
-        // Sync again
-        let results = sync(tag2).expect("Sync failed.");
+        let (_index_dir, index_root) = temp_index_root();
+        let memory = Arc::new(MemoryFileSystem::new());
+        let options = SyncOptions::new()
+            .index_root(index_root.clone())
+            .index_file_system(memory.clone());
 
-        // Check results
-        assert_eq!(results.0.len(), 0);
-        assert_eq!(results.1.len(), 0);
-        assert_eq!(results.2.len(), 5);
-        assert_eq!(results.3.len(), 0);
+        assert_eq!(sync(tag, &options).unwrap().compute.len(), 2);
+        assert!(sync(tag, &options).unwrap() == SyncResults::default());
 
-        // Delete a file in this new branch
-        remove_file(temp_dir.path().join("dir1/file2.txt")).unwrap();
+        // The caches are only in memory, where the next sync finds them
+        let global_path = index_root.provider_dir("default").join(".index_cache");
+        let tag_path = IndexCache::index_cache_path_for_tag(tag, &index_root);
+        for path in [&global_path, &tag_path] {
+            assert!(memory.metadata(path).is_ok());
+            assert!(!path.exists());
+        }
+        assert!(crate::sync::verify_index(tag, &options)
+            .unwrap()
+            .is_consistent());
 
-        // Sync again
-        let results = sync(tag2).expect("Sync failed.");
+        fs::write(temp_dir.path().join("a.txt"), "A2").unwrap();
+        let results = sync(tag, &options).unwrap();
+        assert_eq!((results.compute.len(), results.delete.len()), (1, 1));
+        let global_cache = DiskSet::read(&*memory, &global_path).unwrap();
+        assert_eq!(global_cache.len(), 2);
 
-        // Check results
-        assert_eq!(results.0.len(), 0);
-        assert_eq!(results.1.len(), 0);
-        assert_eq!(results.2.len(), 0);
-        assert_eq!(results.3.len(), 1);
+        // The trees, rev_tags, sync times and locks are in memory too: all the walk of the disk
+        // leaves there is the global ignore file it reads
+        let on_disk: Vec<_> = fs::read_dir(index_root.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(on_disk, [".continueignore"]);
+        assert!(memory.metadata(&tree_path(tag, &index_root)).is_ok());
+        assert!(memory
+            .metadata(&index_root.rev_tags_file("default"))
+            .is_ok());
+        let tags = list_tags(&options, None, None).unwrap();
+        assert_eq!(tags.len(), 1);
+        assert!(tags[0].last_sync.is_some());
+
+        assert_eq!(delete_tag(tag, &options).unwrap().delete.len(), 2);
+        assert!(memory.metadata(&index_root.tag_dir(tag)).is_err());
+        assert!(list_tags(&options, None, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sync_in_memory() {
+        let memory = Arc::new(MemoryFileSystem::new());
+        memory.insert("/workspace/a.txt", "A");
+        memory.insert("/workspace/src/b.rs", "B");
+        let temp_dir = TempDirBuilder::new().create();
+        let index_dir = temp_dir.path().join("index");
+        let options = SyncOptions::new()
+            .index_root(IndexRoot::new(&index_dir))
+            .file_system(memory.clone())
+            .index_file_system(memory.clone());
+        let tag = &Tag {
+            dir: Path::new("/workspace"),
+            branch: "main",
+            provider_id: "default",
+        };
+
+        assert_eq!(sync(tag, &options).unwrap().compute.len(), 2);
+        assert!(is_index_up_to_date(tag, &options).unwrap());
+        memory.insert("/workspace/a.txt", "A2");
+        let results = sync(tag, &options).unwrap();
+        assert_eq!((results.compute.len(), results.delete.len()), (1, 1));
+        assert!(get_root_hash(tag, &options).unwrap().is_some());
+        assert!(verify_index(tag, &options).unwrap().is_consistent());
+
+        // Nothing at all was written to the disk
+        assert!(!index_dir.exists());
+        assert!(memory.metadata(&index_dir.join(".version")).is_ok());
+    }
+
+    #[test]
+    fn test_sync_file_system() {
+        let files = [
+            (".continueignore", "*.log\n"),
+            ("a.txt", "A"),
+            ("b.log", "B"),
+            ("src/c.rs", "C"),
+            ("src/.hidden", "H"),
+        ];
+        let memory = Arc::new(MemoryFileSystem::new());
+        let mut builder = TempDirBuilder::new();
+        for (path, contents) in files {
+            // `TempDirBuilder` ends each file with a newline
+            memory.insert(Path::new("/workspace").join(path), format!("{contents}\n"));
+            builder.add(path, contents);
+        }
+        let temp_dir = builder.create();
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new()
+            .index_root(index_root)
+            .file_system(memory.clone());
+        let tag = &Tag {
+            dir: Path::new("/workspace"),
+            branch: "main",
+            provider_id: "default",
+        };
+
+        // The same files as the walk of the disk finds, with the same ignore rules
+        let results = sync(tag, &options).unwrap();
+        let mut computed: Vec<_> = results.compute.iter().map(|item| &item.path).collect();
+        computed.sort();
+        assert_eq!(computed, ["/workspace/a.txt", "/workspace/src/c.rs"]);
+        let (_other_dir, other_root) = temp_index_root();
+        let disk_tag = &Tag {
+            dir: temp_dir.path(),
+            ..*tag
+        };
+        sync(disk_tag, &SyncOptions::new().index_root(other_root.clone())).unwrap();
+        assert_eq!(
+            get_root_hash(tag, &options).unwrap(),
+            get_root_hash(disk_tag, &SyncOptions::new().index_root(other_root)).unwrap()
+        );
+
+        memory.insert("/workspace/src/c.rs", "C2");
+        memory.remove("/workspace/a.txt");
+        let results = sync(tag, &options).unwrap();
+        assert_eq!(results.compute.len(), 1);
+        assert_eq!(results.delete.len(), 2);
+        assert!(sync(tag, &options).unwrap().compute.is_empty());
+    }
+
+    #[test]
+    fn test_sync_archives() {
+        let temp_dir = TempDirBuilder::new().add("a.txt", "A").create();
+        let dir = temp_dir.path();
+        let jar = dir.join("deps/lib-sources.jar");
+        fs::create_dir(dir.join("deps")).unwrap();
+        let class: &[u8] = &[0xca, 0xfe, 0xba, 0xbe, 0, 0, 0, 0x34];
+        let write_jar = |source: &[u8]| {
+            let files = [("com/Lib.java", source), ("com/Lib.class", class)];
+            fs::write(&jar, archive::tests::zip(&files)).unwrap();
+        };
+        write_jar(b"class Lib {}");
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root).archives(true);
+        let tag = &Tag {
+            dir,
+            branch: "main",
+            provider_id: "default",
+        };
+
+        // The jar's source is a file in the tree, and the compiled class is left out quietly
+        let results = sync(tag, &options).unwrap();
+        let mut computed: Vec<_> = results.compute.iter().map(|item| &item.path).collect();
+        computed.sort();
+        let member = jar.join("com/Lib.java");
+        assert_eq!(computed, [&dir.join("a.txt"), &member]);
+        assert!(results.warnings.is_empty());
+        assert_eq!(read_indexed_file(&member).unwrap(), b"class Lib {}");
+        assert_eq!(read_indexed_file(&dir.join("a.txt")).unwrap(), b"A\n");
+        assert!(read_indexed_file(&jar.join("com/Missing.java")).is_err());
+        assert!(sync(tag, &options).unwrap().compute.is_empty());
+
+        // A change inside the archive is a change to that member
+        write_jar(b"class Lib { int x; }");
+        let results = sync(tag, &options).unwrap();
+        assert_eq!(results.compute.len(), 1);
+        assert_eq!(results.delete.len(), 1);
+        assert_eq!(results.delete[0].path, member);
+
+        // Without the option, archives are ignored as they are by default
+        let results = sync(tag, &options.clone().archives(false)).unwrap();
+        assert_eq!(results.delete.len(), 1);
+        assert!(results.warnings.is_empty());
+    }
+
+    #[test]
+    fn test_sync_store_objects() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.rs", "fn a() {}")
+            .add("b.rs", "fn b() {}")
+            .create();
+        let dir = temp_dir.path();
+        let jar = dir.join("lib.jar");
+        fs::write(&jar, archive::tests::zip(&[("Lib.java", b"class Lib {}")])).unwrap();
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new()
+            .index_root(index_root)
+            .store_objects(true);
+        let tag = &Tag {
+            dir,
+            branch: "main",
+            provider_id: "default",
+        };
+        let object = |item: &SyncResultItem, options: &SyncOptions| {
+            read_object(parse_hash(&item.hash).unwrap(), "default", options).unwrap()
+        };
+
+        // Nothing is saved by a dry run
+        let results = sync(tag, &options.clone().dry_run(true)).unwrap();
+        assert_eq!(object(&results.compute[0], &options), None);
+
+        // What was hashed can be read back after the file changes
+        let results = sync(tag, &options.clone().archives(true)).unwrap();
+        assert_eq!(results.compute.len(), 3);
+        let item = |name: &str| {
+            let path = dir.join(name).display().to_string();
+            results
+                .compute
+                .iter()
+                .find(|item| item.path == path)
+                .unwrap()
+        };
+        fs::write(dir.join("a.rs"), "fn a() { changed }").unwrap();
+        fs::remove_file(&jar).unwrap();
+        assert_eq!(object(item("a.rs"), &options).unwrap(), b"fn a() {}\n");
+        assert_eq!(object(item("b.rs"), &options).unwrap(), b"fn b() {}\n");
+        assert_eq!(
+            object(item("lib.jar/Lib.java"), &options).unwrap(),
+            b"class Lib {}"
+        );
+
+        // An unsaved buffer is saved as it was hashed
+        let buffer = options
+            .clone()
+            .unsaved_buffer(dir.join("b.rs"), "fn b() { unsaved }");
+        let results = sync(tag, &buffer).unwrap();
+        assert_eq!(results.compute.len(), 2);
+        assert!(results.warnings.is_empty());
+        let contents: Vec<Vec<u8>> = results
+            .compute
+            .iter()
+            .map(|item| object(item, &options).unwrap())
+            .collect();
+        assert_eq!(
+            contents,
+            [&b"fn a() { changed }"[..], b"fn b() { unsaved }"]
+        );
+
+        // Another provider has a store of its own
+        assert_eq!(read_object([0; 20], "other", &options).unwrap(), None);
+    }
+
+    #[test]
+    fn test_sync_chunking() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.rs", "fn a() {}\nfn b() {}")
+            .add("copy.rs", "fn a() {}\nfn b() {}")
+            .create();
+        let (_index_dir, index_root) = temp_index_root();
+        let chunking = ChunkOptions {
+            max_size: 10,
+            overlap: 0,
+            syntax_aware: true,
+        };
+        let options = SyncOptions::new().index_root(index_root).chunking(chunking);
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+
+        // The same chunks as the file's content gives, a dry run or not
+        let expected = chunk(Path::new("a.rs"), b"fn a() {}\nfn b() {}\n", chunking);
+        assert_eq!(expected.len(), 2);
+        for options in [options.clone().dry_run(true), options] {
+            let results = sync(tag, &options).unwrap();
+            assert_eq!(results.compute.len(), 1);
+            assert_eq!(results.compute[0].chunks, expected);
+            assert!(results.add_tag[0].chunks.is_empty());
+        }
     }
 }
@@ -0,0 +1,576 @@
+//! The object store, which keeps the content of every file a provider is asked to compute,
+//! keyed by its hash, with `SyncOptions::store_objects`
+//!
+//! The hash a sync reports stands for what the file held when it was hashed, but the file may
+//! have changed again by the time anything downstream gets to it. With the content saved as the
+//! file is reported, re-chunking or re-embedding it later gets exactly the bytes that were
+//! hashed, whatever the workspace holds now. Each object is the file as it was read (before any
+//! transcoding or normalization), so is what hashes to its name under the options it was synced
+//! with.
+//!
+//! Syncs save objects loose, a file each in the provider's `objects` directory, named after
+//! their hash and grouped by its first two digits, as git does. `pack_objects` moves them into
+//! packs in `objects/pack/`, to save on disk space and inodes: a `.pack` file holding the
+//! objects back to back, each gzipped if that makes it smaller, and a `.idx` file listing their
+//! hashes in order with where each one is. A pack is named after the hash of its index, and
+//! the index is written after the pack, so a pack only counts once both are there. `gc`
+//! removes the objects that no tag references any more, rewriting the packs that hold any.
+
+use super::{
+    error::{Result, SyncError},
+    file_system::FileSystem,
+    gzip,
+    index_root::IndexRoot,
+    lock,
+    merkle::{hash_string, parse_hash, BinaryReader, ObjectHash},
+    options::SyncOptions,
+};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::HashSet,
+    convert::TryInto,
+    io::{ErrorKind, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+const PACK_MAGIC: &[u8; 4] = b"CSPK";
+const INDEX_MAGIC: &[u8; 4] = b"CSPI";
+const PACK_VERSION: u8 = 1;
+
+/// A pack is closed once it holds this much, so that writing one never needs more memory
+const MAX_PACK_SIZE: usize = 64 * 1024 * 1024;
+
+/// Where an object is in a pack
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct PackEntry {
+    hash: ObjectHash,
+    offset: u64,
+    len: u64,
+    compressed: bool,
+}
+
+/// A pack and its index
+#[derive(Debug)]
+struct Pack {
+    /// The `.pack` file, next to which the `.idx` is
+    path: PathBuf,
+    size: u64,
+
+    /// In order of their hashes
+    entries: Vec<PackEntry>,
+}
+
+impl Pack {
+    fn find(&self, hash: &ObjectHash) -> Option<&PackEntry> {
+        let index = self
+            .entries
+            .binary_search_by(|entry| entry.hash.cmp(hash))
+            .ok()?;
+        Some(&self.entries[index])
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.path.with_extension("idx")
+    }
+}
+
+/// Where an object being packed comes from
+enum Source<'a> {
+    Loose(PathBuf),
+    Packed(&'a Pack, PackEntry),
+}
+
+/// The index of a pack holding `entries`, in order
+fn encode_index(entries: &[PackEntry]) -> Vec<u8> {
+    let mut bytes = INDEX_MAGIC.to_vec();
+    bytes.push(PACK_VERSION);
+    bytes.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+    for entry in entries {
+        bytes.extend_from_slice(&entry.hash);
+        bytes.extend_from_slice(&entry.offset.to_le_bytes());
+        bytes.extend_from_slice(&entry.len.to_le_bytes());
+        bytes.push(entry.compressed as u8);
+    }
+    bytes
+}
+
+fn decode_index(bytes: &[u8], pack_size: u64) -> std::result::Result<Vec<PackEntry>, String> {
+    let mut reader = BinaryReader { bytes };
+    if reader.take(INDEX_MAGIC.len())? != INDEX_MAGIC {
+        return Err("not a pack index".to_string());
+    }
+    let version = reader.u8()?;
+    if version != PACK_VERSION {
+        return Err(format!("unknown pack version {version}"));
+    }
+    let count = reader.u64()?;
+    let mut entries: Vec<PackEntry> = Vec::new();
+    for _ in 0..count {
+        let entry = PackEntry {
+            hash: reader.hash()?,
+            offset: reader.u64()?,
+            len: reader.u64()?,
+            compressed: reader.u8()? != 0,
+        };
+        if entry
+            .offset
+            .checked_add(entry.len)
+            .is_none_or(|end| end > pack_size)
+        {
+            return Err("an object is past the end of the pack".to_string());
+        }
+        if entries.last().is_some_and(|last| last.hash >= entry.hash) {
+            return Err("objects out of order".to_string());
+        }
+        entries.push(entry);
+    }
+    match reader.bytes.is_empty() {
+        true => Ok(entries),
+        false => Err("trailing bytes".to_string()),
+    }
+}
+
+/// The result of `pack_objects`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PackResults {
+    /// Loose objects moved into packs
+    pub packed_objects: usize,
+
+    /// Packs written, by packing loose objects or merging smaller packs
+    pub packs_written: usize,
+
+    /// Smaller packs whose objects went into the ones written
+    pub packs_merged: usize,
+}
+
+/// A provider's objects, in the index's file system (see `SyncOptions::index_file_system`)
+pub(crate) struct ObjectStore {
+    file_system: Arc<dyn FileSystem>,
+    dir: PathBuf,
+    fsync: bool,
+
+    /// The packs there were when it was opened, or last changed
+    packs: Vec<Pack>,
+}
+
+impl ObjectStore {
+    pub(crate) fn open(
+        index_root: &IndexRoot,
+        provider_id: &str,
+        options: &SyncOptions,
+    ) -> Result<Self> {
+        let mut store = ObjectStore {
+            file_system: options.resolve_index_file_system(),
+            dir: index_root.objects_dir(provider_id),
+            fsync: options.fsync,
+            packs: Vec::new(),
+        };
+        store.packs = store.load_packs()?;
+        Ok(store)
+    }
+
+    /// <objects>/<first 2 hex digits>/<the other 38>
+    fn path(&self, hash: &ObjectHash) -> PathBuf {
+        let name = hash_string(*hash);
+        let (prefix, rest) = name.split_at(2);
+        self.dir.join(prefix).join(rest)
+    }
+
+    fn pack_dir(&self) -> PathBuf {
+        self.dir.join("pack")
+    }
+
+    /// Names in `dir`, or none if it isn't there
+    fn names_in(&self, dir: &Path) -> Result<Vec<String>> {
+        match self.file_system.read_dir(dir) {
+            Ok(names) => Ok(names
+                .into_iter()
+                .filter_map(|name| name.into_string().ok())
+                .collect()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn load_packs(&self) -> Result<Vec<Pack>> {
+        let pack_dir = self.pack_dir();
+        let mut names = self.names_in(&pack_dir)?;
+        names.sort();
+        let mut packs = Vec::new();
+        for name in names {
+            let Some(stem) = name.strip_suffix(".idx") else {
+                continue;
+            };
+            let path = pack_dir.join(format!("{stem}.pack"));
+            let corrupted = |message: String| {
+                SyncError::CorruptedIndex(format!("{}: {message}", path.display()))
+            };
+            let size = match self.file_system.metadata(&path) {
+                Ok(metadata) => metadata.len,
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    return Err(corrupted("missing the pack for its index".to_string()))
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let index = self.file_system.read(&pack_dir.join(&name))?;
+            let entries = decode_index(&index, size).map_err(corrupted)?;
+            packs.push(Pack {
+                path,
+                size,
+                entries,
+            });
+        }
+        Ok(packs)
+    }
+
+    /// Every loose object, with where it is and its size
+    fn loose_objects(&self) -> Result<Vec<(ObjectHash, PathBuf, u64)>> {
+        let mut objects = Vec::new();
+        for prefix in self.names_in(&self.dir)? {
+            if prefix.len() != 2 {
+                continue;
+            }
+            let dir = self.dir.join(&prefix);
+            for name in self.names_in(&dir)? {
+                // Anything else, such as a temporary file left by a crash, isn't an object
+                let Some(hash) = parse_hash(&format!("{prefix}{name}")) else {
+                    continue;
+                };
+                let path = dir.join(name);
+                let size = self.file_system.metadata(&path)?.len;
+                objects.push((hash, path, size));
+            }
+        }
+        objects.sort();
+        Ok(objects)
+    }
+
+    fn packed(&self, hash: &ObjectHash) -> Option<(&Pack, &PackEntry)> {
+        self.packs
+            .iter()
+            .find_map(|pack| Some((pack, pack.find(hash)?)))
+    }
+
+    pub(crate) fn contains(&self, hash: &ObjectHash) -> bool {
+        self.packed(hash).is_some() || self.file_system.metadata(&self.path(hash)).is_ok()
+    }
+
+    pub(crate) fn get(&self, hash: &ObjectHash) -> Result<Option<Vec<u8>>> {
+        match self.file_system.read(&self.path(hash)) {
+            Ok(contents) => return Ok(Some(contents)),
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        let Some((pack, entry)) = self.packed(hash) else {
+            return Ok(None);
+        };
+        let stored = self.read_entry(pack, entry)?;
+        match entry.compressed {
+            true => gzip::decompress(&stored).map(Some).map_err(|message| {
+                SyncError::CorruptedIndex(format!("{}: {message}", pack.path.display()))
+            }),
+            false => Ok(Some(stored)),
+        }
+    }
+
+    pub(crate) fn put(&self, hash: &ObjectHash, contents: &[u8]) -> Result<()> {
+        let path = self.path(hash);
+        self.file_system
+            .create_dir_all(path.parent().unwrap_or(Path::new("")))?;
+        self.file_system.write(&path, contents, self.fsync)?;
+        Ok(())
+    }
+
+    /// The object as it's kept in the pack
+    fn read_entry(&self, pack: &Pack, entry: &PackEntry) -> Result<Vec<u8>> {
+        let mut file = self.file_system.open(&pack.path)?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+        let mut stored = vec![0; entry.len.try_into().unwrap_or(usize::MAX)];
+        file.read_exact(&mut stored)?;
+        Ok(stored)
+    }
+
+    /// Write the objects from `sources` into new packs, in order of their hashes, returning
+    /// the `.pack` files written
+    fn write_packs(&self, mut sources: Vec<(ObjectHash, Source)>) -> Result<Vec<PathBuf>> {
+        sources.sort_by_key(|(hash, _)| *hash);
+        sources.dedup_by_key(|(hash, _)| *hash);
+        let mut written = Vec::new();
+        let mut pack = PACK_MAGIC.to_vec();
+        pack.push(PACK_VERSION);
+        let mut entries = Vec::new();
+        for (index, (hash, source)) in sources.iter().enumerate() {
+            let (stored, compressed) = match source {
+                Source::Loose(path) => {
+                    let contents = self.file_system.read(path)?;
+                    let compressed = gzip::compress(&contents);
+                    match compressed.len() < contents.len() {
+                        true => (compressed, true),
+                        false => (contents, false),
+                    }
+                }
+                Source::Packed(pack, entry) => (self.read_entry(pack, entry)?, entry.compressed),
+            };
+            entries.push(PackEntry {
+                hash: *hash,
+                offset: pack.len() as u64,
+                len: stored.len() as u64,
+                compressed,
+            });
+            pack.extend_from_slice(&stored);
+            if pack.len() >= MAX_PACK_SIZE || index + 1 == sources.len() {
+                written.push(self.write_pack(&pack, &entries)?);
+                pack.truncate(PACK_MAGIC.len() + 1);
+                entries.clear();
+            }
+        }
+        Ok(written)
+    }
+
+    fn write_pack(&self, pack: &[u8], entries: &[PackEntry]) -> Result<PathBuf> {
+        let index = encode_index(entries);
+        let name = hash_string(Sha1::digest(&index).into());
+        let pack_dir = self.pack_dir();
+        self.file_system.create_dir_all(&pack_dir)?;
+        let path = pack_dir.join(format!("pack-{name}.pack"));
+        self.file_system.write(&path, pack, self.fsync)?;
+        self.file_system
+            .write(&path.with_extension("idx"), &index, self.fsync)?;
+        Ok(path)
+    }
+
+    /// Remove a pack, index first so that the pack is never listed without being there
+    fn remove_pack(&self, pack: &Pack) -> Result<()> {
+        self.file_system.remove_file(&pack.index_path())?;
+        self.file_system.remove_file(&pack.path)?;
+        Ok(())
+    }
+
+    fn packed_size(&self, paths: &[PathBuf]) -> Result<u64> {
+        let mut size = 0;
+        for path in paths {
+            size += self.file_system.metadata(path)?.len;
+            size += self.file_system.metadata(&path.with_extension("idx"))?.len;
+        }
+        Ok(size)
+    }
+
+    /// Remove the objects that aren't `live`, rewriting the packs that hold any without them.
+    /// Returns how many were removed, and how many bytes that freed (none for a dry run)
+    pub(crate) fn remove_unreferenced(
+        &mut self,
+        live: &HashSet<ObjectHash>,
+        dry_run: bool,
+    ) -> Result<(usize, u64)> {
+        let mut removed = 0;
+        let mut reclaimed = 0;
+        for (hash, path, size) in self.loose_objects()? {
+            if !live.contains(&hash) {
+                removed += 1;
+                if !dry_run {
+                    self.file_system.remove_file(&path)?;
+                    reclaimed += size;
+                }
+            }
+        }
+        for pack in &self.packs {
+            let kept: Vec<(ObjectHash, Source)> = pack
+                .entries
+                .iter()
+                .filter(|entry| live.contains(&entry.hash))
+                .map(|entry| (entry.hash, Source::Packed(pack, *entry)))
+                .collect();
+            if kept.len() == pack.entries.len() {
+                continue;
+            }
+            removed += pack.entries.len() - kept.len();
+            if dry_run {
+                continue;
+            }
+            let before = self.packed_size(std::slice::from_ref(&pack.path))?;
+            let written = self.write_packs(kept)?;
+            self.remove_pack(pack)?;
+            reclaimed += before.saturating_sub(self.packed_size(&written)?);
+        }
+        if !dry_run {
+            self.packs = self.load_packs()?;
+        }
+        Ok((removed, reclaimed))
+    }
+
+    /// Move the loose objects into packs, along with the objects in packs less than half full,
+    /// so that at most one pack is left less than half full
+    pub(crate) fn pack(&mut self) -> Result<PackResults> {
+        let loose = self.loose_objects()?;
+        let small: Vec<&Pack> = self
+            .packs
+            .iter()
+            .filter(|pack| pack.size < MAX_PACK_SIZE as u64 / 2)
+            .collect();
+        let mut results = PackResults::default();
+        let mut sources: Vec<(ObjectHash, Source)> = Vec::new();
+        for (hash, path, _) in &loose {
+            // Left by a pack that was interrupted before the loose objects were removed
+            if self.packed(hash).is_none() {
+                sources.push((*hash, Source::Loose(path.clone())));
+                results.packed_objects += 1;
+            }
+        }
+        if sources.is_empty() && small.len() < 2 {
+            for (_, path, _) in &loose {
+                self.file_system.remove_file(path)?;
+            }
+            return Ok(results);
+        }
+        for pack in &small {
+            sources.extend(
+                pack.entries
+                    .iter()
+                    .map(|entry| (entry.hash, Source::Packed(pack, *entry))),
+            );
+        }
+        let written = self.write_packs(sources)?;
+        results.packs_written = written.len();
+        for pack in small {
+            // A merge can come out the same as one of the packs merged
+            if !written.contains(&pack.path) {
+                self.remove_pack(pack)?;
+                results.packs_merged += 1;
+            }
+        }
+        for (_, path, _) in &loose {
+            self.file_system.remove_file(path)?;
+        }
+        self.packs = self.load_packs()?;
+        Ok(results)
+    }
+}
+
+/// The content of the file with this hash, as it was when a sync of one of the provider's tags
+/// with `SyncOptions::store_objects` reported it to compute, or None if it wasn't saved
+pub fn read_object(
+    hash: ObjectHash,
+    provider_id: &str,
+    options: &SyncOptions,
+) -> Result<Option<Vec<u8>>> {
+    let index_root = options.resolve_index_root()?;
+    ObjectStore::open(&index_root, provider_id, options)?.get(&hash)
+}
+
+/// Move the provider's loose objects into packs (see `objects.rs`), merging the packs that
+/// aren't yet half full along with them, so that a big object store doesn't take a file per
+/// object. Objects no tag references any more are removed by `gc`
+pub fn pack_objects(provider_id: &str, options: &SyncOptions) -> Result<PackResults> {
+    let index_root = options.resolve_index_root()?;
+    let file_system = options.resolve_index_file_system();
+    file_system.create_dir_all(&index_root.provider_dir(provider_id))?;
+    let lock_path = index_root.provider_lock(provider_id);
+    let _lock = lock::lock_index(options, &lock_path, options.lock_wait)?;
+    ObjectStore::open(&index_root, provider_id, options)?.pack()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDirBuilder;
+    use std::fs;
+
+    #[test]
+    fn test_object_store() {
+        let temp_dir = TempDirBuilder::new().create();
+        let index_root = IndexRoot::new(temp_dir.path());
+        let options = SyncOptions::new();
+        let mut store = ObjectStore::open(&index_root, "default", &options).unwrap();
+        let hash = [0xab; 20];
+
+        assert!(!store.contains(&hash));
+        assert_eq!(store.get(&hash).unwrap(), None);
+        store.put(&hash, b"contents").unwrap();
+        assert!(store.contains(&hash));
+        assert_eq!(store.get(&hash).unwrap().as_deref(), Some(&b"contents"[..]));
+        let objects = temp_dir.path().join("providers/default/objects");
+        assert!(objects.join("ab").join("ab".repeat(19)).is_file());
+
+        // Packed objects are still there, compressed if that helps
+        let repetitive = b"fn main() {}\n".repeat(100);
+        store.put(&[1; 20], &repetitive).unwrap();
+        store.put(&[2; 20], b"x").unwrap();
+        let results = store.pack().unwrap();
+        assert_eq!(
+            results,
+            PackResults {
+                packed_objects: 3,
+                packs_written: 1,
+                packs_merged: 0,
+            }
+        );
+        assert!(!objects.join("ab").join("ab".repeat(19)).exists());
+        assert_eq!(fs::read_dir(objects.join("pack")).unwrap().count(), 2);
+        assert!(store.packs[0].size < repetitive.len() as u64);
+        let store = ObjectStore::open(&index_root, "default", &options).unwrap();
+        assert_eq!(store.get(&hash).unwrap().as_deref(), Some(&b"contents"[..]));
+        assert_eq!(store.get(&[1; 20]).unwrap(), Some(repetitive));
+        assert_eq!(store.get(&[2; 20]).unwrap().as_deref(), Some(&b"x"[..]));
+        assert!(!store.contains(&[3; 20]));
+    }
+
+    #[test]
+    fn test_object_store_gc() {
+        let temp_dir = TempDirBuilder::new().create();
+        let index_root = IndexRoot::new(temp_dir.path());
+        let mut store = ObjectStore::open(&index_root, "default", &SyncOptions::new()).unwrap();
+        for byte in 1..=3 {
+            store.put(&[byte; 20], &[byte; 10]).unwrap();
+        }
+        store.pack().unwrap();
+        for byte in 4..=5 {
+            store.put(&[byte; 20], &[byte; 10]).unwrap();
+        }
+        let live = HashSet::from([[1; 20], [4; 20]]);
+
+        assert_eq!(store.remove_unreferenced(&live, true).unwrap(), (3, 0));
+        assert!(store.contains(&[2; 20]) && store.contains(&[5; 20]));
+        let (removed, reclaimed) = store.remove_unreferenced(&live, false).unwrap();
+        assert_eq!(removed, 3);
+        assert!(reclaimed > 0);
+        for byte in 1..=5 {
+            assert_eq!(store.contains(&[byte; 20]), live.contains(&[byte; 20]));
+        }
+        assert_eq!(store.get(&[1; 20]).unwrap(), Some(vec![1; 10]));
+
+        // The pack rewritten without them is merged with the loose objects next time
+        store.put(&[6; 20], b"6").unwrap();
+        let results = store.pack().unwrap();
+        assert_eq!(
+            results,
+            PackResults {
+                packed_objects: 2,
+                packs_written: 1,
+                packs_merged: 1,
+            }
+        );
+        assert_eq!(store.packs.len(), 1);
+        assert_eq!(store.packs[0].entries.len(), 3);
+    }
+
+    #[test]
+    fn test_corrupted_pack_index() {
+        let temp_dir = TempDirBuilder::new().create();
+        let index_root = IndexRoot::new(temp_dir.path());
+        let options = SyncOptions::new();
+        let mut store = ObjectStore::open(&index_root, "default", &options).unwrap();
+        store.put(&[1; 20], b"1").unwrap();
+        store.pack().unwrap();
+        let index = store.packs[0].index_path();
+        let mut bytes = fs::read(&index).unwrap();
+        bytes.push(0);
+        fs::write(&index, bytes).unwrap();
+        assert!(matches!(
+            ObjectStore::open(&index_root, "default", &options),
+            Err(SyncError::CorruptedIndex(_))
+        ));
+    }
+}
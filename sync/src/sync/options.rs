@@ -0,0 +1,448 @@
+use super::{
+    chunk::ChunkOptions,
+    error::Result,
+    file_system::{FileSystem, OsFileSystem},
+    index_root::IndexRoot,
+    merkle::{Compression, ContentNormalization, TreeFormat},
+    migrate,
+    throttle::{RateLimiter, Throttle},
+    watch, writable,
+};
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+
+/// Per-call configuration for `sync()`
+///
+/// ```
+/// use sync::sync::{IndexRoot, SyncOptions};
+///
+/// let options = SyncOptions::new()
+///     .index_root(IndexRoot::new("/tmp/continue-index"))
+///     .ignore("*.md")
+///     .dry_run(true);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct SyncOptions {
+    pub(crate) index_root: Option<IndexRoot>,
+    pub(crate) ignore_patterns: Vec<String>,
+    pub(crate) dry_run: bool,
+    pub(crate) force: bool,
+    pub(crate) clear_tag_cache: bool,
+    pub(crate) tree_format: TreeFormat,
+    pub(crate) tree_compression: Compression,
+    pub(crate) mmap: bool,
+    pub(crate) xattr_cache: bool,
+    pub(crate) max_file_size: Option<u64>,
+    pub(crate) storage: IndexStorage,
+    pub(crate) fsync: bool,
+    pub(crate) lock_wait: LockWait,
+    pub(crate) defer_compute: bool,
+    pub(crate) normalize_unicode: Option<bool>,
+    pub(crate) case_insensitive: Option<bool>,
+    pub(crate) follow_symlinks: bool,
+    pub(crate) content_normalization: ContentNormalization,
+    pub(crate) provider_normalization: HashMap<String, ContentNormalization>,
+    pub(crate) text_extensions: Vec<String>,
+    pub(crate) binary_extensions: Vec<String>,
+    pub(crate) min_free_space: Option<u64>,
+    pub(crate) read_only_fallback: bool,
+    pub(crate) watch_debounce: Option<Duration>,
+    pub(crate) watch_max_delay: Option<Duration>,
+    pub(crate) file_system: Option<Arc<dyn FileSystem>>,
+    pub(crate) index_file_system: Option<Arc<dyn FileSystem>>,
+    pub(crate) unsaved_buffers: HashMap<PathBuf, Arc<[u8]>>,
+    pub(crate) archives: bool,
+    pub(crate) store_objects: bool,
+    pub(crate) chunking: Option<ChunkOptions>,
+    pub(crate) throttle: Throttle,
+    pub(crate) read_limiter: Option<Arc<RateLimiter>>,
+}
+
+/// Where the caches, rev_tags and sync times are kept. Merkle trees are saved as files either
+/// way
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IndexStorage {
+    /// A flat file per cache, next to the trees, and rev_tags for each provider: a database, or
+    /// a file with `SyncOptions::index_file_system` and on wasm32
+    #[default]
+    Files,
+
+    /// One SQLite database for the whole index root (`index.db`), in which each batch of cache
+    /// changes is a single transaction. State left in files by `Files` is moved into it the
+    /// first time each tag's caches are needed. Not on wasm32, which has no SQLite
+    #[cfg(not(target_arch = "wasm32"))]
+    Sqlite,
+}
+
+/// What a sync does when another one, in this process or another, is already changing the same
+/// provider's part of the index
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LockWait {
+    /// Wait for the other sync to finish
+    #[default]
+    Block,
+
+    /// Fail with `SyncError::Locked` straight away
+    Fail,
+
+    /// Wait up to this long, then fail with `SyncError::Locked`
+    Timeout(Duration),
+}
+
+/// Extensions of files that are always binary, so aren't worth reading to find out
+const BINARY_EXTENSIONS: &[&str] = &[
+    "7z", "a", "avi", "bin", "bmp", "class", "dat", "db", "dll", "dylib", "eot", "exe", "gif",
+    "gz", "ico", "jar", "jpeg", "jpg", "lib", "mov", "mp3", "mp4", "o", "obj", "otf", "pdf", "png",
+    "pyc", "so", "sqlite", "tar", "tgz", "ttf", "wasm", "wav", "webm", "webp", "woff", "woff2",
+    "xz", "zip",
+];
+
+/// Files bigger than this are usually generated (SQL dumps, bundles) and not worth indexing
+pub(crate) const DEFAULT_MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+
+impl SyncOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store the index somewhere other than ~/.continue/index
+    pub fn index_root(mut self, index_root: IndexRoot) -> Self {
+        self.index_root = Some(index_root);
+        self
+    }
+
+    /// Skip files matching this gitignore-style pattern, on top of .gitignore, .continueignore
+    /// and the global ignore file
+    pub fn ignore(mut self, pattern: impl Into<String>) -> Self {
+        self.ignore_patterns.push(pattern.into());
+        self
+    }
+
+    /// Compute the results without saving the new tree or updating any caches, so the next
+    /// sync will see the same changes again
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Report every file in the tag as `compute`, even if the caches say it has already been
+    /// computed. Use this when the downstream index has been wiped
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Forget everything recorded for this tag before syncing, as if it had never been synced.
+    /// Implies `force`
+    pub fn clear_tag_cache(mut self, clear_tag_cache: bool) -> Self {
+        self.clear_tag_cache = clear_tag_cache;
+        self
+    }
+
+    /// How the tree saved by each sync is written. Trees in either format can always be loaded
+    pub fn tree_format(mut self, tree_format: TreeFormat) -> Self {
+        self.tree_format = tree_format;
+        self
+    }
+
+    /// Compress the tree saved by each sync. Compressed trees can always be loaded, whether or
+    /// not this is set
+    pub fn tree_compression(mut self, tree_compression: Compression) -> Self {
+        self.tree_compression = tree_compression;
+        self
+    }
+
+    /// Hash larger files straight from a memory mapping of them, rather than copying them
+    /// through a buffer. Faster when there are many large files to hash, but a file truncated
+    /// by something else while it's being hashed crashes the process. Only used on Unix
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Save each file's hash in an extended attribute on the file, along with its size and
+    /// mtime, so that unchanged files aren't read again even by a sync with no saved tree to go
+    /// on, e.g. for a new tag or from another process. Only works on Linux and macOS, on
+    /// filesystems with extended attributes, and for files that can be written
+    pub fn xattr_cache(mut self, xattr_cache: bool) -> Self {
+        self.xattr_cache = xattr_cache;
+        self
+    }
+
+    /// Leave files bigger than this many bytes out of the tree without reading them, reporting
+    /// each one as a warning. Defaults to 10 MiB; use `u64::MAX` for no limit
+    pub fn max_file_size(mut self, max_file_size: u64) -> Self {
+        self.max_file_size = Some(max_file_size);
+        self
+    }
+
+    /// How the caches are stored. Defaults to `IndexStorage::Files`
+    pub fn storage(mut self, storage: IndexStorage) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Flush every index file to disk before moving on, so that a power cut can't undo a sync
+    /// that returned. Files are always replaced in one step, so a crash never leaves one half
+    /// written either way; this makes syncs slower but durable
+    pub fn fsync(mut self, fsync: bool) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    /// What to do if another sync holds the lock on the index. Defaults to `LockWait::Block`
+    pub fn lock_wait(mut self, lock_wait: LockWait) -> Self {
+        self.lock_wait = lock_wait;
+        self
+    }
+
+    /// Keep files reported as `compute` out of the caches until `mark_computed` is called for
+    /// their hashes, so that if the downstream work fails they're reported as `compute` again
+    /// by the next sync, instead of never
+    pub fn defer_compute(mut self, defer_compute: bool) -> Self {
+        self.defer_compute = defer_compute;
+        self
+    }
+
+    /// Compose the Unicode in paths (to NFC) before they go in the tree, so a file is found at
+    /// the same path whichever form the filesystem and the tools that made it used. Only safe
+    /// on filesystems that open a file by either form, like APFS and HFS+, so it's on by
+    /// default on macOS and off everywhere else
+    pub fn normalize_unicode(mut self, normalize_unicode: bool) -> Self {
+        self.normalize_unicode = Some(normalize_unicode);
+        self
+    }
+
+    /// Treat paths that differ only in case as the same file when comparing a directory with
+    /// its last sync, so renaming `README.md` to `readme.md` isn't a delete and an add. Unless
+    /// set, this is detected from the filesystem the tag's directory is on
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = Some(case_insensitive);
+        self
+    }
+
+    /// Index what symlinks point to, as if it were where the link is, instead of leaving them
+    /// out with an `UnfollowedSymlink` warning. A link to a directory that's already in the
+    /// tree (like one of its own parents) is left out with a `SymlinkLoop` warning instead
+    pub fn follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Normalize files' content like this before hashing it, so that copies that differ only in
+    /// line endings (or trailing whitespace) share a hash, and are only computed once. Changing
+    /// it rehashes every file in a tag on its next sync. Defaults to `ContentNormalization::None`
+    pub fn normalize_content(mut self, normalization: ContentNormalization) -> Self {
+        self.content_normalization = normalization;
+        self
+    }
+
+    /// Like `normalize_content`, but only for tags of this provider, taking precedence over it.
+    /// A provider that embeds exact text may want every byte hashed, while one that ignores
+    /// whitespace can share more
+    pub fn normalize_content_for(
+        mut self,
+        provider_id: impl Into<String>,
+        normalization: ContentNormalization,
+    ) -> Self {
+        self.provider_normalization
+            .insert(provider_id.into(), normalization);
+        self
+    }
+
+    /// These options as they apply to tags of `provider_id`
+    pub(crate) fn for_provider(&self, provider_id: &str) -> SyncOptions {
+        let mut options = self.clone();
+        if let Some(&normalization) = self.provider_normalization.get(provider_id) {
+            options.content_normalization = normalization;
+        }
+        options
+    }
+
+    /// Always hash files with this extension (without the dot) as text, even if they look
+    /// binary or have an extension that usually is
+    pub fn text_extension(mut self, ext: impl Into<String>) -> Self {
+        self.text_extensions.push(ext.into());
+        self
+    }
+
+    /// Leave files with this extension (without the dot) out of the tree as binary, without
+    /// reading them, as is already done for images, archives, fonts and compiled code. Files
+    /// with other extensions are binary if they start with a NUL byte or many control
+    /// characters. Either way they're reported with a `BinaryFile` warning
+    pub fn binary_extension(mut self, ext: impl Into<String>) -> Self {
+        self.binary_extensions.push(ext.into());
+        self
+    }
+
+    /// Whether files with this extension are text (`Some(false)`) or binary (`Some(true)`)
+    /// whatever is in them, or None if it depends on what's in them
+    pub(crate) fn binary_by_extension(&self, ext: &str) -> Option<bool> {
+        let mut binary = (self.binary_extensions.iter().map(String::as_str))
+            .chain(BINARY_EXTENSIONS.iter().copied());
+        if self
+            .text_extensions
+            .iter()
+            .any(|text| text.eq_ignore_ascii_case(ext))
+        {
+            Some(false)
+        } else if binary.any(|binary| binary.eq_ignore_ascii_case(ext)) {
+            Some(true)
+        } else {
+            None
+        }
+    }
+
+    /// Fail a sync with `SyncError::DiskFull` before it changes anything if there are fewer
+    /// than this many bytes free where the index is kept. Defaults to 64 MiB; use 0 to only
+    /// fail once a write does
+    pub fn min_free_space(mut self, min_free_space: u64) -> Self {
+        self.min_free_space = Some(min_free_space);
+        self
+    }
+
+    /// When the index can't be written to (`SyncError::ReadOnlyIndex` or `SyncError::DiskFull`),
+    /// compare the directory with the saved tree anyway, without saving anything or touching
+    /// the caches, and report the changes with an `IndexNotWritable` warning instead of failing.
+    /// A `SyncSession` keeps the new tree in memory, so that its next sync only reports what
+    /// changed since this one
+    pub fn read_only_fallback(mut self, read_only_fallback: bool) -> Self {
+        self.read_only_fallback = read_only_fallback;
+        self
+    }
+
+    /// How long a `SyncWatcher` waits for changes to stop before syncing them, so that a burst
+    /// of them (a checkout, or a package install) is synced once. Defaults to 100ms
+    pub fn watch_debounce(mut self, debounce: Duration) -> Self {
+        self.watch_debounce = Some(debounce);
+        self
+    }
+
+    /// The longest a `SyncWatcher` holds changes back while more keep coming, so that a
+    /// directory that never stops changing is still synced. Defaults to 2s
+    pub fn watch_max_delay(mut self, max_delay: Duration) -> Self {
+        self.watch_max_delay = Some(max_delay);
+        self
+    }
+
+    /// Walk and read the workspace through `file_system` rather than straight from the disk,
+    /// and look the home directory up there too (see `file_system.rs`). The walk honours
+    /// .continueignore, .ignore and .gitignore files (the last only in a git repository),
+    /// leaves out hidden files, and reads every file that has no modification time
+    pub fn file_system(mut self, file_system: Arc<dyn FileSystem>) -> Self {
+        self.file_system = Some(file_system);
+        self
+    }
+
+    /// Keep the index in `file_system` rather than on disk, under the same paths in the index
+    /// root: the trees, the file caches (and their Bloom filters and sorted lengths), rev_tags,
+    /// saved content, the journal, the tags' sync times and the locks. With
+    /// `IndexStorage::Files`, nothing is written to the disk, as a wasm32 build needs. An index
+    /// on disk from an older version isn't upgraded into it
+    pub fn index_file_system(mut self, file_system: Arc<dyn FileSystem>) -> Self {
+        self.index_file_system = Some(file_system);
+        self
+    }
+
+    /// Hash the file at `path` (absolute, in the workspace) as `contents` rather than what's on
+    /// disk, for an editor buffer with unsaved changes, so the index has what the user sees.
+    /// Only files the walk finds are overlaid: one that's ignored, binary by its extension or
+    /// not on disk at all stays out of the tree. The blob is kept without its size and mtime, so
+    /// the next sync without the buffer reads the file again
+    pub fn unsaved_buffer(
+        mut self,
+        path: impl Into<PathBuf>,
+        contents: impl Into<Vec<u8>>,
+    ) -> Self {
+        let contents: Vec<u8> = contents.into();
+        self.unsaved_buffers.insert(path.into(), contents.into());
+        self
+    }
+
+    /// Index zip, jar and tar (or gzipped tar) files as directories, with a file in the tree
+    /// for each file in them (`<archive>/<member>`), so that dependencies vendored as archives
+    /// are indexed and diffed like the rest. Members are hashed again only when their archive's
+    /// size or mtime changes, and `read_indexed_file` reads one back out. Syncs with it are
+    /// handed over as one batch by `sync_in_batches`
+    pub fn archives(mut self, archives: bool) -> Self {
+        self.archives = archives;
+        self
+    }
+
+    /// Save the content of each file reported to compute in the provider's object store, by
+    /// its hash, so that `read_object` can fetch exactly what was hashed after the file has
+    /// changed again. Files are read a second time to save them, and only content that isn't
+    /// saved already. Nothing is saved by a dry run
+    pub fn store_objects(mut self, store_objects: bool) -> Self {
+        self.store_objects = store_objects;
+        self
+    }
+
+    /// Split each file reported to compute into chunks (see `chunk.rs`), and give them with
+    /// it in `SyncResultItem::chunks`, so that embedding them doesn't need the file split again.
+    /// Files are read a second time to split them, and one that has changed since it was hashed
+    /// has no chunks
+    pub fn chunking(mut self, chunking: ChunkOptions) -> Self {
+        self.chunking = Some(chunking);
+        self
+    }
+
+    /// Slow syncs down so that they leave the disk to the IDE (see `throttle.rs`): cap how fast
+    /// files are read, wait between the batches of `sync_in_batches`, or ask the OS to put the
+    /// sync's reads after everyone else's. The rate cap is shared by every sync with these
+    /// options or a clone of them, so a `SyncWatcher` or `sync_providers` doesn't multiply it
+    pub fn throttle(mut self, throttle: Throttle) -> Self {
+        self.read_limiter = (throttle.max_bytes_per_sec)
+            .map(|max_bytes_per_sec| Arc::new(RateLimiter::new(max_bytes_per_sec)));
+        self.throttle = throttle;
+        self
+    }
+
+    /// Count `bytes` of a file as read, waiting if that's faster than `Throttle` allows
+    pub(crate) fn throttle_read(&self, bytes: u64) {
+        if let Some(read_limiter) = &self.read_limiter {
+            read_limiter.consume(bytes);
+        }
+    }
+
+    pub(crate) fn resolve_max_file_size(&self) -> u64 {
+        self.max_file_size.unwrap_or(DEFAULT_MAX_FILE_SIZE)
+    }
+
+    pub(crate) fn resolve_min_free_space(&self) -> u64 {
+        self.min_free_space
+            .unwrap_or(writable::DEFAULT_MIN_FREE_SPACE)
+    }
+
+    pub(crate) fn resolve_watch_debounce(&self) -> Duration {
+        self.watch_debounce.unwrap_or(watch::DEFAULT_DEBOUNCE)
+    }
+
+    pub(crate) fn resolve_watch_max_delay(&self) -> Duration {
+        self.watch_max_delay.unwrap_or(watch::DEFAULT_MAX_DELAY)
+    }
+
+    pub(crate) fn resolve_normalize_unicode(&self) -> bool {
+        self.normalize_unicode.unwrap_or(cfg!(target_os = "macos"))
+    }
+
+    pub(crate) fn resolve_index_file_system(&self) -> Arc<dyn FileSystem> {
+        match &self.index_file_system {
+            Some(file_system) => file_system.clone(),
+            None => Arc::new(OsFileSystem),
+        }
+    }
+
+    /// The configured index root, or the default one (see `IndexRoot::from_env_or_home`),
+    /// upgraded from whatever older version wrote it
+    pub(crate) fn resolve_index_root(&self) -> Result<IndexRoot> {
+        let index_root = match &self.index_root {
+            Some(index_root) => index_root.clone(),
+            None => match &self.file_system {
+                Some(file_system) => IndexRoot::from_env_or_home_in(&**file_system),
+                None => IndexRoot::from_env_or_home(),
+            },
+        };
+        migrate::migrate(self, &index_root)?;
+        Ok(index_root)
+    }
+}
@@ -0,0 +1,174 @@
+use super::{
+    error::Result, mark_computed, merkle::parse_hash, options::SyncOptions, sync,
+    warning::SyncWarning, SyncResults, Tag,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use super::{
+    merkle::{for_each_hashed_file, ObjDescription, ObjectHash},
+    objects::ObjectStore,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use rusqlite::{Connection, OptionalExtension};
+#[cfg(not(target_arch = "wasm32"))]
+use std::collections::HashSet;
+
+/// Which tags have which content at which paths, for a provider that keeps what it knows by
+/// content hash in a database of its own
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) const PATHS_TABLE: &str = "
+    CREATE TABLE IF NOT EXISTS paths (
+        tag TEXT NOT NULL,
+        relative_path TEXT NOT NULL,
+        path TEXT NOT NULL,
+        hash BLOB NOT NULL,
+        PRIMARY KEY (tag, relative_path)
+    ) WITHOUT ROWID;
+    CREATE INDEX IF NOT EXISTS paths_hash ON paths (hash);";
+
+/// An index built in the crate from what syncs find, such as `SearchIndex`, rather than by the
+/// extension. It's kept up to date by being handed the results of every sync of its provider's
+/// tags, and of `delete_tag` for them
+pub trait IndexProvider {
+    /// Bring the index up to date with `results`, from a sync of `tag` with `options`. The
+    /// content of files to compute or add is read as the sync read it; a file that can't be
+    /// read, or has changed since it was hashed, is left out with a warning
+    fn apply(
+        &mut self,
+        tag: &Tag,
+        results: &SyncResults,
+        options: &SyncOptions,
+    ) -> Result<Vec<SyncWarning>>;
+}
+
+/// Sync `tag` and hand what it finds to each of `providers`, returning the results with their
+/// warnings added. The files to compute are held back from the caches (as with
+/// `SyncOptions::defer_compute`) until every provider has them, so that if one fails they're
+/// reported again by the next sync instead of being lost to it. A dry run changes no provider
+pub fn sync_providers(
+    tag: &Tag,
+    options: &SyncOptions,
+    providers: &mut [&mut dyn IndexProvider],
+) -> Result<SyncResults> {
+    let mut results = sync(tag, &options.clone().defer_compute(true))?;
+    if options.dry_run {
+        return Ok(results);
+    }
+    for provider in providers.iter_mut() {
+        let warnings = provider.apply(tag, &results, options)?;
+        results.warnings.extend(warnings);
+    }
+    let computed: Vec<_> = results
+        .compute
+        .iter()
+        .filter_map(|item| parse_hash(&item.hash))
+        .collect();
+    mark_computed(tag, &computed, options)?;
+    Ok(results)
+}
+
+/// The files to compute or add in `results` with content a provider hasn't got yet, going by
+/// `is_indexed`, once for each content. Content another path has brought in isn't read again
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn unindexed(
+    results: &SyncResults,
+    mut is_indexed: impl FnMut(&ObjectHash) -> Result<bool>,
+) -> Result<Vec<ObjDescription>> {
+    let mut seen = HashSet::new();
+    let mut unindexed = Vec::new();
+    for item in results.compute.iter().chain(&results.add_tag) {
+        let Some(hash) = parse_hash(&item.hash) else {
+            continue;
+        };
+        if seen.insert(hash) && !is_indexed(&hash)? {
+            unindexed.push(ObjDescription {
+                hash,
+                path: item.relative_path.clone().into(),
+                is_blob: true,
+            });
+        }
+    }
+    Ok(unindexed)
+}
+
+/// Call `read` with the content of each of `items`, files of `tag`: from the object store where
+/// `SyncOptions::store_objects` saved it, and from the files again otherwise (checking that they
+/// still hash the same, with `for_each_hashed_file`)
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn read_contents(
+    tag: &Tag,
+    items: &[ObjDescription],
+    options: &SyncOptions,
+    read: impl Fn(&ObjDescription, &[u8]) + Sync,
+) -> Result<Vec<SyncWarning>> {
+    let mut unsaved = Vec::new();
+    let objects = match options.store_objects {
+        true => {
+            let index_root = options.resolve_index_root()?;
+            Some(ObjectStore::open(&index_root, tag.provider_id, options)?)
+        }
+        false => None,
+    };
+    for item in items {
+        match objects.as_ref().map(|objects| objects.get(&item.hash)) {
+            Some(Ok(Some(contents))) => read(item, &contents),
+            Some(Err(err)) => return Err(err),
+            _ => unsaved.push(item),
+        }
+    }
+    for_each_hashed_file(tag.dir, &unsaved, options, |item, contents| {
+        read(item, contents);
+        Ok(())
+    })
+}
+
+/// Bring the `paths` table up to date with `results` from a sync of the tag named `tag`,
+/// returning the content no path has any more, for the provider to drop
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn update_paths(
+    conn: &Connection,
+    tag: &str,
+    results: &SyncResults,
+) -> Result<Vec<ObjectHash>> {
+    // Paths are removed before they're added, since a changed file is both
+    let mut removed = HashSet::new();
+    for item in results.remove_tag.iter().chain(&results.delete) {
+        conn.prepare_cached("DELETE FROM paths WHERE tag = ?1 AND relative_path = ?2")?
+            .execute((tag, &item.relative_path))?;
+        removed.extend(parse_hash(&item.hash));
+    }
+    for item in &results.renamed {
+        conn.prepare_cached(
+            "UPDATE paths SET relative_path = ?3, path = ?4
+            WHERE tag = ?1 AND relative_path = ?2",
+        )?
+        .execute((
+            tag,
+            &item.old_relative_path,
+            &item.new_relative_path,
+            &item.new_path,
+        ))?;
+    }
+    for item in results.compute.iter().chain(&results.add_tag) {
+        let Some(hash) = parse_hash(&item.hash) else {
+            continue;
+        };
+        conn.prepare_cached(
+            "INSERT OR REPLACE INTO paths (tag, relative_path, path, hash)
+            VALUES (?1, ?2, ?3, ?4)",
+        )?
+        .execute((tag, &item.relative_path, &item.path, &hash[..]))?;
+    }
+
+    let mut unused = Vec::new();
+    for hash in removed {
+        let used = conn
+            .prepare_cached("SELECT 1 FROM paths WHERE hash = ?1 LIMIT 1")?
+            .query_row((&hash[..],), |_| Ok(()))
+            .optional()?
+            .is_some();
+        if !used {
+            unused.push(hash);
+        }
+    }
+    Ok(unused)
+}
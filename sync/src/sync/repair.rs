@@ -0,0 +1,363 @@
+//! Rebuilding a provider's caches and rev_tags from the trees saved by its tags, for when they
+//! have drifted apart from them
+
+#[cfg(not(target_arch = "wasm32"))]
+use super::index_db::IndexDb;
+use super::{
+    error::Result,
+    index_root::IndexRoot,
+    journal, load_saved_tree_or_warn, lock,
+    merkle::{hash_string, ObjDescription, ObjectHash},
+    options::{IndexStorage, SyncOptions},
+    read_pending, rebuild_cache,
+    rev_tags::RevTags,
+    DiskSet, IndexCache, OwnedTag, SyncResultItem, SyncWarning,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+
+/// What `repair_index` changed for one tag, as the actions to apply to downstream indexes
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TagRepair {
+    pub tag: OwnedTag,
+
+    /// Files whose content wasn't in the global cache, to compute and label for the tag
+    pub compute: Vec<SyncResultItem>,
+
+    /// Files whose content was computed, but not referenced by the tag
+    pub add_tag: Vec<SyncResultItem>,
+
+    /// Content the tag referenced that no file in its tree has. These have no path
+    pub remove_tag: Vec<SyncResultItem>,
+}
+
+/// The result of `repair_index`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RepairResults {
+    /// Only tags that had something change, in the order of their tag strings
+    pub tags: Vec<TagRepair>,
+
+    /// Content that was in the global cache but that no tag has any more, to delete from
+    /// downstream indexes. These have no path
+    pub delete: Vec<SyncResultItem>,
+
+    /// Trees that couldn't be loaded, whose tags were treated as having no files
+    pub warnings: Vec<SyncWarning>,
+}
+
+/// Rebuild the provider's global cache, the cache of each of its tags and its rev_tags from the
+/// trees saved by the last sync of each tag. Files waiting for `mark_computed` stay out of the
+/// caches, as they are after a sync, and references from tags that are no longer indexed are
+/// dropped
+///
+/// The results are worked out against what rev_tags and the global cache said before, so
+/// applying them brings a downstream index that followed the caches back in line. An
+/// interrupted repair can simply be run again. With `dry_run`, nothing is written
+pub fn repair_index(provider_id: &str, options: &SyncOptions) -> Result<RepairResults> {
+    let index_root = &options.resolve_index_root()?;
+    let mut results = RepairResults::default();
+    let file_system = &options.resolve_index_file_system();
+    let mut tags: Vec<OwnedTag> = index_root
+        .tag_dirs_in(&**file_system)?
+        .into_iter()
+        .map(|(_, tag)| tag)
+        .filter(|tag| tag.provider_id == provider_id)
+        .collect();
+    tags.sort_by_key(|tag| tag.to_string());
+
+    file_system.create_dir_all(&index_root.provider_dir(provider_id))?;
+    let lock_path = index_root.provider_lock(provider_id);
+    let _lock = lock::lock_index(options, &lock_path, options.lock_wait)?;
+
+    // What the caches say now
+    let (old_global, old_refs) = match options.storage {
+        IndexStorage::Files => {
+            let global_path = global_cache_path(index_root, provider_id);
+            let old_global = match file_system.metadata(&global_path).is_ok() {
+                true => DiskSet::read(&**file_system, &global_path).unwrap_or_default(),
+                false => Vec::new(),
+            };
+            let old_refs = RevTags::open(index_root, provider_id, options)?.all()?;
+            (old_global, old_refs)
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        IndexStorage::Sqlite => {
+            let index_db = IndexDb::open(index_root)?;
+            for tag in &tags {
+                index_db.migrate_files(&tag.as_tag(), index_root, &**file_system)?;
+            }
+            (
+                index_db.global_hashes(provider_id)?,
+                index_db.all_rev_tags(provider_id)?,
+            )
+        }
+    };
+    let old_global: HashSet<ObjectHash> = old_global.into_iter().collect();
+    let mut old_tag_hashes: HashMap<&str, HashSet<ObjectHash>> = HashMap::new();
+    for (hash, tag) in &old_refs {
+        old_tag_hashes.entry(tag).or_default().insert(*hash);
+    }
+
+    // What the trees say they should be
+    let mut files: Vec<(OwnedTag, Vec<ObjDescription>)> = Vec::new();
+    for tag in &tags {
+        let tag_ref = &tag.as_tag();
+        let tree =
+            load_saved_tree_or_warn(&**file_system, tag_ref, index_root, &mut results.warnings)?;
+        let pending: HashSet<ObjDescription> = read_pending(&**file_system, tag_ref, index_root)?
+            .into_iter()
+            .collect();
+        let mut tag_files = tree.all_obj_descriptions();
+        tag_files.retain(|item| item.is_blob && !pending.contains(item));
+        files.push((tag.clone(), tag_files));
+    }
+    let new_refs: Vec<(ObjectHash, String)> = files
+        .iter()
+        .flat_map(|(tag, tag_files)| {
+            let tag_str = tag.to_string();
+            tag_files
+                .iter()
+                .map(move |item| (item.hash, tag_str.clone()))
+        })
+        .collect();
+    let new_global: HashSet<ObjectHash> = new_refs.iter().map(|(hash, _)| *hash).collect();
+    let new_tag_hashes: Vec<(String, HashSet<ObjectHash>)> = files
+        .iter()
+        .map(|(tag, tag_files)| {
+            let hashes = tag_files.iter().map(|item| item.hash).collect();
+            (tag.to_string(), hashes)
+        })
+        .collect();
+
+    // Compared the same way as a sync would: content is computed the first time it's seen, and
+    // only labelled after that. Content that any tag referenced is already downstream
+    let mut computed: HashSet<ObjectHash> = old_global
+        .iter()
+        .chain(old_refs.iter().map(|(hash, _)| hash))
+        .copied()
+        .collect();
+    let no_path = |hash: ObjectHash| SyncResultItem {
+        path: String::new(),
+        relative_path: String::new(),
+        hash: hash_string(hash),
+        chunks: Vec::new(),
+    };
+    let mut repairs: HashMap<String, TagRepair> = HashMap::new();
+    for (tag, tag_files) in &files {
+        let old = old_tag_hashes.get(tag.to_string().as_str());
+        for item in tag_files {
+            if old.is_some_and(|old| old.contains(&item.hash)) {
+                continue;
+            }
+            let result = SyncResultItem::new(&tag.dir, item);
+            match computed.insert(item.hash) {
+                true => repair_for(&mut repairs, tag).compute.push(result),
+                false => repair_for(&mut repairs, tag).add_tag.push(result),
+            }
+        }
+    }
+    let mut old_tags: Vec<(&&str, &HashSet<ObjectHash>)> = old_tag_hashes.iter().collect();
+    old_tags.sort_by_key(|(tag_str, _)| **tag_str);
+    for (tag_str, old) in old_tags {
+        // A tag that can't be parsed can't be labelled either, but its content may still need
+        // deleting
+        let tag = tag_str.parse::<OwnedTag>().ok();
+        let new = new_tag_hashes
+            .iter()
+            .find(|(new_tag, _)| new_tag == *tag_str)
+            .map(|(_, hashes)| hashes);
+        let mut removed: Vec<&ObjectHash> = old
+            .iter()
+            .filter(|hash| new.is_none_or(|new| !new.contains(*hash)))
+            .filter(|hash| new_global.contains(*hash))
+            .collect();
+        removed.sort();
+        if let (Some(tag), false) = (&tag, removed.is_empty()) {
+            let repair = repair_for(&mut repairs, tag);
+            repair
+                .remove_tag
+                .extend(removed.into_iter().map(|hash| no_path(*hash)));
+        }
+    }
+    let mut deleted: Vec<&ObjectHash> = old_global
+        .iter()
+        .chain(old_refs.iter().map(|(hash, _)| hash))
+        .filter(|hash| !new_global.contains(*hash))
+        .collect();
+    deleted.sort();
+    deleted.dedup();
+    results.delete = deleted.into_iter().map(|hash| no_path(*hash)).collect();
+    let mut repairs: Vec<TagRepair> = repairs.into_values().collect();
+    repairs.sort_by_key(|repair| repair.tag.to_string());
+    results.tags = repairs;
+
+    if options.dry_run {
+        return Ok(results);
+    }
+    match options.storage {
+        IndexStorage::Files => {
+            // The repair replaces whatever the interrupted change was going to do
+            journal::remove(&**file_system, &index_root.journal(provider_id))?;
+            drop(rebuild_cache(
+                file_system,
+                &global_cache_path(index_root, provider_id),
+                true,
+                new_global,
+            )?);
+            for (tag, (_, hashes)) in tags.iter().zip(new_tag_hashes) {
+                let path = IndexCache::index_cache_path_for_tag(&tag.as_tag(), index_root);
+                drop(rebuild_cache(file_system, &path, false, hashes)?);
+            }
+            RevTags::open(index_root, provider_id, options)?.replace_all(&new_refs)?;
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        IndexStorage::Sqlite => {
+            IndexDb::open(index_root)?.replace_provider(
+                provider_id,
+                &new_global,
+                &new_tag_hashes,
+                &new_refs,
+            )?;
+        }
+    }
+    Ok(results)
+}
+
+fn repair_for<'a>(
+    repairs: &'a mut HashMap<String, TagRepair>,
+    tag: &OwnedTag,
+) -> &'a mut TagRepair {
+    repairs.entry(tag.to_string()).or_insert_with(|| TagRepair {
+        tag: tag.clone(),
+        compute: Vec::new(),
+        add_tag: Vec::new(),
+        remove_tag: Vec::new(),
+    })
+}
+
+fn global_cache_path(index_root: &IndexRoot, provider_id: &str) -> PathBuf {
+    index_root.provider_dir(provider_id).join(".index_cache")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sync::{merkle::parse_hash, sync, verify_index, Tag, ITEM_SIZE},
+        utils::{temp_index_root, TempDirBuilder},
+    };
+    use std::fs;
+
+    #[test]
+    fn test_repair_index() {
+        let dir1 = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("b.txt", "B")
+            .create();
+        let dir2 = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("c.txt", "C")
+            .create();
+        let tag1 = &Tag {
+            dir: dir1.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let tag2 = &Tag {
+            dir: dir2.path(),
+            ..*tag1
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root.clone());
+        let (tag1, tag2) = (&tag1.canonicalize(), &tag2.canonicalize());
+        let mut hashes = HashMap::new();
+        for tag in [tag1, tag2] {
+            for file in sync(&tag.as_tag(), &options).unwrap().compute {
+                hashes.insert(file.relative_path, parse_hash(&file.hash).unwrap());
+            }
+        }
+        let consistent = |tag: &OwnedTag| {
+            verify_index(&tag.as_tag(), &options)
+                .unwrap()
+                .is_consistent()
+        };
+        assert!(consistent(tag1) && consistent(tag2));
+
+        // Lose the global cache, and all but one of the references
+        let unknown = [7; ITEM_SIZE];
+        fs::remove_file(global_cache_path(&index_root, "default")).unwrap();
+        RevTags::open_sqlite(&index_root, "default")
+            .unwrap()
+            .replace_all(&[
+                (hashes["a.txt"], tag1.to_string()),
+                (unknown, "/nowhere::main::default".to_string()),
+            ])
+            .unwrap();
+        assert!(!consistent(tag1) && !consistent(tag2));
+
+        let file = |tag: &OwnedTag, path: &str| SyncResultItem {
+            path: tag.dir.join(path).to_string_lossy().into_owned(),
+            relative_path: path.to_string(),
+            hash: hash_string(hashes[path]),
+            chunks: Vec::new(),
+        };
+        let mut expected = vec![
+            TagRepair {
+                tag: tag1.clone(),
+                compute: vec![file(tag1, "b.txt")],
+                add_tag: Vec::new(),
+                remove_tag: Vec::new(),
+            },
+            TagRepair {
+                tag: tag2.clone(),
+                compute: vec![file(tag2, "c.txt")],
+                add_tag: vec![file(tag2, "a.txt")],
+                remove_tag: Vec::new(),
+            },
+        ];
+        expected.sort_by_key(|repair| repair.tag.to_string());
+        let expected = RepairResults {
+            tags: expected,
+            delete: vec![SyncResultItem {
+                path: String::new(),
+                relative_path: String::new(),
+                hash: hash_string(unknown),
+                chunks: Vec::new(),
+            }],
+            warnings: Vec::new(),
+        };
+
+        // A dry run only reports what would change
+        let dry_run = options.clone().dry_run(true);
+        assert_eq!(repair_index("default", &dry_run).unwrap(), expected);
+        assert!(!consistent(tag1));
+
+        assert_eq!(repair_index("default", &options).unwrap(), expected);
+        assert!(consistent(tag1) && consistent(tag2));
+        assert!(global_cache_path(&index_root, "default").exists());
+
+        // Nothing more to do
+        assert_eq!(
+            repair_index("default", &options).unwrap(),
+            RepairResults::default()
+        );
+
+        // The same goes for the database
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new()
+            .index_root(index_root)
+            .storage(IndexStorage::Sqlite);
+        for tag in [tag1, tag2] {
+            sync(&tag.as_tag(), &options).unwrap();
+        }
+        assert_eq!(
+            repair_index("default", &options).unwrap(),
+            RepairResults::default()
+        );
+    }
+}
@@ -0,0 +1,849 @@
+#[cfg(not(target_arch = "wasm32"))]
+use super::tag;
+use super::{
+    error::{Result, SyncError},
+    file_system::FileSystem,
+    index_root::IndexRoot,
+    merkle::{hash_string, parse_hash, ObjectHash},
+    options::SyncOptions,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+#[cfg(not(target_arch = "wasm32"))]
+use std::fs;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// The mapping from each hash to the tags it is currently indexed for, for one provider
+///
+/// Each reference is from one tag, so a tag that has the same content at two paths appears
+/// twice. References are kept in the order they were added, along with how many each hash has.
+/// Whether a removed file's content can be deleted from the global cache is decided by that
+/// count, rather than by counting what `get` returns
+pub(crate) enum RevTags {
+    /// A SQLite database, `rev_tags.db`
+    #[cfg(not(target_arch = "wasm32"))]
+    Sqlite(SqliteRevTags),
+
+    /// A file in the index's `FileSystem`, `rev_tags.json`, with `SyncOptions::index_file_system`
+    /// and on wasm32
+    File(Box<FileRevTags>),
+}
+
+impl RevTags {
+    /// Open (or create) the provider's rev_tags, where `options` keeps them
+    pub(crate) fn open(
+        index_root: &IndexRoot,
+        provider_id: &str,
+        options: &SyncOptions,
+    ) -> Result<Self> {
+        #[cfg(not(target_arch = "wasm32"))]
+        if options.index_file_system.is_none() {
+            return Self::open_sqlite(index_root, provider_id);
+        }
+        Ok(RevTags::File(Box::new(FileRevTags::open(
+            options.resolve_index_file_system(),
+            index_root.rev_tags_file(provider_id),
+        )?)))
+    }
+
+    /// The provider's rev_tags database, whatever `SyncOptions::index_file_system` is
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn open_sqlite(index_root: &IndexRoot, provider_id: &str) -> Result<Self> {
+        Ok(RevTags::Sqlite(SqliteRevTags::open(
+            index_root,
+            provider_id,
+        )?))
+    }
+
+    /// Where `open` keeps the provider's rev_tags
+    pub(crate) fn path(
+        index_root: &IndexRoot,
+        provider_id: &str,
+        options: &SyncOptions,
+    ) -> PathBuf {
+        match options.index_file_system {
+            None if cfg!(not(target_arch = "wasm32")) => index_root.rev_tags_db(provider_id),
+            _ => index_root.rev_tags_file(provider_id),
+        }
+    }
+
+    /// `open`, or None if the provider has no rev_tags yet
+    pub(crate) fn open_existing(
+        index_root: &IndexRoot,
+        provider_id: &str,
+        options: &SyncOptions,
+    ) -> Result<Option<Self>> {
+        let path = Self::path(index_root, provider_id, options);
+        match options.resolve_index_file_system().metadata(&path) {
+            Ok(_) => Self::open(index_root, provider_id, options).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Wait for every commit to reach the disk, for `SyncOptions::fsync`
+    pub(crate) fn sync_every_commit(&self) -> Result<()> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            RevTags::Sqlite(db) => db.sync_every_commit(),
+            RevTags::File(file) => {
+                file.state.lock().unwrap().fsync = true;
+                Ok(())
+            }
+        }
+    }
+
+    /// Start grouping changes into one transaction, dropping any that were left unfinished
+    pub(crate) fn begin(&self) -> Result<()> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            RevTags::Sqlite(db) => db.begin(),
+            RevTags::File(file) => {
+                let mut state = file.state.lock().unwrap();
+                if let Some(committed) = state.committed.take() {
+                    state.refs = committed;
+                }
+                state.committed = Some(state.refs.clone());
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn commit(&self) -> Result<()> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            RevTags::Sqlite(db) => db.commit(),
+            RevTags::File(file) => {
+                let mut state = file.state.lock().unwrap();
+                state.committed = None;
+                file.write(&state)
+            }
+        }
+    }
+
+    pub(crate) fn rollback(&self) -> Result<()> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            RevTags::Sqlite(db) => db.rollback(),
+            RevTags::File(file) => {
+                let mut state = file.state.lock().unwrap();
+                if let Some(committed) = state.committed.take() {
+                    state.refs = committed;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Note that the changes in the current transaction are those of the journal entry `id`,
+    /// replacing the last one noted
+    pub(crate) fn record_journal(&self, id: i64) -> Result<()> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            RevTags::Sqlite(db) => db.record_journal(id),
+            RevTags::File(file) => file.change(|refs| refs.journal = Some(id)),
+        }
+    }
+
+    /// Whether the changes from the journal entry `id` were committed
+    pub(crate) fn has_journal(&self, id: i64) -> Result<bool> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            RevTags::Sqlite(db) => db.has_journal(id),
+            RevTags::File(file) => Ok(file.read(|refs| refs.journal == Some(id))),
+        }
+    }
+
+    /// Every tag referencing the hash, once per reference
+    pub(crate) fn get(&self, hash: &ObjectHash) -> Result<Vec<String>> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            RevTags::Sqlite(db) => db.get(hash),
+            RevTags::File(file) => Ok(file.read(|refs| {
+                refs.ids(hash)
+                    .iter()
+                    .map(|id| refs.by_id[id].1.clone())
+                    .collect()
+            })),
+        }
+    }
+
+    /// Every reference from every tag, in the order they were added
+    pub(crate) fn all(&self) -> Result<Vec<(ObjectHash, String)>> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            RevTags::Sqlite(db) => db.all(),
+            RevTags::File(file) => Ok(file.read(|refs| refs.by_id.values().cloned().collect())),
+        }
+    }
+
+    /// How many references there are to the hash, from any tag
+    pub(crate) fn refcount(&self, hash: &ObjectHash) -> Result<u64> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            RevTags::Sqlite(db) => db.refcount(hash),
+            RevTags::File(file) => Ok(file.read(|refs| refs.ids(hash).len() as u64)),
+        }
+    }
+
+    /// How many references there are to the hash from the tag alone, one for each of its paths
+    /// with that content
+    pub(crate) fn tag_refcount(&self, hash: &ObjectHash, tag: &str) -> Result<u64> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            RevTags::Sqlite(db) => db.tag_refcount(hash, tag),
+            RevTags::File(file) => Ok(file.read(|refs| {
+                refs.ids(hash)
+                    .iter()
+                    .filter(|id| refs.by_id[id].1 == tag)
+                    .count() as u64
+            })),
+        }
+    }
+
+    /// Every hash's refcount
+    pub(crate) fn all_refcounts(&self) -> Result<Vec<(ObjectHash, u64)>> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            RevTags::Sqlite(db) => db.all_refcounts(),
+            RevTags::File(file) => Ok(file.read(|refs| {
+                refs.by_hash
+                    .iter()
+                    .map(|(hash, ids)| (*hash, ids.len() as u64))
+                    .collect()
+            })),
+        }
+    }
+
+    pub(crate) fn add(&self, hash: &ObjectHash, tag: &str) -> Result<()> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            RevTags::Sqlite(db) => db.add(hash, tag),
+            RevTags::File(file) => file.change(|refs| refs.add(*hash, tag.to_string())),
+        }
+    }
+
+    /// Remove one reference from the tag to the hash, if there are any
+    pub(crate) fn remove_one(&self, hash: &ObjectHash, tag: &str) -> Result<()> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            RevTags::Sqlite(db) => db.remove_one(hash, tag),
+            RevTags::File(file) => file.change(|refs| {
+                let first = (refs.ids(hash).iter().copied()).find(|id| refs.by_id[id].1 == tag);
+                if let Some(id) = first {
+                    refs.remove(hash, id);
+                }
+            }),
+        }
+    }
+
+    /// Remove every reference to the hash, from every tag
+    pub(crate) fn remove_all(&self, hash: &ObjectHash) -> Result<()> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            RevTags::Sqlite(db) => db.remove_all(hash),
+            RevTags::File(file) => file.change(|refs| {
+                for id in refs.by_hash.remove(hash).unwrap_or_default() {
+                    refs.by_id.remove(&id);
+                }
+            }),
+        }
+    }
+
+    /// Give the space left by removed references back to the filesystem. The file is written
+    /// whole every time, so it has none to give back
+    pub(crate) fn vacuum(&self) -> Result<()> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            RevTags::Sqlite(db) => db.vacuum(),
+            RevTags::File(_) => Ok(()),
+        }
+    }
+
+    /// Replace every reference with `refs`, in one transaction
+    pub(crate) fn replace_all(&self, refs: &[(ObjectHash, String)]) -> Result<()> {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            RevTags::Sqlite(db) => db.replace_all(refs),
+            RevTags::File(file) => {
+                self.begin()?;
+                file.change(|state| {
+                    *state = Refs {
+                        journal: state.journal,
+                        ..Refs::default()
+                    };
+                    for (hash, tag) in refs {
+                        state.add(*hash, tag.clone());
+                    }
+                })?;
+                self.commit()
+            }
+        }
+    }
+}
+
+/// rev_tags in SQLite. Each row is one reference, and alongside them `refcounts` keeps how many
+/// each hash has, changed in the same transaction as the references themselves
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) struct SqliteRevTags {
+    conn: Connection,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl SqliteRevTags {
+    /// Open (or create) the provider's rev_tags database, moving over any JSON shards left by
+    /// older versions first
+    fn open(index_root: &IndexRoot, provider_id: &str) -> Result<Self> {
+        fs::create_dir_all(index_root.provider_dir(provider_id))?;
+        let conn = Connection::open(index_root.rev_tags_db(provider_id))?;
+
+        // Every change is its own transaction, which is only fast without a sync per commit
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS rev_tags (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                hash BLOB NOT NULL,
+                tag TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS rev_tags_hash ON rev_tags (hash);
+            CREATE TABLE IF NOT EXISTS refcounts (
+                hash BLOB PRIMARY KEY,
+                count INTEGER NOT NULL
+            ) WITHOUT ROWID;
+            CREATE TABLE IF NOT EXISTS journal (
+                id INTEGER PRIMARY KEY
+            );",
+        )?;
+
+        let mut rev_tags = SqliteRevTags { conn };
+        rev_tags.migrate_shards(&index_root.rev_tags_dir(provider_id))?;
+        rev_tags.count_missing_refs()?;
+        Ok(rev_tags)
+    }
+
+    /// Count the references of a database written before there were refcounts
+    fn count_missing_refs(&self) -> Result<()> {
+        let counted = self
+            .conn
+            .prepare_cached("SELECT 1 FROM refcounts LIMIT 1")?
+            .query_row((), |_| Ok(()))
+            .optional()?;
+        match counted {
+            Some(()) => Ok(()),
+            None => self.recount(),
+        }
+    }
+
+    /// Set every refcount from the references, after they've been changed wholesale
+    fn recount(&self) -> Result<()> {
+        self.conn.execute_batch(
+            "DELETE FROM refcounts;
+            INSERT INTO refcounts (hash, count) SELECT hash, COUNT(*) FROM rev_tags GROUP BY hash;",
+        )?;
+        Ok(())
+    }
+
+    /// rev_tags used to be a directory of JSON files, named after the first 2 characters of
+    /// the hashes in them: { "hash": ["tag1", "tag2", ...], ... }. The oldest have tags from
+    /// before their fields were escaped, which are formatted as they are now so they match the
+    /// tags syncs give
+    fn migrate_shards(&mut self, rev_tags_dir: &Path) -> Result<()> {
+        let entries = match fs::read_dir(rev_tags_dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let transaction = self.conn.transaction()?;
+        for entry in entries {
+            let path = entry?.path();
+            let contents = fs::read_to_string(&path)?;
+            if contents.is_empty() {
+                continue;
+            }
+
+            let shard: HashMap<String, Vec<String>> =
+                serde_json::from_str(&contents).map_err(|err| {
+                    SyncError::CorruptedIndex(format!("rev_tags shard {}: {err}", path.display()))
+                })?;
+            for (hash_str, tags) in shard {
+                let hash = parse_hash(&hash_str).ok_or_else(|| {
+                    SyncError::CorruptedIndex(format!(
+                        "invalid hash {hash_str} in rev_tags shard {}",
+                        path.display()
+                    ))
+                })?;
+                for stored in tags {
+                    transaction.execute(
+                        "INSERT INTO rev_tags (hash, tag) VALUES (?1, ?2)",
+                        (&hash[..], tag::reencode(&stored)),
+                    )?;
+                }
+            }
+        }
+        transaction.commit()?;
+        self.recount()?;
+
+        // Only remove the shards once everything in them is safely in the database
+        fs::remove_dir_all(rev_tags_dir)?;
+        Ok(())
+    }
+
+    /// Wait for every commit to reach the disk, rather than only the ones that checkpoint the
+    /// write-ahead log, for `SyncOptions::fsync`
+    fn sync_every_commit(&self) -> Result<()> {
+        self.conn.pragma_update(None, "synchronous", "FULL")?;
+        Ok(())
+    }
+
+    /// Start grouping changes into one transaction, dropping any that were left unfinished
+    fn begin(&self) -> Result<()> {
+        if !self.conn.is_autocommit() {
+            self.conn.execute_batch("ROLLBACK")?;
+        }
+        self.conn.execute_batch("BEGIN")?;
+        Ok(())
+    }
+
+    fn commit(&self) -> Result<()> {
+        self.conn.execute_batch("COMMIT")?;
+        Ok(())
+    }
+
+    fn rollback(&self) -> Result<()> {
+        if !self.conn.is_autocommit() {
+            self.conn.execute_batch("ROLLBACK")?;
+        }
+        Ok(())
+    }
+
+    /// Note that the changes in the current transaction are those of the journal entry `id`,
+    /// replacing the last one noted
+    fn record_journal(&self, id: i64) -> Result<()> {
+        self.conn.execute_batch("DELETE FROM journal")?;
+        self.conn
+            .prepare_cached("INSERT INTO journal (id) VALUES (?1)")?
+            .execute((id,))?;
+        Ok(())
+    }
+
+    /// Whether the changes from the journal entry `id` were committed
+    fn has_journal(&self, id: i64) -> Result<bool> {
+        let found = self
+            .conn
+            .prepare_cached("SELECT 1 FROM journal WHERE id = ?1")?
+            .query_row((id,), |_| Ok(()))
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    /// Every tag referencing the hash, once per reference
+    fn get(&self, hash: &ObjectHash) -> Result<Vec<String>> {
+        let mut statement = self
+            .conn
+            .prepare_cached("SELECT tag FROM rev_tags WHERE hash = ?1 ORDER BY id")?;
+        let tags = statement
+            .query_map((&hash[..],), |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(tags)
+    }
+
+    /// Every reference from every tag, in the order they were added
+    fn all(&self) -> Result<Vec<(ObjectHash, String)>> {
+        let mut statement = self
+            .conn
+            .prepare("SELECT hash, tag FROM rev_tags ORDER BY id")?;
+        let rows = statement
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(rows)
+    }
+
+    /// How many references there are to the hash, from any tag
+    fn refcount(&self, hash: &ObjectHash) -> Result<u64> {
+        let count: Option<i64> = self
+            .conn
+            .prepare_cached("SELECT count FROM refcounts WHERE hash = ?1")?
+            .query_row((&hash[..],), |row| row.get(0))
+            .optional()?;
+        Ok(count.map_or(0, |count| count.max(0) as u64))
+    }
+
+    /// How many references there are to the hash from the tag alone, one for each of its paths
+    /// with that content
+    fn tag_refcount(&self, hash: &ObjectHash, tag: &str) -> Result<u64> {
+        let count: i64 = self
+            .conn
+            .prepare_cached("SELECT COUNT(*) FROM rev_tags WHERE hash = ?1 AND tag = ?2")?
+            .query_row((&hash[..], tag), |row| row.get(0))?;
+        Ok(count as u64)
+    }
+
+    /// Every hash's refcount
+    fn all_refcounts(&self) -> Result<Vec<(ObjectHash, u64)>> {
+        let mut statement = self.conn.prepare("SELECT hash, count FROM refcounts")?;
+        let rows = statement
+            .query_map((), |row| {
+                Ok((row.get(0)?, row.get::<_, i64>(1)?.max(0) as u64))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(rows)
+    }
+
+    fn add(&self, hash: &ObjectHash, tag: &str) -> Result<()> {
+        self.conn
+            .prepare_cached("INSERT INTO rev_tags (hash, tag) VALUES (?1, ?2)")?
+            .execute((&hash[..], tag))?;
+        self.conn
+            .prepare_cached(
+                "INSERT INTO refcounts (hash, count) VALUES (?1, 1)
+                ON CONFLICT (hash) DO UPDATE SET count = count + 1",
+            )?
+            .execute((&hash[..],))?;
+        Ok(())
+    }
+
+    /// Remove one reference from the tag to the hash, if there are any
+    fn remove_one(&self, hash: &ObjectHash, tag: &str) -> Result<()> {
+        let id: Option<i64> = self
+            .conn
+            .prepare_cached("SELECT MIN(id) FROM rev_tags WHERE hash = ?1 AND tag = ?2")?
+            .query_row((&hash[..], tag), |row| row.get(0))
+            .optional()?
+            .flatten();
+        if let Some(id) = id {
+            self.conn
+                .prepare_cached("DELETE FROM rev_tags WHERE id = ?1")?
+                .execute((id,))?;
+            self.conn
+                .prepare_cached("UPDATE refcounts SET count = count - 1 WHERE hash = ?1")?
+                .execute((&hash[..],))?;
+            self.conn
+                .prepare_cached("DELETE FROM refcounts WHERE hash = ?1 AND count <= 0")?
+                .execute((&hash[..],))?;
+        }
+        Ok(())
+    }
+
+    /// Remove every reference to the hash, from every tag
+    fn remove_all(&self, hash: &ObjectHash) -> Result<()> {
+        self.conn
+            .prepare_cached("DELETE FROM rev_tags WHERE hash = ?1")?
+            .execute((&hash[..],))?;
+        self.conn
+            .prepare_cached("DELETE FROM refcounts WHERE hash = ?1")?
+            .execute((&hash[..],))?;
+        Ok(())
+    }
+
+    /// Give the space left by removed references back to the filesystem
+    fn vacuum(&self) -> Result<()> {
+        self.conn
+            .execute_batch("VACUUM; PRAGMA wal_checkpoint(TRUNCATE);")?;
+        Ok(())
+    }
+
+    /// Replace every reference with `refs`, in one transaction
+    fn replace_all(&self, refs: &[(ObjectHash, String)]) -> Result<()> {
+        self.begin()?;
+        self.conn
+            .execute_batch("DELETE FROM rev_tags; DELETE FROM refcounts;")?;
+        for (hash, tag) in refs {
+            self.add(hash, tag)?;
+        }
+        self.commit()
+    }
+}
+
+/// rev_tags kept whole in memory, and written to one file in the index's `FileSystem` on each
+/// commit (or each change made outside a transaction), since a `FileSystem` can only replace a
+/// file in one step by writing all of it
+pub(crate) struct FileRevTags {
+    file_system: Arc<dyn FileSystem>,
+    path: PathBuf,
+    state: Mutex<FileState>,
+}
+
+struct FileState {
+    refs: Refs,
+
+    /// What `rollback` goes back to, while there's a transaction
+    committed: Option<Refs>,
+    fsync: bool,
+}
+
+/// The references, by an id that keeps them in the order they were added
+#[derive(Clone, Default)]
+struct Refs {
+    by_id: BTreeMap<u64, (ObjectHash, String)>,
+    by_hash: HashMap<ObjectHash, Vec<u64>>,
+    next_id: u64,
+    journal: Option<i64>,
+}
+
+impl Refs {
+    fn ids(&self, hash: &ObjectHash) -> &[u64] {
+        self.by_hash.get(hash).map_or(&[], Vec::as_slice)
+    }
+
+    fn add(&mut self, hash: ObjectHash, tag: String) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.by_id.insert(id, (hash, tag));
+        self.by_hash.entry(hash).or_default().push(id);
+    }
+
+    fn remove(&mut self, hash: &ObjectHash, id: u64) {
+        self.by_id.remove(&id);
+        if let Some(ids) = self.by_hash.get_mut(hash) {
+            ids.retain(|other| *other != id);
+            if ids.is_empty() {
+                self.by_hash.remove(hash);
+            }
+        }
+    }
+}
+
+/// `rev_tags.json`: the references in order, with hex-encoded hashes
+#[derive(Serialize, Deserialize)]
+struct SavedRefs {
+    journal: Option<i64>,
+    refs: Vec<(String, String)>,
+}
+
+/// The references in the rev_tags file at `path`, if there is one, for moving them elsewhere
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn read_file(
+    file_system: &dyn FileSystem,
+    path: &Path,
+) -> Result<Vec<(ObjectHash, String)>> {
+    Ok(read_refs(file_system, path)?.by_id.into_values().collect())
+}
+
+fn read_refs(file_system: &dyn FileSystem, path: &Path) -> Result<Refs> {
+    let contents = match file_system.read(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Refs::default()),
+        Err(err) => return Err(err.into()),
+    };
+    let corrupted = |message: String| {
+        SyncError::CorruptedIndex(format!("rev_tags file {}: {message}", path.display()))
+    };
+    let saved: SavedRefs =
+        serde_json::from_slice(&contents).map_err(|err| corrupted(err.to_string()))?;
+    let mut refs = Refs {
+        journal: saved.journal,
+        ..Refs::default()
+    };
+    for (hash_str, tag) in saved.refs {
+        let hash =
+            parse_hash(&hash_str).ok_or_else(|| corrupted(format!("invalid hash {hash_str}")))?;
+        refs.add(hash, tag);
+    }
+    Ok(refs)
+}
+
+impl FileRevTags {
+    fn open(file_system: Arc<dyn FileSystem>, path: PathBuf) -> Result<Self> {
+        let refs = read_refs(&*file_system, &path)?;
+        Ok(FileRevTags {
+            file_system,
+            path,
+            state: Mutex::new(FileState {
+                refs,
+                committed: None,
+                fsync: false,
+            }),
+        })
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&Refs) -> T) -> T {
+        f(&self.state.lock().unwrap().refs)
+    }
+
+    /// Make a change, writing it straight away unless it's part of a transaction
+    fn change(&self, f: impl FnOnce(&mut Refs)) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        f(&mut state.refs);
+        match state.committed {
+            Some(_) => Ok(()),
+            None => self.write(&state),
+        }
+    }
+
+    fn write(&self, state: &FileState) -> Result<()> {
+        let saved = SavedRefs {
+            journal: state.refs.journal,
+            refs: (state.refs.by_id.values())
+                .map(|(hash, tag)| (hash_string(*hash), tag.clone()))
+                .collect(),
+        };
+        if let Some(parent) = self.path.parent() {
+            self.file_system.create_dir_all(parent)?;
+        }
+        let contents = serde_json::to_vec(&saved)?;
+        self.file_system.write(&self.path, &contents, state.fsync)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sync::{merkle::hash_string, MemoryFileSystem, Tag},
+        utils::temp_index_root,
+    };
+
+    #[test]
+    fn test_rev_tags() {
+        let (_index_dir, index_root) = temp_index_root();
+        let rev_tags = SqliteRevTags::open(&index_root, "default").unwrap();
+        let hash = [1; 20];
+        assert!(rev_tags.get(&hash).unwrap().is_empty());
+
+        rev_tags.add(&hash, "a").unwrap();
+        rev_tags.add(&hash, "b").unwrap();
+        rev_tags.add(&hash, "a").unwrap();
+        rev_tags.add(&[2; 20], "a").unwrap();
+        assert_eq!(rev_tags.get(&hash).unwrap(), ["a", "b", "a"]);
+        assert_eq!(rev_tags.refcount(&hash).unwrap(), 3);
+
+        rev_tags.remove_one(&hash, "a").unwrap();
+        rev_tags.remove_one(&hash, "c").unwrap();
+        assert_eq!(rev_tags.get(&hash).unwrap(), ["b", "a"]);
+        assert_eq!(rev_tags.refcount(&hash).unwrap(), 2);
+
+        rev_tags.remove_all(&hash).unwrap();
+        assert!(rev_tags.get(&hash).unwrap().is_empty());
+        assert_eq!(rev_tags.refcount(&hash).unwrap(), 0);
+        assert_eq!(rev_tags.get(&[2; 20]).unwrap(), ["a"]);
+        assert_eq!(rev_tags.all_refcounts().unwrap(), [([2; 20], 1)]);
+
+        // Changes in a transaction that isn't committed are dropped
+        rev_tags.begin().unwrap();
+        rev_tags.add(&hash, "a").unwrap();
+        rev_tags.rollback().unwrap();
+        rev_tags.begin().unwrap();
+        rev_tags.add(&hash, "b").unwrap();
+        rev_tags.begin().unwrap();
+        rev_tags.add(&hash, "c").unwrap();
+        rev_tags.commit().unwrap();
+        assert_eq!(rev_tags.get(&hash).unwrap(), ["c"]);
+        assert_eq!(rev_tags.refcount(&hash).unwrap(), 1);
+        rev_tags.remove_all(&hash).unwrap();
+
+        // A database from before there were refcounts is counted when it's opened
+        rev_tags
+            .conn
+            .execute_batch("DELETE FROM refcounts")
+            .unwrap();
+        drop(rev_tags);
+        let rev_tags = SqliteRevTags::open(&index_root, "default").unwrap();
+        assert_eq!(rev_tags.refcount(&[2; 20]).unwrap(), 1);
+
+        // Other providers are separate
+        let other = SqliteRevTags::open(&index_root, "other").unwrap();
+        assert!(other.get(&[2; 20]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_file_rev_tags() {
+        let memory = Arc::new(MemoryFileSystem::new());
+        let options = SyncOptions::new().index_file_system(memory.clone());
+        let index_root = IndexRoot::new("/index");
+        let rev_tags = RevTags::open(&index_root, "default", &options).unwrap();
+        let hash = [1; 20];
+        rev_tags.add(&hash, "a").unwrap();
+        rev_tags.add(&hash, "b").unwrap();
+        rev_tags.add(&hash, "a").unwrap();
+        rev_tags.add(&[2; 20], "a").unwrap();
+        rev_tags.remove_one(&hash, "a").unwrap();
+        assert_eq!(rev_tags.get(&hash).unwrap(), ["b", "a"]);
+        assert_eq!(rev_tags.refcount(&hash).unwrap(), 2);
+
+        // Changes in a transaction only reach the file when it's committed
+        let path = index_root.rev_tags_file("default");
+        let saved = read_file(&*memory, &path).unwrap();
+        rev_tags.begin().unwrap();
+        rev_tags.remove_all(&hash).unwrap();
+        rev_tags.record_journal(7).unwrap();
+        assert_eq!(read_file(&*memory, &path).unwrap(), saved);
+        rev_tags.rollback().unwrap();
+        assert_eq!(rev_tags.refcount(&hash).unwrap(), 2);
+        assert!(!rev_tags.has_journal(7).unwrap());
+        rev_tags.begin().unwrap();
+        rev_tags.remove_all(&hash).unwrap();
+        rev_tags.record_journal(7).unwrap();
+        rev_tags.commit().unwrap();
+        drop(rev_tags);
+
+        // What was committed is there when it's opened again, and none of it on disk
+        let rev_tags = RevTags::open(&index_root, "default", &options).unwrap();
+        assert!(rev_tags.get(&hash).unwrap().is_empty());
+        assert_eq!(rev_tags.all_refcounts().unwrap(), [([2; 20], 1)]);
+        assert!(rev_tags.has_journal(7).unwrap());
+        assert!(!Path::new("/index").exists());
+    }
+
+    #[test]
+    fn test_migrate_shards() {
+        let (_index_dir, index_root) = temp_index_root();
+        let hash = [0xab; 20];
+        let rev_tags_dir = index_root.rev_tags_dir("default");
+        fs::create_dir_all(&rev_tags_dir).unwrap();
+        fs::write(
+            rev_tags_dir.join("ab"),
+            format!(r#"{{"{}":["a","b","a"]}}"#, hash_string(hash)),
+        )
+        .unwrap();
+        fs::write(rev_tags_dir.join("cd"), "").unwrap();
+
+        let rev_tags = SqliteRevTags::open(&index_root, "default").unwrap();
+        assert_eq!(rev_tags.get(&hash).unwrap(), ["a", "b", "a"]);
+        assert!(!rev_tags_dir.exists());
+        drop(rev_tags);
+
+        // Nothing is migrated twice
+        let rev_tags = SqliteRevTags::open(&index_root, "default").unwrap();
+        assert_eq!(rev_tags.get(&hash).unwrap(), ["a", "b", "a"]);
+
+        // Tags from before their fields were escaped are formatted as they are now
+        let windows = Tag {
+            dir: Path::new("C:\\work"),
+            branch: "main",
+            provider_id: "default",
+        }
+        .to_string();
+        assert_ne!(windows, "C:\\work::main::default");
+        let old_hash = [0xcd; 20];
+        fs::create_dir_all(&rev_tags_dir).unwrap();
+        fs::write(
+            rev_tags_dir.join("cd"),
+            format!(
+                r#"{{"{}":["C:\\work::main::default","/a%b::main::default"]}}"#,
+                hash_string(old_hash)
+            ),
+        )
+        .unwrap();
+        drop(rev_tags);
+        let rev_tags = SqliteRevTags::open(&index_root, "default").unwrap();
+        assert_eq!(
+            rev_tags.get(&old_hash).unwrap(),
+            [windows.as_str(), "/a%25b::main::default"]
+        );
+
+        // A shard that can't be read is left alone
+        fs::create_dir_all(&rev_tags_dir).unwrap();
+        fs::write(rev_tags_dir.join("ef"), "not json").unwrap();
+        let result = SqliteRevTags::open(&index_root, "default");
+        assert!(matches!(result, Err(SyncError::CorruptedIndex(_))));
+        assert!(rev_tags_dir.join("ef").exists());
+    }
+}
@@ -0,0 +1,504 @@
+//! JSON-RPC 2.0 over a pair of streams, one message per line, so that the extension can run the
+//! indexer as a subprocess (`continue-sync serve`) and talk to it over stdin and stdout
+//!
+//! Requests:
+//! - `sync` `{dir, branch, providerId, batchSize?, dryRun?, force?}`: syncs with
+//!   `sync_in_batches`, sending each batch as a `results` notification `{id, results}` and a
+//!   `progress` notification `{id, batches, files}` after it, then replies `{batches, files}`
+//! - `status` `{dir, branch, providerId}`: replies with the tag's `TagStatus`
+//! - `listTags` `{dir?, providerId?}`: replies with the `TagEntry`s from `list_tags`
+//! - `search` `{dir, branch, providerId, query, limit?}`: replies with the `SearchHit`s from
+//!   `search`, which finds what the provider's `SearchIndex` has been given
+//! - `symbols` `{dir, branch, providerId, query, prefix?, limit?}`: replies with the
+//!   `SymbolHit`s from the provider's `SymbolIndex` named `query`, or with `prefix`, starting
+//!   with it (at most `limit` of them). Neither is served by wasm32 builds, which have no SQLite
+//! - `cancel` `{id}`: stops the `sync` with that request id after its current batch, which then
+//!   replies with a `REQUEST_CANCELLED` error. The batches already sent have been applied to
+//!   the caches, and the next sync carries on from there
+//!
+//! Each request runs on a thread of its own, so a long sync doesn't hold up the others.
+
+use super::{
+    error::{Result, SyncError},
+    list_tags,
+    options::SyncOptions,
+    sync_in_batches, tag_status, Tag,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use super::{search::search, symbols::SymbolIndex};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+
+/// A `SyncError`, with its message
+pub const SYNC_ERROR: i64 = -32000;
+
+/// The request was stopped by a `cancel`, as in the Language Server Protocol
+pub const REQUEST_CANCELLED: i64 = -32800;
+
+/// Files per `results` notification when a `sync` doesn't say
+const DEFAULT_BATCH_SIZE: usize = 1000;
+
+/// Hits in the reply to a `search`, or a `symbols` by prefix, that doesn't say
+#[cfg(not(target_arch = "wasm32"))]
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TagParams {
+    dir: PathBuf,
+    branch: String,
+    provider_id: String,
+}
+
+impl TagParams {
+    fn tag(&self) -> Tag<'_> {
+        Tag {
+            dir: &self.dir,
+            branch: &self.branch,
+            provider_id: &self.provider_id,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SyncParams {
+    #[serde(flatten)]
+    tag: TagParams,
+    batch_size: Option<usize>,
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListTagsParams {
+    dir: Option<PathBuf>,
+    provider_id: Option<String>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SearchParams {
+    #[serde(flatten)]
+    tag: TagParams,
+    query: String,
+    limit: Option<usize>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SymbolsParams {
+    #[serde(flatten)]
+    tag: TagParams,
+    query: String,
+    #[serde(default)]
+    prefix: bool,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct CancelParams {
+    id: Value,
+}
+
+/// An error to reply with
+#[derive(Debug, PartialEq, Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<SyncError> for RpcError {
+    fn from(err: SyncError) -> Self {
+        match err {
+            SyncError::Cancelled => RpcError::new(REQUEST_CANCELLED, err.to_string()),
+            err => RpcError::new(SYNC_ERROR, err.to_string()),
+        }
+    }
+}
+
+/// What the requests running at once share
+struct Server<'a, W> {
+    output: Mutex<W>,
+    options: &'a SyncOptions,
+
+    /// Set to cancel the `sync` with the request id, by its id in JSON
+    syncs: Mutex<HashMap<String, Arc<AtomicBool>>>,
+}
+
+impl<W: Write> Server<'_, W> {
+    /// Write one message, on a line of its own. The other end going away isn't an error, as
+    /// there's nobody left to tell
+    fn send(&self, message: &Value) {
+        let mut output = self.output.lock().unwrap();
+        let _ = serde_json::to_writer(&mut *output, message)
+            .map_err(std::io::Error::from)
+            .and_then(|_| output.write_all(b"\n"))
+            .and_then(|_| output.flush());
+    }
+
+    fn reply(&self, id: &Value, result: std::result::Result<Value, RpcError>) {
+        match result {
+            Ok(result) => self.send(&json!({"jsonrpc": "2.0", "id": id, "result": result})),
+            Err(error) => self.send(&json!({"jsonrpc": "2.0", "id": id, "error": error})),
+        }
+    }
+
+    fn notify(&self, method: &str, params: Value) {
+        self.send(&json!({"jsonrpc": "2.0", "method": method, "params": params}));
+    }
+
+    fn handle(
+        &self,
+        id: &Value,
+        method: &str,
+        params: Value,
+    ) -> std::result::Result<Value, RpcError> {
+        match method {
+            "sync" => self.sync(id, parse_params(params)?),
+            "status" => {
+                let params: TagParams = parse_params(params)?;
+                Ok(to_value(tag_status(&params.tag(), self.options)?))
+            }
+            "listTags" => {
+                let params: ListTagsParams = parse_params(params)?;
+                let tags = list_tags(
+                    self.options,
+                    params.dir.as_deref(),
+                    params.provider_id.as_deref(),
+                )?;
+                Ok(to_value(tags))
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            "search" => {
+                let params: SearchParams = parse_params(params)?;
+                let limit = params.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+                let hits = search(&params.tag.tag(), &params.query, limit, self.options)?;
+                Ok(to_value(hits))
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            "symbols" => {
+                let params: SymbolsParams = parse_params(params)?;
+                let tag = params.tag.tag();
+                let index = SymbolIndex::open(tag.provider_id, self.options)?;
+                let hits = match params.prefix {
+                    true => {
+                        let limit = params.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+                        index.find_prefix(&tag, &params.query, limit)?
+                    }
+                    false => index.find(&tag, &params.query)?,
+                };
+                Ok(to_value(hits))
+            }
+            "cancel" => {
+                let params: CancelParams = parse_params(params)?;
+                if let Some(cancelled) = self.syncs.lock().unwrap().get(&params.id.to_string()) {
+                    cancelled.store(true, Ordering::Relaxed);
+                }
+                Ok(Value::Null)
+            }
+            method => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Unknown method {method}"),
+            )),
+        }
+    }
+
+    fn sync(&self, id: &Value, params: SyncParams) -> std::result::Result<Value, RpcError> {
+        let options = self
+            .options
+            .clone()
+            .dry_run(params.dry_run)
+            .force(params.force);
+        let batch_size = params.batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.syncs
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), cancelled.clone());
+
+        let (mut batches, mut files) = (0, 0);
+        let result = sync_in_batches(&params.tag.tag(), &options, batch_size, |results| {
+            batches += 1;
+            files += results.action_count();
+            self.notify("results", json!({"id": id, "results": results}));
+            self.notify(
+                "progress",
+                json!({"id": id, "batches": batches, "files": files}),
+            );
+            match cancelled.load(Ordering::Relaxed) {
+                true => Err(SyncError::Cancelled),
+                false => Ok(()),
+            }
+        });
+        self.syncs.lock().unwrap().remove(&id.to_string());
+        result?;
+        Ok(json!({"batches": batches, "files": files}))
+    }
+}
+
+fn parse_params<T: DeserializeOwned>(params: Value) -> std::result::Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))
+}
+
+fn to_value(value: impl Serialize) -> Value {
+    serde_json::to_value(value).expect("results serialize to JSON")
+}
+
+/// Answer the JSON-RPC requests read from `input` on `output` until `input` ends, then wait for
+/// the ones still running to finish. `options` are used for every request, so say where the
+/// index is
+pub fn serve_json_rpc(
+    input: impl BufRead,
+    output: impl Write + Send,
+    options: &SyncOptions,
+) -> Result<()> {
+    let server = Server {
+        output: Mutex::new(output),
+        options,
+        syncs: Mutex::new(HashMap::new()),
+    };
+    let server = &server;
+    thread::scope(|scope| {
+        for line in input.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let message: Value = match serde_json::from_str(&line) {
+                Ok(message) => message,
+                Err(err) => {
+                    server.reply(
+                        &Value::Null,
+                        Err(RpcError::new(PARSE_ERROR, err.to_string())),
+                    );
+                    continue;
+                }
+            };
+            let method = match message.get("method").and_then(Value::as_str) {
+                Some(method) => method.to_string(),
+                None => {
+                    let id = message.get("id").cloned().unwrap_or(Value::Null);
+                    server.reply(&id, Err(RpcError::new(INVALID_REQUEST, "No method")));
+                    continue;
+                }
+            };
+            let params = message.get("params").cloned().unwrap_or(Value::Null);
+            let id = message.get("id").cloned();
+            if method == "cancel" {
+                // Straight away, rather than behind the request it's cancelling
+                let result = server.handle(&Value::Null, &method, params);
+                if let Some(id) = id {
+                    server.reply(&id, result);
+                }
+                continue;
+            }
+            scope.spawn(move || {
+                let result = server.handle(id.as_ref().unwrap_or(&Value::Null), &method, params);
+                // Notifications don't get a reply
+                if let Some(id) = id {
+                    server.reply(&id, result);
+                }
+            });
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{temp_index_root, TempDirBuilder};
+
+    /// Serve `requests`, returning every message sent back
+    fn serve(requests: &[Value], options: &SyncOptions) -> Vec<Value> {
+        let input: String = requests
+            .iter()
+            .map(|request| format!("{request}\n"))
+            .collect();
+        let mut output = Vec::new();
+        serve_json_rpc(input.as_bytes(), &mut output, options).unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_serve_json_rpc() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("b.txt", "B")
+            .add("c.txt", "C")
+            .create();
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let tag = json!({
+            "dir": temp_dir.path(),
+            "branch": "main",
+            "providerId": "default",
+        });
+        let mut sync_params = tag.clone();
+        sync_params["batchSize"] = json!(2);
+        let mut search_params = tag.clone();
+        search_params["query"] = json!("A");
+
+        let messages = serve(
+            &[json!({"jsonrpc": "2.0", "id": 1, "method": "sync", "params": sync_params})],
+            &options,
+        );
+        let batches: Vec<&Value> = messages
+            .iter()
+            .filter(|message| message["method"] == "results")
+            .collect();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0]["params"]["id"], 1);
+        assert_eq!(
+            batches[0]["params"]["results"]["compute"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+        let progress = messages
+            .iter()
+            .rfind(|message| message["method"] == "progress")
+            .unwrap();
+        assert_eq!(
+            progress["params"],
+            json!({"id": 1, "batches": 2, "files": 3})
+        );
+        assert_eq!(
+            messages.last().unwrap(),
+            &json!({"jsonrpc": "2.0", "id": 1, "result": {"batches": 2, "files": 3}})
+        );
+
+        let messages = serve(
+            &[
+                json!({"jsonrpc": "2.0", "id": "s", "method": "status", "params": tag}),
+                json!({"jsonrpc": "2.0", "id": "t", "method": "listTags", "params": {}}),
+                json!({"jsonrpc": "2.0", "id": "q", "method": "search", "params": search_params}),
+                json!({"jsonrpc": "2.0", "id": "y", "method": "symbols", "params": search_params}),
+            ],
+            &options,
+        );
+        let reply = |id: &str| {
+            messages
+                .iter()
+                .find(|message| message["id"] == id)
+                .unwrap()
+                .clone()
+        };
+        assert_eq!(reply("s")["result"]["upToDate"], true);
+        assert_eq!(reply("s")["result"]["files"], 3);
+        assert_eq!(reply("t")["result"].as_array().unwrap().len(), 1);
+        // Nothing has filled the search or symbol index
+        assert_eq!(reply("q")["result"], json!([]));
+        assert_eq!(reply("y")["result"], json!([]));
+    }
+
+    #[test]
+    fn test_json_rpc_errors() {
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let messages = serve(
+            &[
+                json!({"jsonrpc": "2.0", "id": 1, "method": "bogus"}),
+                json!({"jsonrpc": "2.0", "id": 2, "method": "status", "params": {"dir": "x"}}),
+                json!({"jsonrpc": "2.0", "id": 3}),
+                json!({"jsonrpc": "2.0", "method": "listTags", "params": {}}),
+                json!({"jsonrpc": "2.0", "id": 4, "method": "cancel", "params": {"id": 9}}),
+            ],
+            &options,
+        );
+        let code = |id: i64| {
+            messages
+                .iter()
+                .find(|message| message["id"] == id)
+                .map(|message| message["error"]["code"].clone())
+        };
+        assert_eq!(code(1), Some(json!(METHOD_NOT_FOUND)));
+        assert_eq!(code(2), Some(json!(INVALID_PARAMS)));
+        assert_eq!(code(3), Some(json!(INVALID_REQUEST)));
+        assert_eq!(code(4), Some(Value::Null));
+        // The notification got no reply
+        assert_eq!(messages.len(), 4);
+
+        let mut output = Vec::new();
+        serve_json_rpc("not json\n".as_bytes(), &mut output, &options).unwrap();
+        let reply: Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(reply["error"]["code"], PARSE_ERROR);
+    }
+
+    #[test]
+    fn test_cancel_sync() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("b.txt", "B")
+            .create();
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let server = Server {
+            output: Mutex::new(Vec::new()),
+            options: &options,
+            syncs: Mutex::new(HashMap::new()),
+        };
+        // Cancelled from the first batch, as a `cancel` arriving during it would
+        let params = json!({
+            "dir": temp_dir.path(),
+            "branch": "main",
+            "providerId": "default",
+            "batchSize": 1,
+        });
+        let id = json!(7);
+        let cancel = json!({ "id": id });
+        thread::scope(|scope| {
+            let output = server.output.lock().unwrap();
+            let sync = scope.spawn(|| server.handle(&id, "sync", params));
+            while !server.syncs.lock().unwrap().contains_key("7") {
+                thread::yield_now();
+            }
+            server.handle(&Value::Null, "cancel", cancel).unwrap();
+            drop(output);
+            let result = sync.join().unwrap();
+            assert_eq!(result.unwrap_err().code, REQUEST_CANCELLED);
+        });
+        let output = server.output.into_inner().unwrap();
+        let batches = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .filter(|line| line.contains("\"results\""))
+            .count();
+        assert_eq!(batches, 1);
+    }
+}
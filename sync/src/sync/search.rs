@@ -0,0 +1,381 @@
+//! Full-text search over the files of each tag, as an `IndexProvider`
+//!
+//! The index is an SQLite FTS5 table in `providers/<provider_id>/search.db`, one row per
+//! document: a chunk of a file with `SyncOptions::chunking`, or else the whole file. Documents
+//! are kept by content hash, like the provider's caches, so content that several tags or paths
+//! share is indexed once; which tags have it at which paths is kept beside them, and a search
+//! only finds what the tag it's for has. Content is read when a sync first reports it to compute
+//! or add, from `SyncOptions::store_objects` if it was saved there, and dropped once the last
+//! path with it is removed.
+
+use super::{
+    chunk,
+    error::Result,
+    merkle::{hash_string, ObjectHash},
+    options::SyncOptions,
+    provider::{self, IndexProvider, PATHS_TABLE},
+    warning::SyncWarning,
+    SyncResults, Tag,
+};
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::{path::Path, sync::Mutex};
+
+/// A document found by `search`, at one of the paths the tag has its content at
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchHit {
+    /// Where the file is, under the tag's directory
+    pub path: String,
+
+    /// The path within the tag's directory, as it's kept in the tree
+    pub relative_path: String,
+
+    /// Hex-encoded content hash of the file
+    pub hash: String,
+
+    /// Where the document starts in the file, in bytes
+    pub start_byte: u64,
+
+    /// Where the document ends in the file, in bytes, not including this one
+    pub end_byte: u64,
+
+    /// The line the document starts on, counting from 0
+    pub start_line: u64,
+
+    /// The line the document ends on, including it
+    pub end_line: u64,
+
+    /// How well the document matches, higher for better (BM25)
+    pub score: f64,
+
+    /// The words around the best match in the document
+    pub snippet: String,
+}
+
+/// A piece of content to index, read from a file
+struct Document {
+    hash: ObjectHash,
+    start_byte: u64,
+    end_byte: u64,
+    start_line: u64,
+    end_line: u64,
+    text: String,
+}
+
+/// The documents of a file with `contents`: its chunks with `SyncOptions::chunking`, or else
+/// all of it. Empty files have none
+fn documents(
+    hash: ObjectHash,
+    path: &Path,
+    contents: &[u8],
+    options: &SyncOptions,
+) -> Vec<Document> {
+    let text = |start: u64, end: u64| {
+        String::from_utf8_lossy(&contents[start as usize..end as usize]).into_owned()
+    };
+    match options.chunking {
+        Some(chunking) => chunk::chunk(path, contents, chunking)
+            .into_iter()
+            .map(|chunk| Document {
+                hash,
+                text: text(chunk.start_byte, chunk.end_byte),
+                start_byte: chunk.start_byte,
+                end_byte: chunk.end_byte,
+                start_line: chunk.start_line,
+                end_line: chunk.end_line,
+            })
+            .collect(),
+        None if contents.is_empty() => Vec::new(),
+        None => {
+            let end = contents.len() as u64;
+            let lines = contents.split_inclusive(|&byte| byte == b'\n').count() as u64;
+            vec![Document {
+                hash,
+                text: text(0, end),
+                start_byte: 0,
+                end_byte: end,
+                start_line: 0,
+                end_line: lines - 1,
+            }]
+        }
+    }
+}
+
+/// An FTS5 query matching documents with every word in `query`, as the start of a word, or
+/// None if it has no words. Nothing in `query` is taken as query syntax. Documents are split
+/// into words at underscores too, so a word with them matches its parts in a row
+fn match_query(query: &str) -> Option<String> {
+    let words: Vec<String> = query
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{word}\"*"))
+        .collect();
+    (!words.is_empty()).then(|| words.join(" "))
+}
+
+/// The full-text index of one provider's tags
+pub struct SearchIndex {
+    conn: Connection,
+}
+
+impl SearchIndex {
+    /// Open (or create) the search index of the provider
+    pub fn open(provider_id: &str, options: &SyncOptions) -> Result<Self> {
+        let index_root = options.resolve_index_root()?;
+        options
+            .resolve_index_file_system()
+            .create_dir_all(&index_root.provider_dir(provider_id))?;
+        let conn = Connection::open(index_root.search_db(provider_id))?;
+
+        // Each sync's changes are one transaction, which is only fast without a sync per commit
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS documents (
+                id INTEGER PRIMARY KEY,
+                hash BLOB NOT NULL,
+                start_byte INTEGER NOT NULL,
+                end_byte INTEGER NOT NULL,
+                start_line INTEGER NOT NULL,
+                end_line INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS documents_hash ON documents (hash);
+            CREATE VIRTUAL TABLE IF NOT EXISTS documents_text USING fts5 (text);",
+        )?;
+        conn.execute_batch(PATHS_TABLE)?;
+        Ok(SearchIndex { conn })
+    }
+
+    fn is_indexed(&self, hash: &ObjectHash) -> Result<bool> {
+        Ok(self
+            .conn
+            .prepare_cached("SELECT 1 FROM documents WHERE hash = ?1 LIMIT 1")?
+            .query_row((&hash[..],), |_| Ok(()))
+            .optional()?
+            .is_some())
+    }
+
+    /// The documents of `tag` that best match every word of `query`, best first, and at most
+    /// `limit` of them. Words match the start of words in the documents, ignoring case
+    pub fn search(&self, tag: &Tag, query: &str, limit: usize) -> Result<Vec<SearchHit>> {
+        let Some(query) = match_query(query) else {
+            return Ok(Vec::new());
+        };
+        let tag = tag.canonicalize().to_string();
+        let mut statement = self.conn.prepare_cached(
+            "SELECT paths.path, paths.relative_path, documents.hash, documents.start_byte,
+                documents.end_byte, documents.start_line, documents.end_line,
+                bm25(documents_text), snippet(documents_text, 0, '', '', '...', 16)
+            FROM documents_text
+            JOIN documents ON documents.id = documents_text.rowid
+            JOIN paths ON paths.hash = documents.hash
+            WHERE documents_text MATCH ?1 AND paths.tag = ?2
+            ORDER BY bm25(documents_text), paths.relative_path, documents.start_byte
+            LIMIT ?3",
+        )?;
+        let hits = statement
+            .query_map((query, tag, limit as i64), |row| {
+                let hash: ObjectHash = row.get(2)?;
+                let score: f64 = row.get(7)?;
+                Ok(SearchHit {
+                    path: row.get(0)?,
+                    relative_path: row.get(1)?,
+                    hash: hash_string(hash),
+                    start_byte: row.get(3)?,
+                    end_byte: row.get(4)?,
+                    start_line: row.get(5)?,
+                    end_line: row.get(6)?,
+                    // BM25 is lower for better matches in FTS5
+                    score: -score,
+                    snippet: row.get(8)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(hits)
+    }
+}
+
+impl IndexProvider for SearchIndex {
+    fn apply(
+        &mut self,
+        tag: &Tag,
+        results: &SyncResults,
+        options: &SyncOptions,
+    ) -> Result<Vec<SyncWarning>> {
+        let canonical = tag.canonicalize();
+        let tag = &canonical.as_tag();
+        let options = &options.for_provider(tag.provider_id);
+        let unindexed = provider::unindexed(results, |hash| self.is_indexed(hash))?;
+        let read = Mutex::new(Vec::new());
+        let warnings = provider::read_contents(tag, &unindexed, options, |item, contents| {
+            let found = documents(item.hash, &item.path, contents, options);
+            read.lock().unwrap().extend(found);
+        })?;
+
+        let transaction = self.conn.transaction()?;
+        for document in read.into_inner().unwrap() {
+            transaction
+                .prepare_cached(
+                    "INSERT INTO documents (hash, start_byte, end_byte, start_line, end_line)
+                    VALUES (?1, ?2, ?3, ?4, ?5)",
+                )?
+                .execute((
+                    &document.hash[..],
+                    document.start_byte,
+                    document.end_byte,
+                    document.start_line,
+                    document.end_line,
+                ))?;
+            transaction
+                .prepare_cached("INSERT INTO documents_text (rowid, text) VALUES (?1, ?2)")?
+                .execute((transaction.last_insert_rowid(), &document.text))?;
+        }
+        for hash in provider::update_paths(&transaction, &tag.to_string(), results)? {
+            transaction
+                .prepare_cached(
+                    "DELETE FROM documents_text
+                    WHERE rowid IN (SELECT id FROM documents WHERE hash = ?1)",
+                )?
+                .execute((&hash[..],))?;
+            transaction
+                .prepare_cached("DELETE FROM documents WHERE hash = ?1")?
+                .execute((&hash[..],))?;
+        }
+        transaction.commit()?;
+        Ok(warnings)
+    }
+}
+
+/// The documents of `tag` that best match every word of `query`, at most `limit` of them, from
+/// the provider's `SearchIndex`
+pub fn search(
+    tag: &Tag,
+    query: &str,
+    limit: usize,
+    options: &SyncOptions,
+) -> Result<Vec<SearchHit>> {
+    SearchIndex::open(tag.provider_id, options)?.search(tag, query, limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sync::{delete_tag, provider::sync_providers, ChunkOptions},
+        utils::{temp_index_root, TempDirBuilder},
+    };
+    use std::fs;
+
+    #[test]
+    fn test_match_query() {
+        assert_eq!(match_query(""), None);
+        assert_eq!(match_query(" ();"), None);
+        assert_eq!(
+            match_query("fn sync_tag(\"x\")"),
+            Some("\"fn\"* \"sync_tag\"* \"x\"*".to_string())
+        );
+    }
+
+    #[test]
+    fn test_search_index() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.rs", "fn parse_config() {}")
+            .add("b.rs", "fn render_page() {}")
+            .create();
+        let other_dir = TempDirBuilder::new()
+            .add("c.rs", "fn parse_config() {}")
+            .create();
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let other = &Tag {
+            dir: other_dir.path(),
+            ..*tag
+        };
+        let mut index = SearchIndex::open("default", &options).unwrap();
+        let paths = |hits: Vec<SearchHit>| -> Vec<String> {
+            hits.into_iter().map(|hit| hit.relative_path).collect()
+        };
+
+        // Only the tag's own files are found, by the start of their words
+        sync_providers(tag, &options, &mut [&mut index]).unwrap();
+        assert_eq!(paths(index.search(tag, "parse", 10).unwrap()), ["a.rs"]);
+        assert_eq!(paths(index.search(tag, "RENDER_p", 10).unwrap()), ["b.rs"]);
+        assert_eq!(
+            paths(index.search(tag, "parse_config", 10).unwrap()),
+            ["a.rs"]
+        );
+        assert!(index.search(tag, "config page", 10).unwrap().is_empty());
+        assert!(index.search(other, "parse", 10).unwrap().is_empty());
+        let hit = &index.search(tag, "parse", 10).unwrap()[0];
+        assert_eq!((hit.start_line, hit.end_line), (0, 0));
+        assert_eq!(hit.snippet, "fn parse_config() {}\n");
+
+        // Content already indexed for a tag is found in another
+        sync_providers(other, &options, &mut [&mut index]).unwrap();
+        assert_eq!(paths(index.search(other, "parse", 10).unwrap()), ["c.rs"]);
+
+        // Renamed, changed and deleted files
+        fs::rename(temp_dir.path().join("a.rs"), temp_dir.path().join("d.rs")).unwrap();
+        fs::write(temp_dir.path().join("b.rs"), "fn render_preview() {}\n").unwrap();
+        sync_providers(tag, &options, &mut [&mut index]).unwrap();
+        assert_eq!(paths(index.search(tag, "parse", 10).unwrap()), ["d.rs"]);
+        assert!(index.search(tag, "page", 10).unwrap().is_empty());
+        assert_eq!(
+            search(tag, "preview", 10, &options).unwrap()[0].relative_path,
+            "b.rs"
+        );
+        delete_tag(other, &options)
+            .and_then(|results| index.apply(other, &results, &options))
+            .unwrap();
+        assert!(index.search(other, "parse", 10).unwrap().is_empty());
+        assert_eq!(paths(index.search(tag, "parse", 10).unwrap()), ["d.rs"]);
+
+        // A dry run changes nothing
+        fs::remove_file(temp_dir.path().join("d.rs")).unwrap();
+        let dry_run = options.clone().dry_run(true);
+        sync_providers(tag, &dry_run, &mut [&mut index]).unwrap();
+        assert_eq!(paths(index.search(tag, "parse", 10).unwrap()), ["d.rs"]);
+        sync_providers(tag, &options, &mut [&mut index]).unwrap();
+        assert!(index.search(tag, "parse", 10).unwrap().is_empty());
+        let count: i64 = index
+            .conn
+            .query_row("SELECT COUNT(*) FROM documents", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_search_chunks() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.py", "def first():\n    pass\n\ndef second():\n    pass")
+            .create();
+        let (_index_dir, index_root) = temp_index_root();
+        let chunking = ChunkOptions {
+            max_size: 30,
+            overlap: 0,
+            syntax_aware: true,
+        };
+        let options = SyncOptions::new()
+            .index_root(index_root)
+            .store_objects(true)
+            .chunking(chunking);
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let mut index = SearchIndex::open("default", &options).unwrap();
+        sync_providers(tag, &options, &mut [&mut index]).unwrap();
+
+        // Each chunk is found on its own, with its lines
+        let hits = index.search(tag, "second", 10).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].start_line, hits[0].end_line), (2, 4));
+    }
+}
@@ -0,0 +1,226 @@
+use super::{
+    bloom::Stamp, error::Result, index_root::IndexRoot, load_saved_tree_or_warn, lock_provider,
+    merkle::Tree, options::SyncOptions, prepare_index, sync_from, sync_read_only, tree_path,
+    writable, IndexCache, OwnedTag, SyncResults, SyncWarning, Tag,
+};
+use std::path::PathBuf;
+
+/// Keeps a tag's caches open and its last tree in memory between syncs
+///
+/// Meant for long-lived processes that sync the same tag over and over. If something else
+/// saves a new tree for the tag in the meantime, such as a call to `sync()`, the session notices
+/// and loads it again before its next sync.
+///
+/// ```
+/// use std::path::Path;
+/// use sync::sync::{IndexRoot, SyncOptions, SyncSession, Tag};
+///
+/// let index_dir = tempfile::tempdir().unwrap();
+/// let options = SyncOptions::new().index_root(IndexRoot::new(index_dir.path()));
+/// let tag = Tag {
+///     dir: Path::new("src"),
+///     branch: "main",
+///     provider_id: "default",
+/// };
+///
+/// let mut session = SyncSession::open(&tag, options).unwrap();
+/// let results = session.sync().unwrap();
+/// assert!(session.sync().unwrap().compute.is_empty());
+/// # drop(results);
+/// ```
+pub struct SyncSession {
+    tag: OwnedTag,
+    options: SyncOptions,
+    index_root: IndexRoot,
+    tree: Tree,
+
+    /// The saved tree file that `tree` matches, or None if there wasn't one
+    tree_stamp: Option<Stamp>,
+
+    /// Opened by the first sync that finds a change
+    index_cache: Option<IndexCache>,
+
+    /// Found while opening the session, reported by the first sync
+    warnings: Vec<SyncWarning>,
+
+    /// Set when `tree` has moved on from the saved tree while the index couldn't be written.
+    /// The caches only know about the saved one, so it's loaded again once syncs can save
+    unsaved: bool,
+}
+
+impl SyncSession {
+    pub fn open(tag: &Tag, options: SyncOptions) -> Result<Self> {
+        let canonical = tag.canonicalize();
+        let tag = &canonical.as_tag();
+        let options = options.for_provider(tag.provider_id);
+        let index_root = options.resolve_index_root()?;
+
+        let mut warnings = Vec::new();
+        let file_system = &*options.resolve_index_file_system();
+        let tree_stamp = Stamp::of(file_system, &tree_path(tag, &index_root)).ok();
+        Ok(SyncSession {
+            tag: tag.into(),
+            tree: load_saved_tree_or_warn(file_system, tag, &index_root, &mut warnings)?,
+            tree_stamp,
+            warnings,
+            index_cache: None,
+            unsaved: false,
+            options,
+            index_root,
+        })
+    }
+
+    pub fn tag(&self) -> Tag<'_> {
+        self.tag.as_tag()
+    }
+
+    /// The tree saved by the last sync
+    pub fn tree(&self) -> &Tree {
+        &self.tree
+    }
+
+    /// Same as `sync()`, but without reloading anything from disk first, unless the saved tree
+    /// has changed since the session last saw it
+    pub fn sync(&mut self) -> Result<SyncResults> {
+        self.sync_with(None)
+    }
+
+    /// Same as `sync()`, but only looking at the `changed` paths, relative to the tag's
+    /// directory. Directories that aren't one of them, or above or below one, are taken to be
+    /// the same as in the last sync without being listed again, so this is for callers that
+    /// know everything that has changed since, such as a `SyncWatcher`
+    pub fn sync_changed(&mut self, changed: &[PathBuf]) -> Result<SyncResults> {
+        self.sync_with(Some(changed))
+    }
+
+    fn sync_with(&mut self, changed: Option<&[PathBuf]>) -> Result<SyncResults> {
+        let tag = self.tag.as_tag();
+        if let Err(err) = prepare_index(&tag, &self.index_root, &self.options) {
+            let (results, new_tree) = sync_read_only(
+                &tag,
+                &self.options,
+                &self.index_root,
+                &self.tree,
+                changed,
+                std::mem::take(&mut self.warnings),
+                err,
+            )?;
+            // Nothing was saved, but the next sync can still start from here
+            if !self.options.dry_run {
+                self.tree = new_tree;
+                self.unsaved = true;
+            }
+            return Ok(results);
+        }
+        let _lock = lock_provider(&tag, &self.index_root, &self.options)?;
+        let tree_path = tree_path(&tag, &self.index_root);
+        let file_system = &*self.options.resolve_index_file_system();
+        let mut changed = changed;
+        if self.unsaved || Stamp::of(file_system, &tree_path).ok() != self.tree_stamp {
+            // Nothing is known about what changed since the tree that's loaded
+            changed = None;
+            // The caches may have been changed along with the tree, so reopen those too
+            self.tree_stamp = Stamp::of(file_system, &tree_path).ok();
+            self.tree =
+                load_saved_tree_or_warn(file_system, &tag, &self.index_root, &mut self.warnings)?;
+            self.index_cache = None;
+            self.unsaved = false;
+        }
+
+        let (results, new_tree) = sync_from(
+            &tag,
+            &self.options,
+            &self.index_root,
+            &self.tree,
+            changed,
+            &mut self.index_cache,
+            std::mem::take(&mut self.warnings),
+        )
+        .map_err(|err| writable::classify(err, self.index_root.path()))?;
+
+        if !self.options.dry_run {
+            self.tree = new_tree;
+            self.tree_stamp = Stamp::of(file_system, &tree_path).ok();
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sync::{compute_tree_for_dir, sync},
+        utils::{temp_index_root, TempDirBuilder},
+    };
+    use std::fs;
+
+    #[test]
+    fn test_session_matches_sync() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("b.txt", "B")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let tag2 = &Tag {
+            branch: "other",
+            ..*tag
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+
+        let mut session = SyncSession::open(tag, options.clone()).unwrap();
+        assert_eq!(session.sync().unwrap().compute.len(), 2);
+        assert!(session.sync().unwrap().compute.is_empty());
+
+        fs::write(temp_dir.path().join("a.txt"), "A changed").unwrap();
+        let results = session.sync().unwrap();
+        assert_eq!(results.compute.len(), 1);
+        assert_eq!(results.delete.len(), 1);
+        let tree = compute_tree_for_dir(temp_dir.path(), &options).unwrap();
+        assert_eq!(session.tree().hash(), tree.hash());
+
+        // The session writes the same state to disk as sync() would
+        let results = sync(tag2, &options).unwrap();
+        assert_eq!(results.add_tag.len(), 2);
+        drop(session);
+        let mut session = SyncSession::open(tag, options).unwrap();
+        assert_eq!(session.sync().unwrap(), SyncResults::default());
+    }
+
+    #[test]
+    fn test_session_reloads_changed_tree() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("b.txt", "B")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+
+        let mut session = SyncSession::open(tag, options.clone()).unwrap();
+        assert_eq!(session.sync().unwrap().compute.len(), 2);
+
+        // A sync from outside the session is picked up rather than repeated
+        fs::write(temp_dir.path().join("a.txt"), "A changed").unwrap();
+        let results = sync(tag, &options).unwrap();
+        assert_eq!(results.compute.len(), 1);
+        assert_eq!(session.sync().unwrap(), SyncResults::default());
+        let tree = compute_tree_for_dir(temp_dir.path(), &options).unwrap();
+        assert_eq!(session.tree().hash(), tree.hash());
+
+        // And the session's own syncs still work afterwards
+        fs::write(temp_dir.path().join("b.txt"), "B changed").unwrap();
+        let results = session.sync().unwrap();
+        assert_eq!(results.compute.len(), 1);
+        assert_eq!(sync(tag, &options).unwrap(), SyncResults::default());
+    }
+}
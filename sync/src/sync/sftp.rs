@@ -0,0 +1,839 @@
+//! `SftpFileSystem`, which walks and reads a workspace on another machine over SFTP, so that a
+//! tag can point at a directory on a remote dev box and still be indexed locally
+//!
+//! It speaks version 3 of the protocol, which is what OpenSSH's sftp-server speaks, to
+//! `ssh -s <destination> sftp` (or anything else given a pipe to one). Every request is a round
+//! trip, so it asks for as few as the walk allows:
+//!
+//! - The attributes that come back with a directory's listing answer `metadata` for each entry
+//!   in it, so files aren't stat'ed one by one. Symlinks are the exception, since their listing
+//!   describes the link rather than what it points to.
+//! - `read` of a name that isn't in its directory's listing fails without asking, which is what
+//!   becomes of the ignore files most directories don't have.
+//! - A file's reads are sent a window at a time rather than one after another, and handles are
+//!   closed without waiting for the answer.
+//!
+//! Files come back with modification times in whole seconds, so a file rewritten within a second
+//! of being synced, at the same size, is missed until it changes again. The index stays on this
+//! machine: `home_dir` is the local one, and nothing is written on the remote end.
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    ffi::OsString,
+    fmt,
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use super::{
+    file_system::{local_home_dir, FileHandle, FileMetadata, FileSystem},
+    merkle::{path_from_bytes, path_to_bytes},
+};
+
+const SSH_FXP_INIT: u8 = 1;
+const SSH_FXP_VERSION: u8 = 2;
+const SSH_FXP_OPEN: u8 = 3;
+const SSH_FXP_CLOSE: u8 = 4;
+const SSH_FXP_READ: u8 = 5;
+const SSH_FXP_OPENDIR: u8 = 11;
+const SSH_FXP_READDIR: u8 = 12;
+const SSH_FXP_STAT: u8 = 17;
+const SSH_FXP_STATUS: u8 = 101;
+const SSH_FXP_HANDLE: u8 = 102;
+const SSH_FXP_DATA: u8 = 103;
+const SSH_FXP_NAME: u8 = 104;
+const SSH_FXP_ATTRS: u8 = 105;
+
+const SSH_FX_EOF: u32 = 1;
+const SSH_FX_NO_SUCH_FILE: u32 = 2;
+const SSH_FX_PERMISSION_DENIED: u32 = 3;
+
+const SSH_FILEXFER_ATTR_SIZE: u32 = 0x1;
+const SSH_FILEXFER_ATTR_UIDGID: u32 = 0x2;
+const SSH_FILEXFER_ATTR_PERMISSIONS: u32 = 0x4;
+const SSH_FILEXFER_ATTR_ACMODTIME: u32 = 0x8;
+const SSH_FILEXFER_ATTR_EXTENDED: u32 = 0x8000_0000;
+
+const SSH_FXF_READ: u32 = 0x1;
+
+const S_IFMT: u32 = 0o170_000;
+const S_IFDIR: u32 = 0o040_000;
+const S_IFLNK: u32 = 0o120_000;
+
+/// The protocol version spoken, the last one OpenSSH implements
+const VERSION: u32 = 3;
+
+/// How much each read asks for. Servers may send less, but all of them send this much
+const READ_CHUNK: u32 = 32 * 1024;
+
+/// How many reads of a file are sent before waiting for the first answer: a few to begin with,
+/// which covers most source files, then more for a file that turns out to be large
+const FIRST_READ_WINDOW: u64 = 4;
+const READ_WINDOW: u64 = 32;
+
+/// The longest packet accepted from the server, well past any it sends in answer to these
+/// requests, so that a corrupt length doesn't allocate gigabytes
+const MAX_PACKET_LEN: usize = 16 * 1024 * 1024;
+
+/// A workspace on another machine, read over SFTP (see `sftp.rs`). Give it to
+/// `SyncOptions::file_system`, and the tag's directory is a path on that machine
+pub struct SftpFileSystem {
+    connection: Mutex<Connection>,
+
+    /// What the last listing of each directory said, by directory, to answer `metadata` and
+    /// `read` from
+    listings: Mutex<HashMap<PathBuf, Listing>>,
+}
+
+/// The entries in a directory, with the metadata from its listing if it's still to be used. It's
+/// only used once, by the walk that listed the directory, so that an entry doesn't answer for a
+/// file that has changed since. Symlinks have none, as the listing doesn't follow them
+type Listing = HashMap<OsString, Option<FileMetadata>>;
+
+impl SftpFileSystem {
+    /// Connect to `destination` (`host` or `user@host`, or a `Host` from ~/.ssh/config) with
+    /// `ssh`, which has to be able to log in without asking for a password
+    pub fn connect(destination: &str) -> io::Result<Self> {
+        let mut command = Command::new("ssh");
+        command.args(["-o", "BatchMode=yes", "-s", destination, "sftp"]);
+        Self::spawn(command)
+    }
+
+    /// Run `command`, which speaks SFTP on its stdin and stdout, and talk to it: `ssh` with
+    /// options of its own, or an `sftp-server` to read the local machine through
+    pub fn spawn(mut command: Command) -> io::Result<Self> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let (stdin, stdout) = (child.stdin.take(), child.stdout.take());
+        let (Some(stdin), Some(stdout)) = (stdin, stdout) else {
+            return Err(io::Error::other("the SFTP command has no stdin or stdout"));
+        };
+        let mut connection = Connection::new(Box::new(stdout), Box::new(stdin));
+        connection.child = Some(child);
+        Self::start(connection)
+    }
+
+    /// Talk SFTP over `reader` and `writer`, which lead to the same server
+    pub fn new(
+        reader: impl Read + Send + 'static,
+        writer: impl Write + Send + 'static,
+    ) -> io::Result<Self> {
+        Self::start(Connection::new(Box::new(reader), Box::new(writer)))
+    }
+
+    fn start(mut connection: Connection) -> io::Result<Self> {
+        connection.handshake()?;
+        Ok(SftpFileSystem {
+            connection: Mutex::new(connection),
+            listings: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// The metadata the listing of `path`'s directory gave it, if it's still to be used
+    fn listed_metadata(&self, path: &Path) -> Option<FileMetadata> {
+        let (dir, name) = (path.parent()?, path.file_name()?);
+        let mut listings = self.listings.lock().unwrap();
+        listings.get_mut(dir)?.get_mut(name)?.take()
+    }
+
+    /// Whether the listing of `path`'s directory says there's nothing there
+    fn listed_missing(&self, path: &Path) -> bool {
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            return false;
+        };
+        let listings = self.listings.lock().unwrap();
+        listings
+            .get(dir)
+            .is_some_and(|listing| !listing.contains_key(name))
+    }
+
+    fn unsupported(&self, path: &Path) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "{} is on the SFTP server, which the index can't be kept on",
+                path.display()
+            ),
+        )
+    }
+}
+
+impl fmt::Debug for SftpFileSystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SftpFileSystem").finish_non_exhaustive()
+    }
+}
+
+impl FileSystem for SftpFileSystem {
+    fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        if let Some(metadata) = self.listed_metadata(path) {
+            return Ok(metadata);
+        }
+        let mut connection = self.connection.lock().unwrap();
+        let response = connection.request(SSH_FXP_STAT, &path_request(path))?;
+        match response.kind {
+            SSH_FXP_ATTRS => Ok(Decoder::new(&response.body).attrs()?.metadata()),
+            _ => Err(response.error(path)),
+        }
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        let mut connection = self.connection.lock().unwrap();
+        let handle = connection.open_handle(SSH_FXP_OPENDIR, &path_request(path), path)?;
+        let mut listing = Listing::new();
+        let result = loop {
+            let response = connection.request(SSH_FXP_READDIR, &string_request(&handle))?;
+            if response.kind != SSH_FXP_NAME {
+                break match response.status_code() {
+                    Some(SSH_FX_EOF) => Ok(()),
+                    _ => Err(response.error(path)),
+                };
+            }
+            let mut decoder = Decoder::new(&response.body);
+            for _ in 0..decoder.u32()? {
+                let name = path_from_bytes(decoder.string()?.to_vec());
+                let _long_name = decoder.string()?;
+                let attrs = decoder.attrs()?;
+                if name != Path::new(".") && name != Path::new("..") {
+                    let metadata = (!attrs.is_symlink()).then(|| attrs.metadata());
+                    listing.insert(name.into_os_string(), metadata);
+                }
+            }
+        };
+        connection.close(&handle)?;
+        result?;
+        let names = listing.keys().cloned().collect();
+        drop(connection);
+        self.listings
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), listing);
+        Ok(names)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        if self.listed_missing(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} does not exist", path.display()),
+            ));
+        }
+        let mut connection = self.connection.lock().unwrap();
+        let mut request = path_request(path);
+        request.extend_from_slice(&SSH_FXF_READ.to_be_bytes());
+        // No attributes, as the file isn't created
+        request.extend_from_slice(&0u32.to_be_bytes());
+        let handle = connection.open_handle(SSH_FXP_OPEN, &request, path)?;
+        let contents = connection.read_all(&handle, path);
+        connection.close(&handle)?;
+        contents
+    }
+
+    /// The local home directory, as the index is kept on this machine
+    fn home_dir(&self) -> Option<PathBuf> {
+        local_home_dir()
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+        Err(self.unsupported(path))
+    }
+
+    fn write(&self, path: &Path, _contents: &[u8], _fsync: bool) -> io::Result<()> {
+        Err(self.unsupported(path))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        Err(self.unsupported(path))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        Err(self.unsupported(path))
+    }
+}
+
+/// An answer from the server, to the request with the same id
+struct Response {
+    kind: u8,
+
+    /// What follows the id
+    body: Vec<u8>,
+}
+
+impl Response {
+    /// The code in an `SSH_FXP_STATUS`, or None if it's something else
+    fn status_code(&self) -> Option<u32> {
+        match self.kind {
+            SSH_FXP_STATUS => Decoder::new(&self.body).u32().ok(),
+            _ => None,
+        }
+    }
+
+    /// The error an answer that isn't the one asked for stands for, about `path`
+    fn error(&self, path: &Path) -> io::Error {
+        if self.kind != SSH_FXP_STATUS {
+            return invalid_data(format!("unexpected SFTP packet type {}", self.kind));
+        }
+        let mut decoder = Decoder::new(&self.body);
+        let code = decoder.u32().unwrap_or_default();
+        let message = decoder.string().unwrap_or_default();
+        let kind = match code {
+            SSH_FX_NO_SUCH_FILE => io::ErrorKind::NotFound,
+            SSH_FX_PERMISSION_DENIED => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::Other,
+        };
+        let message = String::from_utf8_lossy(message);
+        io::Error::new(kind, format!("{}: {message}", path.display()))
+    }
+}
+
+/// The pipe to the server, with the answers to requests that haven't been waited for yet
+struct Connection {
+    reader: Box<dyn Read + Send>,
+    writer: BufWriter<Box<dyn Write + Send>>,
+    next_id: u32,
+
+    /// Answers that came in while waiting for another
+    received: HashMap<u32, Response>,
+
+    /// Requests whose answers are thrown away, like those to closing a handle
+    ignored: HashSet<u32>,
+
+    /// Set once the pipe fails, which leaves it partway through a packet
+    lost: bool,
+
+    /// The process at the other end, if it was started here
+    child: Option<Child>,
+}
+
+impl Connection {
+    fn new(reader: Box<dyn Read + Send>, writer: Box<dyn Write + Send>) -> Self {
+        Connection {
+            reader,
+            writer: BufWriter::new(writer),
+            next_id: 0,
+            received: HashMap::new(),
+            ignored: HashSet::new(),
+            lost: false,
+            child: None,
+        }
+    }
+
+    fn handshake(&mut self) -> io::Result<()> {
+        // The only packets without an id
+        self.write_packet(SSH_FXP_INIT, &VERSION.to_be_bytes())?;
+        self.writer.flush()?;
+        let (kind, body) = self.read_packet()?;
+        let version = Decoder::new(&body).u32()?;
+        if kind != SSH_FXP_VERSION || version != VERSION {
+            return Err(invalid_data(format!(
+                "the SFTP server speaks version {version} of the protocol rather than {VERSION}"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Send a request without waiting for its answer, returning its id
+    fn send(&mut self, kind: u8, request: &[u8]) -> io::Result<u32> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let mut packet = Vec::with_capacity(4 + request.len());
+        packet.extend_from_slice(&id.to_be_bytes());
+        packet.extend_from_slice(request);
+        self.write_packet(kind, &packet)?;
+        Ok(id)
+    }
+
+    /// Wait for the answer to the request with `id`
+    fn receive(&mut self, id: u32) -> io::Result<Response> {
+        if let Some(response) = self.received.remove(&id) {
+            return Ok(response);
+        }
+        self.writer.flush()?;
+        loop {
+            let (kind, body) = self.read_packet()?;
+            let mut decoder = Decoder::new(&body);
+            let response_id = decoder.u32()?;
+            let response = Response {
+                kind,
+                body: decoder.rest().to_vec(),
+            };
+            if response_id == id {
+                return Ok(response);
+            }
+            if !self.ignored.remove(&response_id) {
+                self.received.insert(response_id, response);
+            }
+        }
+    }
+
+    fn request(&mut self, kind: u8, request: &[u8]) -> io::Result<Response> {
+        let id = self.send(kind, request)?;
+        self.receive(id)
+    }
+
+    /// The handle to the file or directory that an `SSH_FXP_OPEN` or `SSH_FXP_OPENDIR` of
+    /// `path` opens
+    fn open_handle(&mut self, kind: u8, request: &[u8], path: &Path) -> io::Result<Vec<u8>> {
+        let response = self.request(kind, request)?;
+        match response.kind {
+            SSH_FXP_HANDLE => Ok(Decoder::new(&response.body).string()?.to_vec()),
+            _ => Err(response.error(path)),
+        }
+    }
+
+    /// Close `handle`, without waiting to hear that it's closed
+    fn close(&mut self, handle: &[u8]) -> io::Result<()> {
+        let id = self.send(SSH_FXP_CLOSE, &string_request(handle))?;
+        self.ignored.insert(id);
+        Ok(())
+    }
+
+    /// Everything in the open file `handle`, read a window of chunks at a time
+    fn read_all(&mut self, handle: &[u8], path: &Path) -> io::Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        let mut window = FIRST_READ_WINDOW;
+        loop {
+            let start = contents.len() as u64;
+            let mut ids = Vec::new();
+            for chunk in 0..window {
+                let mut request = string_request(handle);
+                request.extend_from_slice(&(start + chunk * READ_CHUNK as u64).to_be_bytes());
+                request.extend_from_slice(&READ_CHUNK.to_be_bytes());
+                ids.push(self.send(SSH_FXP_READ, &request)?);
+            }
+            // Every answer is waited for, but only those up to the first that comes up short
+            // are used: the rest are asked for again from where it left off
+            let (mut short, mut end, mut error) = (false, false, None);
+            for id in ids {
+                let response = self.receive(id)?;
+                if short || end || error.is_some() {
+                    continue;
+                }
+                match response.kind {
+                    SSH_FXP_DATA => {
+                        let data = Decoder::new(&response.body).string()?;
+                        contents.extend_from_slice(data);
+                        short = data.len() < READ_CHUNK as usize;
+                    }
+                    _ if response.status_code() == Some(SSH_FX_EOF) => end = true,
+                    _ => error = Some(response.error(path)),
+                }
+            }
+            if let Some(error) = error {
+                return Err(error);
+            }
+            if end {
+                return Ok(contents);
+            }
+            window = READ_WINDOW;
+        }
+    }
+
+    fn write_packet(&mut self, kind: u8, body: &[u8]) -> io::Result<()> {
+        self.check_lost()?;
+        let result = (|| {
+            self.writer
+                .write_all(&(1 + body.len() as u32).to_be_bytes())?;
+            self.writer.write_all(&[kind])?;
+            self.writer.write_all(body)
+        })();
+        self.lost = result.is_err();
+        result
+    }
+
+    fn read_packet(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        self.check_lost()?;
+        let result = (|| {
+            let mut len = [0; 4];
+            self.reader.read_exact(&mut len)?;
+            let len = u32::from_be_bytes(len) as usize;
+            if !(1..=MAX_PACKET_LEN).contains(&len) {
+                return Err(invalid_data(format!("an SFTP packet of {len} bytes")));
+            }
+            let mut packet = vec![0; len];
+            self.reader.read_exact(&mut packet)?;
+            let body = packet.split_off(1);
+            Ok((packet[0], body))
+        })();
+        self.lost = result.is_err();
+        result
+    }
+
+    fn check_lost(&self) -> io::Result<()> {
+        match self.lost {
+            true => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "the SFTP connection was lost",
+            )),
+            false => Ok(()),
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // It's only been reading, so there's nothing to finish first
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// What the server says about a file, as much as is used of it
+struct Attrs {
+    size: Option<u64>,
+    permissions: Option<u32>,
+    mtime: Option<u32>,
+}
+
+impl Attrs {
+    fn is_symlink(&self) -> bool {
+        self.permissions
+            .is_some_and(|permissions| permissions & S_IFMT == S_IFLNK)
+    }
+
+    fn metadata(&self) -> FileMetadata {
+        FileMetadata {
+            is_dir: self
+                .permissions
+                .is_some_and(|permissions| permissions & S_IFMT == S_IFDIR),
+            len: self.size.unwrap_or_default(),
+            modified: self
+                .mtime
+                .map(|mtime| SystemTime::UNIX_EPOCH + Duration::from_secs(mtime.into())),
+        }
+    }
+}
+
+/// Reads the fields of a packet in order
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Decoder { bytes }
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err(invalid_data("a truncated SFTP packet".to_string()));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    fn attrs(&mut self) -> io::Result<Attrs> {
+        let flags = self.u32()?;
+        let size = match flags & SSH_FILEXFER_ATTR_SIZE {
+            0 => None,
+            _ => Some(self.u64()?),
+        };
+        if flags & SSH_FILEXFER_ATTR_UIDGID != 0 {
+            self.take(8)?;
+        }
+        let permissions = match flags & SSH_FILEXFER_ATTR_PERMISSIONS {
+            0 => None,
+            _ => Some(self.u32()?),
+        };
+        let mtime = match flags & SSH_FILEXFER_ATTR_ACMODTIME {
+            0 => None,
+            _ => {
+                let _atime = self.u32()?;
+                Some(self.u32()?)
+            }
+        };
+        if flags & SSH_FILEXFER_ATTR_EXTENDED != 0 {
+            for _ in 0..self.u32()? {
+                self.string()?;
+                self.string()?;
+            }
+        }
+        Ok(Attrs {
+            size,
+            permissions,
+            mtime,
+        })
+    }
+}
+
+/// A request made of one string
+fn string_request(bytes: &[u8]) -> Vec<u8> {
+    let mut request = Vec::with_capacity(4 + bytes.len());
+    request.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    request.extend_from_slice(bytes);
+    request
+}
+
+fn path_request(path: &Path) -> Vec<u8> {
+    string_request(&path_to_bytes(path))
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::{
+        sync::{get_root_hash, sync, SyncOptions, Tag},
+        utils::{temp_index_root, TempDirBuilder},
+    };
+    use std::{
+        fs::{self, File},
+        io::{Seek, SeekFrom},
+        os::unix::{fs::MetadataExt, net::UnixStream},
+        sync::Arc,
+        thread,
+    };
+
+    /// The requests a `FakeServer` has been sent, by type and path
+    type Requests = Arc<Mutex<Vec<(u8, PathBuf)>>>;
+
+    /// The names and paths of a directory's entries
+    type Entries = Vec<(PathBuf, PathBuf)>;
+
+    /// Enough of an sftp-server, over the local filesystem, to walk and read a directory, which
+    /// sends at most `max_read` bytes for each read
+    struct FakeServer {
+        stream: UnixStream,
+        requests: Requests,
+        max_read: usize,
+        /// Open files, and directories with the entries still to list
+        handles: HashMap<Vec<u8>, (File, Option<Entries>)>,
+        next_handle: u32,
+    }
+
+    impl FakeServer {
+        fn start(max_read: usize) -> (SftpFileSystem, Requests) {
+            let (client, server) = UnixStream::pair().unwrap();
+            let requests = Requests::default();
+            let mut server = FakeServer {
+                stream: server,
+                requests: requests.clone(),
+                max_read,
+                handles: HashMap::new(),
+                next_handle: 0,
+            };
+            thread::spawn(move || server.serve());
+            let reader = client.try_clone().unwrap();
+            (SftpFileSystem::new(reader, client).unwrap(), requests)
+        }
+
+        fn serve(&mut self) {
+            while let Ok((kind, body)) = self.read_packet() {
+                if kind == SSH_FXP_INIT {
+                    self.send(SSH_FXP_VERSION, &VERSION.to_be_bytes());
+                    continue;
+                }
+                let mut decoder = Decoder::new(&body);
+                let id = decoder.u32().unwrap();
+                let (kind, response) = self.answer(kind, &mut decoder);
+                let mut packet = id.to_be_bytes().to_vec();
+                packet.extend_from_slice(&response);
+                self.send(kind, &packet);
+            }
+        }
+
+        fn answer(&mut self, kind: u8, decoder: &mut Decoder) -> (u8, Vec<u8>) {
+            let argument = decoder.string().unwrap().to_vec();
+            if [SSH_FXP_STAT, SSH_FXP_OPEN, SSH_FXP_OPENDIR].contains(&kind) {
+                let path = path_from_bytes(argument.clone());
+                self.requests.lock().unwrap().push((kind, path));
+            }
+            let path = path_from_bytes(argument.clone());
+            let result = match kind {
+                SSH_FXP_STAT => {
+                    fs::metadata(&path).map(|metadata| (SSH_FXP_ATTRS, attrs(&metadata)))
+                }
+                SSH_FXP_OPEN => File::open(&path).map(|file| self.handle(file, None)),
+                SSH_FXP_OPENDIR => fs::read_dir(&path)
+                    .and_then(|entries| {
+                        let dot = Ok((PathBuf::from("."), path.clone()));
+                        let entries = entries.map(|entry| {
+                            let entry = entry?;
+                            Ok((entry.file_name().into(), entry.path()))
+                        });
+                        std::iter::once(dot).chain(entries).collect()
+                    })
+                    .map(|entries| self.handle(File::open(&path).unwrap(), Some(entries))),
+                SSH_FXP_READDIR => Ok(self.read_dir(&argument)),
+                SSH_FXP_READ => {
+                    let offset = decoder.u64().unwrap();
+                    let len = (decoder.u32().unwrap() as usize).min(self.max_read);
+                    let file = &mut self.handles.get_mut(&argument).unwrap().0;
+                    file.seek(SeekFrom::Start(offset)).unwrap();
+                    let mut data = Vec::new();
+                    file.take(len as u64).read_to_end(&mut data).unwrap();
+                    match data.is_empty() {
+                        true => Ok(status(SSH_FX_EOF)),
+                        false => Ok((SSH_FXP_DATA, string_request(&data))),
+                    }
+                }
+                SSH_FXP_CLOSE => {
+                    self.handles.remove(&argument);
+                    Ok(status(0))
+                }
+                _ => unreachable!(),
+            };
+            result.unwrap_or_else(|err| match err.kind() {
+                io::ErrorKind::NotFound => status(SSH_FX_NO_SUCH_FILE),
+                _ => status(4),
+            })
+        }
+
+        fn handle(&mut self, file: File, entries: Option<Entries>) -> (u8, Vec<u8>) {
+            let handle = self.next_handle.to_string().into_bytes();
+            self.next_handle += 1;
+            self.handles.insert(handle.clone(), (file, entries));
+            (SSH_FXP_HANDLE, string_request(&handle))
+        }
+
+        /// The whole listing the first time, then the end of it
+        fn read_dir(&mut self, handle: &[u8]) -> (u8, Vec<u8>) {
+            let Some(entries) = self.handles.get_mut(handle).unwrap().1.take() else {
+                return status(SSH_FX_EOF);
+            };
+            let mut response = (entries.len() as u32).to_be_bytes().to_vec();
+            for (name, path) in entries {
+                response.extend(path_request(&name));
+                response.extend(string_request(b""));
+                response.extend(attrs(&fs::symlink_metadata(path).unwrap()));
+            }
+            (SSH_FXP_NAME, response)
+        }
+
+        fn read_packet(&mut self) -> io::Result<(u8, Vec<u8>)> {
+            let mut len = [0; 4];
+            self.stream.read_exact(&mut len)?;
+            let mut packet = vec![0; u32::from_be_bytes(len) as usize];
+            self.stream.read_exact(&mut packet)?;
+            let body = packet.split_off(1);
+            Ok((packet[0], body))
+        }
+
+        fn send(&mut self, kind: u8, body: &[u8]) {
+            let mut packet = (1 + body.len() as u32).to_be_bytes().to_vec();
+            packet.push(kind);
+            packet.extend_from_slice(body);
+            let _ = self.stream.write_all(&packet);
+        }
+    }
+
+    fn attrs(metadata: &fs::Metadata) -> Vec<u8> {
+        let flags =
+            SSH_FILEXFER_ATTR_SIZE | SSH_FILEXFER_ATTR_PERMISSIONS | SSH_FILEXFER_ATTR_ACMODTIME;
+        let mut attrs = flags.to_be_bytes().to_vec();
+        attrs.extend_from_slice(&metadata.size().to_be_bytes());
+        attrs.extend_from_slice(&metadata.mode().to_be_bytes());
+        attrs.extend_from_slice(&(metadata.atime() as u32).to_be_bytes());
+        attrs.extend_from_slice(&(metadata.mtime() as u32).to_be_bytes());
+        attrs
+    }
+
+    fn status(code: u32) -> (u8, Vec<u8>) {
+        let mut response = code.to_be_bytes().to_vec();
+        response.extend(string_request(b"failed"));
+        response.extend(string_request(b""));
+        (SSH_FXP_STATUS, response)
+    }
+
+    #[test]
+    fn test_sftp_file_system() {
+        let large = "0123456789".repeat(30_000);
+        let temp_dir = TempDirBuilder::new()
+            .add(".continueignore", "*.log")
+            .add("a.txt", "A")
+            .add("b.log", "B")
+            .add("src/c.rs", "C")
+            .add("src/large.txt", &large)
+            .create();
+        let dir = temp_dir.path();
+        // Reads come back in pieces smaller than asked for
+        let (sftp, requests) = FakeServer::start(10_000);
+
+        let file = sftp.metadata(&dir.join("a.txt")).unwrap();
+        assert_eq!((file.is_dir, file.len), (false, 2));
+        assert!(file.modified.is_some());
+        assert!(sftp.metadata(&dir.join("src")).unwrap().is_dir);
+        let error = sftp.metadata(&dir.join("missing")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        let mut names = sftp.read_dir(&dir.join("src")).unwrap();
+        names.sort();
+        assert_eq!(names, ["c.rs", "large.txt"]);
+        let contents = sftp.read(&dir.join("src/large.txt")).unwrap();
+        assert_eq!(contents, format!("{large}\n").as_bytes());
+        assert!(sftp.open(&dir.join("a.txt")).is_err());
+
+        // The same tree as the walk of the disk finds
+        let sftp = Arc::new(sftp);
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new()
+            .index_root(index_root)
+            .file_system(sftp.clone());
+        let tag = &Tag {
+            dir,
+            branch: "main",
+            provider_id: "default",
+        };
+        requests.lock().unwrap().clear();
+        let results = sync(tag, &options).unwrap();
+        assert_eq!(results.compute.len(), 3);
+        let (_other_dir, other_root) = temp_index_root();
+        let disk_options = SyncOptions::new().index_root(other_root);
+        sync(tag, &disk_options).unwrap();
+        assert_eq!(
+            get_root_hash(tag, &options).unwrap(),
+            get_root_hash(tag, &disk_options).unwrap()
+        );
+
+        // Listed files aren't stat'ed, and missing ignore files aren't opened
+        let opened = |requests: &Requests| {
+            let requests = requests.lock().unwrap();
+            let opened = requests.iter().filter(|(kind, _)| *kind == SSH_FXP_OPEN);
+            opened.map(|(_, path)| path.clone()).collect::<Vec<_>>()
+        };
+        let stated = requests.lock().unwrap().iter().any(|(kind, path)| {
+            *kind == SSH_FXP_STAT
+                && path.starts_with(dir)
+                && path != dir
+                && path != &dir.join(".git")
+        });
+        assert!(!stated);
+        let mut first = opened(&requests);
+        first.sort();
+        let expected = [".continueignore", "a.txt", "src/c.rs", "src/large.txt"];
+        assert_eq!(first, expected.map(|path| dir.join(path)));
+
+        // Nothing has changed, so only the ignore file is read again
+        requests.lock().unwrap().clear();
+        assert!(sync(tag, &options).unwrap().compute.is_empty());
+        assert_eq!(opened(&requests), [dir.join(".continueignore")]);
+    }
+}
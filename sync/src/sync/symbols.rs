@@ -0,0 +1,1096 @@
+//! The top-level symbols of source code, and the index of them kept as an `IndexProvider`
+//!
+//! Symbols are found without parsing: `syntax.rs` splits a file into its top-level
+//! definitions, and the first line of each that isn't a comment or an attribute is read for
+//! the keyword it starts with (after modifiers like `pub` or `export`) and the name after it.
+//! C-like languages also have functions with no keyword, named before their `(`, and Python
+//! has variables assigned at the top level. Whether a symbol is exported goes by the language's
+//! own rule: a keyword (`pub`, `export`, `public`), the lack of one (`private`, `static`), or
+//! the name (capitalized in Go, without a leading `_` in Python and Dart).
+//!
+//! `SymbolIndex` keeps the symbols of a provider's files in `providers/<provider_id>/symbols.db`
+//! by content hash, like `SearchIndex` does its documents, so each symbol can be looked up by
+//! name in a tag and found at every path the tag has its file at.
+
+use super::syntax::{self, Language};
+#[cfg(not(target_arch = "wasm32"))]
+use super::{
+    error::Result,
+    merkle::{hash_string, ObjectHash},
+    options::SyncOptions,
+    provider::{self, IndexProvider, PATHS_TABLE},
+    warning::SyncWarning,
+    SyncResults, Tag,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use rusqlite::{Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::Mutex;
+
+/// What a symbol is the name of
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SymbolKind {
+    Function,
+    /// A function defined outside the type it's a method of, as in Go
+    Method,
+    /// A class, struct, union or object
+    Class,
+    /// An interface, trait or protocol
+    Interface,
+    Enum,
+    /// A type alias, or a Go type that's neither a struct nor an interface
+    Type,
+    /// A module or namespace
+    Module,
+    /// A variable or constant
+    Variable,
+    Macro,
+}
+
+impl SymbolKind {
+    #[cfg(not(target_arch = "wasm32"))]
+    const ALL: [SymbolKind; 9] = [
+        SymbolKind::Function,
+        SymbolKind::Method,
+        SymbolKind::Class,
+        SymbolKind::Interface,
+        SymbolKind::Enum,
+        SymbolKind::Type,
+        SymbolKind::Module,
+        SymbolKind::Variable,
+        SymbolKind::Macro,
+    ];
+
+    /// The kind's name, as it's serialized
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SymbolKind::Function => "function",
+            SymbolKind::Method => "method",
+            SymbolKind::Class => "class",
+            SymbolKind::Interface => "interface",
+            SymbolKind::Enum => "enum",
+            SymbolKind::Type => "type",
+            SymbolKind::Module => "module",
+            SymbolKind::Variable => "variable",
+            SymbolKind::Macro => "macro",
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn parse(name: &str) -> Option<Self> {
+        SymbolKind::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.as_str() == name)
+    }
+}
+
+/// A top-level definition in a file
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+
+    /// Whether the language makes the symbol visible outside its file or package
+    pub exported: bool,
+
+    /// Where the definition starts in the file, in bytes, with its attributes or decorators
+    pub start_byte: u64,
+
+    /// Where the definition ends in the file, in bytes, not including this one
+    pub end_byte: u64,
+
+    /// The line the definition starts on, counting from 0
+    pub start_line: u64,
+
+    /// The line the definition ends on, including it
+    pub end_line: u64,
+}
+
+/// How a language says whether a symbol is exported
+enum Visibility {
+    /// Only with this modifier
+    Keyword(&'static str),
+    /// Unless it has one of these modifiers
+    Unless(&'static [&'static str]),
+    /// If its name starts with a capital letter
+    Capitalized,
+    /// Unless its name starts with `_`
+    NoUnderscore,
+}
+
+/// What a language's top-level definitions look like
+struct Rules {
+    /// The keywords a definition starts with, and what each defines
+    keywords: &'static [(&'static str, SymbolKind)],
+
+    /// The words that can come before the keyword
+    modifiers: &'static [&'static str],
+
+    visibility: Visibility,
+
+    /// Whether a definition can be a function named right before its `(`, with no keyword
+    c_functions: bool,
+
+    /// Whether `name = ...` defines a variable
+    assignments: bool,
+}
+
+fn rules(language: Language) -> Rules {
+    use SymbolKind::*;
+    const C_KEYWORDS: &[(&str, SymbolKind)] =
+        &[("struct", Class), ("union", Class), ("enum", Enum)];
+    const JS_KEYWORDS: &[(&str, SymbolKind)] = &[
+        ("function", Function),
+        ("class", Class),
+        ("const", Variable),
+        ("let", Variable),
+        ("var", Variable),
+    ];
+    let c = Rules {
+        keywords: C_KEYWORDS,
+        modifiers: &[
+            "static", "inline", "extern", "const", "volatile", "unsigned", "signed",
+        ],
+        visibility: Visibility::Unless(&["static"]),
+        c_functions: true,
+        assignments: false,
+    };
+    match language {
+        Language::C | Language::ObjectiveC => c,
+        Language::Cpp => Rules {
+            keywords: &[
+                ("class", Class),
+                ("struct", Class),
+                ("union", Class),
+                ("enum", Enum),
+                ("namespace", Module),
+            ],
+            modifiers: &[
+                "static",
+                "inline",
+                "extern",
+                "const",
+                "constexpr",
+                "virtual",
+                "unsigned",
+                "signed",
+            ],
+            ..c
+        },
+        Language::CSharp => Rules {
+            keywords: &[
+                ("class", Class),
+                ("struct", Class),
+                ("record", Class),
+                ("interface", Interface),
+                ("enum", Enum),
+                ("namespace", Module),
+            ],
+            modifiers: &[
+                "public",
+                "private",
+                "protected",
+                "internal",
+                "static",
+                "sealed",
+                "abstract",
+                "partial",
+                "readonly",
+                "unsafe",
+                "file",
+            ],
+            visibility: Visibility::Keyword("public"),
+            c_functions: false,
+            assignments: false,
+        },
+        Language::Rust => Rules {
+            keywords: &[
+                ("fn", Function),
+                ("struct", Class),
+                ("union", Class),
+                ("enum", Enum),
+                ("trait", Interface),
+                ("type", Type),
+                ("mod", Module),
+                ("const", Variable),
+                ("static", Variable),
+                ("macro_rules", Macro),
+            ],
+            modifiers: &["pub", "async", "unsafe", "extern", "default"],
+            visibility: Visibility::Keyword("pub"),
+            c_functions: false,
+            assignments: false,
+        },
+        Language::Go => Rules {
+            keywords: &[
+                ("func", Function),
+                ("type", Type),
+                ("var", Variable),
+                ("const", Variable),
+            ],
+            modifiers: &[],
+            visibility: Visibility::Capitalized,
+            c_functions: false,
+            assignments: false,
+        },
+        Language::Java => Rules {
+            keywords: &[
+                ("class", Class),
+                ("record", Class),
+                ("interface", Interface),
+                ("enum", Enum),
+            ],
+            modifiers: &[
+                "public",
+                "private",
+                "protected",
+                "static",
+                "final",
+                "abstract",
+                "sealed",
+                "strictfp",
+            ],
+            visibility: Visibility::Keyword("public"),
+            c_functions: false,
+            assignments: false,
+        },
+        Language::Kotlin => Rules {
+            keywords: &[
+                ("fun", Function),
+                ("class", Class),
+                ("object", Class),
+                ("interface", Interface),
+                ("enum", Enum),
+                ("typealias", Type),
+                ("val", Variable),
+                ("var", Variable),
+            ],
+            modifiers: &[
+                "public",
+                "private",
+                "protected",
+                "internal",
+                "open",
+                "abstract",
+                "final",
+                "sealed",
+                "data",
+                "inline",
+                "value",
+                "annotation",
+                "suspend",
+                "const",
+                "external",
+            ],
+            visibility: Visibility::Unless(&["private", "internal"]),
+            c_functions: false,
+            assignments: false,
+        },
+        Language::Scala => Rules {
+            keywords: &[
+                ("def", Function),
+                ("class", Class),
+                ("object", Class),
+                ("trait", Interface),
+                ("enum", Enum),
+                ("type", Type),
+                ("val", Variable),
+                ("var", Variable),
+            ],
+            modifiers: &[
+                "case",
+                "abstract",
+                "final",
+                "sealed",
+                "implicit",
+                "lazy",
+                "private",
+                "protected",
+            ],
+            visibility: Visibility::Unless(&["private", "protected"]),
+            c_functions: false,
+            assignments: false,
+        },
+        Language::Swift => Rules {
+            keywords: &[
+                ("func", Function),
+                ("class", Class),
+                ("struct", Class),
+                ("actor", Class),
+                ("protocol", Interface),
+                ("enum", Enum),
+                ("typealias", Type),
+                ("let", Variable),
+                ("var", Variable),
+            ],
+            modifiers: &[
+                "public",
+                "private",
+                "fileprivate",
+                "internal",
+                "open",
+                "final",
+                "static",
+            ],
+            visibility: Visibility::Unless(&["private", "fileprivate"]),
+            c_functions: false,
+            assignments: false,
+        },
+        Language::JavaScript => Rules {
+            keywords: JS_KEYWORDS,
+            modifiers: &["export", "default", "async"],
+            visibility: Visibility::Keyword("export"),
+            c_functions: false,
+            assignments: false,
+        },
+        Language::TypeScript => Rules {
+            keywords: &[
+                ("function", Function),
+                ("class", Class),
+                ("interface", Interface),
+                ("enum", Enum),
+                ("type", Type),
+                ("namespace", Module),
+                ("module", Module),
+                ("const", Variable),
+                ("let", Variable),
+                ("var", Variable),
+            ],
+            modifiers: &["export", "default", "async", "declare", "abstract"],
+            visibility: Visibility::Keyword("export"),
+            c_functions: false,
+            assignments: false,
+        },
+        Language::Php => Rules {
+            keywords: &[
+                ("function", Function),
+                ("class", Class),
+                ("interface", Interface),
+                ("trait", Interface),
+                ("enum", Enum),
+                ("namespace", Module),
+                ("const", Variable),
+            ],
+            modifiers: &["abstract", "final", "readonly"],
+            visibility: Visibility::Unless(&[]),
+            c_functions: false,
+            assignments: false,
+        },
+        Language::Dart => Rules {
+            keywords: &[
+                ("class", Class),
+                ("mixin", Class),
+                ("enum", Enum),
+                ("typedef", Type),
+                ("var", Variable),
+                ("final", Variable),
+                ("const", Variable),
+            ],
+            modifiers: &[
+                "abstract",
+                "sealed",
+                "base",
+                "interface",
+                "external",
+                "late",
+            ],
+            visibility: Visibility::NoUnderscore,
+            c_functions: true,
+            assignments: false,
+        },
+        Language::Python => Rules {
+            keywords: &[("def", Function), ("class", Class)],
+            modifiers: &["async"],
+            visibility: Visibility::NoUnderscore,
+            c_functions: false,
+            assignments: true,
+        },
+    }
+}
+
+/// Words that can come where a name would, without being one
+const CONTROL_WORDS: &[&str] = &[
+    "if", "for", "while", "switch", "return", "sizeof", "catch", "elif", "else", "try", "finally",
+];
+
+/// The words and symbols of a line of code, leaving out strings and a trailing comment
+fn tokens(line: &str, language: Language) -> Vec<&str> {
+    let bytes = line.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = bytes[i];
+        let start = i;
+        i += 1;
+        if byte.is_ascii_alphabetic() || byte == b'_' || byte == b'$' || byte >= 0x80 {
+            while i < bytes.len()
+                && (bytes[i].is_ascii_alphanumeric()
+                    || bytes[i] == b'_'
+                    || bytes[i] == b'$'
+                    || bytes[i] >= 0x80)
+            {
+                i += 1;
+            }
+            tokens.push(&line[start..i]);
+        } else if byte == b'"' || byte == b'`' || (byte == b'\'' && language != Language::Rust) {
+            while i < bytes.len() && bytes[i] != byte {
+                i += if bytes[i] == b'\\' { 2 } else { 1 };
+            }
+            i += 1;
+        } else if line[start..].starts_with("//") || (byte == b'#' && language == Language::Python)
+        {
+            break;
+        } else if !byte.is_ascii_whitespace() {
+            tokens.push(&line[start..i]);
+        }
+    }
+    tokens
+}
+
+fn is_identifier(token: &str) -> bool {
+    token
+        .bytes()
+        .next()
+        .is_some_and(|byte| byte.is_ascii_alphabetic() || byte == b'_' || byte >= 0x80)
+}
+
+/// Whether `line` (trimmed) is blank or a comment
+fn is_comment(line: &str, language: Language) -> bool {
+    line.is_empty()
+        || match language {
+            Language::Python => line.starts_with('#'),
+            _ => line.starts_with("//") || line.starts_with("/*") || line.starts_with('*'),
+        }
+}
+
+/// Whether `line` (trimmed) is an attribute or decorator of the definition after it
+fn is_attribute(line: &str, language: Language) -> bool {
+    match language {
+        Language::Rust => line.starts_with("#[") || line.starts_with("#!["),
+        Language::CSharp => line.starts_with('['),
+        Language::Cpp => line.starts_with("template"),
+        Language::C | Language::ObjectiveC | Language::Go | Language::Php => false,
+        _ => line.starts_with('@'),
+    }
+}
+
+/// The name, kind and whether it's exported of what the definition starting at `header`
+/// defines, if it's a symbol
+fn header_symbol(header: &str, language: Language) -> Option<(String, SymbolKind, bool)> {
+    let rules = rules(language);
+    let tokens = tokens(header, language);
+    let keyword = |token: &str| {
+        rules
+            .keywords
+            .iter()
+            .find(|(keyword, _)| *keyword == token)
+            .map(|&(_, kind)| kind)
+    };
+
+    // Modifiers, each maybe with something in brackets after it, like `pub(crate)`
+    let mut modifiers = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() && rules.modifiers.contains(&tokens[i]) {
+        modifiers.push(tokens[i]);
+        i += 1;
+        if tokens.get(i) == Some(&"(") {
+            i += tokens[i..].iter().position(|&token| token == ")")? + 1;
+        }
+    }
+
+    let (name, kind) = if tokens.first() == Some(&"#") {
+        // Preprocessor macros
+        match (tokens.get(1), tokens.get(2)) {
+            (Some(&"define"), Some(name)) if is_identifier(name) => (*name, SymbolKind::Macro),
+            _ => return None,
+        }
+    } else if let Some(name) = rules
+        .c_functions
+        .then(|| c_function_name(&tokens[i..]))
+        .flatten()
+    {
+        (name, SymbolKind::Function)
+    } else if let Some(mut kind) = tokens.get(i).and_then(|&token| keyword(token)) {
+        // `const fn` is a function, and `enum class` an enum
+        i += 1;
+        while let Some(next) = tokens.get(i).and_then(|&token| keyword(token)) {
+            if kind == SymbolKind::Variable {
+                kind = next;
+            }
+            i += 1;
+        }
+        // Go's methods have their receiver before their name
+        if language == Language::Go && kind == SymbolKind::Function && tokens.get(i) == Some(&"(") {
+            i += tokens[i..].iter().position(|&token| token == ")")? + 1;
+            kind = SymbolKind::Method;
+        }
+        while matches!(tokens.get(i), Some(&"*") | Some(&"!")) {
+            i += 1;
+        }
+        let name = *tokens.get(i).filter(|token| is_identifier(token))?;
+        let rest = &tokens[i + 1..];
+        match (kind, rest.first()) {
+            (SymbolKind::Type, Some(&"struct")) => kind = SymbolKind::Class,
+            (SymbolKind::Type, Some(&"interface")) => kind = SymbolKind::Interface,
+            (SymbolKind::Variable, _)
+                if rest.windows(2).any(|pair| pair == ["=", ">"]) || rest.contains(&"function") =>
+            {
+                kind = SymbolKind::Function
+            }
+            _ => {}
+        }
+        (name, kind)
+    } else if rules.assignments {
+        // `name = ...`, or `name: type = ...`, but not `name == ...`
+        let name = *tokens.first().filter(|token| is_identifier(token))?;
+        match tokens.get(1) {
+            Some(&":") => (name, SymbolKind::Variable),
+            Some(&"=") if tokens.get(2) != Some(&"=") => (name, SymbolKind::Variable),
+            _ => return None,
+        }
+    } else {
+        return None;
+    };
+    if CONTROL_WORDS.contains(&name) || rules.keywords.iter().any(|(keyword, _)| *keyword == name) {
+        return None;
+    }
+
+    let exported = match rules.visibility {
+        Visibility::Keyword(keyword) => modifiers.contains(&keyword),
+        Visibility::Unless(hidden) => !modifiers.iter().any(|modifier| hidden.contains(modifier)),
+        Visibility::Capitalized => name.starts_with(|c: char| c.is_uppercase()),
+        Visibility::NoUnderscore => !name.starts_with('_'),
+    };
+    Some((name.to_string(), kind, exported))
+}
+
+/// The name of the function a C-like definition is, going by the word before its first `(`,
+/// unless something before it says it's not a function
+fn c_function_name<'a>(tokens: &[&'a str]) -> Option<&'a str> {
+    let paren = tokens.iter().position(|&token| token == "(")?;
+    if paren == 0
+        || tokens[..paren]
+            .iter()
+            .any(|&token| token == "=" || token == "{")
+    {
+        return None;
+    }
+    Some(tokens[paren - 1]).filter(|token| is_identifier(token))
+}
+
+/// The top-level symbols of the file at `path` with `contents`, in the order they're defined.
+/// Files in a language `syntax.rs` doesn't know have none
+pub fn extract_symbols(path: &Path, contents: &[u8]) -> Vec<Symbol> {
+    let Some(language) = Language::of(path) else {
+        return Vec::new();
+    };
+    let lines = syntax::line_ranges(contents);
+    let ends = syntax::definition_ends(language, contents);
+    let text = |line: usize| String::from_utf8_lossy(&contents[lines[line].clone()]);
+    let indent = |line: usize| {
+        let text = &contents[lines[line].clone()];
+        text.len() - text.trim_ascii_start().len()
+    };
+
+    let mut symbols = Vec::new();
+    for definition in syntax::definitions(&ends, 0..lines.len(), 0) {
+        // Lines that don't end a statement, such as Go's `package` or JavaScript's without
+        // `;`, leave more than one definition together, so each line as far out as the first
+        // is a header to try. A symbol starts at its attributes and ends where the next starts
+        let mut headers: Vec<(usize, String, SymbolKind, bool)> = Vec::new();
+        let mut outer = None;
+        let mut start = None;
+        for line in definition.clone() {
+            let text = text(line);
+            let text = text.trim();
+            if is_comment(text, language) || indent(line) > *outer.get_or_insert(indent(line)) {
+                continue;
+            }
+            if is_attribute(text, language) {
+                start.get_or_insert(line);
+                continue;
+            }
+            let start = start.take().unwrap_or(line);
+            if let Some((name, kind, exported)) = header_symbol(text, language) {
+                headers.push((start, name, kind, exported));
+            }
+        }
+
+        for (i, (start, name, kind, exported)) in headers.iter().enumerate() {
+            let mut end = headers.get(i + 1).map_or(definition.end, |header| header.0);
+            while end > start + 1 && is_comment(text(end - 1).trim(), language) {
+                end -= 1;
+            }
+            symbols.push(Symbol {
+                name: name.clone(),
+                kind: *kind,
+                exported: *exported,
+                start_byte: lines[*start].start as u64,
+                end_byte: lines[end - 1].end as u64,
+                start_line: *start as u64,
+                end_line: end as u64 - 1,
+            });
+        }
+    }
+    symbols
+}
+
+/// A symbol found in the index, at one of the paths the tag has its file at
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SymbolHit {
+    /// Where the file is, under the tag's directory
+    pub path: String,
+
+    /// The path within the tag's directory, as it's kept in the tree
+    pub relative_path: String,
+
+    /// Hex-encoded content hash of the file
+    pub hash: String,
+
+    #[serde(flatten)]
+    pub symbol: Symbol,
+}
+
+/// `LIKE` pattern matching names that start with `prefix`, with `\` escaping
+#[cfg(not(target_arch = "wasm32"))]
+fn like_prefix(prefix: &str) -> String {
+    let mut pattern = String::with_capacity(prefix.len() + 1);
+    for c in prefix.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    pattern
+}
+
+/// The symbols of one provider's tags
+#[cfg(not(target_arch = "wasm32"))]
+pub struct SymbolIndex {
+    conn: Connection,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl SymbolIndex {
+    /// Open (or create) the symbol index of the provider
+    pub fn open(provider_id: &str, options: &SyncOptions) -> Result<Self> {
+        let index_root = options.resolve_index_root()?;
+        options
+            .resolve_index_file_system()
+            .create_dir_all(&index_root.provider_dir(provider_id))?;
+        let conn = Connection::open(index_root.symbols_db(provider_id))?;
+
+        // Each sync's changes are one transaction, which is only fast without a sync per commit
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        // `extracted` has all the content symbols have been looked for in, since most has none
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS extracted (hash BLOB PRIMARY KEY) WITHOUT ROWID;
+            CREATE TABLE IF NOT EXISTS symbols (
+                hash BLOB NOT NULL,
+                name TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                exported INTEGER NOT NULL,
+                start_byte INTEGER NOT NULL,
+                end_byte INTEGER NOT NULL,
+                start_line INTEGER NOT NULL,
+                end_line INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS symbols_hash ON symbols (hash);
+            CREATE INDEX IF NOT EXISTS symbols_name ON symbols (name);",
+        )?;
+        conn.execute_batch(PATHS_TABLE)?;
+        Ok(SymbolIndex { conn })
+    }
+
+    fn is_indexed(&self, hash: &ObjectHash) -> Result<bool> {
+        Ok(self
+            .conn
+            .prepare_cached("SELECT 1 FROM extracted WHERE hash = ?1")?
+            .query_row((&hash[..],), |_| Ok(()))
+            .optional()?
+            .is_some())
+    }
+
+    /// The symbols of `tag` named exactly `name`, exported ones first
+    pub fn find(&self, tag: &Tag, name: &str) -> Result<Vec<SymbolHit>> {
+        self.query(
+            tag,
+            "symbols.name = ?2",
+            "symbols.exported DESC, paths.relative_path, symbols.start_byte",
+            name,
+            -1,
+        )
+    }
+
+    /// The symbols of `tag` with names starting with `prefix`, ignoring ASCII case, shortest
+    /// names first and at most `limit` of them
+    pub fn find_prefix(&self, tag: &Tag, prefix: &str, limit: usize) -> Result<Vec<SymbolHit>> {
+        self.query(
+            tag,
+            "symbols.name LIKE ?2 ESCAPE '\\'",
+            "length(symbols.name), symbols.name, paths.relative_path, symbols.start_byte",
+            &like_prefix(prefix),
+            limit as i64,
+        )
+    }
+
+    /// The symbols of the file at `relative_path` in `tag`, in the order they're defined
+    pub fn file_symbols(&self, tag: &Tag, relative_path: &str) -> Result<Vec<SymbolHit>> {
+        self.query(
+            tag,
+            "paths.relative_path = ?2",
+            "symbols.start_byte",
+            relative_path,
+            -1,
+        )
+    }
+
+    /// The symbols of `tag` where `condition` holds of `value`, as `?2`, in `order`. A
+    /// negative `limit` is none
+    fn query(
+        &self,
+        tag: &Tag,
+        condition: &str,
+        order: &str,
+        value: &str,
+        limit: i64,
+    ) -> Result<Vec<SymbolHit>> {
+        let tag = tag.canonicalize().to_string();
+        let mut statement = self.conn.prepare_cached(&format!(
+            "SELECT paths.path, paths.relative_path, symbols.hash, symbols.name, symbols.kind,
+                symbols.exported, symbols.start_byte, symbols.end_byte, symbols.start_line,
+                symbols.end_line
+            FROM symbols
+            JOIN paths ON paths.hash = symbols.hash
+            WHERE paths.tag = ?1 AND {condition}
+            ORDER BY {order}
+            LIMIT ?3"
+        ))?;
+        let hits = statement
+            .query_map((tag, value, limit), symbol_hit)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(hits)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn symbol_hit(row: &Row) -> rusqlite::Result<SymbolHit> {
+    let hash: ObjectHash = row.get(2)?;
+    let kind: String = row.get(4)?;
+    Ok(SymbolHit {
+        path: row.get(0)?,
+        relative_path: row.get(1)?,
+        hash: hash_string(hash),
+        symbol: Symbol {
+            name: row.get(3)?,
+            // Only `SymbolIndex` writes kinds, so an unknown one is from a later version
+            kind: SymbolKind::parse(&kind).unwrap_or(SymbolKind::Variable),
+            exported: row.get(5)?,
+            start_byte: row.get(6)?,
+            end_byte: row.get(7)?,
+            start_line: row.get(8)?,
+            end_line: row.get(9)?,
+        },
+    })
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl IndexProvider for SymbolIndex {
+    fn apply(
+        &mut self,
+        tag: &Tag,
+        results: &SyncResults,
+        options: &SyncOptions,
+    ) -> Result<Vec<SyncWarning>> {
+        let canonical = tag.canonicalize();
+        let tag = &canonical.as_tag();
+        let options = &options.for_provider(tag.provider_id);
+        let unindexed = provider::unindexed(results, |hash| self.is_indexed(hash))?;
+        let read = Mutex::new(Vec::new());
+        let warnings = provider::read_contents(tag, &unindexed, options, |item, contents| {
+            let found = extract_symbols(&item.path, contents);
+            read.lock().unwrap().push((item.hash, found));
+        })?;
+
+        let transaction = self.conn.transaction()?;
+        for (hash, symbols) in read.into_inner().unwrap() {
+            transaction
+                .prepare_cached("INSERT OR IGNORE INTO extracted (hash) VALUES (?1)")?
+                .execute((&hash[..],))?;
+            for symbol in symbols {
+                transaction
+                    .prepare_cached(
+                        "INSERT INTO symbols (hash, name, kind, exported, start_byte, end_byte,
+                            start_line, end_line)
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    )?
+                    .execute((
+                        &hash[..],
+                        &symbol.name,
+                        symbol.kind.as_str(),
+                        symbol.exported,
+                        symbol.start_byte,
+                        symbol.end_byte,
+                        symbol.start_line,
+                        symbol.end_line,
+                    ))?;
+            }
+        }
+        for hash in provider::update_paths(&transaction, &tag.to_string(), results)? {
+            transaction
+                .prepare_cached("DELETE FROM symbols WHERE hash = ?1")?
+                .execute((&hash[..],))?;
+            transaction
+                .prepare_cached("DELETE FROM extracted WHERE hash = ?1")?
+                .execute((&hash[..],))?;
+        }
+        transaction.commit()?;
+        Ok(warnings)
+    }
+}
+
+/// The symbols of `tag` named exactly `name`, from the provider's `SymbolIndex`
+#[cfg(not(target_arch = "wasm32"))]
+pub fn find_symbols(tag: &Tag, name: &str, options: &SyncOptions) -> Result<Vec<SymbolHit>> {
+    SymbolIndex::open(tag.provider_id, options)?.find(tag, name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sync::{delete_tag, provider::sync_providers},
+        utils::{temp_index_root, TempDirBuilder},
+    };
+    use std::fs;
+
+    fn symbols(path: &str, contents: &str) -> Vec<(String, SymbolKind, bool)> {
+        extract_symbols(Path::new(path), contents.as_bytes())
+            .into_iter()
+            .map(|symbol| (symbol.name, symbol.kind, symbol.exported))
+            .collect()
+    }
+
+    fn named(symbols: &[(&str, SymbolKind, bool)]) -> Vec<(String, SymbolKind, bool)> {
+        symbols
+            .iter()
+            .map(|&(name, kind, exported)| (name.to_string(), kind, exported))
+            .collect()
+    }
+
+    #[test]
+    fn test_extract_symbols() {
+        use SymbolKind::*;
+        assert!(symbols("notes.txt", "fn a() {}\n").is_empty());
+
+        let rust = "\
+use std::io;
+
+/// Docs
+#[derive(Debug)]
+pub(crate) struct Config {
+    name: String,
+}
+impl Config {
+    fn new() -> Self {}
+}
+pub const fn limit() -> usize { 1 }
+macro_rules! check {
+    () => {};
+}
+static COUNT: usize = 0;
+";
+        assert_eq!(
+            symbols("lib.rs", rust),
+            named(&[
+                ("Config", Class, true),
+                ("limit", Function, true),
+                ("check", Macro, false),
+                ("COUNT", Variable, false)
+            ])
+        );
+        // The range starts at the attributes, after the comments
+        let config = &extract_symbols(Path::new("lib.rs"), rust.as_bytes())[0];
+        assert_eq!((config.start_line, config.end_line), (3, 6));
+        assert_eq!(
+            &rust[config.start_byte as usize..config.end_byte as usize],
+            "#[derive(Debug)]\npub(crate) struct Config {\n    name: String,\n}\n"
+        );
+
+        let python = "\
+import os
+DEBUG = False
+
+@decorator
+class Parser:
+    def parse(self):
+        pass
+
+async def _fetch(url):
+    pass
+if DEBUG == True:
+    pass
+else:
+    pass
+";
+        assert_eq!(
+            symbols("a.py", python),
+            named(&[
+                ("DEBUG", Variable, true),
+                ("Parser", Class, true),
+                ("_fetch", Function, false)
+            ])
+        );
+
+        let typescript = "\
+import { a } from 'a';
+export interface Options {}
+export default class Editor {
+  open() {}
+}
+const helper = (x: number) => x;
+export type Id = string;
+function* items() {}
+";
+        assert_eq!(
+            symbols("a.ts", typescript),
+            named(&[
+                ("Options", Interface, true),
+                ("Editor", Class, true),
+                ("helper", Function, false),
+                ("Id", Type, true),
+                ("items", Function, false)
+            ])
+        );
+
+        let go = "\
+package main
+type Server struct {
+}
+func (s *Server) Start() {
+}
+func main() {
+}
+";
+        assert_eq!(
+            symbols("main.go", go),
+            named(&[
+                ("Server", Class, true),
+                ("Start", Method, true),
+                ("main", Function, false)
+            ])
+        );
+
+        let c = "\
+#include <stdio.h>
+#define MAX 10
+static int count(const char *s) {
+    return 0;
+}
+struct node *make_node(void);
+struct node {
+    int value;
+};
+";
+        assert_eq!(
+            symbols("a.c", c),
+            named(&[
+                ("MAX", Macro, true),
+                ("count", Function, false),
+                ("make_node", Function, true),
+                ("node", Class, true)
+            ])
+        );
+
+        // Definitions that don't end in `;` or `}` are found between those that do
+        let kotlin = "enum class Color { RED }\nval limit = 3\nprivate fun hidden() {}\n";
+        assert_eq!(
+            symbols("a.kt", kotlin),
+            named(&[
+                ("Color", Enum, true),
+                ("limit", Variable, true),
+                ("hidden", Function, false)
+            ])
+        );
+        let limit = &extract_symbols(Path::new("a.kt"), kotlin.as_bytes())[1];
+        assert_eq!((limit.start_line, limit.end_line), (1, 1));
+    }
+
+    #[test]
+    fn test_symbol_index() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.rs", "pub fn parse_config() {}")
+            .add("b.rs", "fn parse() {}\nstruct Parser;")
+            .add("c.txt", "fn parse_config() {}")
+            .create();
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let mut index = SymbolIndex::open("default", &options).unwrap();
+        let names = |hits: Vec<SymbolHit>| -> Vec<(String, String)> {
+            hits.into_iter()
+                .map(|hit| (hit.relative_path, hit.symbol.name))
+                .collect()
+        };
+        let pair = |path: &str, name: &str| (path.to_string(), name.to_string());
+
+        sync_providers(tag, &options, &mut [&mut index]).unwrap();
+        assert_eq!(
+            names(index.find(tag, "parse_config").unwrap()),
+            [pair("a.rs", "parse_config")]
+        );
+        assert_eq!(
+            names(index.find_prefix(tag, "PARSE", 10).unwrap()),
+            [
+                pair("b.rs", "parse"),
+                pair("b.rs", "Parser"),
+                pair("a.rs", "parse_config")
+            ]
+        );
+        assert_eq!(index.find_prefix(tag, "parse", 1).unwrap().len(), 1);
+        // `_` in a prefix is only an underscore
+        assert!(index.find_prefix(tag, "pars_", 10).unwrap().is_empty());
+        let hits = index.file_symbols(tag, "b.rs").unwrap();
+        assert_eq!(
+            names(hits.clone()),
+            [pair("b.rs", "parse"), pair("b.rs", "Parser")]
+        );
+        assert_eq!(hits[1].symbol.kind, SymbolKind::Class);
+        assert_eq!((hits[1].symbol.start_line, hits[1].symbol.end_line), (1, 1));
+
+        // Renamed, changed and deleted files
+        fs::rename(temp_dir.path().join("a.rs"), temp_dir.path().join("d.rs")).unwrap();
+        fs::write(temp_dir.path().join("b.rs"), "fn render() {}\n").unwrap();
+        sync_providers(tag, &options, &mut [&mut index]).unwrap();
+        assert_eq!(
+            names(find_symbols(tag, "parse_config", &options).unwrap()),
+            [pair("d.rs", "parse_config")]
+        );
+        assert!(index.find(tag, "parse").unwrap().is_empty());
+        assert_eq!(
+            names(index.find(tag, "render").unwrap()),
+            [pair("b.rs", "render")]
+        );
+        delete_tag(tag, &options)
+            .and_then(|results| index.apply(tag, &results, &options))
+            .unwrap();
+        assert!(index.find(tag, "render").unwrap().is_empty());
+        let count: i64 = index
+            .conn
+            .query_row("SELECT COUNT(*) FROM symbols", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+}
@@ -0,0 +1,391 @@
+//! Where definitions end in source code, for `ChunkOptions::syntax_aware` and `symbols.rs`
+//!
+//! This isn't a parser: it's a scanner that knows each language's comments, strings and how
+//! its blocks nest, which is all it takes to tell where a function or class ends. Languages
+//! with braces nest by them, and a line that ends a statement or block (with `;` or `}`) ends
+//! a definition at the depth the braces are left at. Python nests by indentation, and a line
+//! ends a definition when the next line of code starts one at the same indentation or less.
+//! Comments and blank lines between definitions go with the one after them, which is what
+//! they usually document.
+
+use std::{ops::Range, path::Path};
+
+/// A language whose definitions we can find, going by the extension of its files
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Language {
+    C,
+    Cpp,
+    CSharp,
+    ObjectiveC,
+    Rust,
+    Go,
+    Java,
+    Kotlin,
+    Scala,
+    Swift,
+    JavaScript,
+    TypeScript,
+    Php,
+    Dart,
+    Python,
+}
+
+impl Language {
+    /// The language of the file at `path`, if it's one we know
+    pub(crate) fn of(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        Some(match extension.as_str() {
+            "c" | "h" => Language::C,
+            "cc" | "cpp" | "cxx" | "hh" | "hpp" | "hxx" => Language::Cpp,
+            "cs" => Language::CSharp,
+            "m" => Language::ObjectiveC,
+            "rs" => Language::Rust,
+            "go" => Language::Go,
+            "java" => Language::Java,
+            "kt" | "kts" => Language::Kotlin,
+            "scala" => Language::Scala,
+            "swift" => Language::Swift,
+            "js" | "jsx" | "mjs" | "cjs" => Language::JavaScript,
+            "ts" | "tsx" | "mts" | "cts" => Language::TypeScript,
+            "php" => Language::Php,
+            "dart" => Language::Dart,
+            "py" | "pyi" => Language::Python,
+            _ => return None,
+        })
+    }
+
+    fn syntax(self) -> Syntax {
+        let braces = |quoted_strings, directives| Syntax::Braces {
+            quoted_strings,
+            directives,
+        };
+        match self {
+            Language::C | Language::Cpp | Language::CSharp | Language::ObjectiveC => {
+                braces(false, true)
+            }
+            Language::Rust
+            | Language::Go
+            | Language::Java
+            | Language::Kotlin
+            | Language::Scala
+            | Language::Swift => braces(false, false),
+            Language::JavaScript | Language::TypeScript | Language::Php | Language::Dart => {
+                braces(true, false)
+            }
+            Language::Python => Syntax::Indentation,
+        }
+    }
+}
+
+/// How a language nests its blocks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Syntax {
+    Braces {
+        /// Whether `'` quotes strings, rather than characters (and Rust's lifetimes)
+        quoted_strings: bool,
+
+        /// Whether a line starting with `#` is a preprocessor directive
+        directives: bool,
+    },
+    Indentation,
+}
+
+/// Where each line of `contents` is in it, with its `\n`
+pub(crate) fn line_ranges(contents: &[u8]) -> Vec<Range<usize>> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for line in contents.split_inclusive(|&byte| byte == b'\n') {
+        lines.push(start..start + line.len());
+        start += line.len();
+    }
+    lines
+}
+
+/// For each line of `contents`, the least depth at which it ends a definition, or `None` for
+/// a line that doesn't end one
+pub(crate) fn definition_ends(language: Language, contents: &[u8]) -> Vec<Option<usize>> {
+    let lines: Vec<&[u8]> = contents.split_inclusive(|&byte| byte == b'\n').collect();
+    match language.syntax() {
+        Syntax::Braces {
+            quoted_strings,
+            directives,
+        } => brace_ends(&lines, quoted_strings, directives),
+        Syntax::Indentation => indentation_ends(&lines),
+    }
+}
+
+/// The definitions in `lines` at `depth`, by the `ends` from `definition_ends`, each with
+/// whatever comes before it. Lines after the last definition are one of their own
+pub(crate) fn definitions(
+    ends: &[Option<usize>],
+    lines: Range<usize>,
+    depth: usize,
+) -> Vec<Range<usize>> {
+    let mut definitions = Vec::new();
+    let mut start = lines.start;
+    for line in lines.clone() {
+        if ends[line].is_some_and(|end| end <= depth) {
+            definitions.push(start..line + 1);
+            start = line + 1;
+        }
+    }
+    if start < lines.end {
+        definitions.push(start..lines.end);
+    }
+    definitions
+}
+
+/// What a line starts inside of, from the lines before it
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Code,
+    BlockComment,
+    String(u8),
+}
+
+fn brace_ends(lines: &[&[u8]], quoted_strings: bool, directives: bool) -> Vec<Option<usize>> {
+    let mut ends = Vec::with_capacity(lines.len());
+    let mut state = State::Code;
+    let mut depth = 0usize;
+    for line in lines {
+        let starts_in_code = state == State::Code;
+        // The last byte of code on the line, outside comments and strings
+        let mut last = None;
+        let mut i = 0;
+        while i < line.len() {
+            let byte = line[i];
+            match state {
+                State::BlockComment => {
+                    if line[i..].starts_with(b"*/") {
+                        state = State::Code;
+                        i += 1;
+                    }
+                }
+                State::String(quote) => {
+                    if byte == b'\\' {
+                        i += 1;
+                    } else if byte == quote {
+                        state = State::Code;
+                        last = Some(byte);
+                    }
+                }
+                State::Code => match byte {
+                    b'/' if line[i..].starts_with(b"//") => break,
+                    b'/' if line[i..].starts_with(b"/*") => {
+                        state = State::BlockComment;
+                        i += 1;
+                    }
+                    b'"' | b'`' => state = State::String(byte),
+                    b'\'' if quoted_strings => state = State::String(byte),
+                    b'\'' => {
+                        // A character, or else a lifetime or label with nothing to skip
+                        let end = match line.get(i + 1) {
+                            Some(b'\\') => line[i + 2..]
+                                .iter()
+                                .take(10)
+                                .position(|&byte| byte == b'\'')
+                                .map(|position| i + 2 + position),
+                            _ => Some(i + 2).filter(|&end| line.get(end) == Some(&b'\'')),
+                        };
+                        if let Some(end) = end {
+                            i = end;
+                        }
+                        last = Some(byte);
+                    }
+                    _ => {
+                        match byte {
+                            b'{' => depth += 1,
+                            b'}' => depth = depth.saturating_sub(1),
+                            _ => {}
+                        }
+                        if !byte.is_ascii_whitespace() {
+                            last = Some(byte);
+                        }
+                    }
+                },
+            }
+            i += 1;
+        }
+
+        let directive = directives
+            && starts_in_code
+            && line.trim_ascii_start().first() == Some(&b'#')
+            && line.trim_ascii_end().last() != Some(&b'\\');
+        let ends_statement = state == State::Code && matches!(last, Some(b';' | b'}'));
+        ends.push((ends_statement || directive).then_some(depth));
+    }
+    ends
+}
+
+fn indentation_ends(lines: &[&[u8]]) -> Vec<Option<usize>> {
+    let mut ends = vec![None; lines.len()];
+    // The indentation of each block the line is in
+    let mut indents: Vec<usize> = vec![0];
+    // The triple quote of the string the line starts in, if it does
+    let mut string: Option<&[u8]> = None;
+    let mut brackets = 0usize;
+    let mut continued = false;
+    // The last line of code, and whether it was a decorator
+    let mut last_code: Option<(usize, bool)> = None;
+    // The depth of the statement the last line of code is in
+    let mut depth = 0;
+    for (index, line) in lines.iter().enumerate() {
+        let text = line.trim_ascii();
+        let starts_statement = string.is_none() && brackets == 0 && !continued;
+        if starts_statement && (text.is_empty() || text[0] == b'#') {
+            continue;
+        }
+
+        if starts_statement {
+            let indent = line.len() - line.trim_ascii_start().len();
+            while indents.len() > 1 && *indents.last().unwrap() > indent {
+                indents.pop();
+            }
+            if *indents.last().unwrap() < indent {
+                indents.push(indent);
+            }
+            // A definition starts here unless it's the one its decorators are for, or the
+            // first thing in the block the last line opened
+            let statement_depth = indents.len() - 1;
+            if let Some((last, false)) = last_code.filter(|_| statement_depth <= depth) {
+                ends[last] = Some(statement_depth);
+            }
+            depth = statement_depth;
+        }
+
+        let mut i = 0;
+        while i < line.len() {
+            if let Some(quote) = string {
+                if line[i..].starts_with(quote) {
+                    string = None;
+                    i += 2;
+                } else if line[i] == b'\\' {
+                    i += 1;
+                }
+            } else {
+                match line[i] {
+                    b'#' => break,
+                    b'(' | b'[' | b'{' => brackets += 1,
+                    b')' | b']' | b'}' => brackets = brackets.saturating_sub(1),
+                    b'"' | b'\'' if line[i..].starts_with(&[line[i]; 3]) => {
+                        string = Some(&line[i..i + 3]);
+                        i += 2;
+                    }
+                    quote @ (b'"' | b'\'') => {
+                        // Short strings end on the line they start on
+                        i += 1;
+                        while i < line.len() && line[i] != quote && line[i] != b'\n' {
+                            if line[i] == b'\\' {
+                                i += 1;
+                            }
+                            i += 1;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            i += 1;
+        }
+        continued = string.is_none() && text.last() == Some(&b'\\');
+        last_code = Some((index, starts_statement && text.starts_with(b"@")));
+    }
+    ends
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ends(path: &str, contents: &str) -> Option<Vec<Option<usize>>> {
+        Language::of(Path::new(path)).map(|language| definition_ends(language, contents.as_bytes()))
+    }
+
+    #[test]
+    fn test_definition_ends() {
+        assert_eq!(ends("notes.txt", "a;\n"), None);
+        assert_eq!(ends("Makefile", "a;\n"), None);
+        assert_eq!(
+            definitions(&ends("lib.rs", "a;\n\nb;\nc\n").unwrap(), 0..4, 0),
+            [0..1, 1..3, 3..4]
+        );
+
+        let rust = "\
+use std::io;
+/// Docs {
+fn a<'a>(x: &'a str) -> char {
+    let s = \"}\"; // }
+    '}'
+}
+impl B {
+    fn c() {}
+}
+";
+        assert_eq!(
+            ends("lib.rs", rust).unwrap(),
+            [
+                Some(0),
+                None,
+                None,
+                Some(1),
+                None,
+                Some(0),
+                None,
+                Some(1),
+                Some(0)
+            ]
+        );
+
+        // `'` quotes strings in JavaScript, and comments can span lines
+        let js = "const a = '{';\n/* {\n} */\nfunction b() {\n}\n";
+        assert_eq!(
+            ends("a.JS", js).unwrap(),
+            [Some(0), None, None, None, Some(0)]
+        );
+
+        // Preprocessor directives end lines of their own
+        assert_eq!(
+            ends("a.c", "#include <a.h>\n#define B \\\n 1\nint c;\n").unwrap(),
+            [Some(0), None, None, Some(0)]
+        );
+
+        let python = "\
+import os
+
+@decorator
+class A:
+    '''Docs
+def not_a_definition():
+    '''
+    def b(self, x=(
+1)):
+        pass
+
+    # Goes with c
+    def c(self):
+        pass
+def d():
+    pass
+";
+        assert_eq!(
+            ends("a.py", python).unwrap(),
+            [
+                Some(0),
+                None,
+                None,
+                None,
+                None,
+                None,
+                Some(1),
+                None,
+                None,
+                Some(1),
+                None,
+                None,
+                None,
+                Some(0),
+                None,
+                None
+            ]
+        );
+    }
+}
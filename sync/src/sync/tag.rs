@@ -0,0 +1,361 @@
+use super::{
+    merkle::{os_path, path_from_bytes, path_to_bytes},
+    win_path,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    fmt, fs,
+    path::{self, Component, Path, PathBuf},
+    str::FromStr,
+};
+
+const SEPARATOR: &str = "::";
+
+/// Percent-escape the characters that could be confused with the separator
+fn escape(field: &str) -> Cow<'_, str> {
+    if !field.contains(['%', ':']) {
+        return Cow::Borrowed(field);
+    }
+    Cow::Owned(field.replace('%', "%25").replace(':', "%3A"))
+}
+
+/// Like `escape`, but bytes of the directory that aren't valid UTF-8 are escaped too, so that
+/// every directory has its own string
+fn escape_dir(dir: &Path) -> Cow<'_, str> {
+    if let Some(dir) = dir.to_str() {
+        return escape(dir);
+    }
+    let mut escaped = String::new();
+    for chunk in path_to_bytes(dir).utf8_chunks() {
+        escaped.push_str(&escape(chunk.valid()));
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Inverse of `escape_dir`
+fn unescape_dir(field: &str) -> Result<PathBuf, ParseTagError> {
+    let mut bytes = Vec::with_capacity(field.len());
+    let mut rest = field.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        rest = after;
+        if byte != b'%' {
+            bytes.push(byte);
+            continue;
+        }
+        let escaped = rest
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| ParseTagError(format!("invalid escape sequence in {field:?}")))?;
+        bytes.push(escaped);
+        rest = &rest[2..];
+    }
+    Ok(path_from_bytes(bytes))
+}
+
+fn unescape(field: &str) -> Result<String, ParseTagError> {
+    let mut result = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        match (chars.next(), chars.next()) {
+            (Some('2'), Some('5')) => result.push('%'),
+            (Some('3'), Some('A')) => result.push(':'),
+            _ => {
+                return Err(ParseTagError(format!(
+                    "invalid escape sequence in {field:?}"
+                )))
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseTagError(String);
+
+impl fmt::Display for ParseTagError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to parse tag: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseTagError {}
+
+/// A (workspace, branch, provider_id) triple that uniquely identifies an index
+///
+/// Formats as `dir::branch::provider_id`, with `%` and `:` in each field percent-escaped
+/// so the string can always be parsed back into an `OwnedTag`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Tag<'a> {
+    #[serde(borrow)]
+    pub dir: &'a Path,
+    pub branch: &'a str,
+    pub provider_id: &'a str,
+}
+
+/// `dir` made absolute, with symlinks, `.` and `..` resolved, so that every way of reaching a
+/// directory leads to the same one. A directory that doesn't exist (any more) can't have its
+/// symlinks resolved, so only its `.` and `..` are, as written
+pub(crate) fn canonical_dir(dir: &Path) -> PathBuf {
+    let canonical = fs::canonicalize(dir).unwrap_or_else(|_| {
+        let absolute = path::absolute(dir).unwrap_or_else(|_| dir.to_path_buf());
+        let mut resolved = PathBuf::new();
+        for component in absolute.components() {
+            match component {
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    resolved.pop();
+                }
+                _ => resolved.push(component),
+            }
+        }
+        resolved
+    });
+    // On Windows, canonicalizing gives the `\\?\` form
+    win_path::normalize(&canonical).into_owned()
+}
+
+impl Tag<'_> {
+    /// The same tag with its directory canonicalized (see `canonical_dir`), which is what every
+    /// function taking a tag indexes it by, so a workspace opened through a symlink or a
+    /// relative path isn't indexed twice
+    pub fn canonicalize(&self) -> OwnedTag {
+        OwnedTag::new(canonical_dir(self.dir), self.branch, self.provider_id)
+    }
+}
+
+impl<'a> fmt::Display for Tag<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Every spelling of a Windows directory is the same tag
+        let dir = win_path::normalize(self.dir);
+        write!(
+            f,
+            "{}{SEPARATOR}{}{SEPARATOR}{}",
+            escape_dir(&dir),
+            escape(self.branch),
+            escape(self.provider_id)
+        )
+    }
+}
+
+/// Owned version of `Tag`, for storing tags in long-lived structures or sending them across threads
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OwnedTag {
+    #[serde(with = "os_path")]
+    pub dir: PathBuf,
+    pub branch: String,
+    pub provider_id: String,
+}
+
+impl OwnedTag {
+    pub fn new(
+        dir: impl Into<PathBuf>,
+        branch: impl Into<String>,
+        provider_id: impl Into<String>,
+    ) -> Self {
+        OwnedTag {
+            dir: dir.into(),
+            branch: branch.into(),
+            provider_id: provider_id.into(),
+        }
+    }
+
+    /// Borrow as a `Tag` to pass to functions like `sync()`
+    pub fn as_tag(&self) -> Tag<'_> {
+        Tag {
+            dir: &self.dir,
+            branch: &self.branch,
+            provider_id: &self.provider_id,
+        }
+    }
+}
+
+impl<'a> From<Tag<'a>> for OwnedTag {
+    fn from(tag: Tag<'a>) -> Self {
+        OwnedTag::new(tag.dir, tag.branch, tag.provider_id)
+    }
+}
+
+impl<'a> From<&Tag<'a>> for OwnedTag {
+    fn from(tag: &Tag<'a>) -> Self {
+        OwnedTag::from(*tag)
+    }
+}
+
+impl<'a> From<&'a OwnedTag> for Tag<'a> {
+    fn from(tag: &'a OwnedTag) -> Self {
+        tag.as_tag()
+    }
+}
+
+impl fmt::Display for OwnedTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_tag().fmt(f)
+    }
+}
+
+impl FromStr for OwnedTag {
+    type Err = ParseTagError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split(SEPARATOR).collect();
+        match fields.as_slice() {
+            [dir, branch, provider_id] => Ok(OwnedTag::new(
+                unescape_dir(dir)?,
+                unescape(branch)?,
+                unescape(provider_id)?,
+            )),
+            _ => Err(ParseTagError(format!(
+                "expected 3 fields separated by {SEPARATOR:?}, found {} in {s:?}",
+                fields.len()
+            ))),
+        }
+    }
+}
+
+/// A tag's string as it's formatted now, from one stored by any version. Tags used to be
+/// formatted without escaping, so `C:\work::main::default` is one from then: a string that
+/// doesn't format back the same when parsed has its fields taken as they are, with the branch
+/// and provider after the last two separators
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn reencode(stored: &str) -> String {
+    if let Ok(tag) = stored.parse::<OwnedTag>() {
+        let formatted = tag.to_string();
+        if formatted == stored {
+            return formatted;
+        }
+    }
+    let mut fields = stored.rsplitn(3, SEPARATOR);
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some(provider_id), Some(branch), Some(dir)) => Tag {
+            dir: Path::new(dir),
+            branch,
+            provider_id,
+        }
+        .to_string(),
+        _ => stored.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDirBuilder;
+
+    #[test]
+    fn test_owned_tag_round_trip() {
+        let dir = PathBuf::from("/path/to/workspace");
+        let tag = Tag {
+            dir: &dir,
+            branch: "main",
+            provider_id: "default",
+        };
+
+        let owned: OwnedTag = tag.into();
+        assert_eq!(owned.dir, dir);
+        assert_eq!(owned.branch, "main");
+        assert_eq!(owned.provider_id, "default");
+        assert_eq!(owned.as_tag(), tag);
+        assert_eq!(owned.to_string(), tag.to_string());
+
+        // The owned tag outlives the data it was built from
+        let owned = {
+            let branch = String::from("feature");
+            OwnedTag::from(&Tag {
+                dir: &dir,
+                branch: &branch,
+                provider_id: "default",
+            })
+        };
+        let borrowed: Tag = (&owned).into();
+        assert_eq!(borrowed.branch, "feature");
+    }
+
+    #[test]
+    fn test_tag_string_round_trip() {
+        let tags = [
+            OwnedTag::new("/path/to/workspace", "main", "default"),
+            OwnedTag::new("/weird::dir/100%", "nate/pyO3", "default"),
+            OwnedTag::new("C:\\Users\\me", "feature:x", "provider::id"),
+            OwnedTag::new("/%3A", "%25", ":"),
+        ];
+
+        for tag in tags {
+            let tag_str = tag.to_string();
+            assert_eq!(tag_str.matches(SEPARATOR).count(), 2);
+            assert_eq!(tag_str.parse::<OwnedTag>(), Ok(tag));
+        }
+
+        // Directories that aren't UTF-8 keep their exact bytes
+        #[cfg(unix)]
+        {
+            use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+            let tag = OwnedTag::new(OsStr::from_bytes(b"/dir/\xff%"), "main", "default");
+            assert_eq!(tag.to_string(), "/dir/%FF%25::main::default");
+            assert_eq!(tag.to_string().parse::<OwnedTag>(), Ok(tag.clone()));
+            let json = serde_json::to_string(&tag).unwrap();
+            assert_eq!(serde_json::from_str::<OwnedTag>(&json).unwrap(), tag);
+        }
+
+        // Plain tags are formatted exactly as before
+        assert_eq!(
+            OwnedTag::new("/path/to/workspace", "main", "default").to_string(),
+            "/path/to/workspace::main::default"
+        );
+    }
+
+    #[test]
+    fn test_tag_json() {
+        let tag = OwnedTag::new("/path/to/workspace", "main", "default");
+        let json = serde_json::to_string(&tag).unwrap();
+        assert_eq!(
+            json,
+            r#"{"dir":"/path/to/workspace","branch":"main","providerId":"default"}"#
+        );
+        assert_eq!(serde_json::from_str::<OwnedTag>(&json).unwrap(), tag);
+
+        let borrowed: Tag = serde_json::from_str(&json).unwrap();
+        assert_eq!(borrowed, tag.as_tag());
+    }
+
+    #[test]
+    fn test_invalid_tag_strings() {
+        assert!("/dir::main".parse::<OwnedTag>().is_err());
+        assert!("/dir::main::default::extra".parse::<OwnedTag>().is_err());
+        assert!("/dir%ZZ::main::default".parse::<OwnedTag>().is_err());
+        assert!("/dir%3::main::default".parse::<OwnedTag>().is_err());
+    }
+
+    #[test]
+    fn test_canonical_dir() {
+        let temp_dir = TempDirBuilder::new().add("workspace/a.txt", "A").create();
+        let root = fs::canonicalize(temp_dir.path()).unwrap();
+        let workspace = root.join("workspace");
+        assert_eq!(
+            canonical_dir(&root.join("workspace/./../workspace")),
+            workspace
+        );
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&workspace, root.join("link")).unwrap();
+            assert_eq!(canonical_dir(&root.join("link")), workspace);
+        }
+
+        // Gone, so resolved as written
+        assert_eq!(
+            canonical_dir(&root.join("gone/../gone/dir")),
+            root.join("gone/dir")
+        );
+        assert!(canonical_dir(Path::new("relative")).is_absolute());
+    }
+}
@@ -0,0 +1,283 @@
+//! Keeping background syncs out of the IDE's way, with `SyncOptions::throttle`
+//!
+//! The read rate is capped by making each thread that reads past it sleep until the bytes
+//! read so far are due, so the cap holds across every thread hashing files, and across every
+//! sync with the same options (or clones of them), like those of a `SyncWatcher`. Low priority
+//! asks the OS to serve the disk reads of the threads doing a sync after everyone else's: the
+//! idle I/O class on Linux (as `ionice -c3` does), the throttled I/O policy on macOS (as
+//! `taskpolicy -d throttle` does) and background mode on Windows. Elsewhere it does nothing.
+
+use std::{
+    cell::Cell,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How `SyncOptions::throttle` slows syncs down
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Throttle {
+    /// The most bytes of files to read a second, or None for no limit
+    pub max_bytes_per_sec: Option<u64>,
+
+    /// How long `sync_in_batches` waits after handing over each batch
+    pub batch_pause: Option<Duration>,
+
+    /// Whether to ask the OS to put the sync's disk reads after everyone else's
+    pub low_priority: bool,
+}
+
+/// Reads at or below this far behind their rate are let through at once, so a sync that's
+/// been idle can catch up with a burst of this long, but no longer
+const MAX_BURST: Duration = Duration::from_secs(1);
+
+/// Caps how fast files are read, for `Throttle::max_bytes_per_sec`
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    max_bytes_per_sec: u64,
+
+    /// When counting started, and how many bytes have been read since
+    state: Mutex<(Instant, u64)>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(max_bytes_per_sec: u64) -> Self {
+        RateLimiter {
+            max_bytes_per_sec: max_bytes_per_sec.max(1),
+            state: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    /// Count `bytes` as read, sleeping until they're due if they're over the rate
+    pub(crate) fn consume(&self, bytes: u64) {
+        let now = Instant::now();
+        let due = {
+            let mut state = self.state.lock().unwrap();
+            let (start, read) = &mut *state;
+            let due =
+                *start + Duration::from_secs_f64(*read as f64 / self.max_bytes_per_sec as f64);
+            if due + MAX_BURST < now {
+                // Idle for a while: start counting again rather than letting it all through
+                *start = now - MAX_BURST;
+                *read = 0;
+            }
+            *read += bytes;
+            *start + Duration::from_secs_f64(*read as f64 / self.max_bytes_per_sec as f64)
+        };
+        if let Some(wait) = due.checked_duration_since(now) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+thread_local! {
+    /// Whether the thread's priority has been lowered by a `LowPriority`
+    static LOWERED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether the current thread is doing a sync with `Throttle::low_priority`, so the threads it
+/// hands work to should be too
+pub(crate) fn is_lowered() -> bool {
+    LOWERED.with(Cell::get)
+}
+
+/// Lowers the I/O priority of the current thread until it's dropped
+pub(crate) struct LowPriority {
+    /// Whether this is what lowered it, rather than one that was already there
+    entered: bool,
+
+    /// What to put back, if the OS let it be changed
+    previous: Option<imp::Priority>,
+}
+
+impl LowPriority {
+    /// Lower the current thread's priority if `low_priority`, unless it already is. Failing to
+    /// is no reason not to sync, so it isn't an error
+    pub(crate) fn enter(low_priority: bool) -> Self {
+        if !low_priority || is_lowered() {
+            return LowPriority {
+                entered: false,
+                previous: None,
+            };
+        }
+        LOWERED.with(|lowered| lowered.set(true));
+        LowPriority {
+            entered: true,
+            previous: imp::lower(),
+        }
+    }
+}
+
+impl Drop for LowPriority {
+    fn drop(&mut self) {
+        if !self.entered {
+            return;
+        }
+        if let Some(previous) = self.previous.take() {
+            imp::restore(previous);
+        }
+        LOWERED.with(|lowered| lowered.set(false));
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use libc::{c_int, c_long};
+
+    const IOPRIO_WHO_PROCESS: c_int = 1;
+    const IOPRIO_CLASS_SHIFT: c_int = 13;
+    const IOPRIO_CLASS_IDLE: c_int = 3;
+
+    pub(super) type Priority = c_long;
+
+    /// Put the thread in the idle I/O class (a `who` of 0 is the calling thread), returning the
+    /// priority it had
+    pub(super) fn lower() -> Option<Priority> {
+        // SAFETY: ioprio_get and ioprio_set only read their integer arguments
+        unsafe {
+            let previous = libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0);
+            if previous < 0 {
+                return None;
+            }
+            let idle = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+            match libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, idle) {
+                0 => Some(previous),
+                _ => None,
+            }
+        }
+    }
+
+    pub(super) fn restore(previous: Priority) {
+        // SAFETY: as above
+        unsafe {
+            libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, previous);
+        }
+    }
+
+    #[cfg(test)]
+    pub(super) fn current() -> Priority {
+        // SAFETY: as above
+        unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, 0) }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod imp {
+    use libc::c_int;
+
+    const IOPOL_TYPE_DISK: c_int = 0;
+    const IOPOL_SCOPE_THREAD: c_int = 1;
+    const IOPOL_THROTTLE: c_int = 3;
+
+    extern "C" {
+        fn getiopolicy_np(iotype: c_int, scope: c_int) -> c_int;
+        fn setiopolicy_np(iotype: c_int, scope: c_int, policy: c_int) -> c_int;
+    }
+
+    pub(super) type Priority = c_int;
+
+    pub(super) fn lower() -> Option<Priority> {
+        // SAFETY: getiopolicy_np and setiopolicy_np only read their integer arguments
+        unsafe {
+            let previous = getiopolicy_np(IOPOL_TYPE_DISK, IOPOL_SCOPE_THREAD);
+            if previous < 0 {
+                return None;
+            }
+            match setiopolicy_np(IOPOL_TYPE_DISK, IOPOL_SCOPE_THREAD, IOPOL_THROTTLE) {
+                0 => Some(previous),
+                _ => None,
+            }
+        }
+    }
+
+    pub(super) fn restore(previous: Priority) {
+        // SAFETY: as above
+        unsafe {
+            setiopolicy_np(IOPOL_TYPE_DISK, IOPOL_SCOPE_THREAD, previous);
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::ffi::c_void;
+
+    const THREAD_MODE_BACKGROUND_BEGIN: i32 = 0x0001_0000;
+    const THREAD_MODE_BACKGROUND_END: i32 = 0x0002_0000;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentThread() -> *mut c_void;
+        fn SetThreadPriority(thread: *mut c_void, priority: i32) -> i32;
+    }
+
+    /// Background mode is on or off, so there's nothing to remember
+    pub(super) type Priority = ();
+
+    pub(super) fn lower() -> Option<Priority> {
+        // SAFETY: GetCurrentThread returns a pseudo-handle that needs no closing
+        match unsafe { SetThreadPriority(GetCurrentThread(), THREAD_MODE_BACKGROUND_BEGIN) } {
+            0 => None,
+            _ => Some(()),
+        }
+    }
+
+    pub(super) fn restore((): Priority) {
+        // SAFETY: as above
+        unsafe {
+            SetThreadPriority(GetCurrentThread(), THREAD_MODE_BACKGROUND_END);
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod imp {
+    pub(super) type Priority = ();
+
+    pub(super) fn lower() -> Option<Priority> {
+        None
+    }
+
+    pub(super) fn restore((): Priority) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(10_000);
+        let start = Instant::now();
+        limiter.consume(500);
+        limiter.consume(1500);
+        // 2000 bytes at 10000 a second take 200ms
+        assert!(start.elapsed() >= Duration::from_millis(190));
+
+        // Being idle doesn't save up more than a second's worth
+        let limiter = RateLimiter::new(1000);
+        *limiter.state.lock().unwrap() = (Instant::now() - Duration::from_secs(60), 0);
+        let start = Instant::now();
+        limiter.consume(1200);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn test_low_priority() {
+        assert!(!is_lowered());
+        let outer = LowPriority::enter(true);
+        assert!(is_lowered());
+        #[cfg(target_os = "linux")]
+        if outer.previous.is_some() {
+            assert_eq!(imp::current() >> 13, 3);
+        }
+
+        // Only the outermost one changes anything
+        drop(LowPriority::enter(true));
+        assert!(is_lowered());
+        drop(outer);
+        assert!(!is_lowered());
+        drop(LowPriority::enter(false));
+        assert!(!is_lowered());
+    }
+}
@@ -0,0 +1,411 @@
+//! Reading files through io_uring, with the `io_uring` feature on Linux
+//!
+//! Each hashing thread keeps one small ring. While the hasher works through one chunk of a file,
+//! the read of the next chunk is already queued with the kernel, so reading and hashing overlap
+//! instead of taking turns. Only the few parts of io_uring that this needs are here: one read
+//! in flight at a time, waited for with `io_uring_enter`.
+
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::{self, Read},
+    os::unix::io::{AsRawFd, RawFd},
+    ptr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+const ENTRIES: u32 = 4;
+const CHUNK_SIZE: usize = 256 * 1024;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+const IORING_OP_READ: u8 = 22;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+#[repr(C)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    pad: [u64; 3],
+}
+
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// A memory mapping of part of the ring, unmapped on drop
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.ptr.add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+struct Ring {
+    fd: RawFd,
+    params: Params,
+    sq: Mapping,
+    cq: Mapping,
+    sqes: Mapping,
+}
+
+impl Ring {
+    fn new() -> io::Result<Self> {
+        let mut params = Params::default();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                ENTRIES,
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = fd as RawFd;
+
+        let mappings = (|| {
+            let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+            let cq_len = params.cq_off.cqes as usize
+                + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+            let sqes_len = params.sq_entries as usize * std::mem::size_of::<Sqe>();
+            Ok::<_, io::Error>((
+                Mapping::new(fd, sq_len, IORING_OFF_SQ_RING)?,
+                Mapping::new(fd, cq_len, IORING_OFF_CQ_RING)?,
+                Mapping::new(fd, sqes_len, IORING_OFF_SQES)?,
+            ))
+        })();
+        match mappings {
+            Ok((sq, cq, sqes)) => Ok(Ring {
+                fd,
+                params,
+                sq,
+                cq,
+                sqes,
+            }),
+            Err(err) => {
+                unsafe { libc::close(fd) };
+                Err(err)
+            }
+        }
+    }
+
+    fn atomic(mapping: &Mapping, offset: u32) -> &AtomicU32 {
+        unsafe { &*mapping.at::<AtomicU32>(offset) }
+    }
+
+    /// Queue a read of `len` bytes at `offset` into `buffer`, which must stay alive (and not
+    /// be touched) until `wait` returns
+    fn submit_read(
+        &mut self,
+        fd: RawFd,
+        buffer: *mut u8,
+        len: usize,
+        offset: u64,
+    ) -> io::Result<()> {
+        let off = &self.params.sq_off;
+        let mask = unsafe { *self.sq.at::<u32>(off.ring_mask) };
+        let tail = Self::atomic(&self.sq, off.tail).load(Ordering::Acquire);
+        let index = tail & mask;
+        unsafe {
+            self.sqes
+                .at::<Sqe>(index * std::mem::size_of::<Sqe>() as u32)
+                .write(Sqe {
+                    opcode: IORING_OP_READ,
+                    flags: 0,
+                    ioprio: 0,
+                    fd,
+                    off: offset,
+                    addr: buffer as u64,
+                    len: len as u32,
+                    rw_flags: 0,
+                    user_data: 0,
+                    pad: [0; 3],
+                });
+            *self.sq.at::<u32>(off.array + index * 4) = index;
+        }
+        Self::atomic(&self.sq, off.tail).store(tail.wrapping_add(1), Ordering::Release);
+
+        self.enter(1, 0)
+    }
+
+    /// Wait for the read in flight to finish, returning how many bytes it read
+    fn wait(&mut self) -> io::Result<usize> {
+        loop {
+            let off = &self.params.cq_off;
+            let head = Self::atomic(&self.cq, off.head).load(Ordering::Acquire);
+            let tail = Self::atomic(&self.cq, off.tail).load(Ordering::Acquire);
+            if head != tail {
+                let mask = unsafe { *self.cq.at::<u32>(off.ring_mask) };
+                let offset = off.cqes + (head & mask) * std::mem::size_of::<Cqe>() as u32;
+                let res = unsafe { self.cq.at::<Cqe>(offset).read().res };
+                Self::atomic(&self.cq, off.head).store(head.wrapping_add(1), Ordering::Release);
+                return match res {
+                    res if res < 0 => Err(io::Error::from_raw_os_error(-res)),
+                    res => Ok(res as usize),
+                };
+            }
+            self.enter(0, 1)?;
+        }
+    }
+
+    fn enter(&self, to_submit: u32, min_complete: u32) -> io::Result<()> {
+        loop {
+            let result = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd,
+                    to_submit,
+                    min_complete,
+                    if min_complete > 0 {
+                        IORING_ENTER_GETEVENTS
+                    } else {
+                        0
+                    },
+                    ptr::null::<libc::sigset_t>(),
+                    0usize,
+                )
+            };
+            if result >= 0 {
+                return Ok(());
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+thread_local! {
+    /// None once setting up a ring has failed on this thread, e.g. because the kernel is too old
+    /// or io_uring is blocked, so it isn't tried for every file
+    static RING: RefCell<Option<Option<Ring>>> = const { RefCell::new(None) };
+}
+
+/// Reads a file a chunk at a time, always with the next chunk's read already queued
+struct UringReader<'a> {
+    ring: &'a mut Ring,
+    fd: RawFd,
+    buffer: Vec<u8>,
+
+    /// The unread part of `buffer`
+    start: usize,
+    end: usize,
+
+    /// Where the next read starts
+    offset: u64,
+    in_flight: bool,
+}
+
+impl UringReader<'_> {
+    fn submit(&mut self) -> io::Result<()> {
+        let buffer = self.buffer.as_mut_ptr();
+        self.ring
+            .submit_read(self.fd, buffer, self.buffer.len(), self.offset)?;
+        self.in_flight = true;
+        Ok(())
+    }
+}
+
+impl Read for UringReader<'_> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.start == self.end {
+            if !self.in_flight {
+                return Ok(0);
+            }
+            let read = self.ring.wait();
+            self.in_flight = false;
+            let read = read?;
+            if read == 0 {
+                return Ok(0);
+            }
+            self.start = 0;
+            self.end = read;
+            self.offset += read as u64;
+        }
+
+        let len = out.len().min(self.end - self.start);
+        out[..len].copy_from_slice(&self.buffer[self.start..self.start + len]);
+        self.start += len;
+
+        // Start reading the next chunk while the caller deals with this one
+        if self.start == self.end {
+            self.submit()?;
+        }
+        Ok(len)
+    }
+}
+
+impl Drop for UringReader<'_> {
+    fn drop(&mut self) {
+        // The kernel may still be writing into the buffer
+        if self.in_flight {
+            let _ = self.ring.wait();
+        }
+    }
+}
+
+/// Run `f` with a reader of the whole file that reads through this thread's ring, or return
+/// None if io_uring isn't available here
+pub(crate) fn with_reader<T>(
+    file: &File,
+    f: impl FnOnce(&mut dyn Read) -> io::Result<T>,
+) -> io::Result<Option<T>> {
+    RING.with(|ring| {
+        let mut ring = ring.borrow_mut();
+        let ring = match ring.get_or_insert_with(|| Ring::new().ok()) {
+            Some(ring) => ring,
+            None => return Ok(None),
+        };
+
+        let mut reader = UringReader {
+            ring,
+            fd: file.as_raw_fd(),
+            buffer: vec![0; CHUNK_SIZE],
+            start: 0,
+            end: 0,
+            offset: 0,
+            in_flight: false,
+        };
+        reader.submit()?;
+        f(&mut reader).map(Some)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TempDirBuilder;
+
+    #[test]
+    fn test_uring_reader() {
+        let temp_dir = TempDirBuilder::new().create();
+        let path = temp_dir.path().join("a.txt");
+        // Several chunks, the last one partly full
+        let contents: Vec<u8> = (0..CHUNK_SIZE * 3 + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        std::fs::write(&path, &contents).unwrap();
+
+        let file = File::open(&path).unwrap();
+        let read = with_reader(&file, |reader| {
+            let mut read = Vec::new();
+            // Smaller than a chunk, so chunks are handed out in pieces
+            let mut buffer = vec![0; 1000];
+            loop {
+                match reader.read(&mut buffer)? {
+                    0 => return Ok(read),
+                    len => read.extend_from_slice(&buffer[..len]),
+                }
+            }
+        })
+        .unwrap();
+        match read {
+            Some(read) => assert!(read == contents, "read back different contents"),
+            None => eprintln!("io_uring isn't available, so only the fallback was tested"),
+        }
+
+        // Stopping early still waits for the read in flight
+        let file = File::open(&path).unwrap();
+        let first = with_reader(&file, |reader| {
+            let mut buffer = [0; 10];
+            reader.read_exact(&mut buffer)?;
+            Ok(buffer)
+        })
+        .unwrap();
+        if let Some(first) = first {
+            assert_eq!(first[..], contents[..10]);
+        }
+    }
+}
@@ -0,0 +1,485 @@
+//! Checking that a tag's saved tree, its cache, the provider's global cache and rev_tags agree
+//! with each other, without changing any of them
+
+#[cfg(not(target_arch = "wasm32"))]
+use super::index_db::IndexDb;
+use super::{
+    error::{Result, SyncError},
+    index_root::IndexRoot,
+    journal::JournalEntry,
+    load_saved_tree_or_warn,
+    merkle::{hash_string, ObjectHash},
+    options::{IndexStorage, SyncOptions},
+    read_pending,
+    rev_tags::RevTags,
+    DiskSet, IndexCache, OwnedTag, SyncResultItem, SyncWarning, Tag,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    io::ErrorKind,
+    path::Path,
+};
+
+/// Something `verify_index` found that doesn't agree with the rest of the index. Hashes are hex
+/// strings, as in `SyncResultItem`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(
+    tag = "kind",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Inconsistency {
+    /// The tag's saved tree couldn't be read, so the caches were checked against an empty one
+    CorruptedTree { message: String },
+
+    /// A cache or `.pending` file couldn't be read, so it was checked as if it were empty
+    CorruptedCache { message: String },
+
+    /// A bulk change to the caches was interrupted, and is finished the next time they're
+    /// opened. Until then, the hashes it touched may be reported here too
+    InterruptedChange,
+
+    /// A file in the saved tree whose content is neither in the tag's cache nor waiting for
+    /// `mark_computed`
+    NotInTagCache { file: SyncResultItem },
+
+    /// In the tag's cache, but no file in the saved tree has it
+    NotInTree { hash: String },
+
+    /// In the tag's cache or referenced in rev_tags, but not in the provider's global cache
+    NotInGlobalCache { hash: String },
+
+    /// rev_tags should reference the hash from the tag once for every file in the saved tree
+    /// with it (that isn't waiting for `mark_computed`)
+    WrongReferenceCount {
+        hash: String,
+        references: usize,
+        files: usize,
+    },
+
+    /// In the global cache, but no tag references it in rev_tags
+    Unreferenced { hash: String },
+
+    /// rev_tags references the hash from a tag that isn't indexed
+    UnknownTag { hash: String, tag: String },
+
+    /// The refcount kept for the hash isn't how many references rev_tags has to it, so removing
+    /// its files could delete it while it's still used, or never delete it
+    WrongRefcount {
+        hash: String,
+        refcount: u64,
+        references: usize,
+    },
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::CorruptedTree { message } => {
+                write!(f, "Saved tree couldn't be read: {message}")
+            }
+            Inconsistency::CorruptedCache { message } => {
+                write!(f, "Cache couldn't be read: {message}")
+            }
+            Inconsistency::InterruptedChange => write!(f, "A change to the caches was interrupted"),
+            Inconsistency::NotInTagCache { file } => {
+                write!(f, "{} ({}) isn't in the tag's cache", file.path, file.hash)
+            }
+            Inconsistency::NotInTree { hash } => {
+                write!(f, "{hash} is in the tag's cache but not its tree")
+            }
+            Inconsistency::NotInGlobalCache { hash } => {
+                write!(f, "{hash} isn't in the global cache")
+            }
+            Inconsistency::WrongReferenceCount {
+                hash,
+                references,
+                files,
+            } => write!(
+                f,
+                "{hash} is referenced {references} times in rev_tags, but is in {files} files"
+            ),
+            Inconsistency::Unreferenced { hash } => {
+                write!(f, "{hash} is in the global cache but no tag references it")
+            }
+            Inconsistency::UnknownTag { hash, tag } => {
+                write!(f, "{hash} is referenced by {tag}, which isn't indexed")
+            }
+            Inconsistency::WrongRefcount {
+                hash,
+                refcount,
+                references,
+            } => write!(
+                f,
+                "{hash} has a refcount of {refcount}, but {references} references in rev_tags"
+            ),
+        }
+    }
+}
+
+/// Everything `verify_index` found, in the order it was checked. Empty if the index is
+/// consistent
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VerifyReport {
+    pub inconsistencies: Vec<Inconsistency>,
+}
+
+impl VerifyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.inconsistencies.is_empty()
+    }
+}
+
+/// The index's state as `verify_index` reads it
+struct Caches {
+    tag_cache: Vec<ObjectHash>,
+    global_cache: Vec<ObjectHash>,
+
+    /// Every reference from any of the provider's tags
+    rev_tags: Vec<(ObjectHash, String)>,
+
+    /// How many references each hash is counted as having
+    refcounts: Vec<(ObjectHash, u64)>,
+}
+
+/// Check the tag's saved tree and caches against each other, and the provider's global cache
+/// against rev_tags. Nothing is changed, including to finish an interrupted change or rebuild a
+/// corrupted cache, which opening the caches to sync would do
+pub fn verify_index(tag: &Tag, options: &SyncOptions) -> Result<VerifyReport> {
+    let canonical = tag.canonicalize();
+    let tag = &canonical.as_tag();
+    let index_root = options.resolve_index_root()?;
+    let file_system = &*options.resolve_index_file_system();
+    let mut report = VerifyReport::default();
+    let found = &mut report.inconsistencies;
+
+    let mut warnings = Vec::new();
+    let tree = load_saved_tree_or_warn(file_system, tag, &index_root, &mut warnings)?;
+    for warning in warnings {
+        if let SyncWarning::CorruptedTree { message } = warning {
+            found.push(Inconsistency::CorruptedTree { message });
+        }
+    }
+    let pending: HashSet<ObjectHash> = match read_pending(file_system, tag, &index_root) {
+        Ok(pending) => pending.iter().map(|item| item.hash).collect(),
+        Err(SyncError::CorruptedIndex(message)) => {
+            found.push(Inconsistency::CorruptedCache { message });
+            HashSet::new()
+        }
+        Err(err) => return Err(err),
+    };
+    let caches = match options.storage {
+        IndexStorage::Files => read_files(tag, &index_root, options, found)?,
+        #[cfg(not(target_arch = "wasm32"))]
+        IndexStorage::Sqlite => read_db(tag, &index_root)?,
+    };
+
+    let tag_str = tag.to_string();
+    let tag_cache: HashSet<ObjectHash> = caches.tag_cache.iter().copied().collect();
+    let global_cache: HashSet<ObjectHash> = caches.global_cache.iter().copied().collect();
+    let mut references: HashMap<ObjectHash, usize> = HashMap::new();
+    let mut all_references: HashMap<ObjectHash, usize> = HashMap::new();
+    for (hash, rev_tag) in &caches.rev_tags {
+        *all_references.entry(*hash).or_default() += 1;
+        if *rev_tag == tag_str {
+            *references.entry(*hash).or_default() += 1;
+        }
+    }
+    let referenced: HashSet<ObjectHash> = all_references.keys().copied().collect();
+
+    // The tree against the tag's cache and rev_tags
+    let mut files: HashMap<ObjectHash, usize> = HashMap::new();
+    for item in tree.all_obj_descriptions() {
+        if !item.is_blob || pending.contains(&item.hash) {
+            continue;
+        }
+        *files.entry(item.hash).or_default() += 1;
+        if !tag_cache.contains(&item.hash) {
+            found.push(Inconsistency::NotInTagCache {
+                file: SyncResultItem::new(tag.dir, &item),
+            });
+        }
+    }
+    for hash in &caches.tag_cache {
+        if !files.contains_key(hash) {
+            found.push(Inconsistency::NotInTree {
+                hash: hash_string(*hash),
+            });
+        }
+    }
+    let mut counted: Vec<ObjectHash> = files.keys().chain(references.keys()).copied().collect();
+    counted.sort_unstable();
+    counted.dedup();
+    for hash in counted {
+        let (references, files) = (
+            references.get(&hash).copied().unwrap_or(0),
+            files.get(&hash).copied().unwrap_or(0),
+        );
+        if references != files {
+            found.push(Inconsistency::WrongReferenceCount {
+                hash: hash_string(hash),
+                references,
+                files,
+            });
+        }
+    }
+
+    // The global cache against every tag's references
+    let mut in_global: Vec<ObjectHash> = tag_cache.union(&referenced).copied().collect();
+    in_global.sort_unstable();
+    for hash in in_global {
+        if !global_cache.contains(&hash) {
+            found.push(Inconsistency::NotInGlobalCache {
+                hash: hash_string(hash),
+            });
+        }
+    }
+    for hash in &caches.global_cache {
+        if !referenced.contains(hash) {
+            found.push(Inconsistency::Unreferenced {
+                hash: hash_string(*hash),
+            });
+        }
+    }
+
+    // The refcounts against every tag's references
+    let refcounts: HashMap<ObjectHash, u64> = caches.refcounts.iter().copied().collect();
+    let mut counted: Vec<ObjectHash> = refcounts
+        .keys()
+        .chain(all_references.keys())
+        .copied()
+        .collect();
+    counted.sort_unstable();
+    counted.dedup();
+    for hash in counted {
+        let (refcount, references) = (
+            refcounts.get(&hash).copied().unwrap_or(0),
+            all_references.get(&hash).copied().unwrap_or(0),
+        );
+        if refcount != references as u64 {
+            found.push(Inconsistency::WrongRefcount {
+                hash: hash_string(hash),
+                refcount,
+                references,
+            });
+        }
+    }
+
+    let mut known_tags = HashMap::new();
+    for (hash, rev_tag) in &caches.rev_tags {
+        let known =
+            *known_tags
+                .entry(rev_tag)
+                .or_insert_with(|| match rev_tag.parse::<OwnedTag>() {
+                    Ok(other) => file_system
+                        .metadata(&index_root.tag_dir(&other.as_tag()))
+                        .is_ok(),
+                    Err(_) => false,
+                });
+        if !known {
+            found.push(Inconsistency::UnknownTag {
+                hash: hash_string(*hash),
+                tag: rev_tag.clone(),
+            });
+        }
+    }
+    Ok(report)
+}
+
+fn read_files(
+    tag: &Tag,
+    index_root: &IndexRoot,
+    options: &SyncOptions,
+    found: &mut Vec<Inconsistency>,
+) -> Result<Caches> {
+    let file_system = &*options.resolve_index_file_system();
+    if JournalEntry::read(file_system, &index_root.journal(tag.provider_id))?.is_some() {
+        found.push(Inconsistency::InterruptedChange);
+    }
+    let mut read_cache = |path: &Path| match DiskSet::read(file_system, path) {
+        Ok(hashes) => Ok(hashes),
+        Err(SyncError::Io(err)) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(SyncError::CorruptedIndex(message)) => {
+            found.push(Inconsistency::CorruptedCache { message });
+            Ok(Vec::new())
+        }
+        Err(err) => Err(err),
+    };
+    let tag_cache = read_cache(&IndexCache::index_cache_path_for_tag(tag, index_root))?;
+    let global_cache = read_cache(
+        &index_root
+            .provider_dir(tag.provider_id)
+            .join(".index_cache"),
+    )?;
+    let (rev_tags, refcounts) = match RevTags::open_existing(index_root, tag.provider_id, options)?
+    {
+        Some(rev_tags) => (rev_tags.all()?, rev_tags.all_refcounts()?),
+        None => (Vec::new(), Vec::new()),
+    };
+    Ok(Caches {
+        tag_cache,
+        global_cache,
+        rev_tags,
+        refcounts,
+    })
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn read_db(tag: &Tag, index_root: &IndexRoot) -> Result<Caches> {
+    if !index_root.index_db().exists() {
+        return Ok(Caches {
+            tag_cache: Vec::new(),
+            global_cache: Vec::new(),
+            rev_tags: Vec::new(),
+            refcounts: Vec::new(),
+        });
+    }
+    let index_db = IndexDb::open(index_root)?;
+    Ok(Caches {
+        tag_cache: index_db.tag_hashes(&tag.to_string())?,
+        global_cache: index_db.global_hashes(tag.provider_id)?,
+        rev_tags: index_db.all_rev_tags(tag.provider_id)?,
+        refcounts: index_db.all_refcounts(tag.provider_id)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sync::{
+            list_indexed_files, mark_computed, merkle::parse_hash, sync, OsFileSystem, ITEM_SIZE,
+        },
+        utils::{temp_index_root, TempDirBuilder},
+    };
+    use std::{fs, sync::Arc};
+
+    #[test]
+    fn test_verify_index() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("b.txt", "B")
+            .add("copy.txt", "A")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        for storage in [IndexStorage::Files, IndexStorage::Sqlite] {
+            let (_index_dir, index_root) = temp_index_root();
+            let options = SyncOptions::new().index_root(index_root).storage(storage);
+            assert!(verify_index(tag, &options).unwrap().is_consistent());
+            sync(tag, &options).unwrap();
+            assert_eq!(
+                verify_index(tag, &options).unwrap(),
+                VerifyReport::default()
+            );
+        }
+
+        // Files waiting for `mark_computed` aren't expected in the caches yet
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root.clone());
+        sync(tag, &options).unwrap();
+        fs::write(temp_dir.path().join("c.txt"), "C").unwrap();
+        sync(tag, &options.clone().defer_compute(true)).unwrap();
+        assert!(verify_index(tag, &options).unwrap().is_consistent());
+        let c_file = list_indexed_files(tag, &options)
+            .unwrap()
+            .into_iter()
+            .find(|file| file.relative_path == "c.txt")
+            .unwrap();
+        let c_hash = parse_hash(&c_file.hash).unwrap();
+        mark_computed(tag, &[c_hash], &options).unwrap();
+        assert!(verify_index(tag, &options).unwrap().is_consistent());
+
+        // Drift between the caches, made behind their backs
+        let other_tag = Tag {
+            dir: Path::new("/nowhere"),
+            ..*tag
+        }
+        .to_string();
+        let (unknown, unreferenced, stray) = ([7; ITEM_SIZE], [8; ITEM_SIZE], [9; ITEM_SIZE]);
+        let mut tag_cache = DiskSet::new(
+            Arc::new(OsFileSystem),
+            IndexCache::index_cache_path_for_tag(tag, &index_root),
+        )
+        .unwrap();
+        tag_cache.remove(&c_hash).unwrap();
+        tag_cache.add(&stray).unwrap();
+        drop(tag_cache);
+        let global_path = index_root.provider_dir("default").join(".index_cache");
+        let mut global_cache =
+            DiskSet::with_bloom_filter(Arc::new(OsFileSystem), global_path).unwrap();
+        global_cache.add(&unreferenced).unwrap();
+        drop(global_cache);
+        RevTags::open_sqlite(&index_root, "default")
+            .unwrap()
+            .add(&unknown, &other_tag)
+            .unwrap();
+
+        let report = verify_index(tag, &options).unwrap();
+        assert!(!report.is_consistent());
+        assert_eq!(
+            report.inconsistencies,
+            [
+                Inconsistency::NotInTagCache { file: c_file },
+                Inconsistency::NotInTree {
+                    hash: hash_string(stray)
+                },
+                Inconsistency::NotInGlobalCache {
+                    hash: hash_string(unknown)
+                },
+                Inconsistency::NotInGlobalCache {
+                    hash: hash_string(stray)
+                },
+                Inconsistency::Unreferenced {
+                    hash: hash_string(unreferenced)
+                },
+                Inconsistency::UnknownTag {
+                    hash: hash_string(unknown),
+                    tag: other_tag,
+                },
+            ]
+        );
+
+        // As is a refcount that's drifted from the references
+        let b_hash = parse_hash(
+            &list_indexed_files(tag, &options)
+                .unwrap()
+                .into_iter()
+                .find(|file| file.relative_path == "b.txt")
+                .unwrap()
+                .hash,
+        )
+        .unwrap();
+        rusqlite::Connection::open(index_root.rev_tags_db("default"))
+            .unwrap()
+            .execute(
+                "UPDATE refcounts SET count = 5 WHERE hash = ?1",
+                (&b_hash[..],),
+            )
+            .unwrap();
+        let report = verify_index(tag, &options).unwrap();
+        assert!(report
+            .inconsistencies
+            .contains(&Inconsistency::WrongRefcount {
+                hash: hash_string(b_hash),
+                refcount: 5,
+                references: 1,
+            }));
+
+        // Reported rather than repaired
+        let tag_cache_path = IndexCache::index_cache_path_for_tag(tag, &index_root);
+        let contents = fs::read(&tag_cache_path).unwrap();
+        fs::write(&tag_cache_path, &contents[..contents.len() - 1]).unwrap();
+        let report = verify_index(tag, &options).unwrap();
+        assert!(matches!(
+            report.inconsistencies[0],
+            Inconsistency::CorruptedCache { .. }
+        ));
+        assert_eq!(fs::read(&tag_cache_path).unwrap().len(), contents.len() - 1);
+    }
+}
@@ -0,0 +1,132 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A problem that didn't stop the sync, but that the user may want to know about
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum SyncWarning {
+    /// The file couldn't be read, so it was left out of the tree
+    UnreadableFile { path: String, message: String },
+
+    /// The directory couldn't be listed (usually for lack of permission), so it was left out
+    /// of the tree along with everything in it
+    UnreadableDir { path: String, message: String },
+
+    /// The file is bigger than `SyncOptions::max_file_size`, so it was left out of the tree
+    FileTooLarge { path: String, size: u64 },
+
+    /// The file was deleted or written to while the sync was reading it, or files were added
+    /// to or removed from the directory after the sync started, so the results may not match
+    /// what's there now. A deleted file was left out of the tree, and a changed one is hashed
+    /// again by the next sync
+    ChangedDuringSync { path: String },
+
+    /// The tree saved by the last sync couldn't be loaded, so every file was treated as new
+    CorruptedTree { message: String },
+
+    /// There's no home directory and no index root was given, so the index is kept in a
+    /// temporary directory that may not outlive the process's machine or container
+    NoHomeDir { index_root: String },
+
+    /// The entry is a symlink, which is left out of the tree unless
+    /// `SyncOptions::follow_symlinks` is set
+    UnfollowedSymlink { path: String },
+
+    /// The symlink leads to a directory that's already in the tree, so following it would go
+    /// round in circles or index the same files twice. It was left out of the tree
+    SymlinkLoop { path: String },
+
+    /// The file is binary, going by its extension or the start of its content (see
+    /// `SyncOptions::binary_extension`), so it was left out of the tree
+    BinaryFile { path: String },
+
+    /// The index is read-only or its disk is full, so nothing was saved and the caches weren't
+    /// consulted (see `SyncOptions::read_only_fallback`). The same changes are reported again
+    /// once a sync can save them
+    IndexNotWritable { index_root: String, message: String },
+}
+
+impl SyncWarning {
+    /// Whether the warning is for a file or directory that was left out because it couldn't
+    /// be read
+    pub fn is_unreadable(&self) -> bool {
+        matches!(
+            self,
+            SyncWarning::UnreadableFile { .. } | SyncWarning::UnreadableDir { .. }
+        )
+    }
+}
+
+impl fmt::Display for SyncWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncWarning::UnreadableFile { path, message } => {
+                write!(f, "Skipped unreadable file {path}: {message}")
+            }
+            SyncWarning::UnreadableDir { path, message } => {
+                write!(f, "Skipped unreadable directory {path}: {message}")
+            }
+            SyncWarning::FileTooLarge { path, size } => {
+                write!(
+                    f,
+                    "Skipped {path}, which is too large to index ({size} bytes)"
+                )
+            }
+            SyncWarning::ChangedDuringSync { path } => {
+                write!(f, "{path} changed while it was being synced")
+            }
+            SyncWarning::CorruptedTree { message } => {
+                write!(
+                    f,
+                    "Saved tree was corrupted and has been rebuilt: {message}"
+                )
+            }
+            SyncWarning::NoHomeDir { index_root } => {
+                write!(
+                    f,
+                    "No home directory, so the index is kept in {index_root} for now"
+                )
+            }
+            SyncWarning::UnfollowedSymlink { path } => {
+                write!(f, "Skipped symlink {path}")
+            }
+            SyncWarning::SymlinkLoop { path } => {
+                write!(
+                    f,
+                    "Skipped symlink {path}, which leads to a directory already in the tree"
+                )
+            }
+            SyncWarning::BinaryFile { path } => {
+                write!(f, "Skipped binary file {path}")
+            }
+            SyncWarning::IndexNotWritable {
+                index_root,
+                message,
+            } => {
+                write!(
+                    f,
+                    "Nothing was saved to the index at {index_root}, which can't be written to: {message}"
+                )
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_warning_json() {
+        let warning = SyncWarning::FileTooLarge {
+            path: "dir/\u{FFFD}.txt".to_string(),
+            size: 7,
+        };
+        let json = serde_json::to_string(&warning).unwrap();
+        assert_eq!(
+            json,
+            r#"{"kind":"fileTooLarge","path":"dir/�.txt","size":7}"#
+        );
+        assert_eq!(serde_json::from_str::<SyncWarning>(&json).unwrap(), warning);
+    }
+}
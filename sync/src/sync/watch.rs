@@ -0,0 +1,1082 @@
+//! Watching a tag's directory and syncing it again as it changes
+//!
+//! The directory is watched with the OS's own notifications: inotify on Linux, with a watch on
+//! every directory in the tag's tree, an FSEvents stream on macOS and ReadDirectoryChangesW on
+//! Windows. Each sync only looks at what the events since the last one named, through
+//! `SyncSession::sync_changed`. Nothing is ever synced for want of an event: where changes
+//! can't be watched for, opening a watcher fails, and once they can't be any longer (inotify
+//! has run out of watches), so does every `wait`.
+
+use super::{error::Result, options::SyncOptions, session::SyncSession, SyncResults, Tag};
+use std::{
+    collections::BTreeSet,
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// See `SyncOptions::watch_debounce`
+pub(crate) const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// See `SyncOptions::watch_max_delay`
+pub(crate) const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(2);
+
+/// Past this many changed paths, walking everything is quicker than checking every directory
+/// against each of them
+const MAX_CHANGED_PATHS: usize = 1000;
+
+/// How long `SyncWatcher::run` waits for changes at a time. Nothing happens when it's up but
+/// waiting again
+const RUN_WAIT: Duration = Duration::from_secs(60);
+
+/// Files whose changes can add or remove anything under the directory they're in
+const IGNORE_FILES: &[&str] = &[".gitignore", ".ignore", ".continueignore"];
+
+/// What has changed since the last sync
+#[derive(Default)]
+struct Changed {
+    /// Relative to the tag's directory
+    paths: BTreeSet<PathBuf>,
+
+    /// Set when the changes weren't all seen, so only a full sync will do
+    everything: bool,
+}
+
+impl Changed {
+    fn is_empty(&self) -> bool {
+        self.paths.is_empty() && !self.everything
+    }
+
+    /// Record a change to `name` in the directory `dir`
+    fn add(&mut self, dir: &Path, name: &Path) {
+        match IGNORE_FILES
+            .iter()
+            .any(|ignore_file| name == Path::new(ignore_file))
+        {
+            true => self.insert(dir.to_path_buf()),
+            false => self.insert(dir.join(name)),
+        }
+    }
+
+    /// Record a change to `path`, or to anything under it. Paths under one already recorded
+    /// are looked at along with it, so only the one nearest the root is kept
+    fn insert(&mut self, path: PathBuf) {
+        if self.everything || path.ancestors().any(|dir| self.paths.contains(dir)) {
+            return;
+        }
+        // Sorted by component, the paths under this one come straight after it
+        let under: Vec<PathBuf> = self
+            .paths
+            .range(path.clone()..)
+            .take_while(|other| other.starts_with(&path))
+            .cloned()
+            .collect();
+        for other in under {
+            self.paths.remove(&other);
+        }
+        self.paths.insert(path);
+        if self.paths.len() > MAX_CHANGED_PATHS {
+            self.paths.clear();
+            self.everything = true;
+        }
+    }
+}
+
+/// Syncs a tag every time its directory changes, only looking at what changed
+///
+/// ```no_run
+/// use std::path::Path;
+/// use sync::sync::{SyncOptions, SyncWatcher, Tag};
+///
+/// let tag = Tag {
+///     dir: Path::new("src"),
+///     branch: "main",
+///     provider_id: "default",
+/// };
+/// let mut watcher = SyncWatcher::open(&tag, SyncOptions::new()).unwrap();
+/// watcher
+///     .run(|results| {
+///         println!("{} files to compute", results.compute.len());
+///         Ok(true)
+///     })
+///     .unwrap();
+/// ```
+pub struct SyncWatcher {
+    session: SyncSession,
+    dir: PathBuf,
+    watch: Watch,
+    debounce: Duration,
+    max_delay: Duration,
+
+    /// Found since the last sync, waiting for the next
+    changed: Changed,
+
+    /// Why changes can't be watched for any more, if they can't
+    failed: Option<(io::ErrorKind, String)>,
+}
+
+impl SyncWatcher {
+    /// Start watching the tag's directory. Nothing is synced until the first `wait`. Fails
+    /// where the OS can't tell when the directory changes
+    pub fn open(tag: &Tag, options: SyncOptions) -> Result<Self> {
+        let debounce = options.resolve_watch_debounce();
+        let max_delay = options.resolve_watch_max_delay();
+        let session = SyncSession::open(tag, options)?;
+        let dir = session.tag().dir.to_path_buf();
+        Ok(SyncWatcher {
+            watch: Watch::new(&dir)?,
+            dir,
+            session,
+            debounce,
+            max_delay,
+            changed: Changed {
+                everything: true,
+                ..Default::default()
+            },
+            failed: None,
+        })
+    }
+
+    /// The session the watcher syncs through, with the tree from its last sync
+    pub fn session(&self) -> &SyncSession {
+        &self.session
+    }
+
+    /// Wait up to `timeout` for the directory to change, and sync what changed once the changes
+    /// stop (see `SyncOptions::watch_debounce`), all at once. Returns None if nothing changed,
+    /// or if the sync had nothing to report. The first call syncs the whole directory straight
+    /// away, as does any call after events were missed. Once changes can't be watched for,
+    /// every call fails
+    pub fn wait(&mut self, timeout: Duration) -> Result<Option<SyncResults>> {
+        if let Some((kind, message)) = &self.failed {
+            return Err(io::Error::new(*kind, message.clone()).into());
+        }
+        // Changes already known about don't wait for more
+        let timeout = match self.changed.is_empty() {
+            true => timeout,
+            false => Duration::ZERO,
+        };
+        if self.watch.read(timeout, &mut self.changed)? {
+            self.settle()?;
+        }
+        if self.changed.is_empty() {
+            return Ok(None);
+        }
+
+        let changed = std::mem::take(&mut self.changed);
+        let results = match changed.everything {
+            true => self.session.sync(),
+            false => {
+                let paths: Vec<PathBuf> = changed.paths.into_iter().collect();
+                self.session.sync_changed(&paths)
+            }
+        };
+        let results = match results {
+            Ok(results) => results,
+            Err(err) => {
+                // Whatever it didn't get to is picked up by the next sync
+                self.changed.everything = true;
+                return Err(err);
+            }
+        };
+        if let Err(err) = self
+            .watch
+            .update(&self.dir, self.session.tree(), &mut self.changed)
+        {
+            // These results are right, but the next ones couldn't be
+            self.failed = Some((err.kind(), err.to_string()));
+        }
+        match results.has_changes() || !results.warnings.is_empty() {
+            true => Ok(Some(results)),
+            false => Ok(None),
+        }
+    }
+
+    /// Keep reading events until none arrive for the debounce, or the longest delay is up
+    fn settle(&mut self) -> Result<()> {
+        let start = Instant::now();
+        loop {
+            let left = self.max_delay.saturating_sub(start.elapsed());
+            if left.is_zero()
+                || !self
+                    .watch
+                    .read(self.debounce.min(left), &mut self.changed)?
+            {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Sync the directory every time it changes, handing the results to `on_results` until it
+    /// returns false or a sync fails
+    pub fn run(&mut self, mut on_results: impl FnMut(SyncResults) -> Result<bool>) -> Result<()> {
+        loop {
+            if let Some(results) = self.wait(RUN_WAIT)? {
+                if !on_results(results)? {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+use self::inotify::Watch;
+
+#[cfg(target_os = "macos")]
+use self::fsevents::Watch;
+
+#[cfg(windows)]
+use self::directory_changes::Watch;
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+use self::unsupported::Watch;
+
+#[cfg(target_os = "linux")]
+mod inotify {
+    use super::{super::merkle::Tree, Changed};
+    use std::{
+        collections::{HashMap, HashSet},
+        convert::TryInto,
+        ffi::{CString, OsStr},
+        io,
+        os::unix::{
+            ffi::OsStrExt,
+            io::{AsRawFd, FromRawFd, OwnedFd},
+        },
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    const MASK: u32 = libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_MODIFY
+        | libc::IN_CLOSE_WRITE
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_ATTRIB
+        | libc::IN_DELETE_SELF
+        | libc::IN_MOVE_SELF
+        | libc::IN_ONLYDIR;
+
+    /// The size of `struct inotify_event` without its name
+    const EVENT_SIZE: usize = 16;
+
+    /// inotify watches one directory at a time, so every directory in the tree gets a watch
+    pub(super) struct Watch {
+        fd: OwnedFd,
+
+        /// The directories watched, relative to the tag's directory, by watch descriptor
+        dirs: HashMap<i32, PathBuf>,
+        watches: HashMap<PathBuf, i32>,
+    }
+
+    impl Watch {
+        /// Nothing is watched until the first `update`
+        pub(super) fn new(_root: &Path) -> io::Result<Self> {
+            let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Watch {
+                fd: unsafe { OwnedFd::from_raw_fd(fd) },
+                dirs: HashMap::new(),
+                watches: HashMap::new(),
+            })
+        }
+
+        /// Watch every directory in `tree` under `root`, and stop watching the ones that have
+        /// gone. Directories that weren't watched before are added to `changed`, since they
+        /// could have changed in between
+        pub(super) fn update(
+            &mut self,
+            root: &Path,
+            tree: &Tree,
+            changed: &mut Changed,
+        ) -> io::Result<()> {
+            let dirs: HashSet<&Path> = tree.trees_by_path().into_keys().collect();
+            let gone: Vec<PathBuf> = self
+                .watches
+                .keys()
+                .filter(|path| !dirs.contains(path.as_path()))
+                .cloned()
+                .collect();
+            for path in gone {
+                let wd = self.watches.remove(&path).unwrap();
+                self.dirs.remove(&wd);
+                unsafe { libc::inotify_rm_watch(self.fd.as_raw_fd(), wd) };
+            }
+
+            for dir in dirs {
+                if self.watches.contains_key(dir) {
+                    continue;
+                }
+                let path = CString::new(root.join(dir).as_os_str().as_bytes())
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+                let wd =
+                    unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), MASK) };
+                if wd < 0 {
+                    match io::Error::last_os_error() {
+                        // Gone since the sync, which its parent's events tell
+                        err if matches!(err.raw_os_error(), Some(libc::ENOENT | libc::ENOTDIR)) => {
+                            continue
+                        }
+                        err if err.raw_os_error() == Some(libc::ENOSPC) => {
+                            return Err(io::Error::new(
+                                err.kind(),
+                                format!(
+                                    "out of inotify watches, see fs.inotify.max_user_watches \
+                                     ({err})"
+                                ),
+                            ))
+                        }
+                        err => return Err(err),
+                    }
+                }
+                self.dirs.insert(wd, dir.to_path_buf());
+                self.watches.insert(dir.to_path_buf(), wd);
+                changed.insert(dir.to_path_buf());
+            }
+            Ok(())
+        }
+
+        /// Add the changes from the events that arrive within `timeout` to `changed`, along with
+        /// any others already waiting, returning whether there were any
+        pub(super) fn read(
+            &mut self,
+            timeout: Duration,
+            changed: &mut Changed,
+        ) -> io::Result<bool> {
+            let mut poll_fd = libc::pollfd {
+                fd: self.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let timeout = timeout.as_millis().min(i32::MAX as u128) as i32;
+            match unsafe { libc::poll(&mut poll_fd, 1, timeout) } {
+                0 => return Ok(false),
+                n if n < 0 => {
+                    return match io::Error::last_os_error() {
+                        err if err.kind() == io::ErrorKind::Interrupted => Ok(false),
+                        err => Err(err),
+                    }
+                }
+                _ => {}
+            }
+
+            let mut buffer = vec![0u8; 64 * 1024];
+            let mut found = false;
+            loop {
+                let read = unsafe {
+                    libc::read(
+                        self.fd.as_raw_fd(),
+                        buffer.as_mut_ptr().cast(),
+                        buffer.len(),
+                    )
+                };
+                if read < 0 {
+                    return match io::Error::last_os_error() {
+                        err if err.kind() == io::ErrorKind::WouldBlock => Ok(found),
+                        err if err.kind() == io::ErrorKind::Interrupted => continue,
+                        err => Err(err),
+                    };
+                }
+                found = true;
+                let mut events = &buffer[..read as usize];
+                while events.len() >= EVENT_SIZE {
+                    let field = |at: usize| events[at..at + 4].try_into().unwrap();
+                    let wd = i32::from_ne_bytes(field(0));
+                    let mask = u32::from_ne_bytes(field(4));
+                    let len = u32::from_ne_bytes(field(12)) as usize;
+                    let name = &events[EVENT_SIZE..EVENT_SIZE + len];
+                    let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(len)];
+                    self.event(wd, mask, Path::new(OsStr::from_bytes(name)), changed);
+                    events = &events[EVENT_SIZE + len..];
+                }
+            }
+        }
+
+        fn event(&mut self, wd: i32, mask: u32, name: &Path, changed: &mut Changed) {
+            if mask & libc::IN_Q_OVERFLOW != 0 {
+                changed.everything = true;
+                return;
+            }
+            if mask & libc::IN_IGNORED != 0 {
+                if let Some(dir) = self.dirs.remove(&wd) {
+                    self.watches.remove(&dir);
+                }
+                return;
+            }
+            let dir = match self.dirs.get(&wd) {
+                Some(dir) => dir,
+                None => return,
+            };
+            if mask & (libc::IN_DELETE_SELF | libc::IN_MOVE_SELF) != 0 {
+                // Its parent hears about it too, unless it's the tag's directory itself
+                if dir.as_os_str().is_empty() {
+                    changed.everything = true;
+                }
+                return;
+            }
+            changed.add(dir, name);
+        }
+    }
+}
+
+/// Where a change to `path` is recorded, from a watch that reports the whole tree's changes
+/// relative to the tag's directory. A change to the directory itself needs everything looked at
+#[cfg(any(target_os = "macos", windows))]
+fn add_relative(changed: &mut Changed, path: &Path) {
+    match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => changed.add(dir, Path::new(name)),
+        _ => changed.everything = true,
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod fsevents {
+    use super::{super::merkle::Tree, add_relative, Changed};
+    use std::{
+        ffi::{c_void, CStr},
+        io,
+        os::raw::c_char,
+        path::{Path, PathBuf},
+        ptr,
+        sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        time::Duration,
+    };
+
+    type Stream = *mut c_void;
+
+    type Callback = extern "C" fn(
+        stream: Stream,
+        info: *mut c_void,
+        count: usize,
+        paths: *mut c_void,
+        flags: *const u32,
+        ids: *const u64,
+    );
+
+    #[repr(C)]
+    struct StreamContext {
+        version: isize,
+        info: *mut c_void,
+        retain: *const c_void,
+        release: *const c_void,
+        copy_description: *const c_void,
+    }
+
+    const UTF8: u32 = 0x0800_0100;
+    const SINCE_NOW: u64 = u64::MAX;
+
+    /// How long FSEvents holds events back to send them together, in seconds. The watcher
+    /// debounces them anyway
+    const LATENCY: f64 = 0.01;
+
+    const CREATE_NO_DEFER: u32 = 0x02;
+    const CREATE_WATCH_ROOT: u32 = 0x04;
+    const CREATE_FILE_EVENTS: u32 = 0x10;
+
+    const MUST_SCAN_SUB_DIRS: u32 = 0x01;
+    const ROOT_CHANGED: u32 = 0x20;
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    extern "C" {
+        static kCFTypeArrayCallBacks: c_void;
+        fn CFStringCreateWithBytes(
+            allocator: *const c_void,
+            bytes: *const u8,
+            len: isize,
+            encoding: u32,
+            external: u8,
+        ) -> *const c_void;
+        fn CFArrayCreate(
+            allocator: *const c_void,
+            values: *const *const c_void,
+            len: isize,
+            callbacks: *const c_void,
+        ) -> *const c_void;
+        fn CFRelease(object: *const c_void);
+    }
+
+    #[link(name = "CoreServices", kind = "framework")]
+    extern "C" {
+        fn FSEventStreamCreate(
+            allocator: *const c_void,
+            callback: Callback,
+            context: *const StreamContext,
+            paths: *const c_void,
+            since: u64,
+            latency: f64,
+            flags: u32,
+        ) -> Stream;
+        fn FSEventStreamSetDispatchQueue(stream: Stream, queue: *mut c_void);
+        fn FSEventStreamStart(stream: Stream) -> u8;
+        fn FSEventStreamStop(stream: Stream);
+        fn FSEventStreamInvalidate(stream: Stream);
+        fn FSEventStreamRelease(stream: Stream);
+    }
+
+    extern "C" {
+        fn dispatch_queue_create(label: *const c_char, attributes: *const c_void) -> *mut c_void;
+        fn dispatch_release(object: *mut c_void);
+    }
+
+    /// What the stream's callback hands over
+    enum Event {
+        /// A file or directory, as FSEvents names it: the absolute path with symlinks resolved
+        Path(PathBuf, u32),
+        Lost,
+    }
+
+    /// One FSEvents stream watches the whole tree, calling back on a queue of its own, which
+    /// sends the events here
+    pub(super) struct Watch {
+        stream: Stream,
+        queue: *mut c_void,
+        sender: *mut Sender<Event>,
+        receiver: Receiver<Event>,
+
+        /// The tag's directory as FSEvents names it
+        root: PathBuf,
+    }
+
+    impl Watch {
+        pub(super) fn new(root: &Path) -> io::Result<Self> {
+            let root = root.canonicalize()?;
+            // FSEvents takes the path as a CFString, which can't hold one that isn't UTF-8
+            let bytes = match root.to_str() {
+                Some(root) => root.as_bytes(),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{} isn't UTF-8, so FSEvents can't watch it", root.display()),
+                    ))
+                }
+            };
+            let (sender, receiver) = mpsc::channel();
+            let sender = Box::into_raw(Box::new(sender));
+            let context = StreamContext {
+                version: 0,
+                info: sender.cast(),
+                retain: ptr::null(),
+                release: ptr::null(),
+                copy_description: ptr::null(),
+            };
+            // SAFETY: every object made here is released before returning, apart from the
+            // stream and queue, which `Drop` releases, and `sender`, which lasts until then
+            unsafe {
+                let path = CFStringCreateWithBytes(
+                    ptr::null(),
+                    bytes.as_ptr(),
+                    bytes.len() as isize,
+                    UTF8,
+                    0,
+                );
+                let paths = CFArrayCreate(ptr::null(), &path, 1, &kCFTypeArrayCallBacks);
+                let stream = FSEventStreamCreate(
+                    ptr::null(),
+                    callback,
+                    &context,
+                    paths,
+                    SINCE_NOW,
+                    LATENCY,
+                    CREATE_NO_DEFER | CREATE_WATCH_ROOT | CREATE_FILE_EVENTS,
+                );
+                CFRelease(paths);
+                CFRelease(path);
+                if stream.is_null() {
+                    drop(Box::from_raw(sender));
+                    return Err(io::Error::other("couldn't create an FSEvents stream"));
+                }
+                let queue =
+                    dispatch_queue_create(b"continue-sync watch\0".as_ptr().cast(), ptr::null());
+                FSEventStreamSetDispatchQueue(stream, queue);
+                let watch = Watch {
+                    stream,
+                    queue,
+                    sender,
+                    receiver,
+                    root,
+                };
+                if FSEventStreamStart(stream) == 0 {
+                    return Err(io::Error::other("couldn't start an FSEvents stream"));
+                }
+                Ok(watch)
+            }
+        }
+
+        /// The stream covers the whole tree, so there's nothing to add as it grows
+        pub(super) fn update(
+            &mut self,
+            _root: &Path,
+            _tree: &Tree,
+            _changed: &mut Changed,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+
+        /// Add the changes from the events that arrive within `timeout` to `changed`, along with
+        /// any others already waiting, returning whether there were any
+        pub(super) fn read(
+            &mut self,
+            timeout: Duration,
+            changed: &mut Changed,
+        ) -> io::Result<bool> {
+            let mut event = match self.receiver.recv_timeout(timeout) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => return Ok(false),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::other("FSEvents stream stopped"))
+                }
+            };
+            loop {
+                match event {
+                    Event::Lost => changed.everything = true,
+                    Event::Path(_, flags) if flags & ROOT_CHANGED != 0 => changed.everything = true,
+                    Event::Path(path, flags) => match path.strip_prefix(&self.root) {
+                        Ok(path) if flags & MUST_SCAN_SUB_DIRS != 0 => {
+                            changed.insert(path.to_path_buf())
+                        }
+                        Ok(path) => add_relative(changed, path),
+                        Err(_) => changed.everything = true,
+                    },
+                }
+                event = match self.receiver.try_recv() {
+                    Ok(event) => event,
+                    Err(_) => return Ok(true),
+                };
+            }
+        }
+    }
+
+    impl Drop for Watch {
+        fn drop(&mut self) {
+            // SAFETY: once the stream is invalidated its callback isn't called again, so
+            // `sender` can go
+            unsafe {
+                FSEventStreamStop(self.stream);
+                FSEventStreamInvalidate(self.stream);
+                FSEventStreamRelease(self.stream);
+                dispatch_release(self.queue);
+                drop(Box::from_raw(self.sender));
+            }
+        }
+    }
+
+    extern "C" fn callback(
+        _stream: Stream,
+        info: *mut c_void,
+        count: usize,
+        paths: *mut c_void,
+        flags: *const u32,
+        _ids: *const u64,
+    ) {
+        // SAFETY: `info` is the watch's sender, and without the CF types flag `paths` is an
+        // array of C strings, each with its flags
+        unsafe {
+            let sender = &*info.cast::<Sender<Event>>();
+            let paths = std::slice::from_raw_parts(paths.cast::<*const c_char>(), count);
+            let flags = std::slice::from_raw_parts(flags, count);
+            for (&path, &flags) in paths.iter().zip(flags) {
+                let event = match CStr::from_ptr(path).to_str() {
+                    Ok(path) => Event::Path(PathBuf::from(path), flags),
+                    Err(_) => Event::Lost,
+                };
+                let _ = sender.send(event);
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod directory_changes {
+    use super::{super::merkle::Tree, add_relative, Changed};
+    use std::{
+        ffi::{c_void, OsStr, OsString},
+        io, iter,
+        os::windows::ffi::{OsStrExt, OsStringExt},
+        path::{Path, PathBuf},
+        ptr,
+        time::Duration,
+    };
+
+    const FILE_LIST_DIRECTORY: u32 = 0x0001;
+    const FILE_SHARE_ALL: u32 = 0x0007;
+    const OPEN_EXISTING: u32 = 3;
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+    const FILE_FLAG_OVERLAPPED: u32 = 0x4000_0000;
+    const INVALID_HANDLE_VALUE: *mut c_void = -1isize as *mut c_void;
+
+    const FILE_NOTIFY_CHANGE_FILE_NAME: u32 = 0x0001;
+    const FILE_NOTIFY_CHANGE_DIR_NAME: u32 = 0x0002;
+    const FILE_NOTIFY_CHANGE_ATTRIBUTES: u32 = 0x0004;
+    const FILE_NOTIFY_CHANGE_SIZE: u32 = 0x0008;
+    const FILE_NOTIFY_CHANGE_LAST_WRITE: u32 = 0x0010;
+    const FILTER: u32 = FILE_NOTIFY_CHANGE_FILE_NAME
+        | FILE_NOTIFY_CHANGE_DIR_NAME
+        | FILE_NOTIFY_CHANGE_ATTRIBUTES
+        | FILE_NOTIFY_CHANGE_SIZE
+        | FILE_NOTIFY_CHANGE_LAST_WRITE;
+
+    const WAIT_OBJECT_0: u32 = 0;
+    const WAIT_TIMEOUT: u32 = 0x102;
+
+    /// The size of `FILE_NOTIFY_INFORMATION` without its name
+    const EVENT_SIZE: usize = 12;
+
+    #[repr(C)]
+    struct Overlapped {
+        internal: usize,
+        internal_high: usize,
+        offset: u32,
+        offset_high: u32,
+        event: *mut c_void,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateFileW(
+            name: *const u16,
+            access: u32,
+            share: u32,
+            security: *mut c_void,
+            disposition: u32,
+            flags: u32,
+            template: *mut c_void,
+        ) -> *mut c_void;
+        fn CreateEventW(
+            security: *mut c_void,
+            manual_reset: i32,
+            initial_state: i32,
+            name: *const u16,
+        ) -> *mut c_void;
+        fn ReadDirectoryChangesW(
+            dir: *mut c_void,
+            buffer: *mut c_void,
+            len: u32,
+            subtree: i32,
+            filter: u32,
+            returned: *mut u32,
+            overlapped: *mut Overlapped,
+            completion: *mut c_void,
+        ) -> i32;
+        fn WaitForSingleObject(handle: *mut c_void, millis: u32) -> u32;
+        fn GetOverlappedResult(
+            file: *mut c_void,
+            overlapped: *mut Overlapped,
+            transferred: *mut u32,
+            wait: i32,
+        ) -> i32;
+        fn CancelIoEx(file: *mut c_void, overlapped: *mut Overlapped) -> i32;
+        fn CloseHandle(handle: *mut c_void) -> i32;
+    }
+
+    /// ReadDirectoryChangesW watches the whole tree through one handle on its directory. A read
+    /// is always pending, writing into `buffer` and signalling `overlapped`, so those are boxed
+    /// where they can't move
+    pub(super) struct Watch {
+        dir: *mut c_void,
+        event: *mut c_void,
+        overlapped: Box<Overlapped>,
+        buffer: Box<[u32; 16 * 1024]>,
+    }
+
+    impl Watch {
+        pub(super) fn new(root: &Path) -> io::Result<Self> {
+            let name: Vec<u16> = OsStr::new(root)
+                .encode_wide()
+                .chain(iter::once(0))
+                .collect();
+            // SAFETY: `name` is NUL terminated, and the handles are closed by `Drop`
+            unsafe {
+                let dir = CreateFileW(
+                    name.as_ptr(),
+                    FILE_LIST_DIRECTORY,
+                    FILE_SHARE_ALL,
+                    ptr::null_mut(),
+                    OPEN_EXISTING,
+                    FILE_FLAG_BACKUP_SEMANTICS | FILE_FLAG_OVERLAPPED,
+                    ptr::null_mut(),
+                );
+                if dir == INVALID_HANDLE_VALUE {
+                    return Err(io::Error::last_os_error());
+                }
+                let event = CreateEventW(ptr::null_mut(), 1, 0, ptr::null());
+                if event.is_null() {
+                    let err = io::Error::last_os_error();
+                    CloseHandle(dir);
+                    return Err(err);
+                }
+                let mut watch = Watch {
+                    dir,
+                    event,
+                    overlapped: Box::new(Overlapped {
+                        internal: 0,
+                        internal_high: 0,
+                        offset: 0,
+                        offset_high: 0,
+                        event,
+                    }),
+                    buffer: Box::new([0; 16 * 1024]),
+                };
+                watch.start()?;
+                Ok(watch)
+            }
+        }
+
+        /// Ask for the next changes
+        fn start(&mut self) -> io::Result<()> {
+            // SAFETY: `buffer` and `overlapped` stay where they are until the read is done,
+            // which `Drop` waits for
+            let started = unsafe {
+                ReadDirectoryChangesW(
+                    self.dir,
+                    self.buffer.as_mut_ptr().cast(),
+                    std::mem::size_of_val(&*self.buffer) as u32,
+                    1,
+                    FILTER,
+                    ptr::null_mut(),
+                    &mut *self.overlapped,
+                    ptr::null_mut(),
+                )
+            };
+            match started {
+                0 => Err(io::Error::last_os_error()),
+                _ => Ok(()),
+            }
+        }
+
+        /// The handle covers the whole tree, so there's nothing to add as it grows
+        pub(super) fn update(
+            &mut self,
+            _root: &Path,
+            _tree: &Tree,
+            _changed: &mut Changed,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+
+        /// Add the changes that arrive within `timeout` to `changed`, returning whether there
+        /// were any
+        pub(super) fn read(
+            &mut self,
+            timeout: Duration,
+            changed: &mut Changed,
+        ) -> io::Result<bool> {
+            let millis = timeout.as_millis().min(u128::from(u32::MAX - 1)) as u32;
+            // SAFETY: as in `start`
+            unsafe {
+                match WaitForSingleObject(self.event, millis) {
+                    WAIT_OBJECT_0 => {}
+                    WAIT_TIMEOUT => return Ok(false),
+                    _ => return Err(io::Error::last_os_error()),
+                }
+                let mut read = 0;
+                if GetOverlappedResult(self.dir, &mut *self.overlapped, &mut read, 0) == 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let bytes =
+                    std::slice::from_raw_parts(self.buffer.as_ptr().cast::<u8>(), read as usize);
+                match read {
+                    // More changed than the buffer holds
+                    0 => changed.everything = true,
+                    _ => Self::events(bytes, changed),
+                }
+            }
+            self.start()?;
+            Ok(true)
+        }
+
+        /// Record each `FILE_NOTIFY_INFORMATION` in `bytes`
+        fn events(mut bytes: &[u8], changed: &mut Changed) {
+            let field = |bytes: &[u8], at: usize| {
+                u32::from_ne_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+                    as usize
+            };
+            while bytes.len() >= EVENT_SIZE {
+                let next = field(bytes, 0);
+                let len = field(bytes, 8).min(bytes.len() - EVENT_SIZE);
+                let name: Vec<u16> = bytes[EVENT_SIZE..EVENT_SIZE + len]
+                    .chunks_exact(2)
+                    .map(|unit| u16::from_ne_bytes([unit[0], unit[1]]))
+                    .collect();
+                add_relative(changed, &PathBuf::from(OsString::from_wide(&name)));
+                if next == 0 || next > bytes.len() {
+                    return;
+                }
+                bytes = &bytes[next..];
+            }
+        }
+    }
+
+    impl Drop for Watch {
+        fn drop(&mut self) {
+            // SAFETY: the pending read is cancelled and waited for before what it writes to
+            // is freed
+            unsafe {
+                let mut read = 0;
+                CancelIoEx(self.dir, &mut *self.overlapped);
+                GetOverlappedResult(self.dir, &mut *self.overlapped, &mut read, 1);
+                CloseHandle(self.event);
+                CloseHandle(self.dir);
+            }
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod unsupported {
+    use super::{super::merkle::Tree, Changed};
+    use std::{io, path::Path, time::Duration};
+
+    enum Never {}
+
+    /// There's no way to watch for changes here, so there's never one of these
+    pub(super) struct Watch(Never);
+
+    impl Watch {
+        pub(super) fn new(_root: &Path) -> io::Result<Self> {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "watching for changes isn't supported on this platform",
+            ))
+        }
+
+        pub(super) fn update(&mut self, _: &Path, _: &Tree, _: &mut Changed) -> io::Result<()> {
+            match self.0 {}
+        }
+
+        pub(super) fn read(&mut self, _: Duration, _: &mut Changed) -> io::Result<bool> {
+            match self.0 {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{temp_index_root, TempDirBuilder};
+    use std::fs;
+
+    /// The results of the first sync that finds something, waiting a few seconds at most
+    fn next_results(watcher: &mut SyncWatcher) -> SyncResults {
+        for _ in 0..50 {
+            if let Some(results) = watcher.wait(Duration::from_millis(100)).unwrap() {
+                return results;
+            }
+        }
+        panic!("no changes found");
+    }
+
+    #[test]
+    fn test_coalesce_changes() {
+        let mut changed = Changed::default();
+        changed.insert("a/b/c.txt".into());
+        changed.insert("a/b.txt".into());
+        changed.insert("a/b/d.txt".into());
+        changed.insert("ab".into());
+        changed.insert("a/b".into());
+        changed.insert("a/b/e.txt".into());
+        let paths: Vec<&Path> = changed.paths.iter().map(PathBuf::as_path).collect();
+        assert_eq!(paths, ["a/b", "a/b.txt", "ab"].map(Path::new));
+
+        // An ignore file stands for its whole directory
+        changed.add(Path::new("a"), Path::new(".continueignore"));
+        let paths: Vec<&Path> = changed.paths.iter().map(PathBuf::as_path).collect();
+        assert_eq!(paths, ["a", "ab"].map(Path::new));
+
+        for i in 0..MAX_CHANGED_PATHS {
+            changed.insert(PathBuf::from(format!("{i}.txt")));
+        }
+        assert!(changed.everything && changed.paths.is_empty());
+    }
+
+    #[test]
+    fn test_watcher_stops_once_it_cant_watch() {
+        let temp_dir = TempDirBuilder::new().add("a.txt", "A").create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let mut watcher = SyncWatcher::open(tag, options).unwrap();
+        assert_eq!(next_results(&mut watcher).compute.len(), 1);
+
+        // Rather than falling back on syncing everything every so often
+        watcher.failed = Some((io::ErrorKind::Other, "out of watches".to_string()));
+        fs::write(temp_dir.path().join("a.txt"), "A changed").unwrap();
+        for _ in 0..2 {
+            let err = watcher.wait(Duration::from_millis(100)).unwrap_err();
+            assert!(err.to_string().contains("out of watches"));
+        }
+    }
+
+    #[test]
+    fn test_watcher_batches_bursts() {
+        let temp_dir = TempDirBuilder::new().add("a.txt", "A").create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new()
+            .index_root(index_root)
+            .watch_debounce(Duration::from_millis(500))
+            .watch_max_delay(Duration::from_secs(10));
+        let mut watcher = SyncWatcher::open(tag, options).unwrap();
+        assert_eq!(next_results(&mut watcher).compute.len(), 1);
+
+        fs::create_dir(temp_dir.path().join("dir")).unwrap();
+        for i in 0..50 {
+            fs::write(temp_dir.path().join(format!("dir/{i}.txt")), i.to_string()).unwrap();
+            fs::write(temp_dir.path().join("a.txt"), format!("A{i}")).unwrap();
+        }
+        let results = next_results(&mut watcher);
+        assert_eq!(results.compute.len(), 51);
+        assert_eq!(results.delete.len(), 1);
+    }
+
+    #[test]
+    fn test_watcher() {
+        let temp_dir = TempDirBuilder::new()
+            .add("a.txt", "A")
+            .add("dir/b.txt", "B")
+            .create();
+        let tag = &Tag {
+            dir: temp_dir.path(),
+            branch: "main",
+            provider_id: "default",
+        };
+        let (_index_dir, index_root) = temp_index_root();
+        let options = SyncOptions::new().index_root(index_root);
+        let mut watcher = SyncWatcher::open(tag, options.clone()).unwrap();
+        assert_eq!(next_results(&mut watcher).compute.len(), 2);
+
+        fs::write(temp_dir.path().join("dir/b.txt"), "B changed").unwrap();
+        let results = next_results(&mut watcher);
+        assert_eq!(results.compute.len(), 1);
+        assert_eq!(results.delete.len(), 1);
+
+        // Directories made after the watcher started are watched too
+        fs::create_dir(temp_dir.path().join("new")).unwrap();
+        fs::write(temp_dir.path().join("new/c.txt"), "C").unwrap();
+        let results = next_results(&mut watcher);
+        assert_eq!(results.compute.len(), 1);
+        assert_eq!(results.compute[0].relative_path, "new/c.txt");
+        fs::write(temp_dir.path().join("new/c.txt"), "C changed").unwrap();
+        assert_eq!(next_results(&mut watcher).compute.len(), 1);
+
+        fs::write(temp_dir.path().join(".continueignore"), "a.txt\n").unwrap();
+        let results = next_results(&mut watcher);
+        assert_eq!(results.delete.len(), 1);
+        assert_eq!(results.delete[0].relative_path, "a.txt");
+
+        // The tree kept up with every change
+        let tree = crate::sync::compute_tree_for_dir(temp_dir.path(), &options).unwrap();
+        assert_eq!(watcher.session().tree().hash(), tree.hash());
+    }
+}
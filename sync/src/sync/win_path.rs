@@ -0,0 +1,103 @@
+//! Windows paths, which can be spelled several ways for the same file: with either separator,
+//! with the drive letter in either case, or as an extended-length `\\?\` path, which is the only
+//! way to reach files more than `MAX_PATH` (260) characters deep.
+//!
+//! The index keeps every path in one normal form, the same one the user usually sees, and only
+//! uses the extended form to read from the disk. On other platforms nothing here changes a path
+
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+};
+
+/// The path in normal form: backslashes only, an upper case drive letter, and no `\\?\`
+pub(crate) fn normalize(path: &Path) -> Cow<'_, Path> {
+    match path.to_str() {
+        Some(path_str) if cfg!(windows) => Cow::Owned(PathBuf::from(normalize_str(path_str))),
+        _ => Cow::Borrowed(path),
+    }
+}
+
+/// The extended-length form of an absolute path, for reading from the disk
+pub(crate) fn extended(path: &Path) -> Cow<'_, Path> {
+    match path
+        .to_str()
+        .filter(|_| cfg!(windows))
+        .and_then(extended_str)
+    {
+        Some(extended) => Cow::Owned(PathBuf::from(extended)),
+        None => Cow::Borrowed(path),
+    }
+}
+
+fn normalize_str(path: &str) -> String {
+    let path = path.replace('/', "\\");
+    let mut path = match path.strip_prefix(r"\\?\") {
+        Some(rest) => match rest.strip_prefix(r"UNC\") {
+            Some(unc) => format!(r"\\{unc}"),
+            None if has_drive(rest) => rest.to_string(),
+            // Device paths like \\?\Volume{...} have no other spelling
+            None => path,
+        },
+        None => path,
+    };
+    if has_drive(&path) {
+        path[..1].make_ascii_uppercase();
+    }
+    path
+}
+
+/// None if the path is already extended, or is relative and so can't be
+fn extended_str(path: &str) -> Option<String> {
+    if path.starts_with(r"\\?\") {
+        None
+    } else if let Some(unc) = path.strip_prefix(r"\\") {
+        Some(format!(r"\\?\UNC\{unc}"))
+    } else if has_drive(path) && path[2..].starts_with('\\') {
+        Some(format!(r"\\?\{path}"))
+    } else {
+        None
+    }
+}
+
+fn has_drive(path: &str) -> bool {
+    let bytes = path.as_bytes();
+    bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_str() {
+        for (path, normal) in [
+            (r"C:\Users\me", r"C:\Users\me"),
+            ("c:/Users/me", r"C:\Users\me"),
+            (r"\\?\C:\Users\me", r"C:\Users\me"),
+            (r"\\?\UNC\server\share\dir", r"\\server\share\dir"),
+            ("//server/share/dir", r"\\server\share\dir"),
+            (r"\\?\Volume{1234}\dir", r"\\?\Volume{1234}\dir"),
+            (r"relative\dir", r"relative\dir"),
+        ] {
+            assert_eq!(normalize_str(path), normal);
+        }
+    }
+
+    #[test]
+    fn test_extended_str() {
+        for (path, extended) in [
+            (r"C:\Users\me", Some(r"\\?\C:\Users\me")),
+            (r"\\server\share\dir", Some(r"\\?\UNC\server\share\dir")),
+            (r"\\?\C:\Users\me", None),
+            (r"C:relative", None),
+            (r"relative\dir", None),
+        ] {
+            assert_eq!(extended_str(path).as_deref(), extended);
+        }
+
+        // Every normalized path reads from the same place as the path it came from
+        let path = r"\\?\UNC\server\share\dir";
+        assert_eq!(extended_str(&normalize_str(path)).as_deref(), Some(path));
+    }
+}
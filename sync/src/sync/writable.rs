@@ -0,0 +1,181 @@
+//! Making sure the index can be written before a sync changes any of it. A sync that finds the
+//! disk full or the filesystem read-only halfway through has to leave the tree, the caches and
+//! the journal for the next one to put right, so it's better not to start
+
+use super::{
+    error::{Result, SyncError},
+    file_system::FileSystem,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use rusqlite::ErrorCode;
+use std::{
+    fs,
+    io::{self, ErrorKind, Write},
+    path::Path,
+};
+
+/// Syncs don't start with less free space than this where the index is kept, unless
+/// `SyncOptions::min_free_space` says otherwise
+pub(crate) const DEFAULT_MIN_FREE_SPACE: u64 = 64 * 1024 * 1024;
+
+/// Create `dir` if needed, write a file to it, and check that at least `min_free_space` bytes
+/// are left on its filesystem. Fails with `SyncError::ReadOnlyIndex` or `SyncError::DiskFull`
+/// if it can't be written to
+pub(crate) fn check_writable(dir: &Path, min_free_space: u64) -> Result<()> {
+    let probe = || -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        let mut file = tempfile::Builder::new()
+            .prefix(".write_check")
+            .tempfile_in(dir)?;
+        file.write_all(b"\n")
+    };
+    probe().map_err(|err| write_error(err, dir))?;
+
+    match free_space(dir)? {
+        Some(available) if available < min_free_space => Err(SyncError::DiskFull(format!(
+            "{} has {available} bytes free, and syncs need at least {min_free_space}",
+            dir.display()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// `check_writable`, for an index in a `FileSystem` other than the OS's. There's no telling how
+/// much space that has left, so only the write is checked
+pub(crate) fn check_writable_in(file_system: &dyn FileSystem, dir: &Path) -> Result<()> {
+    let path = dir.join(".write_check");
+    let probe = || -> io::Result<()> {
+        file_system.create_dir_all(dir)?;
+        file_system.write(&path, b"\n", false)?;
+        file_system.remove_file(&path)
+    };
+    probe().map_err(|err| write_error(err, dir))
+}
+
+/// The error for failing to write to the index at `path`, as `SyncError::ReadOnlyIndex` or
+/// `SyncError::DiskFull` if that's why
+pub(crate) fn write_error(err: io::Error, path: &Path) -> SyncError {
+    let message = || format!("{}: {err}", path.display());
+    match err.kind() {
+        ErrorKind::ReadOnlyFilesystem | ErrorKind::PermissionDenied => {
+            SyncError::ReadOnlyIndex(message())
+        }
+        ErrorKind::StorageFull | ErrorKind::QuotaExceeded => SyncError::DiskFull(message()),
+        _ => err.into(),
+    }
+}
+
+/// `err`, as `SyncError::ReadOnlyIndex` or `SyncError::DiskFull` if it's from the index's
+/// filesystem becoming read-only or full part way through a sync. Permission errors are left
+/// alone, since they're as likely to be from reading the workspace
+pub(crate) fn classify(err: SyncError, index_dir: &Path) -> SyncError {
+    match err {
+        SyncError::Io(err)
+            if matches!(
+                err.kind(),
+                ErrorKind::ReadOnlyFilesystem | ErrorKind::StorageFull | ErrorKind::QuotaExceeded
+            ) =>
+        {
+            write_error(err, index_dir)
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        SyncError::Database(rusqlite::Error::SqliteFailure(failure, message))
+            if matches!(failure.code, ErrorCode::ReadOnly | ErrorCode::DiskFull) =>
+        {
+            let message = format!(
+                "{}: {}",
+                index_dir.display(),
+                message.unwrap_or_else(|| failure.to_string())
+            );
+            match failure.code {
+                ErrorCode::ReadOnly => SyncError::ReadOnlyIndex(message),
+                _ => SyncError::DiskFull(message),
+            }
+        }
+        err => err,
+    }
+}
+
+/// Bytes available to unprivileged users on the filesystem `dir` is on, or None where that
+/// can't be found out
+#[cfg(unix)]
+fn free_space(dir: &Path) -> io::Result<Option<u64>> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    match unsafe { libc::statvfs(path.as_ptr(), &mut stat) } {
+        #[allow(clippy::unnecessary_cast)]
+        0 => Ok(Some(
+            (stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64),
+        )),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(unix))]
+fn free_space(_dir: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::MemoryFileSystem;
+
+    #[test]
+    fn test_check_writable() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path().join("index");
+        check_writable(&dir, 0).unwrap();
+        assert!(dir.is_dir());
+        // The file written to find out is cleaned up
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        let result = check_writable(&dir, u64::MAX);
+        assert!(matches!(result, Err(SyncError::DiskFull(_))));
+
+        // Somewhere that can't hold a directory
+        let file = temp_dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        let result = check_writable(&file.join("index"), 0);
+        assert!(matches!(result, Err(SyncError::Io(_))));
+    }
+
+    #[test]
+    fn test_check_writable_in() {
+        let file_system = MemoryFileSystem::new();
+        check_writable_in(&file_system, Path::new("/index/tags")).unwrap();
+        assert!(file_system.metadata(Path::new("/index")).is_err());
+    }
+
+    #[test]
+    fn test_classify() {
+        let dir = Path::new("/index");
+        let io_error = |kind: ErrorKind| SyncError::Io(io::Error::from(kind));
+        let result = classify(io_error(ErrorKind::ReadOnlyFilesystem), dir);
+        assert!(
+            matches!(result, SyncError::ReadOnlyIndex(message) if message.starts_with("/index"))
+        );
+        let result = classify(io_error(ErrorKind::StorageFull), dir);
+        assert!(matches!(result, SyncError::DiskFull(_)));
+        let result = classify(io_error(ErrorKind::QuotaExceeded), dir);
+        assert!(matches!(result, SyncError::DiskFull(_)));
+        let result = classify(io_error(ErrorKind::PermissionDenied), dir);
+        assert!(matches!(result, SyncError::Io(_)));
+
+        let sqlite_error = |code: i32| {
+            SyncError::Database(rusqlite::Error::SqliteFailure(
+                rusqlite::ffi::Error::new(code),
+                None,
+            ))
+        };
+        let result = classify(sqlite_error(rusqlite::ffi::SQLITE_FULL), dir);
+        assert!(matches!(result, SyncError::DiskFull(_)));
+        let result = classify(sqlite_error(rusqlite::ffi::SQLITE_READONLY), dir);
+        assert!(matches!(result, SyncError::ReadOnlyIndex(_)));
+        let result = classify(sqlite_error(rusqlite::ffi::SQLITE_BUSY), dir);
+        assert!(matches!(result, SyncError::Database(_)));
+    }
+}
@@ -0,0 +1,80 @@
+//! Reading and writing extended attributes, on the platforms that have them. Everywhere else
+//! every attribute is missing and writes are dropped
+
+use std::{io, path::Path};
+
+/// The value of the attribute `name` of the file at `path`, if it has one. Values longer than
+/// `max_len` are treated as missing
+pub(crate) fn get(path: &Path, name: &str, max_len: usize) -> io::Result<Option<Vec<u8>>> {
+    imp::get(path, name, max_len)
+}
+
+pub(crate) fn set(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    imp::set(path, name, value)
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod imp {
+    use std::{ffi::CString, io, os::unix::ffi::OsStrExt, path::Path};
+
+    fn c_string(bytes: &[u8]) -> io::Result<CString> {
+        CString::new(bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+    }
+
+    #[cfg(target_os = "linux")]
+    const NO_ATTRIBUTE: i32 = libc::ENODATA;
+    #[cfg(target_os = "macos")]
+    const NO_ATTRIBUTE: i32 = libc::ENOATTR;
+
+    pub(super) fn get(path: &Path, name: &str, max_len: usize) -> io::Result<Option<Vec<u8>>> {
+        let path = c_string(path.as_os_str().as_bytes())?;
+        let name = c_string(name.as_bytes())?;
+        let mut value = vec![0u8; max_len];
+        let ptr = value.as_mut_ptr() as *mut libc::c_void;
+
+        #[cfg(target_os = "linux")]
+        let len = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), ptr, max_len) };
+        #[cfg(target_os = "macos")]
+        let len = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), ptr, max_len, 0, 0) };
+
+        if len < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(code) if code == NO_ATTRIBUTE || code == libc::ERANGE => Ok(None),
+                _ => Err(err),
+            };
+        }
+        value.truncate(len as usize);
+        Ok(Some(value))
+    }
+
+    pub(super) fn set(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+        let path = c_string(path.as_os_str().as_bytes())?;
+        let name = c_string(name.as_bytes())?;
+        let ptr = value.as_ptr() as *const libc::c_void;
+
+        #[cfg(target_os = "linux")]
+        let result = unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), ptr, value.len(), 0) };
+        #[cfg(target_os = "macos")]
+        let result =
+            unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), ptr, value.len(), 0, 0) };
+
+        match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod imp {
+    use std::{io, path::Path};
+
+    pub(super) fn get(_path: &Path, _name: &str, _max_len: usize) -> io::Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    pub(super) fn set(_path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+}
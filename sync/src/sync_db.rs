@@ -1,30 +1,57 @@
-use crate::db::{add_tag, create_database, remove_chunks_for_hash, remove_tag};
+use crate::db::{add_tag, create_database, remove_chunks_for_hash, remove_tag, rename_chunks};
 use crate::sync;
 
-pub fn sync_db(tag: &sync::Tag) -> Vec<(String, String)> {
-    create_database();
-
-    let results = sync::sync(tag).unwrap();
-
-    // Send to IDE Extension to compute embeddings
-    let compute = results.0;
-
+/// Apply the delete, add_tag, remove_tag and renamed actions to the database, returning what still
+/// needs to be computed
+fn apply_results(tag: &sync::Tag, results: sync::SyncResults) -> Vec<sync::SyncResultItem> {
     // Delete chunks
-    for (_, hash) in results.1 {
-        remove_chunks_for_hash(hash);
+    for item in results.delete {
+        remove_chunks_for_hash(item.hash);
     }
 
     // Add tag from chunks
-    for (_, hash) in results.2 {
-        add_tag(hash, tag.to_string());
+    for item in results.add_tag {
+        add_tag(item.hash, tag.to_string());
     }
 
     // Remove tag from chunk_rows
-    for (_, hash) in results.3 {
-        remove_tag(hash, tag.to_string());
+    for item in results.remove_tag {
+        remove_tag(item.hash, tag.to_string());
+    }
+
+    // Point chunks of moved files at their new path
+    for item in results.renamed {
+        rename_chunks(item.hash, item.old_path, item.new_path);
     }
 
-    return compute;
+    // Send to IDE Extension to compute embeddings
+    results.compute
+}
+
+pub fn sync_db(tag: &sync::Tag) -> sync::Result<Vec<sync::SyncResultItem>> {
+    create_database();
+
+    let results = sync::sync(tag, &sync::SyncOptions::default())?;
+    Ok(apply_results(tag, results))
+}
+
+/// Like `sync_db`, but hands the files to compute to `on_compute` in pages of up to
+/// `batch_size` while the directory is still being walked, so embedding can start before the
+/// whole diff is known
+pub fn sync_db_in_batches(
+    tag: &sync::Tag,
+    batch_size: usize,
+    mut on_compute: impl FnMut(Vec<sync::SyncResultItem>) -> sync::Result<()>,
+) -> sync::Result<()> {
+    create_database();
+
+    sync::sync_in_batches(tag, &sync::SyncOptions::default(), batch_size, |results| {
+        let compute = apply_results(tag, results);
+        if compute.is_empty() {
+            return Ok(());
+        }
+        on_compute(compute)
+    })
 }
 
 #[cfg(test)]
@@ -39,6 +66,30 @@ mod tests {
             branch: "main",
             provider_id: "test",
         };
-        sync_db(tag);
+        sync_db(tag).expect("Sync failed.");
+    }
+
+    #[test]
+    fn test_sync_db_in_batches() {
+        let tag = &sync::Tag {
+            dir: Path::new("src"),
+            branch: "main",
+            provider_id: "test_batches",
+        };
+        let options = &sync::SyncOptions::default();
+        sync::delete_tag(tag, options).unwrap();
+
+        let mut pages = Vec::new();
+        sync_db_in_batches(tag, 3, |compute| {
+            pages.push(compute);
+            Ok(())
+        })
+        .expect("Sync failed.");
+        assert!(pages.len() > 1);
+        assert!(pages.iter().all(|page| !page.is_empty() && page.len() <= 3));
+
+        // Nothing is left to compute the second time
+        sync_db_in_batches(tag, 3, |_| panic!("Nothing changed")).expect("Sync failed.");
+        sync::delete_tag(tag, options).unwrap();
     }
 }
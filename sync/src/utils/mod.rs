@@ -1,7 +1,15 @@
+use crate::sync::IndexRoot;
 use std::fs::{self, File};
 use std::io::Write;
 use tempfile::tempdir;
 
+/// An empty index in a temp directory, so tests don't touch ~/.continue/index
+pub fn temp_index_root() -> (tempfile::TempDir, IndexRoot) {
+    let temp_dir = tempdir().expect("Failed to create temp dir");
+    let index_root = IndexRoot::new(temp_dir.path());
+    (temp_dir, index_root)
+}
+
 #[derive(Default)]
 pub struct TempDirBuilder {
     files: Vec<(String, String)>,